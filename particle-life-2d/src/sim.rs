@@ -41,12 +41,13 @@ pub struct SimConfig {
 impl Behaviour {
     /// Returns the force on this particle
     ///
-    /// Distance is in the range `0.0..=1.0`
+    /// Distance is in the range `0.0..=1.0`. A threshold of zero means there is no repulsive
+    /// core, and a max distance equal to the threshold means there is only repulsion.
     fn interact(&self, dist: f32) -> f32 {
         if dist < self.inter_threshold {
             let f = dist / self.inter_threshold;
             (1. - f) * -self.default_repulse
-        } else if dist > self.inter_max_dist {
+        } else if dist >= self.inter_max_dist {
             0.0
         } else {
             let x = dist - self.inter_threshold;
@@ -157,6 +158,38 @@ mod tests {
         assert_eq!(behav.interact(behav.inter_max_dist), 0.0);
         assert_eq!(behav.interact(0.85), 0.0);
     }
+
+    #[test]
+    fn test_degenerate_behaviour() {
+        for (inter_threshold, inter_max_dist) in [(0., 0.2), (0.1, 0.1), (0., 0.)] {
+            let behav = Behaviour {
+                default_repulse: 10.,
+                inter_threshold,
+                inter_strength: 3.,
+                inter_max_dist,
+            };
+
+            // Steepest slope of either regime
+            let mut max_slope: f32 = 0.;
+            if inter_threshold > 0. {
+                max_slope = max_slope.max(behav.default_repulse / inter_threshold);
+            }
+            if inter_max_dist > inter_threshold {
+                max_slope =
+                    max_slope.max(2. * behav.inter_strength / (inter_max_dist - inter_threshold));
+            }
+
+            let h = 1e-3;
+            let mut last = behav.interact(0.);
+            for i in 1..=1000 {
+                let force = behav.interact(i as f32 * h);
+                assert!(force.is_finite());
+                assert!((force - last).abs() <= max_slope * h * 1.01 + 1e-6);
+                last = force;
+            }
+            assert_eq!(behav.interact(inter_max_dist), 0.);
+        }
+    }
 }
 
 impl Default for Behaviour {
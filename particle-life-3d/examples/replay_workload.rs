@@ -0,0 +1,82 @@
+//! Rerun the force pass of a captured workload in a timing loop, for optimizing the hot
+//! loops against real emergent states rather than uniform random points. Takes a file holding
+//! a workload blob, or its hex encoding as logged by the client on `CaptureWorkload`, and
+//! optionally the number of repetitions:
+//!
+//! ```text
+//! cargo run --release --features headless --example replay_workload -- workload.hex 50
+//! ```
+//!
+//! Before timing anything, the neighbor lists are recomputed and compared to the captured
+//! ones, which catches accelerator regressions.
+use std::{hint::black_box, process::exit, time::Instant};
+
+use particle_life_3d::workload::Workload;
+
+fn main() {
+    let mut args = std::env::args().skip(1);
+    let Some(path) = args.next() else {
+        eprintln!("Usage: replay_workload <workload file> [repetitions]");
+        exit(2);
+    };
+    let reps: usize = args
+        .next()
+        .map_or(20, |reps| reps.parse().expect("Repetitions"));
+
+    let bytes = std::fs::read(&path).unwrap_or_else(|e| panic!("Reading {}: {}", path, e));
+    let workload = Workload::decode(&bytes).or_else(|_| {
+        let text = String::from_utf8_lossy(&bytes);
+        Workload::from_hex(text.trim().trim_start_matches("Workload: "))
+    });
+    let workload = workload.unwrap_or_else(|e| panic!("Decoding {}: {}", path, e));
+
+    let sim = workload.sim();
+    if let Err(e) = workload.check(&sim) {
+        eprintln!("Accelerator regression: {}", e);
+        exit(1);
+    }
+    let n = workload.positions.len();
+    println!(
+        "{} particles of {} types, {} neighbor pairs, {:?} accelerator with {} cells",
+        n,
+        workload.config.colors.len(),
+        workload.neighbor_pairs(),
+        workload.mode,
+        workload.cells.len()
+    );
+
+    time("total_force", reps, n, || {
+        for i in 0..n {
+            black_box(sim.total_force(i));
+        }
+    });
+    time("energy_due_to", reps, n, || {
+        for (i, &pos) in workload.positions.iter().enumerate() {
+            black_box(sim.energy_due_to(i, pos));
+        }
+    });
+}
+
+/// Run `pass`, which makes `calls` calls, `reps` times and report the time per call
+fn time(name: &str, reps: usize, calls: usize, mut pass: impl FnMut()) {
+    // Warm up the caches
+    pass();
+    let mut per_call: Vec<f64> = (0..reps.max(1))
+        .map(|_| {
+            let start = Instant::now();
+            pass();
+            start.elapsed().as_secs_f64() * 1e9 / calls.max(1) as f64
+        })
+        .collect();
+    per_call.sort_by(f64::total_cmp);
+    let mean = per_call.iter().sum::<f64>() / per_call.len() as f64;
+    println!(
+        "{:>14}: {:.0} ns per call (min {:.0}, median {:.0}, max {:.0}) over {} passes",
+        name,
+        mean,
+        per_call[0],
+        per_call[per_call.len() / 2],
+        per_call[per_call.len() - 1],
+        per_call.len()
+    );
+}
@@ -0,0 +1,345 @@
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diagnostics::{find_clusters, ClusterConfig},
+    placement::SimPlacement,
+    sim::SimState,
+};
+
+/// Sound-worthy happenings in the simulation
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum SimAudioEvent {
+    /// A new cluster appeared where there was none before
+    ClusterFormed { center: Vec3, size: usize },
+    /// Two or more clusters joined together
+    ClusterMerged { center: Vec3, size: usize },
+    /// Two particles met at high speed
+    HighSpeedCollision { pos: Vec3, relative_speed: f32 },
+}
+
+/// Audio events detected during one frame, for consumption by audio plugins
+#[derive(Message, Serialize, Deserialize, Clone, Debug, Default)]
+#[locality("Local")]
+pub struct SimAudioEvents {
+    pub events: Vec<SimAudioEvent>,
+}
+
+/// Toggles and thresholds for audio event detection
+#[derive(Clone, Debug)]
+pub struct AudioEventConfig {
+    pub collisions: bool,
+    pub clusters: bool,
+    /// Particles closer than this are considered to be colliding
+    pub collision_dist: f32,
+    /// Minimum closing speed for a collision to be reported
+    pub collision_speed: f32,
+    pub cluster: ClusterConfig,
+    /// Number of frames between cluster analyses
+    pub cluster_interval: usize,
+    /// Maximum number of events per second, for each kind of event
+    pub max_rate: f32,
+    /// Maximum number of events emitted in a single frame
+    pub max_per_frame: usize,
+}
+
+/// Detects audio events from frame to frame
+pub struct AudioEventDetector {
+    pub config: AudioEventConfig,
+    /// Frames remaining until the next cluster analysis
+    until_clusters: usize,
+    /// Cluster of each particle in the last analysis, if that cluster was large enough to count
+    last_labels: Vec<Option<usize>>,
+    /// Rate limiting token buckets, one per kind of event
+    budget: [f32; 3],
+    window_time: f32,
+    window_count: usize,
+    events_per_second: f32,
+}
+
+impl SimAudioEvent {
+    fn kind(&self) -> usize {
+        match self {
+            SimAudioEvent::HighSpeedCollision { .. } => 0,
+            SimAudioEvent::ClusterFormed { .. } => 1,
+            SimAudioEvent::ClusterMerged { .. } => 2,
+        }
+    }
+
+    /// This event with its position in world space, rather than simulation space
+    pub fn to_world(self, placement: &SimPlacement) -> Self {
+        match self {
+            SimAudioEvent::ClusterFormed { center, size } => SimAudioEvent::ClusterFormed {
+                center: placement.to_world(center),
+                size,
+            },
+            SimAudioEvent::ClusterMerged { center, size } => SimAudioEvent::ClusterMerged {
+                center: placement.to_world(center),
+                size,
+            },
+            SimAudioEvent::HighSpeedCollision {
+                pos,
+                relative_speed,
+            } => SimAudioEvent::HighSpeedCollision {
+                pos: placement.to_world(pos),
+                relative_speed,
+            },
+        }
+    }
+}
+
+impl AudioEventDetector {
+    pub fn new(config: AudioEventConfig) -> Self {
+        Self {
+            budget: [config.max_rate; 3],
+            config,
+            until_clusters: 0,
+            last_labels: vec![],
+            window_time: 0.,
+            window_count: 0,
+            events_per_second: 0.,
+        }
+    }
+
+    /// Find this frame's events. `dt` is the real time elapsed since the last call, in seconds.
+    pub fn detect(&mut self, sim: &SimState, dt: f32) -> Vec<SimAudioEvent> {
+        let mut candidates = vec![];
+
+        if self.config.collisions {
+            self.detect_collisions(sim, &mut candidates);
+        }
+
+        if self.config.clusters {
+            if self.until_clusters == 0 {
+                self.detect_clusters(sim, &mut candidates);
+                self.until_clusters = self.config.cluster_interval.max(1);
+            }
+            self.until_clusters -= 1;
+        }
+
+        // Rate limiting
+        for budget in &mut self.budget {
+            *budget = (*budget + self.config.max_rate * dt).min(self.config.max_rate);
+        }
+
+        let mut events = vec![];
+        for event in candidates {
+            let budget = &mut self.budget[event.kind()];
+            if events.len() < self.config.max_per_frame && *budget >= 1. {
+                *budget -= 1.;
+                events.push(event);
+            }
+        }
+
+        // Debug counter
+        self.window_time += dt;
+        self.window_count += events.len();
+        if self.window_time >= 1. {
+            self.events_per_second = self.window_count as f32 / self.window_time;
+            self.window_time = 0.;
+            self.window_count = 0;
+        }
+
+        events
+    }
+
+    /// Number of events emitted per second, measured over the last second or so
+    pub fn events_per_second(&self) -> f32 {
+        self.events_per_second
+    }
+
+    fn detect_collisions(&self, sim: &SimState, events: &mut Vec<SimAudioEvent>) {
+        let particles = sim.particles();
+
+        // At least one of the two particles must be moving at half of the collision speed
+        let fast_sq = (self.config.collision_speed / 2.).powi(2);
+        let is_fast = |i: usize| particles[i].vel.length_squared() >= fast_sq;
+        let dist_sq = self.config.collision_dist.powi(2);
+
+        for (i, a) in particles.iter().enumerate() {
+            if !is_fast(i) {
+                continue;
+            }
+
+            for j in sim.neighbors(i) {
+                // Report each pair only once
+                if is_fast(j) && j < i {
+                    continue;
+                }
+
+                let b = particles[j];
+                let diff = b.pos - a.pos;
+                if diff.length_squared() > dist_sq {
+                    continue;
+                }
+
+                // Only count particles moving towards each other
+                let rel_vel = a.vel - b.vel;
+                if rel_vel.dot(diff) <= 0. {
+                    continue;
+                }
+
+                let relative_speed = rel_vel.length();
+                if relative_speed >= self.config.collision_speed {
+                    events.push(SimAudioEvent::HighSpeedCollision {
+                        pos: (a.pos + b.pos) / 2.,
+                        relative_speed,
+                    });
+                }
+            }
+        }
+    }
+
+    fn detect_clusters(&mut self, sim: &SimState, events: &mut Vec<SimAudioEvent>) {
+        let result = find_clusters(sim, self.config.cluster.radius);
+        let significant =
+            |label: usize| result.clusters[label].size >= self.config.cluster.min_size;
+
+        // Without a comparable previous analysis, just record the current state
+        if self.last_labels.len() == result.labels.len() {
+            let mut previous: Vec<Vec<usize>> = vec![vec![]; result.clusters.len()];
+            for (&label, &last) in result.labels.iter().zip(&self.last_labels) {
+                if let Some(last) = last {
+                    if !previous[label].contains(&last) {
+                        previous[label].push(last);
+                    }
+                }
+            }
+
+            for (label, cluster) in result.clusters.iter().enumerate() {
+                if !significant(label) {
+                    continue;
+                }
+
+                let (center, size) = (cluster.center, cluster.size);
+                match previous[label].len() {
+                    0 => events.push(SimAudioEvent::ClusterFormed { center, size }),
+                    1 => (),
+                    _ => events.push(SimAudioEvent::ClusterMerged { center, size }),
+                }
+            }
+        }
+
+        self.last_labels = result
+            .labels
+            .iter()
+            .map(|&label| significant(label).then_some(label))
+            .collect();
+    }
+}
+
+impl Default for AudioEventConfig {
+    fn default() -> Self {
+        Self {
+            collisions: true,
+            clusters: true,
+            collision_dist: 0.01,
+            collision_speed: 2.,
+            cluster: ClusterConfig::default(),
+            cluster_interval: 30,
+            max_rate: 20.,
+            max_per_frame: 8,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Behaviour, Particle, SimConfig};
+
+    fn two_types() -> SimConfig {
+        SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 0.,
+            ..Default::default()
+        }
+    }
+
+    fn particle(pos: Vec3, vel: Vec3) -> Particle {
+        Particle { pos, vel, color: 0 }
+    }
+
+    #[test]
+    fn test_collision_detected_once() {
+        let sim = SimState::from_particles(
+            two_types(),
+            vec![
+                particle(Vec3::ZERO, Vec3::X * 5.),
+                particle(Vec3::X * 0.005, -Vec3::X * 5.),
+                // Fast, but moving apart
+                particle(Vec3::Y, -Vec3::X * 5.),
+                particle(Vec3::Y + Vec3::X * 0.005, Vec3::X * 5.),
+            ],
+        );
+
+        let mut detector = AudioEventDetector::new(AudioEventConfig::default());
+        let events = detector.detect(&sim, 1. / 60.);
+        assert_eq!(
+            events,
+            vec![SimAudioEvent::HighSpeedCollision {
+                pos: Vec3::X * 0.0025,
+                relative_speed: 10.
+            }]
+        );
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let particles = (0..100)
+            .flat_map(|i| {
+                let base = Vec3::new(i as f32, 0., 0.);
+                [
+                    particle(base, Vec3::X * 5.),
+                    particle(base + Vec3::X * 0.005, -Vec3::X * 5.),
+                ]
+            })
+            .collect();
+        let sim = SimState::from_particles(two_types(), particles);
+
+        let config = AudioEventConfig {
+            clusters: false,
+            ..Default::default()
+        };
+        let mut detector = AudioEventDetector::new(config.clone());
+        assert_eq!(detector.detect(&sim, 0.).len(), config.max_per_frame);
+
+        // Exhaust the initial burst, after which only the steady rate remains
+        while !detector.detect(&sim, 0.).is_empty() {}
+        let total: usize = (0..60).map(|_| detector.detect(&sim, 1. / 60.).len()).sum();
+        assert!(total as f32 <= config.max_rate);
+    }
+
+    #[test]
+    fn test_cluster_formed() {
+        let config = AudioEventConfig {
+            collisions: false,
+            cluster_interval: 1,
+            cluster: ClusterConfig {
+                min_size: 5,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut detector = AudioEventDetector::new(config);
+
+        let spread = |spacing: f32| {
+            (0..10)
+                .map(|i| particle(Vec3::X * i as f32 * spacing, Vec3::ZERO))
+                .collect()
+        };
+
+        // First analysis only records
+        let gas = SimState::from_particles(two_types(), spread(1.));
+        assert!(detector.detect(&gas, 0.1).is_empty());
+        assert!(detector.detect(&gas, 0.1).is_empty());
+
+        let blob = SimState::from_particles(two_types(), spread(0.01));
+        assert!(matches!(
+            detector.detect(&blob, 0.1)[..],
+            [SimAudioEvent::ClusterFormed { size: 10, .. }]
+        ));
+    }
+}
@@ -0,0 +1,282 @@
+//! Startup self-calibration of the particle count. There is no clock inside the plugin, so
+//! the cost of a step is measured from frame times: a throwaway simulation is stepped a few
+//! times each frame, over a handful of frames, and the growth of the frame time with the
+//! number of steps gives the cost of each.
+//!
+//! Frames at vsync absorb work until it exceeds their slack, which would hide part of it.
+//! Each size is therefore first given enough steps per frame to visibly lengthen frames, and
+//! the cost is taken from the difference between that and twice as many steps.
+use cimvr_engine_interface::pcg::Pcg;
+
+use crate::{
+    diagnostics::sampled_quantile,
+    sim::{SimConfig, SimState},
+};
+
+/// Time step of the throwaway steps
+const CALIBRATION_DT: f32 = 1e-3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CalibrationConfig {
+    /// Time per frame the simulation step should take, in milliseconds
+    pub frame_budget_ms: f32,
+    /// Particle counts to measure at; two or more, to fit the growth with density
+    pub sizes: Vec<usize>,
+    /// Frames measured at each workload, after one frame to settle
+    pub frames: usize,
+    /// Lengthening of the median frame beyond which the work is taken to show
+    pub margin_ms: f32,
+    /// Most steps per frame at any size
+    pub max_steps: usize,
+    /// Range the chosen particle count is clamped to
+    pub min_particles: usize,
+    pub max_particles: usize,
+}
+
+/// Particle count chosen by a calibration
+#[derive(Clone, Debug, PartialEq)]
+pub struct CalibrationResult {
+    pub particles: usize,
+    /// Milliseconds per step at each measured particle count
+    pub costs: Vec<(usize, f32)>,
+    pub frame_budget_ms: f32,
+}
+
+/// A calibration in progress, ticked once per frame
+pub struct Calibration {
+    config: CalibrationConfig,
+    sim_config: SimConfig,
+    /// Throwaway simulation of the size being measured, if past the baseline
+    sim: Option<SimState>,
+    /// Median frame time without any work, once measured
+    baseline_ms: Option<f32>,
+    /// Index of the size being measured
+    size: usize,
+    /// Steps per frame of the current workload
+    steps: usize,
+    /// Median frame time at half the current steps, once they visibly lengthen frames
+    lower_ms: Option<f32>,
+    /// Frame times measured at the current workload
+    frames: Vec<f32>,
+    /// Whether the next frame time is still due to the previous workload
+    settling: bool,
+    costs: Vec<(usize, f32)>,
+}
+
+impl Default for CalibrationConfig {
+    fn default() -> Self {
+        Self {
+            frame_budget_ms: 4.,
+            sizes: vec![250, 1000],
+            frames: 6,
+            margin_ms: 1.,
+            max_steps: 64,
+            min_particles: 200,
+            max_particles: 20_000,
+        }
+    }
+}
+
+impl Calibration {
+    /// Calibrate for simulations of `sim_config`
+    pub fn new(config: CalibrationConfig, sim_config: SimConfig) -> Self {
+        assert!(config.sizes.len() >= 2, "Calibration needs two sizes");
+        Self {
+            config,
+            sim_config,
+            sim: None,
+            baseline_ms: None,
+            size: 0,
+            steps: 0,
+            lower_ms: None,
+            frames: vec![],
+            settling: true,
+            costs: vec![],
+        }
+    }
+
+    /// Call once per frame with the duration of the last frame, if known. Does this frame's
+    /// share of the throwaway steps, and returns the result once done.
+    pub fn tick(&mut self, frame_ms: Option<f32>, rng: &mut Pcg) -> Option<CalibrationResult> {
+        if let Some(ms) = frame_ms {
+            match self.settling {
+                true => self.settling = false,
+                false => self.frames.push(ms),
+            }
+        }
+        if self.frames.len() >= self.config.frames {
+            let median = sampled_quantile(&self.frames, 0.5, usize::MAX);
+            self.frames.clear();
+            self.settling = true;
+            if self.next_workload(median, rng) {
+                return Some(self.result());
+            }
+        }
+
+        if let Some(sim) = &mut self.sim {
+            for _ in 0..self.steps {
+                sim.step(CALIBRATION_DT);
+            }
+        }
+        None
+    }
+
+    /// Particles and steps per frame of the current workload
+    pub fn workload(&self) -> (usize, usize) {
+        match &self.sim {
+            Some(sim) => (sim.particles().len(), self.steps),
+            None => (0, 0),
+        }
+    }
+
+    /// Fraction of the sizes measured, from 0 to 1
+    pub fn progress(&self) -> f32 {
+        self.costs.len() as f32 / self.config.sizes.len() as f32
+    }
+
+    /// Move on from a workload whose median frame time was `median`. Returns true when every
+    /// size is measured.
+    fn next_workload(&mut self, median: f32, rng: &mut Pcg) -> bool {
+        let Some(baseline) = self.baseline_ms else {
+            self.baseline_ms = Some(median);
+            self.start_size(rng);
+            return false;
+        };
+
+        match self.lower_ms {
+            // Not enough work to show yet
+            None if median < baseline + self.config.margin_ms
+                && self.steps < self.config.max_steps =>
+            {
+                self.steps *= 2;
+            }
+            None => {
+                self.lower_ms = Some(median);
+                self.steps *= 2;
+            }
+            Some(lower) => {
+                let n = self.config.sizes[self.size];
+                let ms_per_step = (median - lower) / (self.steps / 2) as f32;
+                self.costs.push((n, ms_per_step.max(0.)));
+                self.size += 1;
+                if self.size == self.config.sizes.len() {
+                    self.sim = None;
+                    return true;
+                }
+                self.start_size(rng);
+            }
+        }
+        false
+    }
+
+    fn start_size(&mut self, rng: &mut Pcg) {
+        let n = self.config.sizes[self.size];
+        self.sim = Some(SimState::new(rng, self.sim_config.clone(), n));
+        self.steps = 1;
+        self.lower_ms = None;
+    }
+
+    fn result(&self) -> CalibrationResult {
+        let particles = particles_for_budget(&self.costs, self.config.frame_budget_ms)
+            .clamp(self.config.min_particles, self.config.max_particles);
+        CalibrationResult {
+            particles,
+            costs: self.costs.clone(),
+            frame_budget_ms: self.config.frame_budget_ms,
+        }
+    }
+}
+
+/// Most particles one step of which fits in `budget_ms`, given the cost of a step at some
+/// particle counts. The cost is modelled as `a n + b n^2`: linear work per particle, plus
+/// neighbors that grow with the density in a fixed volume. Fitted through the first and last
+/// measurements.
+pub fn particles_for_budget(costs: &[(usize, f32)], budget_ms: f32) -> usize {
+    let (Some(&(n1, t1)), Some(&(n2, t2))) = (costs.first(), costs.last()) else {
+        return 0;
+    };
+    let (n1, n2) = (n1 as f32, n2 as f32);
+    let b = if n2 > n1 {
+        ((t2 / n2 - t1 / n1) / (n2 - n1)).max(0.)
+    } else {
+        0.
+    };
+    let a = (t1 / n1 - b * n1).max(0.);
+
+    let n = if b > 0. {
+        (-a + (a * a + 4. * b * budget_ms).sqrt()) / (2. * b)
+    } else if a > 0. {
+        budget_ms / a
+    } else {
+        f32::INFINITY
+    };
+    n.min(usize::MAX as f32) as usize
+}
+
+impl CalibrationResult {
+    pub fn report(&self) -> String {
+        let costs: Vec<String> = self
+            .costs
+            .iter()
+            .map(|(n, ms)| format!("{:.3} ms at {}", ms, n))
+            .collect();
+        format!(
+            "Calibrated to {} particles for a {} ms step (measured {})",
+            self.particles,
+            self.frame_budget_ms,
+            costs.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Behaviour;
+
+    #[test]
+    fn test_particles_for_budget() {
+        // 1 us per particle, plus 1 ns per particle per particle
+        let cost = |n: usize| 1e-3 * n as f32 + 1e-6 * (n * n) as f32;
+        let costs = [(250, cost(250)), (1000, cost(1000))];
+        let n = particles_for_budget(&costs, 4.);
+        assert!((cost(n) - 4.).abs() < 0.01, "{} {}", n, cost(n));
+
+        assert_eq!(particles_for_budget(&[(100, 1.), (200, 2.)], 4.), 400);
+        assert_eq!(particles_for_budget(&[], 4.), 0);
+    }
+
+    #[test]
+    fn test_calibration_through_vsync() {
+        let config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 10.,
+            ..Default::default()
+        };
+        let mut calibration = Calibration::new(CalibrationConfig::default(), config);
+
+        // Frames at 90 Hz with 5 ms of slack, and a known cost per step
+        let cost = |n: usize| 2e-3 * n as f32 + 2e-6 * (n * n) as f32;
+        let frame = |work_ms: f32| 11.1 + (work_ms - 5.).max(0.);
+        let mut rng = Pcg::new();
+        let mut frame_ms = None;
+        let mut frames = 0;
+        let result = loop {
+            if let Some(result) = calibration.tick(frame_ms, &mut rng) {
+                break result;
+            }
+            let (n, steps) = calibration.workload();
+            frame_ms = Some(frame(cost(n) * steps as f32));
+            frames += 1;
+            assert!(frames < 500);
+        };
+
+        for &(n, ms) in &result.costs {
+            assert!((ms - cost(n)).abs() < cost(n) * 1e-3, "{:?}", result);
+        }
+        let expected = particles_for_budget(&[(250, cost(250)), (1000, cost(1000))], 4.);
+        assert_eq!(result.particles, expected);
+        assert!(result.report().contains(&expected.to_string()));
+    }
+}
@@ -0,0 +1,572 @@
+//! Droplet test: whether an attractive self-interaction behaves like a liquid with a surface
+//! tension. Single-type droplets of several sizes are sampled one after another with
+//! Metropolis, and the virial pressure inside each is compared with that of the vapor around
+//! it. By the Young-Laplace relation, `ΔP = 2γ / R` for a droplet of radius `R`, so the
+//! slope of `ΔP` against `1 / R` gives the surface tension `γ`.
+use std::f32::consts::PI;
+
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diagnostics::find_escapes,
+    mcmc::{metropolis_step, MetropolisConfig},
+    sim::{Behaviour, ConfigError, Particle, SimConfig, SimState},
+};
+
+/// Anyone to client: run a droplet test over the following frames, printing its table once
+/// done. `None` cancels a run.
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub struct RunDropletTest {
+    pub test: Option<DropletConfig>,
+}
+
+/// Most particles in a single droplet
+const MAX_DROPLET_SIZE: usize = 100_000;
+
+/// Fraction of the droplet radius sampled as its interior
+const INTERIOR_FRACTION: f32 = 0.5;
+
+/// Inner and outer radius of the vapor shell sampled around the droplet, as multiples of its
+/// radius
+const VAPOR_SHELL: (f32, f32) = (1.5, 3.);
+
+/// Spherical shell around `center`, or a ball with an inner radius of zero
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Region {
+    pub center: Vec3,
+    pub inner: f32,
+    pub outer: f32,
+}
+
+/// Least squares line through a set of points
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct LinearFit {
+    pub slope: f32,
+    pub intercept: f32,
+    /// Coefficient of determination, 1 for a perfect fit
+    pub r_squared: f32,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DropletConfig {
+    /// How the particles of the single type interact, which should attract at some range
+    pub behaviour: Behaviour,
+    /// Number of particles in each droplet, run in order
+    pub sizes: Vec<usize>,
+    /// Particles per unit volume of the ball each droplet starts as
+    pub density: f32,
+    pub metropolis: MetropolisConfig,
+    /// Sweeps discarded after spawning each droplet
+    pub equilibration_sweeps: usize,
+    /// Sweeps averaged over for each droplet
+    pub sample_sweeps: usize,
+}
+
+/// Measurement of one droplet, averaged over its sample sweeps
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DropletRow {
+    pub particles: usize,
+    pub radius: f32,
+    /// Pressure inside the droplet minus that of the vapor around it
+    pub delta_p: f32,
+    /// Surface tension of this droplet alone, `ΔP R / 2`
+    pub gamma: f32,
+}
+
+/// Outcome of a droplet test
+#[derive(Clone, Debug, PartialEq)]
+pub struct DropletResult {
+    pub rows: Vec<DropletRow>,
+    /// Fit of `ΔP` against `1 / R`, with at least two droplets of distinct sizes
+    pub fit: Option<LinearFit>,
+}
+
+/// A droplet test in progress, advanced a budget of sweeps at a time so that it can run over
+/// many frames
+pub struct DropletTest {
+    config: DropletConfig,
+    /// Droplet being sampled, unless every size is done
+    sim: Option<SimState>,
+    /// Index of the current size
+    size: usize,
+    /// Sweeps done on the current droplet
+    sweeps: usize,
+    sum_radius: f64,
+    sum_delta_p: f64,
+    rows: Vec<DropletRow>,
+}
+
+impl Region {
+    pub fn ball(center: Vec3, radius: f32) -> Self {
+        Self {
+            center,
+            inner: 0.,
+            outer: radius,
+        }
+    }
+
+    pub fn contains(&self, pos: Vec3) -> bool {
+        let dist_sq = pos.distance_squared(self.center);
+        self.inner * self.inner <= dist_sq && dist_sq < self.outer * self.outer
+    }
+
+    /// Volume, or area when `planar`
+    pub fn volume(&self, planar: bool) -> f32 {
+        match planar {
+            true => PI * (self.outer.powi(2) - self.inner.powi(2)),
+            false => 4. / 3. * PI * (self.outer.powi(3) - self.inner.powi(3)),
+        }
+    }
+}
+
+/// Virial pressure of the particles in `region`, at the given temperature: the ideal gas
+/// term `ρT` plus, for each particle in the region, half of `r_ij · F_ij` over its neighbors,
+/// divided by the dimension and the volume. Forces are those of the explicit integrator,
+/// with each particle of unit mass, between the neighbors as of the last accelerator rebuild.
+pub fn region_pressure(sim: &SimState, temperature: f32, region: &Region) -> f32 {
+    let planar = sim.constrain_2d();
+    let dims = if planar { 2. } else { 3. };
+    let particles = sim.particles();
+
+    let mut count = 0;
+    let mut virial = 0.;
+    for i in (0..particles.len()).filter(|&i| region.contains(particles[i].pos)) {
+        count += 1;
+        for j in sim.neighbors(i) {
+            let r_ij = sim.metric().separation(particles[j].pos, particles[i].pos);
+            virial += 0.5 * r_ij.dot(sim.accel_between(i, j));
+        }
+    }
+
+    let volume = region.volume(planar);
+    if volume <= 0. {
+        return 0.;
+    }
+    (count as f32 * temperature + virial / dims) / volume
+}
+
+/// Least squares line through `points`, unless fewer than two distinct `x` values make it
+/// undetermined
+pub fn linear_fit(points: &[(f32, f32)]) -> Option<LinearFit> {
+    let n = points.len() as f64;
+    let mean = |f: fn(&(f32, f32)) -> f32| points.iter().map(|p| f(p) as f64).sum::<f64>() / n;
+    let (mean_x, mean_y) = (mean(|p| p.0), mean(|p| p.1));
+
+    let (mut sxx, mut sxy, mut syy) = (0., 0., 0.);
+    for &(x, y) in points {
+        let (dx, dy) = (x as f64 - mean_x, y as f64 - mean_y);
+        sxx += dx * dx;
+        sxy += dx * dy;
+        syy += dy * dy;
+    }
+    if points.len() < 2 || sxx <= 0. {
+        return None;
+    }
+
+    let slope = sxy / sxx;
+    let residual = syy - slope * sxy;
+    let r_squared = match syy > 0. {
+        true => 1. - residual / syy,
+        false => 1.,
+    };
+    Some(LinearFit {
+        slope: slope as f32,
+        intercept: (mean_y - slope * mean_x) as f32,
+        r_squared: r_squared as f32,
+    })
+}
+
+impl Default for DropletConfig {
+    fn default() -> Self {
+        Self {
+            behaviour: Behaviour {
+                default_repulse: 10.,
+                inter_threshold: 0.05,
+                inter_strength: 2.,
+                inter_max_dist: 0.1,
+                ..Default::default()
+            },
+            sizes: vec![100, 200, 400, 800],
+            density: 8_000.,
+            metropolis: MetropolisConfig {
+                temperature: 2e-2,
+                walk_sigma: 5e-3,
+            },
+            equilibration_sweeps: 300,
+            sample_sweeps: 300,
+        }
+    }
+}
+
+impl DropletConfig {
+    /// Check that the behaviour is that of a valid configuration, and that the sizes,
+    /// density and sampling are usable
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        droplet_sim_config(self.behaviour).validate()?;
+        if self.sizes.iter().any(|&n| n == 0 || n > MAX_DROPLET_SIZE) {
+            return Err(ConfigError::Invalid("Droplet size out of range"));
+        }
+        if !(self.density.is_finite() && self.density > 0.) {
+            return Err(ConfigError::Invalid("Density must be finite and positive"));
+        }
+        let MetropolisConfig {
+            temperature,
+            walk_sigma,
+        } = self.metropolis;
+        if ![temperature, walk_sigma]
+            .iter()
+            .all(|v| v.is_finite() && *v >= 0.)
+        {
+            return Err(ConfigError::Invalid(
+                "Temperature and moves must be finite and positive",
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl DropletTest {
+    pub fn new(config: DropletConfig, rng: &mut Pcg) -> Self {
+        let sim = config
+            .sizes
+            .first()
+            .map(|&n| spawn_droplet(&config, n, rng));
+        Self {
+            config,
+            sim,
+            size: 0,
+            sweeps: 0,
+            sum_radius: 0.,
+            sum_delta_p: 0.,
+            rows: vec![],
+        }
+    }
+
+    /// Run up to `max_sweeps` more sweeps. Returns the result once every size is done.
+    pub fn advance(&mut self, max_sweeps: usize, rng: &mut Pcg) -> Option<DropletResult> {
+        let per_size = self.config.equilibration_sweeps + self.config.sample_sweeps;
+        for _ in 0..max_sweeps {
+            let Some(sim) = &mut self.sim else {
+                break;
+            };
+
+            metropolis_step(sim, &self.config.metropolis, rng);
+            self.sweeps += 1;
+            if self.sweeps > self.config.equilibration_sweeps {
+                sim.rebuild_accel();
+                let (radius, delta_p) = measure_droplet(sim, self.config.metropolis.temperature);
+                self.sum_radius += radius as f64;
+                self.sum_delta_p += delta_p as f64;
+            }
+
+            if self.sweeps >= per_size {
+                let samples = self.config.sample_sweeps.max(1) as f64;
+                let radius = (self.sum_radius / samples) as f32;
+                let delta_p = (self.sum_delta_p / samples) as f32;
+                self.rows.push(DropletRow {
+                    particles: self.config.sizes[self.size],
+                    radius,
+                    delta_p,
+                    gamma: delta_p * radius / 2.,
+                });
+
+                self.size += 1;
+                self.sweeps = 0;
+                self.sum_radius = 0.;
+                self.sum_delta_p = 0.;
+                self.sim = (self.config.sizes.get(self.size))
+                    .map(|&n| spawn_droplet(&self.config, n, rng));
+            }
+        }
+        self.result()
+    }
+
+    /// Fraction of the sweeps done, from 0 to 1
+    pub fn progress(&self) -> f32 {
+        let per_size = self.config.equilibration_sweeps + self.config.sample_sweeps;
+        let total = per_size * self.config.sizes.len();
+        match total {
+            0 => 1.,
+            total => (self.size * per_size + self.sweeps) as f32 / total as f32,
+        }
+    }
+
+    /// The result, once every size is done
+    pub fn result(&self) -> Option<DropletResult> {
+        self.sim.is_none().then(|| {
+            let points: Vec<(f32, f32)> = (self.rows.iter())
+                .map(|row| (row.radius.recip(), row.delta_p))
+                .collect();
+            DropletResult {
+                rows: self.rows.clone(),
+                fit: linear_fit(&points),
+            }
+        })
+    }
+
+    /// The droplet being sampled, unless every size is done
+    pub fn sim(&self) -> Option<&SimState> {
+        self.sim.as_ref()
+    }
+}
+
+impl DropletResult {
+    /// Surface tension from the slope of the fit, `2γ`
+    pub fn gamma(&self) -> Option<f32> {
+        self.fit.map(|fit| fit.slope / 2.)
+    }
+
+    /// Human readable table of the droplets, followed by the fit
+    pub fn table(&self) -> String {
+        let mut table = format!("{:>10} {:>10} {:>12} {:>12}\n", "particles", "R", "ΔP", "γ");
+        for row in &self.rows {
+            table += &format!(
+                "{:>10} {:>10.4} {:>12.4} {:>12.4}\n",
+                row.particles, row.radius, row.delta_p, row.gamma
+            );
+        }
+        table += &match self.fit {
+            Some(fit) => format!(
+                "Fitted γ = {:.4} (ΔP = {:.4} / R + {:.4}, R² = {:.3})\n",
+                fit.slope / 2.,
+                fit.slope,
+                fit.intercept,
+                fit.r_squared
+            ),
+            None => "Too few droplet sizes to fit\n".into(),
+        };
+        table
+    }
+
+    pub fn to_csv(&self) -> String {
+        let mut csv = "particles,radius,delta_p,gamma\n".to_string();
+        for row in &self.rows {
+            csv += &format!(
+                "{},{},{},{}\n",
+                row.particles, row.radius, row.delta_p, row.gamma
+            );
+        }
+        csv
+    }
+}
+
+/// A ball of `n` particles of a single type at the configured density, at rest
+fn spawn_droplet(config: &DropletConfig, n: usize, rng: &mut Pcg) -> SimState {
+    let radius = (n as f32 / config.density.max(f32::EPSILON) * 3. / (4. * PI)).cbrt();
+    let particles = (0..n)
+        .map(|_| {
+            // Uniform over the ball, by rejection from the cube around it
+            let pos = loop {
+                let p = Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * 2. - 1.;
+                if p.length_squared() <= 1. {
+                    break p * radius;
+                }
+            };
+            Particle {
+                pos,
+                vel: Vec3::ZERO,
+                color: 0,
+            }
+        })
+        .collect();
+    SimState::from_particles(droplet_sim_config(config.behaviour), particles)
+}
+
+/// Configuration of a single type with the given behaviour, undamped and without gravity
+fn droplet_sim_config(behaviour: Behaviour) -> SimConfig {
+    SimConfig {
+        colors: vec![[1.; 3]],
+        behaviours: vec![behaviour],
+        interaction_scale: 1.,
+        damping: 0.,
+        gravity: None,
+        density_rules: vec![],
+        mobility: None,
+        activity: None,
+    }
+}
+
+/// Radius of the droplet, and the pressure inside it minus that of the vapor around it. The
+/// radius is that of a uniform ball with the same median distance from the center.
+fn measure_droplet(sim: &SimState, temperature: f32) -> (f32, f32) {
+    let cloud = find_escapes(sim, f32::INFINITY);
+    let radius = cloud.median_radius / 0.5f32.cbrt();
+    let interior = Region::ball(cloud.center, radius * INTERIOR_FRACTION);
+    let vapor = Region {
+        center: cloud.center,
+        inner: radius * VAPOR_SHELL.0,
+        outer: radius * VAPOR_SHELL.1,
+    };
+    let delta_p =
+        region_pressure(sim, temperature, &interior) - region_pressure(sim, temperature, &vapor);
+    (radius, delta_p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sim(behaviour: Behaviour, positions: impl Iterator<Item = Vec3>) -> SimState {
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![behaviour],
+            damping: 0.,
+            ..Default::default()
+        };
+        let particles = positions
+            .map(|pos| Particle {
+                pos,
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        SimState::from_particles(config, particles)
+    }
+
+    #[test]
+    fn test_ideal_gas_pressure() {
+        let inert = Behaviour {
+            default_repulse: 0.,
+            inter_strength: 0.,
+            ..Default::default()
+        };
+        let mut rng = Pcg::new();
+        let positions: Vec<Vec3> = (0..4000)
+            .map(|_| Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * 2. - 1.)
+            .collect();
+        let sim = sim(inert, positions.into_iter());
+
+        for region in [
+            Region::ball(Vec3::ZERO, 0.5),
+            Region {
+                center: Vec3::splat(0.1),
+                inner: 0.3,
+                outer: 0.8,
+            },
+        ] {
+            let count = (sim.particles().iter())
+                .filter(|p| region.contains(p.pos))
+                .count();
+            let expected = count as f32 * 0.3 / region.volume(false);
+            let pressure = region_pressure(&sim, 0.3, &region);
+            assert!(
+                (pressure - expected).abs() <= expected * 1e-5,
+                "{}",
+                pressure
+            );
+            // Uniform at 500 per unit volume
+            assert!((pressure / 0.3 - 500.).abs() < 50., "{}", pressure);
+        }
+    }
+
+    #[test]
+    fn test_lattice_pressure() {
+        // Nearest neighbors of a simple cubic lattice repel, and nothing further reaches
+        let spacing = 0.05;
+        let behaviour = Behaviour {
+            default_repulse: 10.,
+            inter_threshold: 0.06,
+            inter_strength: 0.,
+            inter_max_dist: 0.06,
+            ..Default::default()
+        };
+        let n = 12;
+        let positions = (0..n * n * n).map(|i| {
+            let cell = Vec3::new((i % n) as f32, (i / n % n) as f32, (i / n / n) as f32);
+            cell * spacing
+        });
+        let sim = sim(behaviour, positions);
+
+        // Away from the edges of the lattice, so that every particle has six neighbors
+        let center = Vec3::splat(spacing * (n as f32 - 1.) / 2.);
+        let region = Region::ball(center, spacing * 3.);
+        let count = (sim.particles().iter())
+            .filter(|p| region.contains(p.pos))
+            .count();
+        assert!(count > 50, "{}", count);
+
+        // The force is the kernel over the distance, so each of the six neighbors contributes
+        // r·F equal to the kernel's repulsion at the spacing. Half of each pair's virial belongs
+        // to each side, and dividing by three dimensions leaves the repulsion itself.
+        let repulsion = -behaviour.interact(spacing);
+        let temperature = 0.1;
+        let expected = count as f32 * (temperature + repulsion) / region.volume(false);
+        let pressure = region_pressure(&sim, temperature, &region);
+        assert!(
+            (pressure - expected).abs() <= expected * 1e-4,
+            "{} {}",
+            pressure,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_linear_fit() {
+        let exact: Vec<(f32, f32)> = (0..10).map(|i| (i as f32, 3. * i as f32 - 2.)).collect();
+        let fit = linear_fit(&exact).unwrap();
+        assert!((fit.slope - 3.).abs() < 1e-5);
+        assert!((fit.intercept + 2.).abs() < 1e-4);
+        assert!((fit.r_squared - 1.).abs() < 1e-6);
+
+        // Alternating noise lowers the fit quality but leaves the slope close
+        let noisy: Vec<(f32, f32)> = (exact.iter().enumerate())
+            .map(|(i, &(x, y))| (x, y + if i % 2 == 0 { 1. } else { -1. }))
+            .collect();
+        let fit = linear_fit(&noisy).unwrap();
+        assert!((fit.slope - 3.).abs() < 0.2, "{}", fit.slope);
+        assert!(
+            fit.r_squared < 1. && fit.r_squared > 0.95,
+            "{}",
+            fit.r_squared
+        );
+
+        assert_eq!(linear_fit(&[(1., 2.)]), None);
+        assert_eq!(linear_fit(&[(1., 2.), (1., 3.)]), None);
+        assert_eq!(linear_fit(&[(0., 1.), (2., 1.)]).unwrap().r_squared, 1.);
+    }
+
+    #[test]
+    fn test_droplet_orchestration() {
+        let mut rng = Pcg::new();
+        let config = DropletConfig {
+            sizes: vec![40, 80],
+            equilibration_sweeps: 5,
+            sample_sweeps: 5,
+            ..Default::default()
+        };
+        assert_eq!(config.validate(), Ok(()));
+        let mut test = DropletTest::new(config.clone(), &mut rng);
+        assert_eq!(test.sim().unwrap().particles().len(), 40);
+        for invalid in [
+            DropletConfig {
+                sizes: vec![40, 0],
+                ..config.clone()
+            },
+            DropletConfig {
+                density: f32::NAN,
+                ..config
+            },
+        ] {
+            assert!(invalid.validate().is_err());
+        }
+
+        // Over several frames, the second droplet after the first
+        assert_eq!(test.advance(7, &mut rng), None);
+        assert!((test.progress() - 0.35).abs() < 1e-6);
+        assert_eq!(test.advance(7, &mut rng), None);
+        assert_eq!(test.sim().unwrap().particles().len(), 80);
+        let result = test.advance(100, &mut rng).unwrap();
+        assert_eq!(test.progress(), 1.);
+
+        let sizes: Vec<usize> = result.rows.iter().map(|row| row.particles).collect();
+        assert_eq!(sizes, vec![40, 80]);
+        assert!(result.rows[1].radius > result.rows[0].radius);
+        assert!(result.rows.iter().all(|row| row.delta_p.is_finite()));
+        assert!(result.gamma().is_some());
+        assert_eq!(result.to_csv().lines().count(), 3);
+        assert_eq!(result.table().lines().count(), 4);
+    }
+}
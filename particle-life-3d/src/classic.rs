@@ -0,0 +1,176 @@
+//! Import of rules exported from the classic particle life web app, which people share as
+//! JSON: a list of named colors, a flat row-major matrix of attractions from -1 to 1 where
+//! row `i`, column `j` is how strongly color `i` is drawn to color `j`, and one pair of
+//! distances `rmin`/`rmax` for every pair of colors, as fractions of the world's width.
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::sim::{Behaviour, ConfigError, SimConfig};
+
+/// Anyone to client: switch to rules exported by the classic web app, keeping the particles
+/// where they are
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub struct ImportClassic {
+    pub json: String,
+}
+
+/// Factor from the app's attractions to [`Behaviour::inter_strength`], mapping its full
+/// range onto the range of randomized strengths
+pub const CLASSIC_STRENGTH_SCALE: f32 = 15.;
+
+/// Repulsion inside `rmin`, which the app hardcodes rather than exporting
+pub const CLASSIC_REPULSE: f32 = 15.;
+
+/// Damping of imported rules; the app's friction is per frame and does not carry over
+pub const CLASSIC_DAMPING: f32 = 150.;
+
+/// The fields we use of an export. Anything else is ignored.
+#[derive(Deserialize)]
+struct ClassicRules {
+    colors: Vec<String>,
+    #[serde(alias = "attractions")]
+    matrix: Vec<f32>,
+    rmin: f32,
+    rmax: f32,
+}
+
+impl SimConfig {
+    /// Configuration from rules exported by the classic web app, see the module docs
+    pub fn from_classic_json(text: &str) -> Result<SimConfig, ConfigError> {
+        let rules: ClassicRules =
+            serde_json::from_str(text).map_err(|e| ConfigError::Syntax(e.to_string()))?;
+
+        let n = rules.colors.len();
+        if rules.matrix.len() != n * n {
+            return Err(ConfigError::Syntax(format!(
+                "Expected {} attractions for {} colors, got {}",
+                n * n,
+                n,
+                rules.matrix.len()
+            )));
+        }
+        if !(0. ..rules.rmax).contains(&rules.rmin) {
+            return Err(ConfigError::Invalid(
+                "rmin must be at least 0 and below rmax",
+            ));
+        }
+
+        let colors = rules
+            .colors
+            .iter()
+            .map(|name| {
+                named_color(name)
+                    .ok_or_else(|| ConfigError::Syntax(format!("Unknown color {:?}", name)))
+            })
+            .collect::<Result<_, _>>()?;
+
+        let behaviours = rules
+            .matrix
+            .iter()
+            .map(|&attraction| Behaviour {
+                default_repulse: CLASSIC_REPULSE,
+                inter_threshold: rules.rmin,
+                inter_strength: attraction * CLASSIC_STRENGTH_SCALE,
+                inter_max_dist: rules.rmax,
+                ..Default::default()
+            })
+            .collect();
+
+        let config = SimConfig {
+            colors,
+            behaviours,
+            interaction_scale: 1.,
+            damping: CLASSIC_DAMPING,
+            gravity: None,
+            density_rules: vec![],
+            mobility: None,
+            activity: None,
+        };
+        config.validate()?;
+        Ok(config)
+    }
+}
+
+/// RGB of a color as the app names it, or as `#rrggbb`
+fn named_color(name: &str) -> Option<[f32; 3]> {
+    let name = name.trim().to_ascii_lowercase();
+    if let Some(hex) = name.strip_prefix('#') {
+        let channel = |i: usize| {
+            let byte = u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()?;
+            Some(byte as f32 / 255.)
+        };
+        if hex.len() != 6 {
+            return None;
+        }
+        return Some([channel(0)?, channel(2)?, channel(4)?]);
+    }
+
+    let rgb = match name.as_str() {
+        "red" => [1., 0., 0.],
+        "green" => [0., 1., 0.],
+        "blue" => [0., 0., 1.],
+        "yellow" => [1., 1., 0.],
+        "cyan" => [0., 1., 1.],
+        "magenta" => [1., 0., 1.],
+        "orange" => [1., 0.5, 0.],
+        "purple" => [0.5, 0., 1.],
+        "pink" => [1., 0.4, 0.7],
+        "white" => [1., 1., 1.],
+        "gray" | "grey" => [0.5, 0.5, 0.5],
+        _ => return None,
+    };
+    Some(rgb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE: &str = include_str!("classic_rules.json");
+
+    #[test]
+    fn test_sample_export() {
+        let config = SimConfig::from_classic_json(SAMPLE).unwrap();
+        assert_eq!(config.colors.len(), 4);
+        assert_eq!(config.behaviours.len(), 16);
+        assert_eq!(config.colors[0], [1., 0., 0.]);
+        assert_eq!(config.colors[3], [1., 1., 0.]);
+
+        // Row 1 is green, column 2 blue
+        let green_to_blue = config.behaviours[4 + 2];
+        assert_eq!(green_to_blue.inter_strength, -CLASSIC_STRENGTH_SCALE);
+        assert_eq!(
+            config.behaviours[3 * 4].inter_strength,
+            CLASSIC_STRENGTH_SCALE
+        );
+        assert_eq!(green_to_blue.inter_threshold, 0.03);
+        assert_eq!(green_to_blue.inter_max_dist, 0.12);
+        assert_eq!(config.max_interaction_radius(), 0.12);
+    }
+
+    #[test]
+    fn test_malformed_exports() {
+        let err = |text: &str| SimConfig::from_classic_json(text).unwrap_err().to_string();
+
+        assert!(err("{\"colors\": [\"red\"]").contains("EOF"));
+        assert!(err(r#"{"colors": ["red"], "rmin": 0.1, "rmax": 0.2}"#).contains("matrix"));
+        assert!(err(
+            r#"{"colors": ["red", "blue"], "matrix": [1, 0, 0], "rmin": 0.1, "rmax": 0.2}"#
+        )
+        .contains("Expected 4 attractions for 2 colors, got 3"));
+        assert!(
+            err(r#"{"colors": ["chartreuse"], "matrix": [1], "rmin": 0.1, "rmax": 0.2}"#)
+                .contains("chartreuse")
+        );
+        assert!(
+            err(r#"{"colors": ["red"], "matrix": [1], "rmin": 0.3, "rmax": 0.2}"#).contains("rmin")
+        );
+
+        let config = SimConfig::from_classic_json(
+            r##"{"colors": ["#FF8000"], "attractions": [0.5], "rmin": 0, "rmax": 0.2}"##,
+        )
+        .unwrap();
+        assert_eq!(config.colors[0], [1., 128. / 255., 0.]);
+    }
+}
@@ -0,0 +1,1638 @@
+use std::collections::VecDeque;
+
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    persist::SimSnapshot,
+    query_accel::QueryAccelerator,
+    sim::{OrbitalPreset, SimState},
+};
+
+/// A group of particles connected by chains of neighbors
+#[derive(Clone, Copy, Debug)]
+pub struct Cluster {
+    pub center: Vec3,
+    pub size: usize,
+}
+
+/// Result of a cluster analysis
+#[derive(Clone, Debug, Default)]
+pub struct Clusters {
+    /// Index into `clusters` for each particle
+    pub labels: Vec<usize>,
+    pub clusters: Vec<Cluster>,
+}
+
+/// What counts as a cluster, for the analyses built on [`find_clusters`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClusterConfig {
+    /// Connection radius used for cluster detection
+    pub radius: f32,
+    /// Smallest group of particles considered a cluster
+    pub min_size: usize,
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            radius: 0.05,
+            min_size: 20,
+        }
+    }
+}
+
+/// Group particles into clusters; two particles are connected when within `radius` of each other
+pub fn find_clusters(sim: &SimState, radius: f32) -> Clusters {
+    let points: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+    let accel = QueryAccelerator::with_metric(&points, radius, sim.metric());
+
+    let mut set = DisjointSet::new(points.len());
+    for i in 0..points.len() {
+        for neighbor in accel.query_neighbors(&points, i) {
+            set.union(i, neighbor);
+        }
+    }
+
+    // Compact the roots into cluster indices
+    let mut root_to_label = vec![usize::MAX; points.len()];
+    let mut labels = Vec::with_capacity(points.len());
+    let mut clusters: Vec<Cluster> = vec![];
+    for (i, &pos) in points.iter().enumerate() {
+        let root = set.find(i);
+        if root_to_label[root] == usize::MAX {
+            root_to_label[root] = clusters.len();
+            clusters.push(Cluster {
+                center: Vec3::ZERO,
+                size: 0,
+            });
+        }
+
+        let label = root_to_label[root];
+        clusters[label].center += pos;
+        clusters[label].size += 1;
+        labels.push(label);
+    }
+
+    for cluster in &mut clusters {
+        cluster.center /= cluster.size as f32;
+    }
+
+    Clusters { labels, clusters }
+}
+
+/// Undirected edges between particles within `radius` of each other, each listed once as
+/// `(i, j)` with `i < j`. Pass the config's max interaction radius for the interaction network.
+pub fn neighbor_graph(sim: &SimState, radius: f32) -> Vec<(u32, u32)> {
+    let points: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+    let accel = QueryAccelerator::with_metric(&points, radius, sim.metric());
+
+    let mut edges = vec![];
+    for i in 0..points.len() {
+        let mut neighbors: Vec<usize> = accel
+            .query_neighbors(&points, i)
+            .filter(|&j| j > i)
+            .collect();
+        neighbors.sort_unstable();
+        neighbors.dedup();
+        edges.extend(neighbors.into_iter().map(|j| (i as u32, j as u32)));
+    }
+    edges
+}
+
+/// Edge list as CSV with a header row
+pub fn write_edge_list_csv(edges: &[(u32, u32)]) -> String {
+    let mut csv = String::from("source,target\n");
+    for (i, j) in edges {
+        csv += &format!("{},{}\n", i, j);
+    }
+    csv
+}
+
+/// Number of particles with each degree, indexed by degree, for a graph of `n` particles
+pub fn degree_histogram(edges: &[(u32, u32)], n: usize) -> Vec<usize> {
+    let mut degrees = vec![0; n];
+    for &(i, j) in edges {
+        degrees[i as usize] += 1;
+        degrees[j as usize] += 1;
+    }
+
+    let mut histogram = vec![0; degrees.iter().max().map_or(0, |d| d + 1)];
+    for degree in degrees {
+        histogram[degree] += 1;
+    }
+    histogram
+}
+
+/// Permanent link between two particles, by index. The simulation has no bonded interactions
+/// of its own, so bonds are whatever the caller says holds the polymers together.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Bond {
+    pub a: u32,
+    pub b: u32,
+}
+
+/// Unbranched open chain of bonded particles
+#[derive(Clone, Debug, PartialEq)]
+pub struct Chain {
+    /// Particles from one end of the chain to the other
+    pub monomers: Vec<usize>,
+    pub end_to_end: f32,
+    pub radius_of_gyration: f32,
+    /// Estimated from the mean cosine of the angles between consecutive bonds, see
+    /// [`persistence_length`]. `None` for chains of fewer than three monomers, which have no
+    /// angles.
+    pub persistence_length: Option<f32>,
+}
+
+/// Bonded cluster which is not an open chain: a ring, or branched
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BondedCluster {
+    pub size: usize,
+    pub radius_of_gyration: f32,
+}
+
+/// Result of a polymer analysis. Particles without bonds are in none of the lists.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PolymerStats {
+    pub chains: Vec<Chain>,
+    /// Closed loops, in which every particle has two bonds; they have no ends, so are left
+    /// out of the end-to-end statistics
+    pub rings: Vec<BondedCluster>,
+    /// Clusters in which some particle has more than two bonds
+    pub branched: Vec<BondedCluster>,
+    /// Persistence length estimated from the bond angles of every chain together
+    pub persistence_length: Option<f32>,
+}
+
+/// Group the particles joined by `bonds` into chains, rings and branched clusters, and measure
+/// them. Duplicate bonds and bonds of a particle to itself are ignored. Costs a pass over the
+/// particles, so is meant to be run on demand rather than every frame.
+pub fn polymer_stats(sim: &SimState, bonds: &[Bond]) -> PolymerStats {
+    let points: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+
+    let mut edges: Vec<(usize, usize)> = (bonds.iter())
+        .map(|bond| (bond.a.min(bond.b) as usize, bond.a.max(bond.b) as usize))
+        .filter(|(a, b)| a != b)
+        .collect();
+    edges.sort_unstable();
+    edges.dedup();
+    assert!(
+        edges.last().is_none_or(|&(_, b)| b < points.len()),
+        "Bond to a particle out of range"
+    );
+
+    let mut bonded: Vec<Vec<usize>> = vec![vec![]; points.len()];
+    let mut set = DisjointSet::new(points.len());
+    for &(a, b) in &edges {
+        bonded[a].push(b);
+        bonded[b].push(a);
+        set.union(a, b);
+    }
+
+    // Members of each cluster, in the order of their lowest index
+    let mut root_to_label = vec![usize::MAX; points.len()];
+    let mut members: Vec<Vec<usize>> = vec![];
+    for i in (0..points.len()).filter(|&i| !bonded[i].is_empty()) {
+        let root = set.find(i);
+        if root_to_label[root] == usize::MAX {
+            root_to_label[root] = members.len();
+            members.push(vec![]);
+        }
+        members[root_to_label[root]].push(i);
+    }
+
+    let mut stats = PolymerStats::default();
+    let mut cosines = vec![];
+    for cluster in members {
+        let radius_of_gyration = radius_of_gyration(cluster.iter().map(|&i| points[i]));
+        let other = BondedCluster {
+            size: cluster.len(),
+            radius_of_gyration,
+        };
+        let end = cluster.iter().find(|&&i| bonded[i].len() == 1);
+        if cluster.iter().any(|&i| bonded[i].len() > 2) {
+            stats.branched.push(other);
+            continue;
+        }
+        let Some(&end) = end else {
+            stats.rings.push(other);
+            continue;
+        };
+
+        // Walk from one end to the other
+        let mut monomers = vec![end];
+        let mut previous = usize::MAX;
+        let mut current = end;
+        while let Some(&next) = bonded[current].iter().find(|&&j| j != previous) {
+            monomers.push(next);
+            (previous, current) = (current, next);
+        }
+
+        let chain_cosines: Vec<f32> = (monomers.windows(3))
+            .map(|m| {
+                let u = points[m[1]] - points[m[0]];
+                let v = points[m[2]] - points[m[1]];
+                u.normalize_or_zero().dot(v.normalize_or_zero())
+            })
+            .collect();
+        let bond_length = mean_bond_length(&points, &monomers);
+        cosines.extend(chain_cosines.iter().map(|&c| (c, bond_length)));
+        stats.chains.push(Chain {
+            end_to_end: points[monomers[0]].distance(points[*monomers.last().unwrap()]),
+            radius_of_gyration,
+            persistence_length: persistence_length(&chain_cosines, bond_length),
+            monomers,
+        });
+    }
+
+    let (all_cosines, lengths): (Vec<f32>, Vec<f32>) = cosines.into_iter().unzip();
+    let mean_length = lengths.iter().sum::<f32>() / lengths.len().max(1) as f32;
+    stats.persistence_length = persistence_length(&all_cosines, mean_length);
+    stats
+}
+
+/// Persistence length of a worm-like chain whose consecutive bonds, `bond_length` long, turn
+/// by angles of the given cosines: `-b / ln <cos θ>`. Infinite for a straight chain, and zero
+/// once the mean cosine is no longer positive, when the direction is forgotten within a bond.
+pub fn persistence_length(cosines: &[f32], bond_length: f32) -> Option<f32> {
+    if cosines.is_empty() {
+        return None;
+    }
+    let mean = cosines.iter().sum::<f32>() / cosines.len() as f32;
+    Some(match mean {
+        m if m >= 1. => f32::INFINITY,
+        m if m <= 0. => 0.,
+        m => -bond_length / m.ln(),
+    })
+}
+
+/// Root mean square distance of the points from their centroid
+fn radius_of_gyration(points: impl Iterator<Item = Vec3> + Clone) -> f32 {
+    let n = points.clone().count().max(1) as f32;
+    let center = points.clone().sum::<Vec3>() / n;
+    (points.map(|p| p.distance_squared(center)).sum::<f32>() / n).sqrt()
+}
+
+/// Mean distance between consecutive monomers
+fn mean_bond_length(points: &[Vec3], monomers: &[usize]) -> f32 {
+    let total: f32 = (monomers.windows(2))
+        .map(|m| points[m[0]].distance(points[m[1]]))
+        .sum();
+    total / (monomers.len() - 1) as f32
+}
+
+impl PolymerStats {
+    /// Mean number of monomers of the open chains
+    pub fn mean_chain_length(&self) -> Option<f32> {
+        let total: usize = self.chains.iter().map(|c| c.monomers.len()).sum();
+        (!self.chains.is_empty()).then(|| total as f32 / self.chains.len() as f32)
+    }
+
+    /// Mean end-to-end distance of the open chains
+    pub fn mean_end_to_end(&self) -> Option<f32> {
+        let total: f32 = self.chains.iter().map(|c| c.end_to_end).sum();
+        (!self.chains.is_empty()).then(|| total / self.chains.len() as f32)
+    }
+
+    /// Number of open chains of each length, indexed by their number of monomers
+    pub fn chain_length_histogram(&self) -> Vec<usize> {
+        let longest = self.chains.iter().map(|c| c.monomers.len()).max();
+        let mut histogram = vec![0; longest.map_or(0, |n| n + 1)];
+        for chain in &self.chains {
+            histogram[chain.monomers.len()] += 1;
+        }
+        histogram
+    }
+
+    /// Number of open chains with end-to-end distances in each of `bins` equal bins from zero
+    /// to the longest
+    pub fn end_to_end_histogram(&self, bins: usize) -> Vec<usize> {
+        let mut histogram = vec![0; bins];
+        let longest = self.chains.iter().map(|c| c.end_to_end).fold(0., f32::max);
+        for chain in &self.chains {
+            let bin = match longest > 0. {
+                true => (chain.end_to_end / longest * bins as f32) as usize,
+                false => 0,
+            };
+            if let Some(count) = histogram.get_mut(bin.min(bins.saturating_sub(1))) {
+                *count += 1;
+            }
+        }
+        histogram
+    }
+
+    /// One row per chain, ring and branched cluster as CSV with a header row. Columns which do
+    /// not apply are left empty.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("kind,size,end_to_end,radius_of_gyration,persistence_length\n");
+        for chain in &self.chains {
+            let persistence = chain
+                .persistence_length
+                .map_or(String::new(), |l| l.to_string());
+            csv += &format!(
+                "chain,{},{},{},{}\n",
+                chain.monomers.len(),
+                chain.end_to_end,
+                chain.radius_of_gyration,
+                persistence
+            );
+        }
+        let others = (self.rings.iter().map(|c| ("ring", c)))
+            .chain(self.branched.iter().map(|c| ("branched", c)));
+        for (kind, cluster) in others {
+            csv += &format!(
+                "{},{},,{},\n",
+                kind, cluster.size, cluster.radius_of_gyration
+            );
+        }
+        csv
+    }
+}
+
+/// Number of particles of each type over time, kept in a fixed-capacity ring buffer
+pub struct PopulationHistory {
+    capacity: usize,
+    /// Number of frames between samples
+    interval: usize,
+    until_sample: usize,
+    frame: usize,
+    /// Frame number and per-type counts of each sample. The number of types may differ between
+    /// samples if the configuration changed.
+    samples: VecDeque<(usize, Vec<usize>)>,
+}
+
+impl PopulationHistory {
+    /// Keep up to `capacity` samples, taken every `interval` frames
+    pub fn new(capacity: usize, interval: usize) -> Self {
+        Self {
+            capacity: capacity.max(1),
+            interval: interval.max(1),
+            until_sample: 0,
+            frame: 0,
+            samples: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    /// Call once per frame
+    pub fn record(&mut self, sim: &SimState) {
+        if self.until_sample == 0 {
+            if self.samples.len() == self.capacity {
+                self.samples.pop_front();
+            }
+            self.samples.push_back((self.frame, population(sim)));
+            self.until_sample = self.interval;
+        }
+        self.until_sample -= 1;
+        self.frame += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.until_sample = 0;
+    }
+
+    /// Samples from oldest to newest, as frame number and count per type
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &[usize])> {
+        self.samples
+            .iter()
+            .map(|(frame, counts)| (*frame, counts.as_slice()))
+    }
+
+    /// Most recent counts per type
+    pub fn current(&self) -> Option<&[usize]> {
+        self.samples.back().map(|(_, counts)| counts.as_slice())
+    }
+
+    /// The history as CSV with a header row, one column per type. Types which did not exist at
+    /// the time of a sample are left empty.
+    pub fn to_csv(&self) -> String {
+        let n_types = self.samples.iter().map(|(_, c)| c.len()).max().unwrap_or(0);
+
+        let mut csv = String::from("frame");
+        for i in 0..n_types {
+            csv += &format!(",type {}", i);
+        }
+        csv.push('\n');
+
+        for (frame, counts) in self.iter() {
+            csv += &frame.to_string();
+            for i in 0..n_types {
+                csv.push(',');
+                if let Some(count) = counts.get(i) {
+                    csv += &count.to_string();
+                }
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// Cheap summary of a simulation state, for noticing sudden changes
+#[derive(Clone, Debug, PartialEq)]
+pub struct Features {
+    /// Number of clusters of at least the minimum size
+    pub clusters: usize,
+    /// Size of the bounding box of each type along each axis, zero for absent types
+    pub extents: Vec<Vec3>,
+    pub mean_speed: f32,
+}
+
+/// Settings for [`Highlights`]
+#[derive(Clone, Debug)]
+pub struct HighlightConfig {
+    /// Frames between feature samples
+    pub interval_frames: usize,
+    /// Smallest novelty worth keeping, see [`novelty`]
+    pub threshold: f32,
+    /// Largest number of moments kept
+    pub capacity: usize,
+    pub cluster: ClusterConfig,
+}
+
+/// A state captured because it differed sharply from the one before
+#[derive(Clone, Debug)]
+pub struct Moment {
+    /// Frame number, counted from the start of the detector
+    pub frame: usize,
+    pub score: f32,
+    pub snapshot: SimSnapshot,
+}
+
+/// Automatically captured interesting moments, for browsing after an unattended run. Keeps
+/// the highest-scoring moments when there are too many.
+pub struct Highlights {
+    pub config: HighlightConfig,
+    frame: usize,
+    until_sample: usize,
+    last: Option<Features>,
+    /// Captured moments, oldest first
+    moments: Vec<Moment>,
+}
+
+/// Summarize the simulation for [`novelty`]
+pub fn features(sim: &SimState, cluster: ClusterConfig) -> Features {
+    let clusters = find_clusters(sim, cluster.radius)
+        .clusters
+        .iter()
+        .filter(|c| c.size >= cluster.min_size)
+        .count();
+
+    let n_types = sim.config().colors.len();
+    let mut min = vec![Vec3::splat(f32::INFINITY); n_types];
+    let mut max = vec![Vec3::splat(f32::NEG_INFINITY); n_types];
+    let mut speed = 0.;
+    for particle in sim.particles() {
+        let color = particle.color as usize;
+        min[color] = min[color].min(particle.pos);
+        max[color] = max[color].max(particle.pos);
+        speed += particle.vel.length();
+    }
+    let extents = min
+        .iter()
+        .zip(&max)
+        .map(|(&min, &max)| (max - min).max(Vec3::ZERO))
+        .collect();
+
+    Features {
+        clusters,
+        extents,
+        mean_speed: speed / sim.particles().len().max(1) as f32,
+    }
+}
+
+/// How different `current` is from `previous`: the sum of the relative changes in cluster
+/// count, mean speed and the mean per-type bounding box diagonal, each between 0 and 1. Steady
+/// states score near zero; a merger of clusters scores above one.
+pub fn novelty(previous: &Features, current: &Features) -> f32 {
+    let relative = |a: f32, b: f32| {
+        let scale = a.abs().max(b.abs());
+        if scale > f32::EPSILON {
+            (a - b).abs() / scale
+        } else {
+            0.
+        }
+    };
+
+    let pairs = previous.extents.iter().zip(&current.extents);
+    let n_types = pairs.len().max(1) as f32;
+    let extents: f32 = pairs
+        .map(|(a, b)| relative(a.length(), b.length()))
+        .sum::<f32>()
+        / n_types;
+
+    relative(previous.clusters as f32, current.clusters as f32)
+        + relative(previous.mean_speed, current.mean_speed)
+        + extents
+}
+
+impl Highlights {
+    pub fn new(config: HighlightConfig) -> Self {
+        Self {
+            config,
+            frame: 0,
+            until_sample: 0,
+            last: None,
+            moments: vec![],
+        }
+    }
+
+    /// Call once per frame. Returns the score when a moment was captured.
+    pub fn record(&mut self, sim: &SimState) -> Option<f32> {
+        let frame = self.frame;
+        self.frame += 1;
+        if self.until_sample > 0 {
+            self.until_sample -= 1;
+            return None;
+        }
+        self.until_sample = self.config.interval_frames.max(1) - 1;
+
+        let current = features(sim, self.config.cluster);
+        let previous = self.last.replace(current.clone())?;
+        let score = novelty(&previous, &current);
+        if score < self.config.threshold || self.config.capacity == 0 {
+            return None;
+        }
+
+        if self.moments.len() == self.config.capacity {
+            let (lowest, _) = self
+                .moments
+                .iter()
+                .enumerate()
+                .min_by(|(_, a), (_, b)| a.score.total_cmp(&b.score))?;
+            if self.moments[lowest].score >= score {
+                return None;
+            }
+            self.moments.remove(lowest);
+        }
+        self.moments.push(Moment {
+            frame,
+            score,
+            snapshot: SimSnapshot::capture(sim),
+        });
+        Some(score)
+    }
+
+    /// Captured moments, oldest first
+    pub fn moments(&self) -> &[Moment] {
+        &self.moments
+    }
+
+    /// Forget the moments, and the last sample; e.g. after the simulation was replaced, so
+    /// the replacement itself does not count as a moment
+    pub fn reset(&mut self) {
+        self.moments.clear();
+        self.last = None;
+        self.until_sample = 0;
+    }
+}
+
+impl Default for HighlightConfig {
+    fn default() -> Self {
+        Self {
+            interval_frames: 60,
+            threshold: 0.5,
+            capacity: 10,
+            cluster: ClusterConfig::default(),
+        }
+    }
+}
+
+/// Settings for [`Residence`]
+#[derive(Clone, Debug)]
+pub struct ResidenceConfig {
+    /// Frames between samples of the neighborhoods
+    pub interval_frames: usize,
+    /// Relative change in neighbor count which counts as a new neighborhood, on top of a
+    /// change in the dominant neighbor type
+    pub threshold: f32,
+    /// Residence time, in frames, shown halfway along the color ramp
+    pub half_life_frames: f32,
+}
+
+/// Summary of a particle's neighborhood, cheap to compare between samples
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Neighborhood {
+    count: u32,
+    /// Most common neighbor type, if there are any neighbors
+    dominant: Option<u8>,
+}
+
+/// How long each particle has stayed in its current neighborhood. Stable structures keep
+/// their neighbors and grow old, while particles at churning boundaries keep starting over.
+pub struct Residence {
+    pub config: ResidenceConfig,
+    until_sample: usize,
+    /// Neighborhood of each particle at the last sample
+    neighborhoods: Vec<Option<Neighborhood>>,
+    /// Frames each particle has spent in its neighborhood
+    frames: Vec<u32>,
+}
+
+impl Residence {
+    pub fn new(config: ResidenceConfig) -> Self {
+        Self {
+            config,
+            until_sample: 0,
+            neighborhoods: vec![],
+            frames: vec![],
+        }
+    }
+
+    /// Call once per frame, after stepping. Visits the neighbors of every particle on
+    /// sampling frames only. Returns whether this was one.
+    pub fn record(&mut self, sim: &SimState) -> bool {
+        if self.until_sample > 0 {
+            self.until_sample -= 1;
+            return false;
+        }
+        let interval = self.config.interval_frames.max(1);
+        self.until_sample = interval - 1;
+
+        // Added particles start from scratch. Particles reordered by removal are
+        // indistinguishable from ones which moved, and start over on their own.
+        let n = sim.particles().len();
+        self.neighborhoods.resize(n, None);
+        self.frames.resize(n, 0);
+
+        let mut counts = vec![0u32; sim.config().colors.len()];
+        for i in 0..n {
+            counts.iter_mut().for_each(|c| *c = 0);
+            for j in sim.neighbors(i) {
+                counts[sim.particles()[j].color as usize] += 1;
+            }
+            let current = Neighborhood {
+                count: counts.iter().sum(),
+                dominant: (0..counts.len())
+                    .filter(|&t| counts[t] > 0)
+                    .max_by_key(|&t| counts[t])
+                    .map(|t| t as u8),
+            };
+
+            let same = self.neighborhoods[i].is_some_and(|last| {
+                let scale = last.count.max(current.count).max(1) as f32;
+                let change = (last.count as f32 - current.count as f32).abs() / scale;
+                last.dominant == current.dominant && change <= self.config.threshold
+            });
+            self.frames[i] = if same {
+                self.frames[i].saturating_add(interval as u32)
+            } else {
+                0
+            };
+            self.neighborhoods[i] = Some(current);
+        }
+        true
+    }
+
+    /// Frames each particle has spent in its current neighborhood, as of the last sample
+    pub fn frames(&self) -> &[u32] {
+        &self.frames
+    }
+
+    /// Residence time of each particle mapped to `0.0..1.0`: zero for a new neighborhood,
+    /// one half at the half-life, approaching one for long stable ones
+    pub fn levels(&self) -> Vec<f32> {
+        let half_life = self.config.half_life_frames.max(1.);
+        self.frames
+            .iter()
+            .map(|&f| f as f32 / (f as f32 + half_life))
+            .collect()
+    }
+}
+
+impl Default for ResidenceConfig {
+    fn default() -> Self {
+        Self {
+            interval_frames: 10,
+            threshold: 0.25,
+            half_life_frames: 300.,
+        }
+    }
+}
+
+/// Fewest representable positions a move should span, below which moves are noticeably
+/// quantized
+const MIN_ULPS_PER_MOVE: f32 = 16.;
+
+/// Spacing between `x` and the next larger f32 in magnitude
+pub fn ulp(x: f32) -> f32 {
+    let x = x.abs();
+    f32::from_bits(x.to_bits() + 1) - x
+}
+
+/// Warning for moves of length `step` which are too small for the f32 resolution at the far
+/// edge of the cloud. Such moves round to nothing along some axes, so the Monte Carlo
+/// integrators freeze there even though their acceptance rate looks healthy.
+pub fn resolution_warning(sim: &SimState, step: f32) -> Option<String> {
+    let extent = sim
+        .particles()
+        .iter()
+        .map(|p| p.pos.abs().max_element())
+        .fold(0., f32::max);
+    let resolution = ulp(extent);
+    (step < resolution * MIN_ULPS_PER_MOVE).then(|| {
+        format!(
+            "Moves of {:e} are too small for positions as far out as {:e}, which are only \
+             resolved to {:e}",
+            step, extent, resolution
+        )
+    })
+}
+
+/// Approximate `q`th quantile of `values`, from at most `max_samples` evenly strided samples,
+/// selected without sorting. Zero when empty.
+pub fn sampled_quantile(values: &[f32], q: f32, max_samples: usize) -> f32 {
+    let stride = values.len().div_ceil(max_samples.max(1)).max(1);
+    let mut samples: Vec<f32> = values.iter().step_by(stride).copied().collect();
+    if samples.is_empty() {
+        return 0.;
+    }
+    let rank = ((samples.len() - 1) as f32 * q.clamp(0., 1.)).round() as usize;
+    let (_, nth, _) = samples.select_nth_unstable_by(rank, f32::total_cmp);
+    *nth
+}
+
+/// Samples taken to estimate the center and median radius of the cloud, see [`find_escapes`]
+const ESCAPE_SAMPLES: usize = 1024;
+
+/// Anyone to client: how far out particles count as escaped, and what to do with them
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub struct SetEscapePolicy {
+    pub escape: EscapeConfig,
+}
+
+/// What to do with particles which escape the cloud
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum EscapePolicy {
+    /// Only count them
+    #[default]
+    Ignore,
+    /// Teleport them back into the core of the cloud, see [`SimState::recall`]
+    Recall,
+    Remove,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EscapeConfig {
+    /// Multiple of the median radius of the cloud beyond which particles count as escaped
+    pub radius_factor: f32,
+    pub policy: EscapePolicy,
+}
+
+impl Default for EscapeConfig {
+    fn default() -> Self {
+        Self {
+            radius_factor: 20.,
+            policy: EscapePolicy::Ignore,
+        }
+    }
+}
+
+/// Particles far out from the bulk of the cloud
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Escapes {
+    /// Per-axis median position, which unlike the mean is not dragged out by the escapees
+    pub center: Vec3,
+    /// Median distance of the particles from the center
+    pub median_radius: f32,
+    pub indices: Vec<usize>,
+}
+
+/// Where the cloud is and how far out of it a particle has escaped, see [`find_escapes`]
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct EscapeBound {
+    pub center: Vec3,
+    pub median_radius: f32,
+    limit_sq: f32,
+}
+
+impl EscapeBound {
+    /// Center and median radius estimated from a strided sample of the finite positions,
+    /// with the limit at `radius_factor` times the radius
+    pub fn estimate(sim: &SimState, radius_factor: f32) -> Self {
+        let particles = sim.particles();
+        let stride = particles.len().div_ceil(ESCAPE_SAMPLES).max(1);
+        let sample: Vec<Vec3> = (particles.iter().step_by(stride))
+            .map(|p| p.pos)
+            .filter(|pos| pos.is_finite())
+            .collect();
+        let median = |values: Vec<f32>| sampled_quantile(&values, 0.5, ESCAPE_SAMPLES);
+
+        let axis = |a: usize| median(sample.iter().map(|p| p[a]).collect());
+        let center = Vec3::new(axis(0), axis(1), axis(2));
+        let median_radius = median(sample.iter().map(|p| p.distance(center)).collect());
+        Self {
+            center,
+            median_radius,
+            limit_sq: (median_radius * radius_factor).powi(2),
+        }
+    }
+
+    /// Whether a particle at `pos` has escaped, as those no longer finite have
+    pub fn escaped(&self, pos: Vec3) -> bool {
+        !pos.is_finite() || pos.distance_squared(self.center) > self.limit_sq
+    }
+
+    /// The escapees at `indices`, as found by [`EscapeBound::escaped`]
+    pub fn escapes(&self, indices: Vec<usize>) -> Escapes {
+        Escapes {
+            center: self.center,
+            median_radius: self.median_radius,
+            indices,
+        }
+    }
+}
+
+/// Particles further than `radius_factor` times the median radius from the center of the
+/// cloud, and those whose positions are no longer finite, which the query accelerator
+/// quarantines. Only the distance test is a full pass, which the client makes while
+/// building the mesh instead, see [`crate::render::ParticleMesh::update_finding_escapes`].
+pub fn find_escapes(sim: &SimState, radius_factor: f32) -> Escapes {
+    let bound = EscapeBound::estimate(sim, radius_factor);
+    let particles = sim.particles();
+    let indices = (0..particles.len())
+        .filter(|&i| bound.escaped(particles[i].pos))
+        .collect();
+    bound.escapes(indices)
+}
+
+impl Escapes {
+    /// Act on the escapees according to `policy`. Returns whether the particles changed.
+    pub fn handle(&self, sim: &mut SimState, policy: EscapePolicy) -> bool {
+        if self.indices.is_empty() {
+            return false;
+        }
+        match policy {
+            EscapePolicy::Ignore => return false,
+            EscapePolicy::Recall => sim.recall(&self.indices, self.center, self.median_radius),
+            EscapePolicy::Remove => sim.remove_indices(&self.indices),
+        }
+        true
+    }
+}
+
+/// Number of particles of each type
+pub fn population(sim: &SimState) -> Vec<usize> {
+    let mut counts = vec![0; sim.config().colors.len()];
+    for particle in sim.particles() {
+        if let Some(count) = counts.get_mut(particle.color as usize) {
+            *count += 1;
+        }
+    }
+    counts
+}
+
+/// Mean position of the particles of type `color`, if there are any
+pub fn type_centroid(sim: &SimState, color: u8) -> Option<Vec3> {
+    let (sum, n) = (sim.particles().iter())
+        .filter(|p| p.color == color && p.pos.is_finite())
+        .fold((Vec3::ZERO, 0), |(sum, n), p| (sum + p.pos, n + 1));
+    (n > 0).then(|| sum / n as f32)
+}
+
+/// Motion of the particles in a spherical shell about a center, see [`rotation_curve`]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RotBin {
+    /// Distance of the middle of the shell from the center
+    pub radius: f32,
+    pub count: usize,
+    /// Mean speed across the line from the center
+    pub tangential_speed: f32,
+    /// Standard deviation of the speed along the line from the center
+    pub radial_dispersion: f32,
+}
+
+/// Rotation curve of the particles about `center`, in `bins` shells of equal width out to
+/// `r_max`: how fast the particles at each distance circle the center, and how much they
+/// wander in and out. Empty shells are left out. With the simulation confined to the XZ
+/// plane, distances and the tangential direction are taken within the plane. Particles
+/// exactly at the center have no tangential direction, and are left out too.
+pub fn rotation_curve(sim: &SimState, center: Vec3, bins: usize, r_max: f32) -> Vec<RotBin> {
+    let flatten = |v: Vec3| match sim.constrain_2d() {
+        true => Vec3::new(v.x, 0., v.z),
+        false => v,
+    };
+    // Count, sum of tangential speeds, and sum and sum of squares of radial velocities
+    let mut sums = vec![(0, 0_f64, 0_f64, 0_f64); bins];
+    for particle in sim.particles() {
+        let rel = flatten(particle.pos - center);
+        let dist = rel.length();
+        if !(dist > 0. && dist < r_max) {
+            continue;
+        }
+        let bin = ((dist / r_max * bins as f32) as usize).min(bins - 1);
+        let vel = flatten(particle.vel);
+        let radial = vel.dot(rel / dist);
+        let tangential = (vel - rel / dist * radial).length();
+
+        let sum = &mut sums[bin];
+        sum.0 += 1;
+        sum.1 += tangential as f64;
+        sum.2 += radial as f64;
+        sum.3 += (radial as f64).powi(2);
+    }
+
+    let width = r_max / bins as f32;
+    (sums.into_iter().enumerate())
+        .filter(|(_, sum)| sum.0 > 0)
+        .map(|(bin, (count, tangential, radial, radial_sq))| {
+            let n = count as f64;
+            let variance = (radial_sq / n - (radial / n).powi(2)).max(0.);
+            RotBin {
+                radius: (bin as f32 + 0.5) * width,
+                count,
+                tangential_speed: (tangential / n) as f32,
+                radial_dispersion: variance.sqrt() as f32,
+            }
+        })
+        .collect()
+}
+
+/// Rotation curve as CSV with a header row, for plotting speed against radius
+pub fn write_rotation_curve_csv(curve: &[RotBin]) -> String {
+    let mut csv = String::from("radius,count,tangential_speed,radial_dispersion\n");
+    for bin in curve {
+        csv += &format!(
+            "{},{},{},{}\n",
+            bin.radius, bin.count, bin.tangential_speed, bin.radial_dispersion
+        );
+    }
+    csv
+}
+
+/// Anyone to client: print the rotation curve about the centroid of the `core` type, or of
+/// every particle without one, see [`rotation_curve`]
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct PrintRotationCurve {
+    pub core: Option<u8>,
+    pub bins: usize,
+    pub r_max: f32,
+}
+
+/// Anyone to client: replace the particles with an orbiting core and ring, see
+/// [`SimState::spawn_orbital_preset`]
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct MakeOrbitalPreset {
+    pub preset: OrbitalPreset,
+}
+
+/// Weight of each new step in the smoothed growth rate of [`SpreadTracker`]
+const SPREAD_SMOOTHING: f32 = 0.05;
+
+/// How fast the bounding box of the cloud grows, for spotting rule sets whose structures keep
+/// travelling outwards
+#[derive(Clone, Debug, Default)]
+pub struct SpreadTracker {
+    last_diagonal: Option<f32>,
+    /// Smoothed growth of the diagonal per unit of simulated time
+    rate: f32,
+}
+
+impl SpreadTracker {
+    /// Record the cloud after a step of `dt`. Non-finite positions are left out of the box.
+    pub fn record(&mut self, sim: &SimState, dt: f32) {
+        let (min, max) = (sim.particles().iter().map(|p| p.pos))
+            .filter(|pos| pos.is_finite())
+            .fold(
+                (Vec3::splat(f32::MAX), Vec3::splat(f32::MIN)),
+                |(min, max), pos| (min.min(pos), max.max(pos)),
+            );
+        let Some(diagonal) = min.cmple(max).all().then(|| (max - min).length()) else {
+            self.last_diagonal = None;
+            return;
+        };
+        if let Some(last) = self.last_diagonal.filter(|_| dt > 0.) {
+            let rate = (diagonal - last) / dt;
+            self.rate += (rate - self.rate) * SPREAD_SMOOTHING;
+        }
+        self.last_diagonal = Some(diagonal);
+    }
+
+    /// Smoothed growth of the diagonal of the bounding box per unit of simulated time, in
+    /// simulation units; negative while the cloud contracts
+    pub fn rate(&self) -> f32 {
+        self.rate
+    }
+
+    /// Diagonal of the bounding box last recorded
+    pub fn diagonal(&self) -> Option<f32> {
+        self.last_diagonal
+    }
+}
+
+/// Where the cloud is changing: the centroid of the query accelerator cells whose occupancy
+/// changed since the last record, weighted by the change. Follows a travelling wave where the
+/// centroid of every particle would stay put.
+#[derive(Clone, Debug, Default)]
+pub struct ActivityTracker {
+    /// Occupied cells last recorded, sorted, with the radius they were binned at
+    last: Vec<([i32; 3], u32)>,
+    radius: f32,
+}
+
+impl ActivityTracker {
+    /// Compare the cells of `accel` with those last recorded. Returns the centroid of the
+    /// change, or `None` if nothing changed or there was nothing to compare with, as on the
+    /// first record, after the radius changed, or in dense mode, which has no cells.
+    pub fn record(&mut self, accel: &QueryAccelerator) -> Option<Vec3> {
+        let layout = accel.cell_layout();
+        let last = std::mem::replace(&mut self.last, layout);
+        if std::mem::replace(&mut self.radius, accel.radius()) != accel.radius() {
+            return None;
+        }
+
+        let (mut sum, mut weight) = (Vec3::ZERO, 0.);
+        let mut add = |key: [i32; 3], change: u32| {
+            let center =
+                (Vec3::new(key[0] as f32, key[1] as f32, key[2] as f32) + 0.5) * self.radius;
+            sum += center * change as f32;
+            weight += change as f32;
+        };
+        // Merge the two sorted layouts
+        let (mut old, mut new) = (last.iter().peekable(), self.last.iter().peekable());
+        loop {
+            match (old.peek(), new.peek()) {
+                (Some(&&(a, m)), Some(&&(b, n))) if a == b => {
+                    add(a, m.abs_diff(n));
+                    old.next();
+                    new.next();
+                }
+                (Some(&&(a, m)), Some(&&(b, _))) if a < b => {
+                    add(a, m);
+                    old.next();
+                }
+                (_, Some(&&(b, n))) => {
+                    add(b, n);
+                    new.next();
+                }
+                (Some(&&(a, m)), None) => {
+                    add(a, m);
+                    old.next();
+                }
+                (None, None) => break,
+            }
+        }
+        (weight > 0.).then(|| sum / weight)
+    }
+}
+
+/// Union-find over particle indices
+struct DisjointSet {
+    parent: Vec<usize>,
+}
+
+impl DisjointSet {
+    fn new(n: usize) -> Self {
+        Self {
+            parent: (0..n).collect(),
+        }
+    }
+
+    fn find(&mut self, mut i: usize) -> usize {
+        while self.parent[i] != i {
+            // Path halving
+            self.parent[i] = self.parent[self.parent[i]];
+            i = self.parent[i];
+        }
+        i
+    }
+
+    fn union(&mut self, a: usize, b: usize) {
+        let a = self.find(a);
+        let b = self.find(b);
+        if a != b {
+            self.parent[a.max(b)] = a.min(b);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Behaviour, Particle, SimConfig};
+    use cimvr_engine_interface::pcg::Pcg;
+
+    #[test]
+    fn test_activity_follows_travelling_cloud() {
+        let mut rng = Pcg::new();
+        let cloud: Vec<Vec3> = (0..2000)
+            .map(|_| Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()))
+            .collect();
+        // A still cloud, with a blob travelling away from it along x
+        let blob = |x: f32| {
+            cloud
+                .iter()
+                .take(200)
+                .map(move |&p| p * 0.1 + Vec3::new(x, 0.5, 0.5))
+        };
+        let points = |x: f32| -> Vec<Vec3> { cloud.iter().copied().chain(blob(x)).collect() };
+
+        let mut activity = ActivityTracker::default();
+        assert_eq!(
+            activity.record(&QueryAccelerator::new(&points(2.), 0.05)),
+            None
+        );
+        assert_eq!(
+            activity.record(&QueryAccelerator::new(&points(2.), 0.05)),
+            None
+        );
+        let centroid = activity
+            .record(&QueryAccelerator::new(&points(3.), 0.05))
+            .unwrap();
+        // Half way between where the blob left and where it arrived
+        assert!(
+            centroid.distance(Vec3::new(2.55, 0.55, 0.55)) < 0.05,
+            "{}",
+            centroid
+        );
+    }
+
+    #[test]
+    fn test_rotation_curve() {
+        let config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 0.,
+            ..Default::default()
+        };
+        // Rings about (1, 1, 1) in tilted planes: solid body rotation at 2 rad/s, with every
+        // other particle moving in and out at 0.5
+        let center = Vec3::ONE;
+        let mut particles = vec![];
+        for (radius, normal) in [(0.1, Vec3::Y), (0.65, Vec3::new(1., 1., 0.).normalize())] {
+            let u = normal.any_orthonormal_vector();
+            for k in 0..40 {
+                let angle = k as f32 / 40. * std::f32::consts::TAU;
+                let dir = u * angle.cos() + normal.cross(u) * angle.sin();
+                let radial = if k % 2 == 0 { 0.5 } else { -0.5 };
+                particles.push(Particle {
+                    pos: center + dir * radius,
+                    vel: normal.cross(dir) * (2. * radius) + dir * radial,
+                    color: (k % 2) as u8,
+                });
+            }
+        }
+        // Beyond the last bin, and at the center
+        for pos in [Vec3::splat(5.), center] {
+            particles.push(Particle {
+                pos,
+                vel: Vec3::X,
+                color: 1,
+            });
+        }
+        let sim = SimState::from_particles(config, particles);
+
+        let curve = rotation_curve(&sim, center, 4, 1.);
+        assert_eq!(curve.len(), 2);
+        for (bin, radius, middle) in [(curve[0], 0.1, 0.125), (curve[1], 0.65, 0.625)] {
+            assert_eq!(bin.count, 40);
+            assert!((bin.radius - middle).abs() < 1e-6);
+            assert!(
+                (bin.tangential_speed - 2. * radius).abs() < 1e-4,
+                "{:?}",
+                bin
+            );
+            assert!((bin.radial_dispersion - 0.5).abs() < 1e-4, "{:?}", bin);
+        }
+        let csv = write_rotation_curve_csv(&curve);
+        assert_eq!(csv.lines().count(), 3);
+
+        let core = type_centroid(&sim, 0).unwrap();
+        assert!(core.distance(center) < 0.1);
+        assert_eq!(type_centroid(&sim, 2), None);
+    }
+
+    #[test]
+    fn test_find_clusters() {
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour::default()],
+            damping: 0.,
+            ..Default::default()
+        };
+        let particles = [0., 0.1, 0.2, 5., 5.1, 10.]
+            .into_iter()
+            .map(|x| Particle {
+                pos: Vec3::X * x,
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let sim = SimState::from_particles(config, particles);
+
+        let result = find_clusters(&sim, 0.15);
+        assert_eq!(result.labels, vec![0, 0, 0, 1, 1, 2]);
+        let sizes: Vec<usize> = result.clusters.iter().map(|c| c.size).collect();
+        assert_eq!(sizes, vec![3, 2, 1]);
+        assert!((result.clusters[1].center.x - 5.05).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_population_history() {
+        let config = |n: usize| SimConfig {
+            colors: vec![[1.; 3]; n],
+            behaviours: vec![Behaviour::default(); n * n],
+            damping: 0.,
+            ..Default::default()
+        };
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(3), 100);
+        let mut history = PopulationHistory::new(4, 2);
+
+        for _ in 0..6 {
+            history.record(&sim);
+        }
+        assert_eq!(
+            history.iter().map(|(f, _)| f).collect::<Vec<_>>(),
+            vec![0, 2, 4]
+        );
+        assert_eq!(history.current().unwrap().iter().sum::<usize>(), 100);
+
+        // Fewer types mid-run, and the ring buffer wraps
+        sim.set_config(config(2), &mut rng);
+        for _ in 0..4 {
+            history.record(&sim);
+        }
+        assert_eq!(
+            history.iter().map(|(f, _)| f).collect::<Vec<_>>(),
+            vec![2, 4, 6, 8]
+        );
+        assert_eq!(history.current().unwrap().len(), 2);
+
+        let csv = history.to_csv();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(lines[0], "frame,type 0,type 1,type 2");
+        assert_eq!(lines.len(), 5);
+        assert!(lines[4].starts_with("8,") && lines[4].ends_with(','));
+
+        history.clear();
+        assert!(history.current().is_none());
+    }
+
+    /// Clumps of 25 particles at the given centers in the XZ plane, with the given types
+    fn clumps(clumps: &[(f32, f32, u8)], speed: f32) -> SimState {
+        let config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 0.,
+            ..Default::default()
+        };
+        let particles = clumps
+            .iter()
+            .flat_map(|&(x, z, color)| {
+                (0..25).map(move |i| Particle {
+                    pos: Vec3::new(x + (i % 5) as f32 * 0.01, 0., z + (i / 5) as f32 * 0.01),
+                    vel: Vec3::X * speed,
+                    color,
+                })
+            })
+            .collect();
+        SimState::from_particles(config, particles)
+    }
+
+    fn highlight_config() -> HighlightConfig {
+        HighlightConfig {
+            interval_frames: 1,
+            threshold: 0.4,
+            capacity: 2,
+            cluster: ClusterConfig {
+                radius: 0.015,
+                min_size: 20,
+            },
+        }
+    }
+
+    #[test]
+    fn test_novelty() {
+        let config = highlight_config();
+        let features = |sim: &SimState| features(sim, config.cluster);
+        let apart = clumps(
+            &[(-1., -1., 0), (1., 1., 0), (-1., 1., 1), (1., -1., 1)],
+            0.1,
+        );
+        let shifted = clumps(
+            &[(-0.9, -1., 0), (1.1, 1., 0), (-0.9, 1., 1), (1.1, -1., 1)],
+            0.1,
+        );
+        let merged = clumps(&[(0., 0., 0), (0., 0., 1)], 0.1);
+
+        let before = features(&apart);
+        assert_eq!(before.clusters, 4);
+        assert!((before.extents[0].x - 2.04).abs() < 1e-4);
+        assert_eq!(before.extents[1].y, 0.);
+
+        // Drifting along is steady; clusters merging is not
+        assert!(novelty(&before, &features(&shifted)) < 1e-3);
+        let score = novelty(&before, &features(&merged));
+        assert!(score > 1.5, "{}", score);
+        assert_eq!(novelty(&before, &before), 0.);
+
+        // A sudden change of pace counts too
+        let faster = clumps(
+            &[(-1., -1., 0), (1., 1., 0), (-1., 1., 1), (1., -1., 1)],
+            1.,
+        );
+        assert!(novelty(&before, &features(&faster)) > 0.8);
+    }
+
+    #[test]
+    fn test_highlights_keep_best() {
+        let apart = clumps(
+            &[(-1., -1., 0), (1., 1., 0), (-1., 1., 1), (1., -1., 1)],
+            0.,
+        );
+        let merged = clumps(&[(0., 0., 0), (0., 0., 1)], 0.);
+        let pair = clumps(&[(-1., 0., 0), (1., 0., 1)], 0.);
+
+        let mut highlights = Highlights::new(highlight_config());
+        let scores: Vec<Option<f32>> = [&apart, &apart, &merged, &pair, &merged, &apart]
+            .into_iter()
+            .map(|sim| highlights.record(sim))
+            .collect();
+        assert_eq!(scores[..2], [None, None]);
+        assert!(scores[2].unwrap() > 1.5);
+        assert!((scores[3].unwrap() - 0.5).abs() < 1e-3, "{:?}", scores);
+        // As good as the worst kept, so not worth replacing it
+        assert_eq!(scores[4], None);
+        assert!(scores[5].unwrap() > 1.5);
+
+        let frames: Vec<usize> = highlights.moments().iter().map(|m| m.frame).collect();
+        assert_eq!(frames, vec![2, 5]);
+
+        // Moments resume where they were taken
+        let mut rng = Pcg::new();
+        let restored = highlights.moments()[1].snapshot.restore(&mut rng);
+        assert_eq!(restored.particles(), apart.particles());
+
+        highlights.reset();
+        assert!(highlights.moments().is_empty());
+        assert_eq!(highlights.record(&merged), None);
+    }
+
+    #[test]
+    fn test_residence() {
+        // Two clumps of different types, with a particle between them
+        let mut sim = clumps(&[(-1., 0., 0), (1., 0., 1)], 0.);
+        sim.push_particle(Particle {
+            pos: Vec3::ZERO,
+            vel: Vec3::ZERO,
+            color: 0,
+        });
+        sim.rebuild_accel();
+
+        let mut residence = Residence::new(ResidenceConfig {
+            interval_frames: 2,
+            threshold: 0.25,
+            half_life_frames: 4.,
+        });
+        let samples: Vec<bool> = (0..5).map(|_| residence.record(&sim)).collect();
+        assert_eq!(samples, vec![true, false, true, false, true]);
+        assert!(residence.frames().iter().all(|&f| f == 4));
+        assert_eq!(residence.levels()[0], 0.5);
+
+        // Moving into the other clump changes the dominant neighbor type, and the count of
+        // the particle's new neighbors, but only slightly for the rest of them
+        let target = sim.particles()[30].pos + Vec3::splat(1e-3);
+        sim.move_particle(50, target);
+        sim.rebuild_accel();
+        residence.record(&sim);
+        residence.record(&sim);
+        assert_eq!(residence.frames()[50], 0);
+        assert_eq!(residence.frames()[0], 6);
+        assert_eq!(residence.frames()[30], 6);
+
+        // Leaving a stable neighborhood and coming back starts over
+        sim.move_particle(50, Vec3::ZERO);
+        sim.rebuild_accel();
+        residence.record(&sim);
+        residence.record(&sim);
+        assert_eq!(residence.frames()[50], 0);
+
+        // Particles may come and go between samples
+        sim.remove_indices(&[0, 1, 2]);
+        residence.record(&sim);
+        residence.record(&sim);
+        assert_eq!(residence.frames().len(), 48);
+        sim.spawn_in_sphere(Vec3::new(3., 0., 0.), 0.01, 0, 4, &mut Pcg::new());
+        residence.record(&sim);
+        residence.record(&sim);
+        assert_eq!(residence.levels().len(), 52);
+        assert_eq!(residence.frames()[51], 0);
+    }
+
+    #[test]
+    fn test_sampled_quantile() {
+        let values: Vec<f32> = (0..1000).rev().map(|i| i as f32).collect();
+        assert_eq!(sampled_quantile(&values, 0.5, usize::MAX), 500.);
+        assert_eq!(sampled_quantile(&values, 1., usize::MAX), 999.);
+        assert_eq!(sampled_quantile(&values, 0., 10), 99.);
+        assert!((sampled_quantile(&values, 0.95, 100) - 950.).abs() < 20.);
+        assert_eq!(sampled_quantile(&[], 0.5, 10), 0.);
+    }
+
+    #[test]
+    fn test_ulp() {
+        assert_eq!(ulp(1.), f32::EPSILON);
+        assert_eq!(ulp(-1.), f32::EPSILON);
+        assert_eq!(ulp(5e4), 1. / 256.);
+        assert!(ulp(0.) > 0.);
+    }
+
+    #[test]
+    fn test_neighbor_graph() {
+        let mut rng = Pcg::new();
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour::default()],
+            damping: 0.,
+            ..Default::default()
+        };
+        let sim = SimState::new(&mut rng, config, 500);
+        let radius = 0.2;
+
+        let edges = neighbor_graph(&sim, radius);
+        let points: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+        let mut brute = vec![];
+        for i in 0..points.len() {
+            for j in i + 1..points.len() {
+                if points[i].distance_squared(points[j]) <= radius * radius {
+                    brute.push((i as u32, j as u32));
+                }
+            }
+        }
+        assert_eq!(edges, brute);
+
+        let histogram = degree_histogram(&edges, points.len());
+        assert_eq!(histogram.iter().sum::<usize>(), points.len());
+        let degree_sum: usize = histogram.iter().enumerate().map(|(d, n)| d * n).sum();
+        assert_eq!(degree_sum, edges.len() * 2);
+
+        let csv = write_edge_list_csv(&edges[..2]);
+        assert_eq!(
+            csv,
+            format!(
+                "source,target\n{},{}\n{},{}\n",
+                edges[0].0, edges[0].1, edges[1].0, edges[1].1
+            )
+        );
+    }
+
+    /// A tight cloud around (1, 1, 1), with three particles flung far out
+    fn escaping_cloud() -> SimState {
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour::default()],
+            damping: 0.,
+            ..Default::default()
+        };
+        let mut rng = Pcg::new();
+        let mut particles: Vec<Particle> = (0..500)
+            .map(|_| Particle {
+                pos: Vec3::ONE + Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) - 0.5,
+                vel: Vec3::X,
+                color: 0,
+            })
+            .collect();
+        for (i, pos) in [
+            (7, Vec3::splat(1e4)),
+            (123, Vec3::NEG_Y * 50.),
+            (499, Vec3::Z * 30.),
+        ] {
+            particles[i].pos = pos;
+        }
+        SimState::from_particles(config, particles)
+    }
+
+    #[test]
+    fn test_find_escapes() {
+        let sim = escaping_cloud();
+        let escapes = find_escapes(&sim, 20.);
+        assert_eq!(escapes.indices, vec![7, 123, 499]);
+        assert!(
+            escapes.center.distance(Vec3::ONE) < 0.1,
+            "{}",
+            escapes.center
+        );
+        assert!((0.3..0.7).contains(&escapes.median_radius));
+
+        let mut ignored = escaping_cloud();
+        assert!(!escapes.handle(&mut ignored, EscapePolicy::Ignore));
+        assert_eq!(ignored.particles(), sim.particles());
+
+        let mut removed = escaping_cloud();
+        assert!(escapes.handle(&mut removed, EscapePolicy::Remove));
+        assert_eq!(removed.particles().len(), 497);
+        assert!(find_escapes(&removed, 20.).indices.is_empty());
+    }
+
+    #[test]
+    fn test_recall_escapes() {
+        let mut sim = escaping_cloud();
+        sim.clear_journal();
+        let escapes = find_escapes(&sim, 20.);
+        assert!(escapes.handle(&mut sim, EscapePolicy::Recall));
+
+        assert_eq!(sim.journal().recalled, vec![7, 123, 499]);
+        for &i in &escapes.indices {
+            let particle = sim.particles()[i];
+            assert!(particle.pos.distance(escapes.center) <= escapes.median_radius);
+            assert_eq!(particle.vel, Vec3::ZERO);
+        }
+        assert!(find_escapes(&sim, 20.).indices.is_empty());
+        assert!((0..sim.particles().len()).all(|i| sim.accel_consistent(i)));
+        // The accelerator follows the recalled particles
+        assert!(sim.neighbors(7).count() > 0);
+    }
+
+    #[test]
+    fn test_non_finite_escapes() {
+        let mut sim = escaping_cloud();
+        sim.move_particle(42, Vec3::new(0., f32::NAN, 0.));
+        sim.move_particle(300, Vec3::splat(f32::INFINITY));
+        sim.rebuild_accel();
+        assert_eq!(sim.check_invariants(), Ok(()));
+
+        let escapes = find_escapes(&sim, 20.);
+        assert_eq!(escapes.indices, vec![7, 42, 123, 300, 499]);
+        assert!(escapes.center.distance(Vec3::ONE) < 0.1);
+        assert!(escapes.handle(&mut sim, EscapePolicy::Recall));
+        assert!(sim.particles().iter().all(|p| p.pos.is_finite()));
+        assert!(find_escapes(&sim, 20.).indices.is_empty());
+    }
+
+    #[test]
+    fn test_polymer_stats() {
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour::default()],
+            damping: 0.,
+            ..Default::default()
+        };
+        let line = (0..5).map(|k| Vec3::new(k as f32, 0., 0.));
+        let zigzag = [[0, 0], [1, 0], [1, 1], [2, 1], [2, 2]]
+            .map(|[x, y]| Vec3::new(x as f32, y as f32, 10.));
+        let ring =
+            [[0, 0], [1, 0], [1, 1], [0, 1]].map(|[x, y]| Vec3::new(x as f32 + 20., y as f32, 0.));
+        let star = [Vec3::ZERO, Vec3::X, Vec3::Y, Vec3::Z].map(|p| p - Vec3::splat(20.));
+        let free = [Vec3::splat(30.)];
+        let particles = (line.chain(zigzag).chain(ring).chain(star).chain(free))
+            .map(|pos| Particle {
+                pos: pos * 0.1,
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let sim = SimState::from_particles(config, particles);
+
+        let mut bonds: Vec<Bond> = (0..4).chain(5..9).map(|a| Bond { a, b: a + 1 }).collect();
+        bonds.extend(
+            [
+                (10, 11),
+                (12, 11),
+                (12, 13),
+                (13, 10),
+                (14, 15),
+                (14, 16),
+                (17, 14),
+            ]
+            .map(|(a, b)| Bond { a, b }),
+        );
+        // Duplicates and self bonds change nothing
+        bonds.extend([Bond { a: 1, b: 0 }, Bond { a: 3, b: 3 }]);
+        let stats = polymer_stats(&sim, &bonds);
+
+        assert_eq!(stats.chains.len(), 2);
+        let [straight, zigzag] = [&stats.chains[0], &stats.chains[1]];
+        assert_eq!(straight.monomers, [0, 1, 2, 3, 4]);
+        assert!((straight.end_to_end - 0.4).abs() < 1e-6);
+        assert!((straight.radius_of_gyration - 0.1 * 2_f32.sqrt()).abs() < 1e-6);
+        assert_eq!(straight.persistence_length, Some(f32::INFINITY));
+        assert_eq!(zigzag.monomers, [5, 6, 7, 8, 9]);
+        assert!((zigzag.end_to_end - 0.2 * 2_f32.sqrt()).abs() < 1e-6);
+        assert_eq!(zigzag.persistence_length, Some(0.));
+
+        // The ring has no ends, and the star is branched
+        assert_eq!(stats.rings.len(), 1);
+        assert_eq!(stats.rings[0].size, 4);
+        assert!((stats.rings[0].radius_of_gyration - 0.1 * 0.5_f32.sqrt()).abs() < 1e-6);
+        assert_eq!(stats.branched.len(), 1);
+        assert_eq!(stats.branched[0].size, 4);
+
+        // Half the angles are straight, half right angles
+        let expected = -0.1 / 0.5_f32.ln();
+        assert!((stats.persistence_length.unwrap() - expected).abs() < 1e-5);
+        assert_eq!(stats.mean_chain_length(), Some(5.));
+        assert_eq!(stats.chain_length_histogram(), [0, 0, 0, 0, 0, 2]);
+        assert_eq!(stats.end_to_end_histogram(4), [0, 0, 1, 1]);
+        assert_eq!(stats.to_csv().lines().count(), 1 + 4);
+
+        let short = polymer_stats(&sim, &[Bond { a: 0, b: 1 }]);
+        assert_eq!(short.chains[0].persistence_length, None);
+        assert_eq!(short.persistence_length, None);
+    }
+}
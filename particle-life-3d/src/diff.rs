@@ -0,0 +1,391 @@
+//! What changed between two configurations, e.g. before applying one loaded from a preset or
+//! received from elsewhere, and partial application of the changes.
+use std::fmt;
+
+use crate::sim::{Behaviour, ConfigError, Field, InteractionMode, SimConfig};
+
+/// Floats closer than this are considered unchanged, hiding round trips through text
+pub const DIFF_EPSILON: f32 = 1e-5;
+
+/// Entries in which one configuration differs from another, see [`SimConfig::diff`]
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConfigDiff {
+    pub entries: Vec<DiffEntry>,
+    /// The configuration changed to, which entries are taken from when applied
+    target: SimConfig,
+    /// Number of types of the configuration changed from, which the entries index
+    source_types: usize,
+}
+
+/// One difference between two configurations
+#[derive(Clone, Debug, PartialEq)]
+pub enum DiffEntry {
+    /// The number of types differs, so colors and cells are not compared
+    TypeCount {
+        old: usize,
+        new: usize,
+    },
+    Color {
+        ty: usize,
+        old: [f32; 3],
+        new: [f32; 3],
+    },
+    /// One coefficient of the behaviour of type `row` towards type `col`
+    Cell {
+        row: usize,
+        col: usize,
+        field: CellField,
+        old: f32,
+        new: f32,
+    },
+    /// The interaction mode of type `row` towards type `col`
+    Mode {
+        row: usize,
+        col: usize,
+        old: InteractionMode,
+        new: InteractionMode,
+    },
+    Damping {
+        old: f32,
+        new: f32,
+    },
+    /// Factor on every interaction distance, see [`SimConfig::interaction_scale`]
+    InteractionScale {
+        old: f32,
+        new: f32,
+    },
+    /// A part of the configuration compared as a whole
+    Section(Section),
+}
+
+/// A coefficient of a [`Behaviour`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CellField {
+    Coefficient(Field),
+    /// Component of the anisotropy along this axis
+    Anisotropy(usize),
+    Polarity,
+    /// Radius of a pair which is a hard sphere on both sides
+    HardRadius,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Section {
+    Gravity,
+    DensityRules,
+    Mobility,
+    Activity,
+}
+
+impl SimConfig {
+    /// What changes from `self` to `other`. Floats within [`DIFF_EPSILON`] count as equal.
+    /// Configurations with different numbers of types only report that, along with the
+    /// changes outside the behaviour matrix.
+    pub fn diff(&self, other: &SimConfig) -> ConfigDiff {
+        let mut entries = vec![];
+        let n = self.colors.len();
+        if n != other.colors.len() || self.behaviours.len() != other.behaviours.len() {
+            entries.push(DiffEntry::TypeCount {
+                old: n,
+                new: other.colors.len(),
+            });
+        } else {
+            for (ty, (&old, &new)) in self.colors.iter().zip(&other.colors).enumerate() {
+                if (0..3).any(|c| changed(old[c], new[c])) {
+                    entries.push(DiffEntry::Color { ty, old, new });
+                }
+            }
+            for (idx, (a, b)) in self.behaviours.iter().zip(&other.behaviours).enumerate() {
+                let (row, col) = (idx / n.max(1), idx % n.max(1));
+                diff_cell(row, col, a, b, &mut entries);
+            }
+        }
+
+        if changed(self.damping, other.damping) {
+            entries.push(DiffEntry::Damping {
+                old: self.damping,
+                new: other.damping,
+            });
+        }
+        if changed(self.interaction_scale, other.interaction_scale) {
+            entries.push(DiffEntry::InteractionScale {
+                old: self.interaction_scale,
+                new: other.interaction_scale,
+            });
+        }
+        let sections = [
+            (Section::Gravity, self.gravity != other.gravity),
+            (
+                Section::DensityRules,
+                self.density_rules != other.density_rules,
+            ),
+            (Section::Mobility, self.mobility != other.mobility),
+            (Section::Activity, self.activity != other.activity),
+        ];
+        for (section, differs) in sections {
+            if differs {
+                entries.push(DiffEntry::Section(section));
+            }
+        }
+
+        ConfigDiff {
+            entries,
+            target: other.clone(),
+            source_types: n,
+        }
+    }
+}
+
+impl ConfigDiff {
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// `base` with the entries at the given indices applied, validated. Selecting
+    /// [`DiffEntry::TypeCount`] takes the whole target configuration. `base` must have as
+    /// many types as the configuration the diff was taken from.
+    pub fn apply_selected(
+        &self,
+        base: &SimConfig,
+        selected: &[usize],
+    ) -> Result<SimConfig, ConfigError> {
+        let mut config = base.clone();
+        let n = config.colors.len();
+        if n != self.source_types || config.behaviours.len() != n * n {
+            return Err(ConfigError::Invalid(
+                "Changes were taken from another number of types",
+            ));
+        }
+        for &idx in selected {
+            let Some(entry) = self.entries.get(idx) else {
+                continue;
+            };
+            match *entry {
+                DiffEntry::TypeCount { .. } => {
+                    config = self.target.clone();
+                    break;
+                }
+                DiffEntry::Color { ty, new, .. } => config.colors[ty] = new,
+                DiffEntry::Cell {
+                    row,
+                    col,
+                    field,
+                    new,
+                    ..
+                } => field.set(&mut config.behaviours[row * n + col], new),
+                DiffEntry::Mode { row, col, new, .. } => {
+                    config.behaviours[row * n + col].mode = new
+                }
+                DiffEntry::Damping { new, .. } => config.damping = new,
+                DiffEntry::InteractionScale { new, .. } => config.interaction_scale = new,
+                DiffEntry::Section(Section::Gravity) => {
+                    config.gravity = self.target.gravity.clone()
+                }
+                DiffEntry::Section(Section::DensityRules) => {
+                    config.density_rules = self.target.density_rules.clone()
+                }
+                DiffEntry::Section(Section::Mobility) => {
+                    config.mobility = self.target.mobility.clone()
+                }
+                DiffEntry::Section(Section::Activity) => {
+                    config.activity = self.target.activity.clone()
+                }
+            }
+        }
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Every entry, one per line
+    pub fn report(&self) -> String {
+        let lines: Vec<String> = self.entries.iter().map(|e| e.to_string()).collect();
+        lines.join("\n")
+    }
+}
+
+impl CellField {
+    pub fn get(&self, behav: &Behaviour) -> f32 {
+        match *self {
+            CellField::Coefficient(field) => field.get(behav),
+            CellField::Anisotropy(axis) => behav.anisotropy[axis],
+            CellField::Polarity => behav.polarity,
+            CellField::HardRadius => behav.hard_radius().unwrap_or(0.),
+        }
+    }
+
+    /// Set this coefficient; the hard sphere radius is only set on hard spheres
+    pub fn set(&self, behav: &mut Behaviour, value: f32) {
+        match *self {
+            CellField::Coefficient(field) => *field.get_mut(behav) = value,
+            CellField::Anisotropy(axis) => behav.anisotropy[axis] = value,
+            CellField::Polarity => behav.polarity = value,
+            CellField::HardRadius => {
+                if let InteractionMode::HardSphere { radius } = &mut behav.mode {
+                    *radius = value;
+                }
+            }
+        }
+    }
+}
+
+fn changed(a: f32, b: f32) -> bool {
+    (a - b).abs() > DIFF_EPSILON || a.is_nan() != b.is_nan()
+}
+
+/// Push the differences between two behaviours of the same cell
+fn diff_cell(row: usize, col: usize, a: &Behaviour, b: &Behaviour, entries: &mut Vec<DiffEntry>) {
+    let mut fields: Vec<CellField> = Field::ALL.map(CellField::Coefficient).to_vec();
+    fields.extend((0..3).map(CellField::Anisotropy));
+    fields.push(CellField::Polarity);
+
+    match (a.mode, b.mode) {
+        (InteractionMode::HardSphere { .. }, InteractionMode::HardSphere { .. }) => {
+            fields.push(CellField::HardRadius)
+        }
+        (old, new) if old != new => entries.push(DiffEntry::Mode { row, col, old, new }),
+        _ => (),
+    }
+
+    for field in fields {
+        let (old, new) = (field.get(a), field.get(b));
+        if changed(old, new) {
+            entries.push(DiffEntry::Cell {
+                row,
+                col,
+                field,
+                old,
+                new,
+            });
+        }
+    }
+}
+
+impl fmt::Display for DiffEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiffEntry::TypeCount { old, new } => write!(f, "Types: {} -> {}", old, new),
+            DiffEntry::Color { ty, old, new } => {
+                write!(f, "Color of type {}: {:?} -> {:?}", ty, old, new)
+            }
+            DiffEntry::Cell {
+                row,
+                col,
+                field,
+                old,
+                new,
+            } => write!(f, "{:?} of {} -> {}: {} -> {}", field, row, col, old, new),
+            DiffEntry::Mode { row, col, old, new } => {
+                write!(f, "Mode of {} -> {}: {:?} -> {:?}", row, col, old, new)
+            }
+            DiffEntry::Damping { old, new } => write!(f, "Damping: {} -> {}", old, new),
+            DiffEntry::InteractionScale { old, new } => {
+                write!(f, "Interaction scale: {} -> {}", old, new)
+            }
+            DiffEntry::Section(section) => write!(f, "{:?} changed", section),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cimvr_engine_interface::pcg::Pcg;
+
+    fn config(n: usize) -> SimConfig {
+        let mut config = SimConfig {
+            colors: vec![[0.5; 3]; n],
+            behaviours: vec![Behaviour::default(); n * n],
+            damping: 100.,
+            ..Default::default()
+        };
+        config.randomize_field(Field::Strength, false, &mut Pcg::new());
+        config
+    }
+
+    #[test]
+    fn test_identical() {
+        let a = config(3);
+        assert!(a.diff(&a).is_empty());
+
+        // Changes within the epsilon don't count
+        let mut b = a.clone();
+        b.behaviours[4].inter_strength += DIFF_EPSILON / 2.;
+        assert!(a.diff(&b).is_empty());
+    }
+
+    #[test]
+    fn test_single_cell() {
+        let a = config(3);
+        let mut b = a.clone();
+        b.behaviours[5].inter_max_dist = 0.3;
+        let diff = a.diff(&b);
+        assert_eq!(
+            diff.entries,
+            vec![DiffEntry::Cell {
+                row: 1,
+                col: 2,
+                field: CellField::Coefficient(Field::MaxDist),
+                old: 0.2,
+                new: 0.3,
+            }]
+        );
+        assert_eq!(diff.apply_selected(&a, &[0]).unwrap(), b);
+        assert_eq!(diff.apply_selected(&a, &[]).unwrap(), a);
+    }
+
+    #[test]
+    fn test_partial_application() {
+        let a = config(2);
+        let mut b = a.clone();
+        b.damping = 50.;
+        b.colors[1] = [1., 0., 0.];
+        b.behaviours[0].inter_threshold = 0.5;
+        let diff = a.diff(&b);
+        assert_eq!(diff.entries.len(), 3);
+
+        let damping = (diff.entries.iter())
+            .position(|e| matches!(e, DiffEntry::Damping { .. }))
+            .unwrap();
+        let partial = diff.apply_selected(&a, &[damping]).unwrap();
+        assert_eq!(partial.damping, 50.);
+        assert_eq!(partial.colors, a.colors);
+
+        // Each selection is validated as a whole
+        let mut bad = a.clone();
+        bad.behaviours[1].inter_threshold = -1.;
+        let diff = a.diff(&bad);
+        assert!(diff.apply_selected(&a, &[0]).is_err());
+    }
+
+    #[test]
+    fn test_mismatched_sizes() {
+        let a = config(3);
+        let mut b = config(4);
+        b.damping = 10.;
+        let diff = a.diff(&b);
+        assert_eq!(
+            diff.entries,
+            vec![
+                DiffEntry::TypeCount { old: 3, new: 4 },
+                DiffEntry::Damping {
+                    old: 100.,
+                    new: 10.
+                },
+            ]
+        );
+        assert_eq!(diff.apply_selected(&a, &[0]).unwrap(), b);
+        assert_eq!(diff.apply_selected(&a, &[1]).unwrap().colors.len(), 3);
+        assert_eq!(diff.report().lines().count(), 2);
+
+        // Applied to a base of another size, the cells would index past it
+        let mut c = a.clone();
+        c.behaviours[8].inter_max_dist = 0.3;
+        let diff = a.diff(&c);
+        let small = config(2);
+        assert!(matches!(
+            diff.apply_selected(&small, &[0]),
+            Err(ConfigError::Invalid(_))
+        ));
+        assert!(diff.apply_selected(&b, &[]).is_err());
+    }
+}
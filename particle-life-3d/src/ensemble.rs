@@ -0,0 +1,458 @@
+//! Replicas of one configuration from different random starts, stepped side by side, so that
+//! behaviour robust across seeds can be told apart from the luck of one run. Metrics of each
+//! replica are sampled as it runs, and summarized per replica and across replicas.
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diagnostics::{find_clusters, ClusterConfig},
+    mcmc::Integrator,
+    sim::{fork_rng, SimConfig, SimState},
+};
+
+/// Fewest and most replicas in an ensemble
+pub const MIN_REPLICAS: usize = 2;
+pub const MAX_REPLICAS: usize = 8;
+
+/// Anyone to client: run replicas of the current configuration side by side. The main
+/// simulation holds while they run, and the viewed replica is shown in its place.
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub enum EnsembleCommand {
+    /// Start replicas with as many particles as the simulation, see [`EnsembleConfig`]
+    Start { replicas: usize },
+    /// View the replica at this index
+    Select(usize),
+    /// Continue from the replica at this index as the main simulation, ending the ensemble
+    Promote(usize),
+    /// Print the summary so far
+    Report,
+    /// End the ensemble and go back to the main simulation
+    Stop,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnsembleConfig {
+    /// Replicas requested, within [`MIN_REPLICAS`] and [`MAX_REPLICAS`], and fewer if there
+    /// would be more than `max_total_particles`
+    pub replicas: usize,
+    /// Particles across every replica, bounding the memory and time the ensemble takes
+    pub max_total_particles: usize,
+    /// Steps of each replica between samples of its metrics
+    pub sample_interval: usize,
+    pub cluster: ClusterConfig,
+}
+
+/// The quantities compared across replicas
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Metrics {
+    /// Number of clusters of at least the minimum size
+    pub clusters: f32,
+    pub potential_energy: f32,
+    /// Fraction of the particles in the largest cluster
+    pub largest_cluster_fraction: f32,
+}
+
+/// Mean and standard deviation of a stream of values, by Welford's method
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct RunningStats {
+    count: usize,
+    mean: f64,
+    /// Sum of squared deviations from the mean
+    m2: f64,
+}
+
+/// Mean and sample standard deviation
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Spread {
+    pub mean: f32,
+    pub std: f32,
+}
+
+/// Spread of each metric
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct MetricSpreads {
+    pub clusters: Spread,
+    pub potential_energy: Spread,
+    pub largest_cluster_fraction: Spread,
+}
+
+/// Summary of an ensemble: each replica over its samples, and the replicas' means across
+/// replicas
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnsembleSummary {
+    pub per_replica: Vec<MetricSpreads>,
+    pub across: MetricSpreads,
+}
+
+pub struct Replica {
+    sim: SimState,
+    /// Random stream of this replica alone, so that its run doesn't depend on the others
+    rng: Pcg,
+    steps: usize,
+    clusters: RunningStats,
+    potential_energy: RunningStats,
+    largest_cluster_fraction: RunningStats,
+    /// Message of the panic which stopped this replica, if any
+    error: Option<String>,
+}
+
+/// Replicas stepped in turn, see the module documentation
+pub struct Ensemble {
+    pub config: EnsembleConfig,
+    replicas: Vec<Replica>,
+    /// Replica being viewed
+    selected: usize,
+    /// Replica due to step next
+    next: usize,
+}
+
+impl Default for EnsembleConfig {
+    fn default() -> Self {
+        Self {
+            replicas: 4,
+            max_total_particles: 20_000,
+            sample_interval: 60,
+            cluster: ClusterConfig {
+                min_size: 10,
+                ..Default::default()
+            },
+        }
+    }
+}
+
+impl RunningStats {
+    pub fn push(&mut self, value: f32) {
+        self.count += 1;
+        let delta = value as f64 - self.mean;
+        self.mean += delta / self.count as f64;
+        self.m2 += delta * (value as f64 - self.mean);
+    }
+
+    pub fn count(&self) -> usize {
+        self.count
+    }
+
+    /// Mean and sample standard deviation, zero without two values
+    pub fn spread(&self) -> Spread {
+        let var = match self.count {
+            0 | 1 => 0.,
+            n => self.m2 / (n - 1) as f64,
+        };
+        Spread {
+            mean: self.mean as f32,
+            std: var.sqrt() as f32,
+        }
+    }
+}
+
+impl Metrics {
+    pub fn measure(sim: &SimState, cluster: ClusterConfig) -> Self {
+        let clusters = find_clusters(sim, cluster.radius).clusters;
+        let large = clusters.iter().filter(|c| c.size >= cluster.min_size);
+        let largest = clusters.iter().map(|c| c.size).max().unwrap_or(0);
+        Self {
+            clusters: large.count() as f32,
+            potential_energy: sim.potential_energy(),
+            largest_cluster_fraction: largest as f32 / sim.particles().len().max(1) as f32,
+        }
+    }
+}
+
+impl Replica {
+    pub fn sim(&self) -> &SimState {
+        &self.sim
+    }
+
+    /// Steps taken
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn error(&self) -> Option<&str> {
+        self.error.as_deref()
+    }
+
+    fn record(&mut self, metrics: Metrics) {
+        self.clusters.push(metrics.clusters);
+        self.potential_energy.push(metrics.potential_energy);
+        self.largest_cluster_fraction
+            .push(metrics.largest_cluster_fraction);
+    }
+
+    fn spreads(&self) -> MetricSpreads {
+        MetricSpreads {
+            clusters: self.clusters.spread(),
+            potential_energy: self.potential_energy.spread(),
+            largest_cluster_fraction: self.largest_cluster_fraction.spread(),
+        }
+    }
+}
+
+impl Ensemble {
+    /// Start replicas of `sim_config` with `particles` each. Every replica draws its initial
+    /// state from a different stretch of `rng`, and then steps with a stream forked from it.
+    pub fn new(
+        config: EnsembleConfig,
+        sim_config: SimConfig,
+        particles: usize,
+        rng: &mut Pcg,
+    ) -> Self {
+        let n = replica_cap(config.replicas, particles, config.max_total_particles);
+        let replicas = (0..n)
+            .map(|_| Replica {
+                sim: SimState::new(rng, sim_config.clone(), particles),
+                rng: fork_rng(rng),
+                steps: 0,
+                clusters: RunningStats::default(),
+                potential_energy: RunningStats::default(),
+                largest_cluster_fraction: RunningStats::default(),
+                error: None,
+            })
+            .collect();
+        Self {
+            config,
+            replicas,
+            selected: 0,
+            next: 0,
+        }
+    }
+
+    /// Take `steps` steps in all, one replica at a time in turn, so that the replicas keep
+    /// level whatever the budget of each frame. Replicas stopped by a panic are skipped.
+    pub fn step(&mut self, integrator: &Integrator, dt: f32, steps: usize) {
+        let mut taken = 0;
+        while taken < steps && self.replicas.iter().any(|r| r.error.is_none()) {
+            let current = self.next;
+            self.next = (current + 1) % self.replicas.len();
+            let replica = &mut self.replicas[current];
+            if replica.error.is_some() {
+                continue;
+            }
+
+            taken += 1;
+            if let Err(msg) = integrator.try_step(&mut replica.sim, dt, &mut replica.rng) {
+                replica.error = Some(msg);
+                continue;
+            }
+            replica.steps += 1;
+            if replica
+                .steps
+                .is_multiple_of(self.config.sample_interval.max(1))
+            {
+                replica.record(Metrics::measure(&replica.sim, self.config.cluster));
+            }
+        }
+    }
+
+    pub fn replicas(&self) -> &[Replica] {
+        &self.replicas
+    }
+
+    /// The replica being viewed
+    pub fn selected(&self) -> usize {
+        self.selected
+    }
+
+    /// Simulation of the replica being viewed
+    pub fn viewed(&self) -> &SimState {
+        &self.replicas[self.selected].sim
+    }
+
+    /// View replica `i`, if there is one
+    pub fn select(&mut self, i: usize) {
+        if i < self.replicas.len() {
+            self.selected = i;
+        }
+    }
+
+    /// Take replica `i` out of the ensemble, to continue as the main simulation. The
+    /// ensemble carries on with the others.
+    pub fn promote(&mut self, i: usize) -> SimState {
+        let replica = self.replicas.remove(i);
+        let len = self.replicas.len().max(1);
+        self.selected = self.selected.min(len - 1);
+        self.next %= len;
+        replica.sim
+    }
+
+    /// Each replica's metrics over its samples, and the spread of the replicas' means. Only
+    /// replicas with samples take part.
+    pub fn summary(&self) -> EnsembleSummary {
+        let per_replica: Vec<MetricSpreads> = self.replicas.iter().map(Replica::spreads).collect();
+        let sampled = || {
+            (self.replicas.iter().zip(&per_replica))
+                .filter(|(r, _)| r.clusters.count() > 0)
+                .map(|(_, s)| s)
+        };
+        let across = |metric: fn(&MetricSpreads) -> Spread| {
+            let mut stats = RunningStats::default();
+            sampled().for_each(|s| stats.push(metric(s).mean));
+            stats.spread()
+        };
+        EnsembleSummary {
+            across: MetricSpreads {
+                clusters: across(|s| s.clusters),
+                potential_energy: across(|s| s.potential_energy),
+                largest_cluster_fraction: across(|s| s.largest_cluster_fraction),
+            },
+            per_replica,
+        }
+    }
+
+    /// Approximate memory held by every replica, in bytes
+    pub fn memory_bytes(&self) -> usize {
+        self.replicas.iter().map(|r| r.sim.memory_bytes()).sum()
+    }
+
+    pub fn report(&self) -> String {
+        let summary = self.summary();
+        let line = |name: &str, s: &MetricSpreads| {
+            format!(
+                "{}: {:.1} ± {:.1} clusters, energy {:.3} ± {:.3}, largest {:.0}% ± {:.0}%",
+                name,
+                s.clusters.mean,
+                s.clusters.std,
+                s.potential_energy.mean,
+                s.potential_energy.std,
+                s.largest_cluster_fraction.mean * 100.,
+                s.largest_cluster_fraction.std * 100.,
+            )
+        };
+        let mut lines = vec![format!(
+            "{} replicas, {:.1} MB",
+            self.replicas.len(),
+            self.memory_bytes() as f32 / 1e6
+        )];
+        for (i, (replica, spreads)) in self.replicas.iter().zip(&summary.per_replica).enumerate() {
+            let name = match i == self.selected {
+                true => format!("Replica {} (viewed)", i),
+                false => format!("Replica {}", i),
+            };
+            match &replica.error {
+                Some(msg) => lines.push(format!("{}: stopped, {}", name, msg)),
+                None => lines.push(line(&name, spreads)),
+            }
+        }
+        lines.push(line("Across replicas", &summary.across));
+        lines.join("\n")
+    }
+}
+
+/// Replicas to run, `requested` clamped to [`MIN_REPLICAS`]..=[`MAX_REPLICAS`] and then
+/// reduced to keep `particles` each within `max_total_particles`. Never below one.
+pub fn replica_cap(requested: usize, particles: usize, max_total_particles: usize) -> usize {
+    let affordable = max_total_particles / particles.max(1);
+    requested
+        .clamp(MIN_REPLICAS, MAX_REPLICAS)
+        .min(affordable)
+        .max(1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mcmc::{MetropolisConfig, NewtonConfig},
+        sim::{Behaviour, SimConfig},
+    };
+
+    fn config() -> SimConfig {
+        SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 10.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_running_stats() {
+        let mut stats = RunningStats::default();
+        assert_eq!(stats.spread(), Spread::default());
+        for x in [2., 4., 4., 4., 5., 5., 7., 9.] {
+            stats.push(x);
+        }
+        let spread = stats.spread();
+        assert_eq!(spread.mean, 5.);
+        assert!((spread.std - (32f32 / 7.).sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_replica_cap() {
+        assert_eq!(replica_cap(1, 100, 10_000), MIN_REPLICAS);
+        assert_eq!(replica_cap(20, 100, 10_000), MAX_REPLICAS);
+        assert_eq!(replica_cap(8, 3000, 10_000), 3);
+        assert_eq!(replica_cap(4, 50_000, 10_000), 1);
+    }
+
+    #[test]
+    fn test_ensemble() {
+        let mut rng = Pcg::new();
+        let ensemble_config = EnsembleConfig {
+            replicas: 3,
+            sample_interval: 2,
+            cluster: ClusterConfig {
+                min_size: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let mut ensemble = Ensemble::new(ensemble_config, config(), 100, &mut rng);
+        assert_eq!(ensemble.replicas().len(), 3);
+        let first = ensemble.replicas()[0].sim().particles().to_vec();
+        assert_ne!(first, ensemble.replicas()[1].sim().particles());
+        assert!(ensemble.memory_bytes() >= 3 * 100 * std::mem::size_of_val(&first[0]));
+
+        // Round robin keeps the replicas level, even across budgets that don't divide evenly
+        let integrator = Integrator::Newton(NewtonConfig::default());
+        for _ in 0..4 {
+            ensemble.step(&integrator, 1e-3, 5);
+        }
+        let steps: Vec<usize> = ensemble.replicas().iter().map(Replica::steps).collect();
+        assert_eq!(steps, [7, 7, 6]);
+
+        let summary = ensemble.summary();
+        assert_eq!(summary.per_replica.len(), 3);
+        let means: Vec<f32> = (summary.per_replica.iter())
+            .map(|s| s.potential_energy.mean)
+            .collect();
+        let mean = means.iter().sum::<f32>() / 3.;
+        assert!((summary.across.potential_energy.mean - mean).abs() <= mean.abs() * 1e-5);
+        assert!(summary.across.potential_energy.std > 0.);
+        assert_eq!(ensemble.report().lines().count(), 5);
+
+        // The promoted replica leaves the ensemble
+        ensemble.select(2);
+        let promoted = ensemble.promote(2);
+        assert_eq!(promoted.particles().len(), 100);
+        assert_eq!(ensemble.replicas().len(), 2);
+        assert_eq!(ensemble.selected(), 1);
+        ensemble.step(&integrator, 1e-3, 2);
+    }
+
+    #[test]
+    fn test_replicas_step_independently() {
+        // A stochastic integrator, so that sharing a stream would tie the replicas together
+        let integrator = Integrator::Metropolis(MetropolisConfig {
+            temperature: 1e-2,
+            walk_sigma: 5e-3,
+        });
+        let run = |promoted: bool| {
+            let mut rng = Pcg::new();
+            let mut ensemble = Ensemble::new(EnsembleConfig::default(), config(), 100, &mut rng);
+            assert_eq!(ensemble.replicas().len(), 4);
+            if promoted {
+                ensemble.promote(0);
+            }
+            ensemble.step(&integrator, 1e-3, 3 * ensemble.replicas().len());
+            (ensemble.replicas().iter())
+                .map(|r| r.sim().particles().to_vec())
+                .collect::<Vec<_>>()
+        };
+        // Without the first replica between them, the others take the same steps
+        let (all, rest) = (run(false), run(true));
+        assert_eq!(all[1..], rest[..]);
+        assert_ne!(all[1], all[2]);
+    }
+}
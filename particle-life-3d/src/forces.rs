@@ -0,0 +1,340 @@
+//! Accelerations of the particles in a step of the explicit integrator, accumulated from an
+//! ordered list of contributors into a buffer the integrator then reads. Pair forces,
+//! tethers, gravity, walls and propulsion are contributors like any other, so a new kind of
+//! force is one more [`ForceContributor`] registered with [`SimState::add_force`] rather than
+//! another term in the integrator. Damping is not a contributor: it scales velocities after
+//! the kick.
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{query_accel::QueryAccelerator, sim::SimState, timing::Timer};
+
+/// Weight of the newest sample in the smoothed cost of each contributor
+const COST_SMOOTHING: f32 = 0.1;
+
+/// Acceleration of each particle in the step in progress. Kept from step to step, so that
+/// its allocation is reused.
+#[derive(Clone, Debug, Default)]
+pub struct ForceBuffer {
+    accels: Vec<Vec3>,
+}
+
+/// What contributors see of the step in progress
+pub struct StepView<'a> {
+    /// Positions of the particles at the start of the step
+    pub points: &'a [Vec3],
+    /// Query accelerator over `points`
+    pub accel: &'a QueryAccelerator,
+    /// The type being stepped while substepping, or every type
+    pub only: Option<u8>,
+    /// Time step, before any slowing by a time bubble
+    pub dt: f32,
+}
+
+/// Source of acceleration on the particles. Contributors run in the order they were
+/// registered, and only the accelerations of particles which move this step are used, see
+/// [`StepView::moves`]. Neither method may add or remove particles.
+pub trait ForceContributor {
+    /// Name in the list of contributors, by which it is toggled
+    fn name(&self) -> &str;
+
+    /// Add the acceleration of every particle to `forces` at the start of the step, while
+    /// every particle is where it started
+    fn accumulate(&mut self, _sim: &mut SimState, _step: &StepView, _forces: &mut ForceBuffer) {}
+
+    /// Add the acceleration of particle `i` to `forces`, just before it moves. The particles
+    /// before it in the step have already moved, as the scalar integrator has always had it,
+    /// and the random draws of every contributor interleave particle by particle.
+    fn accumulate_particle(
+        &mut self,
+        _sim: &mut SimState,
+        _step: &StepView,
+        _i: usize,
+        _forces: &mut ForceBuffer,
+    ) {
+    }
+}
+
+/// Contributor calling a closure, see [`from_fn`]
+pub struct FnContributor<F> {
+    name: String,
+    f: F,
+}
+
+/// Contributors of a simulation, in order, with the buffer they accumulate into
+#[derive(Default)]
+pub struct ForcePipeline {
+    buffer: ForceBuffer,
+    entries: Vec<Entry>,
+}
+
+struct Entry {
+    contributor: Box<dyn ForceContributor>,
+    enabled: bool,
+    /// Smoothed milliseconds per step, if a clock is available
+    cost_ms: Option<f32>,
+    /// Milliseconds so far in the step in progress
+    step_ms: Option<f32>,
+}
+
+/// A contributor as listed for toggling, with what it costs
+#[derive(Clone, Debug, PartialEq)]
+pub struct ContributorStatus {
+    pub name: String,
+    pub enabled: bool,
+    /// Smoothed milliseconds per step, when timed with the `profiling` feature
+    pub cost_ms: Option<f32>,
+}
+
+/// Anyone to client: switch the named force contributor on or off, e.g. from its entry in
+/// the list of contributors
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub struct SetForceEnabled {
+    pub name: String,
+    pub enabled: bool,
+}
+
+impl ForceBuffer {
+    /// Zero the accelerations of `len` particles, keeping the allocation
+    pub fn reset(&mut self, len: usize) {
+        self.accels.clear();
+        self.accels.resize(len, Vec3::ZERO);
+    }
+
+    pub fn add(&mut self, i: usize, accel: Vec3) {
+        self.accels[i] += accel;
+    }
+
+    pub fn get(&self, i: usize) -> Vec3 {
+        self.accels[i]
+    }
+
+    pub fn as_slice(&self) -> &[Vec3] {
+        &self.accels
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [Vec3] {
+        &mut self.accels
+    }
+}
+
+impl StepView<'_> {
+    /// Whether particle `i` moves this step: not pinned, and of the type being stepped
+    pub fn moves(&self, sim: &SimState, i: usize) -> bool {
+        let other_type = self
+            .only
+            .is_some_and(|only| sim.particles()[i].color != only);
+        !sim.pinned()[i] && !other_type
+    }
+}
+
+/// Contributor named `name` which calls `f` at the start of each step, see
+/// [`ForceContributor::accumulate`]
+pub fn from_fn<F>(name: &str, f: F) -> Box<dyn ForceContributor>
+where
+    F: FnMut(&SimState, &StepView, &mut ForceBuffer) + 'static,
+{
+    Box::new(FnContributor {
+        name: name.into(),
+        f,
+    })
+}
+
+impl<F: FnMut(&SimState, &StepView, &mut ForceBuffer)> ForceContributor for FnContributor<F> {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn accumulate(&mut self, sim: &mut SimState, step: &StepView, forces: &mut ForceBuffer) {
+        (self.f)(sim, step, forces)
+    }
+}
+
+impl ForcePipeline {
+    pub fn new(contributors: Vec<Box<dyn ForceContributor>>) -> Self {
+        let mut pipeline = Self::default();
+        for contributor in contributors {
+            pipeline.push(contributor);
+        }
+        pipeline
+    }
+
+    /// Register a contributor, enabled, to run after the others
+    pub fn push(&mut self, contributor: Box<dyn ForceContributor>) {
+        self.entries.push(Entry {
+            contributor,
+            enabled: true,
+            cost_ms: None,
+            step_ms: None,
+        });
+    }
+
+    /// Unregister the named contributor, returning it if there was one
+    pub fn remove(&mut self, name: &str) -> Option<Box<dyn ForceContributor>> {
+        let idx = self.position(name)?;
+        Some(self.entries.remove(idx).contributor)
+    }
+
+    /// Switch the named contributor on or off; false if there is none by that name
+    pub fn set_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        let Some(idx) = self.position(name) else {
+            return false;
+        };
+        self.entries[idx].enabled = enabled;
+        true
+    }
+
+    /// Every contributor, in the order they run
+    pub fn status(&self) -> Vec<ContributorStatus> {
+        self.entries
+            .iter()
+            .map(|entry| ContributorStatus {
+                name: entry.contributor.name().into(),
+                enabled: entry.enabled,
+                cost_ms: entry.cost_ms,
+            })
+            .collect()
+    }
+
+    /// Start a step, clearing the buffer and accumulating what every enabled contributor
+    /// adds up front
+    pub fn begin(&mut self, sim: &mut SimState, step: &StepView) {
+        self.buffer.reset(step.points.len());
+        for entry in self.entries.iter_mut().filter(|entry| entry.enabled) {
+            let timer = Timer::start();
+            entry.contributor.accumulate(sim, step, &mut self.buffer);
+            entry.step_ms = timer.elapsed_ms();
+        }
+    }
+
+    /// Total acceleration of particle `i`, just before it moves: the buffer, plus what every
+    /// enabled contributor adds for it alone
+    pub fn accel_on(&mut self, sim: &mut SimState, step: &StepView, i: usize) -> Vec3 {
+        for entry in self.entries.iter_mut().filter(|entry| entry.enabled) {
+            let timer = Timer::start();
+            let contributor = &mut entry.contributor;
+            contributor.accumulate_particle(sim, step, i, &mut self.buffer);
+            if let (Some(total), Some(ms)) = (&mut entry.step_ms, timer.elapsed_ms()) {
+                *total += ms;
+            }
+        }
+        self.buffer.get(i)
+    }
+
+    /// End a step, folding its cost into the smoothed cost of each contributor
+    pub fn finish(&mut self) {
+        for entry in &mut self.entries {
+            if let Some(ms) = entry.step_ms.take() {
+                let avg = entry.cost_ms.get_or_insert(ms);
+                *avg += (ms - *avg) * COST_SMOOTHING;
+            }
+        }
+    }
+
+    /// Accelerations accumulated in the last step
+    pub fn buffer(&self) -> &ForceBuffer {
+        &self.buffer
+    }
+
+    fn position(&self, name: &str) -> Option<usize> {
+        (self.entries.iter()).position(|entry| entry.contributor.name() == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Behaviour, Gravity, SimConfig};
+    use cimvr_engine_interface::pcg::Pcg;
+
+    fn config(gravity: Option<Gravity>) -> SimConfig {
+        SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 10.,
+            gravity,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_registered_force() {
+        let gravity = Gravity {
+            down: -Vec3::Y,
+            weights: vec![1., 2.],
+        };
+        let mut builtin = SimState::new(&mut Pcg::new(), config(Some(gravity.clone())), 500);
+        let mut registered = SimState::new(&mut Pcg::new(), config(None), 500);
+        registered.set_pinned(0, true);
+        builtin.set_pinned(0, true);
+
+        // Gravity again, from outside
+        registered.add_force(from_fn("Uniform field", move |sim, step, forces| {
+            for (i, particle) in sim.particles().iter().enumerate() {
+                if step.moves(sim, i) {
+                    forces.add(i, gravity.accel(particle.color));
+                }
+            }
+        }));
+        for _ in 0..10 {
+            builtin.step(1e-3);
+            registered.step(1e-3);
+        }
+        assert_eq!(builtin.particles(), registered.particles());
+        assert_eq!(builtin.forces().get(0), registered.forces().get(0));
+
+        let status = registered.force_status();
+        assert_eq!(status.len(), 7);
+        assert_eq!(status[0].name, "Pair forces");
+        assert_eq!(status[6].name, "Uniform field");
+        assert!(status.iter().all(|force| force.enabled));
+
+        // Switched off, the field is gone and the particles fall no more
+        assert!(registered.set_force_enabled("Uniform field", false));
+        assert!(!registered.set_force_enabled("Levitation", false));
+        assert!(!registered.force_status()[6].enabled);
+        assert!(builtin.set_force_enabled("Gravity", false));
+        let mut plain = SimState::from_particles(config(None), builtin.particles().to_vec());
+        plain.set_pinned(0, true);
+        for sim in [&mut builtin, &mut registered, &mut plain] {
+            sim.step(1e-3);
+        }
+        assert_eq!(builtin.particles(), plain.particles());
+        assert_eq!(registered.particles(), plain.particles());
+
+        assert!(registered.remove_force("Uniform field").is_some());
+        assert!(registered.remove_force("Uniform field").is_none());
+        assert_eq!(registered.force_status().len(), 6);
+    }
+
+    #[test]
+    fn test_panicking_contributor() {
+        let mut sim = SimState::new(&mut Pcg::new(), config(None), 100);
+        sim.add_force(from_fn("Faulty", |_, _, _| panic!("Faulty force")));
+        let before = sim.particles().to_vec();
+        assert_eq!(sim.try_step(1e-3), Err("Faulty force".to_string()));
+        assert_eq!(sim.particles(), before);
+
+        // Recovered with every contributor still registered
+        let status = sim.force_status();
+        assert_eq!(status.len(), 7);
+        assert_eq!(status[0].name, "Pair forces");
+        assert!(sim.remove_force("Faulty").is_some());
+        assert!(sim.try_step(1e-3).is_ok());
+        assert_ne!(sim.particles(), before);
+    }
+
+    #[test]
+    fn test_buffer_reused() {
+        let mut sim = SimState::new(&mut Pcg::new(), config(None), 300);
+        sim.step(1e-3);
+        let buffer = sim.forces().as_slice().as_ptr();
+        for _ in 0..5 {
+            sim.step(1e-3);
+            assert_eq!(sim.forces().as_slice().as_ptr(), buffer);
+        }
+        assert_eq!(sim.forces().as_slice().len(), 300);
+    }
+}
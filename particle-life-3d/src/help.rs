@@ -0,0 +1,216 @@
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::sim::{Behaviour, Field, SimConfig, SimState};
+
+/// Anyone to client: explain a setting, or drive the guided tour, e.g. from the side panel
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub enum HelpCommand {
+    /// Print the explanation of a behaviour coefficient, see [`field_help`]
+    Explain(Field),
+    ExplainDamping,
+    StartTour,
+    NextStage,
+    PreviousStage,
+    /// End the tour, going back to the simulation from before it
+    ExitTour,
+}
+
+/// Explanation of a behaviour coefficient, suitable for hover text
+pub fn field_help(field: Field) -> &'static str {
+    match field {
+        Field::Strength => {
+            "Peak force between two types at mid range. Positive values attract, negative values \
+             repel. Rows act on columns, so the matrix need not be symmetric."
+        }
+        Field::Threshold => {
+            "Distance below which particles always push each other apart, set for each pair \
+             of types. Sets how tightly particles can pack."
+        }
+        Field::MaxDist => {
+            "Distance beyond which two types ignore each other. Larger values let structures \
+             feel each other from further away, at a higher cost per step."
+        }
+        Field::Repulse => {
+            "Strength of the push between particles closer than the threshold. Too low and \
+             particles collapse onto each other."
+        }
+    }
+}
+
+pub const DAMPING_HELP: &str =
+    "Fraction of velocity lost per unit time. High damping settles into stable structures, low \
+     damping keeps things moving.";
+
+/// One step of the guided tour
+pub struct TourStage {
+    pub title: &'static str,
+    pub text: &'static str,
+    /// Demonstration configuration shown during this stage
+    pub config: fn() -> SimConfig,
+}
+
+/// Number of particles in the tour's demonstration simulation
+const TOUR_PARTICLES: usize = 1_500;
+
+pub const TOUR: &[TourStage] = &[
+    TourStage {
+        title: "Particles and types",
+        text: "Every dot is a particle with a type, shown by its color. With no forces between \
+               types, particles only keep their distance from each other.",
+        config: || tour_config(0., 0.),
+    },
+    TourStage {
+        title: "Repulsion",
+        text: "Particles closer than the threshold push each other apart. This keeps \
+               structures from collapsing into a point.",
+        config: || map_behaviours(tour_config(0., 0.), |b| b.default_repulse = 40.),
+    },
+    TourStage {
+        title: "Attraction",
+        text: "The behaviour matrix says how strongly each type pulls on each other type. \
+               Here every type attracts its own kind: watch clusters form.",
+        config: || tour_config(8., 0.),
+    },
+    TourStage {
+        title: "Asymmetry",
+        text: "Rows act on columns, so red can chase green while green flees from red. \
+               Asymmetric matrices make structures that move.",
+        config: || {
+            let mut config = tour_config(4., 0.);
+            config.behaviours[1].inter_strength = 8.;
+            config.behaviours[3].inter_strength = -8.;
+            config
+        },
+    },
+    TourStage {
+        title: "Range",
+        text: "The max distance sets how far the forces reach. Longer range makes larger, \
+               looser structures.",
+        config: || map_behaviours(tour_config(8., 0.), |b| b.inter_max_dist = 0.4),
+    },
+    TourStage {
+        title: "Damping",
+        text: "Damping drains energy. Lower it to keep the system lively, raise it to let \
+               structures settle.",
+        config: || SimConfig {
+            damping: 20.,
+            ..tour_config(8., -3.)
+        },
+    },
+];
+
+/// Guided tour through the controls, using canned demonstrations. The user's simulation is
+/// set aside while the tour runs, and restored when it ends.
+#[derive(Default)]
+pub struct Tour {
+    stage: usize,
+    saved: Option<SimState>,
+}
+
+impl Tour {
+    pub fn is_active(&self) -> bool {
+        self.saved.is_some()
+    }
+
+    /// Set the user's simulation aside and show the first stage
+    pub fn start(&mut self, sim: &mut SimState, rng: &mut Pcg) {
+        if self.is_active() {
+            return;
+        }
+        self.stage = 0;
+        let demo = SimState::new(rng, (TOUR[0].config)(), TOUR_PARTICLES);
+        self.saved = Some(std::mem::replace(sim, demo));
+    }
+
+    /// Current stage, if the tour is running
+    pub fn stage(&self) -> Option<&'static TourStage> {
+        self.is_active().then(|| &TOUR[self.stage])
+    }
+
+    pub fn next(&mut self, sim: &mut SimState, rng: &mut Pcg) {
+        if self.is_active() && self.stage + 1 < TOUR.len() {
+            self.stage += 1;
+            sim.set_config((TOUR[self.stage].config)(), rng);
+        }
+    }
+
+    pub fn back(&mut self, sim: &mut SimState, rng: &mut Pcg) {
+        if self.is_active() && self.stage > 0 {
+            self.stage -= 1;
+            sim.set_config((TOUR[self.stage].config)(), rng);
+        }
+    }
+
+    /// End the tour, restoring the user's simulation as it was
+    pub fn exit(&mut self, sim: &mut SimState) {
+        if let Some(saved) = self.saved.take() {
+            *sim = saved;
+        }
+    }
+}
+
+/// Three types, with the given attraction to their own type and to the others
+fn tour_config(own: f32, other: f32) -> SimConfig {
+    let behav = Behaviour {
+        inter_threshold: 0.05,
+        ..Default::default()
+    };
+
+    SimConfig {
+        colors: vec![[1., 0.2, 0.2], [0.2, 1., 0.2], [0.3, 0.3, 1.]],
+        behaviours: (0..9)
+            .map(|i| behav.with_inter_strength(if i / 3 == i % 3 { own } else { other }))
+            .collect(),
+        interaction_scale: 1.,
+        damping: 150.,
+        gravity: None,
+        density_rules: vec![],
+        mobility: None,
+        activity: None,
+    }
+}
+
+fn map_behaviours(mut config: SimConfig, f: impl Fn(&mut Behaviour)) -> SimConfig {
+    config.behaviours.iter_mut().for_each(f);
+    config
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_help_complete() {
+        for field in Field::ALL {
+            assert!(!field_help(field).is_empty());
+        }
+        assert!(!DAMPING_HELP.is_empty());
+        assert_eq!(TOUR.len(), 6);
+    }
+
+    #[test]
+    fn test_tour_restores_sim() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, tour_config(1., 2.), 10);
+        sim.step(1e-3);
+        let before: Vec<_> = sim.particles().iter().map(|p| p.pos).collect();
+
+        let mut tour = Tour::default();
+        tour.start(&mut sim, &mut rng);
+        assert_eq!(sim.particles().len(), TOUR_PARTICLES);
+        for _ in 0..10 {
+            tour.next(&mut sim, &mut rng);
+        }
+        assert_eq!(tour.stage().unwrap().title, "Damping");
+        assert_eq!(sim.config().damping, 20.);
+        tour.back(&mut sim, &mut rng);
+        assert_eq!(tour.stage().unwrap().title, "Range");
+
+        tour.exit(&mut sim);
+        assert!(tour.stage().is_none());
+        let after: Vec<_> = sim.particles().iter().map(|p| p.pos).collect();
+        assert_eq!(before, after);
+    }
+}
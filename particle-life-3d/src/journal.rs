@@ -0,0 +1,277 @@
+//! Record of what changed in the simulation during a step, so that companion plugins can
+//! follow births, deaths and changes of type without diffing the whole state. Every
+//! [`SimState`](crate::sim::SimState) method that adds, removes or retypes particles writes
+//! to its journal, which is cleared at the start of each step.
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Events kept by default before the journal degrades to counts only
+pub const DEFAULT_JOURNAL_CAP: usize = 4096;
+
+/// Anyone to client: start or stop sending the [`ChangeJournal`] of each step
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub struct PublishJournal {
+    pub publish: bool,
+}
+
+/// A particle which changed type
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub struct TypeChange {
+    pub index: usize,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// Changes since the start of the last step, published as a message when enabled.
+///
+/// Indices are as of the moment of each change. Removals are listed in the order they were
+/// made, each moving the last particle into the hole like `Vec::swap_remove`, so replaying
+/// them in order keeps a mirror of the particles in step.
+#[derive(Message, Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[locality("Local")]
+pub struct ChangeJournal {
+    /// Index of each particle added
+    pub created: Vec<usize>,
+    /// Index and last position of each particle removed
+    pub removed: Vec<(usize, Vec3)>,
+    pub type_changes: Vec<TypeChange>,
+    /// Index of each particle teleported back into the cloud, see [`SimState::recall`]
+    ///
+    /// [`SimState::recall`]: crate::sim::SimState::recall
+    pub recalled: Vec<usize>,
+    /// Number of particles added, removed, retyped and recalled, counted even past the cap
+    pub births: usize,
+    pub deaths: usize,
+    pub retypes: usize,
+    pub recalls: usize,
+    /// Furthest any particle moved
+    pub max_displacement: f32,
+    /// Monte Carlo moves accepted
+    pub accepted_moves: usize,
+    /// Whether there were more events than the cap, so that only the counts were kept
+    pub truncated: bool,
+    /// Events kept before truncating
+    #[serde(skip)]
+    cap: usize,
+}
+
+impl ChangeJournal {
+    pub fn new(cap: usize) -> Self {
+        Self {
+            cap,
+            ..Default::default()
+        }
+    }
+
+    /// Forget every change, keeping the cap
+    pub fn clear(&mut self) {
+        *self = Self::new(self.cap);
+    }
+
+    pub fn cap(&self) -> usize {
+        self.cap
+    }
+
+    /// Set the number of events kept. Takes effect from the next event.
+    pub fn set_cap(&mut self, cap: usize) {
+        self.cap = cap;
+    }
+
+    /// Total number of events, whether kept or not
+    pub fn events(&self) -> usize {
+        self.births + self.deaths + self.retypes + self.recalls
+    }
+
+    pub(crate) fn created(&mut self, index: usize) {
+        self.births += 1;
+        if self.keep() {
+            self.created.push(index);
+        }
+    }
+
+    pub(crate) fn removed(&mut self, index: usize, pos: Vec3) {
+        self.deaths += 1;
+        if self.keep() {
+            self.removed.push((index, pos));
+        }
+    }
+
+    pub(crate) fn retyped(&mut self, index: usize, old: u8, new: u8) {
+        if old == new {
+            return;
+        }
+        self.retypes += 1;
+        if self.keep() {
+            self.type_changes.push(TypeChange { index, old, new });
+        }
+    }
+
+    pub(crate) fn recalled(&mut self, index: usize) {
+        self.recalls += 1;
+        if self.keep() {
+            self.recalled.push(index);
+        }
+    }
+
+    pub(crate) fn moved(&mut self, distance: f32) {
+        self.max_displacement = self.max_displacement.max(distance);
+    }
+
+    pub(crate) fn accepted_move(&mut self) {
+        self.accepted_moves += 1;
+    }
+
+    /// Whether the event just counted is within the cap. Past it, the lists are dropped for
+    /// good, since a partial list would mislead a mirror more than none.
+    fn keep(&mut self) -> bool {
+        if !self.truncated && self.events() > self.cap {
+            self.truncated = true;
+            self.created = vec![];
+            self.removed = vec![];
+            self.type_changes = vec![];
+            self.recalled = vec![];
+        }
+        !self.truncated
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mcmc::{metropolis_step, MetropolisConfig},
+        sim::{Behaviour, DensityRule, Particle, SimConfig, SimState},
+    };
+    use cimvr_engine_interface::pcg::Pcg;
+
+    fn config(n: usize) -> SimConfig {
+        SimConfig {
+            colors: vec![[1.; 3]; n],
+            behaviours: vec![Behaviour::default(); n * n],
+            damping: 10.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_journal_mutations() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(3), 20);
+        sim.step(1e-3);
+        assert!(sim.journal().max_displacement > 0.);
+        assert_eq!(sim.journal().events(), 0);
+
+        // Births and deaths, replayable onto a mirror of the particles
+        let mut mirror = sim.particles().to_vec();
+        let particle = Particle {
+            pos: Vec3::X * 5.,
+            vel: Vec3::ZERO,
+            color: 1,
+        };
+        sim.push_particle(particle);
+        sim.spawn_in_sphere(Vec3::ZERO, 0.1, 2, 2, &mut rng);
+        assert_eq!(sim.journal().created, [20, 21, 22]);
+        for &i in &sim.journal().created {
+            mirror.push(sim.particles()[i]);
+        }
+        let removed_pos = [sim.particles()[3].pos, sim.particles()[5].pos];
+        sim.remove_indices(&[3, 5]);
+        assert_eq!(
+            sim.journal().removed,
+            [(5, removed_pos[1]), (3, removed_pos[0])]
+        );
+        assert_eq!(sim.remove_in_sphere(Vec3::X * 5., 0.1, true), 1);
+        assert_eq!(sim.journal().deaths, 3);
+
+        for &(i, pos) in &sim.journal().removed {
+            assert_eq!(mirror[i].pos, pos);
+            mirror.swap_remove(i);
+        }
+        assert_eq!(mirror, sim.particles());
+
+        // Changes of type, from each source
+        let colors =
+            |sim: &SimState| -> Vec<u8> { sim.particles().iter().map(|p| p.color).collect() };
+        let check_retypes = |sim: &SimState, before: &[u8]| {
+            let after = colors(sim);
+            let changed: Vec<TypeChange> = (0..after.len())
+                .filter(|&i| before[i] != after[i])
+                .map(|i| TypeChange {
+                    index: i,
+                    old: before[i],
+                    new: after[i],
+                })
+                .collect();
+            let mut listed = sim.journal().type_changes.clone();
+            listed.sort_by_key(|c| c.index);
+            assert_eq!(listed, changed);
+        };
+        let new = sim.split_type(0, &mut rng);
+        let mut config = sim.config().clone();
+        config.density_rules = vec![DensityRule {
+            ty: 0,
+            crowded_threshold: 0,
+            crowded_becomes: 1,
+            lonely_threshold: 1,
+            lonely_becomes: 2,
+            check_radius: 10.,
+            probability: 1.,
+        }];
+        type Source<'a> = &'a dyn Fn(&mut SimState, &mut Pcg);
+        let sources: [Source; 5] = [
+            &|sim, rng| sim.reshuffle_types(rng),
+            &|sim, rng| {
+                sim.split_type(1, rng);
+            },
+            &|sim, rng| sim.merge_types(0, new, rng),
+            &|sim, rng| {
+                sim.set_config(config.clone(), rng);
+                sim.step(1e-3);
+            },
+            &|sim, rng| sim.set_config(self::config(1), rng),
+        ];
+        for source in sources {
+            let before = colors(&sim);
+            sim.clear_journal();
+            source(&mut sim, &mut rng);
+            assert!(sim.journal().retypes > 0);
+            check_retypes(&sim, &before);
+        }
+
+        // Monte Carlo moves
+        let stats = metropolis_step(
+            &mut sim,
+            &MetropolisConfig {
+                temperature: 1.,
+                walk_sigma: 1e-2,
+            },
+            &mut rng,
+        );
+        assert_eq!(sim.journal().accepted_moves, stats.accepted);
+        assert!(sim.journal().max_displacement > 0.);
+        assert_eq!(sim.journal().events(), 0);
+    }
+
+    #[test]
+    fn test_journal_cap() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(8), 200);
+        sim.set_journal_cap(10);
+        sim.reshuffle_types(&mut rng);
+        let journal = sim.journal();
+        assert!(journal.truncated);
+        assert!(journal.retypes > 10);
+        assert!(journal.type_changes.is_empty());
+
+        // Under the cap, every event is listed
+        sim.step(1e-3);
+        for _ in 0..5 {
+            sim.push_particle(sim.particles()[0]);
+        }
+        assert!(!sim.journal().truncated);
+        assert_eq!(sim.journal().created.len(), 5);
+        assert_eq!(sim.journal().cap(), 10);
+    }
+}
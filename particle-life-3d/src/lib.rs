@@ -1,27 +1,242 @@
 use cimvr_common::{
     glam::Vec3,
-    render::{CameraComponent, Mesh, MeshHandle, Primitive, Render, UploadMesh, Vertex},
-    vr::{ControllerEvent, VrUpdate},
+    render::{CameraComponent, Mesh, Primitive, Render, UploadMesh},
+    vr::{ControllerEvent, ElementState, VrController, VrUpdate},
     Transform,
 };
-use cimvr_engine_interface::{
-    dbg, make_app_state, pcg::Pcg, pkg_namespace, prelude::*, println, FrameTime,
-};
-mod sim;
+use cimvr_engine_interface::{dbg, make_app_state, pcg::Pcg, prelude::*, println, FrameTime};
+pub mod sim;
 use sim::*;
-mod query_accel;
+pub mod audio;
+pub mod calibrate;
+pub mod capillary;
+pub mod classic;
+pub mod diagnostics;
+pub mod diff;
+pub mod ensemble;
+pub mod forces;
+pub mod help;
+pub mod journal;
+pub mod livecode;
+pub mod mcmc;
+pub mod measure;
+pub mod metric;
+pub mod neighbor_counts;
+pub mod palette;
+pub mod persist;
+pub mod placement;
+pub mod query_accel;
+#[cfg(test)]
+mod regression;
+pub mod relax;
+pub mod render;
+pub mod replay;
+pub mod scenario;
+pub mod shortcuts;
+#[cfg(feature = "simd")]
+pub mod simd;
+pub mod slots;
+pub mod soak;
+pub mod stability;
+pub mod staging;
+pub mod sweep;
+pub mod sync;
+pub mod tables;
+pub mod text;
+pub mod thermo;
+pub mod timing;
+pub mod validation;
+pub mod workload;
+use audio::{AudioEventConfig, AudioEventDetector, SimAudioEvents};
+use calibrate::{Calibration, CalibrationConfig};
+use capillary::{DropletConfig, DropletTest, RunDropletTest};
+use classic::ImportClassic;
+use diagnostics::{
+    resolution_warning, rotation_curve, type_centroid, write_rotation_curve_csv, ActivityTracker,
+    ClusterConfig, EscapeBound, EscapeConfig, HighlightConfig, Highlights, MakeOrbitalPreset,
+    PopulationHistory, PrintRotationCurve, Residence, ResidenceConfig, SetEscapePolicy,
+    SpreadTracker,
+};
+use ensemble::{Ensemble, EnsembleCommand, EnsembleConfig};
+use forces::SetForceEnabled;
+use help::{field_help, HelpCommand, Tour, DAMPING_HELP};
+use journal::PublishJournal;
+use livecode::{ConfigText, ConfigTextError, ConfigUpdate, GetConfigText, SetConfigText};
+use mcmc::{AutoDt, AutoSamples, Integrator, SamplesPolicy, SetAutoDt, SetAutoSamples};
+use measure::{CountingSphere, MeasureCommand, Ruler};
+use persist::{LoadSettings, SettingsSaver, SimSettings, StoreSettings, StoredSettings};
+use placement::{Follow, FollowStructure, FollowWithBubble, PlaceSim, SimPlacement, TwoHandGrab};
+use relax::{Relax, RelaxCommand, RelaxConfig};
+use render::{
+    bubble_mesh, cells_mesh, chunk_handle, clip_mesh, heading_mesh, legend_mesh, measure_mesh,
+    ClipPlane, ColorMode, Echoes, MarkerConfig, MeshUpdate, ParticleMesh, SetClip, SetColorMode,
+    SetEchoes, ShowAccelCells, ShowHeadingTicks, ShowLegend, BUBBLE_HANDLE, CELLS_HANDLE,
+    CLIP_HANDLE, ECHO_HANDLE, HEADING_HANDLE, LABEL_SIZE, LEGEND_HANDLE, MEASURE_HANDLE,
+};
+use replay::{ConfigChange, InputAction, InputLog, InputSession, RecordCommand};
+use scenario::{named, LoadScenario, PrintScenario, Scenario, ScenarioError, ScenarioSource};
+use shortcuts::{Action, Controls, KeyPress, Shortcuts};
+use slots::{ConfigSlots, SlotCommand};
+use soak::{SoakConfig, SoakTest};
+use staging::ScaleInteractions;
+use sweep::{Sweep, SweepCommand, SweepConfig};
+use thermo::{IntegrateFreeEnergy, ThermoIntegration, TiConfig};
+use timing::{Pacer, Phase, Profile, StepCommand, StepController, Timer};
+use validation::{TwoBody, TwoBodyTest, TwoBodyValidation, PATH_HANDLE};
+use workload::{CaptureWorkload, Workload};
+
+/// Updates to wait for the server's stored settings before starting with the defaults, two
+/// per frame
+const RESTORE_TIMEOUT_FRAMES: usize = 120;
+
+/// Frames between redraws of the query accelerator's cell outlines
+const CELLS_REDRAW_FRAMES: usize = 10;
+
+/// Monte Carlo sweeps per frame of a free energy measurement
+const THERMO_SWEEPS_PER_FRAME: usize = 4;
+
+/// Monte Carlo sweeps per frame of a droplet test
+const DROPLET_SWEEPS_PER_FRAME: usize = 4;
 
-const SIM_OFFSET: Vec3 = Vec3::new(0., 1., 0.);
+/// Steps between readouts of a two-body test
+const VALIDATION_READOUT_STEPS: usize = 60;
+
+/// Half-width of each tile of a sweep
+const SWEEP_HALF_WIDTH: f32 = 0.25;
 
 // All state associated with client-side behaviour
 struct ClientState {
     sim: SimState,
+    integrator: Integrator,
+    /// Time step of the explicit integrator
+    dt: f32,
+    /// Chooses `dt` from the stability limit of the configuration, when enabled with
+    /// [`SetAutoDt`]
+    auto_dt: Option<AutoDt>,
+    /// Chooses the kinetic integrator's sample count from a time budget or rate, when enabled
+    /// with [`SetAutoSamples`]
+    auto_samples: Option<AutoSamples>,
+    /// Sample count last shown in the log
+    samples_shown: usize,
+    /// Length of the last frame in seconds
+    frame_s: f32,
     time: f32,
+    /// World positions of the controllers last frame
     last_left_pos: Vec3,
     last_right_pos: Vec3,
+    /// Where the simulation sits in the world
+    placement: SimPlacement,
+    /// Moving the simulation with both triggers held
+    grab: TwoHandGrab,
+    /// Whether the left and right triggers are held
+    triggers: [bool; 2],
+    audio: AudioEventDetector,
+    profile: Profile,
+    mesh: ParticleMesh,
+    population: PopulationHistory,
+    /// Interesting moments captured automatically, for resuming from later
+    highlights: Highlights,
+    /// Time spent by each particle in its neighborhood, tracked for [`ColorMode::Residence`]
+    residence: Residence,
+    /// Detection of particles flung far from the cloud, and what to do with them, see
+    /// [`SetEscapePolicy`]
+    escape: EscapeConfig,
+    /// Why the simulation was paused: a step found unsafe to take, or a panic where panics
+    /// unwind
+    error: Option<String>,
+    pacer: Pacer,
+    /// Whether the user paused the simulation, or is stepping it by hand
+    stepper: StepController,
+    /// Guided tour, which sets the simulation aside while it runs, see [`HelpCommand`]
+    tour: Tour,
+    /// Bindings of keys to common actions, pressed with [`KeyPress`]
+    shortcuts: Shortcuts,
+    /// Configurations saved for the session, loaded by the number keys
+    slots: ConfigSlots,
+    /// Whether the profile is printed
+    debug: bool,
+    /// Whether the list of shortcuts is shown
+    show_help: bool,
+    /// Shared by everything random on the client, so that resets continue the stream
+    /// rather than starting it over
+    rng: Pcg,
+    /// Render entity of each mesh chunk
+    chunk_entities: Vec<EntityId>,
+    /// Ghost layer of past positions, when enabled
+    echoes: Option<Echoes>,
+    /// Render entity of the echo layer, while it has anything to draw
+    echo_entity: Option<EntityId>,
+    /// Debug ticks along the headings of a sample of polar particles, when enabled with
+    /// [`ShowHeadingTicks`]
+    heading_ticks: Option<MarkerConfig>,
+    /// Render entity of the heading ticks, while there are any
+    heading_entity: Option<EntityId>,
+    /// Particle the time bubble stays centered on, if any, see [`FollowWithBubble`]
+    bubble_follow: Option<usize>,
+    /// Render entity of the time bubble outline, and the bubble it was drawn for
+    bubble_entity: Option<(EntityId, TimeBubble)>,
+    /// Render entity of the clipping plane outline, and its unscaled mesh
+    clip_entity: Option<(EntityId, Mesh)>,
+    /// Whether to show a legend of the particle types, see [`ShowLegend`]
+    show_legend: bool,
+    /// Render entity of the legend, its unscaled mesh, and the colors it was drawn for
+    legend_entity: Option<(EntityId, Mesh, Vec<[f32; 3]>)>,
+    /// Number of query accelerator cells to outline, if shown, see [`ShowAccelCells`]
+    cell_budget: Option<usize>,
+    /// Render entity of the cell outlines, their unscaled mesh, and the frames until they are
+    /// redrawn
+    cells_entity: Option<(EntityId, Mesh, usize)>,
+    /// Keeps recent activity in view, if following, see [`FollowStructure`]
+    follow: Option<Follow>,
+    activity: ActivityTracker,
+    spread: SpreadTracker,
+    /// Position of the camera in the simulation's frame, once a camera is found, for labels
+    /// to face
+    camera: Option<Vec3>,
+    /// Whether to send the change journal of each step to other plugins, see [`PublishJournal`]
+    publish_journal: bool,
+    /// Recording of the inputs, or playback of one, see [`RecordCommand`]. Both restart the
+    /// simulation and its random stream.
+    inputs: InputSession,
+    /// Replicas run side by side in place of the simulation, see [`EnsembleCommand`]
+    ensemble: Option<Ensemble>,
+    /// Variations of the configuration side by side in place of the simulation, see
+    /// [`SweepCommand`]
+    sweep: Option<Sweep>,
+    /// Annealing into a nearby energy minimum, when asked for with [`RelaxCommand`]
+    relax: Relax,
+    /// Tenths of the relaxation done, for reporting progress
+    relax_tenths: usize,
+    /// Free energy measurement on a copy of the simulation, with its own random stream, see
+    /// [`IntegrateFreeEnergy`]
+    thermo: Option<(ThermoIntegration, Pcg)>,
+    /// Tenths of the measurement done, for reporting progress
+    thermo_tenths: usize,
+    /// Droplet test run beside the simulation, with its own random stream, see
+    /// [`RunDropletTest`]
+    droplet: Option<(DropletTest, Pcg)>,
+    /// Tenths of the droplet test done, for reporting progress
+    droplet_tenths: usize,
+    /// Measurement tools, see [`MeasureCommand`]
+    ruler: Ruler,
+    counting_sphere: Option<CountingSphere>,
+    measure_entity: Option<EntityId>,
+    /// Reference for a two-body test, see [`TwoBodyTest`]
+    validation: Option<TwoBodyValidation>,
+    /// Overlay of the reference path
+    validation_entity: Option<EntityId>,
+    /// Frames left to wait for stored settings from the server, while the simulation is held
+    restore_frames: Option<usize>,
+    saver: SettingsSaver,
+    /// Whether Monte Carlo moves were last found too small for the position resolution
+    resolution_warned: bool,
+    /// Randomizes settings and checks invariants, with the `soak` feature
+    soak: Option<SoakTest>,
+    /// Measures the machine to size the simulation, unless stored settings already did
+    calibration: Option<Calibration>,
 }
 
-fn new_sim_state(io: &mut EngineIo) -> SimState {
+fn new_sim_state(io: &mut EngineIo, rng: &mut Pcg) -> SimState {
     let mut aa = Behaviour::default();
     aa.inter_threshold = 0.05;
 
@@ -56,28 +271,65 @@ fn new_sim_state(io: &mut EngineIo) -> SimState {
             aa.with_inter_strength(-100.),
         ],
         */
+        interaction_scale: 1.,
         damping: 150.,
+        gravity: None,
+        density_rules: vec![],
+        mobility: None,
+        activity: None,
     };
 
     dbg!(&palette);
 
-    SimState::new(&mut Pcg::new(), palette, 4_000)
+    SimState::new(rng, palette, 4_000)
 }
 
-const SIM_RENDER_ID: MeshHandle = MeshHandle::new(pkg_namespace!("Simulation"));
-
 impl UserState for ClientState {
     // Implement a constructor
     fn new(io: &mut EngineIo, sched: &mut EngineSchedule<Self>) -> Self {
-        let sim = new_sim_state(io);
+        let mut rng = Pcg::new();
+        let sim = new_sim_state(io, &mut rng);
 
-        io.create_entity()
-            .add_component(Transform::identity().with_position(SIM_OFFSET))
-            .add_component(Render::new(SIM_RENDER_ID).primitive(Primitive::Points))
+        sched
+            .add_system(Self::update)
+            .subscribe::<FrameTime>()
+            .subscribe::<StoredSettings>()
+            .subscribe::<ConfigUpdate>()
+            .subscribe::<ConfigTextError>()
+            .subscribe::<RelaxCommand>()
+            .subscribe::<StepCommand>()
+            .subscribe::<PrintRotationCurve>()
+            .subscribe::<MakeOrbitalPreset>()
+            .subscribe::<CaptureWorkload>()
+            .subscribe::<SetClip>()
+            .subscribe::<ShowLegend>()
+            .subscribe::<SetEchoes>()
+            .subscribe::<SetColorMode>()
+            .subscribe::<ShowHeadingTicks>()
+            .subscribe::<FollowWithBubble>()
+            .subscribe::<ImportClassic>()
+            .subscribe::<KeyPress>()
+            .subscribe::<HelpCommand>()
+            .subscribe::<IntegrateFreeEnergy>()
+            .subscribe::<TwoBodyTest>()
+            .subscribe::<RunDropletTest>()
+            .subscribe::<PublishJournal>()
+            .subscribe::<SetAutoDt>()
+            .subscribe::<SetAutoSamples>()
+            .subscribe::<SetEscapePolicy>()
+            .subscribe::<ShowAccelCells>()
+            .subscribe::<FollowStructure>()
+            .subscribe::<LoadScenario>()
+            .subscribe::<PrintScenario>()
+            .subscribe::<ScaleInteractions>()
+            .subscribe::<SetForceEnabled>()
+            .subscribe::<RecordCommand>()
+            .subscribe::<EnsembleCommand>()
+            .subscribe::<SweepCommand>()
+            .subscribe::<SlotCommand>()
+            .subscribe::<MeasureCommand>()
             .build();
 
-        sched.add_system(Self::update).build();
-
         sched
             .add_system(Self::interaction)
             .query(
@@ -88,14 +340,86 @@ impl UserState for ClientState {
             )
             .subscribe::<FrameTime>()
             .subscribe::<VrUpdate>()
+            .subscribe::<PlaceSim>()
             .build();
         sched.add_system(Self::update).build();
 
+        sched
+            .add_system(Self::audio_events)
+            .subscribe::<FrameTime>()
+            .build();
+
+        // The server keeps our settings across plugin reloads
+        io.send(&LoadSettings);
+
+        let calibration = Calibration::new(CalibrationConfig::default(), sim.config().clone());
+
         Self {
             sim,
+            integrator: Integrator::default(),
+            dt: 1e-3,
+            auto_dt: None,
+            auto_samples: None,
+            samples_shown: 0,
+            frame_s: 1. / 60.,
             time: 0.,
             last_left_pos: Vec3::ZERO,
             last_right_pos: Vec3::ZERO,
+            placement: SimPlacement::default(),
+            grab: TwoHandGrab::default(),
+            triggers: [false; 2],
+            audio: AudioEventDetector::new(AudioEventConfig::default()),
+            profile: Profile::default(),
+            mesh: ParticleMesh::default(),
+            population: PopulationHistory::new(2_000, 4),
+            highlights: Highlights::new(HighlightConfig::default()),
+            residence: Residence::new(ResidenceConfig::default()),
+            escape: EscapeConfig::default(),
+            error: None,
+            pacer: Pacer::default(),
+            stepper: StepController::default(),
+            tour: Tour::default(),
+            shortcuts: Shortcuts::default(),
+            slots: ConfigSlots::default(),
+            debug: true,
+            show_help: false,
+            rng,
+            chunk_entities: vec![],
+            echoes: None,
+            echo_entity: None,
+            heading_ticks: None,
+            heading_entity: None,
+            bubble_follow: None,
+            bubble_entity: None,
+            clip_entity: None,
+            show_legend: false,
+            legend_entity: None,
+            cell_budget: None,
+            cells_entity: None,
+            follow: None,
+            activity: ActivityTracker::default(),
+            spread: SpreadTracker::default(),
+            camera: None,
+            publish_journal: false,
+            inputs: InputSession::default(),
+            ensemble: None,
+            sweep: None,
+            relax: Relax::default(),
+            relax_tenths: 0,
+            thermo: None,
+            thermo_tenths: 0,
+            droplet: None,
+            droplet_tenths: 0,
+            ruler: Ruler::default(),
+            counting_sphere: None,
+            measure_entity: None,
+            validation: None,
+            validation_entity: None,
+            restore_frames: Some(RESTORE_TIMEOUT_FRAMES),
+            saver: SettingsSaver::new(30, 600),
+            resolution_warned: false,
+            soak: cfg!(feature = "soak").then(|| SoakTest::new(SoakConfig::default())),
+            calibration: Some(calibration),
         }
     }
 }
@@ -105,6 +429,14 @@ impl ClientState {
         let mut camera_transf = Transform::identity();
         for entity in query.iter("Camera") {
             camera_transf = query.read::<Transform>(entity);
+            self.camera = Some(self.placement.to_sim(camera_transf.pos));
+        }
+
+        if let Some(PlaceSim { placement }) = io.inbox().last() {
+            match placement.is_valid() {
+                true => self.set_placement(io, placement),
+                false => println!("Ignoring placement out of range: {:?}", placement),
+            }
         }
 
         if let Some(VrUpdate {
@@ -113,76 +445,1406 @@ impl ClientState {
             ..
         }) = io.inbox_first()
         {
+            let world =
+                |controller: &VrController| controller.aim.map(|aim| aim.pos + camera_transf.pos);
+            for (held, controller) in self
+                .triggers
+                .iter_mut()
+                .zip([&left_controller, &right_controller])
+            {
+                for event in &controller.events {
+                    match event {
+                        ControllerEvent::Trigger(ElementState::Pressed) => *held = true,
+                        ControllerEvent::Trigger(ElementState::Released) => *held = false,
+                        _ => (),
+                    }
+                }
+            }
+            let hands = match (
+                self.triggers,
+                world(&left_controller),
+                world(&right_controller),
+            ) {
+                ([true, true], Some(left), Some(right)) => Some([left, right]),
+                _ => None,
+            };
+            if let Some(placement) = self.grab.update(&self.placement, hands) {
+                self.pacer.interacted();
+                self.set_placement(io, placement);
+            }
+
             for (controller, last) in [
                 (left_controller, &mut self.last_left_pos),
                 (right_controller, &mut self.last_right_pos),
             ] {
-                if let Some(aim) = controller.aim {
-                    let pos = aim.pos + camera_transf.pos - SIM_OFFSET;
-
-                    let diff = pos - *last;
-                    let mag = (diff.length() * 48.).powi(2);
+                // No stirring while moving the whole simulation
+                if let Some(world_pos) = world(&controller).filter(|_| !self.grab.is_grabbing()) {
+                    // The push follows the hand's speed in the world, so that stirring feels
+                    // the same at any scale, along its direction in simulation space
+                    let pos = self.placement.to_sim(world_pos);
+                    let diff = pos - self.placement.to_sim(*last);
+                    let speed = world_pos.distance(*last);
+                    let mag = (speed * 48.).powi(2);
+                    if speed > 1e-3 {
+                        self.pacer.interacted();
+                    }
 
-                    self.sim.move_neighbors(pos, diff.normalize() * mag);
-                    *last = pos;
+                    let accel = diff.normalize() * mag;
+                    self.sim.move_neighbors(pos, accel);
+                    self.inputs.record(InputAction::Impulse { pt: pos, accel });
+                }
+                if let Some(world_pos) = world(&controller) {
+                    *last = world_pos;
                 }
 
                 if controller.events.contains(&ControllerEvent::Menu(
                     cimvr_common::vr::ElementState::Released,
                 )) {
-                    self.sim = new_sim_state(io);
+                    self.sim = new_sim_state(io, &mut self.rng);
+                    self.error = None;
+                    // The new simulation is seeded from the engine, so it can't be replayed
+                    self.inputs.interrupt();
+                    self.relax = Relax::default();
                 }
             }
         }
     }
 
     fn update(&mut self, io: &mut EngineIo, _query: &mut QueryResult) {
-        let dt = 1e-3;
-        self.sim.step(dt);
+        // Paused after a failed step, until the simulation is reset
+        if self.error.is_some() {
+            return;
+        }
 
-        let mesh = draw_particles(&self.sim, self.time);
-        io.send(&UploadMesh {
-            mesh,
-            id: SIM_RENDER_ID,
+        if !self.restore_settings(io) {
+            return;
+        }
+
+        // The simulation is held until the machine is measured
+        if let Some(calibration) = &mut self.calibration {
+            let frame_ms = io.inbox_first::<FrameTime>().map(|frame| frame.delta * 1e3);
+            let Some(result) = calibration.tick(frame_ms, &mut self.rng) else {
+                return;
+            };
+            println!("{}", result.report());
+            let config = self.sim.config().clone();
+            self.sim = SimState::new(&mut self.rng, config, result.particles);
+            self.calibration = None;
+        }
+
+        // Live edits, keeping the particles where they are. These may arrive mid-frame, so they
+        // are staged and only swapped in at the start of the next step.
+        if let Some(ConfigUpdate { config }) = io.inbox().last() {
+            self.stage_config(config);
+        }
+        if let Some(ImportClassic { json }) = io.inbox().last() {
+            match SimConfig::from_classic_json(&json) {
+                Ok(config) => self.stage_config(config),
+                Err(e) => println!("Classic rules rejected: {}", e),
+            }
+        }
+        if let Some(command) = io.inbox::<ScaleInteractions>().last() {
+            self.scale_interactions(command);
+        }
+        for ConfigTextError { message } in io.inbox() {
+            println!("Configuration rejected: {}", message);
+        }
+        let commands: Vec<RelaxCommand> = io.inbox().collect();
+        for command in commands {
+            self.relax_command(command);
+        }
+        if io.inbox::<CaptureWorkload>().next().is_some() {
+            match Workload::capture(&self.sim) {
+                Some(workload) => println!("Workload: {}", workload.to_hex()),
+                None => println!("No workload to capture until the next step"),
+            }
+        }
+        if let Some(PrintRotationCurve { core, bins, r_max }) = io.inbox().last() {
+            let center = core
+                .and_then(|core| type_centroid(&self.sim, core))
+                .unwrap_or(self.sim.centroid());
+            let curve = rotation_curve(&self.sim, center, bins.max(1), r_max);
+            println!(
+                "Rotation curve about {}:\n{}",
+                center,
+                write_rotation_curve_csv(&curve)
+            );
+        }
+        if let Some(LoadScenario { source }) = io.inbox().last() {
+            self.load_scenario(source);
+        }
+        if io.inbox::<PrintScenario>().next().is_some() {
+            let scenario = Scenario::capture("Captured", &self.sim, &self.integrator, self.dt);
+            println!("Scenario:\n{}", scenario.to_text());
+        }
+        if let Some(MakeOrbitalPreset { preset }) = io.inbox().last() {
+            let n = self.sim.config().colors.len();
+            if (preset.core as usize) < n && (preset.ring as usize) < n {
+                let speed = self.sim.spawn_orbital_preset(&preset, &mut self.rng);
+                println!("Ring set orbiting at speed {:.3}", speed);
+                // The core is scattered from the client's random stream outside any logged input
+                self.inputs.interrupt();
+            } else {
+                println!(
+                    "The orbital preset needs types {} and {}",
+                    preset.core, preset.ring
+                );
+            }
+        }
+        if let Some(SetClip { clip }) = io.inbox().last() {
+            self.set_clip(io, clip);
+        }
+        if let Some(ShowLegend { show }) = io.inbox().last() {
+            self.show_legend = show;
+        }
+        self.update_legend(io);
+        if let Some(SetColorMode { mode, tint_blend }) = io.inbox().last() {
+            if mode == ColorMode::Residence && self.mesh.color_mode != mode {
+                // Neighborhoods are only followed in this mode, so the ages start over
+                self.residence = Residence::new(self.residence.config.clone());
+            }
+            self.mesh.set_color_mode(mode);
+            self.mesh.tint_blend = tint_blend;
+        }
+        if let Some(ShowHeadingTicks { ticks }) = io.inbox().last() {
+            self.heading_ticks = ticks;
+        }
+        if let Some(SetEchoes { echoes }) = io.inbox().last() {
+            // The layer starts over, as its snapshots were taken at the old stride
+            self.echoes = echoes.map(Echoes::new);
+        }
+        if let Some(ShowAccelCells { budget }) = io.inbox().last() {
+            self.cell_budget = budget;
+            // Redraw at once with the new budget
+            if let Some((_, _, frames)) = &mut self.cells_entity {
+                *frames = 0;
+            }
+        }
+        self.update_cells(io);
+        if let Some(FollowStructure { smoothing }) = io.inbox().last() {
+            self.follow = smoothing.map(Follow::new);
+        }
+        if let Some(command) = io.inbox::<FollowWithBubble>().last() {
+            self.follow_with_bubble(command);
+        }
+        if let Some(SetAutoDt { safety }) = io.inbox().last() {
+            self.set_auto_dt(safety);
+        }
+        if let Some(SetAutoSamples { policy }) = io.inbox().last() {
+            self.set_auto_samples(policy);
+        }
+        if let Some(SetEscapePolicy { escape }) = io.inbox().last() {
+            match escape.radius_factor.is_finite() && escape.radius_factor > 1. {
+                true => self.escape = escape,
+                false => println!("Ignoring escape radius factor {}", escape.radius_factor),
+            }
+        }
+        if let Some(PublishJournal { publish }) = io.inbox().last() {
+            self.publish_journal = publish;
+        }
+        for SetForceEnabled { name, enabled } in io.inbox() {
+            if self.sim.set_force_enabled(&name, enabled) {
+                // Toggles are outside the logged input, so a replay would diverge from here
+                self.inputs.interrupt();
+            } else {
+                println!("No force contributor named {:?}", name);
+            }
+        }
+
+        let commands: Vec<RecordCommand> = io.inbox().collect();
+        for command in commands {
+            self.record_command(command);
+        }
+        let commands: Vec<EnsembleCommand> = io.inbox().collect();
+        for command in commands {
+            self.ensemble_command(command);
+        }
+        let commands: Vec<SweepCommand> = io.inbox().collect();
+        for command in commands {
+            self.sweep_command(command);
+        }
+        let commands: Vec<SlotCommand> = io.inbox().collect();
+        for command in commands {
+            self.slot_command(command);
+        }
+        let commands: Vec<MeasureCommand> = io.inbox().collect();
+        let measured = !commands.is_empty();
+        for command in commands {
+            self.measure_command(command);
+        }
+        if measured {
+            // Shown at once, even while paused
+            self.update_measure(io);
+        }
+
+        let settings = SimSettings {
+            placement: self.placement,
+            ..SimSettings::from_sim(&self.sim)
+        };
+        if let Some(blob) = self.saver.poll(&settings) {
+            io.send(&StoreSettings { blob });
+        }
+
+        if let Some(frame) = io.inbox_first::<FrameTime>() {
+            self.frame_s = frame.delta;
+        }
+
+        let commands: Vec<HelpCommand> = io.inbox().collect();
+        for command in commands {
+            self.help_command(command);
+        }
+        let presses: Vec<KeyPress> = io.inbox().collect();
+        for press in presses {
+            self.key_press(press);
+        }
+
+        if let Some(IntegrateFreeEnergy { to }) = io.inbox().last() {
+            self.integrate_free_energy(to);
+        }
+        if let Some(RunDropletTest { test }) = io.inbox().last() {
+            self.run_droplet_test(test);
+        }
+        // Sampling beside the simulation goes on while it is paused
+        self.advance_thermo();
+        self.advance_droplet();
+
+        if let Some(TwoBodyTest { test }) = io.inbox().last() {
+            self.two_body_test(test);
+        }
+
+        let commands: Vec<StepCommand> = io.inbox().collect();
+        for command in commands {
+            command.apply(&mut self.stepper);
+            // Commanded steps are not throttled as idle
+            self.pacer.interacted();
+        }
+
+        // A paused simulation measures nothing, so its sample count holds. Commanded steps
+        // are only used up on frames the pacer would step on.
+        if !(self.pacer.tick() && self.stepper.tick()) {
+            return;
+        }
+
+        let warning = self
+            .integrator
+            .step_size()
+            .and_then(|step| resolution_warning(&self.sim, step));
+        if warning.is_some() != self.resolution_warned {
+            self.resolution_warned = warning.is_some();
+            println!(
+                "{}",
+                warning.as_deref().unwrap_or("Moves are resolved again")
+            );
+        }
+
+        // Only the explicit integrator has a time step to choose
+        if let (Some(auto_dt), Integrator::Newton(_)) = (&mut self.auto_dt, &self.integrator) {
+            self.dt = auto_dt.dt(&self.sim);
+        }
+        let dt = self.dt;
+
+        let mut samples = None;
+        if let (Some(auto), Integrator::Kinetic(config)) =
+            (&mut self.auto_samples, &mut self.integrator)
+        {
+            if let Some(n) = auto.samples(self.sim.particles().len(), self.frame_s) {
+                config.samples = n;
+            }
+            // Only notable changes are shown, rather than every frame's drift
+            if config.samples.abs_diff(self.samples_shown) * 4 > self.samples_shown {
+                self.samples_shown = config.samples;
+                println!("Kinetic samples per step: {}", config.samples);
+            }
+            samples = Some(config.samples);
+        }
+
+        // Only plain steps move the particles in time, for a two-body test to follow
+        let plain_step = self.ensemble.is_none() && self.sweep.is_none() && self.relax.is_idle();
+        let timer = Timer::start();
+        let stepped = match (&mut self.ensemble, &mut self.sweep, self.relax.is_idle()) {
+            // The simulation holds while the replicas take a step each
+            (Some(ensemble), _, _) => {
+                ensemble.step(&self.integrator, dt, ensemble.replicas().len());
+                Ok(())
+            }
+            // Or while the tiles of a sweep step side by side
+            (None, Some(sweep), _) => {
+                sweep.step(dt);
+                Ok(())
+            }
+            (None, None, true) => {
+                (self.inputs).step(&mut self.sim, &mut self.integrator, dt, &mut self.rng)
+            }
+            (None, None, false) => self.advance_relax(),
+        };
+        if let Err(msg) = stepped {
+            println!("Simulation paused: {}", msg);
+            if let Some(soak) = &self.soak {
+                println!("{}", soak.fail(msg.clone()).report());
+            }
+            self.error = Some(msg);
+            return;
+        }
+        if let (Some(auto), Some(samples)) = (&mut self.auto_samples, samples) {
+            auto.record(samples, timer.elapsed_ms());
+        }
+        match self.inputs.take_outcome() {
+            Some(Ok(())) => println!("Replay finished where the recording did"),
+            Some(Err(err)) => println!("{}", err),
+            None => (),
+        }
+        if let Some(soak) = &mut self.soak {
+            if let Err(failure) = soak.tick(&mut self.sim, &mut self.rng) {
+                println!("{}", failure.report());
+                self.error = Some(failure.violation);
+                return;
+            }
+        }
+        let stats = self.sim.stats();
+        let step_ms = timer.elapsed_ms().zip(stats.accel_ms).map(|(t, a)| t - a);
+        self.profile.record(Phase::AccelRebuild, stats.accel_ms);
+        self.profile.record(Phase::Step, step_ms);
+
+        if self.mesh.color_mode == ColorMode::Residence && self.residence.record(&self.sim) {
+            self.mesh.set_residence(self.residence.levels());
+        }
+
+        let particles_dirty = self.sim.take_particles_dirty();
+        let timer = Timer::start();
+        // Escapees are tested for as the positions are written into the mesh, rather than in
+        // a pass of their own. Replicas and sweeps in view are left alone.
+        let bound = (self.ensemble.is_none() && self.sweep.is_none())
+            .then(|| EscapeBound::estimate(&self.sim, self.escape.radius_factor));
+        let (shown, particles_dirty) = match (&self.ensemble, &self.sweep) {
+            (Some(ensemble), _) => (ensemble.viewed(), true),
+            (None, Some(sweep)) => (sweep.view(), true),
+            (None, None) => (&self.sim, particles_dirty),
+        };
+        let mesh_update = catch_panic(|| match &bound {
+            Some(bound) => (self.mesh).update_finding_escapes(shown, particles_dirty, bound),
+            None => (self.mesh.update(shown, particles_dirty), None),
         });
+        let (mesh_update, escaped) = match mesh_update {
+            Ok(update) => update,
+            Err(msg) => {
+                println!("Simulation paused after a panic: {}", msg);
+                self.mesh = ParticleMesh::default();
+                self.error = Some(msg);
+                return;
+            }
+        };
+        if let (Some(bound), Some(indices)) = (bound, escaped) {
+            let escapes = bound.escapes(indices);
+            self.profile.escaped = escapes.indices.len();
+            // The particles are marked as moved, so the mesh catches up next frame
+            if escapes.handle(&mut self.sim, self.escape.policy) {
+                // Recalls draw from the simulation's random stream outside of any logged input
+                self.inputs.interrupt();
+            }
+        }
+        self.profile.record(Phase::MeshBuild, timer.elapsed_ms());
+        // Sent once the escapees are dealt with, so that it lists them
+        if self.publish_journal {
+            io.send(self.sim.journal());
+        }
+        if mesh_update != MeshUpdate::None {
+            self.sync_chunk_entities(io);
+            self.profile.time(Phase::Send, || {
+                for (chunk, mesh) in self.mesh.meshes().iter().enumerate() {
+                    io.send(&UploadMesh {
+                        mesh: self.placement.scale_mesh(mesh),
+                        id: chunk_handle(chunk),
+                    })
+                }
+            });
+        }
+
+        self.update_echoes(io);
+        self.update_heading_ticks(io);
+        self.update_validation(io, dt, plain_step);
+        self.update_measure(io);
+        self.update_time_bubble(io);
+
+        self.population.record(&self.sim);
+        if let Some(score) = self.highlights.record(&self.sim) {
+            println!("Captured an interesting moment, novelty {:.2}", score);
+        }
+
+        self.profile.particles = self.sim.particles().len();
+        self.profile.neighbor_pairs = stats.neighbor_pairs;
+        self.profile.accel_cells = self.sim.accel_cells();
+        self.profile.rebuild_progress = self.sim.rebuild_progress();
+        self.profile.capped_particles = stats.capped_particles;
+        self.spread.record(&self.sim, dt);
+        self.profile.spread_rate = self.spread.rate() * self.placement.scale;
+
+        // The activity is only worth tracking while following it
+        if let Some(follow) = &mut self.follow {
+            let target = self.activity.record(self.sim.last_accel().0);
+            if let Some(placement) = follow.update(&self.placement, target) {
+                self.set_placement(io, placement);
+            }
+        }
+        if self.profile.tick() && self.debug {
+            self.profile.forces = self.sim.force_status();
+            println!("{}", self.profile.report());
+        }
 
         self.time += dt;
     }
+
+    fn relax_command(&mut self, command: RelaxCommand) {
+        let (sim, integrator, rng) = (&mut self.sim, &mut self.integrator, &mut self.rng);
+        let done = match command {
+            RelaxCommand::Start => {
+                let started = self
+                    .relax
+                    .start(RelaxConfig::default(), sim, integrator, rng);
+                if started {
+                    self.relax_tenths = 0;
+                    // Relaxation sweeps are not in the log, so it can't be replayed past here
+                    self.inputs.interrupt();
+                }
+                started
+            }
+            RelaxCommand::Accept => self.relax.accept(sim, integrator, rng),
+            RelaxCommand::Revert => self.relax.revert(sim, integrator, rng),
+        };
+        if !done {
+            println!("Nothing to do for {:?} at this stage of relaxing", command);
+        }
+    }
+
+    fn record_command(&mut self, command: RecordCommand) {
+        let (sim, integrator, rng) = (&mut self.sim, &mut self.integrator, &mut self.rng);
+        match command {
+            RecordCommand::Record => {
+                let settings = SimSettings {
+                    placement: self.placement,
+                    ..SimSettings::from_sim(sim)
+                };
+                (self.inputs).start_recording(settings, sim, integrator, rng);
+                self.relax = Relax::default();
+                println!("Recording from a fresh start");
+            }
+            RecordCommand::StopRecording => match self.inputs.stop_recording(sim) {
+                Some(log) => println!("Recorded {} steps: {}", log.frames, log.to_hex()),
+                None => println!("Not recording"),
+            },
+            RecordCommand::Replay { hex } => match InputLog::from_hex(&hex) {
+                Ok(log) => {
+                    let frames = log.frames;
+                    (self.inputs).start_replay(log, sim, integrator, rng);
+                    self.relax = Relax::default();
+                    println!("Replaying {} steps", frames);
+                }
+                Err(e) => println!("Cannot replay the log: {}", e),
+            },
+        }
+    }
+
+    fn ensemble_command(&mut self, command: EnsembleCommand) {
+        match (command, &mut self.ensemble) {
+            (EnsembleCommand::Start { replicas }, _) => {
+                let config = EnsembleConfig {
+                    replicas,
+                    ..Default::default()
+                };
+                let (sim_config, particles) =
+                    (self.sim.config().clone(), self.sim.particles().len());
+                let ensemble = Ensemble::new(config, sim_config, particles, &mut self.rng);
+                println!("Started {} replicas", ensemble.replicas().len());
+                self.ensemble = Some(ensemble);
+            }
+            (EnsembleCommand::Select(i), Some(ensemble)) => {
+                ensemble.select(i);
+                println!("Viewing replica {}", ensemble.selected());
+            }
+            (EnsembleCommand::Promote(i), Some(ensemble)) if i < ensemble.replicas().len() => {
+                println!("{}", ensemble.report());
+                self.sim = ensemble.promote(i);
+                self.ensemble = None;
+                self.error = None;
+                // The replica ran on a stream of its own, outside any recording
+                self.inputs.interrupt();
+                self.relax = Relax::default();
+                println!("Continuing from replica {}", i);
+            }
+            (EnsembleCommand::Report, Some(ensemble)) => println!("{}", ensemble.report()),
+            (EnsembleCommand::Stop, Some(_)) => {
+                self.ensemble = None;
+                println!("Ensemble stopped");
+            }
+            (command, _) => println!("Nothing to do for {:?}", command),
+        }
+    }
+
+    fn integrate_free_energy(&mut self, to: Option<SimConfig>) {
+        let Some(to) = to else {
+            match self.thermo.take() {
+                Some(_) => println!("Free energy measurement cancelled"),
+                None => println!("No free energy measurement running"),
+            }
+            return;
+        };
+        let from = self.sim.config().clone();
+        if let Err(e) = to.validate() {
+            println!("Free energy target rejected: {}", e);
+            return;
+        }
+        if to.colors.len() != from.colors.len() {
+            println!(
+                "Free energy target has {} types, but the simulation has {}",
+                to.colors.len(),
+                from.colors.len()
+            );
+            return;
+        }
+
+        let copy = SimState::from_particles(from.clone(), self.sim.particles().to_vec());
+        let ti = ThermoIntegration::new(TiConfig::default(), from, to, copy);
+        // The measurement's stream is drawn from the simulation's, outside the logged input
+        self.inputs.interrupt();
+        self.thermo = Some((ti, fork_rng(&mut self.rng)));
+        self.thermo_tenths = 0;
+        println!("Measuring the free energy difference");
+    }
+
+    /// Run this frame's sweeps of the free energy measurement, reporting progress and the
+    /// result
+    fn advance_thermo(&mut self) {
+        let Some((ti, rng)) = &mut self.thermo else {
+            return;
+        };
+        let result = ti.advance(THERMO_SWEEPS_PER_FRAME, rng);
+        let tenths = (ti.progress() * 10.) as usize;
+        if tenths > self.thermo_tenths && result.is_none() {
+            self.thermo_tenths = tenths;
+            println!("Free energy measurement {}% done", tenths * 10);
+        }
+        if let Some(result) = result {
+            println!("{}", result.report());
+            self.thermo = None;
+        }
+    }
+
+    fn run_droplet_test(&mut self, test: Option<DropletConfig>) {
+        let Some(config) = test else {
+            match self.droplet.take() {
+                Some(_) => println!("Droplet test cancelled"),
+                None => println!("No droplet test running"),
+            }
+            return;
+        };
+        if let Err(e) = config.validate() {
+            return println!("Droplet test rejected: {}", e);
+        }
+
+        // The test's stream is drawn from the simulation's, outside the logged input
+        self.inputs.interrupt();
+        let mut rng = fork_rng(&mut self.rng);
+        let test = DropletTest::new(config, &mut rng);
+        self.droplet = Some((test, rng));
+        self.droplet_tenths = 0;
+        println!("Running the droplet test");
+    }
+
+    /// Run this frame's sweeps of the droplet test, reporting progress and the result
+    fn advance_droplet(&mut self) {
+        let Some((test, rng)) = &mut self.droplet else {
+            return;
+        };
+        let result = test.advance(DROPLET_SWEEPS_PER_FRAME, rng);
+        let tenths = (test.progress() * 10.) as usize;
+        if tenths > self.droplet_tenths && result.is_none() {
+            self.droplet_tenths = tenths;
+            println!("Droplet test {}% done", tenths * 10);
+        }
+        if let Some(result) = result {
+            println!("{}", result.table().trim_end());
+            self.droplet = None;
+        }
+    }
+
+    fn two_body_test(&mut self, test: Option<TwoBody>) {
+        let Some(test) = test else {
+            match self.validation.take() {
+                Some(_) => println!("Two-body test ended"),
+                None => println!("No two-body test running"),
+            }
+            return;
+        };
+        if !test.is_valid(self.sim.config()) {
+            return println!("Ignoring two-body test {:?}", test);
+        }
+
+        self.sim = test.setup(self.sim.config().clone());
+        self.validation = Some(TwoBodyValidation::new(&self.sim));
+        self.error = None;
+        // The test replaces the simulation outside the logged input
+        self.inputs.interrupt();
+        self.relax = Relax::default();
+        if !matches!(self.integrator, Integrator::Newton(_)) {
+            println!("The reference is Newtonian; compare with the explicit integrator");
+        }
+        println!("Started two-body test {:?}", test);
+    }
+
+    /// Advance the two-body reference alongside a plain step, uploading its path and
+    /// printing readouts now and then. Ends the test once the simulation is replaced.
+    fn update_validation(&mut self, io: &mut EngineIo, dt: f32, plain_step: bool) {
+        if self.sim.particles().len() != 2 {
+            self.validation = None;
+        }
+        let Some(validation) = &mut self.validation else {
+            if let Some(entity) = self.validation_entity.take() {
+                io.remove_entity(entity);
+            }
+            return;
+        };
+        if !plain_step {
+            return;
+        }
+
+        validation.step(dt);
+        if validation.steps() % VALIDATION_READOUT_STEPS == 0 {
+            println!("{}", validation.readout(&self.sim).report());
+        }
+        if self.validation_entity.is_none() {
+            let entity = io
+                .create_entity()
+                .add_component(self.placement.transform())
+                .add_component(Render::new(PATH_HANDLE).primitive(Primitive::Lines))
+                .build();
+            self.validation_entity = Some(entity);
+        }
+        io.send(&UploadMesh {
+            mesh: self.placement.scale_mesh(&validation.path_mesh()),
+            id: PATH_HANDLE,
+        });
+    }
+
+    fn sweep_command(&mut self, command: SweepCommand) {
+        match (command, &self.sweep) {
+            (SweepCommand::Start(_), _) if self.ensemble.is_some() => {
+                println!("Stop the ensemble before sweeping")
+            }
+            (SweepCommand::Start(config), _) => self.start_sweep(config),
+            (SweepCommand::Report, Some(sweep)) => {
+                println!("{}", sweep.report(ClusterConfig::default()).trim_end())
+            }
+            (SweepCommand::Stop, Some(_)) => {
+                self.sweep = None;
+                println!("Sweep stopped");
+            }
+            (command, _) => println!("Nothing to do for {:?}", command),
+        }
+    }
+
+    fn start_sweep(&mut self, config: SweepConfig) {
+        let base = self.sim.config();
+        if let Err(e) = config.validate(base) {
+            return println!("Sweep rejected: {}", e);
+        }
+        let per_tile = (self.sim.particles().len() / (config.tiles * config.tiles)).max(1);
+        // The tiles draw their particles from the simulation's stream, outside the logged
+        // input
+        self.inputs.interrupt();
+        let sweep = Sweep::new(&config, base, per_tile, SWEEP_HALF_WIDTH, &mut self.rng);
+        println!(
+            "Sweeping {:?} of {:?}:\n{}",
+            config.field,
+            config.pair,
+            sweep.legend().trim_end()
+        );
+        self.sweep = Some(sweep);
+    }
+
+    /// Run this frame's sweeps of the relaxation, reporting progress and the result. Holds
+    /// the simulation once done.
+    fn advance_relax(&mut self) -> Result<(), String> {
+        let report = (self.relax).advance(&mut self.sim, &mut self.integrator, &mut self.rng)?;
+        let tenths = (self.relax.progress().unwrap_or(0.) * 10.) as usize;
+        if tenths > self.relax_tenths {
+            self.relax_tenths = tenths;
+            if let Some(bar) = self.relax.progress_bar(20) {
+                println!("Relaxing {}", bar);
+            }
+        }
+        if let Some(report) = report {
+            println!("{}; accept to keep it or revert to go back", report);
+        }
+        Ok(())
+    }
+
+    /// Apply the settings stored on the server, once they arrive. Returns false while still
+    /// waiting for them.
+    fn restore_settings(&mut self, io: &mut EngineIo) -> bool {
+        let Some(frames) = self.restore_frames else {
+            return true;
+        };
+
+        if let Some(StoredSettings { blob }) = io.inbox_first() {
+            self.restore_frames = None;
+            match blob.as_deref().map(SimSettings::decode) {
+                Some(Ok(settings)) => {
+                    // The stored particle count was already chosen for this machine
+                    self.sim = settings.build(&mut self.rng);
+                    self.set_placement(io, settings.placement);
+                    self.calibration = None;
+                }
+                Some(Err(e)) => println!("Ignoring stored settings: {}", e),
+                None => (),
+            }
+            return true;
+        }
+
+        if frames == 0 {
+            println!("No stored settings from the server, starting with defaults");
+            self.restore_frames = None;
+            return true;
+        }
+        self.restore_frames = Some(frames - 1);
+        false
+    }
+
+    /// Queue a configuration for the next step, reporting what changes and any warnings
+    fn stage_config(&mut self, config: SimConfig) {
+        // Rejected here rather than pausing the next step
+        if let Err(e) = config.validate() {
+            return println!("Configuration rejected: {}", e);
+        }
+        let live = self.sim.pending_config().unwrap_or(self.sim.config());
+        let diff = live.diff(&config);
+        if !diff.is_empty() {
+            println!("Configuration changed:\n{}", diff.report());
+        }
+        for warning in config.stability_report() {
+            println!("{:?}: {}", warning.severity(), warning);
+        }
+        let change = ConfigChange::between(live, &config);
+        self.sim.stage_config(config);
+        if let Some(change) = change {
+            self.inputs.record(InputAction::Config(change));
+        }
+    }
+
+    fn help_command(&mut self, command: HelpCommand) {
+        let (sim, rng) = (&mut self.sim, &mut self.rng);
+        let touring = self.tour.is_active();
+        match command {
+            HelpCommand::Explain(field) => return println!("{:?}: {}", field, field_help(field)),
+            HelpCommand::ExplainDamping => return println!("Damping: {}", DAMPING_HELP),
+            HelpCommand::StartTour => self.tour.start(sim, rng),
+            HelpCommand::NextStage => self.tour.next(sim, rng),
+            HelpCommand::PreviousStage => self.tour.back(sim, rng),
+            HelpCommand::ExitTour => self.tour.exit(sim),
+        }
+        if !touring && !self.tour.is_active() {
+            return println!("No tour running");
+        }
+        // The tour swaps in demonstrations of its own, outside any recording
+        self.inputs.interrupt();
+        self.relax = Relax::default();
+        match self.tour.stage() {
+            Some(stage) => println!("{}\n{}", stage.title, stage.text),
+            None => println!("Tour over"),
+        }
+    }
+
+    fn measure_command(&mut self, command: MeasureCommand) {
+        match command {
+            MeasureCommand::PlaceRuler(point) if point.is_finite() => {
+                self.ruler.place(point);
+                if let Some(length) = self.ruler.length() {
+                    println!("Ruler length: {:.4}", length);
+                }
+            }
+            MeasureCommand::PlaceRuler(point) => println!("Ignoring ruler point {}", point),
+            MeasureCommand::ClearRuler => self.ruler.clear(),
+            MeasureCommand::PlaceSphere { center, radius } => {
+                let sphere = CountingSphere::new(center, radius);
+                match sphere.is_valid() {
+                    true => self.counting_sphere = Some(sphere),
+                    false => println!("Ignoring counting sphere {:?}", sphere),
+                }
+            }
+            MeasureCommand::ClearSphere => self.counting_sphere = None,
+            MeasureCommand::Report => {
+                match self.ruler.length() {
+                    Some(length) => println!("Ruler length: {:.4}", length),
+                    None => println!("Ruler not placed"),
+                }
+                match &mut self.counting_sphere {
+                    Some(sphere) => {
+                        sphere.update(&self.sim);
+                        println!("{}", sphere.report());
+                    }
+                    None => println!("No counting sphere placed"),
+                }
+            }
+        }
+    }
+
+    /// Recount the counting sphere and upload the measurement overlay, or remove it once
+    /// both tools are cleared
+    fn update_measure(&mut self, io: &mut EngineIo) {
+        if let Some(sphere) = &mut self.counting_sphere {
+            sphere.update(&self.sim);
+        }
+        let mesh = measure_mesh(&self.ruler, self.counting_sphere.as_ref(), self.camera);
+        if mesh.vertices.is_empty() {
+            if let Some(entity) = self.measure_entity.take() {
+                io.remove_entity(entity);
+            }
+            return;
+        }
+
+        if self.measure_entity.is_none() {
+            let entity = io
+                .create_entity()
+                .add_component(self.placement.transform())
+                .add_component(Render::new(MEASURE_HANDLE).primitive(Primitive::Lines))
+                .build();
+            self.measure_entity = Some(entity);
+        }
+        io.send(&UploadMesh {
+            mesh: self.placement.scale_mesh(&mesh),
+            id: MEASURE_HANDLE,
+        });
+    }
+
+    fn slot_command(&mut self, command: SlotCommand) {
+        // Slots keep the configuration the next step will use
+        let live = self
+            .sim
+            .pending_config()
+            .unwrap_or(self.sim.config())
+            .clone();
+        match command {
+            SlotCommand::Save(name) => {
+                let idx = self.slots.save(name, live);
+                println!("Saved to slot {}", idx);
+            }
+            SlotCommand::Load(idx) => match self.slots.load(idx) {
+                Some(config) => self.stage_config(config),
+                None => println!("No slot {}", idx),
+            },
+            SlotCommand::Overwrite(idx) if idx < self.slots.len() => {
+                self.slots.overwrite(idx, live);
+                println!("Overwrote slot {}", idx);
+            }
+            SlotCommand::Delete(idx) if idx < self.slots.len() => self.slots.delete(idx),
+            SlotCommand::Overwrite(idx) | SlotCommand::Delete(idx) => println!("No slot {}", idx),
+            SlotCommand::List if self.slots.is_empty() => println!("No slots saved"),
+            SlotCommand::List => println!("Slots:\n{}", self.slots.listing().trim_end()),
+        }
+    }
+
+    /// Run the shortcut bound to a key, if any
+    fn key_press(&mut self, press: KeyPress) {
+        let mut controls = Controls {
+            sim: &mut self.sim,
+            integrator: &mut self.integrator,
+            dt: &mut self.dt,
+            stepper: &mut self.stepper,
+            debug: &mut self.debug,
+            show_help: &mut self.show_help,
+            slots: &mut self.slots,
+            rng: &mut self.rng,
+        };
+        let Some(action) = self.shortcuts.press(press, &mut controls) else {
+            return;
+        };
+        self.pacer.interacted();
+        match action {
+            Action::TogglePause | Action::StepOnce | Action::ToggleDebug => (),
+            Action::ToggleHelp => {
+                if self.show_help {
+                    println!("Shortcuts:\n{}", self.shortcuts.help());
+                }
+            }
+            // Resets and edits from the shortcuts are outside the logged input
+            _ => self.inputs.interrupt(),
+        }
+    }
+
+    /// Step by a fraction of the stability limit, or hold the current time step with `None`
+    fn set_auto_dt(&mut self, safety: Option<f32>) {
+        match safety {
+            Some(safety) if safety > 0. && safety <= 1. => {
+                let default = AutoDt::default();
+                self.auto_dt = Some(AutoDt::new(safety, default.max_dt, default.interval_frames));
+                println!(
+                    "Time step set to {:.0}% of the stability limit",
+                    safety * 100.
+                );
+            }
+            Some(safety) => println!("Ignoring safety factor {}, outside (0, 1]", safety),
+            None => {
+                self.auto_dt = None;
+                println!("Time step held at {}", self.dt);
+            }
+        }
+    }
+
+    /// Choose the kinetic sample count by `policy`, or hold the current count with `None`
+    fn set_auto_samples(&mut self, policy: Option<SamplesPolicy>) {
+        let valid = match policy {
+            Some(SamplesPolicy::FrameBudget { ms }) => ms.is_finite() && ms > 0.,
+            Some(SamplesPolicy::Rate { per_particle }) => {
+                per_particle.is_finite() && per_particle > 0.
+            }
+            None => true,
+        };
+        if !valid {
+            return println!("Ignoring sample policy {:?}", policy);
+        }
+        let default = AutoSamples::default();
+        self.auto_samples = policy.map(|policy| AutoSamples::new(policy, default.min, default.max));
+        if !matches!(self.integrator, Integrator::Kinetic(_)) {
+            println!("The sample count only applies to the kinetic integrator");
+        }
+    }
+
+    /// Change the interaction scale, which rebuilds the query accelerator at its new radius
+    /// once the configuration is swapped in
+    fn scale_interactions(&mut self, command: ScaleInteractions) {
+        let scale = match command {
+            ScaleInteractions::Set(scale) => scale,
+            ScaleInteractions::ToDensity { target_neighbors } => {
+                match self.sim.interaction_scale_for_density(target_neighbors) {
+                    Some(scale) => scale,
+                    None => return println!("No density to rescale to"),
+                }
+            }
+        };
+        if !scale.is_finite() {
+            return println!("Ignoring interaction scale {}", scale);
+        }
+        let mut config = (self.sim.pending_config())
+            .unwrap_or(self.sim.config())
+            .clone();
+        config.interaction_scale = scale.clamp(MIN_INTERACTION_SCALE, MAX_INTERACTION_SCALE);
+        self.stage_config(config);
+    }
+
+    /// Replace the whole setup with a scenario, or say why not and keep the current one
+    fn load_scenario(&mut self, source: ScenarioSource) {
+        let scenario = match source {
+            ScenarioSource::BuiltIn(name) => {
+                named(&name).ok_or_else(|| ScenarioError::UnknownName(name.clone()))
+            }
+            ScenarioSource::Text(text) => Scenario::from_text(&text),
+        };
+        match scenario.and_then(|scenario| Ok((scenario.apply()?, scenario.name))) {
+            Ok((setup, name)) => {
+                self.sim = setup.sim;
+                self.integrator = setup.integrator;
+                self.dt = setup.dt;
+                self.rng = setup.rng;
+                self.error = None;
+                // The scene starts over from a stream of its own, outside any recording
+                self.inputs.interrupt();
+                self.relax = Relax::default();
+                println!("Loaded scenario {:?}", name);
+            }
+            Err(err) => println!("{}", err),
+        }
+    }
+
+    /// Move the simulation in the world. Render entities follow at once; as the scale is in
+    /// the vertices, a new scale also uploads the meshes again.
+    fn set_placement(&mut self, io: &mut EngineIo, placement: SimPlacement) {
+        let rescaled = placement.scale != self.placement.scale;
+        self.placement = placement;
+
+        let entities = (self.chunk_entities.iter().copied())
+            .chain(self.echo_entity)
+            .chain(self.heading_entity)
+            .chain(self.bubble_entity.map(|(entity, _)| entity))
+            .chain(self.clip_entity.as_ref().map(|(entity, _)| *entity))
+            .chain(self.legend_entity.as_ref().map(|(entity, ..)| *entity))
+            .chain(self.cells_entity.as_ref().map(|(entity, ..)| *entity))
+            .chain(self.validation_entity)
+            .chain(self.measure_entity);
+        for entity in entities {
+            io.add_component(entity, placement.transform());
+        }
+
+        if rescaled {
+            for (chunk, mesh) in self.mesh.meshes().iter().enumerate() {
+                io.send(&UploadMesh {
+                    mesh: placement.scale_mesh(mesh),
+                    id: chunk_handle(chunk),
+                })
+            }
+            if let Some((_, bubble)) = self.bubble_entity {
+                io.send(&UploadMesh {
+                    mesh: placement.scale_mesh(&bubble_mesh(&bubble)),
+                    id: BUBBLE_HANDLE,
+                });
+            }
+            if let Some((_, mesh)) = &self.clip_entity {
+                io.send(&UploadMesh {
+                    mesh: placement.scale_mesh(mesh),
+                    id: CLIP_HANDLE,
+                });
+            }
+            if let Some((_, mesh, _)) = &self.legend_entity {
+                io.send(&UploadMesh {
+                    mesh: placement.scale_mesh(mesh),
+                    id: LEGEND_HANDLE,
+                });
+            }
+            if let Some((_, mesh, _)) = &self.cells_entity {
+                io.send(&UploadMesh {
+                    mesh: placement.scale_mesh(mesh),
+                    id: CELLS_HANDLE,
+                });
+            }
+            if self.measure_entity.is_some() {
+                let mesh = measure_mesh(&self.ruler, self.counting_sphere.as_ref(), self.camera);
+                io.send(&UploadMesh {
+                    mesh: placement.scale_mesh(&mesh),
+                    id: MEASURE_HANDLE,
+                });
+            }
+            if let (Some(_), Some(validation)) = (self.validation_entity, &self.validation) {
+                io.send(&UploadMesh {
+                    mesh: placement.scale_mesh(&validation.path_mesh()),
+                    id: PATH_HANDLE,
+                });
+            }
+        }
+    }
+
+    /// Show the legend of the particle types while enabled, redrawing it when the colors
+    /// change. It is written beside the cloud as it was then, facing the camera.
+    fn update_legend(&mut self, io: &mut EngineIo) {
+        if !self.show_legend {
+            if let Some((entity, ..)) = self.legend_entity.take() {
+                io.remove_entity(entity);
+            }
+            return;
+        }
+        let colors = &self.sim.config().colors;
+        if self
+            .legend_entity
+            .as_ref()
+            .is_some_and(|(.., drawn)| drawn == colors)
+        {
+            return;
+        }
+
+        let (min, max) = (self.sim.particles().iter())
+            .map(|p| p.pos)
+            .filter(|pos| pos.is_finite())
+            .fold((Vec3::splat(-1.), Vec3::splat(1.)), |(min, max), pos| {
+                (min.min(pos), max.max(pos))
+            });
+        let origin = Vec3::new(max.x + LABEL_SIZE * 2., max.y, (min.z + max.z) / 2.);
+        let mesh = legend_mesh(colors, origin, self.camera);
+        let entity = match self.legend_entity.take() {
+            Some((entity, ..)) => entity,
+            None => io
+                .create_entity()
+                .add_component(self.placement.transform())
+                .add_component(Render::new(LEGEND_HANDLE).primitive(Primitive::Lines))
+                .build(),
+        };
+        io.send(&UploadMesh {
+            mesh: self.placement.scale_mesh(&mesh),
+            id: LEGEND_HANDLE,
+        });
+        self.legend_entity = Some((entity, mesh, colors.clone()));
+    }
+
+    /// Outline the query accelerator's cells while enabled, redrawing them every few frames
+    /// rather than every step, as even a budgeted outline is a large mesh
+    fn update_cells(&mut self, io: &mut EngineIo) {
+        let Some(budget) = self.cell_budget else {
+            if let Some((entity, ..)) = self.cells_entity.take() {
+                io.remove_entity(entity);
+            }
+            return;
+        };
+        if let Some((_, _, frames)) = &mut self.cells_entity {
+            if *frames > 0 {
+                *frames -= 1;
+                return;
+            }
+        }
+
+        let outlines = cells_mesh(self.sim.last_accel().0, budget, self.camera);
+        let entity = match self.cells_entity.take() {
+            Some((entity, ..)) => entity,
+            None => io
+                .create_entity()
+                .add_component(self.placement.transform())
+                .add_component(Render::new(CELLS_HANDLE).primitive(Primitive::Lines))
+                .build(),
+        };
+        io.send(&UploadMesh {
+            mesh: self.placement.scale_mesh(&outlines.mesh),
+            id: CELLS_HANDLE,
+        });
+        self.cells_entity = Some((entity, outlines.mesh, CELLS_REDRAW_FRAMES));
+    }
+
+    /// Show a cross-section of the cloud, outlining the plane around where the cloud is now
+    fn set_clip(&mut self, io: &mut EngineIo, clip: Option<ClipPlane>) {
+        let clip = clip.and_then(|clip| {
+            let normal = clip.normal.try_normalize()?;
+            let finite = clip.offset.is_finite() && clip.slab.is_none_or(f32::is_finite);
+            finite.then_some(ClipPlane { normal, ..clip })
+        });
+        self.mesh.set_clip(clip);
+
+        let Some(clip) = clip else {
+            if let Some((entity, _)) = self.clip_entity.take() {
+                io.remove_entity(entity);
+            }
+            return;
+        };
+        let center = self.sim.centroid();
+        let extent = (self.sim.particles().iter())
+            .map(|p| p.pos.distance(center))
+            .filter(|d| d.is_finite())
+            .fold(0.1, f32::max);
+        let mesh = clip_mesh(&clip, center, extent);
+        let entity = match self.clip_entity.take() {
+            Some((entity, _)) => entity,
+            None => io
+                .create_entity()
+                .add_component(self.placement.transform())
+                .add_component(Render::new(CLIP_HANDLE).primitive(Primitive::Lines))
+                .build(),
+        };
+        io.send(&UploadMesh {
+            mesh: self.placement.scale_mesh(&mesh),
+            id: CLIP_HANDLE,
+        });
+        self.clip_entity = Some((entity, mesh));
+    }
+
+    /// Create or remove render entities to match the number of mesh chunks
+    fn sync_chunk_entities(&mut self, io: &mut EngineIo) {
+        let n_chunks = self.mesh.meshes().len();
+        while self.chunk_entities.len() < n_chunks {
+            let handle = chunk_handle(self.chunk_entities.len());
+            let entity = io
+                .create_entity()
+                .add_component(self.placement.transform())
+                .add_component(Render::new(handle).primitive(Primitive::Points))
+                .build();
+            self.chunk_entities.push(entity);
+        }
+        for entity in self.chunk_entities.drain(n_chunks..) {
+            io.remove_entity(entity);
+        }
+    }
+
+    /// Record this step for the echo layer and upload it, or remove it when disabled
+    fn update_echoes(&mut self, io: &mut EngineIo) {
+        let mesh = self.echoes.as_mut().and_then(|echoes| {
+            echoes.record(&self.sim);
+            echoes.mesh(&self.sim)
+        });
+        let Some(mesh) = mesh else {
+            if let Some(entity) = self.echo_entity.take() {
+                io.remove_entity(entity);
+            }
+            return;
+        };
+
+        if self.echo_entity.is_none() {
+            let entity = io
+                .create_entity()
+                .add_component(self.placement.transform())
+                .add_component(Render::new(ECHO_HANDLE).primitive(Primitive::Points))
+                .build();
+            self.echo_entity = Some(entity);
+        }
+        io.send(&UploadMesh {
+            mesh: self.placement.scale_mesh(mesh),
+            id: ECHO_HANDLE,
+        });
+    }
+
+    /// Upload the heading ticks, or remove them when disabled or without headings
+    fn update_heading_ticks(&mut self, io: &mut EngineIo) {
+        let mesh = (self.heading_ticks.as_ref())
+            .map(|config| heading_mesh(&self.sim, config))
+            .filter(|mesh| !mesh.vertices.is_empty());
+        let Some(mesh) = mesh else {
+            if let Some(entity) = self.heading_entity.take() {
+                io.remove_entity(entity);
+            }
+            return;
+        };
+
+        if self.heading_entity.is_none() {
+            let entity = io
+                .create_entity()
+                .add_component(self.placement.transform())
+                .add_component(Render::new(HEADING_HANDLE).primitive(Primitive::Lines))
+                .build();
+            self.heading_entity = Some(entity);
+        }
+        io.send(&UploadMesh {
+            mesh: self.placement.scale_mesh(&mesh),
+            id: HEADING_HANDLE,
+        });
+    }
+
+    fn follow_with_bubble(&mut self, FollowWithBubble { index, bubble }: FollowWithBubble) {
+        if let Some(bubble) = bubble {
+            if !bubble.is_valid() {
+                return println!("Ignoring time bubble {:?}", bubble);
+            }
+            self.sim.set_time_bubble(Some(bubble));
+        }
+        match index {
+            Some(i) if i >= self.sim.particles().len() => {
+                return println!("No particle {} for the time bubble to follow", i)
+            }
+            Some(_) if self.sim.time_bubble().is_none() => {
+                println!("No time bubble to follow with; send one along")
+            }
+            _ => (),
+        }
+        self.bubble_follow = index;
+    }
+
+    /// Keep the time bubble on the particle it follows, and its outline up to date
+    fn update_time_bubble(&mut self, io: &mut EngineIo) {
+        if let (Some(i), Some(bubble)) = (self.bubble_follow, self.sim.time_bubble()) {
+            match self.sim.particles().get(i) {
+                Some(particle) => {
+                    let bubble = TimeBubble {
+                        center: particle.pos,
+                        ..*bubble
+                    };
+                    self.sim.set_time_bubble(Some(bubble));
+                }
+                // Removed, or the simulation was replaced
+                None => self.bubble_follow = None,
+            }
+        }
+
+        let bubble = self.sim.time_bubble().copied();
+        if bubble == self.bubble_entity.map(|(_, drawn)| drawn) {
+            return;
+        }
+        let Some(bubble) = bubble else {
+            if let Some((entity, _)) = self.bubble_entity.take() {
+                io.remove_entity(entity);
+            }
+            return;
+        };
+
+        let entity = match self.bubble_entity {
+            Some((entity, _)) => entity,
+            None => io
+                .create_entity()
+                .add_component(self.placement.transform())
+                .add_component(Render::new(BUBBLE_HANDLE).primitive(Primitive::Lines))
+                .build(),
+        };
+        self.bubble_entity = Some((entity, bubble));
+        io.send(&UploadMesh {
+            mesh: self.placement.scale_mesh(&bubble_mesh(&bubble)),
+            id: BUBBLE_HANDLE,
+        });
+    }
+
+    fn audio_events(&mut self, io: &mut EngineIo, _query: &mut QueryResult) {
+        if let Some(frame) = io.inbox_first::<FrameTime>() {
+            let events: Vec<_> = self
+                .audio
+                .detect(&self.sim, frame.delta)
+                .into_iter()
+                .map(|event| event.to_world(&self.placement))
+                .collect();
+
+            if !events.is_empty() {
+                io.send(&SimAudioEvents { events });
+            }
+        }
+    }
 }
 
 // All state associated with server-side behaviour
-struct ServerState;
+struct ServerState {
+    /// Latest settings blob from a client, kept across client reloads
+    settings: Option<Vec<u8>>,
+    /// Latest configuration, from the settings or a live edit
+    config: Option<SimConfig>,
+}
 
 impl UserState for ServerState {
     // Implement a constructor
-    fn new(_io: &mut EngineIo, _sched: &mut EngineSchedule<Self>) -> Self {
+    fn new(_io: &mut EngineIo, sched: &mut EngineSchedule<Self>) -> Self {
         println!("Hello, server!");
-        Self
+
+        sched
+            .add_system(Self::settings)
+            .subscribe::<StoreSettings>()
+            .subscribe::<LoadSettings>()
+            .subscribe::<SetConfigText>()
+            .subscribe::<GetConfigText>()
+            .build();
+
+        Self {
+            settings: None,
+            config: None,
+        }
     }
 }
 
-// Defines entry points for the engine to hook into.
-// Calls new() for the appropriate state.
-make_app_state!(ClientState, ServerState);
-
-fn draw_particles(sim: &SimState, _time: f32) -> Mesh {
-    let mut vertices = vec![];
-    let indices = (0..sim.particles().len() as u32).collect();
+impl ServerState {
+    fn settings(&mut self, io: &mut EngineIo, _query: &mut QueryResult) {
+        if let Some(StoreSettings { blob }) = io.inbox().last() {
+            if let Ok(settings) = SimSettings::decode(&blob) {
+                self.config = Some(settings.config);
+            }
+            self.settings = Some(blob);
+        }
 
-    for particle in sim.particles().iter() {
-        let color = sim.config().colors[particle.color as usize];
+        let edits: Vec<SetConfigText> = io.inbox().collect();
+        for SetConfigText { text } in edits {
+            match livecode::parse_config(&text) {
+                Ok(config) => {
+                    io.send(&ConfigUpdate {
+                        config: config.clone(),
+                    });
+                    self.config = Some(config);
+                }
+                Err(e) => io.send(&ConfigTextError {
+                    message: e.to_string(),
+                }),
+            }
+        }
 
-        let vertex = Vertex {
-            pos: particle.pos.to_array(),
-            uvw: color,
-        };
+        if io.inbox::<GetConfigText>().next().is_some() {
+            io.send(&ConfigText {
+                text: self.config.as_ref().map(livecode::config_to_text),
+            });
+        }
 
-        vertices.push(vertex);
+        if io.inbox::<LoadSettings>().next().is_some() {
+            io.send(&StoredSettings {
+                blob: self.settings.clone(),
+            });
+        }
     }
-
-    Mesh { vertices, indices }
 }
 
+// Defines entry points for the engine to hook into.
+// Calls new() for the appropriate state.
+make_app_state!(ClientState, ServerState);
+
 /// https://gist.github.com/fairlight1337/4935ae72bcbcc1ba5c72
 fn hsv_to_rgb(h: f32, s: f32, v: f32) -> [f32; 3] {
     let c = v * s; // Chroma
@@ -0,0 +1,129 @@
+//! Live editing of the configuration from outside the client, e.g. by a plugin that pushes a
+//! watched file every few seconds.
+//!
+//! Anything may send [`SetConfigText`] to the server with a configuration in the preset
+//! format, which is [`SimConfig`] as JSON. The server validates it and broadcasts it to the
+//! clients as [`ConfigUpdate`], which they apply while keeping their particles, or
+//! broadcasts a [`ConfigTextError`]. [`GetConfigText`] asks the server for the current
+//! configuration in the same format, answered with [`ConfigText`].
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::sim::{ConfigError, SimConfig};
+
+/// Anyone to server: replace the configuration with this text
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[locality("Remote")]
+pub struct SetConfigText {
+    pub text: String,
+}
+
+/// Anyone to server: reply with the current configuration as text
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[locality("Remote")]
+pub struct GetConfigText;
+
+/// Server to all: the current configuration as text, if the server knows it yet
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[locality("Remote")]
+pub struct ConfigText {
+    pub text: Option<String>,
+}
+
+/// Server to clients: apply this validated configuration
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[locality("Remote")]
+pub struct ConfigUpdate {
+    pub config: SimConfig,
+}
+
+/// Server to all: a submitted configuration was rejected. Messages carry no sender, so
+/// everyone gets to see it.
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[locality("Remote")]
+pub struct ConfigTextError {
+    pub message: String,
+}
+
+/// Parse and validate a configuration in the preset format
+pub fn parse_config(text: &str) -> Result<SimConfig, ConfigError> {
+    let config: SimConfig =
+        serde_json::from_str(text).map_err(|e| ConfigError::Syntax(e.to_string()))?;
+    config.validate()?;
+    Ok(config)
+}
+
+/// Write a configuration in the preset format, laid out for hand editing
+pub fn config_to_text(config: &SimConfig) -> String {
+    serde_json::to_string_pretty(config).expect("Configurations are always serializable")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Behaviour, Gravity, SimState};
+    use cimvr_common::glam::Vec3;
+    use cimvr_engine_interface::pcg::Pcg;
+
+    fn config(n: usize) -> SimConfig {
+        SimConfig {
+            colors: vec![[0.5; 3]; n],
+            behaviours: vec![Behaviour::default(); n * n],
+            damping: 20.,
+            gravity: Some(Gravity {
+                down: Vec3::NEG_Y,
+                weights: vec![1.; n],
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_config_text_round_trip() {
+        let config = config(3);
+        let text = config_to_text(&config);
+        assert_eq!(parse_config(&text), Ok(config));
+    }
+
+    #[test]
+    fn test_config_text_errors() {
+        let err = parse_config("{\"colors\": [").unwrap_err();
+        assert!(matches!(err, ConfigError::Syntax(_)), "{:?}", err);
+        assert!(err.to_string().contains("line 1"), "{}", err);
+
+        let mut wrong_size = config(3);
+        wrong_size.behaviours.pop();
+        let err = parse_config(&config_to_text(&wrong_size)).unwrap_err();
+        assert!(matches!(err, ConfigError::Invalid(_)), "{:?}", err);
+
+        let mut flat = config(2);
+        flat.behaviours[1].anisotropy.y = 0.;
+        assert!(parse_config(&config_to_text(&flat)).is_err());
+
+        let mut empty = config(1);
+        empty.colors.clear();
+        empty.behaviours.clear();
+        assert!(parse_config(&config_to_text(&empty)).is_err());
+    }
+
+    #[test]
+    fn test_update_keeps_particles() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(4), 200);
+        let positions: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+
+        let mut edited = config(2);
+        edited.behaviours[1].inter_max_dist = 0.5;
+        let update = parse_config(&config_to_text(&edited)).unwrap();
+        sim.set_config(update, &mut rng);
+
+        assert_eq!(sim.config(), &edited);
+        assert!(sim.particles().iter().all(|p| p.color < 2));
+        assert!(sim
+            .particles()
+            .iter()
+            .zip(&positions)
+            .all(|(p, &pos)| p.pos == pos));
+        assert!((0..200).all(|i| sim.accel_consistent(i)));
+    }
+}
@@ -0,0 +1,1008 @@
+//! Monte Carlo alternatives to the explicit integrator. Rather than following forces, these
+//! sample positions from the Boltzmann distribution of each particle's potential energy, see
+//! [`SimState::energy_due_to`].
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::sim::{
+    catch_panic, random_direction, FarFieldSampling, RebuildSpec, SimConfig, SimState,
+    VelocityReset,
+};
+
+/// Distance of the centroid from the origin beyond which the Monte Carlo integrators
+/// re-center the simulation, before small moves start rounding away
+pub const RECENTER_DISTANCE: f32 = 1e3;
+
+/// Length of the random nudge given to a heading by a Metropolis proposal, before it is
+/// renormalized
+pub const TURN_STEP: f32 = 0.3;
+
+/// How the simulation is advanced each frame
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum Integrator {
+    /// Explicit integration of the forces, see [`SimState::step`]
+    Newton(NewtonConfig),
+    Metropolis(MetropolisConfig),
+    Kinetic(KineticConfig),
+}
+
+/// How velocities and temperature carry over between the explicit and Monte Carlo
+/// integrators. The Monte Carlo integrators ignore velocities, so they are zeroed on the way
+/// in, rather than left stale for the explicit integrator to pick up later.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SwitchPolicy {
+    /// Start the explicit integrator at rest
+    #[default]
+    Reset,
+    /// Start the explicit integrator with thermal velocities at the Monte Carlo temperature
+    Thermalize,
+    /// Thermalize, and also start the Monte Carlo integrators at the kinetic temperature
+    MatchTemperature,
+}
+
+/// Lowest temperature set by [`SwitchPolicy::MatchTemperature`], for particles at rest
+const MIN_MATCHED_TEMPERATURE: f32 = 1e-6;
+
+impl Default for Integrator {
+    fn default() -> Self {
+        Integrator::Newton(NewtonConfig::default())
+    }
+}
+
+/// Explicit integration, optionally with finer steps for stiff types
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct NewtonConfig {
+    /// Inner steps per frame for each type, see [`SimState::step_substeps`]. Types beyond the
+    /// end take a single step.
+    pub substeps_per_type: Vec<u32>,
+    /// Sample interactions beyond a near radius rather than computing them all
+    pub far_field: Option<FarFieldSampling>,
+}
+
+/// Anyone to client: choose the time step of the explicit integrator from its stability limit,
+/// stepping by `safety` of it, or keep the current step fixed with `None`. See [`AutoDt`].
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct SetAutoDt {
+    pub safety: Option<f32>,
+}
+
+/// Time step of the explicit integrator chosen from its stability limit, see
+/// [`SimState::stable_dt`]. The estimate is redone whenever the configuration changes, and
+/// every `interval_frames` frames as the density evolves.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutoDt {
+    /// Fraction of the stability limit to step by
+    pub safety: f32,
+    /// Longest step taken, for configurations with nothing stiff in them
+    pub max_dt: f32,
+    pub interval_frames: usize,
+    dt: Option<f32>,
+    frames: usize,
+    config: Option<SimConfig>,
+}
+
+impl Default for AutoDt {
+    fn default() -> Self {
+        Self::new(0.2, 2e-3, 300)
+    }
+}
+
+impl AutoDt {
+    pub fn new(safety: f32, max_dt: f32, interval_frames: usize) -> Self {
+        Self {
+            safety,
+            max_dt,
+            interval_frames,
+            dt: None,
+            frames: 0,
+            config: None,
+        }
+    }
+
+    /// Time step for this frame
+    pub fn dt(&mut self, sim: &SimState) -> f32 {
+        self.frames += 1;
+        let stale = self.frames >= self.interval_frames.max(1)
+            || self.config.as_ref() != Some(sim.config());
+        match self.dt {
+            Some(dt) if !stale => dt,
+            _ => {
+                self.frames = 0;
+                self.config = Some(sim.config().clone());
+                let dt = sim.stable_dt(self.safety).min(self.max_dt);
+                self.dt = Some(dt);
+                dt
+            }
+        }
+    }
+
+    /// Last computed time step, for display
+    pub fn current(&self) -> Option<f32> {
+        self.dt
+    }
+}
+
+/// What [`AutoSamples`] aims the kinetic sample count at
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub enum SamplesPolicy {
+    /// Spend about this many milliseconds of each frame moving particles
+    FrameBudget { ms: f32 },
+    /// Attempt this many moves per particle per second, so that the cloud evolves at the
+    /// same rate whatever its size
+    Rate { per_particle: f32 },
+}
+
+/// Anyone to client: choose the kinetic sample count by `policy`, between the default bounds
+/// of [`AutoSamples`], or keep the current count with `None`
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct SetAutoSamples {
+    pub policy: Option<SamplesPolicy>,
+}
+
+/// Number of particles moved by each kinetic step, see [`KineticConfig::samples`], chosen
+/// from a time budget or a rate of moves. Changes are smoothed, so that one slow frame does
+/// not make the next one crawl.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutoSamples {
+    pub policy: SamplesPolicy,
+    pub min: usize,
+    pub max: usize,
+    /// Weight given to the newest target each frame
+    pub smoothing: f32,
+    samples: Option<f32>,
+    /// Smoothed milliseconds per sample
+    cost_ms: Option<f32>,
+}
+
+impl Default for AutoSamples {
+    fn default() -> Self {
+        Self::new(SamplesPolicy::FrameBudget { ms: 8. }, 16, 200_000)
+    }
+}
+
+impl AutoSamples {
+    pub fn new(policy: SamplesPolicy, min: usize, max: usize) -> Self {
+        Self {
+            policy,
+            min,
+            max,
+            smoothing: 0.1,
+            samples: None,
+            cost_ms: None,
+        }
+    }
+
+    /// Sample count for this frame, for `particles` particles over a frame of `frame_s`
+    /// seconds. `None` until a time budget has a measured step to go by, which never happens
+    /// without a clock.
+    pub fn samples(&mut self, particles: usize, frame_s: f32) -> Option<usize> {
+        let target = match self.policy {
+            SamplesPolicy::FrameBudget { ms } => self.cost_ms.map(|cost| ms / cost),
+            SamplesPolicy::Rate { per_particle } => Some(per_particle * particles as f32 * frame_s),
+        };
+        if let Some(target) = target.filter(|t| t.is_finite()) {
+            let target = target.clamp(self.min as f32, self.max.max(self.min) as f32);
+            let samples = self.samples.get_or_insert(target);
+            *samples += (target - *samples) * self.smoothing;
+        }
+        self.current()
+    }
+
+    /// Note that a step of `samples` samples took `ms` milliseconds, if measured
+    pub fn record(&mut self, samples: usize, ms: Option<f32>) {
+        let Some(cost) = ms.filter(|_| samples > 0).map(|ms| ms / samples as f32) else {
+            return;
+        };
+        if !cost.is_finite() || cost <= 0. {
+            return;
+        }
+        let avg = self.cost_ms.get_or_insert(cost);
+        *avg += (cost - *avg) * self.smoothing;
+    }
+
+    /// Sample count in effect, for display
+    pub fn current(&self) -> Option<usize> {
+        self.samples.map(|s| s.round() as usize)
+    }
+}
+
+/// Random walk Metropolis: every particle proposes a move, which is accepted with the
+/// Boltzmann probability of its energy change
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MetropolisConfig {
+    pub temperature: f32,
+    /// Half-width of the cube moves are drawn from
+    pub walk_sigma: f32,
+}
+
+/// Rejection-free kinetic Monte Carlo. Sampled particles always move, choosing among a fixed
+/// set of candidate moves in proportion to their Metropolis rates, and a pseudo-time advances
+/// by the inverse total rate. Keeps evolving at temperatures where almost every Metropolis
+/// proposal would be rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KineticConfig {
+    pub temperature: f32,
+    /// Length of each candidate move
+    pub move_length: f32,
+    /// Candidate moves per particle: the axis directions first, then random directions
+    pub candidates: usize,
+    /// Particles moved per step
+    pub samples: usize,
+}
+
+/// Work done by a Monte Carlo step
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct McmcStats {
+    pub proposals: usize,
+    pub accepted: usize,
+    /// Pseudo-time elapsed during a kinetic step; infinite when every move is prohibitively
+    /// uphill
+    pub pseudo_time: f64,
+}
+
+impl Integrator {
+    /// Advance the simulation, checked and recovering like [`SimState::try_step`]. A staged
+    /// configuration is validated and swapped in first, whichever the integrator, and the
+    /// Monte Carlo integrators then re-center a cloud which drifted beyond
+    /// [`RECENTER_DISTANCE`].
+    pub fn try_step(
+        &self,
+        sim: &mut SimState,
+        dt: f32,
+        rng: &mut Pcg,
+    ) -> Result<McmcStats, String> {
+        if let Some(config) = sim.pending_config() {
+            config
+                .validate()
+                .map_err(|e| format!("Staged configuration rejected: {}", e))?;
+        }
+        catch_panic(|| sim.apply_pending_config())?;
+        let monte_carlo = !matches!(self, Integrator::Newton(_));
+        if monte_carlo && sim.centroid().length() > RECENTER_DISTANCE {
+            sim.recenter();
+        }
+
+        match self {
+            Integrator::Newton(config) => {
+                sim.set_far_field(config.far_field);
+                sim.try_step_substeps(dt, &config.substeps_per_type)
+                    .map(|()| McmcStats::default())
+            }
+            Integrator::Metropolis(config) => {
+                sim.check_step(dt)?;
+                catch_panic(|| metropolis_step(sim, config, rng))
+            }
+            Integrator::Kinetic(config) => {
+                sim.check_step(dt)?;
+                catch_panic(|| kinetic_step(sim, config, rng))
+            }
+        }
+    }
+
+    /// Change to `next`, carrying the state of the simulation across according to `policy`
+    pub fn switch_to(
+        &mut self,
+        mut next: Integrator,
+        policy: SwitchPolicy,
+        sim: &mut SimState,
+        rng: &mut Pcg,
+    ) {
+        let velocities = match (self.temperature(), next.temperature()) {
+            // Monte Carlo to explicit
+            (Some(temperature), None) => match policy {
+                SwitchPolicy::Reset => VelocityReset::Zero,
+                SwitchPolicy::Thermalize | SwitchPolicy::MatchTemperature => {
+                    VelocityReset::Thermalize(temperature)
+                }
+            },
+            // Explicit to Monte Carlo
+            (None, Some(_)) => {
+                if policy == SwitchPolicy::MatchTemperature {
+                    next.set_temperature(sim.kinetic_temperature().max(MIN_MATCHED_TEMPERATURE));
+                }
+                VelocityReset::Zero
+            }
+            _ => VelocityReset::Keep,
+        };
+        let spec = RebuildSpec {
+            velocities,
+            ..Default::default()
+        };
+        sim.rebuild(spec, rng);
+        *self = next;
+    }
+
+    /// Temperature of the Monte Carlo integrators
+    pub fn temperature(&self) -> Option<f32> {
+        match self {
+            Integrator::Newton(_) => None,
+            Integrator::Metropolis(config) => Some(config.temperature),
+            Integrator::Kinetic(config) => Some(config.temperature),
+        }
+    }
+
+    /// Set the temperature of the Monte Carlo integrators; the explicit one has none
+    pub fn set_temperature(&mut self, temperature: f32) {
+        match self {
+            Integrator::Newton(_) => (),
+            Integrator::Metropolis(config) => config.temperature = temperature,
+            Integrator::Kinetic(config) => config.temperature = temperature,
+        }
+    }
+
+    /// Length of the moves proposed by the Monte Carlo integrators
+    pub fn step_size(&self) -> Option<f32> {
+        match self {
+            Integrator::Newton(_) => None,
+            Integrator::Metropolis(config) => Some(config.walk_sigma),
+            Integrator::Kinetic(config) => Some(config.move_length),
+        }
+    }
+}
+
+/// One sweep of Metropolis proposals over all unpinned particles. With polar behaviours,
+/// each particle also proposes turning its heading by up to [`TURN_STEP`].
+pub fn metropolis_step(sim: &mut SimState, config: &MetropolisConfig, rng: &mut Pcg) -> McmcStats {
+    sim.clear_journal();
+    sim.rebuild_accel();
+    let mut stats = McmcStats::default();
+    for i in 0..sim.particles().len() {
+        if sim.pinned()[i] || !picked(sim, i, rng) {
+            continue;
+        }
+
+        let pos = sim.particles()[i].pos;
+        let mut step = Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * 2. - 1.;
+        if sim.constrain_2d() {
+            step.y = 0.;
+        }
+        let proposal = pos + step * config.walk_sigma;
+
+        let delta = sim.energy_due_to(i, proposal) - sim.energy_due_to(i, pos);
+        stats.proposals += 1;
+        if delta <= 0. || rng.gen_f32() < (-delta / config.temperature).exp() {
+            sim.move_particle(i, proposal);
+            sim.record_move_energy(i, delta);
+            stats.accepted += 1;
+        }
+
+        // A nudge of fixed length in a uniform direction makes proposals symmetric
+        let Some(heading) = sim.orientations().map(|o| o[i]) else {
+            continue;
+        };
+        let nudge = random_direction(rng, sim.constrain_2d()) * TURN_STEP;
+        let Some(turned) = (heading + nudge).try_normalize() else {
+            continue;
+        };
+        let delta = sim.heading_energy(i, turned) - sim.heading_energy(i, heading);
+        stats.proposals += 1;
+        if delta <= 0. || rng.gen_f32() < (-delta / config.temperature).exp() {
+            sim.set_orientation(i, turned);
+            sim.record_move_energy(i, delta);
+            stats.accepted += 1;
+        }
+    }
+    stats
+}
+
+/// Move `samples` randomly chosen unpinned particles, each along one of its candidate moves
+pub fn kinetic_step(sim: &mut SimState, config: &KineticConfig, rng: &mut Pcg) -> McmcStats {
+    sim.clear_journal();
+    sim.rebuild_accel();
+    let mut stats = McmcStats::default();
+    let n = sim.particles().len();
+    if n == 0 {
+        return stats;
+    }
+
+    let mut total_rate = 0.;
+    let mut deltas = vec![];
+    let mut log_rates = vec![];
+    for _ in 0..config.samples {
+        let i = rng.gen_u32() as usize % n;
+        if sim.pinned()[i] || !picked(sim, i, rng) {
+            continue;
+        }
+
+        let pos = sim.particles()[i].pos;
+        let energy = sim.energy_due_to(i, pos);
+        let moves = candidate_moves(config, sim.constrain_2d(), rng);
+        deltas.clear();
+        deltas.extend(
+            moves
+                .iter()
+                .map(|&step| sim.energy_due_to(i, pos + step) - energy),
+        );
+        log_rates.clear();
+        log_rates.extend(deltas.iter().map(|d| (-d / config.temperature).min(0.)));
+
+        // Relative to the fastest move, so that rates far below f32 range still compare
+        let max_log = log_rates.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        if max_log == f32::NEG_INFINITY {
+            // Hemmed in by hard spheres on every side
+            stats.proposals += moves.len();
+            continue;
+        }
+        let relative: Vec<f32> = log_rates.iter().map(|r| (r - max_log).exp()).collect();
+        let sum: f32 = relative.iter().sum();
+        total_rate += (max_log as f64).exp() * sum as f64;
+
+        let mut pick = rng.gen_f32() * sum;
+        let chosen = relative
+            .iter()
+            .position(|&r| {
+                pick -= r;
+                pick <= 0.
+            })
+            .unwrap_or(relative.len() - 1);
+
+        sim.move_particle(i, pos + moves[chosen]);
+        sim.record_move_energy(i, deltas[chosen]);
+        stats.proposals += moves.len();
+        stats.accepted += 1;
+    }
+
+    if stats.accepted > 0 {
+        stats.pseudo_time = total_rate.recip();
+    }
+    stats
+}
+
+/// Whether particle `i` takes part in this step, with the probability of its rate of time
+/// (see [`crate::sim::TimeBubble`]). Draws from `rng` only for particles in a bubble.
+fn picked(sim: &SimState, i: usize, rng: &mut Pcg) -> bool {
+    let scale = sim.time_scale(i);
+    scale >= 1. || rng.gen_f32() < scale
+}
+
+/// Displacements of the candidate moves for one particle
+fn candidate_moves(config: &KineticConfig, constrain_2d: bool, rng: &mut Pcg) -> Vec<Vec3> {
+    let axes: &[Vec3] = if constrain_2d {
+        &[Vec3::X, Vec3::NEG_X, Vec3::Z, Vec3::NEG_Z]
+    } else {
+        &[
+            Vec3::X,
+            Vec3::NEG_X,
+            Vec3::Y,
+            Vec3::NEG_Y,
+            Vec3::Z,
+            Vec3::NEG_Z,
+        ]
+    };
+
+    let mut moves: Vec<Vec3> = axes.iter().copied().take(config.candidates).collect();
+    while moves.len() < config.candidates.max(1) {
+        let mut dir = Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * 2. - 1.;
+        if constrain_2d {
+            dir.y = 0.;
+        }
+        moves.push(dir.normalize_or_zero());
+    }
+    moves.iter().map(|&dir| dir * config.move_length).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diagnostics::resolution_warning,
+        sim::{Behaviour, InteractionMode, Particle, SimConfig, TimeBubble},
+        tables::DEFAULT_TABLE_TOLERANCE,
+    };
+
+    /// A particle tethered on top of a pinned one, pushed out to a shell by the repulsive core
+    fn shell() -> SimState {
+        let behav = Behaviour {
+            default_repulse: 10.,
+            inter_threshold: 0.1,
+            inter_strength: 0.,
+            inter_max_dist: 0.1,
+            anisotropy: Vec3::ONE,
+            polarity: 0.,
+            mode: InteractionMode::Potential,
+        };
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![behav],
+            damping: 0.,
+            ..Default::default()
+        };
+        let particle = Particle {
+            pos: Vec3::ZERO,
+            vel: Vec3::ZERO,
+            color: 0,
+        };
+        let mut sim = SimState::from_particles(config, vec![particle; 2]);
+        sim.set_homes_to_current();
+        sim.set_tether_stiffness(400.);
+        sim.set_pinned(0, true);
+        sim.move_particle(1, Vec3::X * 0.05);
+        sim
+    }
+
+    /// Histogram of the distance between the two particles of [`shell`]
+    fn histogram(samples: impl Iterator<Item = (f32, f64)>) -> Vec<f64> {
+        let mut bins = [0.; 10];
+        for (dist, weight) in samples {
+            bins[((dist / 0.01) as usize).min(9)] += weight;
+        }
+        let total: f64 = bins.iter().sum();
+        bins.iter().map(|b| b / total).collect()
+    }
+
+    fn separation(sim: &SimState) -> f32 {
+        sim.particles()[1].pos.length()
+    }
+
+    #[test]
+    fn test_time_bubble_holds_particles() {
+        let mut rng = Pcg::new();
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour::default()],
+            damping: 0.,
+            ..Default::default()
+        };
+        let mut sim = SimState::new(&mut rng, config, 200);
+        let stopped = TimeBubble {
+            center: sim.particles()[0].pos,
+            radius: 0.2,
+            shell: 0.,
+            factor: 0.,
+        };
+        sim.set_time_bubble(Some(stopped));
+        let inside = |pos: Vec3| pos.distance(stopped.center) <= stopped.radius;
+        let before = sim.particles().to_vec();
+
+        let integrator = Integrator::Metropolis(MetropolisConfig {
+            temperature: 1e-2,
+            walk_sigma: 5e-3,
+        });
+        for _ in 0..20 {
+            integrator.try_step(&mut sim, 0., &mut rng).unwrap();
+        }
+        let (mut held, mut moved) = (0, 0);
+        for (a, b) in before.iter().zip(sim.particles()) {
+            if inside(a.pos) {
+                assert_eq!(a.pos, b.pos);
+                held += 1;
+            } else if a.pos != b.pos {
+                moved += 1;
+            }
+        }
+        assert!(held > 0 && moved > 100, "{} {}", held, moved);
+    }
+
+    #[test]
+    fn test_hard_spheres_never_overlap() {
+        let radius = 0.05;
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour {
+                mode: InteractionMode::HardSphere { radius },
+                ..Default::default()
+            }],
+            damping: 0.,
+            ..Default::default()
+        };
+        // On a grid just wider than the spheres, so that many moves are blocked
+        let particles = (0..64)
+            .map(|i| Particle {
+                pos: Vec3::new((i % 8) as f32, (i / 8) as f32, 0.) * radius * 1.1,
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let mut sim = SimState::from_particles(config, particles);
+        let mut rng = Pcg::new();
+        let integrators = [
+            Integrator::Metropolis(MetropolisConfig {
+                temperature: 1.,
+                walk_sigma: 0.02,
+            }),
+            Integrator::Kinetic(KineticConfig {
+                temperature: 1.,
+                move_length: 0.02,
+                candidates: 8,
+                samples: 64,
+            }),
+        ];
+        for integrator in integrators {
+            let mut accepted = 0;
+            for _ in 0..50 {
+                accepted += integrator
+                    .try_step(&mut sim, 0., &mut rng)
+                    .unwrap()
+                    .accepted;
+                let points: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+                for (i, a) in points.iter().enumerate() {
+                    for b in &points[i + 1..] {
+                        assert!(a.distance(*b) >= radius, "{:?}", integrator);
+                    }
+                }
+            }
+            assert!(accepted > 0);
+        }
+    }
+
+    #[test]
+    fn test_metropolis_turns_headings() {
+        let behav = Behaviour {
+            inter_threshold: 0.05,
+            inter_strength: 5.,
+            polarity: 1.,
+            mode: InteractionMode::Potential,
+            ..Default::default()
+        };
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![behav],
+            damping: 0.,
+            ..Default::default()
+        };
+        let particles = [Vec3::ZERO, Vec3::X * 0.05]
+            .map(|pos| Particle {
+                pos,
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .to_vec();
+        let mut sim = SimState::from_particles(config, particles);
+        sim.set_orientation(0, Vec3::Y);
+        sim.set_orientation(1, Vec3::NEG_X + Vec3::Z);
+
+        // Moves too small to matter leave only the headings to settle, head to tail
+        let mut rng = Pcg::new();
+        let config = MetropolisConfig {
+            temperature: 1e-4,
+            walk_sigma: 1e-6,
+        };
+        for _ in 0..2_000 {
+            metropolis_step(&mut sim, &config, &mut rng);
+        }
+        let orient = sim.orientations().unwrap();
+        assert!(orient[0].dot(orient[1]) > 0.98, "{:?}", orient);
+        assert!(orient[0].x.abs() > 0.98, "{:?}", orient);
+    }
+
+    #[test]
+    fn test_kinetic_matches_metropolis() {
+        let mut rng = Pcg::new();
+        let temperature = 0.1;
+
+        let mut sim = shell();
+        let config = MetropolisConfig {
+            temperature,
+            walk_sigma: 0.01,
+        };
+        let metropolis = histogram((0..40_000).map(|_| {
+            metropolis_step(&mut sim, &config, &mut rng);
+            (separation(&sim), 1.)
+        }));
+
+        let mut sim = shell();
+        let config = KineticConfig {
+            temperature,
+            move_length: 0.002,
+            candidates: 6,
+            samples: 1,
+        };
+        // Weighted by the time spent in each state
+        let kinetic = histogram((0..40_000).filter_map(|_| {
+            let dist = separation(&sim);
+            let stats = kinetic_step(&mut sim, &config, &mut rng);
+            (stats.accepted > 0).then_some((dist, stats.pseudo_time))
+        }));
+
+        let difference: f64 = metropolis
+            .iter()
+            .zip(&kinetic)
+            .map(|(a, b)| (a - b).abs())
+            .sum();
+        assert!(difference < 0.15, "{:?} {:?}", metropolis, kinetic);
+    }
+
+    #[test]
+    fn test_recentering_restores_resolution() {
+        // Far apart and far from the origin, without any forces
+        let behav = Behaviour {
+            inter_max_dist: 0.1,
+            ..Behaviour::default()
+        };
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![behav],
+            damping: 0.,
+            ..Default::default()
+        };
+        let particles = (0..200)
+            .map(|i| Particle {
+                pos: Vec3::new(5e4 + i as f32 * 0.2, 0., 0.),
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let mut sim = SimState::from_particles(config, particles);
+        let sigma = 1e-4;
+        let metropolis = MetropolisConfig {
+            temperature: 1.,
+            walk_sigma: sigma,
+        };
+        assert!(resolution_warning(&sim, sigma).is_some());
+
+        let x_variance = |sim: &mut SimState, step: &mut dyn FnMut(&mut SimState)| {
+            let mut sum_sq = 0.;
+            let mut count = 0;
+            for _ in 0..20 {
+                let before: Vec<f32> = sim.particles().iter().map(|p| p.pos.x).collect();
+                step(sim);
+                for (p, x) in sim.particles().iter().zip(before) {
+                    sum_sq += ((p.pos.x - x) as f64).powi(2);
+                    count += 1;
+                }
+            }
+            sum_sq / count as f64
+        };
+
+        // Proposals along X round away entirely
+        let mut rng = Pcg::new();
+        let stuck = x_variance(&mut sim, &mut |sim| {
+            metropolis_step(sim, &metropolis, &mut rng);
+        });
+        assert_eq!(stuck, 0.);
+
+        let integrator = Integrator::Metropolis(metropolis);
+        integrator.try_step(&mut sim, 0., &mut rng).unwrap();
+        assert!(sim.centroid().length() < 1.);
+        assert!(resolution_warning(&sim, sigma).is_none());
+
+        // Uniform steps over [-sigma, sigma] have a variance of sigma^2 / 3
+        let moving = x_variance(&mut sim, &mut |sim| {
+            integrator.try_step(sim, 0., &mut rng).unwrap();
+        });
+        let expected = (sigma as f64).powi(2) / 3.;
+        assert!(
+            (moving / expected - 1.).abs() < 0.1,
+            "{} {}",
+            moving,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_kinetic_evolves_when_metropolis_freezes() {
+        let mut rng = Pcg::new();
+        let temperature = 1e-9;
+
+        let mut sim = shell();
+        let config = MetropolisConfig {
+            temperature,
+            walk_sigma: 0.01,
+        };
+        for _ in 0..2_000 {
+            metropolis_step(&mut sim, &config, &mut rng);
+        }
+        let stats = (0..2_000).fold(McmcStats::default(), |acc, _| {
+            let stats = metropolis_step(&mut sim, &config, &mut rng);
+            McmcStats {
+                proposals: acc.proposals + stats.proposals,
+                accepted: acc.accepted + stats.accepted,
+                ..acc
+            }
+        });
+        assert!(stats.accepted * 1_000 < stats.proposals, "{:?}", stats);
+
+        let config = KineticConfig {
+            temperature,
+            move_length: 0.002,
+            candidates: 6,
+            samples: 1,
+        };
+        let mut moved = 0;
+        for _ in 0..100 {
+            let before = sim.particles()[1].pos;
+            kinetic_step(&mut sim, &config, &mut rng);
+            moved += (sim.particles()[1].pos != before) as usize;
+        }
+        assert!(moved > 25, "{}", moved);
+    }
+
+    #[test]
+    fn test_auto_samples() {
+        // A fake clock: each sample costs 2 µs, give or take 5%
+        let mut rng = Pcg::new();
+        let mut auto = AutoSamples::new(SamplesPolicy::FrameBudget { ms: 8. }, 16, 100_000);
+        assert_eq!(auto.samples(1000, 1. / 60.), None);
+        let mut counts = vec![];
+        let mut samples = 500;
+        for _ in 0..400 {
+            let cost = 2e-3 * (0.95 + rng.gen_f32() * 0.1);
+            auto.record(samples, Some(samples as f32 * cost));
+            samples = auto.samples(1000, 1. / 60.).unwrap();
+            counts.push(samples);
+        }
+        // Converges on the budget, and stays within 10% of it
+        for &count in &counts[150..] {
+            assert!((3_600..=4_400).contains(&count), "{}", count);
+        }
+        let settled = &counts[300..];
+        let mean = settled.iter().sum::<usize>() as f32 / settled.len() as f32;
+        assert!((mean - 4_000.).abs() < 100., "{}", mean);
+
+        // The rate policy scales with the particle count
+        let mut auto = AutoSamples::new(SamplesPolicy::Rate { per_particle: 60. }, 16, 100_000);
+        assert_eq!(auto.samples(100, 0.5), Some(3_000));
+        let mut doubled = auto.clone();
+        for _ in 0..200 {
+            doubled.samples(200, 0.5);
+        }
+        assert_eq!(doubled.current(), Some(6_000));
+
+        // Clamped at both ends
+        let mut tiny = AutoSamples::new(SamplesPolicy::Rate { per_particle: 1. }, 16, 100);
+        assert_eq!(tiny.samples(1, 1e-3), Some(16));
+        let mut huge = AutoSamples::new(SamplesPolicy::Rate { per_particle: 1e6 }, 16, 100);
+        assert_eq!(huge.samples(1000, 1.), Some(100));
+    }
+
+    #[test]
+    fn test_auto_dt_keeps_stiff_config_stable() {
+        // Deep attractive wells around hard cores
+        let mut rng = Pcg::new();
+        let mut config = SimConfig {
+            colors: vec![[1.; 3]; 3],
+            behaviours: vec![Behaviour::default(); 9],
+            damping: 5.,
+            ..Default::default()
+        };
+        for idx in 0..9 {
+            config.randomize_cell(idx, true, &mut rng);
+        }
+        for behav in &mut config.behaviours {
+            behav.default_repulse *= 50.;
+            behav.inter_strength = behav.inter_strength.abs() * 50.;
+        }
+        let particles = SimState::new(&mut rng, config.clone(), 30)
+            .particles()
+            .to_vec();
+
+        // With damping the energy can only fall; a rise is the integrator heating the system
+        let energy = |sim: &SimState| -> f32 {
+            sim.particles()
+                .iter()
+                .enumerate()
+                .map(|(i, p)| p.vel.length_squared() / 2. + sim.energy_due_to(i, p.pos) / 2.)
+                .sum()
+        };
+        let heating = |mut dt: Box<dyn FnMut(&SimState) -> f32>| {
+            let mut sim = SimState::from_particles(config.clone(), particles.clone());
+            let mut lowest = f32::INFINITY;
+            let mut rise: f32 = 0.;
+            for step in 0..5_000 {
+                sim.step(dt(&sim));
+                if step % 10 != 9 {
+                    continue;
+                }
+                let energy = energy(&sim);
+                assert!(energy.is_finite());
+                lowest = lowest.min(energy);
+                rise = rise.max(energy - lowest);
+            }
+            rise / lowest.abs()
+        };
+
+        let mut auto = AutoDt::default();
+        let auto = heating(Box::new(move |sim| auto.dt(sim)));
+        let fixed = heating(Box::new(|_| 2e-3));
+        assert!(auto < 1e-2, "{}", auto);
+        assert!(fixed > 0.5, "{}", fixed);
+    }
+
+    #[test]
+    fn test_switch_policies() {
+        let mut rng = Pcg::new();
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour::default()],
+            damping: 0.,
+            ..Default::default()
+        };
+        let mut sim = SimState::new(&mut rng, config, 5_000);
+        let metropolis = Integrator::Metropolis(MetropolisConfig {
+            temperature: 0.5,
+            walk_sigma: 0.01,
+        });
+        let mean_kinetic = |sim: &SimState| {
+            let total: f32 = sim
+                .particles()
+                .iter()
+                .map(|p| p.vel.length_squared() / 2.)
+                .sum();
+            total / sim.particles().len() as f32
+        };
+
+        // Stale velocities are not carried into the explicit integrator
+        sim.thermalize(3., &mut rng);
+        let mut integrator = metropolis.clone();
+        integrator.switch_to(
+            Integrator::default(),
+            SwitchPolicy::Reset,
+            &mut sim,
+            &mut rng,
+        );
+        assert_eq!(mean_kinetic(&sim), 0.);
+
+        // Equipartition: half the temperature per component
+        let mut integrator = metropolis.clone();
+        integrator.switch_to(
+            Integrator::default(),
+            SwitchPolicy::Thermalize,
+            &mut sim,
+            &mut rng,
+        );
+        assert!((mean_kinetic(&sim) / (1.5 * 0.5) - 1.).abs() < 0.05);
+        assert!((sim.kinetic_temperature() / 0.5 - 1.).abs() < 0.05);
+
+        // And back, continuing at the same temperature with no stale velocities left
+        sim.thermalize(0.2, &mut rng);
+        integrator.switch_to(
+            metropolis.clone(),
+            SwitchPolicy::MatchTemperature,
+            &mut sim,
+            &mut rng,
+        );
+        assert!((integrator.temperature().unwrap() / 0.2 - 1.).abs() < 0.05);
+        assert_eq!(mean_kinetic(&sim), 0.);
+
+        // Other policies keep the configured temperature
+        let mut integrator = Integrator::default();
+        integrator.switch_to(metropolis, SwitchPolicy::Thermalize, &mut sim, &mut rng);
+        assert_eq!(integrator.temperature(), Some(0.5));
+
+        sim.set_constrain_2d(true, &mut rng);
+        sim.thermalize(0.5, &mut rng);
+        assert!(sim.particles().iter().all(|p| p.vel.y == 0.));
+        assert!((mean_kinetic(&sim) / 0.5 - 1.).abs() < 0.05);
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_metropolis_tables() {
+        let mut rng = Pcg::new();
+        let mut config = SimConfig {
+            colors: vec![[1.; 3]; 5],
+            behaviours: vec![Behaviour::default(); 25],
+            damping: 0.,
+            ..Default::default()
+        };
+        for idx in 0..25 {
+            config.randomize_cell(idx, false, &mut rng);
+        }
+        let mut sim = SimState::new(&mut rng, config, 5_000);
+        let config = MetropolisConfig {
+            temperature: 1e-3,
+            walk_sigma: 0.005,
+        };
+
+        for _ in 0..3 {
+            let time = |sim: &mut SimState, rng: &mut Pcg| {
+                let start = std::time::Instant::now();
+                metropolis_step(sim, &config, rng);
+                start.elapsed().as_secs_f32() * 1e3
+            };
+            sim.set_use_tables(None);
+            let analytic_ms = time(&mut sim, &mut rng);
+            sim.set_use_tables(Some(DEFAULT_TABLE_TOLERANCE));
+            let tables_ms = time(&mut sim, &mut rng);
+            println!(
+                "analytic {:.2} ms, tables {:.2} ms, speedup {:.2}x",
+                analytic_ms,
+                tables_ms,
+                analytic_ms / tables_ms
+            );
+        }
+    }
+}
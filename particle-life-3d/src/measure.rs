@@ -0,0 +1,183 @@
+//! Tools for measuring the simulation in place: a ruler between two points, and a sphere
+//! counting the particles of each type inside it. Both stay put until cleared; see
+//! [`crate::render::measure_mesh`] for their overlay.
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::sim::SimState;
+
+/// Anyone to client: place or clear the measurement tools. Points and radii are in the
+/// simulation's frame, see [`crate::placement::SimPlacement::to_sim`].
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub enum MeasureCommand {
+    /// Place the next endpoint of the ruler, see [`Ruler::place`]
+    PlaceRuler(Vec3),
+    ClearRuler,
+    /// Count the particles within a sphere, replacing any counting sphere already placed
+    PlaceSphere {
+        center: Vec3,
+        radius: f32,
+    },
+    ClearSphere,
+    /// Print the ruler's length and the sphere's counts
+    Report,
+}
+
+/// Distance between two placed points
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Ruler {
+    start: Option<Vec3>,
+    end: Option<Vec3>,
+}
+
+impl Ruler {
+    /// Place the next endpoint. Once both are placed, the next point starts a new measurement.
+    pub fn place(&mut self, point: Vec3) {
+        match (self.start, self.end) {
+            (Some(_), None) => self.end = Some(point),
+            _ => {
+                *self = Self {
+                    start: Some(point),
+                    end: None,
+                }
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+
+    pub fn start(&self) -> Option<Vec3> {
+        self.start
+    }
+
+    /// Both endpoints, once placed
+    pub fn endpoints(&self) -> Option<(Vec3, Vec3)> {
+        self.start.zip(self.end)
+    }
+
+    pub fn length(&self) -> Option<f32> {
+        self.endpoints().map(|(a, b)| a.distance(b))
+    }
+
+    /// Distance between tick marks: the power of ten giving between 1 and 10 of them
+    pub fn tick_spacing(&self) -> Option<f32> {
+        let length = self.length().filter(|&l| l > 0.)?;
+        Some(10_f32.powf(length.log10().floor()))
+    }
+}
+
+/// Sphere counting the particles of each type within it, refreshed every frame
+#[derive(Clone, Debug, PartialEq)]
+pub struct CountingSphere {
+    pub center: Vec3,
+    pub radius: f32,
+    counts: Vec<usize>,
+}
+
+impl CountingSphere {
+    pub fn new(center: Vec3, radius: f32) -> Self {
+        Self {
+            center,
+            radius,
+            counts: vec![],
+        }
+    }
+
+    /// Recount the particles inside, see [`SimState::count_in_sphere`]
+    pub fn update(&mut self, sim: &SimState) {
+        self.counts = sim.count_in_sphere(self.center, self.radius);
+    }
+
+    /// Particles of each type inside as of the last update
+    pub fn counts(&self) -> &[usize] {
+        &self.counts
+    }
+
+    pub fn total(&self) -> usize {
+        self.counts.iter().sum()
+    }
+
+    /// Whether the center is finite and the radius finite and positive
+    pub fn is_valid(&self) -> bool {
+        self.center.is_finite() && self.radius.is_finite() && self.radius > 0.
+    }
+
+    /// Total inside, followed by the count of each type
+    pub fn report(&self) -> String {
+        let counts: Vec<String> = (self.counts.iter().enumerate())
+            .map(|(color, count)| format!("type {}: {}", color, count))
+            .collect();
+        format!(
+            "{} particles in the sphere ({})",
+            self.total(),
+            counts.join(", ")
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Behaviour, Particle, SimConfig};
+
+    #[test]
+    fn test_ruler() {
+        let mut ruler = Ruler::default();
+        assert_eq!(ruler.length(), None);
+        ruler.place(Vec3::ZERO);
+        assert_eq!(ruler.length(), None);
+        ruler.place(Vec3::new(0.3, 0.4, 0.));
+        assert!((ruler.length().unwrap() - 0.5).abs() < 1e-6);
+        assert!((ruler.tick_spacing().unwrap() - 0.1).abs() < 1e-6);
+
+        // A third point starts over
+        ruler.place(Vec3::X);
+        assert_eq!(ruler.start(), Some(Vec3::X));
+        assert_eq!(ruler.endpoints(), None);
+        ruler.place(Vec3::X * 26.);
+        assert_eq!(ruler.tick_spacing(), Some(10.));
+
+        ruler.clear();
+        assert_eq!(ruler, Ruler::default());
+    }
+
+    #[test]
+    fn test_counting_sphere() {
+        let config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 0.,
+            ..Default::default()
+        };
+        let particles = [(0., 0), (0.05, 1), (0.09, 1), (0.5, 0)]
+            .map(|(x, color)| Particle {
+                pos: Vec3::X * x,
+                vel: Vec3::ZERO,
+                color,
+            })
+            .to_vec();
+        let mut sim = SimState::from_particles(config, particles);
+
+        let mut sphere = CountingSphere::new(Vec3::ZERO, 0.1);
+        sphere.update(&sim);
+        assert_eq!(sphere.counts(), [1, 2]);
+        assert_eq!(
+            sphere.report(),
+            "3 particles in the sphere (type 0: 1, type 1: 2)"
+        );
+        assert!(sphere.is_valid());
+        assert!(!CountingSphere::new(Vec3::ZERO, 0.).is_valid());
+
+        // Follows the particles from frame to frame
+        sim.remove_in_sphere(Vec3::X * 0.05, 0.01, true);
+        sphere.update(&sim);
+        assert_eq!(sphere.counts(), [1, 1]);
+        sphere.radius = 1.;
+        sphere.update(&sim);
+        assert_eq!(sphere.total(), 3);
+    }
+}
@@ -0,0 +1,89 @@
+//! How separations between particles are measured. Positions are never wrapped by the
+//! simulation itself; a toroidal metric only changes which image of each particle is felt,
+//! so structures are translation invariant while positions render unwrapped.
+use cimvr_common::glam::Vec3;
+use serde::{Deserialize, Serialize};
+
+/// Metric used wherever the separation of two particles is computed: forces, energies,
+/// neighbor queries and the diagnostics built on them
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum DistanceMetric {
+    /// Plain straight-line separation
+    #[default]
+    Euclidean,
+    /// Separation to the nearest periodic image, in a box repeating every `period` along
+    /// each axis. The period should be at least twice the interaction radius, or a pair
+    /// could interact through more than one image.
+    Toroidal { period: Vec3 },
+}
+
+impl DistanceMetric {
+    /// Period of the box, if toroidal
+    pub fn period(&self) -> Option<Vec3> {
+        match *self {
+            DistanceMetric::Euclidean => None,
+            DistanceMetric::Toroidal { period } => Some(period),
+        }
+    }
+
+    /// Vector from `from` to the nearest image of `to`
+    pub fn separation(&self, from: Vec3, to: Vec3) -> Vec3 {
+        match *self {
+            DistanceMetric::Euclidean => to - from,
+            DistanceMetric::Toroidal { period } => min_image(to - from, period),
+        }
+    }
+
+    pub fn distance_squared(&self, a: Vec3, b: Vec3) -> f32 {
+        self.separation(a, b).length_squared()
+    }
+
+    pub fn distance(&self, a: Vec3, b: Vec3) -> f32 {
+        self.separation(a, b).length()
+    }
+
+    /// Image of `p` within the box from the origin to the period, or `p` itself if Euclidean
+    pub fn wrap(&self, p: Vec3) -> Vec3 {
+        match *self {
+            DistanceMetric::Euclidean => p,
+            DistanceMetric::Toroidal { period } => wrap(p, period),
+        }
+    }
+}
+
+/// Shortest of the periodic images of `diff`
+pub fn min_image(diff: Vec3, period: Vec3) -> Vec3 {
+    diff - (diff / period).round() * period
+}
+
+/// Image of `p` within the box from the origin to `period`
+pub fn wrap(p: Vec3, period: Vec3) -> Vec3 {
+    Vec3::new(
+        p.x.rem_euclid(period.x),
+        p.y.rem_euclid(period.y),
+        p.z.rem_euclid(period.z),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_toroidal_separation() {
+        let torus = DistanceMetric::Toroidal {
+            period: Vec3::new(1., 2., 4.),
+        };
+        let (a, b) = (Vec3::new(0.05, 0., 0.), Vec3::new(0.95, 1.9, -3.9));
+        assert!(torus.separation(a, b).distance(Vec3::new(-0.1, -0.1, 0.1)) < 1e-5);
+        assert!((torus.distance(a, b) - 0.03_f32.sqrt()).abs() < 1e-5);
+        assert_eq!(DistanceMetric::Euclidean.separation(a, b), b - a);
+
+        // Unwrapped positions many periods out are as near as their images
+        let far = b + Vec3::new(7., -6., 12.);
+        assert!(torus.separation(a, far).distance(torus.separation(a, b)) < 1e-4);
+        let wrapped = torus.wrap(far);
+        assert!(wrapped.cmpge(Vec3::ZERO).all() && wrapped.cmple(Vec3::new(1., 2., 4.)).all());
+        assert!(wrapped.distance(Vec3::new(0.95, 1.9, 0.1)) < 1e-4);
+    }
+}
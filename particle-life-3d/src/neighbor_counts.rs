@@ -0,0 +1,129 @@
+//! Number of neighbors of each particle within a few radii, shared by everything which needs
+//! it, so that one sweep over the query accelerator serves them all.
+use cimvr_common::glam::Vec3;
+
+use crate::query_accel::QueryAccelerator;
+
+/// Neighbor counts of every particle within each registered radius. Radii stay registered
+/// once asked for, and the counts of all of them are refreshed together, lazily, by the first
+/// consumer to ask after [`NeighborCountCache::invalidate`].
+#[derive(Clone, Debug, Default)]
+pub struct NeighborCountCache {
+    /// Registered radii, ascending
+    radii: Vec<f32>,
+    /// Count of each particle within each radius, indexed like `radii`
+    counts: Vec<Vec<u32>>,
+    fresh: bool,
+    /// Number of sweeps over the accelerator so far
+    sweeps: usize,
+}
+
+impl NeighborCountCache {
+    /// Count within `radius` from now on. Registering a new radius marks the counts stale.
+    pub fn register(&mut self, radius: f32) {
+        if let Err(k) = self.radii.binary_search_by(|r| r.total_cmp(&radius)) {
+            self.radii.insert(k, radius);
+            self.counts.insert(k, vec![]);
+            self.fresh = false;
+        }
+    }
+
+    /// Mark the counts stale, e.g. because the particles moved
+    pub fn invalidate(&mut self) {
+        self.fresh = false;
+    }
+
+    pub fn is_fresh(&self) -> bool {
+        self.fresh
+    }
+
+    pub fn radii(&self) -> &[f32] {
+        &self.radii
+    }
+
+    /// Number of sweeps over the accelerator so far
+    pub fn sweeps(&self) -> usize {
+        self.sweeps
+    }
+
+    /// Recount every registered radius unless fresh, in a single query of each particle's
+    /// neighbors out to the largest radius, sorted into buckets by distance
+    pub fn refresh(&mut self, accel: &QueryAccelerator, points: &[Vec3]) {
+        if self.fresh {
+            return;
+        }
+        let Some(&largest) = self.radii.last() else {
+            self.fresh = true;
+            return;
+        };
+
+        let radii_sq: Vec<f32> = self.radii.iter().map(|r| r * r).collect();
+        for counts in &mut self.counts {
+            counts.clear();
+        }
+        let metric = accel.metric();
+        let mut buckets = vec![0u32; self.radii.len()];
+        for (i, &point) in points.iter().enumerate() {
+            buckets.iter_mut().for_each(|b| *b = 0);
+            for j in accel.query_neighbors_radius(points, i, point, largest) {
+                let dist_sq = metric.distance_squared(point, points[j]);
+                buckets[radii_sq.partition_point(|&r_sq| r_sq < dist_sq)] += 1;
+            }
+            let mut within = 0;
+            for (counts, bucket) in self.counts.iter_mut().zip(&buckets) {
+                within += bucket;
+                counts.push(within);
+            }
+        }
+        self.fresh = true;
+        self.sweeps += 1;
+    }
+
+    /// Neighbor count of each particle within `radius`, if registered and fresh
+    pub fn counts(&self, radius: f32) -> Option<&[u32]> {
+        let k = self.radii.binary_search_by(|r| r.total_cmp(&radius)).ok()?;
+        self.fresh.then(|| self.counts[k].as_slice())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cimvr_engine_interface::pcg::Pcg;
+
+    #[test]
+    fn test_counts_match_brute_force() {
+        let mut rng = Pcg::new();
+        let points: Vec<Vec3> = (0..1500)
+            .map(|_| Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()))
+            .collect();
+        let accel = QueryAccelerator::new(&points, 0.1);
+
+        let mut cache = NeighborCountCache::default();
+        // Smaller than, equal to, and larger than the accelerator's radius
+        let radii = [0.1, 0.03, 0.25];
+        for radius in radii {
+            cache.register(radius);
+        }
+        cache.register(0.1);
+        assert_eq!(cache.radii(), [0.03, 0.1, 0.25]);
+        assert!(cache.counts(0.1).is_none());
+
+        cache.refresh(&accel, &points);
+        cache.refresh(&accel, &points);
+        assert_eq!(cache.sweeps(), 1);
+        for radius in radii {
+            let counts = cache.counts(radius).unwrap();
+            for (i, &point) in points.iter().enumerate() {
+                let brute = (points.iter().enumerate())
+                    .filter(|&(j, p)| j != i && p.distance_squared(point) <= radius * radius)
+                    .count();
+                assert_eq!(counts[i] as usize, brute);
+            }
+        }
+        assert!(cache.counts(0.2).is_none());
+
+        cache.invalidate();
+        assert!(cache.counts(0.1).is_none());
+    }
+}
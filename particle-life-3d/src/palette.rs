@@ -0,0 +1,306 @@
+//! Colorblind-safe palettes, and simulated color vision deficiencies for checking custom ones.
+//! Also the color ramps of matrix heatmaps.
+use cimvr_common::glam::{Mat3, Vec3};
+
+use crate::sim::{Field, SimConfig};
+
+/// Okabe-Ito palette, in its usual order
+pub const OKABE_ITO: [u32; 8] = [
+    0x000000, // Black
+    0xE69F00, // Orange
+    0x56B4E9, // Sky blue
+    0x009E73, // Bluish green
+    0xF0E442, // Yellow
+    0x0072B2, // Blue
+    0xD55E00, // Vermillion
+    0xCC79A7, // Reddish purple
+];
+
+/// Stops of the viridis color map, evenly spaced
+const VIRIDIS: [u32; 5] = [0x440154, 0x3B528B, 0x21918C, 0x5EC962, 0xFDE725];
+
+/// Ends of the diverging color map, for negative and positive values; zero is white
+const DIVERGING_NEGATIVE: u32 = 0xB2182B;
+const DIVERGING_POSITIVE: u32 = 0x2166AC;
+
+/// Palettes whose colors stay distinguishable with common color vision deficiencies
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Palette {
+    /// Up to eight categorical colors; black comes last, being hardest to see on a dark
+    /// background. Repeats beyond eight types.
+    OkabeIto,
+    /// Ramp from dark purple to yellow, which also reads well in grayscale
+    Viridis,
+}
+
+/// Color vision to simulate when previewing a palette
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default)]
+pub enum ColorVision {
+    #[default]
+    Normal,
+    /// Missing long-wavelength (red) cones
+    Protan,
+    /// Missing medium-wavelength (green) cones
+    Deutan,
+    /// Missing short-wavelength (blue) cones
+    Tritan,
+}
+
+impl Palette {
+    /// Colors for `n` types
+    pub fn colors(&self, n: usize) -> Vec<[f32; 3]> {
+        match self {
+            Palette::OkabeIto => (0..n)
+                .map(|i| hex_color(OKABE_ITO[(i + 1) % OKABE_ITO.len()]))
+                .collect(),
+            Palette::Viridis => (0..n)
+                .map(|i| viridis(i as f32 / (n.max(2) - 1) as f32))
+                .collect(),
+        }
+    }
+}
+
+impl ColorVision {
+    pub const ALL: [ColorVision; 4] = [
+        ColorVision::Normal,
+        ColorVision::Protan,
+        ColorVision::Deutan,
+        ColorVision::Tritan,
+    ];
+
+    /// Approximate the color as seen with this color vision, using the full severity matrices
+    /// of Machado et al. (2009)
+    pub fn simulate(&self, color: [f32; 3]) -> [f32; 3] {
+        let rows = match self {
+            ColorVision::Normal => return color,
+            ColorVision::Protan => [
+                [0.152286, 1.052583, -0.204868],
+                [0.114503, 0.786281, 0.099216],
+                [-0.003882, -0.048116, 1.051998],
+            ],
+            ColorVision::Deutan => [
+                [0.367322, 0.860646, -0.227968],
+                [0.280085, 0.672501, 0.047413],
+                [-0.011820, 0.042940, 0.968881],
+            ],
+            ColorVision::Tritan => [
+                [1.255528, -0.076749, -0.178779],
+                [-0.078411, 0.930809, 0.147602],
+                [0.004733, 0.691367, 0.303900],
+            ],
+        };
+        let matrix = Mat3::from_cols_array_2d(&rows).transpose();
+        (matrix * Vec3::from(color))
+            .clamp(Vec3::ZERO, Vec3::ONE)
+            .to_array()
+    }
+}
+
+/// Index of the color in `palette` closest to `color` in RGB space, if there are any
+pub fn nearest_color(color: [f32; 3], palette: &[[f32; 3]]) -> Option<usize> {
+    let color = Vec3::from(color);
+    (0..palette.len()).min_by(|&a, &b| {
+        let distance = |i: usize| Vec3::from(palette[i]).distance_squared(color);
+        distance(a).total_cmp(&distance(b))
+    })
+}
+
+/// Color from a 0xRRGGBB value
+pub fn hex_color(hex: u32) -> [f32; 3] {
+    [16, 8, 0].map(|shift| ((hex >> shift) & 0xff) as f32 / 255.)
+}
+
+/// Viridis color map at `t` in `0.0..=1.0`, interpolated between its stops
+pub fn viridis(t: f32) -> [f32; 3] {
+    let x = t.clamp(0., 1.) * (VIRIDIS.len() - 1) as f32;
+    let i = (x as usize).min(VIRIDIS.len() - 2);
+    let (a, b) = (hex_color(VIRIDIS[i]), hex_color(VIRIDIS[i + 1]));
+    let f = x - i as f32;
+    [0, 1, 2].map(|c| a[c] + (b[c] - a[c]) * f)
+}
+
+/// Diverging color map at `t` in `-1.0..=1.0`: red through white at zero to blue
+pub fn diverging(t: f32) -> [f32; 3] {
+    let t = t.clamp(-1., 1.);
+    let end = match t < 0. {
+        true => hex_color(DIVERGING_NEGATIVE),
+        false => hex_color(DIVERGING_POSITIVE),
+    };
+    end.map(|c| 1. + (c - 1.) * t.abs())
+}
+
+/// Background colors for the cells of a matrix, scaled to the range of its values, as a
+/// heatmap to read the matrix at a glance
+#[derive(Clone, Debug, PartialEq)]
+pub struct Heatmap {
+    /// Color of each cell, in the order of the values
+    pub colors: Vec<[f32; 3]>,
+    /// Smallest and largest value, the range of the legend
+    pub min: f32,
+    pub max: f32,
+    /// Whether values are colored by sign with [`diverging`] rather than with [`viridis`]
+    pub diverging: bool,
+}
+
+impl Heatmap {
+    /// Diverging heatmaps are symmetric about zero, which stays white, and scaled by the
+    /// largest magnitude. Sequential ones run from the smallest value to the largest. Values
+    /// which are all the same are colored zero or the middle of the ramp respectively.
+    pub fn new(values: &[f32], diverging: bool) -> Self {
+        let finite = values.iter().copied().filter(|v| v.is_finite());
+        let (min, max) = finite.fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), v| {
+            (min.min(v), max.max(v))
+        });
+        let (min, max) = if min <= max { (min, max) } else { (0., 0.) };
+        let mut heatmap = Self {
+            colors: vec![],
+            min,
+            max,
+            diverging,
+        };
+        heatmap.colors = values.iter().map(|&v| heatmap.color(v)).collect();
+        heatmap
+    }
+
+    /// Heatmap of `field` over the behaviour matrix, diverging for the interaction strength,
+    /// whose sign says whether types attract or repel
+    pub fn of_field(config: &SimConfig, field: Field) -> Self {
+        let values: Vec<f32> = config.behaviours.iter().map(|b| field.get(b)).collect();
+        Self::new(&values, field == Field::Strength)
+    }
+
+    /// Color of `value` on this heatmap's scale
+    pub fn color(&self, value: f32) -> [f32; 3] {
+        if self.diverging {
+            let scale = self.min.abs().max(self.max.abs());
+            match scale > 0. {
+                true => diverging(value / scale),
+                false => diverging(0.),
+            }
+        } else {
+            let range = self.max - self.min;
+            match range > 0. {
+                true => viridis((value - self.min) / range),
+                false => viridis(0.5),
+            }
+        }
+    }
+
+    /// `n` evenly spaced values from the smallest to the largest, with their colors
+    pub fn legend(&self, n: usize) -> Vec<(f32, [f32; 3])> {
+        (0..n)
+            .map(|i| {
+                let t = i as f32 / (n.max(2) - 1) as f32;
+                let value = self.min + (self.max - self.min) * t;
+                (value, self.color(value))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: [f32; 3], b: [f32; 3]) {
+        assert!(
+            a.iter().zip(&b).all(|(x, y)| (x - y).abs() < 1e-5),
+            "{:?} {:?}",
+            a,
+            b
+        );
+    }
+
+    #[test]
+    fn test_palettes() {
+        let colors = Palette::OkabeIto.colors(9);
+        assert_close(colors[0], [230. / 255., 159. / 255., 0.]);
+        assert_close(colors[4], [0., 114. / 255., 178. / 255.]);
+        assert_close(colors[7], [0.; 3]);
+        assert_eq!(colors[8], colors[0]);
+
+        let ramp = Palette::Viridis.colors(3);
+        assert_close(ramp[0], hex_color(0x440154));
+        assert_close(ramp[1], hex_color(0x21918C));
+        assert_close(ramp[2], hex_color(0xFDE725));
+        assert_eq!(Palette::Viridis.colors(1).len(), 1);
+    }
+
+    #[test]
+    fn test_color_vision() {
+        // Each matrix maps pure red to its first column
+        assert_close(
+            ColorVision::Deutan.simulate([1., 0., 0.]),
+            [0.367322, 0.280085, 0.],
+        );
+        assert_close(
+            ColorVision::Tritan.simulate([0., 1., 0.]),
+            [0., 0.930809, 0.691367],
+        );
+        for vision in ColorVision::ALL {
+            let gray = vision.simulate([0.5; 3]);
+            assert!(gray.iter().all(|c| (c - 0.5).abs() < 1e-3), "{:?}", gray);
+        }
+        assert_eq!(
+            ColorVision::Normal.simulate([0.2, 0.4, 0.6]),
+            [0.2, 0.4, 0.6]
+        );
+
+        // Red and green, which deuteranopes confuse, end up much closer together
+        let (red, green) = ([0.8, 0.2, 0.1], [0.3, 0.6, 0.1]);
+        let distance = |a: [f32; 3], b: [f32; 3]| Vec3::from(a).distance(Vec3::from(b));
+        let seen = |c| ColorVision::Deutan.simulate(c);
+        assert!(distance(seen(red), seen(green)) < distance(red, green) * 0.4);
+    }
+
+    #[test]
+    fn test_diverging() {
+        assert_close(diverging(-1.), hex_color(DIVERGING_NEGATIVE));
+        assert_close(diverging(1.), hex_color(DIVERGING_POSITIVE));
+        assert_close(diverging(0.), [1.; 3]);
+        assert_close(diverging(-5.), diverging(-1.));
+        let half = diverging(0.5);
+        let blue = hex_color(DIVERGING_POSITIVE);
+        assert_close(half, [0, 1, 2].map(|c| (1. + blue[c]) / 2.));
+    }
+
+    #[test]
+    fn test_heatmap() {
+        let heatmap = Heatmap::new(&[-2., 0., 4., 1.], true);
+        assert_eq!((heatmap.min, heatmap.max), (-2., 4.));
+        assert_close(heatmap.colors[1], [1.; 3]);
+        assert_close(heatmap.colors[2], diverging(1.));
+        assert_close(heatmap.colors[0], diverging(-0.5));
+
+        let heatmap = Heatmap::new(&[0.1, 0.3, 0.2], false);
+        assert_close(heatmap.colors[0], viridis(0.));
+        assert_close(heatmap.colors[1], viridis(1.));
+        assert_close(heatmap.colors[2], viridis(0.5));
+        let legend = heatmap.legend(3);
+        assert!((legend[1].0 - 0.2).abs() < 1e-6);
+        assert_close(legend[2].1, viridis(1.));
+
+        // Equal values, and no values, must not divide by zero
+        for diverging in [false, true] {
+            let flat = Heatmap::new(&[3.; 4], diverging);
+            assert!(flat.colors.iter().flatten().all(|c| c.is_finite()));
+            assert!(flat.colors.windows(2).all(|w| w[0] == w[1]));
+            let legend = Heatmap::new(&[], diverging).legend(2);
+            assert!(legend
+                .iter()
+                .all(|(v, c)| v.is_finite() && c[0].is_finite()));
+        }
+        assert_close(Heatmap::new(&[0.; 4], true).colors[0], [1.; 3]);
+        assert_close(Heatmap::new(&[3.; 4], false).colors[0], viridis(0.5));
+    }
+
+    #[test]
+    fn test_nearest_color() {
+        let palette = [[1., 0., 0.], [0., 1., 0.], [0., 0., 1.], [1., 1., 0.]];
+        assert_eq!(nearest_color([0.9, 0.1, 0.], &palette), Some(0));
+        assert_eq!(nearest_color([0.2, 0.3, 0.9], &palette), Some(2));
+        // Orange is closer to yellow than to red
+        assert_eq!(nearest_color([1., 0.6, 0.], &palette), Some(3));
+        assert_eq!(nearest_color([0.; 3], &[]), None);
+    }
+}
@@ -0,0 +1,363 @@
+//! Settings persistence across client plugin reloads.
+//!
+//! The server outlives client reloads, so the client keeps its latest settings there. On
+//! startup the client asks for them with [`LoadSettings`], and the server answers with
+//! [`StoredSettings`]. Afterwards the client sends [`StoreSettings`] whenever the settings
+//! change, and every so often regardless.
+//!
+//! Blobs start with a little endian `u32` version, followed by the bincode encoded
+//! [`SimSettings`]. Blobs of any other version are skipped rather than misread.
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    placement::SimPlacement,
+    sim::{Particle, RebuildSpec, SimConfig, SimState},
+};
+
+/// Version of the blob layout; bump when [`SimSettings`] changes
+pub const SETTINGS_VERSION: u32 = 9;
+
+/// Version of the snapshot blob layout
+const SNAPSHOT_VERSION: u32 = 8;
+
+/// Largest particle count accepted from a blob
+const MAX_PARTICLES: usize = 10_000_000;
+
+/// Client to server: keep this blob
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[locality("Remote")]
+pub struct StoreSettings {
+    pub blob: Vec<u8>,
+}
+
+/// Client to server: reply with the stored blob
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[locality("Remote")]
+pub struct LoadSettings;
+
+/// Server to client: the latest stored blob, if any
+#[derive(Message, Serialize, Deserialize, Clone, Debug)]
+#[locality("Remote")]
+pub struct StoredSettings {
+    pub blob: Option<Vec<u8>>,
+}
+
+/// Everything needed to recreate the simulation as the user left it, short of the particles
+/// themselves
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SimSettings {
+    pub n_particles: usize,
+    pub constrain_2d: bool,
+    pub max_neighbors: Option<usize>,
+    pub ghost_walls: Option<f32>,
+    pub tether_stiffness: f32,
+    pub turn_rate: f32,
+    pub config: SimConfig,
+    /// Where the simulation sits in the world, which belongs to the client rather than the
+    /// simulation
+    pub placement: SimPlacement,
+}
+
+/// State of the simulation, sufficient to replay it bit for bit with the explicit integrator
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SimSnapshot {
+    pub settings: SimSettings,
+    pub particles: Vec<Particle>,
+    /// Heading of each particle, with polar behaviours
+    pub orientations: Option<Vec<Vec3>>,
+}
+
+/// Errors arising from decoding a settings blob
+#[derive(Clone, Debug, PartialEq)]
+pub enum PersistError {
+    /// The blob is too short to hold a version
+    Truncated,
+    /// The blob was written by a different version of the plugin
+    Version(u32),
+    /// The blob could not be deserialized
+    Malformed(String),
+    /// The blob deserialized, but describes an unusable simulation
+    Invalid(&'static str),
+}
+
+impl SimSettings {
+    pub fn from_sim(sim: &SimState) -> Self {
+        Self {
+            n_particles: sim.particles().len(),
+            constrain_2d: sim.constrain_2d(),
+            max_neighbors: sim.max_neighbors(),
+            ghost_walls: sim.ghost_walls(),
+            tether_stiffness: sim.tether_stiffness(),
+            turn_rate: sim.turn_rate(),
+            config: sim.config().clone(),
+            placement: SimPlacement::default(),
+        }
+    }
+
+    /// Create a fresh simulation with these settings
+    pub fn build(&self, rng: &mut Pcg) -> SimState {
+        let mut sim = SimState::new(rng, self.config.clone(), self.n_particles);
+        self.apply_options(&mut sim, rng);
+        sim.rebuild(RebuildSpec::default(), rng);
+        sim
+    }
+
+    /// Apply everything but the configuration and particle count to an existing simulation
+    pub fn apply_options(&self, sim: &mut SimState, rng: &mut Pcg) {
+        sim.set_constrain_2d(self.constrain_2d, rng);
+        sim.set_max_neighbors(self.max_neighbors);
+        sim.set_ghost_walls(self.ghost_walls);
+        sim.set_tether_stiffness(self.tether_stiffness);
+        sim.set_turn_rate(self.turn_rate);
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut blob = SETTINGS_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut blob, self).expect("Settings are always serializable");
+        blob
+    }
+
+    pub fn decode(blob: &[u8]) -> Result<Self, PersistError> {
+        let (version, body) = blob
+            .split_first_chunk::<4>()
+            .ok_or(PersistError::Truncated)?;
+        let version = u32::from_le_bytes(*version);
+        if version != SETTINGS_VERSION {
+            return Err(PersistError::Version(version));
+        }
+
+        let settings: Self =
+            bincode::deserialize(body).map_err(|e| PersistError::Malformed(e.to_string()))?;
+
+        let n_colors = settings.config.colors.len();
+        if n_colors == 0 || settings.config.behaviours.len() != n_colors * n_colors {
+            return Err(PersistError::Invalid(
+                "Behaviour matrix does not match the colors",
+            ));
+        }
+        if settings.n_particles > MAX_PARTICLES {
+            return Err(PersistError::Invalid("Too many particles"));
+        }
+        if !settings.placement.is_valid() {
+            return Err(PersistError::Invalid("Placement is out of range"));
+        }
+        Ok(settings)
+    }
+}
+
+impl SimSnapshot {
+    pub fn capture(sim: &SimState) -> Self {
+        Self {
+            settings: SimSettings::from_sim(sim),
+            particles: sim.particles().to_vec(),
+            orientations: sim.orientations().map(|o| o.to_vec()),
+        }
+    }
+
+    /// Recreate the simulation as it was when the snapshot was taken
+    pub fn restore(&self, rng: &mut Pcg) -> SimState {
+        let mut sim =
+            SimState::from_particles(self.settings.config.clone(), self.particles.clone());
+        self.settings.apply_options(&mut sim, rng);
+        for (i, &heading) in self.orientations.iter().flatten().enumerate() {
+            sim.set_orientation(i, heading);
+        }
+        sim.rebuild(RebuildSpec::default(), rng);
+        sim
+    }
+
+    /// Hex encoded blob, for copying out of the log
+    pub fn to_hex(&self) -> String {
+        let mut blob = SNAPSHOT_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut blob, self).expect("Snapshots are always serializable");
+        blob.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, PersistError> {
+        let hex = hex.trim();
+        let blob = (0..hex.len() / 2)
+            .map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| PersistError::Malformed("Not hexadecimal".into()))?;
+
+        let (version, body) = blob
+            .split_first_chunk::<4>()
+            .ok_or(PersistError::Truncated)?;
+        let version = u32::from_le_bytes(*version);
+        if version != SNAPSHOT_VERSION {
+            return Err(PersistError::Version(version));
+        }
+        bincode::deserialize(body).map_err(|e| PersistError::Malformed(e.to_string()))
+    }
+}
+
+/// Decides when the client should send its settings to the server
+pub struct SettingsSaver {
+    /// Blob most recently sent
+    sent: Option<Vec<u8>>,
+    /// Blob waiting for the settings to stop changing
+    pending: Option<Vec<u8>>,
+    /// Frames left until the pending blob is sent
+    until_send: usize,
+    /// Frames left until the last blob is sent again
+    until_resend: usize,
+    /// Number of frames the settings must stay unchanged before they are sent
+    pub debounce_frames: usize,
+    /// Number of frames between sends of unchanged settings, in case the server restarted
+    pub resend_frames: usize,
+}
+
+impl SettingsSaver {
+    pub fn new(debounce_frames: usize, resend_frames: usize) -> Self {
+        Self {
+            sent: None,
+            pending: None,
+            until_send: 0,
+            until_resend: resend_frames,
+            debounce_frames,
+            resend_frames,
+        }
+    }
+
+    /// Call once per frame with the current settings. Returns a blob when it is time to send
+    /// one.
+    pub fn poll(&mut self, settings: &SimSettings) -> Option<Vec<u8>> {
+        let blob = settings.encode();
+
+        if self.sent.as_ref() == Some(&blob) {
+            self.pending = None;
+        } else if self.pending.as_ref() != Some(&blob) {
+            // Changed again; wait for it to settle
+            self.pending = Some(blob);
+            self.until_send = self.debounce_frames;
+        }
+
+        if self.pending.is_some() {
+            if self.until_send == 0 {
+                self.sent = self.pending.take();
+                self.until_resend = self.resend_frames;
+                return self.sent.clone();
+            }
+            self.until_send -= 1;
+        } else if self.sent.is_some() {
+            if self.until_resend == 0 {
+                self.until_resend = self.resend_frames;
+                return self.sent.clone();
+            }
+            self.until_resend -= 1;
+        }
+        None
+    }
+}
+
+impl std::fmt::Display for PersistError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PersistError::Truncated => write!(f, "Settings blob ended early"),
+            PersistError::Version(v) => write!(
+                f,
+                "Settings blob has version {}, expected {}",
+                v, SETTINGS_VERSION
+            ),
+            PersistError::Malformed(msg) => write!(f, "Malformed settings blob: {}", msg),
+            PersistError::Invalid(msg) => write!(f, "Invalid settings: {}", msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Behaviour;
+
+    fn settings() -> SimSettings {
+        let n = 3;
+        SimSettings {
+            n_particles: 200,
+            constrain_2d: true,
+            max_neighbors: Some(12),
+            ghost_walls: Some(0.8),
+            tether_stiffness: 2.5,
+            turn_rate: 7.,
+            config: SimConfig {
+                colors: (0..n).map(|i| [i as f32 / n as f32, 0.5, 1.]).collect(),
+                behaviours: (0..n * n)
+                    .map(|i| Behaviour::default().with_inter_strength(i as f32 - 4.))
+                    .collect(),
+                damping: 42.,
+                ..Default::default()
+            },
+            placement: SimPlacement {
+                offset: Vec3::new(0.3, 0.9, -0.5),
+                yaw: 0.7,
+                scale: 0.2,
+            },
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Pcg::new();
+        let sim = settings().build(&mut rng);
+        // The placement belongs to the client, not the simulation
+        let saved = SimSettings {
+            placement: settings().placement,
+            ..SimSettings::from_sim(&sim)
+        };
+        let restored = SimSettings::decode(&saved.encode()).unwrap();
+        assert_eq!(restored, settings());
+
+        let sim = restored.build(&mut rng);
+        assert_eq!(sim.particles().len(), 200);
+        assert!(sim.particles().iter().all(|p| p.pos.y == 0.));
+        assert_eq!(sim.max_neighbors(), Some(12));
+    }
+
+    #[test]
+    fn test_corrupted_blob() {
+        let blob = settings().encode();
+        assert_eq!(SimSettings::decode(&[]), Err(PersistError::Truncated));
+        assert!(matches!(
+            SimSettings::decode(&blob[..blob.len() - 3]),
+            Err(PersistError::Malformed(_))
+        ));
+
+        let mut future = blob.clone();
+        future[0] = 99;
+        assert_eq!(SimSettings::decode(&future), Err(PersistError::Version(99)));
+
+        let mut garbage = blob.clone();
+        garbage[4..].iter_mut().for_each(|b| *b = 0xff);
+        assert!(SimSettings::decode(&garbage).is_err());
+
+        let mut mismatched = settings();
+        mismatched.config.behaviours.pop();
+        assert!(matches!(
+            SimSettings::decode(&mismatched.encode()),
+            Err(PersistError::Invalid(_))
+        ));
+    }
+
+    #[test]
+    fn test_saver_debounce() {
+        let mut saver = SettingsSaver::new(3, 10);
+        let mut s = settings();
+
+        // Sent once the settings have been left alone for a few frames
+        let sends: Vec<bool> = (0..5).map(|_| saver.poll(&s).is_some()).collect();
+        assert_eq!(sends, [false, false, false, true, false]);
+
+        // Rapid edits delay the send
+        for i in 0..5 {
+            s.config.damping = i as f32;
+            assert!(saver.poll(&s).is_none());
+        }
+        let blob = (0..4).find_map(|_| saver.poll(&s)).unwrap();
+        assert_eq!(SimSettings::decode(&blob).unwrap(), s);
+
+        // Unchanged settings are sent again now and then
+        assert_eq!((0..11).filter_map(|_| saver.poll(&s)).count(), 1);
+    }
+}
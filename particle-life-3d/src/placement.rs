@@ -0,0 +1,310 @@
+//! Where the simulation sits in the world. Simulation space is scaled uniformly, turned about
+//! the vertical and then moved, so the whole cloud can be put on a table or a wall.
+//!
+//! Transforms carry no scale, so render entities only take the position and rotation, and
+//! the scale goes into the vertices as meshes are uploaded. Controller positions go the
+//! other way, into simulation space, before any query. Grabbing the volume with both triggers
+//! held moves, turns and scales it like any other object in VR.
+use cimvr_common::{
+    glam::{Quat, Vec3},
+    render::Mesh,
+    Transform,
+};
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::sim::TimeBubble;
+
+/// Smallest and largest scale, keeping the transform invertible and the cloud in reach
+pub const MIN_SCALE: f32 = 0.01;
+pub const MAX_SCALE: f32 = 20.;
+
+/// Anyone to client: move the simulation, e.g. from drag values
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct PlaceSim {
+    pub placement: SimPlacement,
+}
+
+/// Anyone to client: keep the centroid of recent activity in view, moving the simulation a
+/// `smoothing` fraction of the way each frame, or stop following with `None`. See [`Follow`].
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct FollowStructure {
+    pub smoothing: Option<f32>,
+}
+
+/// Anyone to client: keep the time bubble centered on the particle at `index`, or stop moving
+/// it with `None`. A `bubble` replaces the current one first; without either, following does
+/// nothing.
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct FollowWithBubble {
+    pub index: Option<usize>,
+    pub bubble: Option<TimeBubble>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SimPlacement {
+    /// World position of the simulation's origin
+    pub offset: Vec3,
+    /// Rotation about the vertical, in radians
+    pub yaw: f32,
+    /// World size of a unit of simulation space
+    pub scale: f32,
+}
+
+impl Default for SimPlacement {
+    fn default() -> Self {
+        Self {
+            offset: Vec3::new(0., 1., 0.),
+            yaw: 0.,
+            scale: 1.,
+        }
+    }
+}
+
+impl SimPlacement {
+    /// Whether every part is finite, and the scale within [`MIN_SCALE`] and [`MAX_SCALE`]
+    pub fn is_valid(&self) -> bool {
+        self.offset.is_finite()
+            && self.yaw.is_finite()
+            && (MIN_SCALE..=MAX_SCALE).contains(&self.scale)
+    }
+
+    pub fn rotation(&self) -> Quat {
+        Quat::from_rotation_y(self.yaw)
+    }
+
+    /// World position of the simulation space point `p`
+    pub fn to_world(&self, p: Vec3) -> Vec3 {
+        self.offset + self.rotation() * (p * self.scale)
+    }
+
+    /// Simulation space position of the world point `p`
+    pub fn to_sim(&self, p: Vec3) -> Vec3 {
+        self.rotation().inverse() * (p - self.offset) / self.scale
+    }
+
+    /// Transform of the render entities, which has no scale
+    pub fn transform(&self) -> Transform {
+        Transform::identity()
+            .with_position(self.offset)
+            .with_rotation(self.rotation())
+    }
+
+    /// `mesh` with the scale applied to its vertices, ready to upload
+    pub fn scale_mesh(&self, mesh: &Mesh) -> Mesh {
+        let mut mesh = mesh.clone();
+        if self.scale != 1. {
+            for vertex in &mut mesh.vertices {
+                vertex.pos = vertex.pos.map(|c| c * self.scale);
+            }
+        }
+        mesh
+    }
+}
+
+/// Two-handed manipulation of the placement: while both hands hold on, the simulation keeps
+/// the point between them under their midpoint, turns with the line between them about the
+/// vertical, and scales with the distance between them
+#[derive(Clone, Debug, Default)]
+pub struct TwoHandGrab {
+    /// Placement and hand positions when the grab began
+    start: Option<(SimPlacement, [Vec3; 2])>,
+}
+
+impl TwoHandGrab {
+    /// Follow the hands at the world positions `hands`, or let go with `None`. Returns the
+    /// new placement while grabbing.
+    pub fn update(
+        &mut self,
+        placement: &SimPlacement,
+        hands: Option<[Vec3; 2]>,
+    ) -> Option<SimPlacement> {
+        let Some(hands) = hands else {
+            self.start = None;
+            return None;
+        };
+        let (start, start_hands) = *self.start.get_or_insert((*placement, hands));
+
+        let span = |[a, b]: [Vec3; 2]| b - a;
+        let (before, after) = (span(start_hands), span(hands));
+        let yaw_of = |v: Vec3| (-v.z).atan2(v.x);
+        // Hands too close together, or one above the other, give no scale or heading
+        let ratio = match before.length() > 1e-3 {
+            true => after.length() / before.length(),
+            false => 1.,
+        };
+        let flat = |v: Vec3| Vec3::new(v.x, 0., v.z).length() > 1e-3;
+        let turn = match flat(before) && flat(after) {
+            true => yaw_of(after) - yaw_of(before),
+            false => 0.,
+        };
+
+        let mid = |[a, b]: [Vec3; 2]| (a + b) / 2.;
+        let held = start.to_sim(mid(start_hands));
+        let mut next = SimPlacement {
+            offset: Vec3::ZERO,
+            yaw: start.yaw + turn,
+            scale: (start.scale * ratio).clamp(MIN_SCALE, MAX_SCALE),
+        };
+        next.offset = mid(hands) - next.rotation() * (held * next.scale);
+        Some(next)
+    }
+
+    pub fn is_grabbing(&self) -> bool {
+        self.start.is_some()
+    }
+}
+
+/// Moves the simulation so that a point which wanders through simulation space, such as the
+/// centroid of recent activity, stays put in the world. The point followed is a smoothed copy
+/// of the target, so the simulation glides rather than jumps, and as only the change from
+/// frame to frame is applied, the simulation can still be grabbed and moved meanwhile.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Follow {
+    /// Fraction of the way to the latest target moved each frame
+    pub smoothing: f32,
+    /// Smoothed simulation space position of the followed point
+    tracked: Option<Vec3>,
+}
+
+impl Follow {
+    pub fn new(smoothing: f32) -> Self {
+        Self {
+            smoothing: smoothing.clamp(0., 1.),
+            tracked: None,
+        }
+    }
+
+    /// Ease the followed point towards `target`, if any, and return the placement keeping it
+    /// where it was in the world
+    pub fn update(
+        &mut self,
+        placement: &SimPlacement,
+        target: Option<Vec3>,
+    ) -> Option<SimPlacement> {
+        let target = target.filter(|target| target.is_finite())?;
+        let Some(tracked) = self.tracked else {
+            self.tracked = Some(target);
+            return None;
+        };
+        let next = tracked.lerp(target, self.smoothing);
+        self.tracked = Some(next);
+        let offset = placement.to_world(tracked) - placement.rotation() * (next * placement.scale);
+        Some(SimPlacement {
+            offset,
+            ..*placement
+        })
+    }
+
+    /// Smoothed simulation space position of the followed point, once there is one
+    pub fn tracked(&self) -> Option<Vec3> {
+        self.tracked
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: Vec3, b: Vec3) {
+        assert!(a.distance(b) < 1e-4, "{} {}", a, b);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let placement = SimPlacement {
+            offset: Vec3::new(0.5, 0.8, -1.),
+            yaw: 1.2,
+            scale: 0.2,
+        };
+        for p in [Vec3::ZERO, Vec3::ONE, Vec3::new(-3., 0.1, 2.)] {
+            assert_close(placement.to_sim(placement.to_world(p)), p);
+        }
+        assert_close(placement.to_world(Vec3::ZERO), placement.offset);
+        // A unit along X is 20 cm long in the world
+        let unit = placement.to_world(Vec3::X) - placement.offset;
+        assert!((unit.length() - 0.2).abs() < 1e-6);
+        assert!(unit.y.abs() < 1e-6);
+
+        // The entity transform and the scaled mesh together agree with to_world
+        let mut mesh = Mesh::new();
+        mesh.vertices.push(cimvr_common::render::Vertex {
+            pos: [1., 2., 3.],
+            uvw: [0.; 3],
+        });
+        let scaled = Vec3::from(placement.scale_mesh(&mesh).vertices[0].pos);
+        let transform = placement.transform();
+        assert_close(
+            transform.pos + transform.orient * scaled,
+            placement.to_world(Vec3::new(1., 2., 3.)),
+        );
+
+        assert!(placement.is_valid());
+        assert!(!SimPlacement {
+            scale: 0.,
+            ..placement
+        }
+        .is_valid());
+        assert!(!SimPlacement {
+            yaw: f32::NAN,
+            ..placement
+        }
+        .is_valid());
+    }
+
+    #[test]
+    fn test_follow() {
+        let placement = SimPlacement {
+            offset: Vec3::new(0.5, 0.8, -1.),
+            yaw: 1.2,
+            scale: 0.2,
+        };
+        let mut follow = Follow::new(0.5);
+        assert_eq!(follow.update(&placement, Some(Vec3::ZERO)), None);
+        let held = placement.to_world(Vec3::ZERO);
+
+        // The target jumps, and the followed point glides after it, staying put in the world
+        let mut placement = placement;
+        for step in 1..=20 {
+            placement = follow.update(&placement, Some(Vec3::X)).unwrap();
+            let tracked = follow.tracked().unwrap();
+            assert_close(placement.to_world(tracked), held);
+            assert!((tracked.x - (1. - 0.5_f32.powi(step))).abs() < 1e-4);
+        }
+        assert_eq!(follow.update(&placement, None), None);
+    }
+
+    #[test]
+    fn test_two_hand_grab() {
+        let placement = SimPlacement::default();
+        let mut grab = TwoHandGrab::default();
+        let hands = [Vec3::new(-0.2, 1., 0.), Vec3::new(0.2, 1., 0.)];
+        assert_eq!(grab.update(&placement, Some(hands)), Some(placement));
+        assert!(grab.is_grabbing());
+
+        // Moving both hands carries the simulation along
+        let moved = hands.map(|h| h + Vec3::new(0.3, -0.2, 0.1));
+        let next = grab.update(&placement, Some(moved)).unwrap();
+        assert_close(next.offset, placement.offset + Vec3::new(0.3, -0.2, 0.1));
+        assert!((next.scale - 1.).abs() < 1e-5);
+
+        // Bringing them together shrinks it about their midpoint, which stays put
+        let closer = [Vec3::new(-0.02, 1., 0.), Vec3::new(0.02, 1., 0.)];
+        let next = grab.update(&placement, Some(closer)).unwrap();
+        assert!((next.scale - 0.1).abs() < 1e-5);
+        let mid = Vec3::new(0., 1., 0.);
+        assert_close(next.to_world(placement.to_sim(mid)), mid);
+
+        // Turning them a quarter turn about the vertical turns it too
+        let turned = [Vec3::new(0., 1., 0.2), Vec3::new(0., 1., -0.2)];
+        let next = grab.update(&placement, Some(turned)).unwrap();
+        assert!((next.yaw - std::f32::consts::FRAC_PI_2).abs() < 1e-5);
+        assert_close(next.to_world(Vec3::X), next.offset + Vec3::NEG_Z);
+
+        assert_eq!(grab.update(&next, None), None);
+        assert!(!grab.is_grabbing());
+    }
+}
@@ -1,30 +1,404 @@
 use cimvr_common::glam::Vec3;
+use serde::{Deserialize, Serialize};
 use zwohash::HashMap;
 
-/// Euclidean neighborhood query accelerator. Uses a hashmap grid.
+use crate::metric::{wrap, DistanceMetric};
+
+/// Neighborhood query accelerator. Uses a grid of cells the size of the query radius, stored
+/// compactly when the points are not too spread out. Points with non-finite coordinates are
+/// kept out of the grid, in a quarantine which queries never visit.
+///
+/// Distances are Euclidean unless built with a toroidal [`DistanceMetric`], in which case
+/// points are binned by their image within the periodic box, and each query also visits the
+/// images of its sphere poking out across the seams, so cells at opposite faces neighbor.
+///
+/// An accelerator can outlive the positions it was built over: after
+/// [`QueryAccelerator::refresh_overlay`], points which have since left their cells are found
+/// through a small overlay grid instead, so queries over the moved points stay exact.
+/// Overlay cells are kept between refreshes, and pruned once empty for [`PRUNE_AFTER`]
+/// refreshes in a row, so a cloud which keeps travelling does not leave a trail of them.
 pub struct QueryAccelerator {
-    cells: HashMap<[i32; 3], Vec<usize>>,
+    cells: HashMap<[i32; 3], Vec<u32>>,
+    compact: Option<CompactGrid>,
+    /// Points with non-finite coordinates, in no cell
+    quarantine: Vec<u32>,
+    /// Points binned elsewhere which moved into each cell since
+    overlay: HashMap<[i32; 3], Vec<u32>>,
+    /// Number of refreshes in a row each empty overlay cell has been empty for
+    overlay_idle: HashMap<[i32; 3], u32>,
+    /// Whether each point is in the overlay rather than where it was binned; empty while the
+    /// overlay is
+    overlaid: Vec<bool>,
     neighbors: Vec<[i32; 3]>,
     radius: f32,
     radius_sq: f32,
+    mode: AccelMode,
+    n_points: usize,
+    /// First and last cell coordinates covering all points, unless there are none
+    extent: Option<([i32; 3], [i32; 3])>,
+    /// Extent of the points as binned, which the overlay's occupied cells widen
+    binned_extent: Option<([i32; 3], [i32; 3])>,
+    /// Period of the box points are wrapped into before binning, if the metric is toroidal
+    period: Option<Vec3>,
+}
+
+/// Cells covering the bounding box of the points, with the points of all cells in one array,
+/// sorted by cell
+struct CompactGrid {
+    /// Cell coordinates of the first cell
+    min: [i32; 3],
+    /// Number of cells along each axis
+    dims: [i32; 3],
+    /// Start of each cell's points in `indices`, plus the end of the last cell
+    starts: Vec<u32>,
+    indices: Vec<u32>,
+    occupied: usize,
+}
+
+/// Strategy used to answer queries
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum AccelMode {
+    /// Points are binned into a hashmap of cells the size of the query radius
+    Grid,
+    /// Points are binned into cells the size of the query radius, covering the bounding box
+    /// and laid out by counting sort. No per-cell allocations.
+    Compact,
+    /// Every point is tested. Faster when the radius is so large that the grid collapses into
+    /// a handful of cells.
+    Dense,
 }
 
+/// Largest magnitude of a cell coordinate. Points further out share the outermost cells,
+/// which leaves room to add neighbor offsets to any coordinate without overflowing.
+const MAX_CELL: f32 = (1 << 29) as f32;
+
+/// With fewer occupied cells than a single query visits, the grid is no better than testing
+/// every point
+const MIN_GRID_CELLS: usize = 27;
+
+/// Largest number of compact grid cells per point, beyond which mostly empty cells would cost
+/// more than the hashmap
+const MAX_CELLS_PER_POINT: usize = 8;
+
+/// Refreshes in a row an overlay cell must be empty for before it is pruned
+pub const PRUNE_AFTER: u32 = 4;
+
+/// A full rebuild spread over several steps, so that no single frame pays for binning every
+/// point. Points are binned a slice per [`RebuildInProgress::step`] from a snapshot taken at
+/// the start, into a hashmap grid which is only swapped in once complete; meanwhile queries
+/// go to the previous accelerator, kept exact by its overlay.
+pub struct RebuildInProgress {
+    points: Vec<Vec3>,
+    radius: f32,
+    per_step: usize,
+    next: usize,
+    cells: HashMap<[i32; 3], Vec<u32>>,
+    quarantine: Vec<u32>,
+}
+
+// Indices are stored as u32, which must convert back losslessly
+const _: () = assert!(u32::MAX as u64 <= usize::MAX as u64);
+
 impl QueryAccelerator {
-    /// Construct a new query accelerator
+    /// Construct a new query accelerator, choosing the mode automatically
     pub fn new(points: &[Vec3], radius: f32) -> Self {
-        let mut cells: HashMap<[i32; 3], Vec<usize>> = HashMap::default();
+        let (min, max) = bounds(points);
+        if radius >= (max - min).length() {
+            return Self::with_mode(points, radius, AccelMode::Dense);
+        }
+
+        let accel = Self::from_counting_sort(points, radius);
+        if accel.cell_count() < MIN_GRID_CELLS {
+            Self::with_mode(points, radius, AccelMode::Dense)
+        } else {
+            accel
+        }
+    }
 
-        for (idx, &point) in points.iter().enumerate() {
-            cells.entry(quantize(point, radius)).or_default().push(idx);
+    /// Construct a grid accelerator, compact unless the points are so spread out that most
+    /// cells of their bounding box would be empty, in which case the hashmap grid is used
+    pub fn from_counting_sort(points: &[Vec3], radius: f32) -> Self {
+        let (min, max) = bounds(points);
+        let (min, max) = (quantize(min, radius), quantize(max, radius));
+        let n_cells = (0..3)
+            .map(|axis| (max[axis] as i64 - min[axis] as i64 + 1).max(0) as u64)
+            .fold(1u64, |n, dim| n.saturating_mul(dim));
+
+        let limit = points.len().saturating_mul(MAX_CELLS_PER_POINT) + MIN_GRID_CELLS;
+        if n_cells <= limit as u64 {
+            Self::with_mode(points, radius, AccelMode::Compact)
+        } else {
+            Self::with_mode(points, radius, AccelMode::Grid)
         }
+    }
+
+    /// Construct a new query accelerator using the given mode
+    pub fn with_mode(points: &[Vec3], radius: f32, mode: AccelMode) -> Self {
+        assert!(
+            points.len() <= u32::MAX as usize,
+            "Too many points for u32 indices"
+        );
+
+        let mut cells: HashMap<[i32; 3], Vec<u32>> = HashMap::default();
+        if mode == AccelMode::Grid {
+            for (idx, &point) in points.iter().enumerate() {
+                if let Some(key) = cell_key(point, radius) {
+                    cells.entry(key).or_default().push(idx as u32);
+                }
+            }
+        }
+        let quarantine = (0..points.len() as u32)
+            .filter(|&idx| !points[idx as usize].is_finite())
+            .collect();
+
+        let compact = (mode == AccelMode::Compact).then(|| CompactGrid::new(points, radius));
 
         let neighbors = neighborhood::<3>();
 
+        let (min, max) = bounds(points);
+        let extent = (min.cmple(max).all()).then(|| (quantize(min, radius), quantize(max, radius)));
+
         Self {
             cells,
+            compact,
+            quarantine,
+            overlay: HashMap::default(),
+            overlay_idle: HashMap::default(),
+            overlaid: vec![],
             radius,
             radius_sq: radius * radius,
             neighbors,
+            mode,
+            n_points: points.len(),
+            extent,
+            binned_extent: extent,
+            period: None,
+        }
+    }
+
+    /// Construct a new query accelerator measuring distances in `metric`, choosing the mode
+    /// automatically. Queries still take the points unwrapped.
+    pub fn with_metric(points: &[Vec3], radius: f32, metric: DistanceMetric) -> Self {
+        let Some(period) = metric.period() else {
+            return Self::new(points, radius);
+        };
+        let wrapped: Vec<Vec3> = points.iter().map(|&p| wrap(p, period)).collect();
+        let mut accel = Self::new(&wrapped, radius);
+        accel.period = Some(period);
+        accel
+    }
+
+    pub fn metric(&self) -> DistanceMetric {
+        match self.period {
+            Some(period) => DistanceMetric::Toroidal { period },
+            None => DistanceMetric::Euclidean,
+        }
+    }
+
+    /// Cell point `p` belongs in, after wrapping it into the box if toroidal, or `None` if it
+    /// belongs in the quarantine
+    fn key(&self, p: Vec3) -> Option<[i32; 3]> {
+        match self.period {
+            Some(period) => cell_key(wrap(p, period), self.radius),
+            None => cell_key(p, self.radius),
+        }
+    }
+
+    /// Catch up with `points`, the points this was built over after they moved: each point
+    /// no longer in the cell it was binned into, or the quarantine, is found through the
+    /// overlay instead, so that queries over `points` return exactly what an accelerator
+    /// built over them would. Returns false, leaving the overlay empty, if more than
+    /// `max_overlay` points moved, when building afresh is the better deal.
+    pub fn refresh_overlay(&mut self, points: &[Vec3], max_overlay: usize) -> bool {
+        assert_eq!(points.len(), self.n_points, "Points added or removed");
+        let mut moved = vec![];
+        for (key, cell) in self.binned() {
+            moved.extend((cell.iter()).filter(|&&idx| self.key(points[idx as usize]) != key));
+            if moved.len() > max_overlay {
+                break;
+            }
+        }
+
+        self.overlaid.clear();
+        if moved.len() > max_overlay {
+            self.overlay.clear();
+            self.overlay_idle.clear();
+            return false;
+        }
+        // Cells are emptied rather than dropped, to be reused by points moving in next time
+        for cell in self.overlay.values_mut() {
+            cell.clear();
+        }
+        if !moved.is_empty() {
+            self.overlaid.resize(self.n_points, false);
+        }
+        for idx in moved {
+            self.overlaid[idx as usize] = true;
+            // Still quarantined points stay out of the way
+            if let Some(key) = self.key(points[idx as usize]) {
+                self.overlay.entry(key).or_default().push(idx);
+            }
+        }
+        self.prune_overlay();
+
+        // Rays must reach points which left the cells covered at build time
+        self.extent = self.binned_extent;
+        for (&key, _) in self.overlay.iter().filter(|(_, cell)| !cell.is_empty()) {
+            let (lo, hi) = self.extent.get_or_insert((key, key));
+            for axis in 0..3 {
+                lo[axis] = lo[axis].min(key[axis]);
+                hi[axis] = hi[axis].max(key[axis]);
+            }
+        }
+        true
+    }
+
+    /// Age the empty overlay cells, dropping those empty for [`PRUNE_AFTER`] refreshes in a
+    /// row, and give the table's memory back once it is mostly unused. Occupied cells are
+    /// never dropped.
+    fn prune_overlay(&mut self) {
+        let idle = &mut self.overlay_idle;
+        self.overlay.retain(|key, cell| {
+            if !cell.is_empty() {
+                idle.remove(key);
+                return true;
+            }
+            let age = idle.entry(*key).or_default();
+            *age += 1;
+            if *age < PRUNE_AFTER {
+                return true;
+            }
+            idle.remove(key);
+            false
+        });
+        if self.overlay.capacity() > 2 * self.overlay.len().max(16) {
+            self.overlay.shrink_to_fit();
+            self.overlay_idle.shrink_to_fit();
+        }
+    }
+
+    /// Number of overlay cells kept, occupied or not yet pruned
+    pub fn overlay_cells(&self) -> usize {
+        self.overlay.len()
+    }
+
+    /// Number of points found through the overlay rather than where they were binned
+    pub fn overlay_len(&self) -> usize {
+        self.overlaid.iter().filter(|&&moved| moved).count()
+    }
+
+    /// Every cell with its points as binned, and the quarantine with no key. Dense mode has
+    /// no cells, and only the quarantine is listed.
+    fn binned(&self) -> impl Iterator<Item = (Option<[i32; 3]>, &[u32])> {
+        let compact = self.compact.iter().flat_map(|compact| {
+            (0..compact.starts.len().saturating_sub(1)).map(|id| {
+                (
+                    Some(compact.key(id)),
+                    compact.cell(compact.key(id)).unwrap(),
+                )
+            })
+        });
+        let cells = (self.cells.iter()).map(|(&key, cell)| (Some(key), cell.as_slice()));
+        compact
+            .chain(cells)
+            .chain([(None, self.quarantine.as_slice())])
+    }
+
+    /// Whether point `idx` is in the overlay rather than where it was binned
+    fn is_overlaid(&self, idx: u32) -> bool {
+        self.overlaid.get(idx as usize).copied().unwrap_or(false)
+    }
+
+    /// Points in the cell with the given coordinates, as binned or through the overlay
+    fn points_in(&self, key: [i32; 3]) -> impl Iterator<Item = usize> + '_ {
+        let binned = self.cell(key).iter().filter(|&&idx| !self.is_overlaid(idx));
+        let moved = (!self.overlay.is_empty())
+            .then(|| self.overlay.get(&key))
+            .flatten()
+            .into_iter()
+            .flatten();
+        binned.chain(moved).map(|&idx| idx as usize)
+    }
+
+    pub fn mode(&self) -> AccelMode {
+        self.mode
+    }
+
+    /// Radius of the neighborhood queries, and size of the cells
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// Number of points this was built over
+    pub fn point_count(&self) -> usize {
+        self.n_points
+    }
+
+    /// Whether every index stored in the cells refers to one of the points this was built over
+    pub fn indices_in_range(&self) -> bool {
+        let compact = self.compact.iter().flat_map(|compact| &compact.indices);
+        let mut indices = self.cells.values().flatten().chain(compact);
+        indices.all(|&i| (i as usize) < self.n_points)
+    }
+
+    /// Points with non-finite coordinates, which are in no cell and never returned by queries
+    pub fn quarantined(&self) -> &[u32] {
+        &self.quarantine
+    }
+
+    /// Check that this was built over `points`: that every point is stored exactly once, in
+    /// the cell its position quantizes to, or in the quarantine if it is not finite. Costs a
+    /// pass over every cell, so is meant for debugging.
+    pub fn validate(&self, points: &[Vec3]) -> Result<(), String> {
+        if points.len() != self.n_points {
+            return Err(format!(
+                "Built over {} points, not {}",
+                self.n_points,
+                points.len()
+            ));
+        }
+
+        let mut seen = vec![false; points.len()];
+        let mut visit = |idx: u32| match seen.get_mut(idx as usize) {
+            Some(true) => Err(format!("Point {} is stored twice", idx)),
+            Some(seen) => {
+                *seen = true;
+                Ok(())
+            }
+            None => Err(format!("Point {} is out of range", idx)),
+        };
+        // Points which moved may be anywhere, as long as the overlay finds them
+        for (key, cell) in self.binned() {
+            for &idx in cell {
+                visit(idx)?;
+                let expected = self.key(points[idx as usize]);
+                if expected == key || self.is_overlaid(idx) {
+                    continue;
+                }
+                return Err(match key {
+                    Some(key) => format!(
+                        "Point {} at {} is in cell {:?} rather than {:?}",
+                        idx, points[idx as usize], key, expected
+                    ),
+                    None => format!("Finite point {} is quarantined", idx),
+                });
+            }
+        }
+        for (&key, cell) in &self.overlay {
+            if let Some(&idx) =
+                (cell.iter()).find(|&&idx| self.key(points[idx as usize]) != Some(key))
+            {
+                return Err(format!("Point {} is in overlay cell {:?}", idx, key));
+            }
+        }
+
+        // Dense mode has no cells, so only the quarantine is stored
+        let unstored = (0..points.len()).find(|&i| !seen[i] && points[i].is_finite());
+        match unstored {
+            Some(idx) if self.mode != AccelMode::Dense => {
+                Err(format!("Point {} is in no cell", idx))
+            }
+            _ => Ok(()),
         }
     }
 
@@ -44,21 +418,26 @@ impl QueryAccelerator {
         points: &'p [Vec3],
         query_point: Vec3,
     ) -> impl Iterator<Item = usize> + 's {
-        let origin = quantize(query_point, self.radius);
+        let within_radius =
+            move |&idx: &usize| (points[idx] - query_point).length_squared() <= self.radius_sq;
 
-        self.neighbors
-            .iter()
-            .map(move |diff| {
-                let key = add(origin, *diff);
-                self.cells.get(&key).map(|cell_indices| {
-                    cell_indices.iter().copied().filter(move |&idx| {
-                        let dist = (points[idx] - query_point).length_squared();
-                        dist <= self.radius_sq
-                    })
-                })
+        // A non-finite point has no neighbors
+        let euclidean = self.period.is_none();
+        let grid = (euclidean.then_some(query_point))
+            .and_then(|p| cell_key(p, self.radius))
+            .into_iter()
+            .flat_map(move |origin| {
+                (self.neighbors.iter()).flat_map(move |diff| self.points_in(add(origin, *diff)))
             })
-            .flatten()
-            .flatten()
+            .filter(within_radius);
+
+        let dense = (euclidean && self.mode == AccelMode::Dense)
+            .then(|| (0..self.n_points).filter(within_radius))
+            .into_iter()
+            .flatten();
+
+        grid.chain(dense)
+            .chain(self.query_toroidal(points, query_point, self.radius))
     }
 
     // Query the neighbors of `queried_idx` in `points`
@@ -72,6 +451,254 @@ impl QueryAccelerator {
             .filter(move |i| *i != queried_idx)
     }
 
+    /// Neighbors of `queried_idx` within `radius` of `query_point`, visiting only the cells
+    /// overlapping the bounding box of that sphere. A radius much smaller than the cell size
+    /// usually stays within a few of the 27 cells of a full query; a larger one reaches
+    /// further out.
+    pub fn query_neighbors_radius<'s, 'p: 's>(
+        &'s self,
+        points: &'p [Vec3],
+        queried_idx: usize,
+        query_point: Vec3,
+        radius: f32,
+    ) -> impl Iterator<Item = usize> + 's {
+        let radius_sq = radius * radius;
+        let within_radius = move |&idx: &usize| {
+            idx != queried_idx && (points[idx] - query_point).length_squared() <= radius_sq
+        };
+
+        let euclidean = self.period.is_none();
+        let grid = (euclidean && self.mode != AccelMode::Dense && query_point.is_finite())
+            .then(|| self.cells_overlapping(query_point, radius))
+            .into_iter()
+            .flatten()
+            .filter(within_radius);
+
+        let dense = (euclidean && self.mode == AccelMode::Dense)
+            .then(|| (0..self.n_points).filter(within_radius))
+            .into_iter()
+            .flatten();
+
+        let toroidal = (self.query_toroidal(points, query_point, radius))
+            .filter(move |&idx| idx != queried_idx);
+        grid.chain(dense).chain(toroidal)
+    }
+
+    /// Points in the cells overlapping the bounding box of the sphere of `radius` around
+    /// `center`, as binned or through the overlay
+    fn cells_overlapping(&self, center: Vec3, radius: f32) -> impl Iterator<Item = usize> + '_ {
+        let lo = quantize(center - Vec3::splat(radius), self.radius);
+        let hi = quantize(center + Vec3::splat(radius), self.radius);
+        (lo[0]..=hi[0]).flat_map(move |x| {
+            (lo[1]..=hi[1])
+                .flat_map(move |y| (lo[2]..=hi[2]).flat_map(move |z| self.points_in([x, y, z])))
+        })
+    }
+
+    /// Points whose nearest image is within `radius` of `query_point`, including the point
+    /// itself. Empty unless the metric is toroidal. The sphere around the wrapped query point
+    /// is searched, then each of its images across a seam it pokes out of; while the period
+    /// is at least twice the radius, a point is within at most one of them.
+    fn query_toroidal<'s, 'p: 's>(
+        &'s self,
+        points: &'p [Vec3],
+        query_point: Vec3,
+        radius: f32,
+    ) -> impl Iterator<Item = usize> + 's {
+        let radius_sq = radius * radius;
+        let torus = (self.period).filter(|_| query_point.is_finite());
+        let grid = torus
+            .filter(|_| self.mode != AccelMode::Dense)
+            .into_iter()
+            .flat_map(move |period| {
+                let center = wrap(query_point, period);
+                (self.neighbors.iter())
+                    .map(move |&[x, y, z]| {
+                        center + Vec3::new(x as f32, y as f32, z as f32) * period
+                    })
+                    .filter(move |&image| {
+                        (image - Vec3::splat(radius)).cmplt(period).all()
+                            && (image + Vec3::splat(radius)).cmpge(Vec3::ZERO).all()
+                    })
+                    .flat_map(move |image| {
+                        self.cells_overlapping(image, radius).filter(move |&idx| {
+                            (wrap(points[idx], period) - image).length_squared() <= radius_sq
+                        })
+                    })
+            });
+
+        let dense = torus
+            .filter(|_| self.mode == AccelMode::Dense)
+            .into_iter()
+            .flat_map(move |period| {
+                let metric = DistanceMetric::Toroidal { period };
+                (0..self.n_points).filter(move |&idx| {
+                    metric.distance_squared(query_point, points[idx]) <= radius_sq
+                })
+            });
+
+        grid.chain(dense)
+    }
+
+    /// Cells crossed by the ray from `origin` along `dir`, in order, within the cells covering
+    /// the points. Cells are the size of the query radius, whatever the mode. A digital
+    /// differential analyzer steps to whichever cell boundary the ray reaches first.
+    pub fn raycast_cells(&self, origin: Vec3, dir: Vec3) -> impl Iterator<Item = [i32; 3]> {
+        self.raycast_cells_padded(origin, dir, 0)
+    }
+
+    /// [`QueryAccelerator::raycast_cells`] within `pad` more cells around the points
+    pub fn raycast_cells_padded(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        pad: i32,
+    ) -> impl Iterator<Item = [i32; 3]> {
+        let radius = self.radius;
+        let mut state = self.extent.and_then(|(lo, hi)| {
+            let (lo, hi) = (add(lo, [-pad; 3]), add(hi, [pad; 3]));
+            // Clip the ray to the box of the grid, starting no earlier than its origin
+            let box_min = Vec3::from(lo.map(|v| v as f32)) * radius;
+            let box_max = Vec3::from(hi.map(|v| v as f32 + 1.)) * radius;
+            let (mut enter, mut exit) = (0_f32, f32::INFINITY);
+            for axis in 0..3 {
+                if dir[axis] == 0. {
+                    if origin[axis] < box_min[axis] || origin[axis] > box_max[axis] {
+                        return None;
+                    }
+                    continue;
+                }
+                let a = (box_min[axis] - origin[axis]) / dir[axis];
+                let b = (box_max[axis] - origin[axis]) / dir[axis];
+                enter = enter.max(a.min(b));
+                exit = exit.min(a.max(b));
+            }
+            if enter > exit || dir == Vec3::ZERO {
+                return None;
+            }
+
+            let start = origin + dir * enter;
+            let mut cell = quantize(start, radius);
+            let (mut step, mut next, mut delta) = ([0; 3], [f32::INFINITY; 3], [f32::INFINITY; 3]);
+            for axis in 0..3 {
+                // Rounding may put the entry point just outside the box
+                cell[axis] = cell[axis].clamp(lo[axis], hi[axis]);
+                if dir[axis] != 0. {
+                    step[axis] = dir[axis].signum() as i32;
+                    let boundary = (cell[axis] + step[axis].max(0)) as f32 * radius;
+                    next[axis] = (boundary - origin[axis]) / dir[axis];
+                    delta[axis] = radius / dir[axis].abs();
+                }
+            }
+            Some((cell, step, next, delta, lo, hi))
+        });
+
+        std::iter::from_fn(move || {
+            let (cell, step, next, delta, lo, hi) = state.as_mut()?;
+            let current = *cell;
+
+            let axis = (0..3).min_by(|&a, &b| next[a].total_cmp(&next[b])).unwrap();
+            cell[axis] += step[axis];
+            next[axis] += delta[axis];
+            if cell[axis] < lo[axis] || cell[axis] > hi[axis] {
+                state = None;
+            }
+            Some(current)
+        })
+    }
+
+    /// Indices of the points binned into the cell with the given coordinates, including any
+    /// which have since moved on to the overlay. Always empty in dense mode, which has no
+    /// cells.
+    pub fn cell(&self, key: [i32; 3]) -> &[u32] {
+        let cell = match &self.compact {
+            Some(compact) => compact.cell(key),
+            None => self.cells.get(&key).map(|cell| cell.as_slice()),
+        };
+        cell.unwrap_or_default()
+    }
+
+    /// Indices of the points which may lie within `max_dist` of the ray from `origin` along
+    /// `dir`: those in the cells it crosses and the cells around them. Points may repeat. The
+    /// cells of a toroidal metric hold wrapped points, so every point is listed instead.
+    pub fn query_near_ray(
+        &self,
+        origin: Vec3,
+        dir: Vec3,
+        max_dist: f32,
+    ) -> impl Iterator<Item = usize> + '_ {
+        let reach = (max_dist / self.radius).ceil().max(0.) as i32;
+        let block = combos::<3>(-reach, reach, 1);
+        let dense = self.mode == AccelMode::Dense || self.period.is_some();
+        let grid = (!dense)
+            .then(|| {
+                self.raycast_cells_padded(origin, dir, reach)
+                    .flat_map(move |cell| {
+                        block
+                            .clone()
+                            .into_iter()
+                            .flat_map(move |diff| self.points_in(add(cell, diff)))
+                    })
+            })
+            .into_iter()
+            .flatten();
+
+        let dense = dense.then_some(0..self.n_points).into_iter().flatten();
+
+        grid.chain(dense)
+    }
+
+    /// Coordinates and point count of every occupied cell as binned, sorted by coordinates.
+    /// Empty in dense mode.
+    pub fn cell_layout(&self) -> Vec<([i32; 3], u32)> {
+        let mut layout: Vec<([i32; 3], u32)> = match &self.compact {
+            Some(compact) => (0..compact.starts.len() - 1)
+                .map(|id| (compact.key(id), compact.starts[id + 1] - compact.starts[id]))
+                .filter(|&(_, count)| count > 0)
+                .collect(),
+            None => (self.cells.iter())
+                .map(|(&key, cell)| (key, cell.len() as u32))
+                .collect(),
+        };
+        layout.sort_unstable();
+        layout
+    }
+
+    /// Number of occupied cells
+    pub fn cell_count(&self) -> usize {
+        match &self.compact {
+            Some(compact) => compact.occupied,
+            None => self.cells.len(),
+        }
+    }
+
+    /// Approximate heap memory used, in bytes
+    pub fn memory_bytes(&self) -> usize {
+        use std::mem::size_of;
+        // Hashbrown stores a control byte alongside each bucket
+        let bucket = size_of::<([i32; 3], Vec<u32>)>() + 1;
+        let hashmap = self.cells.capacity() * bucket
+            + self
+                .cells
+                .values()
+                .map(|cell| cell.capacity() * size_of::<u32>())
+                .sum::<usize>();
+        let compact = self.compact.as_ref().map_or(0, |compact| {
+            (compact.starts.capacity() + compact.indices.capacity()) * size_of::<u32>()
+        });
+        let overlay = self.overlay.capacity() * bucket
+            + (self.overlay.values())
+                .map(|cell| cell.capacity() * size_of::<u32>())
+                .sum::<usize>()
+            + self.overlay_idle.capacity() * (size_of::<([i32; 3], u32)>() + 1)
+            + self.overlaid.capacity();
+        hashmap
+            + compact
+            + overlay
+            + self.quarantine.capacity() * size_of::<u32>()
+            + self.neighbors.capacity() * size_of::<[i32; 3]>()
+    }
+
     /*
     pub fn tiles(&self) -> impl Iterator<Item = (&[i32; 3], &Vec<usize>)> {
         self.cells.iter()
@@ -79,13 +706,180 @@ impl QueryAccelerator {
     */
 }
 
+impl RebuildInProgress {
+    /// Start rebuilding over a snapshot of `points`, spreading the work over `steps` steps
+    pub fn new(points: &[Vec3], radius: f32, steps: usize) -> Self {
+        assert!(
+            points.len() <= u32::MAX as usize,
+            "Too many points for u32 indices"
+        );
+        Self {
+            points: points.to_vec(),
+            radius,
+            per_step: points.len().div_ceil(steps.max(1)),
+            next: 0,
+            cells: HashMap::default(),
+            quarantine: vec![],
+        }
+    }
+
+    pub fn radius(&self) -> f32 {
+        self.radius
+    }
+
+    /// Number of points in the snapshot
+    pub fn point_count(&self) -> usize {
+        self.points.len()
+    }
+
+    /// Fraction of the points binned so far
+    pub fn progress(&self) -> f32 {
+        match self.points.is_empty() {
+            true => 1.,
+            false => self.next as f32 / self.points.len() as f32,
+        }
+    }
+
+    /// Bin the next slice of points; returns true once every point is binned
+    pub fn step(&mut self) -> bool {
+        let end = (self.next + self.per_step).min(self.points.len());
+        for idx in self.next..end {
+            match cell_key(self.points[idx], self.radius) {
+                Some(key) => self.cells.entry(key).or_default().push(idx as u32),
+                None => self.quarantine.push(idx as u32),
+            }
+        }
+        self.next = end;
+        self.next == self.points.len()
+    }
+
+    /// The finished accelerator, over the snapshot, binning whatever is left first. Falls
+    /// back to dense mode where [`QueryAccelerator::new`] would.
+    pub fn finish(mut self) -> QueryAccelerator {
+        while !self.step() {}
+        let (min, max) = bounds(&self.points);
+        if self.radius >= (max - min).length() || self.cells.len() < MIN_GRID_CELLS {
+            return QueryAccelerator::with_mode(&self.points, self.radius, AccelMode::Dense);
+        }
+        let mut accel = QueryAccelerator::with_mode(&[], self.radius, AccelMode::Grid);
+        accel.cells = self.cells;
+        accel.quarantine = self.quarantine;
+        accel.n_points = self.points.len();
+        accel.extent = Some((quantize(min, self.radius), quantize(max, self.radius)));
+        accel.binned_extent = accel.extent;
+        accel
+    }
+}
+
+impl CompactGrid {
+    fn new(points: &[Vec3], radius: f32) -> Self {
+        // Quarantined points have no key, and are left out
+        let keys: Vec<Option<[i32; 3]>> = points.iter().map(|&p| cell_key(p, radius)).collect();
+        let (min, max) =
+            keys.iter()
+                .flatten()
+                .fold(([i32::MAX; 3], [i32::MIN; 3]), |(min, max), key| {
+                    (
+                        [0, 1, 2].map(|a| min[a].min(key[a])),
+                        [0, 1, 2].map(|a| max[a].max(key[a])),
+                    )
+                });
+        let dims = if keys.iter().all(Option::is_none) {
+            [0; 3]
+        } else {
+            [0, 1, 2].map(|a| max[a] - min[a] + 1)
+        };
+        let mut grid = Self {
+            min,
+            dims,
+            starts: vec![],
+            indices: vec![],
+            occupied: 0,
+        };
+
+        // Counting sort: count the points of each cell, turn the counts into starts, then
+        // place each point, which leaves every cell's points in index order
+        let n_cells = dims.iter().map(|&d| d as usize).product::<usize>();
+        let mut starts = vec![0u32; n_cells + 1];
+        let cell_ids: Vec<Option<usize>> = keys
+            .iter()
+            .map(|key| key.map(|key| grid.cell_id(key).expect("Points are within their bounds")))
+            .collect();
+        for &id in cell_ids.iter().flatten() {
+            starts[id + 1] += 1;
+        }
+        grid.occupied = starts.iter().filter(|&&count| count > 0).count();
+        for id in 0..n_cells {
+            starts[id + 1] += starts[id];
+        }
+
+        let mut next = starts.clone();
+        let mut indices = vec![0u32; starts[n_cells] as usize];
+        for (idx, &id) in cell_ids.iter().enumerate() {
+            if let Some(id) = id {
+                indices[next[id] as usize] = idx as u32;
+                next[id] += 1;
+            }
+        }
+
+        grid.starts = starts;
+        grid.indices = indices;
+        grid
+    }
+
+    /// Index of the cell with the given coordinates, if within the grid
+    fn cell_id(&self, key: [i32; 3]) -> Option<usize> {
+        let mut id = 0;
+        for axis in (0..3).rev() {
+            let offset = key[axis] as i64 - self.min[axis] as i64;
+            if offset < 0 || offset >= self.dims[axis] as i64 {
+                return None;
+            }
+            id = id * self.dims[axis] as usize + offset as usize;
+        }
+        Some(id)
+    }
+
+    /// Coordinates of the cell with the given index, the inverse of [`CompactGrid::cell_id`]
+    fn key(&self, mut id: usize) -> [i32; 3] {
+        [0, 1, 2].map(|axis| {
+            let dim = self.dims[axis] as usize;
+            let offset = id % dim;
+            id /= dim;
+            self.min[axis] + offset as i32
+        })
+    }
+
+    /// Points of the cell with the given coordinates
+    fn cell(&self, key: [i32; 3]) -> Option<&[u32]> {
+        let id = self.cell_id(key)?;
+        let (start, end) = (self.starts[id] as usize, self.starts[id + 1] as usize);
+        Some(&self.indices[start..end])
+    }
+}
+
+/// Axis-aligned bounding box of the finite points
+fn bounds(points: &[Vec3]) -> (Vec3, Vec3) {
+    points.iter().filter(|p| p.is_finite()).fold(
+        (Vec3::splat(f32::INFINITY), Vec3::splat(f32::NEG_INFINITY)),
+        |(min, max), &p| (min.min(p), max.max(p)),
+    )
+}
+
 fn add(mut a: [i32; 3], b: [i32; 3]) -> [i32; 3] {
     a.iter_mut().zip(b).for_each(|(a, b)| *a += b);
     a
 }
 
+/// Coordinates of the cell containing `p`, clamped to [`MAX_CELL`]. NaN coordinates land in
+/// cell 0, so points which may not be finite go through [`cell_key`].
 fn quantize(p: Vec3, radius: f32) -> [i32; 3] {
-    (*p.as_ref()).map(|v| (v / radius).floor() as i32)
+    (*p.as_ref()).map(|v| (v / radius).floor().clamp(-MAX_CELL, MAX_CELL) as i32)
+}
+
+/// Cell to store `p` in, or `None` if it is not finite and belongs in the quarantine
+fn cell_key(p: Vec3, radius: f32) -> Option<[i32; 3]> {
+    p.is_finite().then(|| quantize(p, radius))
 }
 
 fn neighborhood<const N: usize>() -> Vec<[i32; N]> {
@@ -110,3 +904,449 @@ fn combos<const N: usize>(min: i32, max: i32, step: i32) -> Vec<[i32; N]> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cimvr_engine_interface::pcg::Pcg;
+
+    fn random_points(n: usize, size: f32) -> Vec<Vec3> {
+        let mut rng = Pcg::new();
+        (0..n)
+            .map(|_| Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * size)
+            .collect()
+    }
+
+    fn sorted(iter: impl Iterator<Item = usize>) -> Vec<usize> {
+        let mut v: Vec<usize> = iter.collect();
+        v.sort();
+        v
+    }
+
+    #[test]
+    fn test_dense_matches_grid() {
+        let points = random_points(2000, 1.);
+        for radius in [0.05, 0.3, 2.] {
+            let grid = QueryAccelerator::with_mode(&points, radius, AccelMode::Grid);
+            let dense = QueryAccelerator::with_mode(&points, radius, AccelMode::Dense);
+            for i in (0..points.len()).step_by(7) {
+                assert_eq!(
+                    sorted(grid.query_neighbors(&points, i)),
+                    sorted(dense.query_neighbors(&points, i))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_compact_matches_hashmap() {
+        let mut rng = Pcg::new();
+        for case in 0..20 {
+            let n = 1 + rng.gen_u32() as usize % 1500;
+            let mut points = random_points(n, 0.5 + rng.gen_f32() * 2.);
+            // Negative coordinates, flat layouts and coincident points
+            for p in points.iter_mut().step_by(3) {
+                *p -= Vec3::splat(1.);
+            }
+            if case % 4 == 1 {
+                points.iter_mut().for_each(|p| p.y = 0.);
+            }
+            points.push(points[0]);
+
+            let radius = 0.02 + rng.gen_f32() * 0.3;
+            let hashmap = QueryAccelerator::with_mode(&points, radius, AccelMode::Grid);
+            let compact = QueryAccelerator::with_mode(&points, radius, AccelMode::Compact);
+            assert_eq!(compact.cell_count(), hashmap.cell_count());
+            for i in 0..points.len() {
+                assert_eq!(
+                    sorted(compact.query_neighbors(&points, i)),
+                    sorted(hashmap.query_neighbors(&points, i))
+                );
+            }
+            let outside = Vec3::splat(100.);
+            assert_eq!(
+                compact.query_neighbors_by_point(&points, outside).count(),
+                0
+            );
+        }
+    }
+
+    #[test]
+    fn test_huge_and_non_finite_points() {
+        let mut points = random_points(1000, 1.);
+        let huge = [
+            Vec3::X * 1e15,
+            Vec3::X * 1e15 + Vec3::Y,
+            Vec3::NEG_Y * 1e15,
+            Vec3::new(1e15, -1e15, 1e15),
+        ];
+        let non_finite = [
+            Vec3::splat(f32::NAN),
+            Vec3::new(0.5, f32::NAN, 0.5),
+            Vec3::splat(f32::INFINITY),
+            Vec3::NEG_Z * f32::INFINITY,
+        ];
+        for (i, &p) in huge.iter().chain(&non_finite).enumerate() {
+            points[i * 100] = p;
+        }
+        let quarantined = [400, 500, 600, 700];
+
+        let radius = 0.1;
+        let brute = |points: &[Vec3], i: usize| {
+            (0..points.len())
+                .filter(|&j| j != i && points[j].distance_squared(points[i]) <= radius * radius)
+                .collect::<Vec<_>>()
+        };
+        for accel in [
+            QueryAccelerator::new(&points, radius),
+            QueryAccelerator::with_mode(&points, radius, AccelMode::Grid),
+            QueryAccelerator::with_mode(&points, radius, AccelMode::Dense),
+        ] {
+            assert_eq!(accel.validate(&points), Ok(()), "{:?}", accel.mode());
+            assert_eq!(accel.quarantined(), quarantined);
+            for i in 0..points.len() {
+                assert_eq!(sorted(accel.query_neighbors(&points, i)), brute(&points, i));
+                let mut reduced = accel.query_neighbors_radius(&points, i, points[i], radius / 2.);
+                assert!(reduced.all(|j| points[j].is_finite()));
+            }
+        }
+
+        // Without the huge points, the bounds are small enough for the compact grid
+        for (i, &p) in huge.iter().enumerate() {
+            points[i * 100] = p / 1e15;
+        }
+        let compact = QueryAccelerator::with_mode(&points, radius, AccelMode::Compact);
+        assert_eq!(compact.validate(&points), Ok(()));
+        assert_eq!(compact.quarantined(), quarantined);
+        for i in 0..points.len() {
+            assert_eq!(
+                sorted(compact.query_neighbors(&points, i)),
+                brute(&points, i)
+            );
+        }
+        assert_eq!(
+            QueryAccelerator::new(&points, radius).mode(),
+            AccelMode::Compact
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let mut points = random_points(500, 1.);
+        let accel = QueryAccelerator::with_mode(&points, 0.1, AccelMode::Compact);
+        assert_eq!(accel.validate(&points), Ok(()));
+        points[3] += Vec3::X;
+        assert!(accel.validate(&points).unwrap_err().contains("Point 3"));
+        points[3] = Vec3::NAN;
+        assert!(accel.validate(&points).is_err());
+        points.pop();
+        assert!(accel.validate(&points).is_err());
+    }
+
+    #[test]
+    fn test_counting_sort_falls_back_when_sparse() {
+        let mut points = random_points(1000, 1.);
+        assert_eq!(
+            QueryAccelerator::from_counting_sort(&points, 0.05).mode(),
+            AccelMode::Compact
+        );
+
+        // A single outlier would stretch the bounding box over billions of cells
+        points.push(Vec3::splat(1e4));
+        let accel = QueryAccelerator::new(&points, 0.05);
+        assert_eq!(accel.mode(), AccelMode::Grid);
+        assert_eq!(
+            sorted(accel.query_neighbors(&points, 1000)),
+            Vec::<usize>::new()
+        );
+
+        let empty = QueryAccelerator::with_mode(&[], 0.1, AccelMode::Compact);
+        assert_eq!(empty.cell_count(), 0);
+        assert_eq!(empty.query_neighbors_by_point(&[], Vec3::ZERO).count(), 0);
+    }
+
+    #[test]
+    fn test_reduced_radius_queries() {
+        let mut rng = Pcg::new();
+        for case in 0..10 {
+            let mut points = random_points(1000, 1.);
+            points
+                .iter_mut()
+                .step_by(2)
+                .for_each(|p| *p -= Vec3::splat(0.5));
+            let mode = [AccelMode::Grid, AccelMode::Compact, AccelMode::Dense][case % 3];
+            let accel = QueryAccelerator::with_mode(&points, 0.2, mode);
+            for _ in 0..50 {
+                let i = rng.gen_u32() as usize % points.len();
+                // Mostly below the cell size, sometimes above
+                let radius = rng.gen_f32() * 0.3;
+                let point = points[i] + Vec3::splat(rng.gen_f32() * 0.05);
+                let expected: Vec<usize> = (0..points.len())
+                    .filter(|&j| j != i && (points[j] - point).length_squared() <= radius * radius)
+                    .collect();
+                let found = sorted(accel.query_neighbors_radius(&points, i, point, radius));
+                assert_eq!(found, expected, "{:?} {}", mode, radius);
+            }
+        }
+    }
+
+    /// Time full-radius queries against queries at a tenth of the radius for a quarter of the
+    /// points, as for one short-range type out of four. Run with `--release --ignored
+    /// --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_reduced_radius() {
+        let points = random_points(250_000, 10.);
+        let radius = 0.2;
+        let accel = QueryAccelerator::new(&points, radius);
+        let query_radius = |i: usize| match i % 4 {
+            0 => radius / 10.,
+            _ => radius,
+        };
+
+        let start = std::time::Instant::now();
+        let full: usize = (0..points.len())
+            .map(|i| {
+                let r_sq = query_radius(i).powi(2);
+                (accel.query_neighbors(&points, i))
+                    .filter(|&j| (points[j] - points[i]).length_squared() <= r_sq)
+                    .count()
+            })
+            .sum();
+        let full_ms = start.elapsed().as_secs_f32() * 1e3;
+
+        let start = std::time::Instant::now();
+        let reduced: usize = (0..points.len())
+            .map(|i| {
+                accel
+                    .query_neighbors_radius(&points, i, points[i], query_radius(i))
+                    .count()
+            })
+            .sum();
+        let reduced_ms = start.elapsed().as_secs_f32() * 1e3;
+
+        assert_eq!(full, reduced);
+        println!(
+            "full radius: {:.1} ms, per-type radius: {:.1} ms ({} pairs)",
+            full_ms, reduced_ms, full
+        );
+    }
+
+    /// Compare the compact and hashmap grids at 250k particles. Run with
+    /// `--release --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_compact_grid() {
+        let points = random_points(250_000, 10.);
+        let radius = 0.2;
+        for mode in [AccelMode::Grid, AccelMode::Compact] {
+            let start = std::time::Instant::now();
+            let accel = QueryAccelerator::with_mode(&points, radius, mode);
+            let build_ms = start.elapsed().as_secs_f32() * 1e3;
+
+            let start = std::time::Instant::now();
+            let pairs: usize = (0..points.len())
+                .map(|i| accel.query_neighbors(&points, i).count())
+                .sum();
+            let query_ms = start.elapsed().as_secs_f32() * 1e3;
+
+            println!(
+                "{:?}: build {:.1} ms, query {:.1} ms ({} pairs), {:.1} MB",
+                mode,
+                build_ms,
+                query_ms,
+                pairs,
+                accel.memory_bytes() as f32 / 1e6
+            );
+        }
+    }
+
+    #[test]
+    fn test_raycast_matches_march() {
+        let points = random_points(500, 1.);
+        let radius = 0.1;
+        let accel = QueryAccelerator::with_mode(&points, radius, AccelMode::Compact);
+        let (lo, hi) = accel.extent.unwrap();
+        let inside = |c: &[i32; 3]| (0..3).all(|a| lo[a] <= c[a] && c[a] <= hi[a]);
+
+        // Fine steps along the ray, keeping each new cell
+        let march = |origin: Vec3, dir: Vec3| {
+            let mut cells: Vec<[i32; 3]> = vec![];
+            for k in 0..50_000 {
+                let cell = quantize(origin + dir * (k as f32 * 1e-4), radius);
+                if inside(&cell) && cells.last() != Some(&cell) {
+                    cells.push(cell);
+                }
+            }
+            cells
+        };
+
+        let mut rng = Pcg::new();
+        let mut random = || Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32());
+        let mut rays: Vec<(Vec3, Vec3)> = (0..50)
+            .map(|_| (random() * 3. - 1., (random() * 2. - 1.).normalize()))
+            .collect();
+        // Parallel to the axes, from inside a cell and from outside the grid
+        rays.extend([
+            (Vec3::new(0.55, 0.55, 0.55), Vec3::X),
+            (Vec3::new(0.33, 0.71, 0.12), Vec3::NEG_Y),
+            (Vec3::new(-1., 0.25, 0.25), Vec3::X),
+            (Vec3::new(-1., 2., 0.25), Vec3::X),
+        ]);
+
+        let mut hits = 0;
+        for (origin, dir) in rays {
+            let cells: Vec<[i32; 3]> = accel.raycast_cells(origin, dir).collect();
+            assert_eq!(cells, march(origin, dir), "{} {}", origin, dir);
+            hits += !cells.is_empty() as usize;
+        }
+        assert!(hits > 10, "{}", hits);
+        assert_eq!(accel.raycast_cells(Vec3::ZERO, Vec3::ZERO).count(), 0);
+    }
+
+    #[test]
+    fn test_mode_selection() {
+        let points = random_points(1000, 1.);
+        assert_eq!(
+            QueryAccelerator::new(&points, 0.05).mode(),
+            AccelMode::Compact
+        );
+        assert_eq!(QueryAccelerator::new(&points, 0.9).mode(), AccelMode::Dense);
+        assert_eq!(QueryAccelerator::new(&points, 5.).mode(), AccelMode::Dense);
+        assert_eq!(QueryAccelerator::new(&[], 1.).mode(), AccelMode::Dense);
+    }
+
+    #[test]
+    fn test_overlay_after_moving() {
+        let mut rng = Pcg::new();
+        let mut points = random_points(2000, 1.);
+        for mode in [AccelMode::Grid, AccelMode::Compact] {
+            let mut accel = QueryAccelerator::with_mode(&points, 0.1, mode);
+            for _ in 0..5 {
+                for p in points.iter_mut() {
+                    *p += (Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) - 0.5) * 0.02;
+                }
+                points[3] = Vec3::NAN;
+                assert!(accel.validate(&points).is_err());
+                assert!(accel.refresh_overlay(&points, points.len()));
+                assert!(accel.overlay_len() > 0);
+                accel.validate(&points).unwrap();
+
+                let fresh = QueryAccelerator::with_mode(&points, 0.1, mode);
+                for i in (0..points.len()).step_by(11) {
+                    assert_eq!(
+                        sorted(accel.query_neighbors(&points, i)),
+                        sorted(fresh.query_neighbors(&points, i))
+                    );
+                    assert_eq!(
+                        sorted(accel.query_neighbors_radius(&points, i, points[i], 0.04)),
+                        sorted(fresh.query_neighbors_radius(&points, i, points[i], 0.04))
+                    );
+                }
+            }
+            // Too much movement is left to a fresh build
+            let scattered: Vec<Vec3> = points.iter().map(|p| Vec3::new(p.z, p.x, p.y)).collect();
+            assert!(!accel.refresh_overlay(&scattered, 100));
+            assert_eq!(accel.overlay_len(), 0);
+        }
+    }
+
+    #[test]
+    fn test_overlay_pruning() {
+        let points = random_points(2000, 1.);
+        let shifted = |by: f32| -> Vec<Vec3> { points.iter().map(|&p| p + by).collect() };
+        for mode in [AccelMode::Grid, AccelMode::Compact] {
+            let mut accel = QueryAccelerator::with_mode(&points, 0.1, mode);
+            let away = shifted(5.);
+            assert!(accel.refresh_overlay(&away, points.len()));
+            let cells = accel.overlay_cells();
+
+            // The cells left behind linger for a while, then go
+            let further = shifted(10.);
+            assert!(accel.refresh_overlay(&further, points.len()));
+            let peak = accel.memory_bytes();
+            assert!(accel.overlay_cells() > cells);
+            for _ in 1..PRUNE_AFTER {
+                assert!(accel.refresh_overlay(&further, points.len()));
+            }
+            assert_eq!(accel.overlay_cells(), cells);
+            assert!(accel.memory_bytes() < peak);
+
+            // Every point is still found, where it is now
+            accel.validate(&further).unwrap();
+            let fresh = QueryAccelerator::with_mode(&further, 0.1, mode);
+            assert_eq!(accel.overlay_cells(), fresh.cell_count());
+            for i in (0..points.len()).step_by(7) {
+                assert_eq!(
+                    sorted(accel.query_neighbors(&further, i)),
+                    sorted(fresh.query_neighbors(&further, i))
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_amortized_rebuild() {
+        let mut points = random_points(3000, 1.);
+        points[10] = Vec3::splat(f32::INFINITY);
+        let mut rebuild = RebuildInProgress::new(&points, 0.1, 4);
+        let mut steps = 0;
+        while !rebuild.step() {
+            steps += 1;
+            assert!(rebuild.progress() < 1.);
+        }
+        assert_eq!(steps, 3);
+
+        let accel = rebuild.finish();
+        let fresh = QueryAccelerator::with_mode(&points, 0.1, AccelMode::Grid);
+        assert_eq!(accel.mode(), AccelMode::Grid);
+        assert_eq!(accel.cell_layout(), fresh.cell_layout());
+        assert_eq!(accel.quarantined(), fresh.quarantined());
+        accel.validate(&points).unwrap();
+        for i in (0..points.len()).step_by(13) {
+            assert_eq!(
+                sorted(accel.query_neighbors(&points, i)),
+                sorted(fresh.query_neighbors(&points, i))
+            );
+        }
+
+        // Few cells are not worth a grid
+        let rebuild = RebuildInProgress::new(&points, 0.9, 4);
+        assert_eq!(rebuild.finish().mode(), AccelMode::Dense);
+    }
+
+    #[test]
+    fn test_toroidal_queries() {
+        let period = Vec3::new(1., 0.8, 1.2);
+        let torus = DistanceMetric::Toroidal { period };
+        for (n, radius, mode) in [(2000, 0.1, AccelMode::Compact), (12, 0.3, AccelMode::Dense)] {
+            // Unwrapped, over a few periods either side of the origin
+            let points: Vec<Vec3> = (random_points(n, 3.).into_iter())
+                .map(|p| p - Vec3::splat(1.))
+                .collect();
+            let accel = QueryAccelerator::with_metric(&points, radius, torus);
+            assert_eq!(accel.mode(), mode);
+            assert_eq!(accel.metric(), torus);
+            accel.validate(&points).unwrap();
+
+            let brute = |i: usize, radius: f32| -> Vec<usize> {
+                (0..points.len())
+                    .filter(|&j| j != i && torus.distance(points[i], points[j]) <= radius)
+                    .collect()
+            };
+            let mut across = 0;
+            for i in 0..points.len() {
+                let expected = brute(i, radius);
+                across += (expected.iter())
+                    .filter(|&&j| points[i].distance(points[j]) > radius)
+                    .count();
+                assert_eq!(sorted(accel.query_neighbors(&points, i)), expected);
+                assert_eq!(
+                    sorted(accel.query_neighbors_radius(&points, i, points[i], radius / 2.)),
+                    brute(i, radius / 2.)
+                );
+            }
+            assert!(across > 0);
+        }
+    }
+}
@@ -0,0 +1,244 @@
+//! Golden trajectory tests, guarding the physics against accidental changes.
+//!
+//! Each scenario is deterministic and short enough to stay out of the chaotic regime, and is
+//! compared against a digest recorded here. When a change to the physics is intentional, print
+//! new goldens with
+//!
+//! ```sh
+//! cargo test_pc regression::print_goldens -- --ignored --nocapture
+//! ```
+//!
+//! and paste the output over the values in `GOLDENS`, explaining the change in the commit.
+use cimvr_common::glam::Vec3;
+
+use crate::sim::{
+    Activity, Behaviour, FarFieldSampling, Gravity, InteractionMode, Particle, SimConfig, SimState,
+    Wall,
+};
+
+/// Summary of a simulation state
+#[derive(Clone, Copy, Debug)]
+struct Digest {
+    /// Sum of positions along each axis
+    pos_sum: [f32; 3],
+    kinetic_energy: f32,
+    /// Hash of positions quantized to a coarse grid. Too sensitive to rounding to compare for
+    /// the Newtonian integrator, but recorded for reference.
+    quantized_hash: u64,
+}
+
+/// Recorded result of a scenario
+struct Golden {
+    name: &'static str,
+    pos_sum: [f32; 3],
+    kinetic_energy: f32,
+    /// Exact hash, only for integrators which are bit-for-bit reproducible
+    quantized_hash: Option<u64>,
+}
+
+const N_PARTICLES: usize = 200;
+const N_STEPS: usize = 100;
+const DT: f32 = 1e-3;
+
+/// Relative tolerance for the sums
+const TOLERANCE: f32 = 1e-3;
+
+const GOLDENS: &[Golden] = &[
+    Golden {
+        name: "newton",
+        pos_sum: [-1.7603111, 3.523417, -1.4094121],
+        kinetic_energy: 108.35917,
+        quantized_hash: None,
+    },
+    Golden {
+        name: "fields",
+        pos_sum: [-1.979452, 3.533014, -1.3935875],
+        kinetic_energy: 109.14708,
+        quantized_hash: None,
+    },
+    Golden {
+        name: "far_field",
+        pos_sum: [-1.7869322, 3.3524473, -1.5367366],
+        kinetic_energy: 129.01186,
+        quantized_hash: None,
+    },
+    Golden {
+        name: "capped",
+        pos_sum: [-1.4230638, 4.597863, -1.120055],
+        kinetic_energy: 108.61551,
+        quantized_hash: None,
+    },
+    Golden {
+        name: "active",
+        pos_sum: [-1.7954453, 3.6295164, -1.7235329],
+        kinetic_energy: 122.14096,
+        quantized_hash: None,
+    },
+];
+
+fn scenario(name: &str) -> Digest {
+    let mut sim = SimState::from_particles(config(), particles());
+    match name {
+        "newton" => {}
+        // Symmetric, so forces are computed pairwise, with every external force on top
+        "fields" => {
+            let mut config = config();
+            config.behaviours[1] = config.behaviours[2];
+            config.gravity = Some(Gravity {
+                down: -Vec3::Y,
+                weights: vec![1., -0.5],
+            });
+            sim = SimState::from_particles(config, particles());
+            sim.set_walls(vec![Wall {
+                point: Vec3::new(0., -0.6, 0.),
+                normal: Vec3::Y,
+                affinity: vec![0.5, -0.5],
+                threshold: 0.05,
+                repulse: 10.,
+                range: 0.2,
+            }]);
+            sim.set_homes_to_current();
+            sim.set_tether_stiffness(2.);
+        }
+        "far_field" => sim.set_far_field(Some(FarFieldSampling {
+            k: 4,
+            near_radius: 0.1,
+        })),
+        "capped" => {
+            sim.set_max_neighbors(Some(6));
+            sim.set_ghost_walls(Some(0.5));
+        }
+        "active" => {
+            let mut config = config();
+            config.activity = Some(Activity {
+                speed: vec![0.5],
+                rotational_diffusion: vec![1.],
+                alignment: 1.,
+            });
+            sim = SimState::from_particles(config, particles());
+        }
+        _ => panic!("Unknown scenario {}", name),
+    }
+    (0..N_STEPS).for_each(|_| sim.step(DT));
+    digest(&sim)
+}
+
+fn config() -> SimConfig {
+    let behav = Behaviour {
+        default_repulse: 10.,
+        inter_threshold: 0.05,
+        inter_strength: 1.,
+        inter_max_dist: 0.2,
+        anisotropy: Vec3::ONE,
+        polarity: 0.,
+        mode: InteractionMode::Potential,
+    };
+
+    SimConfig {
+        colors: vec![[1., 0., 0.], [0., 0., 1.]],
+        behaviours: vec![
+            behav.with_inter_strength(3.),
+            behav.with_inter_strength(-2.),
+            behav.with_inter_strength(1.),
+            behav.with_inter_strength(4.),
+        ],
+        damping: 20.,
+        ..Default::default()
+    }
+}
+
+/// Particles in the unit cube, placed by a fixed xorshift sequence
+fn particles() -> Vec<Particle> {
+    let mut state = 0x2545_f491_4f6c_dd1d_u64;
+    let mut rand = move || {
+        state ^= state << 13;
+        state ^= state >> 7;
+        state ^= state << 17;
+        (state >> 40) as f32 / (1 << 24) as f32
+    };
+
+    (0..N_PARTICLES)
+        .map(|i| Particle {
+            pos: Vec3::new(rand(), rand(), rand()) - Vec3::splat(0.5),
+            vel: Vec3::ZERO,
+            color: (i % 2) as u8,
+        })
+        .collect()
+}
+
+fn digest(sim: &SimState) -> Digest {
+    let mut pos_sum = Vec3::ZERO;
+    let mut kinetic_energy = 0.;
+    // FNV-1a
+    let mut quantized_hash = 0xcbf2_9ce4_8422_2325_u64;
+
+    for particle in sim.particles() {
+        pos_sum += particle.pos;
+        kinetic_energy += particle.vel.length_squared() / 2.;
+        for v in particle.pos.to_array() {
+            for byte in ((v * 1e3).round() as i32).to_le_bytes() {
+                quantized_hash = (quantized_hash ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+            }
+        }
+    }
+
+    Digest {
+        pos_sum: pos_sum.to_array(),
+        kinetic_energy,
+        quantized_hash,
+    }
+}
+
+fn assert_close(name: &str, what: &str, actual: f32, expected: f32) {
+    let scale = expected.abs().max(1.);
+    assert!(
+        (actual - expected).abs() <= TOLERANCE * scale,
+        "{}: {} is {}, expected {}",
+        name,
+        what,
+        actual,
+        expected
+    );
+}
+
+#[test]
+fn golden_trajectories() {
+    for golden in GOLDENS {
+        let digest = scenario(golden.name);
+        for axis in 0..3 {
+            assert_close(
+                golden.name,
+                "position sum",
+                digest.pos_sum[axis],
+                golden.pos_sum[axis],
+            );
+        }
+        assert_close(
+            golden.name,
+            "kinetic energy",
+            digest.kinetic_energy,
+            golden.kinetic_energy,
+        );
+        if let Some(hash) = golden.quantized_hash {
+            assert_eq!(
+                digest.quantized_hash, hash,
+                "{}: position hash",
+                golden.name
+            );
+        }
+    }
+}
+
+#[test]
+#[ignore]
+fn print_goldens() {
+    for golden in GOLDENS {
+        let digest = scenario(golden.name);
+        println!("Golden {{");
+        println!("    name: {:?},", golden.name);
+        println!("    pos_sum: {:?},", digest.pos_sum);
+        println!("    kinetic_energy: {:?},", digest.kinetic_energy);
+        println!("    quantized_hash: None, // {:#x}", digest.quantized_hash);
+        println!("}},");
+    }
+}
@@ -0,0 +1,372 @@
+//! One-click relaxation of the current state into a nearby energy minimum. Newton finds
+//! large-scale structure quickly; annealing with Metropolis at a falling temperature then
+//! cleans it up.
+//!
+//! [`Relax`] is a small state machine. Starting it captures a [`SimSnapshot`] and switches to
+//! Metropolis at a temperature taken from the measured kinetic energy, which then decays
+//! exponentially over a number of sweeps, run a budget at a time over many frames. Once done,
+//! stepping holds until [`Relax::accept`] keeps the relaxed state or [`Relax::revert`]
+//! restores the snapshot. Either way, the previous integrator comes back.
+use std::fmt;
+
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mcmc::{Integrator, MetropolisConfig, SwitchPolicy},
+    persist::SimSnapshot,
+    sim::SimState,
+};
+
+/// Anyone to client: drive the relaxation, e.g. from a button
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub enum RelaxCommand {
+    Start,
+    /// Keep the relaxed state
+    Accept,
+    /// Go back to the state before relaxing, also cancelling a relaxation in progress
+    Revert,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelaxConfig {
+    /// Sweeps over the whole annealing schedule
+    pub sweeps: usize,
+    /// Final temperature as a fraction of the starting one
+    pub final_ratio: f32,
+    /// Lowest starting temperature, for particles at rest
+    pub min_temperature: f32,
+    /// Half-width of the cube Metropolis moves are drawn from
+    pub walk_sigma: f32,
+    /// Sweeps run each frame
+    pub sweeps_per_frame: usize,
+}
+
+/// Outcome of a relaxation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RelaxReport {
+    pub sweeps: usize,
+    /// Potential energy of the system before and after, see [`SimState::potential_energy`]
+    pub start_energy: f32,
+    pub end_energy: f32,
+}
+
+/// A relaxation in progress, or waiting for a decision
+#[derive(Clone, Debug, PartialEq)]
+pub struct RelaxRun {
+    config: RelaxConfig,
+    snapshot: SimSnapshot,
+    /// Integrator to go back to
+    previous: Integrator,
+    start_temperature: f32,
+    start_energy: f32,
+    /// Sweeps done so far
+    sweep: usize,
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Relax {
+    #[default]
+    Idle,
+    Running(Box<RelaxRun>),
+    /// Done, with stepping held until the result is accepted or reverted
+    Done(Box<RelaxRun>, RelaxReport),
+}
+
+impl Default for RelaxConfig {
+    fn default() -> Self {
+        Self {
+            sweeps: 300,
+            final_ratio: 1e-3,
+            min_temperature: 1e-4,
+            walk_sigma: 1e-2,
+            sweeps_per_frame: 10,
+        }
+    }
+}
+
+/// Temperature at `sweep` of an exponential schedule falling from `start` to
+/// `start * final_ratio` over `sweeps`
+pub fn anneal_temperature(start: f32, final_ratio: f32, sweep: usize, sweeps: usize) -> f32 {
+    let t = sweep as f32 / (sweeps.max(2) - 1) as f32;
+    start * final_ratio.powf(t.min(1.))
+}
+
+impl RelaxReport {
+    /// Potential energy shed by relaxing; negative if it rose
+    pub fn energy_drop(&self) -> f32 {
+        self.start_energy - self.end_energy
+    }
+}
+
+impl Relax {
+    /// Snapshot the simulation and start annealing it with Metropolis. Does nothing unless
+    /// idle; returns whether it started.
+    pub fn start(
+        &mut self,
+        config: RelaxConfig,
+        sim: &mut SimState,
+        integrator: &mut Integrator,
+        rng: &mut Pcg,
+    ) -> bool {
+        if !self.is_idle() {
+            return false;
+        }
+
+        let snapshot = SimSnapshot::capture(sim);
+        let previous = integrator.clone();
+        let start_temperature = sim.kinetic_temperature().max(config.min_temperature);
+        let metropolis = Integrator::Metropolis(MetropolisConfig {
+            temperature: start_temperature,
+            walk_sigma: config.walk_sigma,
+        });
+        integrator.switch_to(metropolis, SwitchPolicy::Reset, sim, rng);
+
+        *self = Relax::Running(Box::new(RelaxRun {
+            config,
+            snapshot,
+            previous,
+            start_temperature,
+            start_energy: sim.potential_energy(),
+            sweep: 0,
+        }));
+        true
+    }
+
+    /// Run this frame's budget of sweeps, recovering from a panic like
+    /// [`Integrator::try_step`]. Returns the report once the schedule is done.
+    pub fn advance(
+        &mut self,
+        sim: &mut SimState,
+        integrator: &mut Integrator,
+        rng: &mut Pcg,
+    ) -> Result<Option<RelaxReport>, String> {
+        let Relax::Running(run) = self else {
+            return Ok(None);
+        };
+
+        let budget = run.config.sweeps_per_frame.max(1);
+        for _ in 0..budget {
+            if run.sweep >= run.config.sweeps {
+                break;
+            }
+            integrator.set_temperature(anneal_temperature(
+                run.start_temperature,
+                run.config.final_ratio,
+                run.sweep,
+                run.config.sweeps,
+            ));
+            integrator.try_step(sim, 0., rng)?;
+            run.sweep += 1;
+        }
+        if run.sweep < run.config.sweeps {
+            return Ok(None);
+        }
+
+        // Measure at the final positions rather than those of the last sweep's start
+        sim.rebuild_accel();
+        let report = RelaxReport {
+            sweeps: run.sweep,
+            start_energy: run.start_energy,
+            end_energy: sim.potential_energy(),
+        };
+        let Relax::Running(run) = std::mem::take(self) else {
+            unreachable!()
+        };
+        *self = Relax::Done(run, report);
+        Ok(Some(report))
+    }
+
+    /// Keep the relaxed state and go back to the previous integrator. Only once done; returns
+    /// whether it was.
+    pub fn accept(
+        &mut self,
+        sim: &mut SimState,
+        integrator: &mut Integrator,
+        rng: &mut Pcg,
+    ) -> bool {
+        let Relax::Done(..) = self else {
+            return false;
+        };
+        let Relax::Done(run, _) = std::mem::take(self) else {
+            unreachable!()
+        };
+        integrator.switch_to(run.previous, SwitchPolicy::Reset, sim, rng);
+        true
+    }
+
+    /// Restore the simulation as it was before relaxing, and the previous integrator. Only
+    /// what a [`SimSnapshot`] holds comes back. Returns whether there was anything to revert.
+    pub fn revert(
+        &mut self,
+        sim: &mut SimState,
+        integrator: &mut Integrator,
+        rng: &mut Pcg,
+    ) -> bool {
+        let run = match std::mem::take(self) {
+            Relax::Idle => return false,
+            Relax::Running(run) | Relax::Done(run, _) => run,
+        };
+        *sim = run.snapshot.restore(rng);
+        *integrator = run.previous;
+        true
+    }
+
+    pub fn is_idle(&self) -> bool {
+        matches!(self, Relax::Idle)
+    }
+
+    /// Fraction of the schedule done, from 0 to 1, unless idle
+    pub fn progress(&self) -> Option<f32> {
+        match self {
+            Relax::Idle => None,
+            Relax::Running(run) => Some(run.sweep as f32 / run.config.sweeps.max(1) as f32),
+            Relax::Done(..) => Some(1.),
+        }
+    }
+
+    /// Text progress bar `width` characters wide, unless idle
+    pub fn progress_bar(&self, width: usize) -> Option<String> {
+        let progress = self.progress()?;
+        let filled = ((progress * width as f32).round() as usize).min(width);
+        Some(format!(
+            "[{}{}] {:.0}%",
+            "#".repeat(filled),
+            "-".repeat(width - filled),
+            progress * 100.
+        ))
+    }
+}
+
+impl fmt::Display for RelaxReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Relaxed over {} sweeps: potential energy {:.4} -> {:.4}, a drop of {:.4}",
+            self.sweeps,
+            self.start_energy,
+            self.end_energy,
+            self.energy_drop()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mcmc::NewtonConfig,
+        sim::{Behaviour, Field, SimConfig},
+    };
+
+    fn sim(rng: &mut Pcg) -> SimState {
+        let mut config = SimConfig {
+            colors: vec![[1.; 3]; 3],
+            behaviours: vec![Behaviour::default(); 9],
+            damping: 100.,
+            ..Default::default()
+        };
+        config.randomize_field(Field::Strength, false, rng);
+        config.symmetrize();
+        let mut sim = SimState::new(rng, config, 150);
+        // Some structure and motion from the explicit integrator to start from
+        for _ in 0..20 {
+            sim.step(1e-3);
+        }
+        sim
+    }
+
+    fn config() -> RelaxConfig {
+        RelaxConfig {
+            sweeps: 40,
+            sweeps_per_frame: 15,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_schedule() {
+        assert_eq!(anneal_temperature(2., 1e-2, 0, 5), 2.);
+        assert!((anneal_temperature(2., 1e-2, 2, 5) - 0.2).abs() < 1e-6);
+        assert!((anneal_temperature(2., 1e-2, 4, 5) - 0.02).abs() < 1e-6);
+        assert!((anneal_temperature(2., 1e-2, 9, 5) - 0.02).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_transitions() {
+        let mut rng = Pcg::new();
+        let mut sim = sim(&mut rng);
+        let newton = Integrator::Newton(NewtonConfig::default());
+        let mut integrator = newton.clone();
+        let mut relax = Relax::default();
+
+        // Nothing to decide on while idle
+        assert!(!relax.accept(&mut sim, &mut integrator, &mut rng));
+        assert!(!relax.revert(&mut sim, &mut integrator, &mut rng));
+        assert_eq!(relax.progress_bar(4), None);
+
+        assert!(relax.start(config(), &mut sim, &mut integrator, &mut rng));
+        assert!(matches!(integrator, Integrator::Metropolis(_)));
+        assert!(!relax.start(config(), &mut sim, &mut integrator, &mut rng));
+        let start_temperature = integrator.temperature().unwrap();
+
+        // Three frames of 15 sweeps cover the 40 of the schedule
+        let mut reports = vec![];
+        for _ in 0..3 {
+            assert!(!relax.accept(&mut sim, &mut integrator, &mut rng));
+            reports.push(relax.advance(&mut sim, &mut integrator, &mut rng).unwrap());
+        }
+        assert_eq!(reports[..2], [None, None]);
+        let report = reports[2].unwrap();
+        assert_eq!(report.sweeps, 40);
+        assert!(report.energy_drop() > 0., "{}", report);
+        assert!(integrator.temperature().unwrap() < start_temperature * 2e-3);
+        assert_eq!(relax.progress_bar(4).unwrap(), "[####] 100%");
+
+        // Held once done
+        let held = sim.particles().to_vec();
+        assert_eq!(relax.advance(&mut sim, &mut integrator, &mut rng), Ok(None));
+        assert_eq!(sim.particles(), held);
+
+        assert!(relax.accept(&mut sim, &mut integrator, &mut rng));
+        assert!(relax.is_idle());
+        assert_eq!(integrator, newton);
+        for (a, b) in held.iter().zip(sim.particles()) {
+            assert_eq!(a.pos, b.pos);
+        }
+    }
+
+    #[test]
+    fn test_revert_is_exact() {
+        let mut rng = Pcg::new();
+        let mut sim = sim(&mut rng);
+        let before = sim.particles().to_vec();
+        let newton = Integrator::Newton(NewtonConfig::default());
+        let mut integrator = newton.clone();
+        let mut relax = Relax::default();
+
+        // From partway through the schedule, and from the end of it
+        for frames in [1, 3] {
+            assert!(relax.start(config(), &mut sim, &mut integrator, &mut rng));
+            for _ in 0..frames {
+                relax.advance(&mut sim, &mut integrator, &mut rng).unwrap();
+            }
+            assert_ne!(sim.particles(), before);
+            assert!(relax.revert(&mut sim, &mut integrator, &mut rng));
+            assert!(relax.is_idle());
+            assert_eq!(integrator, newton);
+            for (a, b) in before.iter().zip(sim.particles()) {
+                assert_eq!(
+                    a.pos.to_array().map(f32::to_bits),
+                    b.pos.to_array().map(f32::to_bits)
+                );
+                assert_eq!(
+                    a.vel.to_array().map(f32::to_bits),
+                    b.vel.to_array().map(f32::to_bits)
+                );
+                assert_eq!(a.color, b.color);
+            }
+        }
+    }
+}
@@ -0,0 +1,1730 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    f32::consts::TAU,
+    hash::{Hash, Hasher},
+    ops::Range,
+};
+
+use cimvr_common::{
+    glam::Vec3,
+    render::{Mesh, MeshHandle, Vertex},
+};
+use cimvr_engine_interface::{pkg_namespace, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diagnostics::{sampled_quantile, EscapeBound},
+    measure::{CountingSphere, Ruler},
+    palette::{viridis, ColorVision},
+    query_accel::QueryAccelerator,
+    sim::{SimState, TimeBubble},
+    sweep::Sweep,
+    text::{add_label, add_text, add_text_facing},
+};
+
+/// Largest number of particles in a single mesh, keeping each upload message reasonably sized
+pub const MAX_CHUNK_PARTICLES: usize = 65_536;
+
+/// Largest number of meshes the particles are split into. Beyond this, chunks grow instead.
+pub const MAX_CHUNKS: usize = 32;
+
+/// Quantile of the stress mapped to the top of the ramp in [`ColorMode::Force`]
+const FORCE_QUANTILE: f32 = 0.95;
+
+/// Particles sampled to estimate the quantile
+const FORCE_SAMPLES: usize = 1_024;
+
+/// Weight of the newest quantile in the rolling scale of [`ColorMode::Force`]
+const FORCE_SMOOTHING: f32 = 0.05;
+
+/// Brightness of hidden types drawn with [`HiddenStyle::Dim`], relative to their luminance
+const HIDDEN_DIM: f32 = 0.15;
+
+/// Handle of the first chunk; the others follow it
+const CHUNK_HANDLE_BASE: u128 = pkg_namespace!("Simulation");
+
+/// Handle of the echo layer, see [`Echoes`]
+pub const ECHO_HANDLE: MeshHandle = MeshHandle::new(pkg_namespace!("Echoes"));
+
+/// Handle of the time bubble outline, see [`bubble_mesh`]
+pub const BUBBLE_HANDLE: MeshHandle = MeshHandle::new(pkg_namespace!("TimeBubble"));
+
+/// Handle of the heading ticks, see [`heading_mesh`]
+pub const HEADING_HANDLE: MeshHandle = MeshHandle::new(pkg_namespace!("Headings"));
+
+/// Handle of the clipping plane outline, see [`clip_mesh`]
+pub const CLIP_HANDLE: MeshHandle = MeshHandle::new(pkg_namespace!("ClipPlane"));
+
+/// Normals of the axis aligned clipping planes, see [`ClipPlane::axis`]
+pub const CLIP_NORMALS: [Vec3; 3] = [Vec3::X, Vec3::Y, Vec3::Z];
+
+/// Color of the clipping plane outline
+const CLIP_COLOR: [f32; 3] = [0.8, 0.5, 0.2];
+
+/// Handle of the per-type legend, see [`legend_mesh`]
+pub const LEGEND_HANDLE: MeshHandle = MeshHandle::new(pkg_namespace!("Legend"));
+
+/// Height of the capitals of measurement readouts and legend entries
+pub const LABEL_SIZE: f32 = 0.04;
+
+/// Handle of the query accelerator's cell outlines, see [`cells_mesh`]
+pub const CELLS_HANDLE: MeshHandle = MeshHandle::new(pkg_namespace!("AccelCells"));
+
+/// Handle of the measurement tools' overlay, see [`measure_mesh`]
+pub const MEASURE_HANDLE: MeshHandle = MeshHandle::new(pkg_namespace!("Measure"));
+
+/// Number of cell outlines drawn unless asked otherwise, about 100k lines
+pub const DEFAULT_CELL_BUDGET: usize = 4_096;
+
+/// Coarsest level of detail of the cell outlines, in halvings of the resolution
+const MAX_CELL_LOD: u32 = 4;
+
+/// Point mesh of the particles, kept between frames so that only the parts which changed are
+/// rebuilt. Split into chunks of consecutive particles, each uploaded as its own mesh.
+pub struct ParticleMesh {
+    chunks: Vec<Mesh>,
+    chunk_size: usize,
+    /// Hash of the palette the vertex colors were written with
+    palette_hash: Option<u64>,
+    /// Lighten pinned particles, to tell them apart
+    pub tint_pinned: bool,
+    /// Darken particles in proportion to their blend value
+    pub tint_blend: bool,
+    /// Show colors as seen with this color vision, for checking palettes
+    pub color_vision: ColorVision,
+    /// What the vertex colors show
+    pub color_mode: ColorMode,
+    /// Largest fraction by which each particle's brightness is perturbed, by an amount fixed
+    /// for its index (see [`dither_offset`]), so that neighbors of one type can be told apart
+    /// in a dense cloud. Zero for none.
+    pub dither: f32,
+    /// Factor applied to every vertex color, before `gamma`
+    pub brightness: f32,
+    /// Vertex colors are raised to the power `1 / gamma`, so values above one lift dark
+    /// palettes off the background
+    pub gamma: f32,
+    /// Level of each particle for [`ColorMode::Residence`], see [`ParticleMesh::set_residence`]
+    residence: Vec<f32>,
+    residence_hash: u64,
+    /// Rolling log-scaled stress at the top of the ramp in [`ColorMode::Force`]
+    force_scale: Option<f32>,
+    /// Whether each type is hidden; types beyond the end are shown. Only affects drawing.
+    hidden: Vec<bool>,
+    hidden_style: HiddenStyle,
+    /// Cross-section of the cloud to show, if any
+    clip: Option<ClipPlane>,
+    /// Whether visibility changed since the last full update
+    visibility_dirty: bool,
+    /// Number of particles at the last full update
+    meshed: usize,
+}
+
+/// How the particles of hidden types are drawn
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HiddenStyle {
+    /// Left out of the mesh entirely
+    #[default]
+    Invisible,
+    /// As dim grey points, to keep the shown types in context
+    Dim,
+}
+
+/// Anyone to client: show a cross-section of the cloud, or all of it with `None`
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct SetClip {
+    pub clip: Option<ClipPlane>,
+}
+
+/// Anyone to client: show or hide a legend of the particle types next to the simulation, see
+/// [`legend_mesh`]
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct ShowLegend {
+    pub show: bool,
+}
+
+/// Anyone to client: outline the occupied cells of the query accelerator, at most `budget` of
+/// them (such as [`DEFAULT_CELL_BUDGET`]), or hide the outlines with `None`. See
+/// [`cells_mesh`].
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct ShowAccelCells {
+    pub budget: Option<usize>,
+}
+
+/// Anyone to client: draw ticks along the headings of a sample of polar particles, or remove
+/// them with `None`. See [`heading_mesh`].
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub struct ShowHeadingTicks {
+    pub ticks: Option<MarkerConfig>,
+}
+
+/// Anyone to client: trail each particle with dimmed echoes of where it was, or remove them
+/// with `None`. See [`Echoes`].
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub struct SetEchoes {
+    pub echoes: Option<EchoConfig>,
+}
+
+/// Cross-section of the cloud, to see inside it. Particles behind the plane, or outside the
+/// slab around it, are left out of the mesh or dimmed; the simulation is unaffected.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ClipPlane {
+    /// Unit normal, pointing to the side which is shown
+    pub normal: Vec3,
+    /// Signed distance of the plane from the origin, along the normal
+    pub offset: f32,
+    /// Show only a slab of this thickness centered on the plane, rather than a half-space
+    pub slab: Option<f32>,
+    /// How the clipped particles are drawn
+    pub style: HiddenStyle,
+}
+
+/// Anyone to client: choose what the colors of the particles show
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub struct SetColorMode {
+    pub mode: ColorMode,
+    /// Darken particles by their blend value, see [`ParticleMesh::tint_blend`]
+    pub tint_blend: bool,
+}
+
+/// What the vertex colors of the particles show
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Default, Serialize, Deserialize)]
+pub enum ColorMode {
+    /// The color of each particle's type
+    #[default]
+    Type,
+    /// How long each particle has stayed in its neighborhood, bright while churning and
+    /// dark once stable; see [`crate::diagnostics::Residence`]
+    Residence,
+    /// How hard each particle is pushed, log scaled, so that force chains and stressed
+    /// regions light up; see [`SimState::stress`]
+    Force,
+}
+
+/// Adjustments of every vertex color, see [`ParticleMesh::dither`]
+#[derive(Clone, Copy, Debug)]
+struct Tone {
+    dither: f32,
+    brightness: f32,
+    gamma: f32,
+}
+
+/// Overlay of small per-type line glyphs, so types can be told apart without color
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MarkerConfig {
+    /// Draw a marker on every `stride`th particle
+    pub stride: usize,
+    /// Half-width of each glyph
+    pub size: f32,
+    /// Largest number of vertices in the overlay; markers beyond it are left out
+    pub max_vertices: usize,
+}
+
+/// Which past states of the cloud [`Echoes`] keeps and draws
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct EchoConfig {
+    /// Frames between snapshots
+    pub stride: usize,
+    /// Snapshots kept, bounding the age of the oldest echo to `stride * slots` frames
+    pub slots: usize,
+    /// Age in frames of each echo drawn, youngest first. Drawn from the snapshot closest in
+    /// age, once there is one within a stride.
+    pub ages: Vec<usize>,
+    /// Brightness of the youngest echo, each older one being dimmer by this factor again
+    pub dimming: f32,
+    /// Draw echoes in this color, rather than that of each particle's type
+    pub monochrome: Option<[f32; 3]>,
+}
+
+/// Ring buffer of past particle positions, drawn as a dimmed point mesh under
+/// [`ECHO_HANDLE`] so that the direction and speed of motion show even in a still frame
+pub struct Echoes {
+    pub config: EchoConfig,
+    /// Positions and types of each snapshot. Slots are reused rather than reallocated.
+    snapshots: Vec<(Vec<Vec3>, Vec<u8>)>,
+    /// Slot of the newest snapshot
+    newest: usize,
+    /// Number of slots holding a snapshot of the current particles
+    filled: usize,
+    /// Frames recorded since the newest snapshot
+    since_newest: usize,
+    mesh: Mesh,
+}
+
+/// What an update did to the mesh
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MeshUpdate {
+    /// Nothing changed; there is no need to upload
+    None,
+    /// Only vertex colors were rewritten
+    Colors,
+    /// Positions and colors were rewritten
+    Full,
+}
+
+impl ParticleMesh {
+    /// Bring the mesh up to date with the simulation. `particles_dirty` should be set when
+    /// particle positions or types changed since the last update, see
+    /// [`SimState::take_particles_dirty`].
+    pub fn update(&mut self, sim: &SimState, particles_dirty: bool) -> MeshUpdate {
+        self.update_visiting(sim, particles_dirty, |_| ())
+    }
+
+    /// [`ParticleMesh::update`], testing each particle against `bound` as its position is
+    /// written. Returns the escapees along with the update whenever every position was
+    /// rewritten, which is whenever any of them could have changed.
+    pub fn update_finding_escapes(
+        &mut self,
+        sim: &SimState,
+        particles_dirty: bool,
+        bound: &EscapeBound,
+    ) -> (MeshUpdate, Option<Vec<usize>>) {
+        let mut escaped = vec![];
+        let particles = sim.particles();
+        let update = self.update_visiting(sim, particles_dirty, |i| {
+            if bound.escaped(particles[i].pos) {
+                escaped.push(i);
+            }
+        });
+        (update, (update == MeshUpdate::Full).then_some(escaped))
+    }
+
+    /// Update, calling `visit` with the index of every particle when the positions are
+    /// rewritten
+    fn update_visiting(
+        &mut self,
+        sim: &SimState,
+        particles_dirty: bool,
+        visit: impl FnMut(usize),
+    ) -> MeshUpdate {
+        let tone = Tone {
+            dither: self.dither,
+            brightness: self.brightness,
+            gamma: self.gamma,
+        };
+        let mut palette_hash = hash_palette(
+            &sim.config().colors,
+            self.color_vision,
+            self.color_mode,
+            tone,
+            [self.tint_pinned, self.tint_blend],
+        );
+        // Position of each particle along the color ramp, in the modes which use one
+        let ramp: Option<Vec<f32>> = match self.color_mode {
+            ColorMode::Type => None,
+            ColorMode::Residence => {
+                palette_hash ^= self.residence_hash;
+                Some(self.residence.iter().map(|level| 1. - level).collect())
+            }
+            // Stress only changes along with the positions
+            ColorMode::Force => Some(self.force_levels(sim)),
+        };
+
+        let (tint_pinned, tint_blend, vision) =
+            (self.tint_pinned, self.tint_blend, self.color_vision);
+        let hidden = std::mem::take(&mut self.hidden);
+        let is_hidden = |i: usize| {
+            let ty = sim.particles()[i].color as usize;
+            hidden.get(ty).copied().unwrap_or(false)
+        };
+        let dim = self.hidden_style == HiddenStyle::Dim;
+        let clip = self.clip;
+        let clip_dim = clip.is_some_and(|clip| clip.style == HiddenStyle::Dim);
+        let is_clipped = |i: usize| clip.is_some_and(|clip| !clip.shows(sim.particles()[i].pos));
+        let color = |i| {
+            let color = color(sim, i, tint_pinned, tint_blend, vision, ramp.as_deref());
+            let color = tone.apply(color, i);
+            match (dim && is_hidden(i)) || (clip_dim && is_clipped(i)) {
+                true => [luminance(color) * HIDDEN_DIM; 3],
+                false => color,
+            }
+        };
+        let drawn = |i| (dim || !is_hidden(i)) && (clip_dim || !is_clipped(i));
+        let dirty = particles_dirty || self.visibility_dirty;
+        let update = self.write(sim, dirty, palette_hash, color, drawn, visit);
+        self.hidden = hidden;
+        self.visibility_dirty = false;
+        update
+    }
+
+    /// Show or hide the particles of type `ty`. The simulation is unaffected.
+    pub fn set_visible(&mut self, ty: usize, visible: bool) {
+        if self.hidden.len() <= ty {
+            self.hidden.resize(ty + 1, false);
+        }
+        self.hidden[ty] = !visible;
+        self.visibility_dirty = true;
+    }
+
+    pub fn is_visible(&self, ty: usize) -> bool {
+        !self.hidden.get(ty).copied().unwrap_or(false)
+    }
+
+    /// Hide every type of `sim` but `ty`
+    pub fn solo(&mut self, ty: usize, sim: &SimState) {
+        let n = sim.config().colors.len().max(ty + 1);
+        self.hidden = (0..n).map(|other| other != ty).collect();
+        self.visibility_dirty = true;
+    }
+
+    pub fn show_all(&mut self) {
+        self.hidden.clear();
+        self.visibility_dirty = true;
+    }
+
+    pub fn hidden_style(&self) -> HiddenStyle {
+        self.hidden_style
+    }
+
+    pub fn set_hidden_style(&mut self, style: HiddenStyle) {
+        self.hidden_style = style;
+        self.visibility_dirty = true;
+    }
+
+    /// Show only a cross-section of the cloud, or all of it with `None`
+    pub fn set_clip(&mut self, clip: Option<ClipPlane>) {
+        self.clip = clip;
+        self.visibility_dirty = true;
+    }
+
+    pub fn clip(&self) -> Option<&ClipPlane> {
+        self.clip.as_ref()
+    }
+
+    /// Log-scaled stress of each particle from 0 to 1, relative to a rolling high quantile so
+    /// that a few outliers do not wash out the ramp
+    fn force_levels(&mut self, sim: &SimState) -> Vec<f32> {
+        let stress = sim.stress();
+        let high = sampled_quantile(stress, FORCE_QUANTILE, FORCE_SAMPLES).ln_1p();
+        let scale = match self.force_scale {
+            Some(scale) => scale + (high - scale) * FORCE_SMOOTHING,
+            None => high,
+        };
+        self.force_scale = Some(scale);
+
+        stress
+            .iter()
+            .map(|s| {
+                if scale > 0. {
+                    (s.ln_1p() / scale).min(1.)
+                } else {
+                    0.
+                }
+            })
+            .collect()
+    }
+
+    fn write(
+        &mut self,
+        sim: &SimState,
+        particles_dirty: bool,
+        palette_hash: u64,
+        color: impl Fn(usize) -> [f32; 3],
+        drawn: impl Fn(usize) -> bool,
+        mut visit: impl FnMut(usize),
+    ) -> MeshUpdate {
+        let n = sim.particles().len();
+        let ranges = chunk_ranges(n, self.chunk_size.max(n.div_ceil(MAX_CHUNKS)));
+
+        if particles_dirty || self.meshed != n || ranges.len() != self.chunks.len() {
+            self.chunks.resize_with(ranges.len(), Mesh::default);
+            for (mesh, range) in self.chunks.iter_mut().zip(ranges) {
+                mesh.vertices.clear();
+                for i in range {
+                    visit(i);
+                    if !drawn(i) {
+                        continue;
+                    }
+                    let vertex = Vertex {
+                        pos: sim.particles()[i].pos.to_array(),
+                        uvw: color(i),
+                    };
+                    mesh.vertices.push(vertex);
+                }
+                // Indices only depend on the number of vertices, which hidden types change
+                if mesh.indices.len() != mesh.vertices.len() {
+                    mesh.indices.clear();
+                    mesh.indices.extend(0..mesh.vertices.len() as u32);
+                }
+            }
+            self.meshed = n;
+            self.palette_hash = Some(palette_hash);
+            return MeshUpdate::Full;
+        }
+
+        if self.palette_hash == Some(palette_hash) {
+            return MeshUpdate::None;
+        }
+
+        for (mesh, range) in self.chunks.iter_mut().zip(ranges) {
+            for (vertex, i) in mesh.vertices.iter_mut().zip(range.filter(|&i| drawn(i))) {
+                vertex.uvw = color(i);
+            }
+        }
+        self.palette_hash = Some(palette_hash);
+        MeshUpdate::Colors
+    }
+
+    /// Show `mode` from the next update. Residence levels and the force scale left from an
+    /// earlier time in that mode are dropped, as they stopped being tracked when it was left.
+    pub fn set_color_mode(&mut self, mode: ColorMode) {
+        if mode == self.color_mode {
+            return;
+        }
+        match mode {
+            ColorMode::Type => (),
+            ColorMode::Residence => self.set_residence(vec![]),
+            ColorMode::Force => self.force_scale = None,
+        }
+        self.color_mode = mode;
+    }
+
+    /// Set the level of each particle in `0.0..1.0` for [`ColorMode::Residence`], e.g. from
+    /// [`crate::diagnostics::Residence::levels`]. Particles beyond the end keep their type
+    /// colors.
+    pub fn set_residence(&mut self, levels: Vec<f32>) {
+        let mut hasher = DefaultHasher::new();
+        for level in &levels {
+            level.to_bits().hash(&mut hasher);
+        }
+        self.residence_hash = hasher.finish();
+        self.residence = levels;
+    }
+
+    /// Set the largest number of particles per chunk; takes effect on the next full update
+    pub fn set_chunk_size(&mut self, chunk_size: usize) {
+        self.chunk_size = chunk_size.max(1);
+        self.chunks.clear();
+    }
+
+    /// Meshes of each chunk, to be uploaded with the handle from [`chunk_handle`]
+    pub fn meshes(&self) -> &[Mesh] {
+        &self.chunks
+    }
+}
+
+impl ClipPlane {
+    /// Plane across the axis `axis` of [`CLIP_NORMALS`], `offset` along it, showing the
+    /// positive side
+    pub fn axis(axis: usize, offset: f32) -> Self {
+        Self {
+            normal: CLIP_NORMALS[axis],
+            offset,
+            slab: None,
+            style: HiddenStyle::Invisible,
+        }
+    }
+
+    /// Signed distance of `pos` from the plane, positive on the side which is shown
+    pub fn distance(&self, pos: Vec3) -> f32 {
+        self.normal.dot(pos) - self.offset
+    }
+
+    /// Whether a particle at `pos` is in the cross-section
+    pub fn shows(&self, pos: Vec3) -> bool {
+        let distance = self.distance(pos);
+        match self.slab {
+            Some(thickness) => distance.abs() <= thickness / 2.,
+            None => distance >= 0.,
+        }
+    }
+}
+
+impl Default for ParticleMesh {
+    fn default() -> Self {
+        Self {
+            chunks: vec![],
+            chunk_size: MAX_CHUNK_PARTICLES,
+            palette_hash: None,
+            tint_pinned: false,
+            tint_blend: false,
+            color_vision: ColorVision::Normal,
+            color_mode: ColorMode::Type,
+            dither: 0.,
+            brightness: 1.,
+            gamma: 1.,
+            residence: vec![],
+            residence_hash: 0,
+            force_scale: None,
+            hidden: vec![],
+            hidden_style: HiddenStyle::Invisible,
+            clip: None,
+            visibility_dirty: false,
+            meshed: 0,
+        }
+    }
+}
+
+impl Default for EchoConfig {
+    fn default() -> Self {
+        Self {
+            stride: 5,
+            slots: 10,
+            ages: vec![10, 20],
+            dimming: 0.4,
+            monochrome: None,
+        }
+    }
+}
+
+impl Echoes {
+    pub fn new(config: EchoConfig) -> Self {
+        Self {
+            config,
+            snapshots: vec![],
+            newest: 0,
+            filled: 0,
+            since_newest: 0,
+            mesh: Mesh::new(),
+        }
+    }
+
+    /// Call once per step. Takes a snapshot every `stride` steps, forgetting the older ones
+    /// when the number of particles changed since.
+    pub fn record(&mut self, sim: &SimState) {
+        let particles = sim.particles();
+        let n_slots = self.config.slots.max(1);
+        if self.snapshots.len() != n_slots {
+            self.snapshots.resize_with(n_slots, Default::default);
+            self.filled = 0;
+        }
+        if self.filled > 0 && self.snapshots[self.newest].0.len() != particles.len() {
+            self.filled = 0;
+        }
+
+        self.since_newest += 1;
+        if self.filled > 0 && self.since_newest < self.config.stride.max(1) {
+            return;
+        }
+        self.newest = (self.newest + 1) % n_slots;
+        let (positions, types) = &mut self.snapshots[self.newest];
+        positions.clear();
+        positions.extend(particles.iter().map(|p| p.pos));
+        types.clear();
+        types.extend(particles.iter().map(|p| p.color));
+        self.filled = (self.filled + 1).min(n_slots);
+        self.since_newest = 0;
+    }
+
+    /// Forget every snapshot, keeping the buffers
+    pub fn clear(&mut self) {
+        self.filled = 0;
+    }
+
+    /// Snapshot closest to `age` frames old, if one is within a stride of it
+    fn snapshot(&self, age: usize) -> Option<&(Vec<Vec3>, Vec<u8>)> {
+        let stride = self.config.stride.max(1);
+        let n_slots = self.snapshots.len();
+        (0..self.filled)
+            .map(|k| (k, self.since_newest + k * stride))
+            .min_by_key(|&(_, snapshot_age)| snapshot_age.abs_diff(age))
+            .filter(|&(_, snapshot_age)| snapshot_age.abs_diff(age) < stride)
+            .map(|(k, _)| &self.snapshots[(self.newest + n_slots - k) % n_slots])
+    }
+
+    /// Point mesh of every echo recorded so far, or `None` when there is none to draw
+    pub fn mesh(&mut self, sim: &SimState) -> Option<&Mesh> {
+        let mut mesh = std::mem::take(&mut self.mesh);
+        mesh.vertices.clear();
+        mesh.indices.clear();
+        let colors = &sim.config().colors;
+        let mut brightness = self.config.dimming;
+        for &age in &self.config.ages {
+            if let Some((positions, types)) = self.snapshot(age) {
+                for (pos, &color) in positions.iter().zip(types) {
+                    let base = match self.config.monochrome {
+                        Some(color) => color,
+                        None => colors.get(color as usize).copied().unwrap_or([1.; 3]),
+                    };
+                    let idx = mesh.push_vertex(Vertex {
+                        pos: pos.to_array(),
+                        uvw: base.map(|c| c * brightness),
+                    });
+                    mesh.push_indices(&[idx]);
+                }
+            }
+            brightness *= self.config.dimming;
+        }
+        self.mesh = mesh;
+        (!self.mesh.vertices.is_empty()).then_some(&self.mesh)
+    }
+}
+
+/// Mesh handle of the given chunk
+pub fn chunk_handle(chunk: usize) -> MeshHandle {
+    MeshHandle::new(CHUNK_HANDLE_BASE.wrapping_add(chunk as u128))
+}
+
+/// Split `n` particles into consecutive ranges of at most `chunk_size`
+pub fn chunk_ranges(n: usize, chunk_size: usize) -> Vec<Range<usize>> {
+    let chunk_size = chunk_size.max(1);
+    (0..n.div_ceil(chunk_size))
+        .map(|i| i * chunk_size..((i + 1) * chunk_size).min(n))
+        .collect()
+}
+
+fn color(
+    sim: &SimState,
+    i: usize,
+    tint_pinned: bool,
+    tint_blend: bool,
+    vision: ColorVision,
+    ramp: Option<&[f32]>,
+) -> [f32; 3] {
+    let mut color = match ramp.and_then(|levels| levels.get(i)) {
+        Some(&level) => viridis(level),
+        None => sim.config().colors[sim.particles()[i].color as usize],
+    };
+    if tint_blend {
+        color = color.map(|c| c * (1. - 0.6 * sim.blend()[i]));
+    }
+    if tint_pinned && sim.pinned()[i] {
+        color = color.map(|c| (c + 1.) / 2.);
+    }
+    vision.simulate(color)
+}
+
+impl Tone {
+    fn apply(&self, color: [f32; 3], i: usize) -> [f32; 3] {
+        let mut scale = self.brightness;
+        if self.dither != 0. {
+            scale *= 1. + self.dither * dither_offset(i);
+        }
+        let color = color.map(|c| (c * scale).clamp(0., 1.));
+        match self.gamma == 1. {
+            true => color,
+            false => color.map(|c| c.powf(self.gamma.recip())),
+        }
+    }
+
+    fn hash(&self, hasher: &mut impl Hasher) {
+        [self.dither, self.brightness, self.gamma]
+            .map(f32::to_bits)
+            .hash(hasher);
+    }
+}
+
+/// Perceived brightness of a linear color, by the Rec. 709 weights
+fn luminance([r, g, b]: [f32; 3]) -> f32 {
+    0.2126 * r + 0.7152 * g + 0.0722 * b
+}
+
+/// Offset in `-1.0..1.0` of the brightness of the particle at index `i` when dithering,
+/// scattered by a hash of the index so that it is the same every frame
+pub fn dither_offset(i: usize) -> f32 {
+    // Finalizer of MurmurHash3
+    let mut h = i as u64;
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xff51afd7ed558ccd);
+    h ^= h >> 33;
+    h = h.wrapping_mul(0xc4ceb9fe1a85ec53);
+    h ^= h >> 33;
+    (h >> 40) as f32 / (1u64 << 23) as f32 - 1.
+}
+
+/// Line segments of the glyph for each type, in units of the glyph's half-width. Types beyond
+/// the end reuse glyphs from the start.
+const GLYPHS: &[&[[[f32; 2]; 2]]] = &[
+    // Cross
+    &[[[-1., -1.], [1., 1.]], [[-1., 1.], [1., -1.]]],
+    // Triangle
+    &[
+        [[-1., -0.8], [1., -0.8]],
+        [[1., -0.8], [0., 1.]],
+        [[0., 1.], [-1., -0.8]],
+    ],
+    // Square
+    &[
+        [[-1., -1.], [1., -1.]],
+        [[1., -1.], [1., 1.]],
+        [[1., 1.], [-1., 1.]],
+        [[-1., 1.], [-1., -1.]],
+    ],
+    // Plus
+    &[[[-1., 0.], [1., 0.]], [[0., -1.], [0., 1.]]],
+    // Diamond
+    &[
+        [[0., -1.], [1., 0.]],
+        [[1., 0.], [0., 1.]],
+        [[0., 1.], [-1., 0.]],
+        [[-1., 0.], [0., -1.]],
+    ],
+];
+
+/// Line mesh of a glyph for every `stride`th particle, in the XY plane around its position
+pub fn marker_mesh(sim: &SimState, config: &MarkerConfig) -> Mesh {
+    let mut mesh = Mesh::new();
+    for particle in sim.particles().iter().step_by(config.stride.max(1)) {
+        let glyph = GLYPHS[particle.color as usize % GLYPHS.len()];
+        if mesh.vertices.len() + glyph.len() * 2 > config.max_vertices {
+            break;
+        }
+
+        let uvw = sim.config().colors[particle.color as usize];
+        for segment in glyph {
+            let [a, b] = segment.map(|[x, y]| {
+                let pos = particle.pos + Vec3::new(x, y, 0.) * config.size;
+                mesh.push_vertex(Vertex {
+                    pos: pos.to_array(),
+                    uvw,
+                })
+            });
+            mesh.push_indices(&[a, b]);
+        }
+    }
+    mesh
+}
+
+/// Line mesh of a tick from every `stride`th particle along its heading, `size` long. Empty
+/// without polar behaviours, whose particles have no headings.
+pub fn heading_mesh(sim: &SimState, config: &MarkerConfig) -> Mesh {
+    let mut mesh = Mesh::new();
+    let Some(orient) = sim.orientations() else {
+        return mesh;
+    };
+    let ticks = sim
+        .particles()
+        .iter()
+        .zip(orient)
+        .step_by(config.stride.max(1));
+    for (particle, &heading) in ticks {
+        if mesh.vertices.len() + 2 > config.max_vertices {
+            break;
+        }
+
+        let uvw = sim.config().colors[particle.color as usize];
+        let [a, b] = [particle.pos, particle.pos + heading * config.size].map(|pos| {
+            mesh.push_vertex(Vertex {
+                pos: pos.to_array(),
+                uvw,
+            })
+        });
+        mesh.push_indices(&[a, b]);
+    }
+    mesh
+}
+
+/// Color of the measurement overlay
+const MEASURE_COLOR: [f32; 3] = [1., 1., 1.];
+
+/// Color of the outline of a time bubble, faint so as not to hide what it holds
+const BUBBLE_COLOR: [f32; 3] = [0.3, 0.4, 0.6];
+
+/// Segments of each circle outlining a sphere
+const SPHERE_SEGMENTS: usize = 48;
+
+/// Line mesh of the measurement tools: the ruler with a tick mark every
+/// [`Ruler::tick_spacing`], a cross at its start while the end is not yet placed, and three
+/// great circles outlining the counting sphere. The length of the ruler and the count in the
+/// sphere are written above them, facing `camera` if known.
+pub fn measure_mesh(ruler: &Ruler, sphere: Option<&CountingSphere>, camera: Option<Vec3>) -> Mesh {
+    let mut mesh = Mesh::new();
+    let line = |mesh: &mut Mesh, a: Vec3, b: Vec3| {
+        let [a, b] = [a, b].map(|pos| {
+            mesh.push_vertex(Vertex {
+                pos: pos.to_array(),
+                uvw: MEASURE_COLOR,
+            })
+        });
+        mesh.push_indices(&[a, b]);
+    };
+
+    if let (Some((start, end)), Some(spacing)) = (ruler.endpoints(), ruler.tick_spacing()) {
+        line(&mut mesh, start, end);
+        let dir = (end - start).normalize();
+        let across = dir.any_orthonormal_vector() * spacing * 0.1;
+        let ticks = (start.distance(end) / spacing).floor() as usize;
+        for k in 0..=ticks {
+            let tick = start + dir * spacing * k as f32;
+            line(&mut mesh, tick - across, tick + across);
+        }
+        let readout = format!("{:.3}", start.distance(end));
+        let anchor = (start + end) / 2. + Vec3::Y * LABEL_SIZE;
+        add_label(
+            &mut mesh,
+            &readout,
+            anchor,
+            LABEL_SIZE,
+            MEASURE_COLOR,
+            camera,
+        );
+    } else if let Some(start) = ruler.start() {
+        for axis in [Vec3::X, Vec3::Y, Vec3::Z] {
+            line(&mut mesh, start - axis * 0.01, start + axis * 0.01);
+        }
+    }
+
+    if let Some(sphere) = sphere {
+        sphere_outline(&mut mesh, sphere.center, sphere.radius, MEASURE_COLOR);
+        let readout = format!("N={}", sphere.total());
+        let anchor = sphere.center + Vec3::Y * (sphere.radius + LABEL_SIZE);
+        add_label(
+            &mut mesh,
+            &readout,
+            anchor,
+            LABEL_SIZE,
+            MEASURE_COLOR,
+            camera,
+        );
+    }
+    mesh
+}
+
+/// Line mesh of a time bubble: great circles at its radius, and fainter ones where its shell
+/// ends
+pub fn bubble_mesh(bubble: &TimeBubble) -> Mesh {
+    let mut mesh = Mesh::new();
+    sphere_outline(&mut mesh, bubble.center, bubble.radius, BUBBLE_COLOR);
+    if bubble.shell > 0. {
+        let faint = BUBBLE_COLOR.map(|c| c * 0.5);
+        sphere_outline(
+            &mut mesh,
+            bubble.center,
+            bubble.radius + bubble.shell,
+            faint,
+        );
+    }
+    mesh
+}
+
+/// Line mesh outlining a clipping plane as a square of half-width `extent` around the point
+/// of the plane closest to `center`, with both faces of a slab
+pub fn clip_mesh(clip: &ClipPlane, center: Vec3, extent: f32) -> Mesh {
+    let mut mesh = Mesh::new();
+    let u = clip.normal.any_orthonormal_vector();
+    let v = clip.normal.cross(u);
+    let on_plane = center - clip.normal * clip.distance(center);
+    let faces = match clip.slab {
+        Some(thickness) => vec![-thickness / 2., thickness / 2.],
+        None => vec![0.],
+    };
+    for shift in faces {
+        let corners = [(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)]
+            .map(|(a, b)| on_plane + clip.normal * shift + (u * a + v * b) * extent);
+        for k in 0..4 {
+            let [a, b] = [corners[k], corners[(k + 1) % 4]].map(|pos| {
+                mesh.push_vertex(Vertex {
+                    pos: pos.to_array(),
+                    uvw: CLIP_COLOR,
+                })
+            });
+            mesh.push_indices(&[a, b]);
+        }
+    }
+    mesh
+}
+
+/// Add three great circles outlining a sphere
+fn sphere_outline(mesh: &mut Mesh, center: Vec3, radius: f32, color: [f32; 3]) {
+    for (u, v) in [(Vec3::X, Vec3::Y), (Vec3::Y, Vec3::Z), (Vec3::Z, Vec3::X)] {
+        let point = |k: usize| {
+            let angle = k as f32 / SPHERE_SEGMENTS as f32 * TAU;
+            center + (u * angle.cos() + v * angle.sin()) * radius
+        };
+        for k in 0..SPHERE_SEGMENTS {
+            let [a, b] = [point(k), point(k + 1)].map(|pos| {
+                mesh.push_vertex(Vertex {
+                    pos: pos.to_array(),
+                    uvw: color,
+                })
+            });
+            mesh.push_indices(&[a, b]);
+        }
+    }
+}
+
+/// Mesh of all tiles of a sweep, each drawn at its offset
+pub fn sweep_mesh(sweep: &Sweep) -> Mesh {
+    let mut mesh = Mesh::new();
+    for tile in sweep.tiles() {
+        let colors = &tile.sim.config().colors;
+        for particle in tile.sim.particles() {
+            let vertex = Vertex {
+                pos: (particle.pos + tile.offset).to_array(),
+                uvw: colors[particle.color as usize],
+            };
+            let idx = mesh.push_vertex(vertex);
+            mesh.push_indices(&[idx]);
+        }
+    }
+    mesh
+}
+
+/// Line mesh of the swept value of each tile of a sweep, written in front of the tile (towards
+/// +Z), facing `camera` if known
+pub fn sweep_label_mesh(sweep: &Sweep, camera: Option<Vec3>) -> Mesh {
+    let mut mesh = Mesh::new();
+    let half_width = sweep.half_width();
+    let size = half_width * 0.2;
+    for tile in sweep.tiles() {
+        let anchor = tile.offset + Vec3::new(0., 0., half_width + size);
+        let label = format!("{:.3}", tile.value);
+        add_label(&mut mesh, &label, anchor, size, MEASURE_COLOR, camera);
+    }
+    mesh
+}
+
+/// Line mesh of a legend of the particle types, one line each going down from `origin`: a
+/// swatch of the type's color, then its number written in it, facing `camera` if known
+pub fn legend_mesh(colors: &[[f32; 3]], origin: Vec3, camera: Option<Vec3>) -> Mesh {
+    let mut mesh = Mesh::new();
+    for (color, &uvw) in colors.iter().enumerate() {
+        let row = origin - Vec3::Y * (LABEL_SIZE * 1.5 * color as f32);
+        let [a, b] = [row, row + Vec3::X * LABEL_SIZE].map(|pos| {
+            mesh.push_vertex(Vertex {
+                pos: (pos + Vec3::Y * LABEL_SIZE / 2.).to_array(),
+                uvw,
+            })
+        });
+        mesh.push_indices(&[a, b]);
+        let name = format!("TYPE {}", color);
+        let start = row + Vec3::X * (LABEL_SIZE * 1.5);
+        match camera {
+            Some(camera) => add_text_facing(&mut mesh, &name, start, LABEL_SIZE, uvw, camera),
+            None => add_text(&mut mesh, &name, start, LABEL_SIZE, uvw),
+        };
+    }
+    mesh
+}
+
+/// Outlines of the query accelerator's cells, and what it took to fit them in the budget
+pub struct CellOutlines {
+    pub mesh: Mesh,
+    /// Number of outlines drawn
+    pub shown: usize,
+    /// Number of occupied blocks left out as the sparsest
+    pub culled: usize,
+    /// Level of detail drawn: each block merges `2^level` cells along each axis
+    pub level: u32,
+}
+
+/// Line mesh outlining the occupied cells of `accel`, colored along a ramp by how many points
+/// each holds, with a readout of what was left out written above them, facing `camera` if
+/// known. Beyond `budget` cells, blocks of 2x2x2 are merged into one, summing their points,
+/// up to a few times; if that is still too many, only the densest `budget` are drawn. The
+/// ramp runs from the sparsest to the densest drawn, logarithmically, so that it does not
+/// saturate at a few points per cell.
+pub fn cells_mesh(accel: &QueryAccelerator, budget: usize, camera: Option<Vec3>) -> CellOutlines {
+    let mut cells = accel.cell_layout();
+    let mut level = 0;
+    while cells.len() > budget && level < MAX_CELL_LOD {
+        // Sorted by coordinates, so the halved ones stay sorted and merge in one pass
+        let mut merged: Vec<([i32; 3], u32)> = vec![];
+        for (key, count) in cells {
+            let key = key.map(|v| v.div_euclid(2));
+            match merged.last_mut() {
+                Some((last, total)) if *last == key => *total += count,
+                _ => merged.push((key, count)),
+            }
+        }
+        merged.sort_unstable();
+        cells = merged;
+        level += 1;
+    }
+
+    let culled = cells.len().saturating_sub(budget);
+    if culled > 0 {
+        cells.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        cells.truncate(budget);
+    }
+
+    let mut mesh = Mesh::new();
+    let size = accel.radius() * (1 << level) as f32;
+    let (min, max) = cells.iter().fold((u32::MAX, 0), |(min, max), &(_, count)| {
+        (min.min(count), max.max(count))
+    });
+    let span = ((max as f32 + 1.) / (min as f32 + 1.)).ln();
+    let mut top = Vec3::splat(f32::NEG_INFINITY);
+    for &(key, count) in &cells {
+        let t = match span > 0. {
+            true => ((count as f32 + 1.) / (min as f32 + 1.)).ln() / span,
+            false => 1.,
+        };
+        let corner = Vec3::from(key.map(|v| v as f32)) * size;
+        cube_outline(&mut mesh, corner, size, viridis(t));
+        top = top.max(corner + size);
+    }
+
+    if culled > 0 || level > 0 {
+        let readout = format!("LOD {} CULLED {}", level, culled);
+        let anchor = Vec3::new(top.x - size * 2., top.y + LABEL_SIZE, top.z - size * 2.);
+        add_label(
+            &mut mesh,
+            &readout,
+            anchor,
+            LABEL_SIZE,
+            MEASURE_COLOR,
+            camera,
+        );
+    }
+
+    CellOutlines {
+        mesh,
+        shown: cells.len(),
+        culled,
+        level,
+    }
+}
+
+/// Add the twelve edges of the cube with its lowest corner at `corner`
+fn cube_outline(mesh: &mut Mesh, corner: Vec3, size: f32, color: [f32; 3]) {
+    let base = mesh.vertices.len() as u32;
+    for k in 0..8 {
+        let offset = Vec3::new((k & 1) as f32, (k >> 1 & 1) as f32, (k >> 2) as f32);
+        mesh.push_vertex(Vertex {
+            pos: (corner + offset * size).to_array(),
+            uvw: color,
+        });
+    }
+    // Corners differing in exactly one bit share an edge
+    for k in 0..8 {
+        for bit in [1, 2, 4] {
+            if k & bit == 0 {
+                mesh.push_indices(&[base + k, base + (k | bit)]);
+            }
+        }
+    }
+}
+
+fn hash_palette(
+    colors: &[[f32; 3]],
+    color_vision: ColorVision,
+    color_mode: ColorMode,
+    tone: Tone,
+    tints: [bool; 2],
+) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    color_vision.hash(&mut hasher);
+    color_mode.hash(&mut hasher);
+    tone.hash(&mut hasher);
+    tints.hash(&mut hasher);
+    for color in colors {
+        color.map(f32::to_bits).hash(&mut hasher);
+    }
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        diagnostics::find_escapes,
+        query_accel::AccelMode,
+        sim::{Behaviour, Field, Particle, SimConfig},
+        sweep::SweepConfig,
+    };
+    use cimvr_engine_interface::pcg::Pcg;
+
+    fn config(colors: Vec<[f32; 3]>) -> SimConfig {
+        SimConfig {
+            behaviours: vec![Behaviour::default(); colors.len() * colors.len()],
+            colors,
+            damping: 150.,
+            ..Default::default()
+        }
+    }
+
+    fn update(mesh: &mut ParticleMesh, sim: &mut SimState) -> MeshUpdate {
+        let dirty = sim.take_particles_dirty();
+        mesh.update(sim, dirty)
+    }
+
+    #[test]
+    fn test_colors_only_update() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(vec![[1., 0., 0.], [0., 1., 0.]]), 100);
+        let mut mesh = ParticleMesh::default();
+        mesh.set_chunk_size(30);
+
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Full);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::None);
+        assert_eq!(mesh.meshes().len(), 4);
+        let before = mesh.meshes().to_vec();
+
+        // Same types, new colors
+        sim.set_config(config(vec![[0., 0., 1.], [1., 1., 1.]]), &mut rng);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+
+        let vertices = |meshes: &[Mesh]| -> Vec<Vertex> {
+            meshes.iter().flat_map(|m| m.vertices.clone()).collect()
+        };
+        for ((old, new), particle) in vertices(&before)
+            .iter()
+            .zip(&vertices(mesh.meshes()))
+            .zip(sim.particles())
+        {
+            assert_eq!(old.pos.map(f32::to_bits), new.pos.map(f32::to_bits));
+            assert_eq!(new.uvw, sim.config().colors[particle.color as usize]);
+        }
+        for (old, new) in before.iter().zip(mesh.meshes()) {
+            assert_eq!(old.indices, new.indices);
+        }
+
+        sim.step(1e-3);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Full);
+    }
+
+    #[test]
+    fn test_index_cache() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(vec![[1., 0., 0.]]), 100);
+        let mut mesh = ParticleMesh::default();
+        mesh.set_chunk_size(40);
+        update(&mut mesh, &mut sim);
+        let indices = |mesh: &ParticleMesh| -> Vec<*const u32> {
+            mesh.meshes().iter().map(|m| m.indices.as_ptr()).collect()
+        };
+        let before = indices(&mesh);
+
+        // Moving keeps the indices as they were
+        sim.step(1e-3);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Full);
+        assert_eq!(indices(&mesh), before);
+
+        // Fewer particles shorten the last chunk's indices to match
+        sim.remove_indices(&[0, 1, 2, 3, 4]);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Full);
+        for m in mesh.meshes() {
+            let expected: Vec<u32> = (0..m.vertices.len() as u32).collect();
+            assert_eq!(m.indices, expected);
+        }
+        assert_eq!(mesh.meshes()[2].indices.len(), 15);
+    }
+
+    #[test]
+    fn test_escapes_found_with_mesh() {
+        let mut rng = Pcg::new();
+        let mut particles: Vec<Particle> = (0..200)
+            .map(|i| Particle {
+                pos: Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()),
+                vel: Vec3::ZERO,
+                color: (i % 2) as u8,
+            })
+            .collect();
+        let far = 41;
+        particles[far].pos = Vec3::X * 1e3;
+        let mut sim = SimState::from_particles(config(vec![[1.; 3], [0.; 3]]), particles);
+        let expected = find_escapes(&sim, 20.);
+        assert_eq!(expected.indices, [far]);
+
+        // Hidden particles are tested too
+        let mut mesh = ParticleMesh::default();
+        mesh.set_visible(1, false);
+        let bound = EscapeBound::estimate(&sim, 20.);
+        let dirty = sim.take_particles_dirty();
+        let (update, escaped) = mesh.update_finding_escapes(&sim, dirty, &bound);
+        assert_eq!(update, MeshUpdate::Full);
+        assert_eq!(escaped, Some(expected.indices));
+
+        // Nothing moved, so there is nothing new to find
+        let dirty = sim.take_particles_dirty();
+        let (update, escaped) = mesh.update_finding_escapes(&sim, dirty, &bound);
+        assert_eq!((update, escaped), (MeshUpdate::None, None));
+    }
+
+    #[test]
+    fn test_hidden_types() {
+        let mut rng = Pcg::new();
+        let colors = vec![[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]];
+        let mut sim = SimState::new(&mut rng, config(colors), 300);
+        let count = |ty: u8| sim.particles().iter().filter(|p| p.color == ty).count();
+        let (red, green) = (count(0), count(1));
+        let mut mesh = ParticleMesh::default();
+        mesh.set_chunk_size(70);
+        update(&mut mesh, &mut sim);
+        let vertices = |mesh: &ParticleMesh| -> Vec<Vertex> {
+            mesh.meshes()
+                .iter()
+                .flat_map(|m| m.vertices.clone())
+                .collect()
+        };
+        let indices_match = |mesh: &ParticleMesh| {
+            (mesh.meshes().iter())
+                .all(|m| m.indices == (0..m.vertices.len() as u32).collect::<Vec<_>>())
+        };
+
+        // Hiding drops the type's vertices, and the indices follow
+        mesh.set_visible(1, false);
+        assert!(!mesh.is_visible(1));
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Full);
+        assert_eq!(vertices(&mesh).len(), 300 - green);
+        assert!(indices_match(&mesh));
+        assert!(vertices(&mesh).iter().all(|v| v.uvw[1] == 0.));
+
+        // Colors alone still line up with the particles left in the mesh
+        mesh.brightness = 0.5;
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+        assert!(vertices(&mesh).iter().all(|v| v.uvw[1] == 0.));
+        assert!(vertices(&mesh).iter().all(|v| v.uvw.contains(&0.5)));
+
+        // Solo keeps one type; the simulation still has every particle
+        mesh.solo(0, &sim);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Full);
+        assert_eq!(vertices(&mesh).len(), red);
+        assert!(indices_match(&mesh));
+        sim.step(1e-3);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Full);
+        assert_eq!(vertices(&mesh).len(), red);
+        assert_eq!(sim.particles().len(), 300);
+
+        // Dimmed rather than left out
+        mesh.set_hidden_style(HiddenStyle::Dim);
+        update(&mut mesh, &mut sim);
+        assert_eq!(vertices(&mesh).len(), 300);
+        let grey = |v: &Vertex| v.uvw[0] == v.uvw[1] && v.uvw[1] == v.uvw[2];
+        assert_eq!(
+            vertices(&mesh).iter().filter(|v| grey(v)).count(),
+            300 - red
+        );
+
+        mesh.show_all();
+        update(&mut mesh, &mut sim);
+        assert!(vertices(&mesh).iter().all(|v| !grey(v)));
+    }
+
+    #[test]
+    fn test_clip_plane() {
+        let mut rng = Pcg::new();
+        let colors = vec![[1., 0., 0.], [0., 1., 0.]];
+        let mut sim = SimState::new(&mut rng, config(colors), 300);
+        let mut mesh = ParticleMesh::default();
+        mesh.set_chunk_size(70);
+        update(&mut mesh, &mut sim);
+        let vertices = |mesh: &ParticleMesh| -> Vec<Vertex> {
+            mesh.meshes()
+                .iter()
+                .flat_map(|m| m.vertices.clone())
+                .collect()
+        };
+        let positions: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+        let count = |f: &dyn Fn(Vec3) -> bool| positions.iter().filter(|&&pos| f(pos)).count();
+
+        // Only the positive side of the plane is left
+        let clip = ClipPlane::axis(0, 0.1);
+        mesh.set_clip(Some(clip));
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Full);
+        let shown = vertices(&mesh);
+        assert_eq!(shown.len(), count(&|pos| pos.x >= 0.1));
+        assert!(!shown.is_empty() && shown.len() < 300);
+        assert!(shown.iter().all(|v| v.pos[0] >= 0.1));
+
+        // A slab keeps a slice of its thickness around the plane
+        let slab = ClipPlane {
+            slab: Some(0.2),
+            ..ClipPlane::axis(2, -0.2)
+        };
+        mesh.set_clip(Some(slab));
+        update(&mut mesh, &mut sim);
+        let shown = vertices(&mesh);
+        assert_eq!(shown.len(), count(&|pos| (pos.z + 0.2).abs() <= 0.1));
+        assert!(shown.iter().all(|v| (v.pos[2] + 0.2).abs() <= 0.1));
+
+        // Dimmed rather than left out, and the simulation is untouched
+        mesh.set_clip(Some(ClipPlane {
+            style: HiddenStyle::Dim,
+            ..slab
+        }));
+        update(&mut mesh, &mut sim);
+        let grey = |v: &Vertex| v.uvw[0] == v.uvw[1] && v.uvw[1] == v.uvw[2];
+        assert_eq!(vertices(&mesh).len(), 300);
+        assert_eq!(
+            vertices(&mesh).iter().filter(|v| grey(v)).count(),
+            count(&|pos| (pos.z + 0.2).abs() > 0.1)
+        );
+
+        mesh.set_clip(None);
+        update(&mut mesh, &mut sim);
+        assert!(vertices(&mesh).iter().all(|v| !grey(v)));
+
+        // The outline lies on both faces of the slab
+        let outline = clip_mesh(&slab, Vec3::new(0.3, 0.2, 0.5), 1.);
+        assert_eq!(outline.vertices.len(), 16);
+        assert!(outline
+            .vertices
+            .iter()
+            .all(|v| ((slab.distance(Vec3::from(v.pos))).abs() - 0.1).abs() < 1e-5));
+    }
+
+    #[test]
+    fn test_dither() {
+        let offsets: Vec<f32> = (0..1_000).map(dither_offset).collect();
+        assert_eq!(offsets, (0..1_000).map(dither_offset).collect::<Vec<_>>());
+        assert!(offsets.iter().all(|o| (-1. ..1.).contains(o)));
+        let mean = offsets.iter().sum::<f32>() / offsets.len() as f32;
+        assert!(mean.abs() < 0.1, "{}", mean);
+        assert!(offsets.windows(2).all(|w| w[0] != w[1]));
+
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(vec![[0.5; 3]]), 50);
+        let mut mesh = ParticleMesh::default();
+        update(&mut mesh, &mut sim);
+        mesh.dither = 0.2;
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+        let colors: Vec<[f32; 3]> = mesh.meshes()[0].vertices.iter().map(|v| v.uvw).collect();
+        for (i, color) in colors.iter().enumerate() {
+            let expected = 0.5 * (1. + 0.2 * dither_offset(i));
+            assert_eq!(*color, [expected; 3]);
+        }
+
+        // Stable as particles move
+        sim.step(1e-3);
+        update(&mut mesh, &mut sim);
+        let moved: Vec<[f32; 3]> = mesh.meshes()[0].vertices.iter().map(|v| v.uvw).collect();
+        assert_eq!(moved, colors);
+
+        // Gamma lifts dark colors, and is applied in the same pass
+        mesh.dither = 0.;
+        mesh.gamma = 2.;
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+        let lifted = mesh.meshes()[0].vertices[0].uvw[0];
+        assert!((lifted - 0.5f32.sqrt()).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_color_vision_preview() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(vec![[1., 0., 0.], [0., 1., 0.]]), 10);
+        let mut mesh = ParticleMesh::default();
+        update(&mut mesh, &mut sim);
+
+        mesh.color_vision = ColorVision::Deutan;
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+        let particle = sim.particles()[0];
+        let expected = ColorVision::Deutan.simulate(sim.config().colors[particle.color as usize]);
+        assert_eq!(mesh.meshes()[0].vertices[0].uvw, expected);
+    }
+
+    #[test]
+    fn test_residence_colors() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(vec![[1., 0., 0.]]), 10);
+        let mut mesh = ParticleMesh::default();
+        update(&mut mesh, &mut sim);
+
+        // Levels are only shown in their mode
+        mesh.set_residence(vec![0., 1.]);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::None);
+        mesh.color_mode = ColorMode::Residence;
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+        let uvw = |mesh: &ParticleMesh, i: usize| mesh.meshes()[0].vertices[i].uvw;
+        assert_eq!(uvw(&mesh, 0), viridis(1.));
+        assert_eq!(uvw(&mesh, 1), viridis(0.));
+        assert_eq!(uvw(&mesh, 2), [1., 0., 0.]);
+
+        // New levels are written, but the same ones again are not
+        mesh.set_residence(vec![0.5; 10]);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+        mesh.set_residence(vec![0.5; 10]);
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::None);
+        assert_eq!(uvw(&mesh, 9), viridis(0.5));
+
+        mesh.color_mode = ColorMode::Type;
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+        assert_eq!(uvw(&mesh, 0), [1., 0., 0.]);
+
+        // Coming back to the mode drops the levels from before
+        mesh.set_color_mode(ColorMode::Residence);
+        update(&mut mesh, &mut sim);
+        assert_eq!(uvw(&mesh, 9), [1., 0., 0.]);
+    }
+
+    #[test]
+    fn test_blend_tint() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(vec![[1., 0., 0.]]), 10);
+        let pt = sim.particles()[0].pos;
+        sim.set_blend_within(pt, 1e-6, 1.);
+        let mut mesh = ParticleMesh::default();
+        update(&mut mesh, &mut sim);
+        let uvw = |mesh: &ParticleMesh, i: usize| mesh.meshes()[0].vertices[i].uvw;
+        assert_eq!(uvw(&mesh, 0), [1., 0., 0.]);
+
+        // Recolored at once, even with nothing moving
+        mesh.tint_blend = true;
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+        assert!((uvw(&mesh, 0)[0] - 0.4).abs() < 1e-6);
+        assert_eq!(uvw(&mesh, 1), [1., 0., 0.]);
+    }
+
+    #[test]
+    fn test_force_colors() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(vec![[1., 0., 0.]; 2]), 500);
+        let mut mesh = ParticleMesh::default();
+        update(&mut mesh, &mut sim);
+
+        // Switching modes recolors; before any step nothing has acted on the particles
+        mesh.color_mode = ColorMode::Force;
+        assert_eq!(update(&mut mesh, &mut sim), MeshUpdate::Colors);
+        let uvw = |mesh: &ParticleMesh, i: usize| mesh.meshes()[0].vertices[i].uvw;
+        assert!((0..500).all(|i| uvw(&mesh, i) == viridis(0.)));
+
+        // The most stressed particles saturate, the least stressed stay near the bottom
+        sim.step(1e-3);
+        update(&mut mesh, &mut sim);
+        let by_stress = |a: &usize, b: &usize| sim.stress()[*a].total_cmp(&sim.stress()[*b]);
+        let calmest = (0..500).min_by(by_stress).unwrap();
+        let busiest = (0..500).max_by(by_stress).unwrap();
+        assert_eq!(uvw(&mesh, busiest), viridis(1.));
+        assert_ne!(uvw(&mesh, calmest), viridis(1.));
+
+        // Coming back starts the rolling scale over from the stress at that time
+        mesh.set_color_mode(ColorMode::Type);
+        sim.step(1e-3);
+        update(&mut mesh, &mut sim);
+        mesh.set_color_mode(ColorMode::Force);
+        assert_eq!(mesh.force_scale, None);
+        update(&mut mesh, &mut sim);
+        let stress = sim.stress();
+        let busiest = (0..500).max_by(|a, b| stress[*a].total_cmp(&stress[*b]));
+        assert_eq!(uvw(&mesh, busiest.unwrap()), viridis(1.));
+    }
+
+    #[test]
+    fn test_measure_mesh() {
+        let mut ruler = Ruler::default();
+        assert!(measure_mesh(&ruler, None, None).vertices.is_empty());
+        let readout = |text: &str| {
+            let mut mesh = Mesh::new();
+            add_text(&mut mesh, text, Vec3::ZERO, LABEL_SIZE, MEASURE_COLOR);
+            mesh.indices.len()
+        };
+
+        // A cross marks the first endpoint
+        ruler.place(Vec3::ZERO);
+        assert_eq!(measure_mesh(&ruler, None, None).indices.len(), 3 * 2);
+
+        // The line and ticks at 0, 0.1, ..., 0.5, and the length
+        ruler.place(Vec3::new(0.3, 0.4, 0.));
+        let mesh = measure_mesh(&ruler, None, None);
+        assert_eq!(mesh.indices.len(), (1 + 6) * 2 + readout("0.500"));
+
+        let sphere = CountingSphere::new(Vec3::ONE, 0.2);
+        let mesh = measure_mesh(&Ruler::default(), Some(&sphere), None);
+        let outline = 3 * SPHERE_SEGMENTS * 2;
+        assert_eq!(mesh.indices.len(), outline + readout("N=0"));
+        for v in &mesh.vertices[..outline] {
+            assert!((Vec3::from(v.pos).distance(Vec3::ONE) - 0.2).abs() < 1e-5);
+        }
+        // Written above the sphere
+        assert!(mesh.vertices[outline..].iter().all(|v| v.pos[1] > 1.2));
+    }
+
+    #[test]
+    fn test_marker_mesh() {
+        let mut rng = Pcg::new();
+        let colors = vec![[1.; 3]; GLYPHS.len() + 1];
+        let sim = SimState::new(&mut rng, config(colors), 1_000);
+
+        let mut markers = MarkerConfig {
+            stride: 10,
+            size: 0.01,
+            max_vertices: usize::MAX,
+        };
+        let mesh = marker_mesh(&sim, &markers);
+        let expected: usize = sim
+            .particles()
+            .iter()
+            .step_by(10)
+            .map(|p| GLYPHS[p.color as usize % GLYPHS.len()].len() * 2)
+            .sum();
+        assert_eq!(mesh.vertices.len(), expected);
+        assert_eq!(mesh.indices.len(), expected);
+
+        markers.stride = 1;
+        markers.max_vertices = 101;
+        let mesh = marker_mesh(&sim, &markers);
+        assert!(mesh.vertices.len() <= 101 && mesh.vertices.len() > 90);
+    }
+
+    #[test]
+    fn test_cells_mesh() {
+        let mut rng = Pcg::new();
+        // A dense clump in a sparse cloud
+        let mut points: Vec<Vec3> = (0..20_000)
+            .map(|_| Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * 2.)
+            .collect();
+        points.extend((0..5_000).map(|_| Vec3::splat(0.5) + Vec3::splat(rng.gen_f32() * 0.01)));
+        let accel = QueryAccelerator::with_mode(&points, 0.05, AccelMode::Grid);
+        let cells = accel.cell_count();
+
+        let all = cells_mesh(&accel, usize::MAX, None);
+        assert_eq!((all.shown, all.culled, all.level), (cells, 0, 0));
+        // Eight corners and twelve edges each, and no readout
+        assert_eq!(all.mesh.vertices.len(), cells * 8);
+        assert_eq!(all.mesh.indices.len(), cells * 24);
+
+        // Sparse cells are not all the same color, unlike the dense one
+        let colors: Vec<[f32; 3]> = (all.mesh.vertices.iter()).map(|v| v.uvw).collect();
+        let densest = viridis(1.);
+        assert_eq!(colors.iter().filter(|&&c| c == densest).count(), 8);
+        assert!(colors.iter().any(|&c| c != colors[0] && c != densest));
+
+        // Coarser blocks first, then the sparsest are culled
+        let coarse = cells_mesh(&accel, 2_000, None);
+        assert!(coarse.level > 0 && coarse.shown <= 2_000);
+        assert!(coarse.mesh.vertices.len() > coarse.shown * 8);
+        let culled = cells_mesh(&accel, 10, None);
+        assert_eq!(culled.level, MAX_CELL_LOD);
+        assert_eq!(culled.shown, 10);
+        assert!(culled.culled > 0);
+        assert!(culled.mesh.vertices.iter().any(|v| v.uvw == densest));
+
+        assert_eq!(cells_mesh(&accel, 0, None).shown, 0);
+    }
+
+    #[test]
+    fn test_labels() {
+        let base = config(vec![[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]]);
+        let sweep_config = SweepConfig {
+            field: Field::Strength,
+            pair: (0, 1),
+            min: -1.,
+            max: 1.,
+            tiles: 2,
+        };
+        let sweep = Sweep::new(&sweep_config, &base, 10, 0.25, &mut Pcg::new());
+        let mesh = sweep_label_mesh(&sweep, Some(Vec3::new(0., 5., 5.)));
+        assert!(!mesh.vertices.is_empty());
+        // Each label is in front of its tile
+        for tile in sweep.tiles() {
+            let near = |v: &Vertex| {
+                let d = Vec3::from(v.pos) - tile.offset;
+                d.x.abs() < 0.25 && d.z > 0.25
+            };
+            assert!(mesh.vertices.iter().any(near));
+        }
+
+        let mesh = legend_mesh(&base.colors, Vec3::ZERO, None);
+        for color in &base.colors {
+            assert!(mesh.vertices.iter().filter(|v| v.uvw == *color).count() > 2);
+        }
+    }
+
+    #[test]
+    fn test_bubble_mesh() {
+        let mut bubble = TimeBubble {
+            center: Vec3::ONE,
+            radius: 0.2,
+            shell: 0.,
+            factor: 0.1,
+        };
+        assert_eq!(bubble_mesh(&bubble).indices.len(), 3 * SPHERE_SEGMENTS * 2);
+        bubble.shell = 0.05;
+        let mesh = bubble_mesh(&bubble);
+        assert_eq!(mesh.indices.len(), 2 * 3 * SPHERE_SEGMENTS * 2);
+        let outer = Vec3::from(mesh.vertices.last().unwrap().pos);
+        assert!((outer.distance(bubble.center) - 0.25).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_heading_mesh() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(vec![[1.; 3]; 2]), 100);
+        let ticks = MarkerConfig {
+            stride: 3,
+            size: 0.02,
+            max_vertices: usize::MAX,
+        };
+        assert!(heading_mesh(&sim, &ticks).vertices.is_empty());
+
+        let mut polar = sim.config().clone();
+        polar.behaviours[0].polarity = 1.;
+        sim.set_config(polar, &mut rng);
+        let mesh = heading_mesh(&sim, &ticks);
+        assert_eq!(mesh.vertices.len(), 34 * 2);
+        for (k, tick) in mesh.vertices.chunks(2).enumerate() {
+            let [a, b] = [tick[0].pos, tick[1].pos].map(Vec3::from);
+            assert_eq!(a, sim.particles()[k * 3].pos);
+            assert!((a.distance(b) - 0.02).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn test_echoes() {
+        // A ring of particles turning counterclockwise about the Y axis, seen from above
+        let n = 16;
+        let ring = |turn: f32| -> Vec<Particle> {
+            (0..n)
+                .map(|i| {
+                    let angle = i as f32 / n as f32 * TAU + turn;
+                    Particle {
+                        pos: Vec3::new(angle.cos(), 0., -angle.sin()),
+                        vel: Vec3::ZERO,
+                        color: (i % 2) as u8,
+                    }
+                })
+                .collect()
+        };
+        let colors = vec![[1., 0., 0.], [0., 0., 1.]];
+        let mut sim = SimState::from_particles(config(colors), ring(0.));
+        let mut echoes = Echoes::new(EchoConfig::default());
+        assert!(echoes.mesh(&sim).is_none());
+
+        let mut frame = 0;
+        let mut spin = |sim: &mut SimState, echoes: &mut Echoes, frames: usize| {
+            for _ in 0..frames {
+                frame += 1;
+                for (i, particle) in ring(frame as f32 * 0.01).into_iter().enumerate() {
+                    sim.move_particle(i, particle.pos);
+                }
+                echoes.record(sim);
+            }
+        };
+
+        // The youngest echo appears first, then both
+        spin(&mut sim, &mut echoes, 12);
+        assert_eq!(echoes.mesh(&sim).unwrap().vertices.len(), n);
+        spin(&mut sim, &mut echoes, 60);
+        let mesh = echoes.mesh(&sim).unwrap();
+        assert_eq!(mesh.vertices.len(), 2 * n);
+
+        // Each echo trails its particle, clockwise of it, and the older one is dimmer
+        for (k, vertex) in mesh.vertices.iter().enumerate() {
+            let now = sim.particles()[k % n].pos;
+            let echo = Vec3::from(vertex.pos);
+            assert!(echo.cross(now).y > 0., "{} {}", echo, now);
+        }
+        let brightness = |k: usize| mesh.vertices[k].uvw.iter().sum::<f32>();
+        assert!(brightness(n) < brightness(0));
+
+        // Slots are reused once the ring buffer wraps around
+        let buffers: Vec<_> = echoes.snapshots.iter().map(|(p, _)| p.as_ptr()).collect();
+        spin(&mut sim, &mut echoes, 100);
+        assert!(echoes.snapshots.iter().map(|(p, _)| p.as_ptr()).eq(buffers));
+
+        // A change in the number of particles drops the stale snapshots
+        sim.push_particle(sim.particles()[0]);
+        echoes.record(&sim);
+        assert_eq!(echoes.filled, 1);
+        assert!(echoes.mesh(&sim).is_none());
+
+        echoes.config.monochrome = Some([0., 1., 0.]);
+        echoes.config.ages = vec![0];
+        let mesh = echoes.mesh(&sim).unwrap();
+        assert!(mesh
+            .vertices
+            .iter()
+            .all(|v| v.uvw[0] == 0. && v.uvw[1] > 0.));
+    }
+
+    #[test]
+    fn test_chunk_ranges() {
+        assert!(chunk_ranges(0, 10).is_empty());
+        assert_eq!(chunk_ranges(10, 10), vec![0..10]);
+        assert_eq!(chunk_ranges(11, 10), vec![0..10, 10..11]);
+        assert_eq!(chunk_ranges(30, 10), vec![0..10, 10..20, 20..30]);
+        assert_eq!(chunk_ranges(3, 0), vec![0..1, 1..2, 2..3]);
+
+        // Chunks grow rather than exceeding the maximum count
+        let n = MAX_CHUNK_PARTICLES * MAX_CHUNKS + 1;
+        let ranges = chunk_ranges(n, MAX_CHUNK_PARTICLES.max(n.div_ceil(MAX_CHUNKS)));
+        assert_eq!(ranges.len(), MAX_CHUNKS);
+        assert_eq!(ranges.last().unwrap().end, n);
+    }
+}
@@ -0,0 +1,672 @@
+//! Recording of everything from outside the simulation that changes it, so that a run can be
+//! regenerated exactly from a log a tiny fraction of the size of its positions.
+//!
+//! The simulation is deterministic given its starting state, its random stream and the
+//! actions applied between steps. [`Pcg`] cannot be seeded, so a recording starts the
+//! simulation over from its settings with a fresh stream, which a replay recreates in turn.
+//! Each [`InputAction`] is logged with the number of steps taken before it, and a replay
+//! applies it at the same point. The hash of the final positions is kept to check the replay.
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mcmc::{Integrator, SwitchPolicy},
+    persist::{PersistError, SimSettings},
+    sim::{Behaviour, RebuildSpec, SimConfig, SimState},
+    sync::{quantize, state_hash},
+};
+
+/// Version of the log blob layout
+const INPUT_LOG_VERSION: u32 = 3;
+
+/// Anyone to client: record the run's inputs, or play a recording back
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub enum RecordCommand {
+    /// Start the simulation over from its settings and log every input from there
+    Record,
+    /// Stop recording and print the log in hexadecimal
+    StopRecording,
+    /// Start over from a printed log and play it back one step per frame
+    Replay { hex: String },
+}
+
+/// Something done to the simulation from outside, between two steps
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum InputAction {
+    /// The time step changed
+    Dt(f32),
+    Config(ConfigChange),
+    /// Switch integrators, see [`Integrator::switch_to`]
+    Integrator {
+        next: Integrator,
+        policy: SwitchPolicy,
+    },
+    /// Push the particles near `pt`, see [`SimState::move_neighbors`]
+    Impulse {
+        pt: Vec3,
+        accel: Vec3,
+    },
+    /// Set the blend value of the particles near `pt`, see [`SimState::set_blend_within`]
+    Paint {
+        pt: Vec3,
+        radius: f32,
+        blend: f32,
+    },
+    /// Reset parts of the simulation, drawing from the logged random stream
+    Rebuild(RebuildSpec),
+}
+
+/// An edit of the configuration, with enough of the configuration before it to notice a replay
+/// which has drifted from the recording
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ConfigChange {
+    /// Index, old and new value of each cell of the behaviour matrix which changed
+    Cells(Vec<(usize, Behaviour, Behaviour)>),
+    /// Any other change, recorded whole
+    Whole {
+        before: Box<SimConfig>,
+        after: Box<SimConfig>,
+    },
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct LoggedAction {
+    /// Steps taken before the action
+    pub frame: u64,
+    pub action: InputAction,
+}
+
+/// A recorded run, see the module documentation
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct InputLog {
+    /// Settings the run started from
+    pub settings: SimSettings,
+    pub integrator: Integrator,
+    pub actions: Vec<LoggedAction>,
+    /// Steps taken during the recording
+    pub frames: u64,
+    /// [`sim_hash`] at the end of the recording
+    pub final_hash: u64,
+}
+
+/// Builds an [`InputLog`] as a run goes on
+pub struct InputRecorder {
+    log: InputLog,
+    /// Time step of the last step recorded
+    last_dt: Option<f32>,
+}
+
+/// Regenerates a run from its [`InputLog`]
+pub struct Replay {
+    log: InputLog,
+    /// Next action to apply
+    next: usize,
+    frame: u64,
+    dt: f32,
+}
+
+/// The client's recording or playback, whichever is going on. Steps go through
+/// [`InputSession::step`] so that they are logged or replayed.
+#[derive(Default)]
+pub struct InputSession {
+    recorder: Option<InputRecorder>,
+    replay: Option<Replay>,
+    /// How the last playback ended, until taken
+    outcome: Option<Result<(), ReplayError>>,
+}
+
+/// Ways a replay can fail to reproduce its recording
+#[derive(Clone, Debug, PartialEq)]
+pub enum ReplayError {
+    /// A configuration edit found a different configuration than when it was recorded
+    Diverged { frame: u64 },
+    /// The log steps before setting a time step
+    MissingDt,
+    /// A step panicked
+    Panicked(String),
+    /// The replay ended on different positions than the recording
+    HashMismatch { expected: u64, found: u64 },
+}
+
+impl ConfigChange {
+    /// The change from `before` to `after`, or `None` if they are the same
+    pub fn between(before: &SimConfig, after: &SimConfig) -> Option<Self> {
+        if before == after {
+            return None;
+        }
+        let only_cells = SimConfig {
+            behaviours: after.behaviours.clone(),
+            ..before.clone()
+        };
+        if only_cells != *after || before.behaviours.len() != after.behaviours.len() {
+            return Some(ConfigChange::Whole {
+                before: Box::new(before.clone()),
+                after: Box::new(after.clone()),
+            });
+        }
+        let cells = (before.behaviours.iter().zip(&after.behaviours))
+            .enumerate()
+            .filter(|(_, (a, b))| a != b)
+            .map(|(idx, (&a, &b))| (idx, a, b))
+            .collect();
+        Some(ConfigChange::Cells(cells))
+    }
+
+    /// `config` with this change made, or `None` if `config` is not what the change was made
+    /// to
+    pub fn apply(&self, config: &SimConfig) -> Option<SimConfig> {
+        match self {
+            ConfigChange::Cells(cells) => {
+                let mut config = config.clone();
+                for &(idx, before, after) in cells {
+                    let cell = config.behaviours.get_mut(idx)?;
+                    if *cell != before {
+                        return None;
+                    }
+                    *cell = after;
+                }
+                Some(config)
+            }
+            ConfigChange::Whole { before, after } => {
+                (**before == *config).then(|| (**after).clone())
+            }
+        }
+    }
+}
+
+impl InputAction {
+    /// Make this change to the simulation, or to the time step. Configuration edits are staged
+    /// for the next step. Returns false if a configuration edit does not fit the current
+    /// configuration, which is then kept.
+    pub fn apply(
+        &self,
+        sim: &mut SimState,
+        integrator: &mut Integrator,
+        dt: &mut f32,
+        rng: &mut Pcg,
+    ) -> bool {
+        match self {
+            InputAction::Dt(new) => *dt = *new,
+            // Staged like the live edits, so that type changes are remapped the same way
+            InputAction::Config(change) => {
+                let live = sim.pending_config().unwrap_or(sim.config());
+                match change.apply(live) {
+                    Some(config) => sim.stage_config(config),
+                    None => return false,
+                }
+            }
+            InputAction::Integrator { next, policy } => {
+                integrator.switch_to(next.clone(), *policy, sim, rng)
+            }
+            InputAction::Impulse { pt, accel } => sim.move_neighbors(*pt, *accel),
+            InputAction::Paint { pt, radius, blend } => sim.set_blend_within(*pt, *radius, *blend),
+            InputAction::Rebuild(spec) => sim.rebuild(*spec, rng),
+        }
+        true
+    }
+}
+
+impl InputRecorder {
+    /// Start recording from `settings`, returning the simulation to run and its random stream.
+    /// Everything random during the run must draw from this stream for the replay to match.
+    pub fn start(settings: SimSettings, integrator: Integrator) -> (Self, SimState, Pcg) {
+        let mut rng = Pcg::new();
+        let sim = settings.build(&mut rng);
+        let recorder = Self {
+            log: InputLog {
+                settings,
+                integrator,
+                actions: vec![],
+                frames: 0,
+                final_hash: 0,
+            },
+            last_dt: None,
+        };
+        (recorder, sim, rng)
+    }
+
+    /// Log an action which was made to the simulation since the last step
+    pub fn record(&mut self, action: InputAction) {
+        self.log.actions.push(LoggedAction {
+            frame: self.log.frames,
+            action,
+        });
+    }
+
+    /// Make an action and log it
+    pub fn apply(
+        &mut self,
+        action: InputAction,
+        sim: &mut SimState,
+        integrator: &mut Integrator,
+        dt: &mut f32,
+        rng: &mut Pcg,
+    ) {
+        if action.apply(sim, integrator, dt, rng) {
+            self.record(action);
+        }
+    }
+
+    /// Log that a step of `dt` was taken
+    pub fn record_step(&mut self, dt: f32) {
+        if self.last_dt != Some(dt) {
+            self.record(InputAction::Dt(dt));
+            self.last_dt = Some(dt);
+        }
+        self.log.frames += 1;
+    }
+
+    /// Steps recorded so far
+    pub fn frames(&self) -> u64 {
+        self.log.frames
+    }
+
+    /// End the recording, noting where the simulation got to
+    pub fn finish(mut self, sim: &SimState) -> InputLog {
+        self.log.final_hash = sim_hash(sim);
+        self.log
+    }
+}
+
+impl Replay {
+    /// Recreate the start of the recorded run, returning the simulation, integrator and random
+    /// stream to step with [`Replay::step`]
+    pub fn start(log: InputLog) -> (Self, SimState, Integrator, Pcg) {
+        let mut rng = Pcg::new();
+        let sim = log.settings.build(&mut rng);
+        let integrator = log.integrator.clone();
+        let replay = Self {
+            log,
+            next: 0,
+            frame: 0,
+            dt: 0.,
+        };
+        (replay, sim, integrator, rng)
+    }
+
+    /// Apply the actions due before the next step, and take it. Returns false, without
+    /// stepping, once every recorded step has been taken.
+    pub fn step(
+        &mut self,
+        sim: &mut SimState,
+        integrator: &mut Integrator,
+        rng: &mut Pcg,
+    ) -> Result<bool, ReplayError> {
+        if self.finished() {
+            return Ok(false);
+        }
+        while let Some(logged) = self.log.actions.get(self.next) {
+            if logged.frame > self.frame {
+                break;
+            }
+            if !logged.action.apply(sim, integrator, &mut self.dt, rng) {
+                return Err(ReplayError::Diverged { frame: self.frame });
+            }
+            self.next += 1;
+        }
+        if self.dt == 0. {
+            return Err(ReplayError::MissingDt);
+        }
+
+        integrator
+            .try_step(sim, self.dt, rng)
+            .map_err(ReplayError::Panicked)?;
+        self.frame += 1;
+        Ok(true)
+    }
+
+    /// Whether every recorded step has been taken
+    pub fn finished(&self) -> bool {
+        self.frame >= self.log.frames
+    }
+
+    /// Steps taken so far
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    /// Check that the replay ended where the recording did
+    pub fn verify(&self, sim: &SimState) -> Result<(), ReplayError> {
+        let found = sim_hash(sim);
+        match found == self.log.final_hash {
+            true => Ok(()),
+            false => Err(ReplayError::HashMismatch {
+                expected: self.log.final_hash,
+                found,
+            }),
+        }
+    }
+}
+
+impl InputSession {
+    /// Start recording, replacing the simulation and random stream with fresh ones built from
+    /// the simulation's settings. Ends any playback.
+    pub fn start_recording(
+        &mut self,
+        settings: SimSettings,
+        sim: &mut SimState,
+        integrator: &Integrator,
+        rng: &mut Pcg,
+    ) {
+        let (recorder, new_sim, new_rng) = InputRecorder::start(settings, integrator.clone());
+        *sim = new_sim;
+        *rng = new_rng;
+        self.recorder = Some(recorder);
+        self.replay = None;
+    }
+
+    /// End the recording, if any
+    pub fn stop_recording(&mut self, sim: &SimState) -> Option<InputLog> {
+        self.recorder.take().map(|recorder| recorder.finish(sim))
+    }
+
+    /// Start playing `log` back, replacing the simulation, integrator and random stream. Ends
+    /// any recording.
+    pub fn start_replay(
+        &mut self,
+        log: InputLog,
+        sim: &mut SimState,
+        integrator: &mut Integrator,
+        rng: &mut Pcg,
+    ) {
+        let (replay, new_sim, new_integrator, new_rng) = Replay::start(log);
+        *sim = new_sim;
+        *integrator = new_integrator;
+        *rng = new_rng;
+        self.replay = Some(replay);
+        self.recorder = None;
+    }
+
+    /// Log an action made to the simulation, while recording
+    pub fn record(&mut self, action: InputAction) {
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(action);
+        }
+    }
+
+    /// Stop recording or playing back, as the simulation was changed outside the log
+    pub fn interrupt(&mut self) {
+        self.recorder = None;
+        self.replay = None;
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// Take the next step of the playback, or else a step of `integrator` by `dt`, logged while
+    /// recording. A playback which has run out is checked and ended, see
+    /// [`InputSession::take_outcome`], before stepping on as usual.
+    pub fn step(
+        &mut self,
+        sim: &mut SimState,
+        integrator: &mut Integrator,
+        dt: f32,
+        rng: &mut Pcg,
+    ) -> Result<(), String> {
+        if let Some(replay) = &mut self.replay {
+            let outcome = match replay.step(sim, integrator, rng) {
+                Ok(true) => return Ok(()),
+                Ok(false) => replay.verify(sim),
+                Err(ReplayError::Panicked(msg)) => return Err(msg),
+                Err(err) => Err(err),
+            };
+            self.outcome = Some(outcome);
+            self.replay = None;
+        }
+
+        integrator.try_step(sim, dt, rng)?;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record_step(dt);
+        }
+        Ok(())
+    }
+
+    /// How the last playback ended, once it has
+    pub fn take_outcome(&mut self) -> Option<Result<(), ReplayError>> {
+        self.outcome.take()
+    }
+}
+
+impl InputLog {
+    /// Replay the whole log, checking it ends where the recording did
+    pub fn replay(&self) -> Result<SimState, ReplayError> {
+        let (mut replay, mut sim, mut integrator, mut rng) = Replay::start(self.clone());
+        while replay.step(&mut sim, &mut integrator, &mut rng)? {}
+        replay.verify(&sim)?;
+        Ok(sim)
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut blob = INPUT_LOG_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut blob, self).expect("Input logs are always serializable");
+        blob
+    }
+
+    pub fn decode(blob: &[u8]) -> Result<Self, PersistError> {
+        let (version, body) = blob
+            .split_first_chunk::<4>()
+            .ok_or(PersistError::Truncated)?;
+        let version = u32::from_le_bytes(*version);
+        if version != INPUT_LOG_VERSION {
+            return Err(PersistError::Version(version));
+        }
+
+        let log: Self =
+            bincode::deserialize(body).map_err(|e| PersistError::Malformed(e.to_string()))?;
+        if log.settings.config.validate().is_err() {
+            return Err(PersistError::Invalid("Invalid starting configuration"));
+        }
+        Ok(log)
+    }
+
+    pub fn to_hex(&self) -> String {
+        self.encode().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, PersistError> {
+        let hex = hex.trim();
+        let blob = (0..hex.len() / 2)
+            .map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| PersistError::Malformed("Not hexadecimal".into()))?;
+        Self::decode(&blob)
+    }
+}
+
+/// Hash of the particle positions, at the resolution of [`crate::sync`]
+pub fn sim_hash(sim: &SimState) -> u64 {
+    let positions: Vec<_> = sim.particles().iter().map(|p| quantize(p.pos)).collect();
+    state_hash(&positions)
+}
+
+impl std::fmt::Display for ReplayError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReplayError::Diverged { frame } => {
+                write!(f, "Replay diverged from the recording at step {}", frame)
+            }
+            ReplayError::MissingDt => write!(f, "Input log steps without a time step"),
+            ReplayError::Panicked(msg) => write!(f, "Replay panicked: {}", msg),
+            ReplayError::HashMismatch { expected, found } => write!(
+                f,
+                "Replay ended with hash {:016x}, recording with {:016x}",
+                found, expected
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mcmc::{MetropolisConfig, NewtonConfig},
+        sim::{Field, TypeReset, VelocityReset},
+    };
+
+    fn settings() -> SimSettings {
+        let n = 3;
+        let mut config = SimConfig {
+            colors: vec![[1.; 3]; n],
+            behaviours: vec![Behaviour::default(); n * n],
+            damping: 10.,
+            ..Default::default()
+        };
+        let mut rng = Pcg::new();
+        config.randomize_field(Field::Strength, false, &mut rng);
+        SimSettings {
+            n_particles: 300,
+            constrain_2d: false,
+            max_neighbors: None,
+            ghost_walls: None,
+            tether_stiffness: 0.,
+            turn_rate: 0.,
+            config,
+            placement: Default::default(),
+        }
+    }
+
+    /// A short session of edits, pushes and integrator switches, as the client would make
+    fn record_session() -> (InputLog, SimState) {
+        let (mut recorder, mut sim, mut rng) =
+            InputRecorder::start(settings(), Integrator::default());
+        let mut integrator = Integrator::default();
+        let mut dt = 1e-3;
+        for frame in 0..300 {
+            let mut actions = vec![];
+            match frame {
+                20 => actions.push(InputAction::Impulse {
+                    pt: sim.particles()[0].pos,
+                    accel: Vec3::X * 5.,
+                }),
+                50 => {
+                    let mut config = sim.config().clone();
+                    config.behaviours[1].inter_strength = -8.;
+                    let change = ConfigChange::between(sim.config(), &config).unwrap();
+                    assert!(matches!(&change, ConfigChange::Cells(c) if c.len() == 1));
+                    actions.push(InputAction::Config(change));
+                }
+                100 => {
+                    actions.push(InputAction::Integrator {
+                        next: Integrator::Metropolis(MetropolisConfig {
+                            temperature: 1e-2,
+                            walk_sigma: 5e-3,
+                        }),
+                        policy: SwitchPolicy::Reset,
+                    });
+                    actions.push(InputAction::Paint {
+                        pt: Vec3::ZERO,
+                        radius: 0.5,
+                        blend: 1.,
+                    });
+                }
+                150 => actions.push(InputAction::Integrator {
+                    next: Integrator::Newton(NewtonConfig::default()),
+                    policy: SwitchPolicy::Thermalize,
+                }),
+                200 => actions.push(InputAction::Rebuild(RebuildSpec {
+                    velocities: VelocityReset::Thermalize(0.5),
+                    types: TypeReset::Reshuffle,
+                    ..Default::default()
+                })),
+                _ => (),
+            }
+            for action in actions {
+                recorder.apply(action, &mut sim, &mut integrator, &mut dt, &mut rng);
+            }
+            if frame >= 250 {
+                dt = 2e-3;
+            }
+
+            integrator.try_step(&mut sim, dt, &mut rng).unwrap();
+            recorder.record_step(dt);
+        }
+        (recorder.finish(&sim), sim)
+    }
+
+    #[test]
+    fn test_replay_matches_recording() {
+        let (log, recorded) = record_session();
+        assert_eq!(log.frames, 300);
+        assert_eq!(log.final_hash, sim_hash(&recorded));
+
+        // Far smaller than the positions of a single frame
+        let blob = log.encode();
+        assert!(blob.len() < 2_000, "{}", blob.len());
+        let decoded = InputLog::decode(&blob).unwrap();
+        assert_eq!(decoded, log);
+
+        let replayed = decoded.replay().unwrap();
+        assert_eq!(replayed.particles(), recorded.particles());
+    }
+
+    #[test]
+    fn test_replay_detects_divergence() {
+        let (mut log, _) = record_session();
+        let InputAction::Impulse { accel, .. } = &mut log.actions[1].action else {
+            panic!("Expected the impulse second, after the time step");
+        };
+        *accel *= 2.;
+        assert!(matches!(
+            log.replay(),
+            Err(ReplayError::HashMismatch { .. })
+        ));
+
+        // An edit made to a configuration which differs from the recorded one
+        let (mut log, _) = record_session();
+        log.settings.config.behaviours[1].inter_strength += 1.;
+        assert_eq!(
+            log.replay().err(),
+            Some(ReplayError::Diverged { frame: 50 })
+        );
+    }
+
+    /// Record and play back the way the client does on [`RecordCommand`]s
+    #[test]
+    fn test_session_records_and_replays() {
+        let mut rng = Pcg::new();
+        let mut sim = settings().build(&mut rng);
+        let mut integrator = Integrator::default();
+        let mut session = InputSession::default();
+        // Stepping before recording is not logged
+        for _ in 0..10 {
+            (session.step(&mut sim, &mut integrator, 1e-3, &mut rng)).unwrap();
+        }
+
+        session.start_recording(SimSettings::from_sim(&sim), &mut sim, &integrator, &mut rng);
+        assert!(session.is_recording());
+        for frame in 0..100 {
+            if frame == 30 {
+                let (pt, accel) = (sim.particles()[0].pos, Vec3::X * 5.);
+                sim.move_neighbors(pt, accel);
+                session.record(InputAction::Impulse { pt, accel });
+            }
+            let dt = if frame < 60 { 1e-3 } else { 2e-3 };
+            (session.step(&mut sim, &mut integrator, dt, &mut rng)).unwrap();
+        }
+        let log = session.stop_recording(&sim).unwrap();
+        assert_eq!(log.frames, 100);
+        let recorded = sim.particles().to_vec();
+
+        let decoded = InputLog::from_hex(&log.to_hex()).unwrap();
+        session.start_replay(decoded, &mut sim, &mut integrator, &mut rng);
+        assert!(session.is_replaying());
+        for _ in 0..100 {
+            (session.step(&mut sim, &mut integrator, 1., &mut rng)).unwrap();
+        }
+        assert_eq!(sim.particles(), &recorded[..]);
+        assert_eq!(session.take_outcome(), None);
+
+        // The step after the last one checks the playback and steps on as usual
+        (session.step(&mut sim, &mut integrator, 1e-3, &mut rng)).unwrap();
+        assert_eq!(session.take_outcome(), Some(Ok(())));
+        assert!(!session.is_replaying());
+    }
+}
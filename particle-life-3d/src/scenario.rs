@@ -0,0 +1,649 @@
+//! Complete scenes in one small file: the configuration, how the particles are spawned, the
+//! boundaries they are kept within, the integrator and the seed. Sharing a scenario
+//! reproduces the whole setup rather than just the behaviour matrix.
+//!
+//! Scenarios are JSON, like the preset format, and carry a [`SCENARIO_VERSION`]; files of
+//! another version are refused rather than misread. Fields this version does not know are
+//! ignored, and most fields may be left out for their defaults, so files stay short.
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mcmc::Integrator,
+    metric::DistanceMetric,
+    persist::SimSettings,
+    placement::SimPlacement,
+    sim::{
+        Behaviour, ConfigError, Gravity, OrbitalPreset, SimConfig, SimState, Wall,
+        DEFAULT_TURN_RATE,
+    },
+};
+
+/// Version of the scenario format; bump when a change would make older files misread
+pub const SCENARIO_VERSION: u32 = 1;
+
+/// Largest particle count a scenario may spawn
+const MAX_PARTICLES: usize = 1_000_000;
+
+/// Largest seed, as seeding skips that many draws
+const MAX_SEED: u32 = 1 << 20;
+
+/// Most inner steps per frame a type may take
+const MAX_SUBSTEPS: u32 = 1000;
+
+/// Most candidate moves per particle of the kinetic integrator
+const MAX_CANDIDATES: usize = 1000;
+
+/// Anyone to client: replace the whole setup with a scenario
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub struct LoadScenario {
+    pub source: ScenarioSource,
+}
+
+/// Anyone to client: log the current setup as a scenario, see [`Scenario::capture`]
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub struct PrintScenario;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ScenarioSource {
+    /// One of the [`built_in`] scenarios, by name
+    BuiltIn(String),
+    /// A scenario file's text
+    Text(String),
+}
+
+/// Everything needed to set up a scene from scratch
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Scenario {
+    pub version: u32,
+    pub name: String,
+    #[serde(default)]
+    pub description: String,
+    pub config: ConfigSource,
+    pub spawn: Spawn,
+    #[serde(default)]
+    pub boundary: Boundary,
+    #[serde(default)]
+    pub integrator: Integrator,
+    #[serde(default = "default_dt")]
+    pub dt: f32,
+    #[serde(default)]
+    pub max_neighbors: Option<usize>,
+    #[serde(default)]
+    pub tether_stiffness: f32,
+    #[serde(default = "default_turn_rate")]
+    pub turn_rate: f32,
+    /// Random stream to start from. [`Pcg`] cannot be seeded, so this is the number of draws
+    /// skipped from a fresh stream.
+    #[serde(default)]
+    pub seed: u32,
+}
+
+/// Where a scenario's configuration comes from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum ConfigSource {
+    Inline(Box<SimConfig>),
+    /// The configuration of one of the [`built_in`] scenarios, by name
+    Preset(String),
+}
+
+/// How the particles are laid out to begin with
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+pub enum Spawn {
+    /// Scattered through the cube from -1 to 1, with random types, as by [`SimState::new`]
+    Random { count: usize },
+    /// A pinned core with a ring orbiting it, see [`SimState::spawn_orbital_preset`]
+    Orbital(OrbitalPreset),
+}
+
+/// What keeps the particles in
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct Boundary {
+    /// Confine particles to the XZ plane
+    #[serde(default)]
+    pub constrain_2d: bool,
+    /// See [`SimState::set_ghost_walls`]
+    #[serde(default)]
+    pub ghost_walls: Option<f32>,
+    #[serde(default)]
+    pub walls: Vec<Wall>,
+    #[serde(default)]
+    pub metric: DistanceMetric,
+}
+
+/// The simulation and the client state around it, as set up by a scenario
+pub struct ScenarioSetup {
+    pub sim: SimState,
+    pub integrator: Integrator,
+    pub dt: f32,
+    /// Random stream, advanced past the seed and the spawn
+    pub rng: Pcg,
+}
+
+/// Errors arising from reading or applying a scenario
+#[derive(Clone, Debug, PartialEq)]
+pub enum ScenarioError {
+    /// The text is not a scenario
+    Syntax(String),
+    /// The scenario was written for a different version of the format
+    Version(u32),
+    /// No built-in scenario has this name
+    UnknownName(String),
+    Config(ConfigError),
+    /// The scenario parsed, but describes an unusable setup
+    Invalid(&'static str),
+}
+
+fn default_dt() -> f32 {
+    1e-3
+}
+
+fn default_turn_rate() -> f32 {
+    DEFAULT_TURN_RATE
+}
+
+impl Scenario {
+    /// Parse and validate a scenario file
+    pub fn from_text(text: &str) -> Result<Self, ScenarioError> {
+        // Check the version alone first, as the rest may not parse under another version
+        #[derive(Deserialize)]
+        struct Header {
+            version: u32,
+        }
+        let header: Header =
+            serde_json::from_str(text).map_err(|e| ScenarioError::Syntax(e.to_string()))?;
+        if header.version != SCENARIO_VERSION {
+            return Err(ScenarioError::Version(header.version));
+        }
+
+        let scenario: Self =
+            serde_json::from_str(text).map_err(|e| ScenarioError::Syntax(e.to_string()))?;
+        scenario.validate()?;
+        Ok(scenario)
+    }
+
+    /// Write a scenario file, laid out for hand editing
+    pub fn to_text(&self) -> String {
+        serde_json::to_string_pretty(self).expect("Scenarios are always serializable")
+    }
+
+    /// The configuration, looking presets up among the built-in scenarios
+    pub fn resolve_config(&self) -> Result<SimConfig, ScenarioError> {
+        match &self.config {
+            ConfigSource::Inline(config) => Ok(config.as_ref().clone()),
+            ConfigSource::Preset(name) => match named(name).map(|preset| preset.config) {
+                Some(ConfigSource::Inline(config)) => Ok(*config),
+                _ => Err(ScenarioError::UnknownName(name.clone())),
+            },
+        }
+    }
+
+    /// Check that the scenario can be applied, without building anything
+    pub fn validate(&self) -> Result<(), ScenarioError> {
+        let config = self.resolve_config()?;
+        config.validate().map_err(ScenarioError::Config)?;
+
+        let n = config.colors.len();
+        match self.spawn {
+            Spawn::Random { count } if count > MAX_PARTICLES => {
+                return Err(ScenarioError::Invalid("Too many particles"));
+            }
+            Spawn::Orbital(preset) => {
+                if preset.core as usize >= n || preset.ring as usize >= n {
+                    return Err(ScenarioError::Invalid("Spawned type out of range"));
+                }
+                if preset.core_count.saturating_add(preset.ring_count) > MAX_PARTICLES {
+                    return Err(ScenarioError::Invalid("Too many particles"));
+                }
+                let radii = [preset.core_radius, preset.ring_radius];
+                if !radii.iter().all(|r| r.is_finite() && *r >= 0.) {
+                    return Err(ScenarioError::Invalid("Radii must be finite and positive"));
+                }
+            }
+            Spawn::Random { .. } => {}
+        }
+
+        for wall in &self.boundary.walls {
+            let finite = wall.point.is_finite()
+                && [wall.threshold, wall.repulse, wall.range]
+                    .iter()
+                    .all(|v| v.is_finite())
+                && wall.affinity.iter().all(|v| v.is_finite());
+            if !finite || !wall.normal.is_finite() || wall.normal.length_squared() == 0. {
+                return Err(ScenarioError::Invalid(
+                    "Walls must be finite, with a normal",
+                ));
+            }
+        }
+        if let Some(period) = self.boundary.metric.period() {
+            if !(period.is_finite() && period.min_element() >= 2. * config.max_interaction_radius())
+            {
+                return Err(ScenarioError::Invalid(
+                    "Period must be at least twice the interaction radius",
+                ));
+            }
+        }
+        if self
+            .boundary
+            .ghost_walls
+            .is_some_and(|size| size.is_nan() || size <= 0.)
+        {
+            return Err(ScenarioError::Invalid("Ghost walls must be positive"));
+        }
+
+        if !(self.dt.is_finite() && self.dt > 0.) {
+            return Err(ScenarioError::Invalid("Time step must be positive"));
+        }
+        if !(self.tether_stiffness.is_finite() && self.turn_rate.is_finite()) {
+            return Err(ScenarioError::Invalid("Options must be finite"));
+        }
+        if self.seed > MAX_SEED {
+            return Err(ScenarioError::Invalid("Seed out of range"));
+        }
+        self.validate_integrator()
+    }
+
+    fn validate_integrator(&self) -> Result<(), ScenarioError> {
+        let non_negative = |values: &[f32]| values.iter().all(|v| v.is_finite() && *v >= 0.);
+        match &self.integrator {
+            Integrator::Newton(newton) => {
+                if newton.substeps_per_type.iter().any(|&k| k > MAX_SUBSTEPS) {
+                    return Err(ScenarioError::Invalid("Too many substeps"));
+                }
+                if let Some(far_field) = newton.far_field {
+                    if far_field.k > MAX_PARTICLES {
+                        return Err(ScenarioError::Invalid("Too many far field samples"));
+                    }
+                    if !non_negative(&[far_field.near_radius]) {
+                        return Err(ScenarioError::Invalid(
+                            "Near radius must be finite and positive",
+                        ));
+                    }
+                }
+            }
+            Integrator::Metropolis(metropolis) => {
+                if !non_negative(&[metropolis.temperature, metropolis.walk_sigma]) {
+                    return Err(ScenarioError::Invalid(
+                        "Temperature and moves must be finite and positive",
+                    ));
+                }
+            }
+            Integrator::Kinetic(kinetic) => {
+                if !non_negative(&[kinetic.temperature, kinetic.move_length]) {
+                    return Err(ScenarioError::Invalid(
+                        "Temperature and moves must be finite and positive",
+                    ));
+                }
+                if kinetic.candidates > MAX_CANDIDATES || kinetic.samples > MAX_PARTICLES {
+                    return Err(ScenarioError::Invalid("Too many candidates or samples"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Set up the scene from scratch, through the same pathways as stored settings and the
+    /// presets. Nothing is built unless the scenario is valid.
+    pub fn apply(&self) -> Result<ScenarioSetup, ScenarioError> {
+        self.validate()?;
+        let config = self.resolve_config()?;
+
+        let mut rng = Pcg::new();
+        for _ in 0..self.seed {
+            rng.gen_u32();
+        }
+        let settings = SimSettings {
+            n_particles: match self.spawn {
+                Spawn::Random { count } => count,
+                Spawn::Orbital(_) => 0,
+            },
+            constrain_2d: self.boundary.constrain_2d,
+            max_neighbors: self.max_neighbors,
+            ghost_walls: self.boundary.ghost_walls,
+            tether_stiffness: self.tether_stiffness,
+            turn_rate: self.turn_rate,
+            config,
+            placement: SimPlacement::default(),
+        };
+        let mut sim = settings.build(&mut rng);
+        if let Spawn::Orbital(preset) = &self.spawn {
+            sim.spawn_orbital_preset(preset, &mut rng);
+        }
+        sim.set_walls(self.boundary.walls.clone());
+        sim.set_metric(self.boundary.metric);
+
+        Ok(ScenarioSetup {
+            sim,
+            integrator: self.integrator.clone(),
+            dt: self.dt,
+            rng,
+        })
+    }
+
+    /// The current setup as a scenario. Positions are not kept, so the particles respawn at
+    /// random, as many as there are now; see [`SimSnapshot`] for the exact state.
+    ///
+    /// [`SimSnapshot`]: crate::persist::SimSnapshot
+    pub fn capture(name: &str, sim: &SimState, integrator: &Integrator, dt: f32) -> Self {
+        Self {
+            version: SCENARIO_VERSION,
+            name: name.into(),
+            description: String::new(),
+            config: ConfigSource::Inline(Box::new(sim.config().clone())),
+            spawn: Spawn::Random {
+                count: sim.particles().len(),
+            },
+            boundary: Boundary {
+                constrain_2d: sim.constrain_2d(),
+                ghost_walls: sim.ghost_walls(),
+                walls: sim.walls().to_vec(),
+                metric: sim.metric(),
+            },
+            integrator: integrator.clone(),
+            dt,
+            max_neighbors: sim.max_neighbors(),
+            tether_stiffness: sim.tether_stiffness(),
+            turn_rate: sim.turn_rate(),
+            seed: 0,
+        }
+    }
+}
+
+/// The scenarios which come with the plugin
+pub fn built_in() -> Vec<Scenario> {
+    vec![lava_lamp(), orbit()]
+}
+
+/// The built-in scenario called `name`, if any
+pub fn named(name: &str) -> Option<Scenario> {
+    built_in()
+        .into_iter()
+        .find(|scenario| scenario.name == name)
+}
+
+/// Blobs of a heavy and a light type, which shun each other, sinking and rising between a
+/// floor and a ceiling
+fn lava_lamp() -> Scenario {
+    let behav = |inter_strength| Behaviour {
+        inter_threshold: 0.05,
+        inter_strength,
+        ..Default::default()
+    };
+    let (like, unlike) = (behav(8.), behav(-4.));
+    let wall = |y: f32| Wall {
+        point: Vec3::new(0., y, 0.),
+        normal: Vec3::new(0., -y.signum(), 0.),
+        affinity: vec![],
+        threshold: 0.02,
+        repulse: 10.,
+        range: 0.1,
+    };
+    Scenario {
+        version: SCENARIO_VERSION,
+        name: "Lava lamp".into(),
+        description: "Heavy and light blobs sinking and rising between a floor and a ceiling"
+            .into(),
+        config: ConfigSource::Inline(Box::new(SimConfig {
+            colors: vec![[1., 0.3, 0.05], [1., 0.85, 0.2]],
+            behaviours: vec![like, unlike, unlike, like],
+            interaction_scale: 1.,
+            damping: 150.,
+            gravity: Some(Gravity {
+                down: Vec3::new(0., -1., 0.),
+                weights: vec![5., -5.],
+            }),
+            density_rules: vec![],
+            mobility: None,
+            activity: None,
+        })),
+        spawn: Spawn::Random { count: 3000 },
+        boundary: Boundary {
+            walls: vec![wall(-1.), wall(1.)],
+            ..Default::default()
+        },
+        integrator: Integrator::default(),
+        dt: default_dt(),
+        max_neighbors: None,
+        tether_stiffness: 0.,
+        turn_rate: DEFAULT_TURN_RATE,
+        seed: 0,
+    }
+}
+
+/// A ring of light particles circling a pinned core, in the plane
+fn orbit() -> Scenario {
+    let behav = |inter_strength| Behaviour {
+        inter_strength,
+        ..Default::default()
+    };
+    Scenario {
+        version: SCENARIO_VERSION,
+        name: "Orbit".into(),
+        description: "A ring set circling a pinned core".into(),
+        config: ConfigSource::Inline(Box::new(SimConfig {
+            colors: vec![[1., 0.9, 0.4], [0.3, 0.6, 1.]],
+            // The ring is drawn to the core, and keeps its spacing
+            behaviours: vec![behav(0.), behav(0.), behav(5.), behav(-1.)],
+            interaction_scale: 1.,
+            damping: 0.5,
+            gravity: None,
+            density_rules: vec![],
+            mobility: None,
+            activity: None,
+        })),
+        spawn: Spawn::Orbital(OrbitalPreset::default()),
+        boundary: Boundary {
+            constrain_2d: true,
+            ..Default::default()
+        },
+        integrator: Integrator::default(),
+        dt: default_dt(),
+        max_neighbors: None,
+        tether_stiffness: 0.,
+        turn_rate: DEFAULT_TURN_RATE,
+        seed: 0,
+    }
+}
+
+impl std::fmt::Display for ScenarioError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ScenarioError::Syntax(msg) => write!(f, "Malformed scenario: {}", msg),
+            ScenarioError::Version(v) => write!(
+                f,
+                "Scenario has version {}, expected {}",
+                v, SCENARIO_VERSION
+            ),
+            ScenarioError::UnknownName(name) => write!(f, "No built-in scenario {:?}", name),
+            ScenarioError::Config(err) => write!(f, "Invalid configuration: {}", err),
+            ScenarioError::Invalid(msg) => write!(f, "Invalid scenario: {}", msg),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mcmc::{KineticConfig, MetropolisConfig, NewtonConfig},
+        sim::FarFieldSampling,
+    };
+
+    #[test]
+    fn test_built_in_scenarios() {
+        for scenario in built_in() {
+            let text = scenario.to_text();
+            assert_eq!(Scenario::from_text(&text).unwrap(), scenario);
+
+            let mut setup = scenario.apply().unwrap();
+            let count = match scenario.spawn {
+                Spawn::Random { count } => count,
+                Spawn::Orbital(preset) => preset.core_count + preset.ring_count,
+            };
+            assert_eq!(setup.sim.particles().len(), count);
+            for _ in 0..10 {
+                setup.sim.step(setup.dt);
+            }
+            assert!(setup.sim.particles().iter().all(|p| p.pos.is_finite()));
+
+            // Capturing the setup gives it back, short of the spawn
+            let captured =
+                Scenario::capture(&scenario.name, &setup.sim, &setup.integrator, setup.dt);
+            assert_eq!(captured.boundary, scenario.boundary);
+            assert_eq!(captured.resolve_config(), scenario.resolve_config());
+        }
+        let lamp = named("Lava lamp").unwrap();
+        assert_eq!(lamp.boundary.walls.len(), 2);
+        assert!(named("Lava").is_none());
+
+        // The same seed spawns the same scene, and another seed another
+        let positions = |seed| {
+            let scenario = Scenario {
+                seed,
+                ..lamp.clone()
+            };
+            let setup = scenario.apply().unwrap();
+            setup
+                .sim
+                .particles()
+                .iter()
+                .map(|p| p.pos)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(positions(3), positions(3));
+        assert_ne!(positions(3), positions(4));
+    }
+
+    #[test]
+    fn test_scenario_files() {
+        // Unknown fields are ignored, and missing ones defaulted
+        let text = r#"{
+            "version": 1,
+            "name": "Shared",
+            "author": "somebody",
+            "config": { "Preset": "Lava lamp" },
+            "spawn": { "Random": { "count": 500 } },
+            "boundary": { "constrain_2d": true, "shape": "round" }
+        }"#;
+        let scenario = Scenario::from_text(text).unwrap();
+        assert_eq!(scenario.dt, default_dt());
+        assert_eq!(scenario.turn_rate, DEFAULT_TURN_RATE);
+        assert!(scenario.boundary.constrain_2d);
+        assert_eq!(
+            scenario.resolve_config().unwrap(),
+            lava_lamp().resolve_config().unwrap()
+        );
+        assert_eq!(scenario.apply().unwrap().sim.particles().len(), 500);
+
+        // Other versions are refused before anything else is read
+        let future = text.replace("\"version\": 1", "\"version\": 2");
+        assert_eq!(Scenario::from_text(&future), Err(ScenarioError::Version(2)));
+        let unversioned = text.replace("\"version\": 1,", "");
+        assert!(matches!(
+            Scenario::from_text(&unversioned),
+            Err(ScenarioError::Syntax(_))
+        ));
+
+        let unknown = text.replace("Lava lamp", "Lava");
+        assert_eq!(
+            Scenario::from_text(&unknown),
+            Err(ScenarioError::UnknownName("Lava".into()))
+        );
+        let mut orbit = orbit();
+        if let Spawn::Orbital(preset) = &mut orbit.spawn {
+            preset.ring = 7;
+        }
+        assert!(matches!(orbit.apply(), Err(ScenarioError::Invalid(_))));
+        let mut lamp = lava_lamp();
+        lamp.dt = 0.;
+        assert!(Scenario::from_text(&lamp.to_text()).is_err());
+    }
+
+    #[test]
+    fn test_integrator_bounds() {
+        let metropolis = MetropolisConfig {
+            temperature: 1.,
+            walk_sigma: 1e-2,
+        };
+        let kinetic = KineticConfig {
+            temperature: 1.,
+            move_length: 1e-2,
+            candidates: 8,
+            samples: 100,
+        };
+        let newton = NewtonConfig {
+            substeps_per_type: vec![1, 4],
+            far_field: Some(FarFieldSampling::default()),
+        };
+        let good = [
+            Integrator::Newton(newton.clone()),
+            Integrator::Metropolis(metropolis),
+            Integrator::Kinetic(kinetic),
+        ];
+        let bad = [
+            Integrator::Newton(NewtonConfig {
+                substeps_per_type: vec![1, u32::MAX],
+                ..newton.clone()
+            }),
+            Integrator::Newton(NewtonConfig {
+                far_field: Some(FarFieldSampling {
+                    k: usize::MAX,
+                    near_radius: 0.2,
+                }),
+                ..newton.clone()
+            }),
+            Integrator::Newton(NewtonConfig {
+                far_field: Some(FarFieldSampling {
+                    k: 4,
+                    near_radius: f32::NAN,
+                }),
+                ..newton
+            }),
+            Integrator::Metropolis(MetropolisConfig {
+                temperature: f32::NAN,
+                ..metropolis
+            }),
+            Integrator::Metropolis(MetropolisConfig {
+                walk_sigma: -1.,
+                ..metropolis
+            }),
+            Integrator::Kinetic(KineticConfig {
+                move_length: f32::INFINITY,
+                ..kinetic
+            }),
+            Integrator::Kinetic(KineticConfig {
+                temperature: -1.,
+                ..kinetic
+            }),
+            Integrator::Kinetic(KineticConfig {
+                candidates: usize::MAX,
+                ..kinetic
+            }),
+            Integrator::Kinetic(KineticConfig {
+                samples: usize::MAX,
+                ..kinetic
+            }),
+        ];
+        for integrator in good {
+            let scenario = Scenario {
+                integrator,
+                ..lava_lamp()
+            };
+            assert_eq!(scenario.validate(), Ok(()));
+        }
+        for integrator in bad {
+            let scenario = Scenario {
+                integrator: integrator.clone(),
+                ..lava_lamp()
+            };
+            assert!(
+                matches!(scenario.validate(), Err(ScenarioError::Invalid(_))),
+                "{:?}",
+                integrator
+            );
+        }
+    }
+}
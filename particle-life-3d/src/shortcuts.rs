@@ -0,0 +1,344 @@
+//! Keyboard shortcuts for the common actions, so that a demo can be driven without the side
+//! panel. Every binding lives in one remappable table; [`Action::apply`] is the handler for
+//! each action.
+use std::fmt;
+
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mcmc::Integrator,
+    sim::{Field, SimState},
+    slots::ConfigSlots,
+    timing::StepController,
+};
+
+/// Factor by which the up and down arrows scale the temperature or time step
+const ADJUST_FACTOR: f32 = 1.1;
+
+/// Half-width of the cube particles are scattered over on reset
+const RESET_RADIUS: f32 = 1.;
+
+/// Anyone to client: a key was pressed, e.g. forwarded by the desktop UI, see [`Shortcuts`]
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub struct KeyPress {
+    pub key: Key,
+    /// Whether a text field has focus, which then takes the key instead
+    pub text_focus: bool,
+}
+
+/// Something a shortcut can do
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Action {
+    TogglePause,
+    /// Run a single step, pausing first if running
+    StepOnce,
+    ResetParticles,
+    RandomizeBehaviours,
+    ToggleDebug,
+    /// Load the configuration slot with this index
+    LoadSlot(usize),
+    /// Raise the Monte Carlo temperature, or the time step of the explicit integrator
+    Increase,
+    Decrease,
+    ToggleHelp,
+}
+
+/// A key, as far as shortcuts are concerned. Letters are matched regardless of case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Key {
+    Char(char),
+    Up,
+    Down,
+}
+
+/// Everything the shortcuts act on
+pub struct Controls<'a> {
+    pub sim: &'a mut SimState,
+    pub integrator: &'a mut Integrator,
+    /// Time step of the explicit integrator
+    pub dt: &'a mut f32,
+    pub stepper: &'a mut StepController,
+    pub debug: &'a mut bool,
+    /// Whether the overlay listing the bindings is shown
+    pub show_help: &'a mut bool,
+    pub slots: &'a mut ConfigSlots,
+    pub rng: &'a mut Pcg,
+}
+
+/// The binding of each action to a key
+#[derive(Clone, Debug, PartialEq)]
+pub struct Shortcuts {
+    bindings: Vec<(Action, Key)>,
+}
+
+impl Action {
+    pub fn description(&self) -> String {
+        match self {
+            Action::TogglePause => "Pause or resume".into(),
+            Action::StepOnce => "Advance a single step, then pause".into(),
+            Action::ResetParticles => "Scatter the particles again".into(),
+            Action::RandomizeBehaviours => "Randomize the interaction strengths".into(),
+            Action::ToggleDebug => "Show or hide debug output".into(),
+            Action::LoadSlot(idx) => format!("Load configuration slot {}", idx + 1),
+            Action::Increase => "Raise the temperature, or the time step".into(),
+            Action::Decrease => "Lower the temperature, or the time step".into(),
+            Action::ToggleHelp => "Show or hide this list".into(),
+        }
+    }
+
+    pub fn apply(&self, controls: &mut Controls) {
+        match *self {
+            Action::TogglePause => controls.stepper.toggle(),
+            Action::StepOnce => controls.stepper.step(1),
+            Action::ResetParticles => controls
+                .sim
+                .rerandomize_positions(RESET_RADIUS, controls.rng),
+            Action::RandomizeBehaviours => {
+                let mut config = controls.sim.config().clone();
+                config.randomize_field(Field::Strength, false, controls.rng);
+                controls.sim.set_config(config, controls.rng);
+            }
+            Action::ToggleDebug => *controls.debug = !*controls.debug,
+            Action::LoadSlot(idx) => {
+                if let Some(config) = controls.slots.load(idx) {
+                    controls.sim.set_config(config, controls.rng);
+                }
+            }
+            Action::Increase => adjust(controls, ADJUST_FACTOR),
+            Action::Decrease => adjust(controls, ADJUST_FACTOR.recip()),
+            Action::ToggleHelp => *controls.show_help = !*controls.show_help,
+        }
+    }
+}
+
+/// Scale the temperature of the Monte Carlo integrators, or the time step of the explicit one
+fn adjust(controls: &mut Controls, factor: f32) {
+    match controls.integrator {
+        Integrator::Newton(_) => *controls.dt *= factor,
+        Integrator::Metropolis(config) => config.temperature *= factor,
+        Integrator::Kinetic(config) => config.temperature *= factor,
+    }
+}
+
+impl Shortcuts {
+    /// The action bound to a key press, unless a text field has focus and should receive it
+    pub fn action(&self, key: Key, text_focus: bool) -> Option<Action> {
+        if text_focus {
+            return None;
+        }
+        let key = key.normalized();
+        self.bindings
+            .iter()
+            .find(|(_, bound)| *bound == key)
+            .map(|(action, _)| *action)
+    }
+
+    /// Apply the action bound to a key press, if any, returning it
+    pub fn press(&self, press: KeyPress, controls: &mut Controls) -> Option<Action> {
+        let action = self.action(press.key, press.text_focus)?;
+        action.apply(controls);
+        Some(action)
+    }
+
+    pub fn key(&self, action: Action) -> Option<Key> {
+        self.bindings
+            .iter()
+            .find(|(bound, _)| *bound == action)
+            .map(|(_, key)| *key)
+    }
+
+    /// Bind an action to a key. An action already bound to that key takes over the old key
+    /// of this one, so that no key does two things.
+    pub fn remap(&mut self, action: Action, key: Key) {
+        let key = key.normalized();
+        let old = self.key(action);
+        for (bound, bound_key) in &mut self.bindings {
+            if *bound == action {
+                *bound_key = key;
+            } else if *bound_key == key {
+                match old {
+                    Some(old) => *bound_key = old,
+                    None => *bound = action,
+                }
+            }
+        }
+        if self.key(action).is_none() {
+            self.bindings.push((action, key));
+        }
+        self.bindings.dedup();
+    }
+
+    /// All bindings, in the order they are listed
+    pub fn bindings(&self) -> &[(Action, Key)] {
+        &self.bindings
+    }
+
+    /// Text of the overlay listing the bindings
+    pub fn help(&self) -> String {
+        self.bindings
+            .iter()
+            .map(|(action, key)| format!("{:>6}  {}\n", key.to_string(), action.description()))
+            .collect()
+    }
+}
+
+impl Key {
+    fn normalized(self) -> Self {
+        match self {
+            Key::Char(c) => Key::Char(c.to_ascii_lowercase()),
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Key::Char(' ') => write!(f, "Space"),
+            Key::Char(c) => write!(f, "{}", c.to_ascii_uppercase()),
+            Key::Up => write!(f, "Up"),
+            Key::Down => write!(f, "Down"),
+        }
+    }
+}
+
+impl Default for Shortcuts {
+    fn default() -> Self {
+        let mut bindings = vec![
+            (Action::TogglePause, Key::Char(' ')),
+            (Action::StepOnce, Key::Char('.')),
+            (Action::ResetParticles, Key::Char('r')),
+            (Action::RandomizeBehaviours, Key::Char('b')),
+            (Action::ToggleDebug, Key::Char('d')),
+        ];
+        bindings.extend(
+            ('1'..='9')
+                .enumerate()
+                .map(|(idx, digit)| (Action::LoadSlot(idx), Key::Char(digit))),
+        );
+        bindings.extend([
+            (Action::Increase, Key::Up),
+            (Action::Decrease, Key::Down),
+            (Action::ToggleHelp, Key::Char('?')),
+        ]);
+        Self { bindings }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        mcmc::MetropolisConfig,
+        sim::{Behaviour, SimConfig},
+    };
+
+    fn config() -> SimConfig {
+        SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 10.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_bindings() {
+        let mut shortcuts = Shortcuts::default();
+        let keys: Vec<Key> = shortcuts.bindings().iter().map(|(_, k)| *k).collect();
+        for (i, key) in keys.iter().enumerate() {
+            assert!(!keys[i + 1..].contains(key), "{:?} bound twice", key);
+        }
+
+        assert_eq!(
+            shortcuts.action(Key::Char('R'), false),
+            Some(Action::ResetParticles)
+        );
+        assert_eq!(
+            shortcuts.action(Key::Char('3'), false),
+            Some(Action::LoadSlot(2))
+        );
+        assert_eq!(shortcuts.action(Key::Char('r'), true), None);
+        assert_eq!(shortcuts.action(Key::Char('x'), false), None);
+
+        // Taking a key from another action swaps them
+        shortcuts.remap(Action::ResetParticles, Key::Char('B'));
+        assert_eq!(shortcuts.key(Action::ResetParticles), Some(Key::Char('b')));
+        assert_eq!(
+            shortcuts.key(Action::RandomizeBehaviours),
+            Some(Key::Char('r'))
+        );
+        assert_eq!(shortcuts.bindings().len(), keys.len());
+
+        let help = shortcuts.help();
+        assert_eq!(help.lines().count(), keys.len());
+        assert!(help.contains("Space  Pause or resume"), "{}", help);
+    }
+
+    #[test]
+    fn test_actions() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config(), 50);
+        let mut integrator = Integrator::Metropolis(MetropolisConfig {
+            temperature: 1.,
+            walk_sigma: 0.01,
+        });
+        let (mut dt, mut debug, mut show_help) = (1e-3, false, false);
+        let mut stepper = StepController::default();
+        let mut slots = ConfigSlots::default();
+        let mut slot_config = config();
+        slot_config.damping = 99.;
+        slots.save("Slot".into(), slot_config);
+
+        let mut controls = Controls {
+            sim: &mut sim,
+            integrator: &mut integrator,
+            dt: &mut dt,
+            stepper: &mut stepper,
+            debug: &mut debug,
+            show_help: &mut show_help,
+            slots: &mut slots,
+            rng: &mut rng,
+        };
+
+        let shortcuts = Shortcuts::default();
+        let press = |c: &mut Controls, key| {
+            let press = KeyPress {
+                key,
+                text_focus: false,
+            };
+            shortcuts.press(press, c).unwrap();
+        };
+        press(&mut controls, Key::Char(' '));
+        press(&mut controls, Key::Char('?'));
+        press(&mut controls, Key::Up);
+        press(&mut controls, Key::Up);
+        press(&mut controls, Key::Char('1'));
+        press(&mut controls, Key::Char('2'));
+        press(&mut controls, Key::Char('b'));
+
+        assert!(controls.stepper.is_paused() && *controls.show_help && !*controls.debug);
+        let typed = KeyPress {
+            key: Key::Char(' '),
+            text_focus: true,
+        };
+        assert_eq!(shortcuts.press(typed, &mut controls), None);
+        assert!(controls.stepper.is_paused());
+        press(&mut controls, Key::Char('.'));
+        press(&mut controls, Key::Char('.'));
+        assert_eq!(*controls.stepper, StepController::SteppingRemaining(2));
+        let Integrator::Metropolis(metropolis) = controls.integrator else {
+            unreachable!()
+        };
+        assert!((metropolis.temperature - 1.21).abs() < 1e-6);
+        assert_eq!(*controls.dt, 1e-3);
+        assert_eq!(controls.sim.config().damping, 99.);
+        assert_ne!(controls.sim.config().behaviours[0], Behaviour::default());
+
+        *controls.integrator = Integrator::default();
+        press(&mut controls, Key::Down);
+        assert!((*controls.dt - 1e-3 / 1.1).abs() < 1e-9);
+    }
+}
@@ -1,26 +1,233 @@
+use std::{
+    cell::{Ref, RefCell},
+    f32::consts::{PI, TAU},
+};
+
 use cimvr_common::glam::Vec3;
 use cimvr_engine_interface::pcg::Pcg;
+use serde::{Deserialize, Serialize};
 
-use crate::query_accel::QueryAccelerator;
+use crate::{
+    forces::{ContributorStatus, ForceBuffer, ForceContributor, ForcePipeline, StepView},
+    journal::{ChangeJournal, DEFAULT_JOURNAL_CAP},
+    metric::{min_image, DistanceMetric},
+    neighbor_counts::NeighborCountCache,
+    query_accel::{AccelMode, QueryAccelerator, RebuildInProgress},
+    staging::PendingConfig,
+    tables::BehaviourTables,
+    timing::Timer,
+};
 
 pub struct SimState {
     particles: Vec<Particle>,
+    /// Configuration as set, see [`SimState::config`]
     config: SimConfig,
+    /// Configuration as evaluated, with every interaction distance multiplied by the
+    /// interaction scale, which is then one, see [`SimConfig::scaled`]
+    scaled: SimConfig,
     max_interaction_radius: f32,
+    /// Interaction radius of each type, see [`SimConfig::max_radius_for_type`]
+    type_radius: Vec<f32>,
+    /// Squared interaction cutoff of each pair of types, see [`SimConfig::cutoff_sq_table`]
+    cutoff_sq: Vec<f32>,
     last_accel: QueryAccelerator,
     last_points: Vec<Vec3>,
+    /// Number of steps each rebuild of the query accelerator is spread over, if amortized
+    amortized_rebuild: Option<usize>,
+    /// Replacement for the query accelerator being binned a slice per step
+    rebuild: Option<RebuildInProgress>,
+    /// Neighbor counts at the last points, shared by everything which asks for them
+    neighbor_counts: RefCell<NeighborCountCache>,
+    /// How separations between particles are measured
+    metric: DistanceMetric,
+    /// Whether particles are confined to the XZ plane
+    constrain_2d: bool,
+    /// Position each particle is tethered to, if any
+    home: Option<Vec<Vec3>>,
+    /// Spring constant pulling particles towards their homes
+    tether_stiffness: f32,
+    /// Pinned particles exert forces but never move
+    pinned: Vec<bool>,
+    /// Whether each particle follows the fast behaviours, see [`Mobility`]
+    fast: Vec<bool>,
+    /// Second behaviour matrix, which particles follow in proportion to their blend value
+    blend_behaviours: Option<Vec<Behaviour>>,
+    /// Weight of the second behaviour matrix for each particle, from 0 to 1
+    blend: Vec<f32>,
+    /// Half-width of the cube whose walls mirror ghost particles, if enabled
+    ghost_walls: Option<f32>,
+    /// Rounding error of each position not yet applied, when using compensated summation
+    pos_compensation: Option<Vec<Vec3>>,
+    /// Magnitude of what last acted on each particle, see [`SimState::stress`]
+    stress: Vec<f32>,
+    stats: StepStats,
+    /// Whether particle positions or types changed since this was last taken
+    particles_dirty: bool,
+    /// Beyond this many neighbors, the neighbors of a particle are sampled
+    max_neighbors: Option<usize>,
+    /// Neighbors of the particle in progress while sampling them, kept for the allocation
+    neighbor_scratch: Vec<usize>,
+    /// Interactions beyond a near radius are sampled, if set
+    far_field: Option<FarFieldSampling>,
+    /// Planes particles are kept in front of, and stick to by type
+    walls: Vec<Wall>,
+    /// Tolerance of the lookup tables, if enabled
+    table_tolerance: Option<f32>,
+    /// Lookup tables of the current behaviours, when enabled and not blending
+    tables: Option<BehaviourTables>,
+    /// Whether to compute forces with the SIMD kernel where it applies
+    simd: bool,
+    /// Unit heading of each particle, kept while any behaviour is polar or any type active
+    orient: Option<Vec<Vec3>>,
+    /// Rate at which headings turn down the polar energy of their pairs
+    turn_rate: f32,
+    /// Region in which time runs slower, if any
+    time_bubble: Option<TimeBubble>,
+    /// Particles added, removed and retyped since the start of the last step
+    journal: ChangeJournal,
+    /// Configuration to swap in at the start of the next step, see [`SimState::stage_config`]
+    pending_config: Option<SimConfig>,
+    /// Contributors to the accelerations of each step, and the buffer they accumulate into
+    forces: ForcePipeline,
+    /// Randomness used by the simulation itself, forked from the caller's stream by
+    /// [`SimState::new`]
+    rng: Pcg,
+}
+
+/// Stochastic far-field interactions. Each particle interacts exactly with its neighbors
+/// within `near_radius`, and with `k` particles drawn from the whole population, counting
+/// those beyond `near_radius` with a weight of `(n - 1) / k`. The far force is then right on
+/// average at a cost of `O(n k)` rather than `O(n^2)`, with noise shrinking as `k` grows.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FarFieldSampling {
+    pub k: usize,
+    pub near_radius: f32,
+}
+
+impl Default for FarFieldSampling {
+    fn default() -> Self {
+        Self {
+            k: 4,
+            near_radius: 0.2,
+        }
+    }
+}
+
+/// A core of pinned particles, heavy in effect, and a ring of light ones about it in the XZ
+/// plane, set circling it. See [`SimState::spawn_orbital_preset`].
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct OrbitalPreset {
+    pub core: Color,
+    pub core_count: usize,
+    pub core_radius: f32,
+    pub ring: Color,
+    pub ring_count: usize,
+    pub ring_radius: f32,
+}
+
+impl Default for OrbitalPreset {
+    fn default() -> Self {
+        Self {
+            core: 0,
+            core_count: 100,
+            core_radius: 0.03,
+            ring: 1,
+            ring_count: 200,
+            ring_radius: 0.1,
+        }
+    }
+}
+
+/// What [`SimState::rebuild`] regenerates. Everything defaults to being kept.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct RebuildSpec {
+    pub positions: PositionReset,
+    pub velocities: VelocityReset,
+    pub types: TypeReset,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum PositionReset {
+    #[default]
+    Keep,
+    /// Scatter the particles uniformly over a cube of this half-width around the origin
+    Scatter(f32),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum VelocityReset {
+    #[default]
+    Keep,
+    Zero,
+    /// Draw velocities at this temperature, see [`SimState::thermalize`]
+    Thermalize(f32),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum TypeReset {
+    #[default]
+    Keep,
+    /// Give every particle a new random type
+    Reshuffle,
+}
+
+/// Work done during the last step. Timings are only measured with the `profiling` feature.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StepStats {
+    /// Time taken to rebuild the query accelerator, if a clock is available
+    pub accel_ms: Option<f32>,
+    /// Number of neighbor pairs visited by the force calculation
+    pub neighbor_pairs: usize,
+    /// Number of particles whose neighbors were sampled because of `max_neighbors`
+    pub capped_particles: usize,
 }
 
 type Color = u8;
 
-#[derive(Clone, Copy)]
+/// Separation below which [`Behaviour::effective_stiffness`] stops growing, for behaviours
+/// without a repulsive core to hold particles apart
+const MIN_STIFFNESS_DISTANCE: f32 = 0.01;
+
+/// Brightness of a type split off by [`SimConfig::split_type`], relative to the original
+const SPLIT_SHADE: f32 = 0.6;
+
+/// Default of [`SimState::set_turn_rate`]
+pub const DEFAULT_TURN_RATE: f32 = 20.;
+
+fn unit_scale() -> f32 {
+    1.
+}
+
+/// Smallest and largest [`SimConfig::interaction_scale`]
+pub const MIN_INTERACTION_SCALE: f32 = 0.01;
+pub const MAX_INTERACTION_SCALE: f32 = 100.;
+
+/// Fraction of its threshold by which a particle's speed must pass it to switch between the
+/// slow and fast behaviours, so that particles hovering about the threshold don't flicker
+/// between the two every step. See [`Mobility::fast_threshold`].
+pub const FAST_DEAD_BAND: f32 = 0.1;
+
+/// Passes over the touching hard sphere pairs each step. Resolving one contact can push a
+/// particle into another, so chains and clusters of contacts take a few passes to settle.
+pub const COLLISION_PASSES: usize = 4;
+
+/// Multiple of its radius to which hard sphere contacts are searched for, so that pairs are
+/// still found after moving towards each other since the query accelerator was built
+const HARD_SPHERE_REACH: f32 = 2.;
+
+/// Largest fraction of the particles an amortized query accelerator keeps in its overlay.
+/// Beyond this, as after a reset, building afresh is cheaper than querying around the old
+/// cells.
+const MAX_OVERLAY_FRACTION: f32 = 0.25;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Particle {
     pub pos: Vec3,
     pub vel: Vec3,
     pub color: Color,
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
 pub struct Behaviour {
     /// Magnitude of the default repulsion force
     pub default_repulse: f32,
@@ -30,25 +237,218 @@ pub struct Behaviour {
     pub inter_strength: f32,
     /// Maximum distance of particle interaction (0 to 1)
     pub inter_max_dist: f32,
+    /// Per-axis scale applied to the separation before measuring distance and direction, i.e.
+    /// the diagonal of a metric tensor. Larger values make particles effectively farther
+    /// apart along that axis. Components must be positive.
+    pub anisotropy: Vec3,
+    /// Dependence of the force on the orientations of the pair, from 0 for none to 1 for
+    /// full strength head to tail, none side by side and reversed head to head. See
+    /// [`polar_factor`].
+    pub polarity: f32,
+    pub mode: InteractionMode,
+}
+
+/// How a pair of particles interacts
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum InteractionMode {
+    /// Through the force and potential of the behaviour's coefficients
+    #[default]
+    Potential,
+    /// As hard spheres which touch at `radius` and bounce off each other elastically,
+    /// conserving momentum and kinetic energy, with no force otherwise. The coefficients are
+    /// ignored, and the Monte Carlo integrators see an infinite energy while overlapping.
+    /// A pair collides if either type treats the other as a hard sphere, at the larger radius.
+    HardSphere { radius: f32 },
 }
 
 /// Display colors and physical behaviour coefficients
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 pub struct SimConfig {
     pub colors: Vec<[f32; 3]>,
     pub behaviours: Vec<Behaviour>,
+    /// Factor on every interaction distance of the behaviours, fast behaviours and density
+    /// rules, applied as they are evaluated, so the ranges can be zoomed without touching
+    /// the matrix. See [`SimConfig::scaled`].
+    #[serde(default = "unit_scale")]
+    pub interaction_scale: f32,
     pub damping: f32,
+    /// Constant per-type force, for stratifying types by weight
+    pub gravity: Option<Gravity>,
+    /// Changes of type driven by crowding, applied after each step
+    pub density_rules: Vec<DensityRule>,
+    /// Speed limits, and behaviours of fast particles, by type
+    pub mobility: Option<Mobility>,
+    /// Self-propulsion along the headings, by type
+    pub activity: Option<Activity>,
+}
+
+/// No types, no damping and none of the optional forces, for filling in the rest of a
+/// configuration with `..Default::default()`
+impl Default for SimConfig {
+    fn default() -> Self {
+        Self {
+            colors: vec![],
+            behaviours: vec![],
+            interaction_scale: 1.,
+            damping: 0.,
+            gravity: None,
+            density_rules: vec![],
+            mobility: None,
+            activity: None,
+        }
+    }
+}
+
+/// Mobility of each type: how fast it may go, and how it behaves while going fast. Only the
+/// explicit integrator has velocities, so the Monte Carlo integrators ignore speed limits and
+/// leave particles in whichever behaviours the last explicit step chose.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Mobility {
+    /// Greatest speed of each type, to which velocities are clamped after each step. Types
+    /// beyond the end are unlimited.
+    pub max_speed: Vec<f32>,
+    /// Behaviours followed instead of `SimConfig::behaviours` by particles moving faster than
+    /// the threshold of their type, indexed like them
+    pub fast_behaviours: Option<Vec<Behaviour>>,
+    /// Speed above which each type follows the fast behaviours. A particle switches to them
+    /// once its speed exceeds the threshold by [`FAST_DEAD_BAND`], and back once it falls
+    /// short by as much. Types beyond the end never switch.
+    pub fast_threshold: Vec<f32>,
+}
+
+/// Self-propulsion of each type, for active matter such as flocks, swarms and mills. Active
+/// particles keep a heading, shared with polar behaviours, and are pushed along it each step
+/// of the explicit integrator while it wanders by rotational diffusion. Propulsion has no
+/// equilibrium, so the Monte Carlo integrators ignore it.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Activity {
+    /// Speed at which a free particle of each type swims; the force along its heading is
+    /// this times the damping. Types beyond the end are passive.
+    pub speed: Vec<f32>,
+    /// Rotational diffusion rate of the heading of each type, in square radians per unit of
+    /// time. Headings stay straight for about `1 / ((d - 1) * rate)` in `d` dimensions.
+    pub rotational_diffusion: Vec<f32>,
+    /// Rate at which the headings of swimming particles turn towards their velocities, so
+    /// that collisions steer them
+    pub alignment: f32,
+}
+
+/// Constant force along a shared "down" axis, scaled by a weight for each type
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Gravity {
+    /// Direction of the force on positive weights; need not be normalized
+    pub down: Vec3,
+    /// Weight of each type. Positive weights sink, negative weights float, and types beyond
+    /// the end are weightless.
+    pub weights: Vec<f32>,
+}
+
+/// Change of type driven by local crowding, like birth and death in the Game of Life. After
+/// each step, a particle of type `ty` with more than `crowded_threshold` neighbors within
+/// `check_radius` becomes `crowded_becomes`, and one with fewer than `lonely_threshold`
+/// becomes `lonely_becomes`, each with chance `probability`. The first rule for a type whose
+/// threshold is crossed decides.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DensityRule {
+    pub ty: Color,
+    pub crowded_threshold: usize,
+    pub crowded_becomes: Color,
+    pub lonely_threshold: usize,
+    pub lonely_becomes: Color,
+    /// Radius neighbors are counted within; the interaction radius at most, beyond which
+    /// neighbors are not found
+    pub check_radius: f32,
+    /// Chance of the change each step the threshold is crossed
+    pub probability: f32,
+}
+
+/// Sphere inside which time runs slower than outside, for watching part of the cloud in slow
+/// motion while the rest carries on. The explicit integrator scales the time step of each
+/// particle by [`TimeBubble::factor_at`] its position. The Monte Carlo integrators have no
+/// time step, and instead pick each particle for a move with that probability, leaving the
+/// acceptance alone; this slows the region's sampling in proportion, but is not exactly
+/// dynamics at a slower rate.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TimeBubble {
+    pub center: Vec3,
+    pub radius: f32,
+    /// Width of the shell outside `radius` over which the rate eases back to normal, so that
+    /// the dynamics have no discontinuity at the surface
+    pub shell: f32,
+    /// Rate of time inside, relative to outside, e.g. 0.1 for ten times slower
+    pub factor: f32,
+}
+
+/// Plane that particles cannot pass, and that particles of some types stick to, like the
+/// substrate of a deposition. Each type is drawn towards the plane as by a [`Behaviour`]
+/// whose interaction strength is its affinity, with distance from the plane as the distance.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Wall {
+    /// Any point on the plane
+    pub point: Vec3,
+    /// Side of the plane particles are kept on; need not be normalized
+    pub normal: Vec3,
+    /// Affinity of each type for the plane. Positive affinities attract, negative ones
+    /// repel, and types beyond the end are indifferent.
+    pub affinity: Vec<f32>,
+    /// Distance at which attracted particles settle, and within which every type is repelled
+    pub threshold: f32,
+    /// Magnitude of that repulsion
+    pub repulse: f32,
+    /// Distance from the plane at which the affinity has faded to zero
+    pub range: f32,
+}
+
+/// Coarse description of an interaction strength, for quick sketching of rules
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Qualitative {
+    StrongAttract,
+    WeakAttract,
+    Neutral,
+    WeakRepel,
+    StrongRepel,
+}
+
+/// A single behaviour coefficient, selectable for editing across the whole matrix
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Field {
+    Strength,
+    Threshold,
+    MaxDist,
+    Repulse,
+}
+
+/// Errors arising from loading or editing a configuration
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConfigError {
+    /// A cell could not be parsed as a number
+    Parse {
+        row: usize,
+        col: usize,
+        text: String,
+    },
+    /// The matrix shape does not match the number of particle types
+    Dimensions {
+        expected: usize,
+        rows: usize,
+        cols: usize,
+    },
+    /// A whole configuration could not be parsed
+    Syntax(String),
+    /// The configuration parsed, but describes an unusable simulation
+    Invalid(&'static str),
 }
 
 impl Behaviour {
     /// Returns the force on this particle
     ///
-    /// Distance is in the range `0.0..=1.0`
-    fn interact(&self, dist: f32) -> f32 {
+    /// Distance is in the range `0.0..=1.0`. A threshold of zero means there is no repulsive
+    /// core, and a max distance equal to the threshold means there is only repulsion.
+    pub fn interact(&self, dist: f32) -> f32 {
         if dist < self.inter_threshold {
             let f = dist / self.inter_threshold;
             (1. - f) * -self.default_repulse
-        } else if dist > self.inter_max_dist {
+        } else if dist >= self.inter_max_dist {
             0.0
         } else {
             let x = dist - self.inter_threshold;
@@ -58,136 +458,5682 @@ impl Behaviour {
             x * self.inter_strength
         }
     }
+
+    /// Steepest slope of [`Behaviour::interact`] over distance, i.e. the stiffest spring the
+    /// piecewise-linear kernel acts as
+    pub fn stiffness(&self) -> f32 {
+        let (t, m) = (self.inter_threshold, self.inter_max_dist);
+        let repulse = if t > 0. {
+            self.default_repulse.abs() / t
+        } else {
+            0.
+        };
+        let interact = if m > t {
+            2. * self.inter_strength.abs() / (m - t)
+        } else {
+            0.
+        };
+        repulse.max(interact)
+    }
+
+    /// Effective spring constant of a pair near its equilibrium separation. The acceleration
+    /// is the kernel divided by distance, so the kernel's slope is divided by the threshold,
+    /// and the metric stretches it by the largest anisotropy.
+    pub fn effective_stiffness(&self) -> f32 {
+        self.stiffness() * self.anisotropy.max_element()
+            / self.inter_threshold.max(MIN_STIFFNESS_DISTANCE)
+    }
+
+    /// Acceleration towards a particle at `diff`, already in the metric of this behaviour.
+    /// Pairs further apart than `sqrt(cutoff_sq)` exert no force.
+    fn accel(&self, diff: Vec3, cutoff_sq: f32) -> Vec3 {
+        // Coincident particles have no direction to push in, and hard spheres only collide
+        let dist_sq = diff.length_squared();
+        if dist_sq > cutoff_sq || dist_sq == 0. || self.hard_radius().is_some() {
+            return Vec3::ZERO;
+        }
+
+        // Force over distance. Scaling the difference directly rather than normalizing it
+        // first takes one square root and one division.
+        diff * (self.interact(dist_sq.sqrt()) / dist_sq)
+    }
+
+    /// Potential energy at the given distance, whose derivative is the magnitude of
+    /// [`Behaviour::accel`]. Zero at the max distance and beyond. Hard spheres have an
+    /// infinite potential while overlapping, and none otherwise.
+    pub fn potential(&self, dist: f32) -> f32 {
+        if let Some(radius) = self.hard_radius() {
+            return if dist < radius { f32::INFINITY } else { 0. };
+        }
+        let (t, m) = (self.inter_threshold, self.inter_max_dist);
+        // The core potential diverges logarithmically at zero
+        let d = dist.max(1e-6);
+        if d >= m {
+            return 0.;
+        }
+
+        // Integral of force over distance from `a` to `b`, given an antiderivative
+        let segment = |a: f32, b: f32, f: &dyn Fn(f32) -> f32| if a < b { f(b) - f(a) } else { 0. };
+
+        let repulse = self.default_repulse;
+        let mut integral = segment(d, t.min(m), &|r| repulse * (r / t - r.ln()));
+        if t < m {
+            let c = (t + m) / 2.;
+            let strength = self.inter_strength;
+            integral += segment(d.max(t), c, &|r| strength / (c - t) * (r - t * r.ln()));
+            integral += segment(d.max(c), m, &|r| strength / (m - c) * (m * r.ln() - r));
+        }
+        -integral
+    }
+
+    /// Radius at which a hard sphere pair touches, see [`InteractionMode::HardSphere`]
+    pub fn hard_radius(&self) -> Option<f32> {
+        match self.mode {
+            InteractionMode::Potential => None,
+            InteractionMode::HardSphere { radius } => Some(radius),
+        }
+    }
+
+    /// Largest distance at which this behaviour acts, along any axis, counting hard spheres
+    /// out to [`HARD_SPHERE_REACH`] times their radius
+    pub fn reach(&self) -> f32 {
+        match self.hard_radius() {
+            Some(radius) => radius * HARD_SPHERE_REACH,
+            None => self.inter_max_dist / self.anisotropy.min_element(),
+        }
+    }
+
+    /// Integral of the force over distance; the net pull of this behaviour
+    fn drive(&self) -> f32 {
+        let repulsion = -self.default_repulse * self.inter_threshold.max(0.) / 2.;
+        let window = (self.inter_max_dist - self.inter_threshold).max(0.);
+        repulsion + self.inter_strength * window / 2.
+    }
+}
+
+impl Gravity {
+    /// Acceleration of a particle of the given type
+    pub fn accel(&self, color: Color) -> Vec3 {
+        let weight = self.weights.get(color as usize).copied().unwrap_or(0.);
+        self.down.normalize_or_zero() * weight
+    }
+}
+
+impl Mobility {
+    /// Speed limit of the given type, if any
+    pub fn max_speed(&self, color: Color) -> Option<f32> {
+        self.max_speed.get(color as usize).copied()
+    }
+
+    /// Whether a particle of the given type moving at `speed` follows the fast behaviours,
+    /// given whether it did before
+    pub fn is_fast(&self, color: Color, speed: f32, was_fast: bool) -> bool {
+        let Some(threshold) = self.fast_threshold.get(color as usize) else {
+            return false;
+        };
+        self.fast_behaviours.is_some()
+            && match was_fast {
+                true => speed >= threshold * (1. - FAST_DEAD_BAND),
+                false => speed > threshold * (1. + FAST_DEAD_BAND),
+            }
+    }
+}
+
+impl Activity {
+    /// Swimming speed of the given type
+    pub fn speed(&self, color: Color) -> f32 {
+        self.speed.get(color as usize).copied().unwrap_or(0.)
+    }
+
+    /// Rotational diffusion rate of the heading of the given type
+    pub fn rotational_diffusion(&self, color: Color) -> f32 {
+        (self.rotational_diffusion.get(color as usize))
+            .copied()
+            .unwrap_or(0.)
+    }
+
+    /// Whether any type swims or turns
+    pub fn is_active(&self) -> bool {
+        (self.speed.iter().chain(&self.rotational_diffusion)).any(|&v| v != 0.)
+    }
+}
+
+impl TimeBubble {
+    /// Whether every field is finite, and the sizes and factor are not negative
+    pub fn is_valid(&self) -> bool {
+        self.center.is_finite()
+            && [self.radius, self.shell, self.factor]
+                .iter()
+                .all(|x| x.is_finite() && *x >= 0.)
+    }
+
+    /// Rate of time at `pos`: `factor` within the radius, one beyond the shell, and eased
+    /// smoothly between
+    pub fn factor_at(&self, pos: Vec3) -> f32 {
+        let dist = pos.distance(self.center);
+        if dist <= self.radius {
+            return self.factor;
+        }
+        if dist >= self.radius + self.shell {
+            return 1.;
+        }
+        let t = (dist - self.radius) / self.shell;
+        let eased = t * t * (3. - 2. * t);
+        self.factor + (1. - self.factor) * eased
+    }
+}
+
+impl Wall {
+    /// Horizontal wall at the given height, keeping particles above it
+    pub fn floor(height: f32, affinity: Vec<f32>) -> Self {
+        Self {
+            point: Vec3::Y * height,
+            normal: Vec3::Y,
+            affinity,
+            threshold: 0.02,
+            repulse: 10.,
+            range: 0.1,
+        }
+    }
+
+    /// Signed distance of `pos` from the plane, positive on the side particles are kept on
+    pub fn distance(&self, pos: Vec3) -> f32 {
+        (pos - self.point).dot(self.normal.normalize_or_zero())
+    }
+
+    /// Interaction of a particle of the given type with the plane
+    fn behaviour(&self, color: Color) -> Behaviour {
+        Behaviour {
+            default_repulse: self.repulse,
+            inter_threshold: self.threshold,
+            inter_strength: self.affinity.get(color as usize).copied().unwrap_or(0.),
+            inter_max_dist: self.range,
+            anisotropy: Vec3::ONE,
+            polarity: 0.,
+            mode: InteractionMode::Potential,
+        }
+    }
+
+    /// Acceleration of a particle of the given type at `pos`
+    pub fn accel(&self, pos: Vec3, color: Color) -> Vec3 {
+        // Towards the nearest point of the plane. A particle on the plane has no direction
+        // to be pushed in, like coincident particles.
+        let diff = -self.normal.normalize_or_zero() * self.distance(pos).max(0.);
+        self.behaviour(color).accel(diff, self.range * self.range)
+    }
+
+    /// Potential energy of a particle of the given type at `pos`, infinite behind the plane
+    pub fn potential(&self, pos: Vec3, color: Color) -> f32 {
+        match self.distance(pos) {
+            d if d < 0. => f32::INFINITY,
+            d => self.behaviour(color).potential(d),
+        }
+    }
+
+    /// Bring a particle behind the plane back in front by reflecting it off the plane
+    pub fn collide(&self, particle: &mut Particle) {
+        let distance = self.distance(particle.pos);
+        if distance < 0. {
+            let normal = self.normal.normalize_or_zero();
+            particle.pos -= normal * (2. * distance);
+            particle.vel -= normal * (2. * particle.vel.dot(normal).min(0.));
+        }
+    }
 }
 
 impl SimState {
     pub fn new(rng: &mut Pcg, config: SimConfig, n: usize) -> Self {
         let particles = (0..n).map(|_| random_particle(rng, &config)).collect();
-        let max_interaction_radius: f32 = config
-            .behaviours
-            .iter()
-            .map(|b| b.inter_max_dist)
-            .fold(0., |r, acc| acc.max(r));
+        Self::with_rng(config, particles, fork_rng(rng))
+    }
 
-        Self {
+    /// Create a simulation from an existing set of particles. Its own random stream is the
+    /// same for every simulation made this way.
+    pub fn from_particles(config: SimConfig, particles: Vec<Particle>) -> Self {
+        Self::with_rng(config, particles, Pcg::new())
+    }
+
+    fn with_rng(config: SimConfig, particles: Vec<Particle>, rng: Pcg) -> Self {
+        let scaled = config.scaled();
+        let max_interaction_radius = scaled.max_interaction_radius();
+        let type_radius = scaled.type_radius_table();
+        let cutoff_sq = scaled.cutoff_sq_table();
+        let last_points: Vec<Vec3> = particles.iter().map(|p| p.pos).collect();
+        let last_accel = QueryAccelerator::new(&last_points, max_interaction_radius);
+        let n = particles.len();
+
+        let mut sim = Self {
             particles,
             config,
+            scaled,
             max_interaction_radius,
-            last_points: vec![],
-            last_accel: QueryAccelerator::new(&[], 1.),
+            type_radius,
+            cutoff_sq,
+            last_points,
+            last_accel,
+            amortized_rebuild: None,
+            rebuild: None,
+            neighbor_counts: RefCell::default(),
+            metric: DistanceMetric::Euclidean,
+            constrain_2d: false,
+            home: None,
+            tether_stiffness: 0.,
+            pinned: vec![false; n],
+            fast: vec![false; n],
+            blend_behaviours: None,
+            blend: vec![0.; n],
+            pos_compensation: None,
+            stress: vec![0.; n],
+            ghost_walls: None,
+            stats: StepStats::default(),
+            particles_dirty: true,
+            max_neighbors: None,
+            neighbor_scratch: vec![],
+            far_field: None,
+            walls: vec![],
+            table_tolerance: None,
+            tables: None,
+            simd: false,
+            orient: None,
+            turn_rate: DEFAULT_TURN_RATE,
+            time_bubble: None,
+            journal: ChangeJournal::new(DEFAULT_JOURNAL_CAP),
+            pending_config: None,
+            forces: ForcePipeline::new(builtin_forces()),
+            rng,
+        };
+        sim.update_orientations();
+        sim
+    }
+
+    /// Confine particles to the XZ plane, or release them back into 3D
+    pub fn set_constrain_2d(&mut self, enable: bool, rng: &mut Pcg) {
+        if enable == self.constrain_2d {
+            return;
+        }
+
+        for particle in &mut self.particles {
+            if enable {
+                particle.pos.y = 0.;
+                particle.vel.y = 0.;
+            } else {
+                // Break the symmetry so particles can expand out of the plane again
+                particle.pos.y = (rng.gen_f32() * 2. - 1.) * 1e-3;
+            }
+        }
+        if let Some(orient) = &mut self.orient {
+            for heading in orient.iter_mut() {
+                // Headings straight up or down have no direction left in the plane
+                *heading = flatten_heading(*heading, enable).unwrap_or(Vec3::X);
+            }
         }
+
+        self.constrain_2d = enable;
+        self.particles_dirty = true;
+        self.rebuild_accel();
     }
 
-    pub fn move_neighbors(&mut self, pt: Vec3, accel: Vec3) {
-        for i in self
-            .last_accel
-            .query_neighbors_by_point(&self.last_points, pt)
-        {
-            self.particles[i].vel += accel;
+    pub fn constrain_2d(&self) -> bool {
+        self.constrain_2d
+    }
+
+    /// Tether each particle to where it is now
+    pub fn set_homes_to_current(&mut self) {
+        self.home = Some(self.particles.iter().map(|p| p.pos).collect());
+    }
+
+    /// Tether particles to the sites of a lattice around their centroid; cubic in 3D and
+    /// hexagonal when confined to 2D. Particles are matched to nearby sites by sorting both
+    /// along a space-filling curve.
+    pub fn assign_homes_to_lattice(&mut self, spacing: f32) {
+        let n = self.particles.len();
+        if n == 0 {
+            return;
+        }
+
+        let centroid = self.particles.iter().map(|p| p.pos).sum::<Vec3>() / n as f32;
+        let mut sites = if self.constrain_2d {
+            hexagonal_lattice(n, spacing)
+        } else {
+            cubic_lattice(n, spacing)
+        };
+
+        // Keep the sites closest to the center
+        sites.sort_by(|a, b| a.length_squared().total_cmp(&b.length_squared()));
+        sites.truncate(n);
+        let mut sites: Vec<Vec3> = sites.into_iter().map(|s| s + centroid).collect();
+
+        let particle_pos: Vec<Vec3> = self.particles.iter().map(|p| p.pos).collect();
+        let mut order: Vec<usize> = (0..n).collect();
+        let bounds = morton_bounds(&particle_pos);
+        order.sort_by_key(|&i| morton_key(particle_pos[i], bounds));
+        let bounds = morton_bounds(&sites);
+        let site_keys: Vec<u64> = sites.iter().map(|&s| morton_key(s, bounds)).collect();
+        let mut site_order: Vec<usize> = (0..n).collect();
+        site_order.sort_by_key(|&i| site_keys[i]);
+        let sorted_sites: Vec<Vec3> = site_order.iter().map(|&i| sites[i]).collect();
+
+        for (&particle, site) in order.iter().zip(sorted_sites) {
+            sites[particle] = site;
         }
+        self.home = Some(sites);
     }
 
-    pub fn step(&mut self, dt: f32) {
-        let points: Vec<Vec3> = self.particles.iter().map(|p| p.pos).collect();
-        let accel = QueryAccelerator::new(&points, self.max_interaction_radius);
+    pub fn clear_homes(&mut self) {
+        self.home = None;
+    }
 
-        let len = self.particles.len();
-        for i in 0..len {
-            let mut total_accel = Vec3::ZERO;
-            for neighbor in accel.query_neighbors(&points, i) {
-                let a = self.particles[i];
-                let b = self.particles[neighbor];
+    pub fn homes(&self) -> Option<&[Vec3]> {
+        self.home.as_deref()
+    }
 
-                // The vector pointing from a to b
-                let diff = b.pos - a.pos;
+    pub fn set_tether_stiffness(&mut self, stiffness: f32) {
+        self.tether_stiffness = stiffness;
+    }
 
-                // Distance is capped
-                let dist = diff.length();
+    pub fn tether_stiffness(&self) -> f32 {
+        self.tether_stiffness
+    }
 
-                // Accelerate towards b
-                let normal = diff.normalize();
-                let behav = self.config.get_bahaviour(a.color, b.color);
-                let accel = normal * behav.interact(dist) / dist;
-                total_accel += accel;
+    /// Keep particles within the cube of the given half-width by reflecting them off its walls
+    pub fn reflect_walls(&mut self, half_width: f32) {
+        for particle in &mut self.particles {
+            for axis in 0..3 {
+                let (pos, vel) = (&mut particle.pos[axis], &mut particle.vel[axis]);
+                if *pos > half_width {
+                    *pos = (2. * half_width - *pos).max(-half_width);
+                    *vel = -vel.abs();
+                } else if *pos < -half_width {
+                    *pos = (-2. * half_width - *pos).min(half_width);
+                    *vel = vel.abs();
+                }
             }
+        }
+        self.particles_dirty = true;
+    }
 
-            let vel = self.particles[i].vel + total_accel * dt;
+    /// Balance the forces on particles near the walls of the cube with the given half-width
+    /// (see [`SimState::reflect_walls`]) by adding mirror images of their neighbors beyond the
+    /// walls. Costs extra work for each particle near a wall.
+    pub fn set_ghost_walls(&mut self, half_width: Option<f32>) {
+        self.ghost_walls = half_width;
+    }
 
-            // Dampen velocity
-            let vel = vel * (1. - dt * self.config.damping);
+    pub fn ghost_walls(&self) -> Option<f32> {
+        self.ghost_walls
+    }
 
-            self.particles[i].vel = vel;
-            self.particles[i].pos += vel * dt;
+    /// Set the planes particles are kept in front of, moving any particles behind them back
+    /// in front
+    pub fn set_walls(&mut self, walls: Vec<Wall>) {
+        for particle in &mut self.particles {
+            for wall in &walls {
+                wall.collide(particle);
+            }
         }
+        self.walls = walls;
+        self.particles_dirty = true;
+        self.rebuild_accel();
+    }
 
-        self.last_accel = accel;
-        self.last_points = points;
+    pub fn walls(&self) -> &[Wall] {
+        &self.walls
     }
 
-    pub fn particles(&self) -> &[Particle] {
-        &self.particles
+    /// Pin or unpin a particle. Pinned particles keep exerting forces, but stay put.
+    pub fn set_pinned(&mut self, i: usize, pinned: bool) {
+        if self.pinned[i] != pinned {
+            self.pinned[i] = pinned;
+            self.particles[i].vel = Vec3::ZERO;
+            self.particles_dirty = true;
+        }
     }
 
-    pub fn config(&self) -> &SimConfig {
-        &self.config
+    /// Pin or unpin all particles within `radius` of `pt`
+    pub fn set_pinned_within(&mut self, pt: Vec3, radius: f32, pinned: bool) {
+        let radius_sq = radius * radius;
+        for i in 0..self.particles.len() {
+            if self.particles[i].pos.distance_squared(pt) <= radius_sq {
+                self.set_pinned(i, pinned);
+            }
+        }
     }
-}
 
-impl SimConfig {
-    fn random_color(&self, rng: &mut Pcg) -> Color {
-        (rng.gen_u32() as usize % self.colors.len()) as u8
+    /// Whether each particle is pinned
+    pub fn pinned(&self) -> &[bool] {
+        &self.pinned
     }
 
-    pub fn get_bahaviour(&self, a: Color, b: Color) -> Behaviour {
-        let idx = a as usize * self.colors.len() + b as usize;
-        self.behaviours[idx]
+    /// Accumulate position updates with compensated (Kahan) summation. Keeps small steps from
+    /// being lost to rounding when particles are far from the origin or move slowly, over long
+    /// runs. Off by default.
+    pub fn set_compensated_positions(&mut self, enable: bool) {
+        self.pos_compensation = enable.then(|| vec![Vec3::ZERO; self.particles.len()]);
     }
-}
 
-fn random_particle(rng: &mut Pcg, config: &SimConfig) -> Particle {
-    let range = 2.0;
-    Particle {
-        pos: Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * range
-            - Vec3::splat(range / 2.),
-        vel: Vec3::ZERO,
-        color: config.random_color(rng),
+    /// Bound the cost of each particle's force calculation. A particle with more than `cap`
+    /// neighbors interacts with `cap` of them, evenly strided from a random start, each force
+    /// scaled by `total / cap` so that the force is unchanged on average. Off (`None`) by
+    /// default.
+    pub fn set_max_neighbors(&mut self, cap: Option<usize>) {
+        self.max_neighbors = cap.map(|cap| cap.max(1));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    pub fn max_neighbors(&self) -> Option<usize> {
+        self.max_neighbors
+    }
 
-    #[test]
-    fn test_behaviour() {
-        let behav = Behaviour {
-            default_repulse: 1.0,
-            inter_threshold: 0.25,
-            inter_strength: 3.0,
-            inter_max_dist: 0.75,
-        };
+    /// Sample interactions beyond a near radius, see [`FarFieldSampling`]. The query
+    /// accelerator then only reaches the near radius, and so do [`SimState::neighbors`] and
+    /// [`SimState::energy_due_to`].
+    pub fn set_far_field(&mut self, far_field: Option<FarFieldSampling>) {
+        self.far_field = far_field;
+    }
 
-        assert_eq!(behav.interact(0.), -behav.default_repulse);
-        assert_eq!(behav.interact(behav.inter_threshold), 0.0);
-        assert_eq!(behav.interact(0.25 + 0.125), behav.inter_strength / 2.);
-        assert_eq!(behav.interact(0.5), behav.inter_strength);
-        assert_eq!(behav.interact(behav.inter_max_dist), 0.0);
-        assert_eq!(behav.interact(0.85), 0.0);
+    pub fn far_field(&self) -> Option<FarFieldSampling> {
+        self.far_field
     }
-}
 
-impl Default for Behaviour {
-    fn default() -> Self {
-        Self {
-            default_repulse: 10.,
-            inter_threshold: 0.02,
-            inter_strength: 1.,
-            inter_max_dist: 0.2,
+    /// Radius within which interactions are computed exactly
+    fn near_radius(&self) -> f32 {
+        match self.far_field {
+            Some(far_field) => far_field.near_radius.min(self.max_interaction_radius),
+            None => self.max_interaction_radius,
+        }
+    }
+
+    /// Rebuild the query accelerator from the current positions
+    pub(crate) fn rebuild_accel(&mut self) {
+        self.last_points = self.particles.iter().map(|p| p.pos).collect();
+        self.last_accel = QueryAccelerator::with_metric(
+            &self.last_points,
+            self.max_interaction_radius,
+            self.metric,
+        );
+        self.neighbor_counts.get_mut().invalidate();
+    }
+
+    /// Measure separations in `metric` from now on, for forces, energies and neighbor
+    /// queries alike. Positions are left as they are, wrapped or not.
+    pub fn set_metric(&mut self, metric: DistanceMetric) {
+        self.metric = metric;
+        self.rebuild = None;
+        self.rebuild_accel();
+    }
+
+    pub fn metric(&self) -> DistanceMetric {
+        self.metric
+    }
+
+    /// Regenerate the parts of the simulation named by `spec`, keeping the rest. Every reset
+    /// goes through here, so that the per-particle arrays and the query accelerator, which is
+    /// always rebuilt from the final positions, can never be left stale.
+    pub fn rebuild(&mut self, spec: RebuildSpec, rng: &mut Pcg) {
+        if spec.types == TypeReset::Reshuffle {
+            for (i, particle) in self.particles.iter_mut().enumerate() {
+                let old = particle.color;
+                particle.color = self.scaled.random_color(rng);
+                self.journal.retyped(i, old, particle.color);
+            }
+        }
+
+        if let PositionReset::Scatter(half_width) = spec.positions {
+            for particle in &mut self.particles {
+                particle.pos = random_position(rng, half_width);
+                if self.constrain_2d {
+                    particle.pos.y = 0.;
+                }
+            }
+            if let Some(compensation) = &mut self.pos_compensation {
+                compensation.fill(Vec3::ZERO);
+            }
+        }
+
+        match spec.velocities {
+            VelocityReset::Keep => (),
+            VelocityReset::Zero => self.zero_velocities(),
+            VelocityReset::Thermalize(temperature) => self.thermalize(temperature, rng),
+        }
+
+        self.particles_dirty = true;
+        self.rebuild_accel();
+        debug_assert_eq!(self.check_invariants(), Ok(()));
+    }
+
+    /// Check that every per-particle array has an entry for each particle, that the behaviour
+    /// matrix is square with a valid interaction radius per type, that types are in range, and
+    /// that the query accelerator only refers to points it was built over
+    pub fn check_invariants(&self) -> Result<(), String> {
+        let n = self.particles.len();
+        let mut lengths = vec![
+            ("pinned", self.pinned.len()),
+            ("fast", self.fast.len()),
+            ("blend", self.blend.len()),
+            ("stress", self.stress.len()),
+        ];
+        if let Some(home) = &self.home {
+            lengths.push(("home", home.len()));
+        }
+        if let Some(compensation) = &self.pos_compensation {
+            lengths.push(("position compensation", compensation.len()));
+        }
+        if let Some(orient) = &self.orient {
+            lengths.push(("orientation", orient.len()));
+        }
+        if let Some((name, len)) = lengths.into_iter().find(|&(_, len)| len != n) {
+            return Err(format!("{} has {} entries for {} particles", name, len, n));
+        }
+
+        let n_colors = self.scaled.colors.len();
+        if self.scaled.behaviours.len() != n_colors * n_colors {
+            return Err(format!(
+                "{} behaviours for {} types",
+                self.scaled.behaviours.len(),
+                n_colors
+            ));
+        }
+        if self.type_radius.len() != n_colors {
+            return Err(format!(
+                "{} interaction radii for {} types",
+                self.type_radius.len(),
+                n_colors
+            ));
+        }
+        if let Some(t) = self
+            .type_radius
+            .iter()
+            .position(|r| !r.is_finite() || *r < 0.)
+        {
+            return Err(format!(
+                "Type {} has interaction radius {}",
+                t, self.type_radius[t]
+            ));
+        }
+        if let Some(i) = self
+            .particles
+            .iter()
+            .position(|p| p.color as usize >= n_colors)
+        {
+            return Err(format!(
+                "Particle {} has type {} of {}",
+                i, self.particles[i].color, n_colors
+            ));
+        }
+
+        if self.last_accel.point_count() != self.last_points.len() {
+            return Err(format!(
+                "Accelerator was built over {} points, not the {} last points",
+                self.last_accel.point_count(),
+                self.last_points.len()
+            ));
+        }
+        if !self.last_accel.indices_in_range() {
+            return Err("Accelerator refers to points out of range".into());
+        }
+        self.last_accel
+            .validate(&self.last_points)
+            .map_err(|e| format!("Accelerator: {}", e))?;
+        Ok(())
+    }
+
+    /// Approximate heap memory held by the particles, their per-particle arrays and the
+    /// query accelerator
+    pub fn memory_bytes(&self) -> usize {
+        use std::mem::size_of;
+        let optional = |v: &Option<Vec<Vec3>>| v.as_ref().map_or(0, |v| v.capacity());
+        self.particles.capacity() * size_of::<Particle>()
+            + (self.last_points.capacity()
+                + optional(&self.home)
+                + optional(&self.pos_compensation)
+                + optional(&self.orient))
+                * size_of::<Vec3>()
+            + self.pinned.capacity()
+            + self.fast.capacity()
+            + (self.blend.capacity() + self.stress.capacity()) * size_of::<f32>()
+            + self.last_accel.memory_bytes()
+    }
+
+    /// Assign every particle a new random type, keeping positions and velocities
+    pub fn reshuffle_types(&mut self, rng: &mut Pcg) {
+        let spec = RebuildSpec {
+            types: TypeReset::Reshuffle,
+            ..Default::default()
+        };
+        self.rebuild(spec, rng);
+    }
+
+    /// Scatter the particles over a cube of the given half-width, at rest
+    pub fn rerandomize_positions(&mut self, radius: f32, rng: &mut Pcg) {
+        let spec = RebuildSpec {
+            positions: PositionReset::Scatter(radius),
+            velocities: VelocityReset::Zero,
+            ..Default::default()
+        };
+        self.rebuild(spec, rng);
+    }
+
+    pub fn zero_velocities(&mut self) {
+        for particle in &mut self.particles {
+            particle.vel = Vec3::ZERO;
+        }
+    }
+
+    /// Draw velocities from the Maxwell-Boltzmann distribution at `temperature`, in the energy
+    /// units of [`SimState::energy_due_to`] with unit mass, so that each free component has a
+    /// variance of `temperature`. Pinned particles stay at rest.
+    pub fn thermalize(&mut self, temperature: f32, rng: &mut Pcg) {
+        let sigma = temperature.max(0.).sqrt();
+        for (particle, &pinned) in self.particles.iter_mut().zip(&self.pinned) {
+            particle.vel = if pinned {
+                Vec3::ZERO
+            } else {
+                Vec3::new(gaussian(rng), gaussian(rng), gaussian(rng)) * sigma
+            };
+            if self.constrain_2d {
+                particle.vel.y = 0.;
+            }
+        }
+    }
+
+    /// Temperature at which the unpinned particles' kinetic energy is typical, by
+    /// equipartition; the inverse of [`SimState::thermalize`]
+    pub fn kinetic_temperature(&self) -> f32 {
+        let (sum_sq, free) = self
+            .particles
+            .iter()
+            .zip(&self.pinned)
+            .filter(|(_, &pinned)| !pinned)
+            .fold((0., 0), |(sum, n), (p, _)| {
+                (sum + p.vel.length_squared(), n + 1)
+            });
+        let dims = if self.constrain_2d { 2 } else { 3 };
+        if free == 0 {
+            0.
+        } else {
+            sum_sq / (free * dims) as f32
+        }
+    }
+
+    /// Remove the given particles, which may be in any order and repeated. Remaining
+    /// particles may be reordered. Every per-particle array is kept in step here; see also
+    /// [`SimState::push_particle`].
+    pub fn remove_indices(&mut self, indices: &[usize]) {
+        let mut indices = indices.to_vec();
+        indices.sort_unstable();
+        indices.dedup();
+        if let Some(&last) = indices.last() {
+            assert!(
+                last < self.particles.len(),
+                "Particle index {} out of range",
+                last
+            );
+        }
+
+        // Highest first, so that the particle swapped into each hole is never one still due
+        // for removal
+        for &i in indices.iter().rev() {
+            self.journal.removed(i, self.particles[i].pos);
+            self.particles.swap_remove(i);
+            self.pinned.swap_remove(i);
+            self.fast.swap_remove(i);
+            self.blend.swap_remove(i);
+            if let Some(home) = &mut self.home {
+                home.swap_remove(i);
+            }
+            if let Some(compensation) = &mut self.pos_compensation {
+                compensation.swap_remove(i);
+            }
+            if let Some(orient) = &mut self.orient {
+                orient.swap_remove(i);
+            }
+            self.stress.swap_remove(i);
+        }
+
+        self.particles_dirty = true;
+        self.rebuild_accel();
+    }
+
+    /// Add a particle, unpinned and unblended, tethered where it is if tethers are in use,
+    /// and facing a random way if headings are.
+    /// The query accelerator is not updated until the next step or
+    /// [`SimState::rebuild_accel`].
+    pub fn push_particle(&mut self, mut particle: Particle) {
+        if self.constrain_2d {
+            particle.pos.y = 0.;
+            particle.vel.y = 0.;
+        }
+        self.pinned.push(false);
+        self.fast.push(false);
+        self.blend.push(0.);
+        if let Some(home) = &mut self.home {
+            home.push(particle.pos);
+        }
+        if let Some(compensation) = &mut self.pos_compensation {
+            compensation.push(Vec3::ZERO);
+        }
+        if let Some(orient) = &mut self.orient {
+            orient.push(random_direction(&mut self.rng, self.constrain_2d));
+        }
+        self.stress.push(0.);
+        self.journal.created(self.particles.len());
+        self.particles.push(particle);
+        self.particles_dirty = true;
+    }
+
+    /// Mean position of the particles, or the origin if there are none
+    pub fn centroid(&self) -> Vec3 {
+        if self.particles.is_empty() {
+            return Vec3::ZERO;
+        }
+        self.particles.iter().map(|p| p.pos).sum::<Vec3>() / self.particles.len() as f32
+    }
+
+    /// Translate the particles, their homes, the walls and the time bubble so that the
+    /// centroid is at the origin, restoring position resolution after the cloud drifted far
+    /// away. Returns the translation, or `None` with ghost walls, which stay around the origin.
+    pub fn recenter(&mut self) -> Option<Vec3> {
+        if self.ghost_walls.is_some() {
+            return None;
+        }
+
+        let mut shift = -self.centroid();
+        if self.constrain_2d {
+            shift.y = 0.;
+        }
+        for particle in &mut self.particles {
+            particle.pos += shift;
+        }
+        if let Some(home) = &mut self.home {
+            home.iter_mut().for_each(|h| *h += shift);
+        }
+        for wall in &mut self.walls {
+            wall.point += shift;
+        }
+        if let Some(bubble) = &mut self.time_bubble {
+            bubble.center += shift;
+        }
+        self.particles_dirty = true;
+        self.rebuild_accel();
+        Some(shift)
+    }
+
+    /// Teleport the given particles to random positions within `radius` of `center`, at rest,
+    /// e.g. to bring back those which escaped the cloud. The query accelerator is rebuilt, so
+    /// that the far cells they left stop taking up room.
+    pub fn recall(&mut self, indices: &[usize], center: Vec3, radius: f32) {
+        for &i in indices {
+            // Uniform over the ball, or the disc in 2D
+            let u = self.rng.gen_f32();
+            let dist = radius
+                * if self.constrain_2d {
+                    u.sqrt()
+                } else {
+                    u.cbrt()
+                };
+            let pos = center + random_direction(&mut self.rng, self.constrain_2d) * dist;
+            self.journal.recalled(i);
+            self.move_particle(i, pos);
+            self.particles[i].vel = Vec3::ZERO;
+            if let Some(compensation) = &mut self.pos_compensation {
+                compensation[i] = Vec3::ZERO;
+            }
+        }
+        self.rebuild_accel();
+        self.particles_dirty = true;
+    }
+
+    /// Remove the particles within `radius` of `center`, or with `inside` false, those
+    /// beyond it. Returns the number removed.
+    pub fn remove_in_sphere(&mut self, center: Vec3, radius: f32, inside: bool) -> usize {
+        let radius_sq = radius * radius;
+        let indices: Vec<usize> = (0..self.particles.len())
+            .filter(|&i| (self.particles[i].pos.distance_squared(center) <= radius_sq) == inside)
+            .collect();
+        self.remove_indices(&indices);
+        indices.len()
+    }
+
+    /// Number of particles of each type within `radius` of `center`, as of the last step.
+    /// Spheres no larger than the query radius are answered by the accelerator, larger ones
+    /// by a scan of every particle.
+    pub fn count_in_sphere(&self, center: Vec3, radius: f32) -> Vec<usize> {
+        let mut counts = vec![0; self.scaled.colors.len()];
+        let radius_sq = radius * radius;
+        let fresh = self.last_points.len() == self.particles.len();
+        let pos = |i: usize| match fresh {
+            true => self.last_points[i],
+            false => self.particles[i].pos,
+        };
+        let mut count = |i: usize| {
+            if pos(i).distance_squared(center) <= radius_sq {
+                if let Some(count) = counts.get_mut(self.particles[i].color as usize) {
+                    *count += 1;
+                }
+            }
+        };
+
+        if fresh && radius <= self.last_accel.radius() {
+            self.last_accel
+                .query_neighbors_by_point(&self.last_points, center)
+                .for_each(&mut count);
+        } else {
+            (0..self.particles.len()).for_each(&mut count);
+        }
+        counts
+    }
+
+    /// Add `n` particles of the given type at rest, uniformly within `radius` of `center`
+    pub fn spawn_in_sphere(
+        &mut self,
+        center: Vec3,
+        radius: f32,
+        color: Color,
+        n: usize,
+        rng: &mut Pcg,
+    ) {
+        assert!(
+            (color as usize) < self.scaled.colors.len(),
+            "Unknown type {}",
+            color
+        );
+        for _ in 0..n {
+            let offset = loop {
+                let mut offset = random_position(rng, radius);
+                if self.constrain_2d {
+                    offset.y = 0.;
+                }
+                if offset.length_squared() <= radius * radius {
+                    break offset;
+                }
+            };
+            self.push_particle(Particle {
+                pos: center + offset,
+                vel: Vec3::ZERO,
+                color,
+            });
+        }
+        self.rebuild_accel();
+    }
+
+    /// Replace every particle with `preset`: a pinned core around the origin, and a ring given
+    /// the speed of a circular orbit under the current behaviours. The inward acceleration of
+    /// the ring is sampled from the net force on each of its particles, due to the core and
+    /// the rest of the ring alike, and averaged; the speed is then `sqrt(a r)`. Returns that
+    /// speed, zero if the ring is pushed outwards and can't orbit at all.
+    pub fn spawn_orbital_preset(&mut self, preset: &OrbitalPreset, rng: &mut Pcg) -> f32 {
+        assert!(
+            (preset.ring as usize) < self.scaled.colors.len(),
+            "Unknown type {}",
+            preset.ring
+        );
+        self.remove_indices(&(0..self.particles.len()).collect::<Vec<_>>());
+        let core = preset.core_count;
+        self.spawn_in_sphere(Vec3::ZERO, preset.core_radius, preset.core, core, rng);
+        for i in 0..core {
+            self.set_pinned(i, true);
+        }
+
+        let radius = preset.ring_radius;
+        for k in 0..preset.ring_count {
+            let angle = k as f32 / preset.ring_count as f32 * TAU;
+            self.push_particle(Particle {
+                pos: Vec3::new(angle.cos(), 0., angle.sin()) * radius,
+                vel: Vec3::ZERO,
+                color: preset.ring,
+            });
+        }
+        self.rebuild_accel();
+
+        let ring = core..self.particles.len();
+        let inward: f32 = (ring.clone())
+            .map(|i| {
+                -self
+                    .total_force(i)
+                    .dot(self.particles[i].pos.normalize_or_zero())
+            })
+            .sum::<f32>()
+            / ring.len().max(1) as f32;
+        let speed = (inward.max(0.) * radius).sqrt();
+        for i in ring {
+            let tangent = Vec3::Y.cross(self.particles[i].pos).normalize_or_zero();
+            self.particles[i].vel = tangent * speed;
+        }
+        speed
+    }
+
+    /// Change the type of every particle of type `t` to `mapping[t]`. Types beyond the end of
+    /// `mapping` are left alone.
+    pub fn remap_types(&mut self, mapping: &[Color]) {
+        for (i, particle) in self.particles.iter_mut().enumerate() {
+            if let Some(&color) = mapping.get(particle.color as usize) {
+                self.journal.retyped(i, particle.color, color);
+                particle.color = color;
+            }
+        }
+        self.particles_dirty = true;
+    }
+
+    /// Merge type `b` into type `a`, see [`SimConfig::merge_types`]. Particles of type `b`
+    /// become type `a`.
+    pub fn merge_types(&mut self, a: Color, b: Color, rng: &mut Pcg) {
+        let mut config = self.config.clone();
+        let mapping = config.merge_types(a, b);
+        self.remap_types(&mapping);
+        for wall in &mut self.walls {
+            let affinity = &mut wall.affinity;
+            affinity.resize(mapping.len(), 0.);
+            affinity[a as usize] = (affinity[a as usize] + affinity[b as usize]) / 2.;
+            affinity.remove(b as usize);
+        }
+        self.set_config(config, rng);
+    }
+
+    /// Split type `a` in two, see [`SimConfig::split_type`]. A random half of its particles
+    /// take the new type, whose index is returned.
+    pub fn split_type(&mut self, a: Color, rng: &mut Pcg) -> Color {
+        let mut config = self.config.clone();
+        let new = config.split_type(a);
+        for wall in &mut self.walls {
+            wall.affinity.resize(new as usize, 0.);
+            wall.affinity.push(wall.affinity[a as usize]);
+        }
+
+        // Shuffle the particles of type `a`, then relabel the first half
+        let mut members: Vec<usize> = (0..self.particles.len())
+            .filter(|&i| self.particles[i].color == a)
+            .collect();
+        for i in (1..members.len()).rev() {
+            members.swap(i, rng.gen_u32() as usize % (i + 1));
+        }
+        for &i in &members[..members.len() / 2] {
+            self.particles[i].color = new;
+            self.journal.retyped(i, a, new);
+        }
+        self.particles_dirty = true;
+
+        self.set_config(config, rng);
+        new
+    }
+
+    /// Replace the configuration while keeping the particles. Particles whose type no longer
+    /// exists are given a random new type.
+    pub fn set_config(&mut self, config: SimConfig, rng: &mut Pcg) {
+        let n_colors = config.colors.len();
+        for (i, particle) in self.particles.iter_mut().enumerate() {
+            if particle.color as usize >= n_colors {
+                let old = particle.color;
+                particle.color = config.random_color(rng);
+                self.journal.retyped(i, old, particle.color);
+                self.particles_dirty = true;
+            }
+        }
+        self.swap_config(config);
+    }
+
+    /// Swap in `config`, whose types every particle already has
+    pub(crate) fn swap_config(&mut self, config: SimConfig) {
+        self.scaled = config.scaled();
+        self.config = config;
+        if self
+            .blend_behaviours
+            .as_ref()
+            .is_some_and(|b| b.len() != self.scaled.behaviours.len())
+        {
+            self.blend_behaviours = None;
+        }
+        self.update_interaction_scale();
+        self.update_orientations();
+        self.rebuild_accel();
+    }
+
+    /// Queue `config` to replace the configuration at the start of the next step, instead of
+    /// in the middle of one. Configurations arriving from elsewhere go through here, since the
+    /// type count may change under them. Staging again before the next step replaces the
+    /// queued configuration, so only the latest is applied.
+    pub fn stage_config(&mut self, config: SimConfig) {
+        self.pending_config = Some(config);
+    }
+
+    /// Configuration waiting for the next step, if any
+    pub fn pending_config(&self) -> Option<&SimConfig> {
+        self.pending_config.as_ref()
+    }
+
+    /// Swap in the staged configuration, if any. This is the one point at which the stepping
+    /// code sees the type count change, before any neighbor queries; particles of types which
+    /// no longer exist take the type of the nearest color, as [`PendingConfig`] proposes.
+    /// Returns whether there was one to apply.
+    pub fn apply_pending_config(&mut self) -> bool {
+        let Some(config) = self.pending_config.take() else {
+            return false;
+        };
+        PendingConfig::new(self, config).apply(self);
+        if let Err(message) = self.check_invariants() {
+            panic!("Staged configuration broke an invariant: {}", message);
+        }
+        true
+    }
+
+    /// Set the second behaviour matrix which particles follow in proportion to their blend
+    /// value, indexed like `SimConfig::behaviours`. It is dropped when the number of types
+    /// changes.
+    pub fn set_blend_behaviours(&mut self, behaviours: Option<Vec<Behaviour>>) {
+        if let Some(b) = &behaviours {
+            assert_eq!(b.len(), self.scaled.behaviours.len(), "Blend matrix size");
+        }
+        self.blend_behaviours = behaviours;
+        self.update_interaction_scale();
+        self.update_orientations();
+        self.rebuild_accel();
+    }
+
+    pub fn blend_behaviours(&self) -> Option<&[Behaviour]> {
+        self.blend_behaviours.as_deref()
+    }
+
+    /// Set the blend value of all particles within `radius` of `pt`
+    pub fn set_blend_within(&mut self, pt: Vec3, radius: f32, blend: f32) {
+        let radius_sq = radius * radius;
+        for (particle, value) in self.particles.iter().zip(&mut self.blend) {
+            if particle.pos.distance_squared(pt) <= radius_sq {
+                *value = blend.clamp(0., 1.);
+            }
+        }
+        self.particles_dirty = true;
+    }
+
+    /// Set blend values to a gradient along X, from 0 at `min_x` to 1 at `max_x`
+    pub fn set_blend_gradient_x(&mut self, min_x: f32, max_x: f32) {
+        for (particle, value) in self.particles.iter().zip(&mut self.blend) {
+            *value = ((particle.pos.x - min_x) / (max_x - min_x)).clamp(0., 1.);
+        }
+        self.particles_dirty = true;
+    }
+
+    pub fn blend(&self) -> &[f32] {
+        &self.blend
+    }
+
+    /// Whether each particle followed the fast behaviours during the last step
+    pub fn fast(&self) -> &[bool] {
+        &self.fast
+    }
+
+    /// Switch particles between the slow and fast behaviours by their current speed
+    fn update_fast(&mut self) {
+        let Some(mobility) = &self.scaled.mobility else {
+            self.fast.fill(false);
+            return;
+        };
+        for (particle, fast) in self.particles.iter().zip(&mut self.fast) {
+            *fast = mobility.is_fast(particle.color, particle.vel.length(), *fast);
+        }
+    }
+
+    /// Interpolate the potential and force from lookup tables sampled to within `tolerance`
+    /// (see [`BehaviourTables::new`]), or evaluate them directly with `None`. The tables are
+    /// resampled whenever the behaviours change, and skipped while blending or switching to
+    /// fast behaviours, since the behaviours then differ per particle, and with hard spheres.
+    pub fn set_use_tables(&mut self, tolerance: Option<f32>) {
+        if tolerance != self.table_tolerance {
+            self.table_tolerance = tolerance;
+            self.tables = None;
+            self.update_interaction_scale();
+        }
+    }
+
+    pub fn use_tables(&self) -> bool {
+        self.table_tolerance.is_some()
+    }
+
+    /// Lookup tables in use, if any
+    pub fn tables(&self) -> Option<&BehaviourTables> {
+        self.tables.as_ref()
+    }
+
+    /// Compute forces with the SIMD kernel of [`crate::simd`], which gathers the forces on
+    /// each particle from [`crate::simd::LANES`] neighbors at a time. Only takes effect with
+    /// the `simd` feature, and only where no lookup tables, blending, fast behaviours, neighbor
+    /// caps, hard spheres or polar behaviours are in use; the scalar path is taken otherwise.
+    pub fn set_use_simd(&mut self, enable: bool) {
+        self.simd = enable;
+    }
+
+    /// Whether the next step computes forces with the SIMD kernel
+    pub fn uses_simd(&self) -> bool {
+        let n = self.scaled.colors.len();
+        cfg!(feature = "simd")
+            && self.simd
+            && self.tables.is_none()
+            && self.blend_behaviours.is_none()
+            && self.scaled.fast_behaviours().is_none()
+            && !self.scaled.has_hard_spheres()
+            && self.max_neighbors.is_none()
+            && self.orient.is_none()
+            && self.metric == DistanceMetric::Euclidean
+            && self.scaled.behaviours.len() == n * n
+            && self.cutoff_sq.len() == n * n
+    }
+
+    /// Heading of each particle, a unit vector, while any behaviour is polar or any type active
+    pub fn orientations(&self) -> Option<&[Vec3]> {
+        self.orient.as_deref()
+    }
+
+    /// Point particle `i` along `heading`, if headings are in use. Headings too short to
+    /// normalize are ignored.
+    pub fn set_orientation(&mut self, i: usize, heading: Vec3) {
+        if let Some(orient) = &mut self.orient {
+            orient[i] = flatten_heading(heading, self.constrain_2d).unwrap_or(orient[i]);
+            self.particles_dirty = true;
+        }
+    }
+
+    /// Set how fast headings turn in response to their neighbors, per unit of polar energy
+    pub fn set_turn_rate(&mut self, rate: f32) {
+        self.turn_rate = rate;
+    }
+
+    pub fn turn_rate(&self) -> f32 {
+        self.turn_rate
+    }
+
+    /// Slow time down within a region, or run everywhere at the same rate with `None`
+    pub fn set_time_bubble(&mut self, bubble: Option<TimeBubble>) {
+        self.time_bubble = bubble;
+    }
+
+    pub fn time_bubble(&self) -> Option<&TimeBubble> {
+        self.time_bubble.as_ref()
+    }
+
+    /// Rate of time for particle `i`, see [`TimeBubble`]
+    pub fn time_scale(&self, i: usize) -> f32 {
+        match &self.time_bubble {
+            Some(bubble) => bubble.factor_at(self.particles[i].pos),
+            None => 1.,
+        }
+    }
+
+    /// Start or drop the headings as the behaviours become polar or active or stop being so,
+    /// so that a simulation without either carries no headings and spends nothing on them
+    fn update_orientations(&mut self) {
+        let polar = self.scaled.is_polar()
+            || self.scaled.is_active()
+            || (self.blend_behaviours.iter().flatten()).any(|b| b.polarity != 0.);
+        if !polar {
+            self.orient = None;
+        } else if self.orient.is_none() {
+            let n = self.particles.len();
+            let planar = self.constrain_2d;
+            self.orient = Some(
+                (0..n)
+                    .map(|_| random_direction(&mut self.rng, planar))
+                    .collect(),
+            );
+        }
+    }
+
+    /// Update the interaction radius, cutoffs and lookup tables from both behaviour matrices
+    fn update_interaction_scale(&mut self) {
+        self.max_interaction_radius = self.scaled.max_interaction_radius();
+        self.type_radius = self.scaled.type_radius_table();
+        self.cutoff_sq = self.scaled.cutoff_sq_table();
+        // Hard spheres have no smooth potential to tabulate
+        let direct = self.blend_behaviours.is_some()
+            || self.scaled.fast_behaviours().is_some()
+            || self.scaled.has_hard_spheres();
+        self.tables = match self.table_tolerance {
+            Some(tolerance) if !direct => match self.tables.take() {
+                Some(tables) if tables.matches(&self.scaled.behaviours) => Some(tables),
+                _ => Some(BehaviourTables::new(&self.scaled.behaviours, tolerance)),
+            },
+            _ => None,
+        };
+        if let Some(blend) = &self.blend_behaviours {
+            // Lerped behaviours never reach further than both ends
+            let scale = self.config.interaction_scale;
+            let other = SimConfig {
+                behaviours: blend.iter().map(|b| b.scaled(scale)).collect(),
+                ..self.scaled.clone()
+            };
+            self.max_interaction_radius = self
+                .max_interaction_radius
+                .max(other.max_interaction_radius());
+            for (cutoff, other) in self.cutoff_sq.iter_mut().zip(other.cutoff_sq_table()) {
+                *cutoff = cutoff.max(other);
+            }
+            for (radius, other) in self.type_radius.iter_mut().zip(other.type_radius_table()) {
+                *radius = radius.max(other);
+            }
+        }
+    }
+
+    /// Neighbors of particle `i` within the interaction radius, as of the last step
+    pub fn neighbors(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+        (self.last_points.len() == self.particles.len())
+            .then(|| self.last_accel.query_neighbors(&self.last_points, i))
+            .into_iter()
+            .flatten()
+    }
+
+    /// Neighbors of particle `i` within the interaction radius of its type, as of the last
+    /// accelerator rebuild. These are the neighbors the force pass visits, short of any
+    /// neighbor cap or far field sampling.
+    pub fn interaction_neighbors(&self, i: usize) -> impl Iterator<Item = usize> + '_ {
+        let radius = self.type_radius[self.particles[i].color as usize];
+        (self.last_points.len() == self.particles.len())
+            .then(|| {
+                (self.last_accel).query_neighbors_radius(
+                    &self.last_points,
+                    i,
+                    self.last_points[i],
+                    radius,
+                )
+            })
+            .into_iter()
+            .flatten()
+    }
+
+    /// Whether the query accelerator finds exactly the particles within the interaction radius
+    /// of particle `i`, as of the last step. Costs a pass over all particles.
+    pub fn accel_consistent(&self, i: usize) -> bool {
+        if self.last_points.len() != self.particles.len() {
+            return false;
+        }
+
+        let pt = self.last_points[i];
+        let radius_sq = self.max_interaction_radius * self.max_interaction_radius;
+        let within = |j: usize| self.metric.distance_squared(pt, self.last_points[j]) <= radius_sq;
+        let mut expected: Vec<usize> = (0..self.last_points.len())
+            .filter(|&j| j != i && within(j))
+            .collect();
+        let mut found: Vec<usize> = self.neighbors(i).collect();
+        expected.sort_unstable();
+        found.sort_unstable();
+        expected == found
+    }
+
+    pub fn move_neighbors(&mut self, pt: Vec3, mut accel: Vec3) {
+        if self.constrain_2d {
+            accel.y = 0.;
+        }
+
+        for i in self
+            .last_accel
+            .query_neighbors_by_point(&self.last_points, pt)
+        {
+            if !self.pinned[i] {
+                self.particles[i].vel += accel;
+            }
+        }
+    }
+
+    pub fn step(&mut self, dt: f32) {
+        self.journal.clear();
+        self.apply_pending_config();
+        self.step_types(dt, None);
+        self.apply_density_rules();
+    }
+
+    /// Step with `substeps[t]` inner steps of `dt / substeps[t]` for particles of type `t`, so
+    /// that a stiff type can be integrated finely without slowing everything else down. Types
+    /// beyond the end of `substeps` take a single step. This is a multiple time stepping
+    /// approximation: while a type substeps, the particles of other types are held at their
+    /// positions at the start of the step, and only the type itself moves. Identical to
+    /// [`SimState::step`] when every type takes a single step.
+    pub fn step_substeps(&mut self, dt: f32, substeps: &[u32]) {
+        if substeps.iter().all(|&k| k <= 1) {
+            return self.step(dt);
+        }
+        self.journal.clear();
+        self.apply_pending_config();
+
+        let start = self.particles.clone();
+        let mut end = start.clone();
+        for color in 0..self.scaled.colors.len() {
+            if !start.iter().any(|p| p.color as usize == color) {
+                continue;
+            }
+
+            // Every type starts from, and sees the others at, the start of the step
+            self.particles.clone_from(&start);
+            let k = substeps.get(color).copied().unwrap_or(1).max(1);
+            for _ in 0..k {
+                self.step_types(dt / k as f32, Some(color as Color));
+            }
+            for (end, particle) in end.iter_mut().zip(&self.particles) {
+                if particle.color as usize == color {
+                    *end = *particle;
+                }
+            }
+        }
+        // Substeps started over from `start`, so only the end of the step is a displacement
+        self.journal.max_displacement = 0.;
+        for (start, end) in start.iter().zip(&end) {
+            self.journal.moved(start.pos.distance(end.pos));
+        }
+        self.particles = end;
+        self.apply_density_rules();
+    }
+
+    /// Change types by the configuration's [`DensityRule`]s. Neighbors are counted at the
+    /// positions of the last accelerator rebuild, and every change is decided before any is
+    /// made, so that the outcome does not depend on the order of the particles.
+    fn apply_density_rules(&mut self) {
+        if self.scaled.density_rules.is_empty() || self.last_points.len() != self.particles.len() {
+            return;
+        }
+
+        // Counted together in one sweep
+        for rule in &self.scaled.density_rules {
+            let radius = self.density_radius(rule);
+            self.neighbor_counts.get_mut().register(radius);
+        }
+
+        let n_types = self.scaled.colors.len();
+        let mut changes = vec![];
+        for i in 0..self.particles.len() {
+            let color = self.particles[i].color;
+            for rule in self.scaled.density_rules.iter().filter(|r| r.ty == color) {
+                let count = self.neighbor_counts(self.density_radius(rule))[i] as usize;
+                let becomes = if count > rule.crowded_threshold {
+                    rule.crowded_becomes
+                } else if count < rule.lonely_threshold {
+                    rule.lonely_becomes
+                } else {
+                    continue;
+                };
+                if (becomes as usize) < n_types
+                    && (rule.probability >= 1. || self.rng.gen_f32() < rule.probability)
+                {
+                    changes.push((i, becomes));
+                }
+                break;
+            }
+        }
+
+        for &(i, color) in &changes {
+            self.journal.retyped(i, self.particles[i].color, color);
+            self.particles[i].color = color;
+        }
+        if !changes.is_empty() {
+            self.particles_dirty = true;
+        }
+    }
+
+    /// Radius within which a density rule counts neighbors: its own, within the neighbors the
+    /// accelerator finds
+    fn density_radius(&self, rule: &DensityRule) -> f32 {
+        rule.check_radius.min(self.last_accel.radius())
+    }
+
+    /// Number of neighbors of each particle within `radius` as of the last step, empty if
+    /// particles were added or removed since. Comes from a cache shared by every consumer:
+    /// the radius stays registered once asked for, and all registered radii are counted in a
+    /// single sweep, at most once per step unless new radii keep being asked for.
+    pub fn neighbor_counts(&self, radius: f32) -> Ref<'_, [u32]> {
+        if self.last_points.len() != self.particles.len() {
+            return Ref::map(self.neighbor_counts.borrow(), |_| &[][..]);
+        }
+        let mut cache = self.neighbor_counts.borrow_mut();
+        cache.register(radius);
+        cache.refresh(&self.last_accel, &self.last_points);
+        drop(cache);
+        Ref::map(self.neighbor_counts.borrow(), |cache| {
+            cache.counts(radius).unwrap()
+        })
+    }
+
+    /// Number of sweeps made to count neighbors so far, see [`SimState::neighbor_counts`]
+    pub fn neighbor_count_sweeps(&self) -> usize {
+        self.neighbor_counts.borrow().sweeps()
+    }
+
+    /// Register a contributor to the accelerations of each step, enabled, to run after the
+    /// built-in forces and those registered before it
+    pub fn add_force(&mut self, contributor: Box<dyn ForceContributor>) {
+        self.forces.push(contributor);
+    }
+
+    /// Unregister the named force contributor, returning it if there was one
+    pub fn remove_force(&mut self, name: &str) -> Option<Box<dyn ForceContributor>> {
+        self.forces.remove(name)
+    }
+
+    /// Switch the named force contributor, built-in or not, on or off; false if there is
+    /// none by that name
+    pub fn set_force_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        self.forces.set_enabled(name, enabled)
+    }
+
+    /// Every force contributor in the order they run, with what each costs per step
+    pub fn force_status(&self) -> Vec<ContributorStatus> {
+        self.forces.status()
+    }
+
+    /// Accelerations of the particles as of the last step, before any constraint to 2D
+    pub fn forces(&self) -> &ForceBuffer {
+        self.forces.buffer()
+    }
+
+    /// Step the particles of type `only`, or all of them, against all the others
+    fn step_types(&mut self, dt: f32, only: Option<Color>) {
+        self.update_fast();
+        let points: Vec<Vec3> = self.particles.iter().map(|p| p.pos).collect();
+        let timer = Timer::start();
+        let near_radius = self.near_radius();
+        let accel = self.next_accel(&points, near_radius);
+        self.stats.accel_ms = timer.elapsed_ms();
+
+        self.stats.neighbor_pairs = 0;
+        self.stats.capped_particles = 0;
+
+        let gradients = self.heading_gradients(&accel, &points);
+
+        let step = StepView {
+            points: &points,
+            accel: &accel,
+            only,
+            dt,
+        };
+        // Set aside while its contributors run, since they may read any of the simulation, and
+        // put back even if one panics, so that a step recovered by `try_step` keeps its forces
+        let mut pipeline = std::mem::take(&mut self.forces);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            self.integrate(&mut pipeline, &step, &gradients)
+        }));
+        self.forces = pipeline;
+        if let Err(payload) = result {
+            std::panic::resume_unwind(payload);
+        }
+
+        if self.scaled.has_hard_spheres() {
+            self.collide(&accel, &points, only);
+        }
+
+        self.last_accel = accel;
+        self.last_points = points;
+        self.neighbor_counts.get_mut().invalidate();
+        self.particles_dirty = true;
+    }
+
+    /// Kick and drift each particle which moves in `step` by the accelerations `pipeline`
+    /// accumulates, and turn its heading down `gradients`
+    fn integrate(&mut self, pipeline: &mut ForcePipeline, step: &StepView, gradients: &[Vec3]) {
+        pipeline.begin(self, step);
+        let len = self.particles.len();
+        self.stress.resize(len, 0.);
+        for i in 0..len {
+            if step
+                .only
+                .is_some_and(|color| self.particles[i].color != color)
+            {
+                continue;
+            }
+            if self.pinned[i] {
+                self.stress[i] = 0.;
+                continue;
+            }
+
+            let mut total_accel = pipeline.accel_on(self, step, i);
+            if self.constrain_2d {
+                total_accel.y = 0.;
+            }
+            self.stress[i] = total_accel.length();
+
+            // Everything this particle does during the step happens at its own rate of time
+            let dt = step.dt * self.time_scale(i);
+            let vel = self.particles[i].vel + total_accel * dt;
+
+            // Dampen velocity
+            let mut vel = vel * (1. - dt * self.scaled.damping);
+
+            let color = self.particles[i].color;
+            if let Some(max_speed) = self
+                .scaled
+                .mobility
+                .as_ref()
+                .and_then(|m| m.max_speed(color))
+            {
+                vel = vel.clamp_length_max(max_speed);
+            }
+
+            self.particles[i].vel = vel;
+            let before = self.particles[i].pos;
+            match &mut self.pos_compensation {
+                // Kahan summation
+                Some(compensation) => {
+                    let pos = self.particles[i].pos;
+                    let delta = vel * dt - compensation[i];
+                    let sum = pos + delta;
+                    compensation[i] = (sum - pos) - delta;
+                    self.particles[i].pos = sum;
+                }
+                None => self.particles[i].pos += vel * dt,
+            }
+            for wall in &self.walls {
+                wall.collide(&mut self.particles[i]);
+            }
+            self.journal.moved(before.distance(self.particles[i].pos));
+
+            // Turn down the gradient, within the sphere of headings, then renormalize
+            if let Some(orient) = &mut self.orient {
+                let heading = orient[i];
+                let torque = gradients[i] - heading * gradients[i].dot(heading);
+                let turned = heading - torque * (self.turn_rate * dt);
+                orient[i] = flatten_heading(turned, self.constrain_2d).unwrap_or(heading);
+            }
+
+            // Active headings also follow the velocity, and wander
+            if let (Some(activity), Some(orient)) = (&self.scaled.activity, &mut self.orient) {
+                let heading = orient[i];
+                let mut turn = Vec3::ZERO;
+                if activity.speed(color) != 0. && activity.alignment != 0. {
+                    if let Some(dir) = vel.try_normalize() {
+                        turn += (dir - heading * dir.dot(heading)) * (activity.alignment * dt);
+                    }
+                }
+                let diffusion = activity.rotational_diffusion(color);
+                if diffusion != 0. {
+                    let rng = &mut self.rng;
+                    let kick = Vec3::new(gaussian(rng), gaussian(rng), gaussian(rng))
+                        * (2. * diffusion * dt).sqrt();
+                    turn += kick - heading * kick.dot(heading);
+                }
+                // Passive headings are left bit for bit alone
+                if turn != Vec3::ZERO {
+                    orient[i] =
+                        flatten_heading(heading + turn, self.constrain_2d).unwrap_or(heading);
+                }
+            }
+        }
+
+        pipeline.finish();
+    }
+
+    /// Query accelerator over `points` for this step. Built afresh every step unless
+    /// rebuilds are amortized, in which case the last one is kept, with the points which
+    /// left their cells in its overlay, while its replacement is binned a slice per step.
+    /// Positions as binned are then up to twice the amortization steps old, but the overlay
+    /// keeps every query exact. Falls back to a full build when the radius or the particle
+    /// count changed, or when too many particles moved for the overlay to pay off. Rebuilds
+    /// are never amortized with a toroidal metric.
+    fn next_accel(&mut self, points: &[Vec3], radius: f32) -> QueryAccelerator {
+        let steps = match self.amortized_rebuild {
+            Some(steps) if steps > 1 && self.metric == DistanceMetric::Euclidean => steps,
+            _ => {
+                self.rebuild = None;
+                return QueryAccelerator::with_metric(points, radius, self.metric);
+            }
+        };
+        let matches = |r: f32, n: usize| r == radius && n == points.len();
+        if !(self.rebuild.as_ref()).is_some_and(|r| matches(r.radius(), r.point_count())) {
+            self.rebuild = None;
+        }
+        if !matches(self.last_accel.radius(), self.last_accel.point_count()) {
+            return QueryAccelerator::new(points, radius);
+        }
+
+        let rebuild =
+            (self.rebuild).get_or_insert_with(|| RebuildInProgress::new(points, radius, steps));
+        let mut accel = match rebuild.step() {
+            true => self.rebuild.take().unwrap().finish(),
+            false => std::mem::replace(&mut self.last_accel, QueryAccelerator::new(&[], radius)),
+        };
+        let max_overlay = (points.len() as f32 * MAX_OVERLAY_FRACTION) as usize;
+        if accel.refresh_overlay(points, max_overlay) {
+            return accel;
+        }
+        self.rebuild = None;
+        QueryAccelerator::new(points, radius)
+    }
+
+    /// Spread each full rebuild of the query accelerator over `steps` steps, or rebuild it
+    /// every step with `None`. See [`SimState::rebuild_progress`].
+    pub fn set_amortized_rebuild(&mut self, steps: Option<usize>) {
+        self.amortized_rebuild = steps;
+        self.rebuild = None;
+    }
+
+    pub fn amortized_rebuild(&self) -> Option<usize> {
+        self.amortized_rebuild
+    }
+
+    /// Fraction of the particles binned by the accelerator rebuild in progress, if any
+    pub fn rebuild_progress(&self) -> Option<f32> {
+        self.rebuild.as_ref().map(|rebuild| rebuild.progress())
+    }
+
+    /// Resolve overlapping hard sphere pairs among the neighbors found at `points`, exchanging
+    /// the velocity of each approaching pair along the line between them as in an elastic
+    /// collision of equal masses, and pushing them apart until they just touch. Pinned
+    /// particles act as infinitely heavy. With `only`, pairs of other types are left alone.
+    fn collide(&mut self, accel: &QueryAccelerator, points: &[Vec3], only: Option<Color>) {
+        let mut contacts = vec![];
+        for i in 0..points.len() {
+            for j in accel.query_neighbors(points, i).filter(|&j| j > i) {
+                let (a, b) = (self.particles[i].color, self.particles[j].color);
+                if only.is_some_and(|color| a != color && b != color) {
+                    continue;
+                }
+                let there = self.pair_behaviour(i, b).1.hard_radius();
+                let back = self.pair_behaviour(j, a).1.hard_radius();
+                let radius = match (there, back) {
+                    (Some(a), Some(b)) => Some(a.max(b)),
+                    (a, b) => a.or(b),
+                };
+                if let Some(radius) = radius {
+                    contacts.push((i, j, radius));
+                }
+            }
+        }
+
+        for _ in 0..COLLISION_PASSES {
+            let mut touching = false;
+            for &(i, j, radius) in &contacts {
+                let (a, b) = (self.particles[i], self.particles[j]);
+                let diff = self.metric.separation(a.pos, b.pos);
+                let dist = diff.length();
+                if dist >= radius || dist == 0. {
+                    continue;
+                }
+                touching = true;
+                let normal = diff / dist;
+
+                // Share of the correction each takes, by inverse mass
+                let (share_a, share_b) = match (self.pinned[i], self.pinned[j]) {
+                    (false, false) => (0.5, 0.5),
+                    (true, false) => (0., 1.),
+                    (false, true) => (1., 0.),
+                    (true, true) => continue,
+                };
+                let approach = (b.vel - a.vel).dot(normal);
+                if approach < 0. {
+                    let impulse = normal * (2. * approach);
+                    self.particles[i].vel += impulse * share_a;
+                    self.particles[j].vel -= impulse * share_b;
+                }
+                let overlap = normal * (radius - dist);
+                self.particles[i].pos -= overlap * share_a;
+                self.particles[j].pos += overlap * share_b;
+            }
+            if !touching {
+                break;
+            }
+        }
+    }
+
+    /// Step the simulation, unless [`SimState::check_step`] finds that it would panic, e.g.
+    /// due to a corrupt configuration, in which case nothing moves and the problem is
+    /// returned. A panic from anything not checked restores the particles to where they were
+    /// and returns the panic message, but only where panics unwind, which is not the case in
+    /// wasm.
+    pub fn try_step(&mut self, dt: f32) -> Result<(), String> {
+        self.try_step_substeps(dt, &[])
+    }
+
+    /// [`SimState::step_substeps`], checked and recovering like [`SimState::try_step`]
+    pub fn try_step_substeps(&mut self, dt: f32, substeps: &[u32]) -> Result<(), String> {
+        self.check_step(dt)?;
+        let backup = self.particles.clone();
+        let result = catch_panic(|| self.step_substeps(dt, substeps));
+        if result.is_err() {
+            self.particles = backup;
+        }
+        result
+    }
+
+    /// Check what the step relies on without checking itself: a valid configuration,
+    /// particles of types within it, and a finite, non-negative time step. Panics cannot be
+    /// caught in wasm, so they are ruled out before stepping.
+    pub fn check_step(&self, dt: f32) -> Result<(), String> {
+        self.config.validate().map_err(|e| e.to_string())?;
+        let n_colors = self.config.colors.len();
+        if self.particles.iter().any(|p| p.color as usize >= n_colors) {
+            return Err("Particle of a type outside the configuration".into());
+        }
+        if !(dt.is_finite() && dt >= 0.) {
+            return Err(format!("Time step {} must be finite and not negative", dt));
+        }
+        Ok(())
+    }
+
+    /// Whether the step visits each pair of neighbors once, applying equal and opposite forces
+    /// to both, rather than gathering the forces on each particle separately. This halves the
+    /// work and conserves momentum, but is only possible when the behaviour matrix is
+    /// symmetric and neither blending, fast behaviours nor neighbor caps make forces differ
+    /// per particle.
+    pub fn pairwise_forces(&self) -> bool {
+        self.blend_behaviours.is_none()
+            && self.scaled.fast_behaviours().is_none()
+            && self.max_neighbors.is_none()
+            && self.scaled.is_symmetric()
+    }
+
+    /// Add the acceleration of every particle due to its neighbors at `points` to `forces`,
+    /// computing the force of each pair once, and return the number of pairs visited. See
+    /// [`SimState::pairwise_forces`].
+    fn pair_forces(&self, accel: &QueryAccelerator, points: &[Vec3], forces: &mut [Vec3]) -> usize {
+        // Monomorphized per metric, keeping the match out of the loop
+        match self.metric {
+            DistanceMetric::Euclidean => self.pair_forces_in(accel, points, forces, |a, b| b - a),
+            DistanceMetric::Toroidal { period } => {
+                self.pair_forces_in(accel, points, forces, |a, b| min_image(b - a, period))
+            }
+        }
+    }
+
+    /// [`SimState::pair_forces`], with `separation` giving the vector between two points
+    fn pair_forces_in(
+        &self,
+        accel: &QueryAccelerator,
+        points: &[Vec3],
+        forces: &mut [Vec3],
+        separation: impl Fn(Vec3, Vec3) -> Vec3,
+    ) -> usize {
+        let n_colors = self.scaled.colors.len();
+        let mut visited = 0;
+        for i in 0..points.len() {
+            let row = self.particles[i].color as usize * n_colors;
+            for j in accel.query_neighbors(points, i).filter(|&j| j > i) {
+                let pair = row + self.particles[j].color as usize;
+                let behav = &self.scaled.behaviours[pair];
+                let diff = separation(points[i], points[j]) * behav.anisotropy;
+                let force =
+                    self.behaviour_accel(pair, behav, diff) * self.polar_scale(behav, i, j, diff);
+                forces[i] += force;
+                forces[j] -= force;
+                visited += 1;
+            }
+        }
+        visited
+    }
+
+    /// Add the acceleration of every particle due to its neighbors at `points` by the SIMD
+    /// kernel to `forces`, and return the number of pairs visited
+    #[cfg(feature = "simd")]
+    fn simd_forces(&self, accel: &QueryAccelerator, points: &[Vec3], forces: &mut [Vec3]) -> usize {
+        crate::simd::gather_forces(
+            &self.scaled,
+            &self.cutoff_sq,
+            &self.particles,
+            accel,
+            points,
+            forces,
+        )
+    }
+
+    #[cfg(not(feature = "simd"))]
+    fn simd_forces(&self, _: &QueryAccelerator, _: &[Vec3], _: &mut [Vec3]) -> usize {
+        unreachable!("SIMD forces without the simd feature")
+    }
+
+    /// Acceleration of particle `i` due to its neighbors, and the number of neighbors visited
+    fn pair_accel(&mut self, accel: &QueryAccelerator, points: &[Vec3], i: usize) -> (Vec3, usize) {
+        // Never beyond the accelerator, which may stop at the near radius of the far field
+        let radius = self.type_radius[self.particles[i].color as usize].min(accel.radius());
+        let neighbors = accel.query_neighbors_radius(points, i, points[i], radius);
+
+        let mut total_accel = Vec3::ZERO;
+        let mut visited = 0;
+        if let Some(cap) = self.max_neighbors {
+            let mut found = std::mem::take(&mut self.neighbor_scratch);
+            found.clear();
+            found.extend(neighbors);
+            if found.len() > cap {
+                // `cap` neighbors at evenly strided ranks from a random start, each standing
+                // for `stride` of them, which keeps the expected force unbiased
+                let stride = found.len() as f32 / cap as f32;
+                let start = self.rng.gen_f32();
+                for k in 0..cap {
+                    let rank = (((k as f32 + start) * stride) as usize).min(found.len() - 1);
+                    total_accel += self.accel_from(i, found[rank]) * stride;
+                }
+                visited = cap;
+                self.stats.capped_particles += 1;
+            } else {
+                for &neighbor in &found {
+                    total_accel += self.accel_from(i, neighbor);
+                }
+                visited = found.len();
+            }
+            self.neighbor_scratch = found;
+        } else {
+            for neighbor in neighbors {
+                total_accel += self.accel_from(i, neighbor);
+                visited += 1;
+            }
+        }
+
+        if let Some(half_width) = self.ghost_walls {
+            total_accel += self.ghost_accel(accel, points, i, half_width);
+        }
+
+        (total_accel, visited)
+    }
+
+    /// Unbiased estimate of the acceleration of particle `i` due to the particles at `points`
+    /// beyond `near_radius`, from `k` others drawn at random
+    fn far_accel(&mut self, points: &[Vec3], i: usize, k: usize, near_radius: f32) -> Vec3 {
+        let n = points.len();
+        if n < 2 || k == 0 {
+            return Vec3::ZERO;
+        }
+
+        let near_sq = near_radius * near_radius;
+        let mut total_accel = Vec3::ZERO;
+        for _ in 0..k {
+            let mut j = self.rng.gen_u32() as usize % (n - 1);
+            if j >= i {
+                j += 1;
+            }
+            // Near neighbors were already counted exactly
+            let diff = self.metric.separation(points[i], points[j]);
+            if diff.length_squared() <= near_sq {
+                continue;
+            }
+            let (pair, behav) = self.pair_behaviour(i, self.particles[j].color);
+            let diff = diff * behav.anisotropy;
+            total_accel +=
+                self.behaviour_accel(pair, &behav, diff) * self.polar_scale(&behav, i, j, diff);
+        }
+        total_accel * ((n - 1) as f32 / k as f32)
+    }
+
+    /// Acceleration of particle `i` due to particle `j` alone, as in the explicit integrator
+    pub fn accel_between(&self, i: usize, j: usize) -> Vec3 {
+        self.accel_from(i, j)
+    }
+
+    /// Force on particle `i` due to its [`SimState::interaction_neighbors`], as the force
+    /// pass computes it with neither ghost walls nor neighbor caps
+    pub fn total_force(&self, i: usize) -> Vec3 {
+        self.interaction_neighbors(i)
+            .map(|j| self.accel_from(i, j))
+            .fold(Vec3::ZERO, |total, accel| total + accel)
+    }
+
+    /// The query accelerator as of the last rebuild, and the points it was built over
+    pub fn last_accel(&self) -> (&QueryAccelerator, &[Vec3]) {
+        (&self.last_accel, &self.last_points)
+    }
+
+    /// Acceleration of particle `i` due to particle `j`, scaled by their polarity
+    fn accel_from(&self, i: usize, j: usize) -> Vec3 {
+        let b = self.particles[j];
+        let (pair, behav) = self.pair_behaviour(i, b.color);
+        let diff = self.metric.separation(self.particles[i].pos, b.pos) * behav.anisotropy;
+        self.behaviour_accel(pair, &behav, diff) * self.polar_scale(&behav, i, j, diff)
+    }
+
+    /// Acceleration of particle `i` due to a particle of type `color` at `pos`, regardless of
+    /// headings
+    fn accel_towards(&self, i: usize, pos: Vec3, color: Color) -> Vec3 {
+        let (pair, behav) = self.pair_behaviour(i, color);
+
+        // The vector pointing from a to b, in the metric of this behaviour
+        let diff = (pos - self.particles[i].pos) * behav.anisotropy;
+        self.behaviour_accel(pair, &behav, diff)
+    }
+
+    /// Acceleration due to `behav` of the given pair towards `diff`, from the lookup tables
+    /// if in use
+    fn behaviour_accel(&self, pair: usize, behav: &Behaviour, diff: Vec3) -> Vec3 {
+        match &self.tables {
+            Some(tables) => tables.accel(pair, diff, self.cutoff_sq[pair]),
+            None => behav.accel(diff, self.cutoff_sq[pair]),
+        }
+    }
+
+    /// Potential of `behav` of the given pair at `dist`, from the lookup tables if in use
+    fn behaviour_potential(&self, pair: usize, behav: &Behaviour, dist: f32) -> f32 {
+        match &self.tables {
+            Some(tables) => tables.potential(pair, dist),
+            None => behav.potential(dist),
+        }
+    }
+
+    /// [`polar_factor`] of particles `i` and `j`, `diff` lying along the line between them.
+    /// One when headings are not in use.
+    fn polar_scale(&self, behav: &Behaviour, i: usize, j: usize, diff: Vec3) -> f32 {
+        match &self.orient {
+            Some(orient) if behav.polarity != 0. => polar_factor(
+                behav.polarity,
+                orient[i],
+                orient[j],
+                diff.normalize_or_zero(),
+            ),
+            _ => 1.,
+        }
+    }
+
+    /// Gradient of the polar energy of each particle with respect to its heading, due to its
+    /// neighbors at `points`. Empty when headings are not in use.
+    fn heading_gradients(&self, accel: &QueryAccelerator, points: &[Vec3]) -> Vec<Vec3> {
+        let Some(orient) = &self.orient else {
+            return vec![];
+        };
+        (0..points.len())
+            .map(|i| {
+                let mut gradient = Vec3::ZERO;
+                for j in accel.query_neighbors(points, i) {
+                    let (pair, behav) = self.pair_behaviour(i, self.particles[j].color);
+                    let diff = self.metric.separation(points[i], points[j]) * behav.anisotropy;
+                    let dist_sq = diff.length_squared();
+                    if behav.polarity == 0. || dist_sq > self.cutoff_sq[pair] || dist_sq == 0. {
+                        continue;
+                    }
+                    // Derivative of `potential * polar_factor` with respect to `orient[i]`
+                    let dist = dist_sq.sqrt();
+                    let dir = diff / dist;
+                    let potential = self.behaviour_potential(pair, &behav, dist);
+                    gradient += dir * (potential * behav.polarity * orient[j].dot(dir));
+                }
+                gradient
+            })
+            .collect()
+    }
+
+    /// Index into the behaviour matrix, and the behaviour of particle `i` towards type `color`
+    fn pair_behaviour(&self, i: usize, color: Color) -> (usize, Behaviour) {
+        let pair = self.particles[i].color as usize * self.scaled.colors.len() + color as usize;
+        let mut behav = match self.scaled.fast_behaviours() {
+            Some(fast) if self.fast[i] => fast[pair],
+            _ => self.scaled.behaviours[pair],
+        };
+        if let Some(blend) = &self.blend_behaviours {
+            let scale = self.config.interaction_scale;
+            behav = behav.lerp(&blend[pair].scaled(scale), self.blend[i]);
+        }
+        (pair, behav)
+    }
+
+    /// Potential energy of particle `i` if it were at `pos`, due to its neighbors as of the
+    /// last accelerator rebuild, its tether, gravity and walls. Like the forces, this is the
+    /// energy as felt by `i`; an asymmetric matrix has no energy of the system as a whole.
+    /// Ghost walls are not taken into account.
+    pub fn energy_due_to(&self, i: usize, pos: Vec3) -> f32 {
+        self.pair_energy(i, pos, self.heading(i)) + self.external_energy(i, pos)
+    }
+
+    /// Energy of particle `i` due to its neighbors if it were facing along `heading`, for
+    /// comparing headings like [`SimState::energy_due_to`] compares positions
+    pub fn heading_energy(&self, i: usize, heading: Vec3) -> f32 {
+        self.pair_energy(i, self.particles[i].pos, Some(heading))
+    }
+
+    /// Potential energy of the whole system, counting each pair once, as of the last
+    /// accelerator rebuild. Only meaningful for a symmetric matrix, like the Monte Carlo
+    /// integrators whose equilibrium it describes.
+    pub fn potential_energy(&self) -> f32 {
+        self.particles
+            .iter()
+            .enumerate()
+            .map(|(i, p)| {
+                self.pair_energy(i, p.pos, self.heading(i)) / 2. + self.external_energy(i, p.pos)
+            })
+            .sum()
+    }
+
+    /// Energy of particle `i` at `pos`, facing along `heading` if headings are in use, due to
+    /// its neighbors, see [`SimState::energy_due_to`]
+    fn pair_energy(&self, i: usize, pos: Vec3, heading: Option<Vec3>) -> f32 {
+        let radius = self.type_radius[self.particles[i].color as usize];
+        self.last_accel
+            .query_neighbors_radius(&self.last_points, i, pos, radius)
+            .map(|j| {
+                let b = self.particles[j];
+                let (pair, behav) = self.pair_behaviour(i, b.color);
+                let diff = self.metric.separation(pos, b.pos) * behav.anisotropy;
+                let dist_sq = diff.length_squared();
+                if dist_sq > self.cutoff_sq[pair] {
+                    return 0.;
+                }
+                let potential = self.behaviour_potential(pair, &behav, dist_sq.sqrt());
+                match (heading, &self.orient) {
+                    (Some(heading), Some(orient)) if behav.polarity != 0. => {
+                        let dir = diff.normalize_or_zero();
+                        potential * polar_factor(behav.polarity, heading, orient[j], dir)
+                    }
+                    _ => potential,
+                }
+            })
+            .sum()
+    }
+
+    /// Heading of particle `i`, if headings are in use
+    fn heading(&self, i: usize) -> Option<Vec3> {
+        self.orient.as_ref().map(|orient| orient[i])
+    }
+
+    /// Energy of particle `i` at `pos` due to its tether, gravity and walls
+    fn external_energy(&self, i: usize, pos: Vec3) -> f32 {
+        let mut energy = 0.;
+        if let Some(home) = &self.home {
+            energy += self.tether_stiffness * home[i].distance_squared(pos) / 2.;
+        }
+        if let Some(gravity) = &self.scaled.gravity {
+            energy -= gravity.accel(self.particles[i].color).dot(pos);
+        }
+        for wall in &self.walls {
+            energy += wall.potential(pos, self.particles[i].color);
+        }
+        energy
+    }
+
+    /// Move particle `i` to `pos`, keeping its velocity. The query accelerator is not
+    /// updated until the next step or [`SimState::rebuild_accel`].
+    pub fn move_particle(&mut self, i: usize, mut pos: Vec3) {
+        if self.constrain_2d {
+            pos.y = 0.;
+        }
+        self.journal.moved(self.particles[i].pos.distance(pos));
+        self.particles[i].pos = pos;
+        self.particles_dirty = true;
+    }
+
+    /// Acceleration of particle `i` due to mirror images of itself and its neighbors across
+    /// each nearby wall of the cube with the given half-width
+    fn ghost_accel(
+        &self,
+        accel: &QueryAccelerator,
+        points: &[Vec3],
+        i: usize,
+        half_width: f32,
+    ) -> Vec3 {
+        let pos = self.particles[i].pos;
+        let mut total_accel = Vec3::ZERO;
+        for axis in 0..3 {
+            if self.constrain_2d && axis == 1 {
+                continue;
+            }
+
+            for wall in [-half_width, half_width] {
+                if (wall - pos[axis]).abs() > self.max_interaction_radius {
+                    continue;
+                }
+
+                let sources = accel.query_neighbors(points, i).chain(std::iter::once(i));
+                for j in sources {
+                    let mut ghost = self.particles[j].pos;
+                    ghost[axis] = 2. * wall - ghost[axis];
+                    if ghost != pos {
+                        total_accel += self.accel_towards(i, ghost, self.particles[j].color);
+                    }
+                }
+            }
+        }
+        total_accel
+    }
+
+    /// Returns whether particle positions or types changed since the last call, and clears
+    /// the flag
+    pub fn take_particles_dirty(&mut self) -> bool {
+        std::mem::take(&mut self.particles_dirty)
+    }
+
+    /// Magnitude of what last acted on each particle: the net acceleration during the last
+    /// explicit step, or the energy change of the last accepted Monte Carlo move. Zero for
+    /// pinned particles and ones not yet acted on.
+    pub fn stress(&self) -> &[f32] {
+        &self.stress
+    }
+
+    /// Record the energy change of an accepted Monte Carlo move of particle `i`, and count
+    /// the move in the journal
+    pub(crate) fn record_move_energy(&mut self, i: usize, delta: f32) {
+        self.stress.resize(self.particles.len(), 0.);
+        // Leaving a hard sphere overlap releases an infinite energy
+        self.stress[i] = delta.abs().min(f32::MAX);
+        self.journal.accepted_move();
+    }
+
+    /// Particles added, removed and retyped since the start of the last step, including by
+    /// edits made since
+    pub fn journal(&self) -> &ChangeJournal {
+        &self.journal
+    }
+
+    /// Number of events the journal lists before keeping only counts
+    pub fn set_journal_cap(&mut self, cap: usize) {
+        self.journal.set_cap(cap);
+    }
+
+    /// Start a new journal, as each step does. For integrators stepping by other means.
+    pub fn clear_journal(&mut self) {
+        self.journal.clear();
+    }
+
+    /// Work done during the last step
+    pub fn stats(&self) -> StepStats {
+        self.stats
+    }
+
+    /// Particle closest to the ray from `origin` along `dir`, in front of the origin and at
+    /// most `max_dist` from it, as of the last step. Only the cells along the ray are searched.
+    pub fn pick(&self, origin: Vec3, dir: Vec3, max_dist: f32) -> Option<usize> {
+        let dir = dir.normalize_or_zero();
+        if dir == Vec3::ZERO || self.last_points.len() != self.particles.len() {
+            return None;
+        }
+
+        let points = &self.last_points;
+        self.last_accel
+            .query_near_ray(origin, dir, max_dist)
+            .filter_map(|i| {
+                let rel = points[i] - origin;
+                let along = rel.dot(dir);
+                let dist = (rel - dir * along).length();
+                (along >= 0. && dist <= max_dist).then_some((i, dist))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1).then(a.0.cmp(&b.0)))
+            .map(|(i, _)| i)
+    }
+
+    /// Mean number of neighbors within the interaction radius, as of the last step
+    pub fn mean_neighbors(&self) -> f32 {
+        let n = self.particles.len();
+        if n == 0 {
+            return 0.;
+        }
+        let counts = self.neighbor_counts(self.last_accel.radius());
+        let total: usize = counts.iter().map(|&count| count as usize).sum();
+        total as f32 / n as f32
+    }
+
+    /// Largest time step at which the explicit integrator stays stable, times `safety`. Each
+    /// particle is treated as held by springs of the stiffest constant to all of its mean
+    /// number of neighbors, giving a frequency `w` and the limit `2 / w` of symplectic Euler;
+    /// damping must also not overshoot. Crowded clumps denser than the mean can still exceed
+    /// it, so `safety` should be well below 1. Infinite when nothing limits the step.
+    pub fn stable_dt(&self, safety: f32) -> f32 {
+        let omega = (self.scaled.max_stiffness() * (1. + self.mean_neighbors())).sqrt();
+        let mut limit = 2. / omega;
+        if self.scaled.damping > 0. {
+            limit = limit.min(2. / self.scaled.damping);
+        }
+        limit * safety
+    }
+
+    /// Number of occupied cells in the query accelerator
+    pub fn accel_cells(&self) -> usize {
+        self.last_accel.cell_count()
+    }
+
+    /// Strategy currently used by the query accelerator
+    pub fn accel_mode(&self) -> AccelMode {
+        self.last_accel.mode()
+    }
+
+    pub fn particles(&self) -> &[Particle] {
+        &self.particles
+    }
+
+    /// Configuration as set, with the interaction scale unapplied
+    pub fn config(&self) -> &SimConfig {
+        &self.config
+    }
+
+    /// Particles per unit volume around the particles, or per unit area if confined to the
+    /// plane, measured from the mean neighbor count within the interaction radius
+    pub fn measured_density(&self) -> f32 {
+        let r = self.last_accel.radius();
+        let within = match self.constrain_2d {
+            true => PI * r * r,
+            false => 4. / 3. * PI * r * r * r,
+        };
+        match within > 0. {
+            true => self.mean_neighbors() / within,
+            false => 0.,
+        }
+    }
+
+    /// [`SimConfig::interaction_scale`] at which particles would have `target_neighbors`
+    /// neighbors on average at the measured density, or `None` if nothing is measured
+    pub fn interaction_scale_for_density(&self, target_neighbors: f32) -> Option<f32> {
+        (self.config).interaction_scale_for_density(
+            self.measured_density(),
+            target_neighbors,
+            self.constrain_2d,
+        )
+    }
+}
+
+impl SimConfig {
+    fn random_color(&self, rng: &mut Pcg) -> Color {
+        (rng.gen_u32() as usize % self.colors.len()) as u8
+    }
+
+    /// Re-roll one field across the whole matrix. When `symmetric`, each cell below the
+    /// diagonal is made to match its mirror image.
+    pub fn randomize_field(&mut self, field: Field, symmetric: bool, rng: &mut Pcg) {
+        for behav in &mut self.behaviours {
+            behav.randomize_field(field, rng);
+        }
+        if symmetric {
+            self.symmetrize();
+        }
+    }
+
+    /// Re-roll every field of a single cell, and its mirror image when `symmetric`
+    pub fn randomize_cell(&mut self, idx: usize, symmetric: bool, rng: &mut Pcg) {
+        // Max dist first, so that the threshold is drawn from a range below it
+        for field in [
+            Field::MaxDist,
+            Field::Threshold,
+            Field::Strength,
+            Field::Repulse,
+        ] {
+            self.behaviours[idx].randomize_field(field, rng);
+        }
+        if symmetric {
+            let n = self.colors.len();
+            self.behaviours[(idx % n) * n + idx / n] = self.behaviours[idx];
+        }
+    }
+
+    /// Scale every field of every cell by a random factor within `1 ± fraction`, keeping
+    /// thresholds below max distances. The fraction is clamped to `[0, 1]`, so that no field
+    /// changes sign.
+    pub fn jitter(&mut self, fraction: f32, symmetric: bool, rng: &mut Pcg) {
+        let fraction = fraction.clamp(0., 1.);
+        for behav in &mut self.behaviours {
+            for field in Field::ALL {
+                *field.get_mut(behav) *= 1. + (rng.gen_f32() * 2. - 1.) * fraction;
+            }
+            behav.inter_threshold = behav.inter_threshold.clamp(0., behav.inter_max_dist);
+        }
+        if symmetric {
+            self.symmetrize();
+        }
+    }
+
+    /// Copy each cell above the diagonal onto its mirror image
+    pub fn symmetrize(&mut self) {
+        let n = self.colors.len();
+        for row in 0..n {
+            for col in 0..row {
+                self.behaviours[row * n + col] = self.behaviours[col * n + row];
+            }
+        }
+    }
+
+    /// Per-field linear interpolation towards `other`, which must have as many types. Colors,
+    /// density rules and mobility are those of `self`; a missing gravity counts as weightless.
+    pub fn lerp(&self, other: &SimConfig, t: f32) -> SimConfig {
+        assert_eq!(self.colors.len(), other.colors.len(), "Number of types");
+        let gravity = match (&self.gravity, &other.gravity) {
+            (None, None) => None,
+            (a, b) => {
+                let weightless = |g: &Gravity| Gravity {
+                    down: g.down,
+                    weights: vec![],
+                };
+                let a = a.clone().unwrap_or_else(|| weightless(b.as_ref().unwrap()));
+                let b = b.clone().unwrap_or_else(|| weightless(&a));
+                let n = a.weights.len().max(b.weights.len());
+                let weight = |g: &Gravity, i: usize| g.weights.get(i).copied().unwrap_or(0.);
+                Some(Gravity {
+                    down: a.down.lerp(b.down, t),
+                    weights: (0..n)
+                        .map(|i| weight(&a, i) + (weight(&b, i) - weight(&a, i)) * t)
+                        .collect(),
+                })
+            }
+        };
+        SimConfig {
+            colors: self.colors.clone(),
+            behaviours: (self.behaviours.iter().zip(&other.behaviours))
+                .map(|(a, b)| a.lerp(b, t))
+                .collect(),
+            interaction_scale: self.interaction_scale
+                + (other.interaction_scale - self.interaction_scale) * t,
+            damping: self.damping + (other.damping - self.damping) * t,
+            gravity,
+            density_rules: self.density_rules.clone(),
+            mobility: self.mobility.clone(),
+            activity: self.activity.clone(),
+        }
+    }
+
+    /// Whether the potential energy of [`SimConfig::lerp`] towards `other` is linear in the
+    /// interpolation parameter, which holds when they differ only in the strengths of
+    /// interactions, repulsion and gravity
+    pub fn lerp_is_linear(&self, other: &SimConfig) -> bool {
+        let shape = |b: &Behaviour| {
+            (
+                b.inter_threshold,
+                b.inter_max_dist,
+                b.anisotropy,
+                b.polarity,
+            )
+        };
+        let down = |c: &SimConfig| c.gravity.as_ref().map(|g| g.down.normalize_or_zero());
+        let same_down = match (down(self), down(other)) {
+            (Some(a), Some(b)) => a == b,
+            _ => true,
+        };
+        self.behaviours.len() == other.behaviours.len()
+            && (self.behaviours.iter().zip(&other.behaviours)).all(|(a, b)| shape(a) == shape(b))
+            && same_down
+    }
+
+    /// Fold type `b` into type `a`: every interaction of the survivor is the average of the
+    /// two types' rows and columns, and `b` is removed. Returns the new index of each old type,
+    /// for [`SimState::remap_types`].
+    pub fn merge_types(&mut self, a: Color, b: Color) -> Vec<Color> {
+        let n = self.colors.len();
+        let (a, b) = (a as usize, b as usize);
+        assert!(
+            a < n && b < n && a != b,
+            "Cannot merge type {} into {}",
+            b,
+            a
+        );
+
+        self.behaviours = merge_matrix(&self.behaviours, n, a, b);
+
+        self.colors.remove(b);
+        if let Some(gravity) = &mut self.gravity {
+            gravity.weights.resize(n, 0.);
+            gravity.weights[a] = (gravity.weights[a] + gravity.weights[b]) / 2.;
+            gravity.weights.remove(b);
+        }
+        if let Some(mobility) = &mut self.mobility {
+            for per_type in [&mut mobility.max_speed, &mut mobility.fast_threshold] {
+                per_type.resize(n, f32::MAX);
+                per_type[a] = per_type[a] / 2. + per_type[b] / 2.;
+                per_type.remove(b);
+            }
+            if let Some(fast) = &mut mobility.fast_behaviours {
+                *fast = merge_matrix(fast, n, a, b);
+            }
+        }
+        if let Some(activity) = &mut self.activity {
+            for per_type in [&mut activity.speed, &mut activity.rotational_diffusion] {
+                per_type.resize(n, 0.);
+                per_type[a] = (per_type[a] + per_type[b]) / 2.;
+                per_type.remove(b);
+            }
+        }
+
+        let mapping: Vec<Color> = (0..n)
+            .map(|i| if i == b { a } else { i })
+            .map(|i| if i > b { i - 1 } else { i } as Color)
+            .collect();
+        let remap = |t: Color| mapping.get(t as usize).copied().unwrap_or(t);
+        for rule in &mut self.density_rules {
+            rule.ty = remap(rule.ty);
+            rule.crowded_becomes = remap(rule.crowded_becomes);
+            rule.lonely_becomes = remap(rule.lonely_becomes);
+        }
+        mapping
+    }
+
+    /// Add a copy of type `a`, interacting with every type and following density rules
+    /// exactly as `a` does, in a darker shade of its color. Returns the index of the new type, which comes last.
+    pub fn split_type(&mut self, a: Color) -> Color {
+        let n = self.colors.len();
+        let a = a as usize;
+        assert!(a < n, "Cannot split type {} of {}", a, n);
+        assert!(n <= Color::MAX as usize, "Too many types to split");
+
+        self.behaviours = split_matrix(&self.behaviours, n, a);
+
+        self.colors.push(self.colors[a].map(|c| c * SPLIT_SHADE));
+        if let Some(gravity) = &mut self.gravity {
+            gravity.weights.resize(n, 0.);
+            gravity.weights.push(gravity.weights[a]);
+        }
+        if let Some(mobility) = &mut self.mobility {
+            for per_type in [&mut mobility.max_speed, &mut mobility.fast_threshold] {
+                per_type.resize(n, f32::MAX);
+                per_type.push(per_type[a]);
+            }
+            if let Some(fast) = &mut mobility.fast_behaviours {
+                *fast = split_matrix(fast, n, a);
+            }
+        }
+        if let Some(activity) = &mut self.activity {
+            for per_type in [&mut activity.speed, &mut activity.rotational_diffusion] {
+                per_type.resize(n, 0.);
+                per_type.push(per_type[a]);
+            }
+        }
+        let copies: Vec<DensityRule> = (self.density_rules.iter())
+            .filter(|rule| rule.ty as usize == a)
+            .map(|&rule| DensityRule {
+                ty: n as Color,
+                ..rule
+            })
+            .collect();
+        self.density_rules.extend(copies);
+        n as Color
+    }
+
+    /// Check that the configuration describes a usable simulation, e.g. one received from
+    /// outside the plugin
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        let n = self.colors.len();
+        if n == 0 || n > Color::MAX as usize + 1 {
+            return Err(ConfigError::Invalid("Number of types out of range"));
+        }
+        if self.behaviours.len() != n * n {
+            return Err(ConfigError::Invalid(
+                "Behaviour matrix does not match the colors",
+            ));
+        }
+        if self
+            .fast_behaviours()
+            .is_some_and(|fast| fast.len() != n * n)
+        {
+            return Err(ConfigError::Invalid(
+                "Fast behaviour matrix does not match the colors",
+            ));
+        }
+        for behav in self.all_behaviours() {
+            let values = [
+                behav.default_repulse,
+                behav.inter_threshold,
+                behav.inter_strength,
+                behav.inter_max_dist,
+                behav.polarity,
+            ];
+            if !values.iter().all(|v| v.is_finite()) {
+                return Err(ConfigError::Invalid(
+                    "Behaviour coefficients must be finite",
+                ));
+            }
+            if behav.inter_threshold < 0. || behav.inter_max_dist < 0. {
+                return Err(ConfigError::Invalid("Distances must not be negative"));
+            }
+            if !(behav.anisotropy.is_finite() && behav.anisotropy.min_element() > 0.) {
+                return Err(ConfigError::Invalid("Anisotropy must be positive"));
+            }
+            if !(0. ..=1.).contains(&behav.polarity) {
+                return Err(ConfigError::Invalid("Polarity must be between 0 and 1"));
+            }
+            if let Some(radius) = behav.hard_radius() {
+                if !(radius.is_finite() && radius >= 0.) {
+                    return Err(ConfigError::Invalid(
+                        "Hard sphere radius must not be negative",
+                    ));
+                }
+                // Collisions are round and head-blind
+                if behav.anisotropy != Vec3::ONE || behav.polarity != 0. {
+                    return Err(ConfigError::Invalid(
+                        "Hard spheres must be isotropic and apolar",
+                    ));
+                }
+            }
+        }
+        if !(MIN_INTERACTION_SCALE..=MAX_INTERACTION_SCALE).contains(&self.interaction_scale) {
+            return Err(ConfigError::Invalid("Interaction scale out of range"));
+        }
+        if !self.damping.is_finite() {
+            return Err(ConfigError::Invalid("Damping must be finite"));
+        }
+        if let Some(gravity) = &self.gravity {
+            if !(gravity.down.is_finite() && gravity.weights.iter().all(|w| w.is_finite())) {
+                return Err(ConfigError::Invalid("Gravity must be finite"));
+            }
+        }
+        if let Some(mobility) = &self.mobility {
+            let speeds = mobility.max_speed.iter().chain(&mobility.fast_threshold);
+            if !speeds.into_iter().all(|&v| v >= 0. && !v.is_nan()) {
+                return Err(ConfigError::Invalid("Speeds must not be negative"));
+            }
+        }
+        if let Some(activity) = &self.activity {
+            let rates = activity.speed.iter().chain(&activity.rotational_diffusion);
+            if !rates
+                .chain([&activity.alignment])
+                .all(|&v| v >= 0. && v.is_finite())
+            {
+                return Err(ConfigError::Invalid(
+                    "Propulsion and noise must be finite and not negative",
+                ));
+            }
+        }
+        for rule in &self.density_rules {
+            let types = [rule.ty, rule.crowded_becomes, rule.lonely_becomes];
+            if types.iter().any(|&t| t as usize >= n) {
+                return Err(ConfigError::Invalid("Density rule type out of range"));
+            }
+            if !(rule.check_radius.is_finite() && rule.probability.is_finite()) {
+                return Err(ConfigError::Invalid("Density rules must be finite"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether each type behaves towards every other type as that type behaves towards it
+    pub fn is_symmetric(&self) -> bool {
+        let n = self.colors.len();
+        (0..n).all(|row| {
+            (0..row).all(|col| self.behaviours[row * n + col] == self.behaviours[col * n + row])
+        })
+    }
+
+    /// Behaviours of fast particles, if any type switches to them
+    pub fn fast_behaviours(&self) -> Option<&[Behaviour]> {
+        self.mobility.as_ref()?.fast_behaviours.as_deref()
+    }
+
+    /// Behaviours of both slow and fast particles
+    fn all_behaviours(&self) -> impl Iterator<Item = &Behaviour> {
+        self.behaviours
+            .iter()
+            .chain(self.fast_behaviours().into_iter().flatten())
+    }
+
+    /// Whether any pair collides as hard spheres
+    pub fn has_hard_spheres(&self) -> bool {
+        self.all_behaviours().any(|b| b.hard_radius().is_some())
+    }
+
+    /// Whether any behaviour depends on the headings of the particles
+    pub fn is_polar(&self) -> bool {
+        self.all_behaviours().any(|b| b.polarity != 0.)
+    }
+
+    /// Whether any type propels itself, see [`Activity`]
+    pub fn is_active(&self) -> bool {
+        self.activity.as_ref().is_some_and(Activity::is_active)
+    }
+
+    /// Stiffest effective spring constant of any pair, see [`Behaviour::effective_stiffness`]
+    pub fn max_stiffness(&self) -> f32 {
+        self.all_behaviours()
+            .map(Behaviour::effective_stiffness)
+            .fold(0., f32::max)
+    }
+
+    /// Largest distance at which any pair of particles interacts, along any axis, counting
+    /// hard spheres out to [`HARD_SPHERE_REACH`] times their radius
+    pub fn max_interaction_radius(&self) -> f32 {
+        self.all_behaviours()
+            .map(Behaviour::reach)
+            .fold(0., |r, acc| acc.max(r))
+            * self.interaction_scale
+    }
+
+    /// Largest distance at which particles of type `ty` feel any other particle, the furthest
+    /// reach over its row of the matrix, fast behaviours included. Types whose row is
+    /// missing from a malformed matrix get the reach of the whole matrix.
+    pub fn max_radius_for_type(&self, ty: usize) -> f32 {
+        let n = self.colors.len();
+        let row = ty * n..(ty + 1) * n;
+        let Some(slow) = self.behaviours.get(row.clone()) else {
+            return self.max_interaction_radius();
+        };
+        let fast = self.fast_behaviours().and_then(|fast| fast.get(row));
+        (slow.iter())
+            .chain(fast.into_iter().flatten())
+            .map(Behaviour::reach)
+            .fold(0., f32::max)
+            * self.interaction_scale
+    }
+
+    /// Half-width of the spawn cube (or square, if `planar`) at which `n` uniformly scattered
+    /// particles have on average `target_neighbors` neighbors within the max interaction radius.
+    /// Boundary effects make the actual count somewhat lower.
+    pub fn suggested_spawn_radius(&self, n: usize, target_neighbors: f32, planar: bool) -> f32 {
+        let r = self.max_interaction_radius();
+        let target = target_neighbors.max(f32::EPSILON);
+        if planar {
+            // Neighbors = n / (2R)^2 * pi r^2
+            (n as f32 * PI * r * r / target).sqrt() / 2.
+        } else {
+            // Neighbors = n / (2R)^3 * 4/3 pi r^3
+            (n as f32 * 4. / 3. * PI * r * r * r / target).cbrt() / 2.
+        }
+    }
+
+    /// [`SimConfig::interaction_scale`] at which particles at `density`, per unit volume or
+    /// per unit area if `planar`, have on average `target_neighbors` neighbors within the max
+    /// interaction radius: [`SimConfig::suggested_spawn_radius`] in reverse. Clamped to
+    /// [`MIN_INTERACTION_SCALE`] and [`MAX_INTERACTION_SCALE`], and `None` unless the density
+    /// is positive and anything interacts.
+    pub fn interaction_scale_for_density(
+        &self,
+        density: f32,
+        target_neighbors: f32,
+        planar: bool,
+    ) -> Option<f32> {
+        let unit_radius = self.max_interaction_radius() / self.interaction_scale;
+        if !(density > 0. && unit_radius > 0.) {
+            return None;
+        }
+        let target = target_neighbors.max(f32::EPSILON);
+        let r = match planar {
+            // Neighbors = density * pi r^2
+            true => (target / (density * PI)).sqrt(),
+            // Neighbors = density * 4/3 pi r^3
+            false => (target / (density * 4. / 3. * PI)).cbrt(),
+        };
+        let scale = r / unit_radius;
+        scale
+            .is_finite()
+            .then(|| scale.clamp(MIN_INTERACTION_SCALE, MAX_INTERACTION_SCALE))
+    }
+
+    /// This configuration as evaluated: every interaction distance multiplied by the
+    /// interaction scale, which is then one. The distances are the thresholds, cutoffs and
+    /// hard sphere radii of the slow and fast behaviours, and the radii of the density rules.
+    pub fn scaled(&self) -> SimConfig {
+        let scale = self.interaction_scale;
+        let mut scaled = SimConfig {
+            interaction_scale: 1.,
+            ..self.clone()
+        };
+        if scale == 1. {
+            return scaled;
+        }
+        for behav in &mut scaled.behaviours {
+            *behav = behav.scaled(scale);
+        }
+        if let Some(fast) = (scaled.mobility.as_mut()).and_then(|m| m.fast_behaviours.as_mut()) {
+            for behav in fast {
+                *behav = behav.scaled(scale);
+            }
+        }
+        for rule in &mut scaled.density_rules {
+            rule.check_radius *= scale;
+        }
+        scaled
+    }
+
+    /// [`SimConfig::max_radius_for_type`] of every type
+    pub fn type_radius_table(&self) -> Vec<f32> {
+        (0..self.colors.len())
+            .map(|ty| self.max_radius_for_type(ty))
+            .collect()
+    }
+
+    /// Squared `inter_max_dist` of each behaviour, indexed like `behaviours`. Pairs further
+    /// apart than this (in the metric of the behaviour) exert no force on each other. The
+    /// boundary is inclusive, like the query accelerator's radius, and the force there is zero.
+    /// With fast behaviours, this is the further of the slow and fast cutoffs.
+    pub fn cutoff_sq_table(&self) -> Vec<f32> {
+        let cutoff_sq = |b: &Behaviour| (b.inter_max_dist * self.interaction_scale).powi(2);
+        let mut table: Vec<f32> = self.behaviours.iter().map(cutoff_sq).collect();
+        for (cutoff, fast) in table
+            .iter_mut()
+            .zip(self.fast_behaviours().into_iter().flatten())
+        {
+            *cutoff = cutoff.max(cutoff_sq(fast));
+        }
+        table
+    }
+
+    /// How much more each type is drawn towards each other type than the other way around,
+    /// indexed like `behaviours`. Positive at `(a, b)` means `a` chases `b`. The matrix is
+    /// antisymmetric. Anisotropy is not taken into account.
+    pub fn asymmetry_matrix(&self) -> Vec<f32> {
+        let n = self.colors.len();
+        (0..n * n)
+            .map(|i| {
+                let (a, b) = (i / n, i % n);
+                self.behaviours[a * n + b].drive() - self.behaviours[b * n + a].drive()
+            })
+            .collect()
+    }
+
+    pub fn get_bahaviour(&self, a: Color, b: Color) -> Behaviour {
+        let idx = a as usize * self.colors.len() + b as usize;
+        self.behaviours[idx].scaled(self.interaction_scale)
+    }
+
+    /// Write the given field of the behaviour matrix as CSV. Rows are the acting type, columns
+    /// the type acted upon.
+    pub fn field_to_csv(&self, field: Field) -> String {
+        let n = self.colors.len();
+        let mut csv = String::new();
+        for row in self.behaviours.chunks(n) {
+            let line: Vec<String> = row.iter().map(|b| field.get(b).to_string()).collect();
+            csv += &line.join(",");
+            csv.push('\n');
+        }
+        csv
+    }
+
+    /// Set the given field of the behaviour matrix from CSV (or TSV) text. Nothing is modified
+    /// unless the whole matrix parses and matches the number of types.
+    pub fn apply_csv_to_field(&mut self, field: Field, csv: &str) -> Result<(), ConfigError> {
+        let mut rows = vec![];
+        for (row, line) in csv.lines().filter(|l| !l.trim().is_empty()).enumerate() {
+            let cells = line
+                .split([',', '\t'])
+                .enumerate()
+                .map(|(col, text)| {
+                    text.trim().parse::<f32>().map_err(|_| ConfigError::Parse {
+                        row,
+                        col,
+                        text: text.trim().to_string(),
+                    })
+                })
+                .collect::<Result<Vec<f32>, _>>()?;
+            rows.push(cells);
+        }
+
+        let n = self.colors.len();
+        let cols = rows.iter().map(|r| r.len()).find(|&len| len != n);
+        if rows.len() != n || cols.is_some() {
+            return Err(ConfigError::Dimensions {
+                expected: n,
+                rows: rows.len(),
+                cols: cols.unwrap_or(n),
+            });
+        }
+
+        for (behav, value) in self.behaviours.iter_mut().zip(rows.into_iter().flatten()) {
+            *field.get_mut(behav) = value;
+        }
+
+        Ok(())
+    }
+}
+
+impl Qualitative {
+    /// In cycling order
+    pub const ALL: [Qualitative; 5] = [
+        Qualitative::StrongAttract,
+        Qualitative::WeakAttract,
+        Qualitative::Neutral,
+        Qualitative::WeakRepel,
+        Qualitative::StrongRepel,
+    ];
+
+    /// Interaction strength written when this class is chosen
+    pub fn strength(&self) -> f32 {
+        match self {
+            Qualitative::StrongAttract => 10.,
+            Qualitative::WeakAttract => 3.,
+            Qualitative::Neutral => 0.,
+            Qualitative::WeakRepel => -3.,
+            Qualitative::StrongRepel => -10.,
+        }
+    }
+
+    /// Class of an arbitrary strength. Boundaries lie halfway between the canned strengths,
+    /// at magnitudes 1.5 and 6.5, and belong to the stronger class.
+    pub fn classify(strength: f32) -> Self {
+        if strength >= 6.5 {
+            Qualitative::StrongAttract
+        } else if strength >= 1.5 {
+            Qualitative::WeakAttract
+        } else if strength <= -6.5 {
+            Qualitative::StrongRepel
+        } else if strength <= -1.5 {
+            Qualitative::WeakRepel
+        } else {
+            Qualitative::Neutral
+        }
+    }
+
+    pub fn glyph(&self) -> &'static str {
+        match self {
+            Qualitative::StrongAttract => "++",
+            Qualitative::WeakAttract => "+",
+            Qualitative::Neutral => "0",
+            Qualitative::WeakRepel => "-",
+            Qualitative::StrongRepel => "--",
+        }
+    }
+
+    /// The next class in cycling order, wrapping around; backwards if `forward` is false
+    pub fn cycle(&self, forward: bool) -> Self {
+        let idx = Self::ALL.iter().position(|q| q == self).unwrap();
+        let len = Self::ALL.len();
+        let idx = if forward { idx + 1 } else { idx + len - 1 };
+        Self::ALL[idx % len]
+    }
+}
+
+impl Field {
+    pub const ALL: [Field; 4] = [
+        Field::Strength,
+        Field::Threshold,
+        Field::MaxDist,
+        Field::Repulse,
+    ];
+
+    pub fn get(&self, behav: &Behaviour) -> f32 {
+        match self {
+            Field::Strength => behav.inter_strength,
+            Field::Threshold => behav.inter_threshold,
+            Field::MaxDist => behav.inter_max_dist,
+            Field::Repulse => behav.default_repulse,
+        }
+    }
+
+    pub fn get_mut<'b>(&self, behav: &'b mut Behaviour) -> &'b mut f32 {
+        match self {
+            Field::Strength => &mut behav.inter_strength,
+            Field::Threshold => &mut behav.inter_threshold,
+            Field::MaxDist => &mut behav.inter_max_dist,
+            Field::Repulse => &mut behav.default_repulse,
+        }
+    }
+
+    /// Range of sensible random values for this field, given the rest of the behaviour
+    pub fn random_range(&self, behav: &Behaviour) -> (f32, f32) {
+        match self {
+            Field::Strength => (-15., 15.),
+            Field::Threshold => (0.01, behav.inter_max_dist.clamp(0.01, 0.3)),
+            Field::MaxDist => (behav.inter_threshold.clamp(0., 0.9), 0.9),
+            Field::Repulse => (1., 30.),
+        }
+    }
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Parse { row, col, text } => {
+                write!(
+                    f,
+                    "Could not parse {:?} at row {}, column {}",
+                    text,
+                    row + 1,
+                    col + 1
+                )
+            }
+            ConfigError::Dimensions {
+                expected,
+                rows,
+                cols,
+            } => write!(
+                f,
+                "Expected a {}x{} matrix, got {} rows and a row of {} columns",
+                expected, expected, rows, cols
+            ),
+            ConfigError::Syntax(msg) => write!(f, "Could not parse configuration: {}", msg),
+            ConfigError::Invalid(msg) => write!(f, "Invalid configuration: {}", msg),
+        }
+    }
+}
+
+/// Forces on the particles due to their neighbors, by the SIMD kernel, pairwise, or gathered
+/// per particle as the configuration allows, and to the mirror images of ghost walls
+struct PairForces;
+
+/// Interactions beyond the near radius, sampled, see [`FarFieldSampling`]
+struct FarField;
+
+/// Springs pulling particles towards their homes
+struct Tethers;
+
+/// See [`Gravity`]
+struct GravityForce;
+
+/// Attraction and repulsion of the [`Wall`]s, by type
+struct WallForces;
+
+/// Push of active particles along their headings, see [`Activity`]
+struct Propulsion;
+
+/// Contributors of the built-in forces, in the order they accumulate
+fn builtin_forces() -> Vec<Box<dyn ForceContributor>> {
+    vec![
+        Box::new(PairForces),
+        Box::new(FarField),
+        Box::new(Tethers),
+        Box::new(GravityForce),
+        Box::new(WallForces),
+        Box::new(Propulsion),
+    ]
+}
+
+impl ForceContributor for PairForces {
+    fn name(&self) -> &str {
+        "Pair forces"
+    }
+
+    /// Pairwise and SIMD forces, all at once
+    fn accumulate(&mut self, sim: &mut SimState, step: &StepView, forces: &mut ForceBuffer) {
+        let (accel, points) = (step.accel, step.points);
+        sim.stats.neighbor_pairs += if sim.uses_simd() {
+            sim.simd_forces(accel, points, forces.as_mut_slice())
+        } else if sim.pairwise_forces() {
+            sim.pair_forces(accel, points, forces.as_mut_slice())
+        } else {
+            0
+        };
+    }
+
+    /// Ghost walls, or the forces gathered per particle otherwise
+    fn accumulate_particle(
+        &mut self,
+        sim: &mut SimState,
+        step: &StepView,
+        i: usize,
+        forces: &mut ForceBuffer,
+    ) {
+        let (accel, points) = (step.accel, step.points);
+        if sim.uses_simd() || sim.pairwise_forces() {
+            if let Some(half_width) = sim.ghost_walls {
+                forces.add(i, sim.ghost_accel(accel, points, i, half_width));
+            }
+            return;
+        }
+        let (total_accel, visited) = sim.pair_accel(accel, points, i);
+        forces.add(i, total_accel);
+        sim.stats.neighbor_pairs += visited;
+    }
+}
+
+impl ForceContributor for FarField {
+    fn name(&self) -> &str {
+        "Far field"
+    }
+
+    fn accumulate_particle(
+        &mut self,
+        sim: &mut SimState,
+        step: &StepView,
+        i: usize,
+        forces: &mut ForceBuffer,
+    ) {
+        if let Some(far_field) = sim.far_field {
+            let near_radius = sim.near_radius();
+            forces.add(i, sim.far_accel(step.points, i, far_field.k, near_radius));
+        }
+    }
+}
+
+impl ForceContributor for Tethers {
+    fn name(&self) -> &str {
+        "Tethers"
+    }
+
+    fn accumulate_particle(
+        &mut self,
+        sim: &mut SimState,
+        _: &StepView,
+        i: usize,
+        forces: &mut ForceBuffer,
+    ) {
+        if let Some(home) = &sim.home {
+            forces.add(i, (home[i] - sim.particles[i].pos) * sim.tether_stiffness);
+        }
+    }
+}
+
+impl ForceContributor for GravityForce {
+    fn name(&self) -> &str {
+        "Gravity"
+    }
+
+    fn accumulate_particle(
+        &mut self,
+        sim: &mut SimState,
+        _: &StepView,
+        i: usize,
+        forces: &mut ForceBuffer,
+    ) {
+        if let Some(gravity) = &sim.scaled.gravity {
+            forces.add(i, gravity.accel(sim.particles[i].color));
+        }
+    }
+}
+
+impl ForceContributor for WallForces {
+    fn name(&self) -> &str {
+        "Walls"
+    }
+
+    fn accumulate_particle(
+        &mut self,
+        sim: &mut SimState,
+        _: &StepView,
+        i: usize,
+        forces: &mut ForceBuffer,
+    ) {
+        let particle = &sim.particles[i];
+        for wall in &sim.walls {
+            forces.add(i, wall.accel(particle.pos, particle.color));
+        }
+    }
+}
+
+impl ForceContributor for Propulsion {
+    fn name(&self) -> &str {
+        "Propulsion"
+    }
+
+    fn accumulate_particle(
+        &mut self,
+        sim: &mut SimState,
+        _: &StepView,
+        i: usize,
+        forces: &mut ForceBuffer,
+    ) {
+        if let (Some(activity), Some(orient)) = (&sim.scaled.activity, &sim.orient) {
+            let speed = activity.speed(sim.particles[i].color);
+            if speed != 0. {
+                forces.add(i, orient[i] * (speed * sim.scaled.damping));
+            }
+        }
+    }
+}
+
+/// Run `f`, returning the panic message if it panics
+pub fn catch_panic<R>(f: impl FnOnce() -> R) -> Result<R, String> {
+    std::panic::catch_unwind(std::panic::AssertUnwindSafe(f)).map_err(|payload| {
+        if let Some(msg) = payload.downcast_ref::<&str>() {
+            msg.to_string()
+        } else if let Some(msg) = payload.downcast_ref::<String>() {
+            msg.clone()
+        } else {
+            "Unknown panic".to_string()
+        }
+    })
+}
+
+/// Most draws a forked stream is advanced by, see [`fork_rng`]
+const FORK_SKIP: u32 = 1 << 16;
+
+/// A random stream of its own, derived from `rng`. [`Pcg`] cannot be seeded, so as with the
+/// seed of a scenario, this is a fresh stream advanced by a number of draws taken from `rng`.
+pub fn fork_rng(rng: &mut Pcg) -> Pcg {
+    let mut forked = Pcg::new();
+    for _ in 0..rng.gen_u32() % FORK_SKIP {
+        forked.gen_u32();
+    }
+    forked
+}
+
+fn random_particle(rng: &mut Pcg, config: &SimConfig) -> Particle {
+    Particle {
+        pos: random_position(rng, 1.0),
+        vel: Vec3::ZERO,
+        color: config.random_color(rng),
+    }
+}
+
+/// At least `n` sites of a cubic lattice centered on the origin
+fn cubic_lattice(n: usize, spacing: f32) -> Vec<Vec3> {
+    let side = (n as f32).cbrt().ceil() as i32 + 1;
+    let offset = (side - 1) as f32 / 2.;
+    let mut sites = vec![];
+    for x in 0..side {
+        for y in 0..side {
+            for z in 0..side {
+                let idx = Vec3::new(x as f32, y as f32, z as f32) - Vec3::splat(offset);
+                sites.push(idx * spacing);
+            }
+        }
+    }
+    sites
+}
+
+/// At least `n` sites of a hexagonal lattice on the XZ plane, centered on the origin
+fn hexagonal_lattice(n: usize, spacing: f32) -> Vec<Vec3> {
+    let row_spacing = spacing * 3_f32.sqrt() / 2.;
+    let side = (n as f32).sqrt().ceil() as i32 + 2;
+    let offset = (side - 1) as f32 / 2.;
+    let mut sites = vec![];
+    for row in 0..side {
+        let stagger = if row % 2 == 1 { 0.5 } else { 0. };
+        for col in 0..side {
+            let x = (col as f32 - offset + stagger) * spacing;
+            let z = (row as f32 - offset) * row_spacing;
+            sites.push(Vec3::new(x, 0., z));
+        }
+    }
+    sites
+}
+
+/// Minimum corner and extent of the bounds of `all`, for [`morton_key`]
+fn morton_bounds(all: &[Vec3]) -> (Vec3, Vec3) {
+    let min = all
+        .iter()
+        .copied()
+        .fold(Vec3::splat(f32::INFINITY), Vec3::min);
+    let max = all
+        .iter()
+        .copied()
+        .fold(Vec3::splat(f32::NEG_INFINITY), Vec3::max);
+    (min, (max - min).max(Vec3::splat(f32::EPSILON)))
+}
+
+/// Z-order curve key of a point, relative to bounds from [`morton_bounds`]
+fn morton_key(p: Vec3, (min, extent): (Vec3, Vec3)) -> u64 {
+    let q = ((p - min) / extent * 1023.).to_array().map(|v| v as u64);
+
+    let mut key = 0;
+    for bit in 0..10 {
+        for (axis, v) in q.iter().enumerate() {
+            key |= ((v >> bit) & 1) << (bit * 3 + axis);
+        }
+    }
+    key
+}
+
+/// Scale of the force between two particles facing along `a` and `b`, `dir` being the unit
+/// vector along the line between them. The product of their headings' components along the
+/// line is 1 when they line up head to tail, 0 when either is side on and -1 head to head,
+/// and the force is scaled by it in proportion to `polarity`. Both headings enter alike, so
+/// the forces of a pair stay equal and opposite.
+pub fn polar_factor(polarity: f32, a: Vec3, b: Vec3, dir: Vec3) -> f32 {
+    1. + polarity * (a.dot(dir) * b.dot(dir) - 1.)
+}
+
+/// Uniformly random unit vector, within the XZ plane if `planar`
+/// Behaviour matrix of `n` types with type `b` folded into `a`, see [`SimConfig::merge_types`]
+fn merge_matrix(behaviours: &[Behaviour], n: usize, a: usize, b: usize) -> Vec<Behaviour> {
+    let cell = |row: usize, col: usize| behaviours[row * n + col];
+    let row = |row: usize, col: usize| match row == a {
+        true => cell(a, col).lerp(&cell(b, col), 0.5),
+        false => cell(row, col),
+    };
+    let merged = |r: usize, col: usize| match col == a {
+        true => row(r, a).lerp(&row(r, b), 0.5),
+        false => row(r, col),
+    };
+    let keep: Vec<usize> = (0..n).filter(|&i| i != b).collect();
+    keep.iter()
+        .flat_map(|&r| keep.iter().map(move |&c| (r, c)))
+        .map(|(r, c)| merged(r, c))
+        .collect()
+}
+
+/// Behaviour matrix of `n` types with a copy of type `a` added last, see
+/// [`SimConfig::split_type`]
+fn split_matrix(behaviours: &[Behaviour], n: usize, a: usize) -> Vec<Behaviour> {
+    let source = |i: usize| if i == n { a } else { i };
+    (0..=n)
+        .flat_map(|row| (0..=n).map(move |col| behaviours[source(row) * n + source(col)]))
+        .collect()
+}
+
+pub(crate) fn random_direction(rng: &mut Pcg, planar: bool) -> Vec3 {
+    loop {
+        let dir = Vec3::new(gaussian(rng), gaussian(rng), gaussian(rng));
+        if let Some(dir) = flatten_heading(dir, planar) {
+            return dir;
+        }
+    }
+}
+
+/// `heading` normalized, after dropping its Y component if `planar`, unless too short
+fn flatten_heading(mut heading: Vec3, planar: bool) -> Option<Vec3> {
+    if planar {
+        heading.y = 0.;
+    }
+    heading.try_normalize()
+}
+
+/// Uniformly random position in the cube of the given half-width around the origin
+/// Standard normal sample, by the Box-Muller transform
+fn gaussian(rng: &mut Pcg) -> f32 {
+    // Avoid the logarithm of zero
+    let u = 1. - rng.gen_f32();
+    let v = rng.gen_f32();
+    (-2. * u.ln()).sqrt() * (2. * PI * v).cos()
+}
+
+fn random_position(rng: &mut Pcg, radius: f32) -> Vec3 {
+    let range = radius * 2.;
+    Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * range - Vec3::splat(range / 2.)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_behaviour() {
+        let behav = Behaviour {
+            default_repulse: 1.0,
+            inter_threshold: 0.25,
+            inter_strength: 3.0,
+            inter_max_dist: 0.75,
+            anisotropy: Vec3::ONE,
+            polarity: 0.,
+            mode: InteractionMode::Potential,
+        };
+
+        assert_eq!(behav.interact(0.), -behav.default_repulse);
+        assert_eq!(behav.interact(behav.inter_threshold), 0.0);
+        assert_eq!(behav.interact(0.25 + 0.125), behav.inter_strength / 2.);
+        assert_eq!(behav.interact(0.5), behav.inter_strength);
+        assert_eq!(behav.interact(behav.inter_max_dist), 0.0);
+        assert_eq!(behav.interact(0.85), 0.0);
+    }
+
+    #[test]
+    fn test_degenerate_behaviour() {
+        for (inter_threshold, inter_max_dist) in [(0., 0.2), (0.1, 0.1), (0., 0.)] {
+            let behav = Behaviour {
+                default_repulse: 10.,
+                inter_threshold,
+                inter_strength: 3.,
+                inter_max_dist,
+                anisotropy: Vec3::ONE,
+                polarity: 0.,
+                mode: InteractionMode::Potential,
+            };
+
+            // Steepest slope of either regime
+            let mut max_slope: f32 = 0.;
+            if inter_threshold > 0. {
+                max_slope = max_slope.max(behav.default_repulse / inter_threshold);
+            }
+            if inter_max_dist > inter_threshold {
+                max_slope =
+                    max_slope.max(2. * behav.inter_strength / (inter_max_dist - inter_threshold));
+            }
+
+            let h = 1e-3;
+            let mut last = behav.interact(0.);
+            for i in 1..=1000 {
+                let force = behav.interact(i as f32 * h);
+                assert!(force.is_finite());
+                assert!((force - last).abs() <= max_slope * h * 1.01 + 1e-6);
+                last = force;
+            }
+            assert_eq!(behav.interact(inter_max_dist), 0.);
+        }
+    }
+
+    #[test]
+    fn test_constrain_2d_rebuilds_accel() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 500);
+        sim.step(1e-3);
+        sim.set_constrain_2d(true, &mut rng);
+
+        let projected: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+        assert!(projected.iter().all(|p| p.y == 0.));
+        assert!(sim.particles().iter().all(|p| p.vel.y == 0.));
+
+        let fresh = QueryAccelerator::new(&projected, sim.max_interaction_radius);
+        for i in 0..projected.len() {
+            let mut expected: Vec<usize> = fresh.query_neighbors(&projected, i).collect();
+            let mut actual: Vec<usize> = sim.neighbors(i).collect();
+            expected.sort();
+            actual.sort();
+            assert_eq!(actual, expected);
+        }
+
+        sim.step(1e-3);
+        assert!(sim.particles().iter().all(|p| p.pos.y == 0.));
+
+        sim.set_constrain_2d(false, &mut rng);
+        assert!(sim.particles().iter().any(|p| p.pos.y != 0.));
+    }
+
+    #[test]
+    fn test_amortized_rebuild() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 1000);
+        sim.set_amortized_rebuild(Some(3));
+        let mut progress = vec![];
+        for _ in 0..12 {
+            sim.step(1e-3);
+            sim.check_invariants().unwrap();
+            progress.push(sim.rebuild_progress());
+
+            let (accel, points) = sim.last_accel();
+            let fresh = QueryAccelerator::new(points, sim.max_interaction_radius);
+            for i in (0..points.len()).step_by(7) {
+                let mut expected: Vec<usize> = fresh.query_neighbors(points, i).collect();
+                let mut actual: Vec<usize> = accel.query_neighbors(points, i).collect();
+                expected.sort();
+                actual.sort();
+                assert_eq!(actual, expected);
+            }
+        }
+        // Each rebuild bins a third of the particles per step, then is swapped in
+        assert!(progress.iter().any(|p| p.is_some()));
+        assert!(progress.iter().any(|p| p.is_none()));
+
+        sim.set_amortized_rebuild(None);
+        assert_eq!(sim.rebuild_progress(), None);
+    }
+
+    #[test]
+    fn test_partial_resets() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(4), 1000);
+        (0..10).for_each(|_| sim.step(1e-3));
+        let before = sim.particles().to_vec();
+
+        sim.reshuffle_types(&mut rng);
+        assert!(sim
+            .particles()
+            .iter()
+            .zip(&before)
+            .all(|(a, b)| a.pos == b.pos));
+        assert!(sim
+            .particles()
+            .iter()
+            .zip(&before)
+            .any(|(a, b)| a.color != b.color));
+
+        let colors: Vec<u8> = sim.particles().iter().map(|p| p.color).collect();
+        sim.rerandomize_positions(0.5, &mut rng);
+        assert!(sim.particles().iter().map(|p| p.color).eq(colors));
+        assert!(sim.particles().iter().all(|p| p.vel == Vec3::ZERO));
+        assert!(sim
+            .particles()
+            .iter()
+            .all(|p| p.pos.abs().max_element() <= 0.5));
+        assert_eq!(
+            sim.last_points,
+            sim.particles().iter().map(|p| p.pos).collect::<Vec<_>>()
+        );
+
+        sim.step(1e-3);
+        sim.zero_velocities();
+        assert!(sim.particles().iter().all(|p| p.vel == Vec3::ZERO));
+    }
+
+    #[test]
+    fn test_rebuild_specs() {
+        let mut rng = Pcg::new();
+        for positions in [PositionReset::Keep, PositionReset::Scatter(0.5)] {
+            for velocities in [
+                VelocityReset::Keep,
+                VelocityReset::Zero,
+                VelocityReset::Thermalize(1.),
+            ] {
+                for types in [TypeReset::Keep, TypeReset::Reshuffle] {
+                    let mut sim = SimState::new(&mut rng, test_config(4), 500);
+                    sim.set_homes_to_current();
+                    sim.set_compensated_positions(true);
+                    (0..5).for_each(|_| sim.step(1e-3));
+                    let before = sim.particles().to_vec();
+                    let spec = RebuildSpec {
+                        positions,
+                        velocities,
+                        types,
+                    };
+                    sim.rebuild(spec, &mut rng);
+
+                    let after = sim.particles();
+                    let changed = |f: fn(&Particle, &Particle) -> bool| {
+                        before.iter().zip(after).any(|(a, b)| f(a, b))
+                    };
+                    let moved = changed(|a, b| a.pos != b.pos);
+                    let accelerated = changed(|a, b| a.vel != b.vel);
+                    let recolored = changed(|a, b| a.color != b.color);
+                    assert_eq!(moved, positions != PositionReset::Keep, "{:?}", spec);
+                    assert_eq!(accelerated, velocities != VelocityReset::Keep, "{:?}", spec);
+                    assert_eq!(recolored, types != TypeReset::Keep, "{:?}", spec);
+                    if velocities == VelocityReset::Zero {
+                        assert!(after.iter().all(|p| p.vel == Vec3::ZERO));
+                    }
+                    if let PositionReset::Scatter(half_width) = positions {
+                        assert!(after
+                            .iter()
+                            .all(|p| p.pos.abs().max_element() <= half_width));
+                    }
+
+                    assert_eq!(sim.check_invariants(), Ok(()));
+                    assert!(sim.last_points.iter().eq(after.iter().map(|p| &p.pos)));
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_check_invariants() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 100);
+        assert_eq!(sim.check_invariants(), Ok(()));
+
+        sim.blend.pop();
+        assert!(sim.check_invariants().unwrap_err().contains("blend"));
+        sim.blend.push(0.);
+
+        sim.particles[7].color = 3;
+        assert!(sim.check_invariants().is_err());
+        sim.particles[7].color = 0;
+
+        // Points added since the last rebuild are fine, but the accelerator must match the
+        // points it was built over
+        sim.push_particle(sim.particles[0]);
+        assert_eq!(sim.check_invariants(), Ok(()));
+        sim.last_points.pop();
+        assert!(sim.check_invariants().is_err());
+        sim.rebuild(RebuildSpec::default(), &mut rng);
+        assert_eq!(sim.check_invariants(), Ok(()));
+
+        let cell = sim.scaled.behaviours.pop().unwrap();
+        assert!(sim.check_invariants().unwrap_err().contains("behaviours"));
+        sim.scaled.behaviours.push(cell);
+        sim.type_radius[1] = f32::NAN;
+        assert!(sim.check_invariants().unwrap_err().contains("radius"));
+    }
+
+    #[test]
+    fn test_staged_config() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(4), 200);
+        sim.stage_config(test_config(2));
+        sim.stage_config(test_config(3));
+        assert_eq!(sim.config().colors.len(), 4);
+        assert_eq!(sim.pending_config().unwrap().colors.len(), 3);
+
+        sim.step(1e-3);
+        assert!(sim.pending_config().is_none());
+        assert_eq!(sim.config().colors.len(), 3);
+        assert!(sim.particles().iter().all(|p| p.color < 3));
+        assert_eq!(sim.check_invariants(), Ok(()));
+    }
+
+    /// Configurations of every size staged between steps of every kind, as they would arrive
+    /// over the network while the simulation runs
+    #[test]
+    fn test_staged_config_stress() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 80);
+        let integrator = crate::mcmc::Integrator::default();
+        for iter in 0..2000 {
+            // Zero to two configurations per frame, the last one winning
+            for _ in 0..rng.gen_u32() % 3 {
+                let n = 1 + rng.gen_u32() as usize % 6;
+                let mut config = test_config(n);
+                for behav in &mut config.behaviours {
+                    behav.inter_strength = rng.gen_f32() * 20. - 10.;
+                    behav.inter_max_dist = 0.05 + rng.gen_f32() * 0.3;
+                    behav.polarity = if rng.gen_u32().is_multiple_of(8) {
+                        1.
+                    } else {
+                        0.
+                    };
+                }
+                config.colors = (0..n).map(|_| [rng.gen_f32(); 3]).collect();
+                sim.stage_config(config);
+            }
+            // Blending against the live matrix, which a staged resize then has to drop
+            if rng.gen_u32().is_multiple_of(50) {
+                sim.set_blend_behaviours(Some(sim.scaled.behaviours.clone()));
+            }
+
+            match iter % 3 {
+                0 => sim.step(1e-3),
+                1 => sim.step_substeps(1e-3, &[2, 1, 3]),
+                _ => {
+                    integrator.try_step(&mut sim, 1e-3, &mut rng).unwrap();
+                }
+            }
+            assert!(sim.pending_config().is_none());
+            assert_eq!(sim.check_invariants(), Ok(()), "after step {}", iter);
+        }
+    }
+
+    /// Config whose cell in row `r` and column `c` has strength `10 r + c`
+    fn labelled_config(n: usize) -> SimConfig {
+        let mut config = test_config(n);
+        for (idx, behav) in config.behaviours.iter_mut().enumerate() {
+            behav.inter_strength = (10 * (idx / n) + idx % n) as f32;
+        }
+        config.colors = (1..=n).map(|i| [i as f32 / n as f32; 3]).collect();
+        config.gravity = Some(Gravity {
+            down: -Vec3::Y,
+            weights: (0..n).map(|i| i as f32).collect(),
+        });
+        config
+    }
+
+    /// Propulsion with speed `i` and rotational diffusion `2 i` for type `i`, up to `n - 1`
+    fn labelled_activity(n: usize) -> Activity {
+        Activity {
+            speed: (0..n).map(|i| i as f32).collect(),
+            rotational_diffusion: (0..n - 1).map(|i| 2. * i as f32).collect(),
+            alignment: 1.,
+        }
+    }
+
+    #[test]
+    fn test_merge_types() {
+        for (n, a, b) in [(3, 0, 2), (3, 2, 1), (5, 1, 3), (5, 4, 0), (5, 2, 3)] {
+            let mut config = labelled_config(n);
+            config.activity = Some(labelled_activity(n));
+            let original = config.clone();
+            let mapping = config.merge_types(a, b);
+            assert_eq!(config.validate(), Ok(()));
+            assert_eq!(config.colors.len(), n - 1);
+            assert_eq!(mapping.len(), n);
+            assert_eq!(mapping[b as usize], mapping[a as usize]);
+
+            // Each old type's rows and columns, with `b` standing in for `a`
+            let sources = |new: usize| -> Vec<usize> {
+                (0..n).filter(|&old| mapping[old] as usize == new).collect()
+            };
+            for row in 0..n - 1 {
+                for col in 0..n - 1 {
+                    let (rows, cols) = (sources(row), sources(col));
+                    let expected = rows
+                        .iter()
+                        .flat_map(|r| cols.iter().map(move |c| (10 * r + c) as f32))
+                        .sum::<f32>()
+                        / (rows.len() * cols.len()) as f32;
+                    let strength = config.behaviours[row * (n - 1) + col].inter_strength;
+                    assert_eq!(strength, expected, "{} types, merging {} into {}", n, b, a);
+                }
+                let old = sources(row)[0];
+                if old != b as usize && old != a as usize {
+                    assert_eq!(config.colors[row], original.colors[old]);
+                }
+            }
+
+            let weights = &config.gravity.as_ref().unwrap().weights;
+            assert_eq!(weights[mapping[a as usize] as usize], (a + b) as f32 / 2.);
+            assert_eq!(weights.len(), n - 1);
+
+            // The last type has no diffusion of its own
+            let activity = config.activity.as_ref().unwrap();
+            let diffusion = |t: Color| {
+                if t as usize == n - 1 {
+                    0.
+                } else {
+                    2. * t as f32
+                }
+            };
+            let survivor = mapping[a as usize];
+            assert_eq!(activity.speed(survivor), (a + b) as f32 / 2.);
+            assert_eq!(
+                activity.rotational_diffusion(survivor),
+                (diffusion(a) + diffusion(b)) / 2.
+            );
+            assert_eq!(activity.speed.len(), n - 1);
+            assert_eq!(activity.rotational_diffusion.len(), n - 1);
+            for old in (0..n as Color).filter(|&t| t != a && t != b) {
+                assert_eq!(activity.speed(mapping[old as usize]), old as f32);
+                assert_eq!(
+                    activity.rotational_diffusion(mapping[old as usize]),
+                    diffusion(old)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_split_type() {
+        for (n, a) in [(3, 1), (5, 0), (5, 4)] {
+            let mut config = labelled_config(n);
+            config.activity = Some(labelled_activity(n));
+            let new = config.split_type(a) as usize;
+            assert_eq!(new, n);
+            assert_eq!(config.validate(), Ok(()));
+
+            let source = |i: usize| if i == new { a as usize } else { i };
+            for row in 0..=n {
+                for col in 0..=n {
+                    let strength = config.behaviours[row * (n + 1) + col].inter_strength;
+                    assert_eq!(strength, (10 * source(row) + source(col)) as f32);
+                }
+            }
+            assert_eq!(config.gravity.as_ref().unwrap().weights[new], a as f32);
+            let activity = config.activity.as_ref().unwrap();
+            assert_eq!(activity.speed(new as Color), a as f32);
+            assert_eq!(
+                activity.rotational_diffusion(new as Color),
+                activity.rotational_diffusion(a)
+            );
+            assert_ne!(config.colors[new], config.colors[a as usize]);
+
+            // Splitting and merging back is the identity
+            let mapping = config.merge_types(a, new as Color);
+            assert_eq!(config.behaviours, labelled_config(n).behaviours);
+            let activity = config.activity.as_ref().unwrap();
+            assert_eq!(activity.speed, labelled_activity(n).speed);
+            assert_eq!(mapping, (0..n as Color).chain([a]).collect::<Vec<_>>());
+        }
+
+        // Half of the particles take the new type, and can diverge at once
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, labelled_config(3), 1000);
+        let count = |sim: &SimState, color: Color| {
+            sim.particles().iter().filter(|p| p.color == color).count()
+        };
+        let reds = count(&sim, 0);
+        let dark_reds = sim.split_type(0, &mut rng);
+        assert_eq!(count(&sim, 0), reds - reds / 2);
+        assert_eq!(count(&sim, dark_reds), reds / 2);
+        assert_eq!(sim.check_invariants(), Ok(()));
+
+        let mut config = sim.config().clone();
+        config.behaviours[dark_reds as usize].inter_strength = -5.;
+        sim.set_config(config, &mut rng);
+        sim.step(1e-3);
+
+        sim.merge_types(0, dark_reds, &mut rng);
+        assert_eq!(count(&sim, 0), reds);
+        assert_eq!(sim.config().colors.len(), 3);
+        assert_eq!(sim.check_invariants(), Ok(()));
+    }
+
+    #[test]
+    fn test_density_rules() {
+        let mut rng = Pcg::new();
+        let rule = DensityRule {
+            ty: 0,
+            crowded_threshold: 10,
+            crowded_becomes: 1,
+            lonely_threshold: 1,
+            lonely_becomes: 2,
+            check_radius: 0.1,
+            probability: 1.,
+        };
+        let mut config = test_config(3);
+        config.density_rules = vec![rule];
+        assert_eq!(config.validate(), Ok(()));
+        let particle = |pos: Vec3, color: Color| Particle {
+            pos,
+            vel: Vec3::ZERO,
+            color,
+        };
+        let cluster = |n: usize, rng: &mut Pcg| -> Vec<Particle> {
+            (0..n)
+                .map(|_| particle(random_position(rng, 0.02), 0))
+                .collect()
+        };
+        let count = |sim: &SimState, color: Color| {
+            sim.particles().iter().filter(|p| p.color == color).count()
+        };
+
+        // A crowded cluster converts entirely, and a lone particle by the other rule
+        let mut particles = cluster(40, &mut rng);
+        particles.push(particle(Vec3::X * 5., 0));
+        particles.push(particle(Vec3::Y * 5., 1));
+        let mut sim = SimState::from_particles(config.clone(), particles);
+        sim.step(1e-4);
+        assert_eq!(count(&sim, 1), 41);
+        assert_eq!(sim.particles()[40].color, 2);
+        assert_eq!(sim.particles()[41].color, 1);
+
+        // At lower probability, about that fraction converts
+        config.density_rules[0].probability = 0.5;
+        let mut sim = SimState::from_particles(config.clone(), cluster(1000, &mut rng));
+        sim.step(1e-4);
+        let converted = count(&sim, 1) as f32 / 1000.;
+        assert!((converted - 0.5).abs() < 0.05, "{}", converted);
+
+        // Splitting copies the rules of the type, and merging maps them back
+        let new = config.split_type(0);
+        assert_eq!(config.density_rules[1].ty, new);
+        config.merge_types(0, new);
+        let merged = DensityRule {
+            probability: 0.5,
+            ..rule
+        };
+        assert_eq!(config.density_rules, [merged, merged]);
+        config.density_rules[0].lonely_becomes = 3;
+        assert!(config.validate().is_err());
+    }
+
+    #[test]
+    fn test_toroidal_metric() {
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour::default()],
+            damping: 10.,
+            ..Default::default()
+        };
+        // 0.9 apart, or 0.1 through the seam at x = 1
+        let particles = [0.95, 0.05].map(|x| Particle {
+            pos: Vec3::new(x, 0.5, 0.5),
+            vel: Vec3::ZERO,
+            color: 0,
+        });
+        let mut sim = SimState::from_particles(config, particles.to_vec());
+        assert_eq!(sim.total_force(0), Vec3::ZERO);
+
+        sim.set_metric(DistanceMetric::Toroidal { period: Vec3::ONE });
+        assert!(sim.total_force(0).x > 0. && sim.total_force(1).x < 0.);
+        assert!(sim.energy_due_to(0, particles[0].pos) < 0.);
+        for _ in 0..20 {
+            sim.step(1e-3);
+        }
+        // Towards each other through the seam, positions left unwrapped
+        let [a, b] = [0, 1].map(|i| sim.particles()[i].pos.x);
+        assert!(a > 0.95 && b < 0.05, "{} {}", a, b);
+        assert!(sim.metric().distance(Vec3::X * a, Vec3::X * b) < 0.1);
+    }
+
+    #[test]
+    fn test_metrics_agree_on_a_small_cloud() {
+        let mut config = test_config(3);
+        config.damping = 5.;
+        let euclidean = SimState::new(&mut Pcg::new(), config.clone(), 800);
+        let mut toroidal = SimState::new(&mut Pcg::new(), config, 800);
+        toroidal.set_metric(DistanceMetric::Toroidal {
+            period: Vec3::splat(100.),
+        });
+
+        let mut sims = [euclidean, toroidal];
+        for _ in 0..5 {
+            for i in (0..800).step_by(17) {
+                let [a, b] = [0, 1].map(|k| sims[k].total_force(i));
+                assert!(a.distance(b) <= 1e-4 * a.length().max(1.), "{} {}", a, b);
+                let [a, b] = [0, 1].map(|k| sims[k].energy_due_to(i, sims[k].particles[i].pos));
+                assert!((a - b).abs() <= 1e-4 * a.abs().max(1.));
+                let [mut a, mut b] = [0, 1].map(|k| sims[k].neighbors(i).collect::<Vec<_>>());
+                a.sort_unstable();
+                b.sort_unstable();
+                assert_eq!(a, b);
+            }
+            sims.iter_mut().for_each(|sim| sim.step(1e-3));
+        }
+        let drift = (sims[0].particles.iter().zip(&sims[1].particles))
+            .map(|(a, b)| a.pos.distance(b.pos))
+            .fold(0_f32, f32::max);
+        assert!(drift < 1e-4, "{}", drift);
+    }
+
+    #[test]
+    fn test_shared_neighbor_counts() {
+        let mut rng = Pcg::new();
+        let mut config = test_config(3);
+        config.density_rules = vec![DensityRule {
+            ty: 0,
+            crowded_threshold: usize::MAX,
+            crowded_becomes: 1,
+            lonely_threshold: 0,
+            lonely_becomes: 2,
+            check_radius: 0.05,
+            probability: 1.,
+        }];
+        let mut sim = SimState::new(&mut rng, config, 1000);
+        sim.step(1e-3);
+        sim.mean_neighbors();
+
+        // The density rules, the mean and any other consumer share one sweep per step
+        for _ in 0..3 {
+            let sweeps = sim.neighbor_count_sweeps();
+            sim.step(1e-3);
+            let mean = sim.mean_neighbors();
+            let counts = sim.neighbor_counts(0.05).to_vec();
+            sim.stable_dt(0.5);
+            assert_eq!(sim.neighbor_count_sweeps(), sweeps + 1);
+
+            let brute: usize = (0..1000).map(|i| sim.neighbors(i).count()).sum();
+            assert!((mean - brute as f32 / 1000.).abs() < 1e-6);
+            let (accel, points) = sim.last_accel();
+            for (i, &count) in counts.iter().enumerate() {
+                let near = (accel.query_neighbors(points, i))
+                    .filter(|&j| points[j].distance(points[i]) <= 0.05)
+                    .count();
+                assert_eq!(count as usize, near);
+            }
+        }
+    }
+
+    #[test]
+    fn test_density_rules_order_independent() {
+        // Conversions of either type change the neighbors the other type counts, so applying
+        // them in turn would depend on the order of the particles
+        let mut rng = Pcg::new();
+        let mut config = test_config(3);
+        let rule = |ty, crowded_becomes, lonely_becomes| DensityRule {
+            ty,
+            crowded_threshold: 4,
+            crowded_becomes,
+            lonely_threshold: 2,
+            lonely_becomes,
+            check_radius: 0.15,
+            probability: 1.,
+        };
+        config.density_rules = vec![rule(0, 1, 2), rule(1, 0, 2)];
+        let particles: Vec<Particle> = (0..500)
+            .map(|_| Particle {
+                pos: random_position(&mut rng, 0.5),
+                vel: Vec3::ZERO,
+                color: (rng.gen_u32() % 2) as Color,
+            })
+            .collect();
+
+        let types_after_step = |particles: Vec<Particle>| -> Vec<Color> {
+            let mut sim = SimState::from_particles(config.clone(), particles);
+            sim.step(1e-4);
+            sim.particles().iter().map(|p| p.color).collect()
+        };
+        let forward = types_after_step(particles.clone());
+        let mut reversed = types_after_step(particles.iter().rev().copied().collect());
+        reversed.reverse();
+        assert_eq!(forward, reversed);
+
+        // Every rule fired somewhere
+        for (old, new) in [(0, 1), (1, 0), (0, 2), (1, 2)] {
+            let fired = (particles.iter().zip(&forward)).any(|(p, &c)| p.color == old && c == new);
+            assert!(fired, "{} to {}", old, new);
+        }
+    }
+
+    #[test]
+    fn test_tether() {
+        let mut rng = Pcg::new();
+        let mut config = test_config(2);
+        config
+            .behaviours
+            .iter_mut()
+            .for_each(|b| b.inter_strength = 0.);
+        config.damping = 50.;
+        let mut sim = SimState::new(&mut rng, config, 200);
+
+        sim.assign_homes_to_lattice(0.1);
+        let homes = sim.homes().unwrap().to_vec();
+        assert_eq!(homes.len(), 200);
+        for (i, a) in homes.iter().enumerate() {
+            for b in &homes[i + 1..] {
+                assert!(a.distance(*b) > 0.099);
+            }
+        }
+
+        // Kick particles away from their homes; they should return
+        sim.set_homes_to_current();
+        let homes = sim.homes().unwrap().to_vec();
+        for particle in &mut sim.particles {
+            particle.vel = Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * 2. - 1.;
+        }
+        sim.set_tether_stiffness(5000.);
+        (0..1000).for_each(|_| sim.step(1e-3));
+        for (particle, home) in sim.particles().iter().zip(&homes) {
+            assert!(particle.pos.distance(*home) < 1e-3);
+        }
+
+        sim.set_constrain_2d(true, &mut rng);
+        sim.assign_homes_to_lattice(0.1);
+        assert!(sim.homes().unwrap().iter().all(|h| h.y == 0.));
+    }
+
+    #[test]
+    fn test_anisotropic_equilibrium() {
+        let behav = Behaviour {
+            default_repulse: 10.,
+            inter_threshold: 0.05,
+            inter_strength: 5.,
+            inter_max_dist: 0.2,
+            anisotropy: Vec3::new(1., 1., 4.),
+            polarity: 0.,
+            mode: InteractionMode::Potential,
+        };
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![behav],
+            damping: 50.,
+            ..Default::default()
+        };
+
+        let separation = |axis: Vec3| {
+            let particles = [Vec3::ZERO, axis * 0.03]
+                .into_iter()
+                .map(|pos| Particle {
+                    pos,
+                    vel: Vec3::ZERO,
+                    color: 0,
+                })
+                .collect();
+            let mut sim = SimState::from_particles(config.clone(), particles);
+            (0..20_000).for_each(|_| sim.step(1e-3));
+            (sim.particles()[1].pos - sim.particles()[0].pos).length()
+        };
+
+        let along_x = separation(Vec3::X);
+        let along_z = separation(Vec3::Z);
+        assert!((along_x - 0.05).abs() < 1e-3, "{}", along_x);
+        assert!(
+            (along_x / along_z - 4.).abs() < 0.1,
+            "{} {}",
+            along_x,
+            along_z
+        );
+    }
+
+    fn polar_config(polarity: f32) -> SimConfig {
+        SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour {
+                inter_threshold: 0.05,
+                inter_strength: 5.,
+                polarity,
+                ..Default::default()
+            }],
+            damping: 50.,
+            ..Default::default()
+        }
+    }
+
+    fn mobility_config(mobility: Mobility) -> SimConfig {
+        SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour {
+                inter_strength: 20.,
+                ..Default::default()
+            }],
+            damping: 0.,
+            mobility: Some(mobility),
+            ..Default::default()
+        }
+    }
+
+    fn hard_sphere_config(radius: f32) -> SimConfig {
+        SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour {
+                mode: InteractionMode::HardSphere { radius },
+                ..Default::default()
+            }],
+            damping: 0.,
+            ..Default::default()
+        }
+    }
+
+    /// Step two hard spheres until they first collide, returning the velocities expected from
+    /// an elastic collision along the line between them as they met, and those found
+    fn first_collision(a: Particle, b: Particle) -> ([Vec3; 2], [Vec3; 2]) {
+        let dt = 1e-3;
+        let mut sim = SimState::from_particles(hard_sphere_config(0.05), vec![a, b]);
+        for _ in 0..1_000 {
+            let [a, b] = [sim.particles()[0], sim.particles()[1]];
+            sim.step(dt);
+            let found = [sim.particles()[0].vel, sim.particles()[1].vel];
+            if found != [a.vel, b.vel] {
+                let normal = ((b.pos + b.vel * dt) - (a.pos + a.vel * dt)).normalize();
+                let exchange = normal * (a.vel - b.vel).dot(normal);
+                return ([a.vel - exchange, b.vel + exchange], found);
+            }
+        }
+        panic!("The spheres never met");
+    }
+
+    #[test]
+    fn test_hard_sphere_head_on() {
+        let a = Particle {
+            pos: Vec3::ZERO,
+            vel: Vec3::X,
+            color: 0,
+        };
+        let b = Particle {
+            pos: Vec3::X * 0.1,
+            vel: Vec3::ZERO,
+            color: 0,
+        };
+        let (expected, found) = first_collision(a, b);
+        assert_eq!(found, [Vec3::ZERO, Vec3::X]);
+        assert_eq!(expected, found);
+    }
+
+    #[test]
+    fn test_hard_sphere_oblique() {
+        let a = Particle {
+            pos: Vec3::ZERO,
+            vel: Vec3::X,
+            color: 0,
+        };
+        let b = Particle {
+            pos: Vec3::new(0.1, 0.03, 0.),
+            vel: Vec3::ZERO,
+            color: 0,
+        };
+        let (expected, found) = first_collision(a, b);
+        for (e, f) in expected.iter().zip(&found) {
+            assert!(e.distance(*f) < 1e-6, "{} {}", e, f);
+        }
+        // Equal masses, one at rest, leave at right angles with the energy shared out
+        assert!(found[0].dot(found[1]).abs() < 1e-6);
+        assert!((found[0].length_squared() + found[1].length_squared() - 1.).abs() < 1e-6);
+        assert!(found[0].y < 0. && found[1].y > 0.);
+    }
+
+    #[test]
+    fn test_hard_sphere_gas_conserves_energy() {
+        let mut rng = Pcg::new();
+        // Packed closely enough that most collide before the gas spreads out
+        let particles = (0..100)
+            .map(|_| Particle {
+                pos: (Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) - 0.5) * 0.4,
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let mut sim = SimState::from_particles(hard_sphere_config(0.05), particles);
+        sim.thermalize(1., &mut rng);
+        let kinetic = |sim: &SimState| -> f64 {
+            (sim.particles().iter())
+                .map(|p| p.vel.length_squared() as f64 / 2.)
+                .sum()
+        };
+        let momentum = |sim: &SimState| -> Vec3 { sim.particles().iter().map(|p| p.vel).sum() };
+        let (energy, start_momentum) = (kinetic(&sim), momentum(&sim));
+        let start: Vec<Vec3> = sim.particles().iter().map(|p| p.vel).collect();
+
+        for _ in 0..1_000 {
+            sim.step(1e-3);
+        }
+        let changed = (sim.particles().iter().zip(&start))
+            .filter(|(p, &v)| p.vel != v)
+            .count();
+        assert!(changed > 75, "Only {} particles collided", changed);
+        assert!((kinetic(&sim) / energy - 1.).abs() < 1e-4);
+        assert!(momentum(&sim).distance(start_momentum) < 1e-3);
+    }
+
+    #[test]
+    fn test_time_bubble() {
+        let bubble = TimeBubble {
+            center: Vec3::ZERO,
+            radius: 0.1,
+            shell: 0.05,
+            factor: 0.1,
+        };
+        assert_eq!(bubble.factor_at(Vec3::X * 0.1), 0.1);
+        assert_eq!(bubble.factor_at(Vec3::X * 0.15), 1.);
+        let shell: Vec<f32> = (0..=50)
+            .map(|k| bubble.factor_at(Vec3::X * (0.1 + 0.001 * k as f32)))
+            .collect();
+        assert!(shell.windows(2).all(|w| w[1] >= w[0] && w[1] - w[0] < 0.05));
+
+        // Far enough apart not to interact
+        let particles = [Vec3::ZERO, Vec3::X * 5.]
+            .into_iter()
+            .map(|pos| Particle {
+                pos,
+                vel: Vec3::Z,
+                color: 0,
+            })
+            .collect();
+        let mut config = polar_config(0.);
+        config.damping = 0.;
+        let mut sim = SimState::from_particles(config, particles);
+        sim.set_time_bubble(Some(bubble));
+        (0..100).for_each(|_| sim.step(1e-3));
+        let [slow, fast] = [0, 1].map(|i| sim.particles()[i].pos.z);
+        assert!((slow / fast - 0.1).abs() < 1e-4, "{} {}", slow, fast);
+    }
+
+    #[test]
+    fn test_max_speed_clamp() {
+        let mut rng = Pcg::new();
+        let max_speed = 0.05;
+        let config = mobility_config(Mobility {
+            max_speed: vec![max_speed],
+            fast_behaviours: None,
+            fast_threshold: vec![],
+        });
+        let mut sim = SimState::new(&mut rng, config, 300);
+        sim.thermalize(1., &mut rng);
+        for _ in 0..200 {
+            sim.step(1e-3);
+            for p in sim.particles() {
+                assert!(
+                    p.vel.length() <= max_speed * (1. + 1e-6),
+                    "{}",
+                    p.vel.length()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_fast_behaviour_hysteresis() {
+        // The fast behaviours ignore the other particle entirely
+        let threshold = 1.;
+        let quiet = Behaviour {
+            default_repulse: 0.,
+            inter_strength: 0.,
+            ..Default::default()
+        };
+        let mut config = mobility_config(Mobility {
+            max_speed: vec![],
+            fast_behaviours: Some(vec![quiet]),
+            fast_threshold: vec![threshold],
+        });
+        config.damping = 2.;
+        let particles = vec![
+            Particle {
+                pos: Vec3::ZERO,
+                vel: Vec3::X * 1.5,
+                color: 0,
+            },
+            Particle {
+                pos: Vec3::X * 0.1,
+                vel: Vec3::X * 1.05,
+                color: 0,
+            },
+        ];
+        let mut sim = SimState::from_particles(config, particles);
+
+        // Through the dead band the switch keeps its state, whichever side it came from
+        let mut expected = [false; 2];
+        let mut switched_off = None;
+        for _ in 0..300 {
+            let speeds: Vec<f32> = sim.particles().iter().map(|p| p.vel.length()).collect();
+            let vels: Vec<Vec3> = sim.particles().iter().map(|p| p.vel).collect();
+            sim.step(1e-3);
+            for (i, &speed) in speeds.iter().enumerate() {
+                let was = expected[i];
+                expected[i] = match was {
+                    true => speed >= threshold * (1. - FAST_DEAD_BAND),
+                    false => speed > threshold * (1. + FAST_DEAD_BAND),
+                };
+                if i == 0 && was && !expected[0] {
+                    switched_off = Some(speed);
+                }
+            }
+            assert_eq!(sim.fast(), &expected);
+
+            // A fast particle feels nothing, so only damping slows it
+            if sim.fast()[0] {
+                let damped = vels[0] * (1. - 1e-3 * 2.);
+                assert_eq!(sim.particles()[0].vel, damped);
+            }
+        }
+        assert!(!sim.fast()[1], "Never fast within the dead band");
+        let off = switched_off.expect("Slowed out of the fast behaviours");
+        assert!(
+            off < threshold * (1. - FAST_DEAD_BAND) && off > 0.85,
+            "{}",
+            off
+        );
+    }
+
+    #[test]
+    fn test_polar_head_to_tail() {
+        let particles = [Vec3::ZERO, Vec3::X * 0.08]
+            .into_iter()
+            .map(|pos| Particle {
+                pos,
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let mut sim = SimState::from_particles(polar_config(1.), particles);
+        sim.set_orientation(0, Vec3::new(1., 1., 0.));
+        sim.set_orientation(1, Vec3::new(1., -1., 0.5));
+        (0..10_000).for_each(|_| sim.step(1e-3));
+
+        // Both face the same way along the line between them, at the usual separation
+        let [a, b] = [0, 1].map(|i| sim.particles()[i].pos);
+        let dir = (b - a).normalize();
+        let orient = sim.orientations().unwrap();
+        assert!(orient[0].dot(dir) > 0.999, "{} {}", orient[0], dir);
+        assert!(orient[1].dot(dir) > 0.999, "{} {}", orient[1], dir);
+        assert!((a.distance(b) - 0.05).abs() < 1e-3, "{}", a.distance(b));
+    }
+
+    #[test]
+    fn test_headings_stay_unit() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, polar_config(0.), 30);
+        assert!(sim.orientations().is_none());
+
+        let mut config = polar_config(0.5);
+        config.colors = vec![[1.; 3]; 3];
+        config.behaviours = vec![config.behaviours[0]; 9];
+        for idx in 0..9 {
+            config.randomize_cell(idx, true, &mut rng);
+        }
+        sim.set_config(config, &mut rng);
+        sim.rebuild(RebuildSpec::default(), &mut rng);
+        sim.set_turn_rate(200.);
+        for step in 0..10_000 {
+            if step == 5_000 {
+                sim.set_constrain_2d(true, &mut rng);
+            }
+            sim.step(1e-3);
+        }
+        let orient = sim.orientations().unwrap();
+        assert_eq!(orient.len(), 30);
+        for heading in orient {
+            assert!((heading.length() - 1.).abs() < 1e-5, "{}", heading);
+            assert_eq!(heading.y, 0.);
+        }
+        assert_eq!(sim.check_invariants(), Ok(()));
+
+        // Headings go away with the last polar behaviour
+        sim.set_config(polar_config(0.), &mut rng);
+        assert!(sim.orientations().is_none());
+    }
+
+    #[test]
+    fn test_active_persistence() {
+        // Free swimmers in the plane, whose headings decorrelate after tau = 1 / rate
+        let (speed, rate, damping, dt) = (1., 4., 50., 1e-3);
+        let tau = 1. / rate;
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour {
+                default_repulse: 0.,
+                inter_strength: 0.,
+                ..Default::default()
+            }],
+            damping,
+            activity: Some(Activity {
+                speed: vec![speed],
+                rotational_diffusion: vec![rate],
+                alignment: 0.,
+            }),
+            ..Default::default()
+        };
+        let particles = (0..200)
+            .map(|i| Particle {
+                pos: Vec3::new((i % 20) as f32 * 0.3, 0., (i / 20) as f32 * 0.3),
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let mut rng = Pcg::new();
+        let mut sim = SimState::from_particles(config, particles);
+        sim.set_constrain_2d(true, &mut rng);
+        // Up to speed first; the explicit damping leaves the terminal speed a little short
+        (0..100).for_each(|_| sim.step(dt));
+        let speed = speed * (1. - damping * dt);
+
+        let start: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+        let mut msd = vec![];
+        for step in 1..=1_500 {
+            sim.step(dt);
+            if step % 25 == 0 {
+                let sum: f32 = (sim.particles().iter().zip(&start))
+                    .map(|(p, s)| p.pos.distance_squared(*s))
+                    .sum();
+                msd.push((step as f32 * dt, sum / start.len() as f32));
+            }
+        }
+
+        // Active Brownian particles: ballistic well within tau, diffusive well beyond it
+        let expected =
+            |tau: f32, t: f32| 2. * speed * speed * tau * (t - tau * (1. - (-t / tau).exp()));
+        let (t, early) = msd[0];
+        assert!((early / (speed * t).powi(2) - 1.).abs() < 0.1, "{}", early);
+        let (t, late) = msd[msd.len() - 1];
+        let (t_before, before) = msd[msd.len() - 11];
+        let slope = (late - before) / (t - t_before);
+        assert!(
+            (slope / (2. * speed * speed * tau) - 1.).abs() < 0.2,
+            "{}",
+            slope
+        );
+
+        // The crossover between the two is at the persistence time
+        let misfit = |tau: f32| -> f32 {
+            msd.iter()
+                .map(|&(t, msd)| (msd / expected(tau, t)).ln().powi(2))
+                .sum()
+        };
+        let fitted = (50..=200)
+            .map(|k| k as f32 * 0.005)
+            .min_by(|a, b| misfit(*a).total_cmp(&misfit(*b)))
+            .unwrap();
+        assert!((fitted / tau - 1.).abs() < 0.15, "{}", fitted);
+    }
+
+    #[test]
+    fn test_zero_activity_is_passive() {
+        // Inactive types leave every trajectory exactly as without activity, headings and all
+        let mut rng = Pcg::new();
+        let mut config = polar_config(0.5);
+        config.colors = vec![[1.; 3]; 2];
+        config.behaviours = vec![config.behaviours[0]; 4];
+        for idx in 0..4 {
+            config.randomize_cell(idx, true, &mut rng);
+        }
+        let particles = SimState::new(&mut rng, config.clone(), 60)
+            .particles()
+            .to_vec();
+        let inactive = SimConfig {
+            activity: Some(Activity {
+                speed: vec![0., 0.],
+                rotational_diffusion: vec![0.],
+                alignment: 3.,
+            }),
+            ..config.clone()
+        };
+        assert!(!inactive.is_active());
+        for polar in [true, false] {
+            let with_polarity = |mut config: SimConfig| {
+                if !polar {
+                    config.behaviours.iter_mut().for_each(|b| b.polarity = 0.);
+                }
+                config
+            };
+            let mut passive =
+                SimState::from_particles(with_polarity(config.clone()), particles.clone());
+            let mut idle =
+                SimState::from_particles(with_polarity(inactive.clone()), particles.clone());
+            for _ in 0..300 {
+                passive.step(1e-3);
+                idle.step(1e-3);
+            }
+            assert_eq!(passive.particles(), idle.particles());
+            assert_eq!(passive.orientations(), idle.orientations());
+            assert_eq!(idle.orientations().is_some(), polar);
+        }
+    }
+
+    #[test]
+    fn test_max_neighbors_above_count_is_exact() {
+        let mut rng = Pcg::new();
+        let mut exact = SimState::new(&mut rng, test_config(3), 300);
+        let mut capped = SimState::from_particles(test_config(3), exact.particles.clone());
+        capped.set_max_neighbors(Some(300));
+
+        for _ in 0..20 {
+            exact.step(1e-3);
+            capped.step(1e-3);
+        }
+        assert_eq!(capped.stats().capped_particles, 0);
+        for (a, b) in exact.particles().iter().zip(capped.particles()) {
+            assert_eq!(
+                a.pos.to_array().map(f32::to_bits),
+                b.pos.to_array().map(f32::to_bits)
+            );
+        }
+    }
+
+    #[test]
+    fn test_max_neighbors_unbiased() {
+        let mut rng = Pcg::new();
+        let mut config = test_config(2);
+        config
+            .behaviours
+            .iter_mut()
+            .for_each(|b| b.inter_max_dist = 0.5);
+        let particles = (0..200)
+            .map(|_| Particle {
+                pos: random_position(&mut rng, 0.1),
+                vel: Vec3::ZERO,
+                color: (rng.gen_u32() % 2) as u8,
+            })
+            .collect();
+        let mut sim = SimState::from_particles(config, particles);
+        let points: Vec<Vec3> = sim.particles.iter().map(|p| p.pos).collect();
+        let accel = QueryAccelerator::new(&points, sim.max_interaction_radius);
+
+        let (exact, _) = sim.pair_accel(&accel, &points, 0);
+        sim.set_max_neighbors(Some(20));
+        let trials = 4000;
+        let mean = (0..trials)
+            .map(|_| sim.pair_accel(&accel, &points, 0).0)
+            .sum::<Vec3>()
+            / trials as f32;
+        assert_eq!(sim.stats.capped_particles, trials);
+        assert!(
+            mean.distance(exact) < exact.length() * 0.05,
+            "{} {}",
+            mean,
+            exact
+        );
+    }
+
+    #[test]
+    fn test_pinned() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 500);
+        sim.set_pinned_within(Vec3::ZERO, 0.5, true);
+        sim.set_pinned(0, true);
+        let before = sim.particles.clone();
+        let n_pinned = sim.pinned().iter().filter(|&&p| p).count();
+        assert!(n_pinned > 1 && n_pinned < 500);
+
+        sim.move_neighbors(Vec3::ZERO, Vec3::X);
+        (0..50).for_each(|_| sim.step(1e-3));
+        let mut n_moved = 0;
+        for ((a, b), &pinned) in before.iter().zip(sim.particles()).zip(sim.pinned()) {
+            if pinned {
+                assert_eq!((a.pos, b.vel), (b.pos, Vec3::ZERO));
+            } else if a.pos != b.pos {
+                n_moved += 1;
+            }
+        }
+        assert!(n_moved > 0);
+
+        // Pinned particles still push others around
+        let particles = vec![
+            Particle {
+                pos: Vec3::ZERO,
+                vel: Vec3::ZERO,
+                color: 0,
+            },
+            Particle {
+                pos: Vec3::X * 0.01,
+                vel: Vec3::ZERO,
+                color: 0,
+            },
+        ];
+        let mut sim = SimState::from_particles(test_config(1), particles);
+        sim.set_pinned(0, true);
+        sim.step(1e-3);
+        assert_eq!(sim.particles()[0].pos, Vec3::ZERO);
+        assert!(sim.particles()[1].pos.x > 0.01);
+    }
+
+    #[test]
+    fn test_try_step_recovers() {
+        let mut rng = Pcg::new();
+        let mut config = test_config(3);
+        // Not square
+        config.behaviours.truncate(4);
+        let mut sim = SimState::new(&mut rng, config, 100);
+        let before = sim.particles.clone();
+
+        // Found before stepping, rather than by catching the panic it would cause
+        let err = sim.check_step(1e-3).unwrap_err();
+        assert!(err.contains("does not match the colors"), "{}", err);
+        assert_eq!(sim.try_step(1e-3), Err(err));
+        for (a, b) in before.iter().zip(sim.particles()) {
+            assert_eq!((a.pos, a.vel), (b.pos, b.vel));
+        }
+
+        let mut sim = SimState::new(&mut rng, test_config(3), 100);
+        assert!(sim.try_step(f32::NAN).is_err());
+        assert!(sim.try_step(1e-3).is_ok());
+    }
+
+    #[test]
+    fn test_force_at_cutoff() {
+        let behav = Behaviour {
+            inter_threshold: 0.125,
+            inter_max_dist: 0.25,
+            inter_strength: 3.,
+            ..Default::default()
+        };
+        assert_eq!(behav.interact(0.25), 0.);
+
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![behav],
+            damping: 0.,
+            ..Default::default()
+        };
+        assert_eq!(config.max_interaction_radius(), 0.25);
+        let particles = [0., 0.25]
+            .into_iter()
+            .map(|x| Particle {
+                pos: Vec3::X * x,
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let mut sim = SimState::from_particles(config, particles);
+        sim.step(1e-3);
+        assert_eq!(sim.neighbors(0).collect::<Vec<_>>(), vec![1]);
+        assert!(sim.particles().iter().all(|p| p.vel == Vec3::ZERO));
+    }
+
+    #[test]
+    fn test_suggested_spawn_radius() {
+        let mut rng = Pcg::new();
+        for (n_types, max_dist, planar) in [(1, 0.1, false), (3, 0.2, false), (5, 0.05, true)] {
+            let mut config = test_config(n_types);
+            config
+                .behaviours
+                .iter_mut()
+                .for_each(|b| b.inter_max_dist = max_dist);
+            let radius = config.suggested_spawn_radius(2000, 8., planar);
+
+            let mut sim = SimState::new(&mut rng, config, 2000);
+            sim.set_constrain_2d(planar, &mut rng);
+            sim.rerandomize_positions(radius, &mut rng);
+            let mean = (0..2000).map(|i| sim.neighbors(i).count()).sum::<usize>() as f32 / 2000.;
+            assert!((4.0..=16.).contains(&mean), "{} {}", radius, mean);
+        }
+    }
+
+    #[test]
+    fn test_interaction_scale() {
+        let mut config = test_config(3);
+        config.behaviours[4].mode = InteractionMode::HardSphere { radius: 0.02 };
+        config.density_rules.push(DensityRule {
+            ty: 0,
+            crowded_threshold: 6,
+            crowded_becomes: 1,
+            lonely_threshold: 0,
+            lonely_becomes: 0,
+            check_radius: 0.1,
+            probability: 0.5,
+        });
+        // Twice the scale over half the distances is the same configuration as evaluated
+        let mut halved = config.clone();
+        halved.interaction_scale = 2.;
+        for behav in &mut halved.behaviours {
+            *behav = behav.scaled(0.5);
+        }
+        halved.density_rules[0].check_radius /= 2.;
+        assert_eq!(halved.scaled(), config.scaled());
+        assert_eq!(halved.cutoff_sq_table(), config.cutoff_sq_table());
+        assert_eq!(halved.get_bahaviour(1, 1), config.get_bahaviour(1, 1));
+
+        let mut a = SimState::new(&mut Pcg::new(), config.clone(), 1000);
+        let mut b = SimState::new(&mut Pcg::new(), halved.clone(), 1000);
+        assert_eq!(b.config(), &halved);
+        assert_eq!(a.last_accel().0.radius(), b.last_accel().0.radius());
+        for _ in 0..5 {
+            for i in 0..1000 {
+                assert_eq!(a.total_force(i), b.total_force(i));
+            }
+            a.step(1e-3);
+            b.step(1e-3);
+            assert_eq!(a.particles(), b.particles());
+        }
+
+        // A new scale rebuilds the accelerator at the new radius
+        let radius = a.last_accel().0.radius();
+        let mut rng = Pcg::new();
+        a.set_config(
+            SimConfig {
+                interaction_scale: 2.,
+                ..config
+            },
+            &mut rng,
+        );
+        assert_eq!(a.last_accel().0.radius(), radius * 2.);
+        a.last_accel().0.validate(a.last_accel().1).unwrap();
+    }
+
+    #[test]
+    fn test_rescale_to_density() {
+        let mut rng = Pcg::new();
+        for planar in [false, true] {
+            let mut sim = SimState::new(&mut rng, test_config(2), 2000);
+            sim.set_constrain_2d(planar, &mut rng);
+            sim.rerandomize_positions(0.5, &mut rng);
+            let scale = sim.interaction_scale_for_density(8.).unwrap();
+            let config = SimConfig {
+                interaction_scale: scale,
+                ..sim.config().clone()
+            };
+            sim.set_config(config, &mut rng);
+            let mean = sim.mean_neighbors();
+            assert!((4.0..=16.).contains(&mean), "{} {}", scale, mean);
+        }
+    }
+
+    #[test]
+    fn test_blend() {
+        let config = test_config(1);
+        let b = Behaviour {
+            inter_strength: 20.,
+            inter_max_dist: 0.3,
+            ..config.behaviours[0]
+        };
+        let particles = [0., 0.1]
+            .into_iter()
+            .map(|x| Particle {
+                pos: Vec3::X * x,
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let mut sim = SimState::from_particles(config.clone(), particles);
+        sim.set_blend_behaviours(Some(vec![b]));
+        assert_eq!(sim.max_interaction_radius, 0.3);
+
+        // Force on particle 0 with the given blend, compared to a sim using that behaviour
+        let mut accel_at = |t: f32, expected: Behaviour| {
+            sim.blend[0] = t;
+            let points: Vec<Vec3> = sim.particles.iter().map(|p| p.pos).collect();
+            let accel = QueryAccelerator::new(&points, sim.max_interaction_radius);
+            let (actual, _) = sim.pair_accel(&accel, &points, 0);
+
+            let mut plain = SimState::from_particles(
+                SimConfig {
+                    behaviours: vec![expected],
+                    ..config.clone()
+                },
+                sim.particles.clone(),
+            );
+            let (expected, _) = plain.pair_accel(&accel, &points, 0);
+            assert!(actual.distance(expected) < 1e-4, "{} {}", actual, expected);
+        };
+        accel_at(0., config.behaviours[0]);
+        accel_at(1., b);
+        accel_at(0.5, config.behaviours[0].lerp(&b, 0.5));
+
+        sim.set_blend_gradient_x(0., 0.2);
+        assert_eq!(sim.blend(), &[0., 0.5]);
+        sim.set_blend_within(Vec3::X * 0.1, 0.01, 2.);
+        assert_eq!(sim.blend(), &[0., 1.]);
+        sim.set_config(test_config(2), &mut Pcg::new());
+        assert!(sim.blend_behaviours().is_none());
+    }
+
+    #[test]
+    fn test_compensated_positions() {
+        // A particle oscillating on a weak spring far from the origin
+        let offset = 1e4;
+        let (stiffness, dt, steps) = (100., 1e-3, 2000);
+        let start = Vec3::X * (offset + 0.1);
+        let run = |compensated: bool| {
+            let particle = Particle {
+                pos: start,
+                vel: Vec3::ZERO,
+                color: 0,
+            };
+            let mut sim = SimState::from_particles(test_config(1), vec![particle]);
+            sim.set_compensated_positions(compensated);
+            sim.home = Some(vec![Vec3::X * offset]);
+            sim.set_tether_stiffness(stiffness);
+
+            // Same integrator in f64
+            let (mut x, mut v) = (start.x as f64, 0f64);
+            let mut max_err: f64 = 0.;
+            for _ in 0..steps {
+                sim.step(dt);
+                v += (offset as f64 - x) * stiffness as f64 * dt as f64;
+                x += v * dt as f64;
+                max_err = max_err.max((sim.particles[0].pos.x as f64 - x).abs());
+            }
+            max_err
+        };
+
+        let naive = run(false);
+        let compensated = run(true);
+        assert!(naive > 1e-2, "{}", naive);
+        assert!(compensated < naive / 30., "{} {}", compensated, naive);
+    }
+
+    #[test]
+    fn test_shared_rng_streams_differ() {
+        let mut rng = Pcg::new();
+        let a = SimState::new(&mut rng, test_config(2), 100);
+        let b = SimState::new(&mut rng, test_config(2), 100);
+        let same = a
+            .particles()
+            .iter()
+            .zip(b.particles())
+            .filter(|(a, b)| a.pos == b.pos)
+            .count();
+        assert_eq!(same, 0);
+    }
+
+    #[test]
+    fn test_asymmetry_matrix() {
+        let mut config = test_config(3);
+        config
+            .behaviours
+            .iter_mut()
+            .for_each(|b| *b = Behaviour::default());
+        assert!(config.asymmetry_matrix().iter().all(|&a| a == 0.));
+
+        // Type 0 is attracted to type 1, but not the other way around
+        config.behaviours[1].inter_strength = 5.;
+        config.behaviours[3].inter_strength = 0.;
+        let asymmetry = config.asymmetry_matrix();
+        assert!(asymmetry[1] > 0.);
+        assert_eq!(asymmetry[3], -asymmetry[1]);
+        assert_eq!(asymmetry[2], 0.);
+
+        // The drive matches the force curve
+        let behav = Behaviour::default();
+        let n = 10_000;
+        let h = behav.inter_max_dist / n as f32;
+        let integral: f32 = (0..n)
+            .map(|i| behav.interact((i as f32 + 0.5) * h) * h)
+            .sum();
+        assert!((integral - behav.drive()).abs() < 1e-3);
+    }
+
+    #[test]
+    fn test_qualitative() {
+        let class = |s: f32| {
+            Behaviour::default()
+                .with_inter_strength(s)
+                .qualitative_class()
+        };
+        assert_eq!(class(100.), Qualitative::StrongAttract);
+        assert_eq!(class(6.5), Qualitative::StrongAttract);
+        assert_eq!(class(6.4), Qualitative::WeakAttract);
+        assert_eq!(class(1.5), Qualitative::WeakAttract);
+        assert_eq!(class(1.4), Qualitative::Neutral);
+        assert_eq!(class(-1.4), Qualitative::Neutral);
+        assert_eq!(class(-1.5), Qualitative::WeakRepel);
+        assert_eq!(class(-6.5), Qualitative::StrongRepel);
+        assert_eq!(class(f32::NAN), Qualitative::Neutral);
+        for q in Qualitative::ALL {
+            assert_eq!(Qualitative::classify(q.strength()), q);
+            assert_eq!(q.cycle(true).cycle(false), q);
+        }
+
+        let mut behav = Behaviour::default().with_inter_strength(4.2);
+        behav.cycle_qualitative(true);
+        assert_eq!(behav.inter_strength, 0.);
+        assert_eq!(behav.inter_max_dist, Behaviour::default().inter_max_dist);
+        behav.cycle_qualitative(false);
+        behav.cycle_qualitative(false);
+        assert_eq!(behav.inter_strength, 10.);
+        behav.cycle_qualitative(false);
+        assert_eq!(behav.inter_strength, -10.);
+    }
+
+    #[test]
+    fn test_ghost_walls() {
+        // A row of particles continuing evenly up to a wall half a spacing away
+        let (half_width, spacing) = (1., 0.05);
+        let particles = (0..20)
+            .map(|k| Particle {
+                pos: Vec3::X * (half_width - spacing / 2. - k as f32 * spacing),
+                vel: Vec3::ZERO,
+                color: 0,
+            })
+            .collect();
+        let mut config = test_config(1);
+        config.behaviours[0].inter_strength = 5.;
+        let mut sim = SimState::from_particles(config, particles);
+        let points: Vec<Vec3> = sim.particles.iter().map(|p| p.pos).collect();
+        let accel = QueryAccelerator::new(&points, sim.max_interaction_radius);
+
+        let bulk = sim.pair_accel(&accel, &points, 10).0;
+        assert!(bulk.length() < 1e-3);
+        let edge_without = sim.pair_accel(&accel, &points, 0).0;
+        sim.set_ghost_walls(Some(half_width));
+        let edge_with = sim.pair_accel(&accel, &points, 0).0;
+        assert!(edge_without.length() > 1.);
+        assert!((edge_with - bulk).length() < 1e-3, "{}", edge_with);
+        assert_eq!(sim.pair_accel(&accel, &points, 10).0, bulk);
+    }
+
+    #[test]
+    fn test_scoped_randomization() {
+        let mut rng = Pcg::new();
+        let mut config = test_config(4);
+
+        let before = config.clone();
+        config.randomize_field(Field::Repulse, false, &mut rng);
+        for (new, old) in config.behaviours.iter().zip(&before.behaviours) {
+            assert!((1.0..=30.).contains(&new.default_repulse));
+            assert_eq!(new.inter_strength, old.inter_strength);
+        }
+
+        for _ in 0..50 {
+            config.randomize_cell(5, true, &mut rng);
+            let behav = config.behaviours[5];
+            assert!((-15.0..=15.).contains(&behav.inter_strength));
+            assert!((0.01..=0.3).contains(&behav.inter_threshold));
+            assert!(behav.inter_threshold <= behav.inter_max_dist);
+            assert!(behav.inter_max_dist <= 0.9);
+        }
+        config.randomize_cell(6, true, &mut rng);
+        assert_eq!(config.behaviours[6], config.behaviours[9]);
+
+        for _ in 0..100 {
+            config.jitter(0.5, false, &mut rng);
+            assert!(config
+                .behaviours
+                .iter()
+                .all(|b| b.inter_threshold <= b.inter_max_dist));
+        }
+
+        // Past one, max distances would go negative
+        for _ in 0..100 {
+            config.jitter(1.5, false, &mut rng);
+            assert!(config
+                .behaviours
+                .iter()
+                .all(|b| (0.0..=b.inter_max_dist).contains(&b.inter_threshold)));
+        }
+
+        config.randomize_field(Field::Strength, true, &mut rng);
+        assert_eq!(config.behaviours[1], config.behaviours[4]);
+        assert_eq!(config.behaviours[11], config.behaviours[14]);
+    }
+
+    #[test]
+    fn test_remove_indices() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 50);
+        sim.set_homes_to_current();
+        sim.set_compensated_positions(true);
+        sim.set_pinned(10, true);
+        sim.set_blend_within(sim.particles[20].pos, 0., 0.5);
+        let before = sim.particles.clone();
+
+        // Overlapping, unordered, and including the last particle
+        let removed = [3, 49, 10, 3, 48, 0, 49];
+        sim.remove_indices(&removed);
+        assert_eq!(sim.particles.len(), 45);
+        assert_eq!(sim.pinned.len(), 45);
+        assert_eq!(sim.blend.len(), 45);
+        assert_eq!(sim.homes().unwrap().len(), 45);
+        assert_eq!(sim.pos_compensation.as_ref().unwrap().len(), 45);
+
+        // Exactly the survivors remain, each with its own per-particle state
+        for (i, particle) in before.iter().enumerate() {
+            let found = sim.particles.iter().position(|p| p == particle);
+            assert_eq!(found.is_none(), removed.contains(&i));
+            if let Some(j) = found {
+                assert_eq!(sim.homes().unwrap()[j], particle.pos);
+                assert_eq!(sim.blend[j] != 0., i == 20);
+            }
+        }
+        assert!(sim.pinned.iter().all(|&p| !p));
+
+        // Queries never return stale indices
+        sim.step(1e-3);
+        for i in 0..sim.particles.len() {
+            assert!(sim.neighbors(i).all(|j| j < sim.particles.len()));
+            assert!(sim.accel_consistent(i));
+        }
+        sim.remove_indices(&[]);
+        assert_eq!(sim.particles.len(), 45);
+    }
+
+    #[test]
+    fn test_sphere_regions() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 500);
+        let center = sim.centroid();
+
+        let removed = sim.remove_in_sphere(center, 0.5, true);
+        assert!(removed > 0);
+        assert!(sim.particles.iter().all(|p| p.pos.distance(center) > 0.5));
+        assert!((0..sim.particles.len()).all(|i| sim.accel_consistent(i)));
+
+        sim.spawn_in_sphere(center, 0.3, 2, 100, &mut rng);
+        assert_eq!(sim.particles.len(), 500 - removed + 100);
+        let spawned = &sim.particles[500 - removed..];
+        assert!(spawned
+            .iter()
+            .all(|p| p.color == 2 && p.pos.distance(center) <= 0.3));
+
+        let kept = sim.particles.len() - sim.remove_in_sphere(center, 0.3, false);
+        assert_eq!(kept, 100);
+        assert!((0..sim.particles.len()).all(|i| sim.accel_consistent(i)));
+    }
+
+    #[test]
+    fn test_orbital_preset() {
+        let mut rng = Pcg::new();
+        let config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 0.,
+            ..Default::default()
+        };
+        let mut sim = SimState::new(&mut rng, config, 500);
+        let preset = OrbitalPreset {
+            ring_count: 24,
+            ..Default::default()
+        };
+        let speed = sim.spawn_orbital_preset(&preset, &mut rng);
+        assert!(speed > 0.);
+        assert_eq!(sim.particles().len(), 124);
+        assert!(sim.pinned()[..100].iter().all(|&p| p));
+        let ring = &sim.particles()[100..];
+        assert!(ring
+            .iter()
+            .all(|p| p.color == 1 && p.vel.dot(p.pos).abs() < 1e-6));
+
+        // Around and around, neither falling in nor flying off
+        let period = TAU * preset.ring_radius / speed;
+        let steps = (period / 1e-4) as usize;
+        for _ in 0..steps {
+            sim.step(1e-4);
+        }
+        let radii: Vec<f32> = sim.particles()[100..]
+            .iter()
+            .map(|p| p.pos.length())
+            .collect();
+        assert!(radii.iter().all(|r| (r - preset.ring_radius).abs() < 0.01));
+    }
+
+    #[test]
+    fn test_potential_matches_force() {
+        let behav = Behaviour {
+            default_repulse: 12.,
+            inter_threshold: 0.1,
+            inter_strength: 5.,
+            inter_max_dist: 0.4,
+            anisotropy: Vec3::ONE,
+            polarity: 0.,
+            mode: InteractionMode::Potential,
+        };
+        let h = 1e-3;
+        for dist in [0.03, 0.08, 0.15, 0.2, 0.3, 0.38] {
+            let slope = (behav.potential(dist + h) - behav.potential(dist - h)) / (2. * h);
+            let force = behav.interact(dist) / dist;
+            assert!(
+                (slope - force).abs() < 1e-2 * force.abs().max(1.),
+                "{} {}",
+                slope,
+                force
+            );
+        }
+        assert_eq!(behav.potential(0.4), 0.);
+        assert!(behav.potential(0.01) > behav.potential(0.05));
+
+        let repulsive_only = Behaviour {
+            inter_max_dist: 0.05,
+            ..behav
+        };
+        assert!(repulsive_only.potential(0.02) > 0.);
+        assert_eq!(repulsive_only.potential(0.07), 0.);
+    }
+
+    #[test]
+    fn test_walls_plate_out() {
+        let mut rng = Pcg::new();
+
+        // Types that only keep their distance, starting within reach of the floor
+        let behav = Behaviour {
+            default_repulse: 5.,
+            inter_threshold: 0.03,
+            inter_strength: 0.,
+            inter_max_dist: 0.03,
+            anisotropy: Vec3::ONE,
+            polarity: 0.,
+            mode: InteractionMode::Potential,
+        };
+        let config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![behav; 4],
+            damping: 20.,
+            ..Default::default()
+        };
+        // On a grid wider than they reach, so that no two start out overlapping
+        let particles = (0..200)
+            .map(|i| Particle {
+                pos: Vec3::new(
+                    (i % 20) as f32 * 0.05,
+                    0.03 + rng.gen_f32() * 0.06,
+                    (i / 20) as f32 * 0.05,
+                ),
+                vel: Vec3::ZERO,
+                color: (rng.gen_u32() % 2) as Color,
+            })
+            .collect();
+        let mut sim = SimState::from_particles(config, particles);
+        let floor = Wall::floor(0., vec![10.]);
+        sim.set_walls(vec![floor.clone()]);
+        for _ in 0..1_500 {
+            sim.step(2e-3);
+        }
+
+        // Type 0 lies in a single layer at the wall's threshold, type 1 stays above it
+        let heights = |color: Color| -> Vec<f32> {
+            let particles = sim.particles().iter().filter(|p| p.color == color);
+            particles.map(|p| p.pos.y).collect()
+        };
+        let mean = |h: &[f32]| h.iter().sum::<f32>() / h.len() as f32;
+        assert!(
+            heights(0)
+                .iter()
+                .all(|h| (h - floor.threshold).abs() < 0.01),
+            "{:?}",
+            heights(0)
+        );
+        assert!(heights(1).iter().all(|&h| h >= 0.));
+        assert!(mean(&heights(1)) > 2. * mean(&heights(0)));
+
+        // Particles on the plane feel nothing rather than NaN, and cannot be behind it
+        assert_eq!(floor.accel(Vec3::X, 0), Vec3::ZERO);
+        assert!(floor.potential(Vec3::X, 0).is_finite());
+        assert_eq!(floor.potential(-Vec3::Y * 1e-3, 1), f32::INFINITY);
+        assert_eq!(floor.accel(Vec3::Y * 0.2, 0), Vec3::ZERO);
+    }
+
+    #[test]
+    fn test_recenter_moves_walls_and_bubble() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(2), 100);
+        for particle in &mut sim.particles {
+            particle.pos += Vec3::new(50., 20., -30.);
+        }
+        sim.set_walls(vec![Wall::floor(15., vec![1.])]);
+        sim.set_time_bubble(Some(TimeBubble {
+            center: sim.particles[0].pos,
+            radius: 0.3,
+            shell: 0.1,
+            factor: 0.1,
+        }));
+        let wall_distances = |sim: &SimState| -> Vec<f32> {
+            let wall = &sim.walls()[0];
+            sim.particles()
+                .iter()
+                .map(|p| wall.distance(p.pos))
+                .collect()
+        };
+        let rates = |sim: &SimState| -> Vec<f32> {
+            let bubble = sim.time_bubble().unwrap();
+            sim.particles()
+                .iter()
+                .map(|p| bubble.factor_at(p.pos))
+                .collect()
+        };
+        let (distances, factors) = (wall_distances(&sim), rates(&sim));
+
+        let shift = sim.recenter().unwrap();
+        assert!(sim.centroid().length() < 1e-3);
+        assert_eq!(sim.time_bubble().unwrap().center, sim.particles[0].pos);
+        for (a, b) in wall_distances(&sim).iter().zip(distances) {
+            assert!((a - b).abs() < 1e-4, "{} {}", a, b);
+        }
+        for (a, b) in rates(&sim).iter().zip(factors) {
+            assert!((a - b).abs() < 1e-3, "{} {}", a, b);
+        }
+        assert_eq!(sim.walls()[0].point.y, 15. + shift.y);
+    }
+
+    #[test]
+    fn test_gravity_stratifies() {
+        let mut rng = Pcg::new();
+        let weight = 2.;
+        let stiffness = 50.;
+        let down = Vec3::new(1., -1., 0.);
+
+        // Two non-interacting types of opposite weight, tethered to the origin
+        let behav = Behaviour {
+            default_repulse: 0.,
+            ..Behaviour::default().with_inter_strength(0.)
+        };
+        let config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![behav; 4],
+            // Close to critical damping for the tether, to settle quickly
+            damping: 14.,
+            gravity: Some(Gravity {
+                down,
+                weights: vec![weight, -weight],
+            }),
+            ..Default::default()
+        };
+        let mut sim = SimState::new(&mut rng, config, 20);
+        sim.home = Some(vec![Vec3::ZERO; 20]);
+        sim.set_tether_stiffness(stiffness);
+        for _ in 0..2_000 {
+            sim.step(1e-3);
+        }
+
+        let centroid = |color: Color| {
+            let layer: Vec<Vec3> = sim
+                .particles()
+                .iter()
+                .filter(|p| p.color == color)
+                .map(|p| p.pos)
+                .collect();
+            layer.iter().sum::<Vec3>() / layer.len() as f32
+        };
+
+        // Each layer rests where the spring balances its weight
+        let separation = centroid(0) - centroid(1);
+        let expected = down.normalize() * 2. * weight / stiffness;
+        assert!(
+            (separation - expected).length() < expected.length() * 0.02,
+            "{} {}",
+            separation,
+            expected
+        );
+    }
+
+    /// Pair acceleration as computed before the squared distance path
+    fn reference_accel(behav: &Behaviour, diff: Vec3, cutoff_sq: f32) -> Vec3 {
+        let dist_sq = diff.length_squared();
+        if dist_sq > cutoff_sq {
+            return Vec3::ZERO;
+        }
+        let dist = dist_sq.sqrt();
+        diff.normalize() * behav.interact(dist) / dist
+    }
+
+    /// Behaviour, difference and cutoff of each neighbor pair
+    fn neighbor_pairs(sim: &SimState) -> Vec<(Behaviour, Vec3, f32)> {
+        let n = sim.scaled.colors.len();
+        (0..sim.particles.len())
+            .flat_map(|i| sim.neighbors(i).map(move |j| (i, j)))
+            .map(|(i, j)| {
+                let (a, b) = (sim.particles[i], sim.particles[j]);
+                let pair = a.color as usize * n + b.color as usize;
+                let behav = sim.scaled.behaviours[pair];
+                let diff = (b.pos - a.pos) * behav.anisotropy;
+                (behav, diff, sim.cutoff_sq[pair])
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_fused_force_matches_reference() {
+        let mut rng = Pcg::new();
+        for _ in 0..20 {
+            let mut config = test_config(3);
+            config.randomize_field(Field::Threshold, false, &mut rng);
+            config.randomize_field(Field::Strength, false, &mut rng);
+            let sim = SimState::new(&mut rng, config, 300);
+
+            for (behav, diff, cutoff_sq) in neighbor_pairs(&sim) {
+                let fused = behav.accel(diff, cutoff_sq);
+                let reference = reference_accel(&behav, diff, cutoff_sq);
+                let tolerance = reference.length() * 1e-5 + 1e-6;
+                assert!(
+                    (fused - reference).length() <= tolerance,
+                    "{} {}",
+                    fused,
+                    reference
+                );
+            }
+        }
+
+        // Coincident particles exert no force, rather than a NaN
+        let mut sim = SimState::new(&mut rng, test_config(2), 2);
+        let pos = sim.particles[0].pos;
+        sim.particles[1].pos = pos;
+        assert_eq!(
+            sim.accel_towards(1, pos, sim.particles[0].color),
+            Vec3::ZERO
+        );
+    }
+
+    /// Compare the fused and reference force paths over the neighbor pairs of a large
+    /// simulation. Run with `--release --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_force_path() {
+        let mut rng = Pcg::new();
+        let sim = SimState::new(&mut rng, test_config(3), 20_000);
+        let pairs = neighbor_pairs(&sim);
+
+        let time = |f: fn(&Behaviour, Vec3, f32) -> Vec3| {
+            let start = std::time::Instant::now();
+            let total: Vec3 = pairs.iter().map(|(b, diff, cut)| f(b, *diff, *cut)).sum();
+            std::hint::black_box(total);
+            start.elapsed().as_secs_f32() * 1e3
+        };
+        for _ in 0..3 {
+            println!(
+                "{} pairs: reference {:.2} ms, fused {:.2} ms",
+                pairs.len(),
+                time(reference_accel),
+                time(Behaviour::accel)
+            );
+        }
+    }
+
+    #[test]
+    fn test_pair_forces_match_gather() {
+        let mut rng = Pcg::new();
+        let mut config = test_config(3);
+        assert!(!config.is_symmetric());
+        config.symmetrize();
+        assert!(config.is_symmetric());
+
+        let mut sim = SimState::new(&mut rng, config, 400);
+        assert!(sim.pairwise_forces());
+        let points: Vec<Vec3> = sim.particles.iter().map(|p| p.pos).collect();
+        let accel = QueryAccelerator::new(&points, sim.max_interaction_radius);
+
+        let mut forces = vec![Vec3::ZERO; points.len()];
+        let pairs = sim.pair_forces(&accel, &points, &mut forces);
+        let mut gathered_pairs = 0;
+        let mut scale = 0.;
+        for (i, force) in forces.iter().enumerate() {
+            let (gathered, visited) = sim.pair_accel(&accel, &points, i);
+            gathered_pairs += visited;
+            scale += gathered.length();
+            assert!(
+                (*force - gathered).length() <= gathered.length() * 1e-4 + 1e-4,
+                "{} {}",
+                force,
+                gathered
+            );
+        }
+        assert!(pairs > 0);
+        // Gathering only visits the neighbors within the radius of each particle's own type
+        let within_type_radius = (0..points.len())
+            .flat_map(|i| (0..points.len()).map(move |j| (i, j)))
+            .filter(|&(i, j)| {
+                let radius = sim.type_radius[sim.particles[i].color as usize];
+                i != j && (points[j] - points[i]).length_squared() <= radius * radius
+            })
+            .count();
+        assert_eq!(gathered_pairs, within_type_radius);
+        assert!(gathered_pairs <= pairs * 2);
+
+        // Every force is cancelled by its reaction, up to rounding
+        let net: Vec3 = forces.iter().sum();
+        assert!(net.length() <= scale * 1e-5, "{} {}", net, scale);
+
+        // Stepping without damping keeps the total momentum
+        let momentum = |sim: &SimState| sim.particles.iter().map(|p| p.vel).sum::<Vec3>();
+        for _ in 0..10 {
+            sim.step(1e-3);
+        }
+        let speed: f32 = sim.particles.iter().map(|p| p.vel.length()).sum();
+        assert!(
+            momentum(&sim).length() <= speed * 1e-5,
+            "{}",
+            momentum(&sim)
+        );
+
+        // Anything making forces particle-specific falls back to gathering
+        sim.set_max_neighbors(Some(5));
+        assert!(!sim.pairwise_forces());
+        sim.set_max_neighbors(None);
+        sim.set_blend_behaviours(Some(sim.scaled.behaviours.clone()));
+        assert!(!sim.pairwise_forces());
+    }
+
+    #[test]
+    fn test_stiffness_matches_finite_differences() {
+        let mut rng = Pcg::new();
+        let mut config = test_config(3);
+        for idx in 0..9 {
+            config.randomize_cell(idx, false, &mut rng);
+        }
+        config.behaviours[0].inter_threshold = 0.;
+        config.behaviours[1].inter_max_dist = config.behaviours[1].inter_threshold;
+
+        let h = 1e-4;
+        for behav in &config.behaviours {
+            let steepest = (0..10_000)
+                .map(|k| k as f32 * h)
+                .map(|d| ((behav.interact(d + h) - behav.interact(d)) / h).abs())
+                .fold(0., f32::max);
+            let stiffness = behav.stiffness();
+            assert!(
+                (steepest - stiffness).abs() < 1e-2 * stiffness,
+                "{:?}",
+                behav
+            );
+        }
+    }
+
+    #[test]
+    fn test_pick() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 2_000);
+        sim.step(1e-3);
+
+        let brute_force = |origin: Vec3, dir: Vec3, max_dist: f32| {
+            let dir = dir.normalize();
+            (0..sim.particles().len())
+                .filter_map(|i| {
+                    let rel = sim.last_points[i] - origin;
+                    let dist = (rel - dir * rel.dot(dir)).length();
+                    (rel.dot(dir) >= 0. && dist <= max_dist).then_some((i, dist))
+                })
+                .min_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(i, _)| i)
+        };
+
+        let mut random = || Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * 2. - 1.;
+        for k in 0..100 {
+            let origin = random() * 3.;
+            let dir = random();
+            // Pick radii below and above the cell size
+            let max_dist = [1e-2, 0.05, 0.5][k % 3];
+            assert_eq!(
+                sim.pick(origin, dir, max_dist),
+                brute_force(origin, dir, max_dist)
+            );
+        }
+
+        // Nothing behind the origin, nor within reach of a ray passing the cloud by
+        assert_eq!(sim.pick(Vec3::X * 2., Vec3::X, 1.), None);
+        assert_eq!(sim.pick(Vec3::Y * 3., Vec3::X, 0.5), None);
+        assert!(sim.pick(Vec3::X * 2., Vec3::NEG_X, 0.05).is_some());
+    }
+
+    #[test]
+    fn test_far_field_unbiased() {
+        // Gentle attraction reaching across the whole cloud
+        let behav = Behaviour {
+            inter_max_dist: 1.,
+            ..Default::default()
+        };
+        let config = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![behav],
+            damping: 0.,
+            ..Default::default()
+        };
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, config, 300);
+        let far_field = FarFieldSampling::default();
+        sim.set_far_field(Some(far_field));
+        let points: Vec<Vec3> = sim.particles().iter().map(|p| p.pos).collect();
+
+        let exact: Vec3 = (1..points.len())
+            .map(|j| points[j] - points[0])
+            .filter(|diff| diff.length() > far_field.near_radius)
+            .map(|diff| behav.accel(diff, 1.))
+            .sum();
+
+        let draws = 20_000;
+        let samples: Vec<Vec3> = (0..draws)
+            .map(|_| sim.far_accel(&points, 0, far_field.k, far_field.near_radius))
+            .collect();
+        let mean = samples.iter().sum::<Vec3>() / draws as f32;
+        let variance = samples
+            .iter()
+            .map(|s| (*s - mean) * (*s - mean))
+            .sum::<Vec3>()
+            / (draws - 1) as f32;
+        let std_error = (variance / draws as f32).powf(0.5);
+        assert!(exact.length() > 0.);
+        for axis in 0..3 {
+            let error = (mean[axis] - exact[axis]).abs();
+            assert!(
+                error < 5. * std_error[axis],
+                "{} {} {}",
+                mean,
+                exact,
+                std_error
+            );
+        }
+    }
+
+    #[test]
+    fn test_internal_stream_follows_seed() {
+        let mut config = test_config(2);
+        config
+            .behaviours
+            .iter_mut()
+            .for_each(|b| b.inter_max_dist = 1.);
+        let far_samples = |rng: &mut Pcg| {
+            let mut sim = SimState::new(rng, config.clone(), 200);
+            // The same particles for every seed, so only the internal stream differs
+            let reference = SimState::new(&mut Pcg::new(), config.clone(), 200);
+            sim.particles = reference.particles;
+            let points: Vec<Vec3> = sim.particles.iter().map(|p| p.pos).collect();
+            (0..20)
+                .map(|i| sim.far_accel(&points, i, 4, 0.))
+                .collect::<Vec<Vec3>>()
+        };
+        let mut rng = Pcg::new();
+        let first = far_samples(&mut rng);
+        let second = far_samples(&mut rng);
+        assert_ne!(first, second);
+        assert_eq!(far_samples(&mut Pcg::new()), first);
+    }
+
+    #[test]
+    fn test_count_in_sphere() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 3_000);
+        sim.step(1e-3);
+
+        let brute_force = |center: Vec3, radius: f32| {
+            let mut counts = vec![0; 3];
+            for (point, particle) in sim.last_points.iter().zip(sim.particles()) {
+                if point.distance(center) <= radius {
+                    counts[particle.color as usize] += 1;
+                }
+            }
+            counts
+        };
+        // Within the query radius and beyond it
+        assert!(sim.last_accel.radius() < 0.5);
+        for (center, radius) in [
+            (Vec3::ZERO, 0.1),
+            (Vec3::new(0.9, -0.3, 0.2), 0.2),
+            (Vec3::ZERO, 0.5),
+            (Vec3::X, 1.5),
+        ] {
+            let counts = sim.count_in_sphere(center, radius);
+            assert_eq!(counts, brute_force(center, radius));
+            assert!(counts.iter().sum::<usize>() > 0);
+        }
+    }
+
+    #[test]
+    fn test_substeps() {
+        // Type 0 binds tightly at a separation of 0.05, type 1 is a soft gas
+        let stiff = Behaviour {
+            default_repulse: 1e4,
+            inter_threshold: 0.05,
+            inter_strength: 1e4,
+            inter_max_dist: 0.1,
+            anisotropy: Vec3::ONE,
+            polarity: 0.,
+            mode: InteractionMode::Potential,
+        };
+        let mut config = test_config(2);
+        config.behaviours[0] = stiff;
+        config.damping = 50.;
+        // Visiting pairs once keeps the pair from propelling itself
+        config.symmetrize();
+
+        let pair = |x: f32| Particle {
+            pos: Vec3::X * x,
+            vel: Vec3::ZERO,
+            color: 0,
+        };
+        let separation = |sim: &SimState| sim.particles()[0].pos.distance(sim.particles()[1].pos);
+        let dt = 2e-3;
+
+        let mut plain = SimState::from_particles(config.clone(), vec![pair(0.), pair(0.06)]);
+        (0..100).for_each(|_| plain.step(dt));
+        assert!(separation(&plain) > 1., "{}", separation(&plain));
+
+        let mut substepped = SimState::from_particles(config.clone(), vec![pair(0.), pair(0.06)]);
+        (0..100).for_each(|_| substepped.step_substeps(dt, &[8, 1]));
+        assert!(
+            (separation(&substepped) - 0.05).abs() < 5e-3,
+            "{}",
+            separation(&substepped)
+        );
+
+        // Types taking a single step move exactly as without substeps
+        let mut rng = Pcg::new();
+        let gas: Vec<Particle> = (0..200)
+            .map(|_| Particle {
+                color: 1,
+                ..random_particle(&mut rng, &config)
+            })
+            .collect();
+        let mut plain = SimState::from_particles(config.clone(), gas.clone());
+        let mut substepped = SimState::from_particles(config, gas);
+        for _ in 0..20 {
+            plain.step(dt);
+            substepped.step_substeps(dt, &[8, 1]);
+        }
+        for (a, b) in plain.particles().iter().zip(substepped.particles()) {
+            assert_eq!((a.pos, a.vel), (b.pos, b.vel));
+        }
+    }
+
+    /// Compare pairwise and gathered forces on a large symmetric simulation. Run with
+    /// `--release --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn bench_pair_forces() {
+        let mut rng = Pcg::new();
+        let mut config = test_config(3);
+        config.symmetrize();
+        let mut sim = SimState::new(&mut rng, config, 20_000);
+        let points: Vec<Vec3> = sim.particles.iter().map(|p| p.pos).collect();
+        let accel = QueryAccelerator::new(&points, sim.max_interaction_radius);
+
+        for _ in 0..3 {
+            let start = std::time::Instant::now();
+            let mut forces = vec![Vec3::ZERO; points.len()];
+            let pairs = sim.pair_forces(&accel, &points, &mut forces);
+            std::hint::black_box(forces);
+            let pairwise_ms = start.elapsed().as_secs_f32() * 1e3;
+
+            let start = std::time::Instant::now();
+            let mut gathered = 0;
+            for i in 0..points.len() {
+                let (force, visited) = sim.pair_accel(&accel, &points, i);
+                std::hint::black_box(force);
+                gathered += visited;
+            }
+            let gather_ms = start.elapsed().as_secs_f32() * 1e3;
+
+            println!(
+                "pairwise: {} evaluations, {:.2} ms; gather: {} evaluations, {:.2} ms",
+                pairs, pairwise_ms, gathered, gather_ms
+            );
+        }
+    }
+
+    #[test]
+    fn test_tables_follow_config() {
+        let mut rng = Pcg::new();
+        let mut sim = SimState::new(&mut rng, test_config(3), 500);
+        sim.set_use_tables(Some(1e-3));
+        assert!(sim.tables().unwrap().matches(&sim.scaled.behaviours));
+
+        // Energies and forces agree with the analytic ones
+        let energy = |sim: &SimState| -> Vec<f32> {
+            (0..sim.particles.len())
+                .map(|i| sim.energy_due_to(i, sim.particles[i].pos))
+                .collect()
+        };
+        let tabled = energy(&sim);
+        let tabled_accel = sim.accel_towards(0, sim.particles[0].pos + Vec3::X * 0.1, 1);
+        sim.set_use_tables(None);
+        assert!(sim.tables().is_none());
+        for (a, b) in tabled.iter().zip(energy(&sim)) {
+            assert!((a - b).abs() <= 1e-2 * b.abs().max(1.), "{} {}", a, b);
+        }
+        let accel = sim.accel_towards(0, sim.particles[0].pos + Vec3::X * 0.1, 1);
+        assert!(tabled_accel.distance(accel) <= 1e-2 * accel.length().max(1.));
+
+        // Any edit of the matrix resamples them, and blending sets them aside
+        sim.set_use_tables(Some(1e-3));
+        let mut config = sim.config.clone();
+        config.behaviours[4].inter_strength += 1.;
+        sim.set_config(config, &mut rng);
+        assert!(sim.tables().unwrap().matches(&sim.scaled.behaviours));
+        sim.set_blend_behaviours(Some(sim.config.behaviours.clone()));
+        assert!(sim.tables().is_none());
+        sim.set_blend_behaviours(None);
+        assert!(sim.tables().is_some());
+    }
+
+    fn test_config(n: usize) -> SimConfig {
+        let behaviours = (0..n * n)
+            .map(|i| Behaviour {
+                default_repulse: 10. + i as f32,
+                inter_threshold: 0.01 * i as f32,
+                inter_strength: -3.5 + 1.25 * i as f32,
+                inter_max_dist: 0.2 + 0.015 * i as f32,
+                anisotropy: Vec3::ONE,
+                polarity: 0.,
+                mode: InteractionMode::Potential,
+            })
+            .collect();
+        SimConfig {
+            colors: vec![[1.; 3]; n],
+            behaviours,
+            damping: 0.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_csv_round_trip() {
+        let cfg = test_config(3);
+        for field in Field::ALL {
+            let csv = cfg.field_to_csv(field);
+            let mut other = test_config(3);
+            other
+                .behaviours
+                .iter_mut()
+                .for_each(|b| *field.get_mut(b) = 0.);
+            other.apply_csv_to_field(field, &csv).unwrap();
+            for (a, b) in cfg.behaviours.iter().zip(&other.behaviours) {
+                assert_eq!(field.get(a), field.get(b));
+            }
+        }
+    }
+
+    #[test]
+    fn test_csv_accepts_tsv() {
+        let mut cfg = test_config(2);
+        cfg.apply_csv_to_field(Field::Strength, " 1\t2 \n3\t -4\n\n")
+            .unwrap();
+        let strengths: Vec<f32> = cfg.behaviours.iter().map(|b| b.inter_strength).collect();
+        assert_eq!(strengths, vec![1., 2., 3., -4.]);
+    }
+
+    #[test]
+    fn test_csv_rejects_wrong_dimensions() {
+        let mut cfg = test_config(3);
+        let before = cfg.field_to_csv(Field::Repulse);
+        let err = cfg.apply_csv_to_field(Field::Repulse, "1,2,3,4\n5,6,7,8\n9,10,11,12\n");
+        assert_eq!(
+            err,
+            Err(ConfigError::Dimensions {
+                expected: 3,
+                rows: 3,
+                cols: 4
+            })
+        );
+        assert_eq!(cfg.field_to_csv(Field::Repulse), before);
+    }
+}
+
+impl Default for Behaviour {
+    fn default() -> Self {
+        Self {
+            default_repulse: 10.,
+            inter_threshold: 0.02,
+            inter_strength: 1.,
+            inter_max_dist: 0.2,
+            anisotropy: Vec3::ONE,
+            polarity: 0.,
+            mode: InteractionMode::Potential,
+        }
+    }
+}
+
+impl Behaviour {
+    /// Per-field linear interpolation towards `other`
+    pub fn lerp(&self, other: &Behaviour, t: f32) -> Behaviour {
+        let lerp = |a: f32, b: f32| a + (b - a) * t;
+        Behaviour {
+            default_repulse: lerp(self.default_repulse, other.default_repulse),
+            inter_threshold: lerp(self.inter_threshold, other.inter_threshold),
+            inter_strength: lerp(self.inter_strength, other.inter_strength),
+            inter_max_dist: lerp(self.inter_max_dist, other.inter_max_dist),
+            anisotropy: self.anisotropy.lerp(other.anisotropy, t),
+            polarity: lerp(self.polarity, other.polarity),
+            mode: match (self.mode, other.mode) {
+                (
+                    InteractionMode::HardSphere { radius: a },
+                    InteractionMode::HardSphere { radius: b },
+                ) => InteractionMode::HardSphere { radius: lerp(a, b) },
+                // Modes can't be mixed, so switch halfway
+                (a, b) => match t < 0.5 {
+                    true => a,
+                    false => b,
+                },
+            },
+        }
+    }
+
+    /// Re-roll one field within its sensible range, see [`Field::random_range`]
+    pub fn randomize_field(&mut self, field: Field, rng: &mut Pcg) {
+        let (min, max) = field.random_range(self);
+        *field.get_mut(self) = min + (max - min) * rng.gen_f32();
+    }
+
+    pub fn qualitative_class(&self) -> Qualitative {
+        Qualitative::classify(self.inter_strength)
+    }
+
+    /// Move the strength to the next qualitative class, leaving other fields untouched
+    pub fn cycle_qualitative(&mut self, forward: bool) {
+        self.inter_strength = self.qualitative_class().cycle(forward).strength();
+    }
+
+    /// This behaviour with its distances multiplied by `scale`: the threshold, cutoff and hard
+    /// sphere radius
+    pub fn scaled(&self, scale: f32) -> Behaviour {
+        let mode = match self.mode {
+            InteractionMode::HardSphere { radius } => InteractionMode::HardSphere {
+                radius: radius * scale,
+            },
+            mode => mode,
+        };
+        Behaviour {
+            inter_threshold: self.inter_threshold * scale,
+            inter_max_dist: self.inter_max_dist * scale,
+            mode,
+            ..*self
         }
     }
-}
 
-impl Behaviour {
     pub fn with_inter_strength(mut self, inter_strength: f32) -> Self {
         self.inter_strength = inter_strength;
         self
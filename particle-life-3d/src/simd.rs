@@ -0,0 +1,266 @@
+//! Pair force kernel processing [`LANES`] neighbors at a time, with the `simd` feature. See
+//! [`crate::sim::SimState::set_use_simd`].
+//!
+//! Positions are split into separate x, y and z arrays, so that the neighbors of a particle
+//! load into one register per axis. The piecewise-linear kernel of [`Behaviour::interact`] is
+//! evaluated without branches: the attractive tent is clamped at zero beyond the max distance,
+//! and the repulsive core is blended in below the threshold. The scalar path in
+//! [`crate::sim`] remains the reference this must agree with.
+use cimvr_common::glam::Vec3;
+use wide::{f32x8, CmpGt, CmpLe, CmpLt};
+
+use crate::{
+    query_accel::QueryAccelerator,
+    sim::{Behaviour, Particle, SimConfig},
+};
+
+/// Neighbors processed together
+pub const LANES: usize = 8;
+
+/// Coefficients of every pair of types, indexed like `SimConfig::behaviours`, with the
+/// divisions of the kernel done up front
+struct PairCoefficients {
+    threshold: Vec<f32>,
+    /// Reciprocal of the threshold, or zero without a core
+    inv_threshold: Vec<f32>,
+    /// Reciprocal of the distance from threshold to max distance, or zero if they meet
+    inv_span: Vec<f32>,
+    strength: Vec<f32>,
+    repulse: Vec<f32>,
+    anisotropy: Vec<Vec3>,
+    cutoff_sq: Vec<f32>,
+}
+
+/// Add the acceleration of every particle due to its neighbors at `points` to `forces`,
+/// gathering the forces on each particle separately, and return the number of pairs visited.
+/// `cutoff_sq` holds the squared cutoff of each pair, like [`SimConfig::cutoff_sq_table`].
+pub fn gather_forces(
+    config: &SimConfig,
+    cutoff_sq: &[f32],
+    particles: &[Particle],
+    accel: &QueryAccelerator,
+    points: &[Vec3],
+    forces: &mut [Vec3],
+) -> usize {
+    let n_colors = config.colors.len();
+    let coeffs = PairCoefficients::new(&config.behaviours, cutoff_sq);
+    let xs: Vec<f32> = points.iter().map(|p| p.x).collect();
+    let ys: Vec<f32> = points.iter().map(|p| p.y).collect();
+    let zs: Vec<f32> = points.iter().map(|p| p.z).collect();
+
+    let mut neighbors: Vec<usize> = vec![];
+    let mut visited = 0;
+    for i in 0..points.len() {
+        neighbors.clear();
+        neighbors.extend(accel.query_neighbors(points, i));
+        visited += neighbors.len();
+
+        let row = particles[i].color as usize * n_colors;
+        let mut total = [f32x8::ZERO; 3];
+        for chunk in neighbors.chunks(LANES) {
+            // Unused lanes are left coincident with `i`, which exerts no force
+            let mut lanes = Lanes::default();
+            for (lane, &j) in chunk.iter().enumerate() {
+                let pair = row + particles[j].color as usize;
+                let anisotropy = coeffs.anisotropy[pair];
+                lanes.diff[0][lane] = (xs[j] - xs[i]) * anisotropy.x;
+                lanes.diff[1][lane] = (ys[j] - ys[i]) * anisotropy.y;
+                lanes.diff[2][lane] = (zs[j] - zs[i]) * anisotropy.z;
+                lanes.threshold[lane] = coeffs.threshold[pair];
+                lanes.inv_threshold[lane] = coeffs.inv_threshold[pair];
+                lanes.inv_span[lane] = coeffs.inv_span[pair];
+                lanes.strength[lane] = coeffs.strength[pair];
+                lanes.repulse[lane] = coeffs.repulse[pair];
+                lanes.cutoff_sq[lane] = coeffs.cutoff_sq[pair];
+            }
+            let scale = lanes.force_over_dist_sq();
+            for (total, diff) in total.iter_mut().zip(lanes.diff) {
+                *total += f32x8::new(diff) * scale;
+            }
+        }
+        let [x, y, z] = total.map(f32x8::reduce_add);
+        forces[i] += Vec3::new(x, y, z);
+    }
+    visited
+}
+
+/// Inputs of the kernel for one batch of neighbors
+#[derive(Default)]
+struct Lanes {
+    diff: [[f32; LANES]; 3],
+    threshold: [f32; LANES],
+    inv_threshold: [f32; LANES],
+    inv_span: [f32; LANES],
+    strength: [f32; LANES],
+    repulse: [f32; LANES],
+    cutoff_sq: [f32; LANES],
+}
+
+impl Lanes {
+    /// [`Behaviour::interact`] over distance squared in each lane, the factor the difference
+    /// is scaled by; zero beyond the cutoff and for coincident particles
+    fn force_over_dist_sq(&self) -> f32x8 {
+        let [x, y, z] = self.diff.map(f32x8::new);
+        let dist_sq = x * x + y * y + z * z;
+        let dist = dist_sq.sqrt();
+        let threshold = f32x8::new(self.threshold);
+
+        // Tent rising from the threshold and falling back to zero at the max distance
+        let x = (dist - threshold) * f32x8::new(self.inv_span);
+        let tent = (f32x8::ONE - (x * 2. - 1.).abs()).max(f32x8::ZERO);
+        let attract = tent * f32x8::new(self.strength);
+
+        let core = (dist * f32x8::new(self.inv_threshold) - 1.) * f32x8::new(self.repulse);
+        let force = dist.cmp_lt(threshold).blend(core, attract);
+
+        let live = dist_sq.cmp_gt(f32x8::ZERO) & dist_sq.cmp_le(f32x8::new(self.cutoff_sq));
+        live.blend(force / dist_sq, f32x8::ZERO)
+    }
+}
+
+impl PairCoefficients {
+    fn new(behaviours: &[Behaviour], cutoff_sq: &[f32]) -> Self {
+        let recip_or_zero = |x: f32| if x > 0. { x.recip() } else { 0. };
+        Self {
+            threshold: behaviours.iter().map(|b| b.inter_threshold).collect(),
+            inv_threshold: behaviours
+                .iter()
+                .map(|b| recip_or_zero(b.inter_threshold))
+                .collect(),
+            inv_span: behaviours
+                .iter()
+                .map(|b| recip_or_zero(b.inter_max_dist - b.inter_threshold))
+                .collect(),
+            strength: behaviours.iter().map(|b| b.inter_strength).collect(),
+            repulse: behaviours.iter().map(|b| b.default_repulse).collect(),
+            anisotropy: behaviours.iter().map(|b| b.anisotropy).collect(),
+            cutoff_sq: cutoff_sq.to_vec(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{PositionReset, RebuildSpec, SimState};
+    use cimvr_engine_interface::pcg::Pcg;
+
+    fn random_config(n: usize, symmetric: bool, rng: &mut Pcg) -> SimConfig {
+        let mut config = SimConfig {
+            colors: vec![[1.; 3]; n],
+            behaviours: vec![Behaviour::default(); n * n],
+            damping: 0.,
+            ..Default::default()
+        };
+        for idx in 0..n * n {
+            config.randomize_cell(idx, symmetric, rng);
+        }
+        config
+    }
+
+    /// Acceleration of particle `i` at `start` by the scalar kernel, and a bound on its
+    /// rounding error, which grows with the number and magnitudes of the terms summed rather
+    /// than with their sum, which may cancel. A few more roundings are allowed for each term
+    /// and for turning the sum into a velocity.
+    fn scalar_accel(sim: &SimState, start: &[Particle], i: usize) -> (Vec3, f32) {
+        let n = sim.config().colors.len();
+        let mut total = Vec3::ZERO;
+        let mut magnitudes = 0.;
+        let mut terms = 0;
+        for j in sim.neighbors(i) {
+            let behav =
+                sim.config().behaviours[start[i].color as usize * n + start[j].color as usize];
+            let diff = (start[j].pos - start[i].pos) * behav.anisotropy;
+            let dist_sq = diff.length_squared();
+            if dist_sq > 0. && dist_sq <= behav.inter_max_dist * behav.inter_max_dist {
+                let term = diff * (behav.interact(dist_sq.sqrt()) / dist_sq);
+                total += term;
+                magnitudes += term.length();
+                terms += 1;
+            }
+        }
+        (total, f32::EPSILON * (terms + 4) as f32 * magnitudes)
+    }
+
+    #[test]
+    fn test_simd_matches_scalar() {
+        let mut rng = Pcg::new();
+        let mut configs = vec![
+            random_config(4, true, &mut rng),
+            random_config(4, false, &mut rng),
+        ];
+
+        // Stretched metrics, no repulsive core, and a core reaching the max distance
+        let mut edge_cases = random_config(3, false, &mut rng);
+        edge_cases.behaviours[0].anisotropy = Vec3::new(2., 1., 0.5);
+        edge_cases.behaviours[1].inter_threshold = 0.;
+        edge_cases.behaviours[2].inter_threshold = edge_cases.behaviours[2].inter_max_dist;
+        configs.push(edge_cases);
+
+        // From rest and without damping, velocities after a step are the forces times `dt`
+        let dt = 1e-3;
+        for config in configs {
+            let mut scalar = SimState::new(&mut rng, config, 2000);
+            let start = scalar.particles().to_vec();
+            let mut lanes = SimState::from_particles(scalar.config().clone(), start.clone());
+            lanes.set_use_simd(true);
+            assert!(lanes.uses_simd() && !scalar.uses_simd());
+            lanes.step(dt);
+
+            let mut moved = 0;
+            for (i, particle) in lanes.particles().iter().enumerate() {
+                let (expected, bound) = scalar_accel(&lanes, &start, i);
+                let error = (particle.vel / dt - expected).length();
+                assert!(
+                    error <= 2. * bound,
+                    "{} {} {}",
+                    particle.vel / dt,
+                    expected,
+                    i
+                );
+                moved += (expected != Vec3::ZERO) as usize;
+            }
+            assert!(moved > 1000);
+
+            // The pairwise scalar path also sees every particle at the start of the step
+            if scalar.pairwise_forces() {
+                scalar.step(dt);
+                for (i, (s, l)) in scalar.particles().iter().zip(lanes.particles()).enumerate() {
+                    let (_, bound) = scalar_accel(&lanes, &start, i);
+                    assert!((s.vel - l.vel).length() <= 2. * bound * dt, "{}", i);
+                }
+            }
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_simd_forces() {
+        // Run with --release for meaningful numbers
+        let mut rng = Pcg::new();
+        let config = random_config(5, false, &mut rng);
+        let mut sim = SimState::new(&mut rng, config, 50_000);
+        let spec = RebuildSpec {
+            positions: PositionReset::Scatter(4.),
+            ..Default::default()
+        };
+        sim.rebuild(spec, &mut rng);
+
+        for _ in 0..3 {
+            let mut time = |simd: bool| {
+                sim.set_use_simd(simd);
+                let start = std::time::Instant::now();
+                sim.step(1e-4);
+                start.elapsed().as_secs_f32() * 1e3
+            };
+            let scalar_ms = time(false);
+            let simd_ms = time(true);
+            println!(
+                "scalar {:.2} ms, simd {:.2} ms, speedup {:.2}x",
+                scalar_ms,
+                simd_ms,
+                scalar_ms / simd_ms
+            );
+        }
+    }
+}
@@ -0,0 +1,147 @@
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::sim::SimConfig;
+
+/// Anyone to client: keep the configuration in a slot for the session, or bring one back.
+/// Loading goes through the same staging as any other configuration edit, so a slot with a
+/// different number of types adapts the particles rather than resetting them.
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub enum SlotCommand {
+    /// Save the configuration into a new slot with this name
+    Save(String),
+    Load(usize),
+    /// Save the configuration over a slot, keeping its name
+    Overwrite(usize),
+    Delete(usize),
+    /// Print the slots, marking the loaded one
+    List,
+}
+
+/// Named configurations kept for the duration of the session, for quick switching
+#[derive(Default)]
+pub struct ConfigSlots {
+    slots: Vec<(String, SimConfig)>,
+    loaded: Option<usize>,
+}
+
+impl ConfigSlots {
+    /// Save a configuration into a new slot, returning its index
+    pub fn save(&mut self, name: String, config: SimConfig) -> usize {
+        self.slots.push((name, config));
+        self.loaded = Some(self.slots.len() - 1);
+        self.slots.len() - 1
+    }
+
+    /// Replace the configuration stored in a slot, keeping its name
+    pub fn overwrite(&mut self, idx: usize, config: SimConfig) {
+        if let Some((_, slot)) = self.slots.get_mut(idx) {
+            *slot = config;
+        }
+    }
+
+    pub fn delete(&mut self, idx: usize) {
+        if idx >= self.slots.len() {
+            return;
+        }
+
+        self.slots.remove(idx);
+        self.loaded = match self.loaded {
+            Some(loaded) if loaded == idx => None,
+            Some(loaded) if loaded > idx => Some(loaded - 1),
+            other => other,
+        };
+    }
+
+    /// Mark a slot as loaded and return a copy of its configuration
+    pub fn load(&mut self, idx: usize) -> Option<SimConfig> {
+        let (_, config) = self.slots.get(idx)?;
+        self.loaded = Some(idx);
+        Some(config.clone())
+    }
+
+    /// The most recently loaded or saved slot
+    pub fn loaded(&self) -> Option<usize> {
+        self.loaded
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &SimConfig)> {
+        self.slots.iter().map(|(name, cfg)| (name.as_str(), cfg))
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// One line per slot with its index and name, the loaded slot marked with `*`
+    pub fn listing(&self) -> String {
+        let mut listing = String::new();
+        for (idx, (name, config)) in self.iter().enumerate() {
+            let mark = if self.loaded == Some(idx) { '*' } else { ' ' };
+            listing += &format!(
+                "{} {}: {} ({} types)\n",
+                mark,
+                idx,
+                name,
+                config.colors.len()
+            );
+        }
+        listing
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Behaviour, SimState};
+    use cimvr_engine_interface::pcg::Pcg;
+
+    fn config(n: usize) -> SimConfig {
+        SimConfig {
+            colors: vec![[1.; 3]; n],
+            behaviours: vec![Behaviour::default(); n * n],
+            damping: 150.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_slot_bookkeeping() {
+        let mut slots = ConfigSlots::default();
+        slots.save("crystal".into(), config(2));
+        slots.save("swarm".into(), config(5));
+        slots.save("other".into(), config(3));
+        assert_eq!(slots.loaded(), Some(2));
+
+        assert_eq!(slots.load(1).unwrap().colors.len(), 5);
+        slots.delete(0);
+        assert_eq!(slots.loaded(), Some(0));
+        slots.delete(0);
+        assert_eq!(slots.loaded(), None);
+        assert!(slots.load(5).is_none());
+
+        let names: Vec<&str> = slots.iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["other"]);
+        assert_eq!(slots.listing(), "  0: other (3 types)\n");
+        slots.load(0);
+        assert_eq!(slots.listing(), "* 0: other (3 types)\n");
+    }
+
+    #[test]
+    fn test_load_fewer_types() {
+        let mut rng = Pcg::new();
+        let mut slots = ConfigSlots::default();
+        slots.save("small".into(), config(2));
+
+        let mut sim = SimState::new(&mut rng, config(6), 1000);
+        sim.step(1e-3);
+        sim.set_config(slots.load(0).unwrap(), &mut rng);
+        assert!(sim.particles().iter().all(|p| p.color < 2));
+        sim.step(1e-3);
+    }
+}
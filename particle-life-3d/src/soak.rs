@@ -0,0 +1,276 @@
+//! Developer soak test: keeps changing the settings at random while checking invariants, and
+//! captures a reproducible failure bundle when one breaks.
+//!
+//! Enabled with the `soak` feature. The checks look at a few particles each frame, so they
+//! can run continuously; over time every particle is covered.
+use std::collections::VecDeque;
+
+use cimvr_engine_interface::pcg::Pcg;
+
+use crate::{
+    persist::{SimSettings, SimSnapshot},
+    sim::{Field, SimState},
+};
+
+/// Number of actions kept for the failure bundle
+const ACTION_LOG_LEN: usize = 20;
+
+#[derive(Clone, Debug)]
+pub struct SoakConfig {
+    /// Frames between randomizations
+    pub interval_frames: usize,
+    /// Range of particle counts to pick from
+    pub min_particles: usize,
+    pub max_particles: usize,
+    /// Particles checked for finite positions and velocities each frame
+    pub samples: usize,
+}
+
+/// One random change made by the soak test
+#[derive(Clone, Debug, PartialEq)]
+pub enum SoakAction {
+    RandomizeField(Field),
+    Jitter(f32),
+    Constrain2d(bool),
+    Particles(usize),
+}
+
+/// Everything needed to turn a soak failure into a regression test
+#[derive(Clone, Debug)]
+pub struct SoakFailure {
+    pub violation: String,
+    /// Snapshot taken right after the last randomization
+    pub snapshot: SimSnapshot,
+    /// Steps taken from the snapshot until the failure
+    pub steps: usize,
+    /// Most recent actions, oldest first
+    pub actions: Vec<SoakAction>,
+}
+
+pub struct SoakTest {
+    pub config: SoakConfig,
+    until_randomize: usize,
+    /// Particle to check next
+    cursor: usize,
+    actions: VecDeque<SoakAction>,
+    snapshot: Option<SimSnapshot>,
+    steps: usize,
+}
+
+impl SoakTest {
+    pub fn new(config: SoakConfig) -> Self {
+        Self {
+            until_randomize: config.interval_frames,
+            config,
+            cursor: 0,
+            actions: VecDeque::new(),
+            snapshot: None,
+            steps: 0,
+        }
+    }
+
+    /// Call after each step. Checks the invariants, and randomizes the simulation when it is
+    /// time to.
+    pub fn tick(&mut self, sim: &mut SimState, rng: &mut Pcg) -> Result<(), Box<SoakFailure>> {
+        match self.snapshot {
+            None => self.snapshot = Some(SimSnapshot::capture(sim)),
+            Some(_) => self.steps += 1,
+        }
+
+        if let Err(violation) = self.check(sim) {
+            return Err(Box::new(self.fail(violation)));
+        }
+
+        if self.until_randomize == 0 {
+            self.until_randomize = self.config.interval_frames;
+            self.randomize(sim, rng);
+        } else {
+            self.until_randomize -= 1;
+        }
+        Ok(())
+    }
+
+    /// Failure bundle for a problem found elsewhere, e.g. a panic during the step
+    pub fn fail(&self, violation: String) -> SoakFailure {
+        SoakFailure {
+            violation,
+            snapshot: self
+                .snapshot
+                .clone()
+                .expect("Snapshot is taken on the first tick"),
+            steps: self.steps,
+            actions: self.actions.iter().cloned().collect(),
+        }
+    }
+
+    fn check(&mut self, sim: &SimState) -> Result<(), String> {
+        let particles = sim.particles();
+        if particles.is_empty() {
+            return Ok(());
+        }
+
+        let energy: f32 = particles.iter().map(|p| p.vel.length_squared() / 2.).sum();
+        if !energy.is_finite() {
+            return Err(format!("Kinetic energy is {}", energy));
+        }
+
+        for _ in 0..self.config.samples {
+            self.cursor = (self.cursor + 1) % particles.len();
+            let p = particles[self.cursor];
+            if !p.pos.is_finite() || !p.vel.is_finite() {
+                return Err(format!(
+                    "Particle {} is at {} with velocity {}",
+                    self.cursor, p.pos, p.vel
+                ));
+            }
+        }
+
+        // The spot check costs a pass over all particles, so only one per frame
+        if !sim.accel_consistent(self.cursor) {
+            return Err(format!(
+                "Accelerator disagrees about the neighbors of particle {}",
+                self.cursor
+            ));
+        }
+        sim.check_invariants()
+    }
+
+    fn randomize(&mut self, sim: &mut SimState, rng: &mut Pcg) {
+        let mut settings = SimSettings::from_sim(sim);
+
+        let mut actions = vec![];
+        let field = Field::ALL[rng.gen_u32() as usize % Field::ALL.len()];
+        settings.config.randomize_field(field, false, rng);
+        actions.push(SoakAction::RandomizeField(field));
+
+        if rng.gen_f32() < 0.5 {
+            let fraction = rng.gen_f32() * 0.5;
+            settings.config.jitter(fraction, false, rng);
+            actions.push(SoakAction::Jitter(fraction));
+        }
+        if rng.gen_f32() < 0.25 {
+            settings.constrain_2d = !settings.constrain_2d;
+            actions.push(SoakAction::Constrain2d(settings.constrain_2d));
+        }
+        if rng.gen_f32() < 0.25 {
+            let span = self
+                .config
+                .max_particles
+                .saturating_sub(self.config.min_particles);
+            settings.n_particles =
+                self.config.min_particles + (rng.gen_f32() * span as f32) as usize;
+            actions.push(SoakAction::Particles(settings.n_particles));
+        }
+
+        if settings.n_particles == sim.particles().len() {
+            sim.set_config(settings.config.clone(), rng);
+            settings.apply_options(sim, rng);
+        } else {
+            *sim = settings.build(rng);
+        }
+
+        for action in actions {
+            if self.actions.len() == ACTION_LOG_LEN {
+                self.actions.pop_front();
+            }
+            self.actions.push_back(action);
+        }
+        self.snapshot = Some(SimSnapshot::capture(sim));
+        self.steps = 0;
+    }
+}
+
+impl SoakFailure {
+    /// Human readable bundle, for copying out of the log
+    pub fn report(&self) -> String {
+        let mut report = format!("Soak test failed: {}\n", self.violation);
+        report += &format!("Steps since snapshot: {}\n", self.steps);
+        report += "Recent actions:\n";
+        for action in &self.actions {
+            report += &format!("  {:?}\n", action);
+        }
+        report += &format!("Snapshot: {}\n", self.snapshot.to_hex());
+        report
+    }
+}
+
+impl Default for SoakConfig {
+    fn default() -> Self {
+        Self {
+            interval_frames: 600,
+            min_particles: 500,
+            max_particles: 8_000,
+            samples: 64,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Behaviour, SimConfig};
+    use cimvr_common::glam::Vec3;
+
+    fn sim(rng: &mut Pcg) -> SimState {
+        let config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 50.,
+            ..Default::default()
+        };
+        SimState::new(rng, config, 100)
+    }
+
+    #[test]
+    fn test_soak_randomizes_and_logs() {
+        let mut rng = Pcg::new();
+        let mut sim = sim(&mut rng);
+        let mut soak = SoakTest::new(SoakConfig {
+            interval_frames: 2,
+            min_particles: 50,
+            max_particles: 150,
+            samples: 16,
+        });
+
+        for _ in 0..100 {
+            sim.step(1e-3);
+            soak.tick(&mut sim, &mut rng).unwrap();
+        }
+        assert_eq!(soak.actions.len(), ACTION_LOG_LEN);
+        assert!((50..=150).contains(&sim.particles().len()));
+    }
+
+    #[test]
+    fn test_soak_failure_replays() {
+        let mut rng = Pcg::new();
+        let mut sim = sim(&mut rng);
+        let mut soak = SoakTest::new(SoakConfig::default());
+        soak.tick(&mut sim, &mut rng).unwrap();
+        sim.step(1e-3);
+        soak.tick(&mut sim, &mut rng).unwrap();
+        let expected = sim.particles()[0];
+
+        // Corrupt a particle the checks will get to
+        sim.step(1e-3);
+        let n = sim.particles().len();
+        let mut particles = sim.particles().to_vec();
+        particles[n / 2].vel = Vec3::NAN;
+        let mut corrupt = SimState::from_particles(sim.config().clone(), particles);
+
+        let failure = (0..n)
+            .find_map(|_| soak.tick(&mut corrupt, &mut rng).err())
+            .unwrap();
+        assert!(failure.violation.contains("energy"));
+        assert_eq!(failure.steps, 2);
+        assert!(failure.report().contains("Snapshot: "));
+
+        // The bundle replays the run from the snapshot
+        let snapshot = SimSnapshot::from_hex(&failure.snapshot.to_hex()).unwrap();
+        assert_eq!(snapshot, failure.snapshot);
+        let mut replay = snapshot.restore(&mut rng);
+        replay.step(1e-3);
+        assert_eq!(replay.particles()[0], expected);
+
+        assert!(SimSnapshot::from_hex("zz").is_err());
+    }
+}
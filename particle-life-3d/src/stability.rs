@@ -0,0 +1,305 @@
+//! Static analysis of configurations which are doomed before the first step: pairs which
+//! collapse to a point, and chases which nothing slows down. See [`SimConfig::stability_report`].
+use std::fmt;
+
+use crate::sim::{Behaviour, SimConfig};
+
+/// Distances at which the force between a pair is sampled, out to its reach
+const SAMPLES: usize = 512;
+
+/// Peak attraction, relative to the strongest push of the repulsive core, beyond which a crowd
+/// of attracting neighbors can squeeze a pair through its core
+pub const COLLAPSE_RATIO: f32 = 1.;
+
+/// How bad a warning is, for coloring its icon
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    /// Likely to misbehave
+    Caution,
+    /// Certain to blow up or collapse
+    Danger,
+}
+
+/// Something about a configuration which will make it blow up or collapse
+#[derive(Clone, Debug, PartialEq)]
+pub enum StabilityWarning {
+    /// The pair attracts, but the force never turns from repulsive inside to attractive
+    /// outside, so there is no separation at which it can rest and it falls together
+    NoEquilibrium { a: usize, b: usize },
+    /// The pull of the pair at its strongest is `ratio` times the strongest push of its core
+    Collapse { a: usize, b: usize, ratio: f32 },
+    /// Beyond their cores, `chaser` is drawn towards `target` while `target` is driven away,
+    /// so without damping the pair accelerates forever
+    Runaway { chaser: usize, target: usize },
+    /// Each type is drawn towards the next around the loop more than the next is drawn back,
+    /// and without damping the loop keeps pumping energy in
+    ChaseCycle { types: [usize; 3] },
+}
+
+impl Severity {
+    /// Color of the icon of a warning: yellow for caution, red for danger
+    pub fn color(&self) -> [f32; 3] {
+        match self {
+            Severity::Caution => [1., 0.8, 0.],
+            Severity::Danger => [1., 0.15, 0.1],
+        }
+    }
+}
+
+impl StabilityWarning {
+    pub fn severity(&self) -> Severity {
+        match self {
+            StabilityWarning::NoEquilibrium { .. } | StabilityWarning::Runaway { .. } => {
+                Severity::Danger
+            }
+            StabilityWarning::Collapse { .. } | StabilityWarning::ChaseCycle { .. } => {
+                Severity::Caution
+            }
+        }
+    }
+
+    /// Cells of the behaviour matrix responsible, as acting and acted upon type
+    pub fn cells(&self) -> Vec<(usize, usize)> {
+        match *self {
+            StabilityWarning::NoEquilibrium { a, b } | StabilityWarning::Collapse { a, b, .. } => {
+                vec![(a, b), (b, a)]
+            }
+            StabilityWarning::Runaway { chaser, target } => {
+                vec![(chaser, target), (target, chaser)]
+            }
+            StabilityWarning::ChaseCycle { types: [a, b, c] } => vec![(a, b), (b, c), (c, a)],
+        }
+    }
+}
+
+impl fmt::Display for StabilityWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StabilityWarning::NoEquilibrium { a, b } => write!(
+                f,
+                "Types {} and {} attract with nothing to hold them apart, and collapse to a point",
+                a, b
+            ),
+            StabilityWarning::Collapse { a, b, ratio } => write!(
+                f,
+                "Types {} and {} pull {:.1}x harder than their core pushes back, and may collapse",
+                a, b, ratio
+            ),
+            StabilityWarning::Runaway { chaser, target } => write!(
+                f,
+                "Type {} chases type {}, which flees, and without damping they accelerate forever",
+                chaser, target
+            ),
+            StabilityWarning::ChaseCycle { types: [a, b, c] } => write!(
+                f,
+                "Types {} -> {} -> {} -> {} chase each other in a loop, which without damping \
+                 keeps gaining energy",
+                a, b, c, a
+            ),
+        }
+    }
+}
+
+impl SimConfig {
+    /// Warnings about pairs of types, and loops of them, which will certainly blow up or
+    /// collapse, found from the behaviour matrix alone. Anisotropy, polarity and the fast
+    /// behaviours are not taken into account, and hard sphere pairs are always stable.
+    pub fn stability_report(&self) -> Vec<StabilityWarning> {
+        let n = self.colors.len();
+        let mut warnings = vec![];
+        for a in 0..n {
+            for b in a..n {
+                let pair = [self.behaviours[a * n + b], self.behaviours[b * n + a]];
+                if pair.iter().any(|behav| behav.hard_radius().is_some()) {
+                    continue;
+                }
+                warnings.extend(pair_warning(a, b, pair));
+            }
+        }
+
+        // Only damping bounds the speed of a chase
+        if self.damping > 0. {
+            return warnings;
+        }
+        let chase = |a: usize, b: usize| {
+            pull(&self.behaviours[a * n + b]) - pull(&self.behaviours[b * n + a])
+        };
+        for a in 0..n {
+            for b in (0..n).filter(|&b| b != a) {
+                let flees = pull(&self.behaviours[b * n + a]) < 0.;
+                if pull(&self.behaviours[a * n + b]) > 0. && flees {
+                    warnings.push(StabilityWarning::Runaway {
+                        chaser: a,
+                        target: b,
+                    });
+                }
+            }
+        }
+        // Each loop once, starting from its lowest type
+        for a in 0..n {
+            for b in (a + 1..n).filter(|&b| chase(a, b) > 0.) {
+                for c in (a + 1..n).filter(|&c| c != b) {
+                    if chase(b, c) > 0. && chase(c, a) > 0. {
+                        warnings.push(StabilityWarning::ChaseCycle { types: [a, b, c] });
+                    }
+                }
+            }
+        }
+        warnings
+    }
+}
+
+/// Pull of a behaviour beyond its repulsive core, negative for a push
+fn pull(behav: &Behaviour) -> f32 {
+    match behav.inter_max_dist > behav.inter_threshold {
+        true => behav.inter_strength,
+        false => 0.,
+    }
+}
+
+/// Warning about the relative motion of a pair of types, which feel the sum of their
+/// behaviours towards each other
+fn pair_warning(a: usize, b: usize, pair: [Behaviour; 2]) -> Option<StabilityWarning> {
+    let force = |dist: f32| pair[0].interact(dist) + pair[1].interact(dist);
+    let reach = pair[0].inter_max_dist.max(pair[1].inter_max_dist);
+    if reach <= 0. {
+        return None;
+    }
+
+    let mut peak = 0_f32;
+    let mut bound = false;
+    let mut last = None;
+    for k in 0..SAMPLES {
+        let force = force(reach * (k as f32 + 0.5) / SAMPLES as f32);
+        peak = peak.max(force);
+        // Pushed apart inside and pulled together outside, so stable in between
+        bound |= last.is_some_and(|last| last <= 0.) && force > 0.;
+        last = Some(force);
+    }
+    if peak <= 0. {
+        return None;
+    }
+    if !bound {
+        return Some(StabilityWarning::NoEquilibrium { a, b });
+    }
+
+    let core = pair[0].default_repulse + pair[1].default_repulse;
+    let ratio = peak / core;
+    (ratio > COLLAPSE_RATIO).then_some(StabilityWarning::Collapse { a, b, ratio })
+}
+
+/// Worst severity of the warnings about each cell of the behaviour matrix of `n` types,
+/// indexed like the matrix, for icons on its grid
+pub fn cell_severity(warnings: &[StabilityWarning], n: usize) -> Vec<Option<Severity>> {
+    let mut cells = vec![None; n * n];
+    for warning in warnings {
+        for (a, b) in warning.cells() {
+            if let Some(cell) = cells.get_mut(a * n + b) {
+                *cell = (*cell).max(Some(warning.severity()));
+            }
+        }
+    }
+    cells
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(behaviours: Vec<Behaviour>, damping: f32) -> SimConfig {
+        let n = (behaviours.len() as f32).sqrt() as usize;
+        SimConfig {
+            colors: vec![[1.; 3]; n],
+            behaviours,
+            damping,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_healthy() {
+        assert!(config(vec![Behaviour::default(); 9], 10.)
+            .stability_report()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_no_equilibrium() {
+        let coreless = Behaviour {
+            inter_threshold: 0.,
+            ..Default::default()
+        };
+        let mut behaviours = vec![Behaviour::default(); 4];
+        behaviours[3] = coreless;
+        let warnings = config(behaviours, 10.).stability_report();
+        assert_eq!(warnings, [StabilityWarning::NoEquilibrium { a: 1, b: 1 }]);
+        assert_eq!(warnings[0].severity(), Severity::Danger);
+
+        // A repulsive pair needs no equilibrium
+        let repulsive = Behaviour {
+            inter_threshold: 0.,
+            inter_strength: -1.,
+            ..Default::default()
+        };
+        assert!(config(vec![repulsive], 10.).stability_report().is_empty());
+    }
+
+    #[test]
+    fn test_collapse() {
+        let weak_core = Behaviour {
+            default_repulse: 1.,
+            inter_strength: 5.,
+            ..Default::default()
+        };
+        let mut behaviours = vec![Behaviour::default(); 4];
+        behaviours[1] = weak_core;
+        behaviours[2] = weak_core;
+        let warnings = config(behaviours, 10.).stability_report();
+        assert_eq!(warnings.len(), 1);
+        let StabilityWarning::Collapse { a: 0, b: 1, ratio } = warnings[0] else {
+            panic!("{:?}", warnings);
+        };
+        assert!((ratio - 5.).abs() < 0.1);
+
+        let cells = cell_severity(&warnings, 2);
+        assert_eq!(
+            cells,
+            [None, Some(Severity::Caution), Some(Severity::Caution), None]
+        );
+    }
+
+    #[test]
+    fn test_chases() {
+        let attract = Behaviour::default();
+        let repel = Behaviour {
+            inter_strength: -1.,
+            ..Default::default()
+        };
+        // 0 chases 1, which flees
+        let pursuit = config(vec![attract, attract, repel, attract], 0.);
+        assert_eq!(
+            pursuit.stability_report(),
+            [StabilityWarning::Runaway {
+                chaser: 0,
+                target: 1
+            }]
+        );
+        // Damping bounds the speed
+        assert!(config(pursuit.behaviours.clone(), 1.)
+            .stability_report()
+            .is_empty());
+
+        // 0 chases 1 chases 2 chases 0, each a little
+        let mut behaviours = vec![attract; 9];
+        for (a, b) in [(0, 1), (1, 2), (2, 0)] {
+            behaviours[a * 3 + b].inter_strength = 2.;
+        }
+        let warnings = config(behaviours, 0.).stability_report();
+        assert_eq!(
+            warnings,
+            [StabilityWarning::ChaseCycle { types: [0, 1, 2] }]
+        );
+        assert_eq!(warnings[0].cells(), [(0, 1), (1, 2), (2, 0)]);
+        assert!(warnings[0].to_string().contains("0 -> 1 -> 2 -> 0"));
+    }
+}
@@ -0,0 +1,266 @@
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    palette::nearest_color,
+    sim::{SimConfig, SimState},
+};
+
+/// Anyone to client: zoom the interaction ranges without touching the behaviour matrix, see
+/// [`SimConfig::interaction_scale`]
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub enum ScaleInteractions {
+    /// Use this scale, e.g. from a slider
+    Set(f32),
+    /// Use the scale at which particles would have this many neighbors on average at the
+    /// measured density, see [`SimState::interaction_scale_for_density`]
+    ToDensity { target_neighbors: f32 },
+}
+
+/// Edits to the configuration, either applied to the simulation as they are made (live) or
+/// collected in a staging copy until applied all at once
+pub struct StagedConfig {
+    live_update: bool,
+    staging: SimConfig,
+}
+
+impl StagedConfig {
+    /// Start out live, with a staging copy of the simulation's configuration
+    pub fn new(sim: &SimState) -> Self {
+        Self {
+            live_update: true,
+            staging: sim.config().clone(),
+        }
+    }
+
+    pub fn live_update(&self) -> bool {
+        self.live_update
+    }
+
+    /// Switch between live and staged editing. Going live applies any staged edits.
+    pub fn set_live_update(&mut self, live_update: bool, sim: &mut SimState, rng: &mut Pcg) {
+        self.live_update = live_update;
+        if live_update {
+            self.apply(sim, rng);
+        }
+    }
+
+    /// Edit the staging copy, applying it right away when live
+    pub fn edit(&mut self, sim: &mut SimState, rng: &mut Pcg, f: impl FnOnce(&mut SimConfig)) {
+        f(&mut self.staging);
+        if self.live_update {
+            self.apply(sim, rng);
+        }
+    }
+
+    /// Make the simulation use the staged configuration
+    pub fn apply(&mut self, sim: &mut SimState, rng: &mut Pcg) {
+        if self.is_modified(sim.config()) {
+            sim.set_config(self.staging.clone(), rng);
+        }
+    }
+
+    /// Discard staged edits
+    pub fn revert(&mut self, sim: &SimState) {
+        self.staging = sim.config().clone();
+    }
+
+    pub fn staging(&self) -> &SimConfig {
+        &self.staging
+    }
+
+    /// Whether the staged configuration differs from `live`
+    pub fn is_modified(&self, live: &SimConfig) -> bool {
+        self.staging != *live
+    }
+
+    /// For each cell of the staged behaviour matrix, whether it differs from `live`. Cells
+    /// which do not exist in `live` count as modified.
+    pub fn modified_behaviours(&self, live: &SimConfig) -> Vec<bool> {
+        self.staging
+            .behaviours
+            .iter()
+            .enumerate()
+            .map(|(i, behav)| {
+                let n = self.staging.colors.len();
+                let (row, col) = (i / n, i % n);
+                let live_n = live.colors.len();
+                row >= live_n || col >= live_n || live.behaviours[row * live_n + col] != *behav
+            })
+            .collect()
+    }
+}
+
+/// A configuration from elsewhere waiting to replace the live one, along with the type each
+/// live type will become. With fewer types than are live, the orphaned types have to be
+/// mapped onto the new ones before the configuration can be swapped in; dropping this
+/// instead leaves the simulation untouched.
+pub struct PendingConfig {
+    config: SimConfig,
+    /// New type of each live type
+    mapping: Vec<u8>,
+}
+
+impl PendingConfig {
+    /// Propose `config` for `sim`. Types which still exist keep their index, since that is
+    /// what the behaviour matrix goes by; orphaned types default to the type of the nearest
+    /// color.
+    pub fn new(sim: &SimState, config: SimConfig) -> Self {
+        let n = config.colors.len();
+        let mapping = sim
+            .config()
+            .colors
+            .iter()
+            .enumerate()
+            .map(|(t, &color)| {
+                if t < n {
+                    t as u8
+                } else {
+                    nearest_color(color, &config.colors).unwrap_or(0) as u8
+                }
+            })
+            .collect();
+        Self { config, mapping }
+    }
+
+    pub fn config(&self) -> &SimConfig {
+        &self.config
+    }
+
+    /// Whether some live types no longer exist and are being remapped
+    pub fn needs_remap(&self) -> bool {
+        self.mapping.len() > self.config.colors.len()
+    }
+
+    /// New type of each live type
+    pub fn mapping(&self) -> &[u8] {
+        &self.mapping
+    }
+
+    /// Make live type `from` become type `to` of the new configuration
+    pub fn set_mapping(&mut self, from: usize, to: u8) {
+        assert!(
+            (to as usize) < self.config.colors.len(),
+            "Unknown type {}",
+            to
+        );
+        self.mapping[from] = to;
+    }
+
+    /// Types of the new configuration which no live type maps to, and which start out empty
+    pub fn unused_types(&self) -> Vec<usize> {
+        (0..self.config.colors.len())
+            .filter(|&t| !self.mapping.contains(&(t as u8)))
+            .collect()
+    }
+
+    /// Remap the particles' types, then swap the configuration in. Every particle then has a
+    /// type of the new configuration, so nothing is drawn at random.
+    pub fn apply(self, sim: &mut SimState) {
+        sim.remap_types(&self.mapping);
+        sim.swap_config(self.config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Behaviour, Gravity};
+    use cimvr_common::glam::Vec3;
+
+    #[test]
+    fn test_staged_edits() {
+        let mut rng = Pcg::new();
+        let config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 100.,
+            ..Default::default()
+        };
+        let mut sim = SimState::new(&mut rng, config, 10);
+        let mut staged = StagedConfig::new(&sim);
+
+        staged.edit(&mut sim, &mut rng, |c| c.damping = 50.);
+        assert_eq!(sim.config().damping, 50.);
+
+        staged.set_live_update(false, &mut sim, &mut rng);
+        staged.edit(&mut sim, &mut rng, |c| {
+            c.behaviours[2].inter_strength = 200.
+        });
+        assert_eq!(sim.config().behaviours[2].inter_strength, 1.);
+        assert_eq!(
+            staged.modified_behaviours(sim.config()),
+            vec![false, false, true, false]
+        );
+
+        staged.revert(&sim);
+        assert!(!staged.is_modified(sim.config()));
+
+        staged.edit(&mut sim, &mut rng, |c| c.behaviours[1].inter_strength = -3.);
+        staged.apply(&mut sim, &mut rng);
+        assert_eq!(sim.config().behaviours[1].inter_strength, -3.);
+        assert!(!staged.is_modified(sim.config()));
+
+        // An edit of gravity alone still counts
+        let gravity = Gravity {
+            down: -Vec3::Y,
+            weights: vec![1., -1.],
+        };
+        staged.edit(&mut sim, &mut rng, |c| c.gravity = Some(gravity.clone()));
+        assert!(staged.is_modified(sim.config()));
+        assert_eq!(sim.config().gravity, None);
+        staged.apply(&mut sim, &mut rng);
+        assert_eq!(sim.config().gravity, Some(gravity));
+        assert!(!staged.is_modified(sim.config()));
+    }
+
+    fn config(colors: Vec<[f32; 3]>) -> SimConfig {
+        SimConfig {
+            behaviours: vec![Behaviour::default(); colors.len() * colors.len()],
+            colors,
+            damping: 100.,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_pending_config() {
+        let mut rng = Pcg::new();
+        let six = vec![
+            [1., 0., 0.],
+            [0., 1., 0.],
+            [0., 0., 1.],
+            [1., 1., 0.],
+            [0., 0.2, 0.9],
+            [0.9, 0., 0.1],
+        ];
+        let mut sim = SimState::new(&mut rng, config(six.clone()), 600);
+        let before: Vec<u8> = sim.particles().iter().map(|p| p.color).collect();
+
+        // Cancelling leaves everything as it was
+        let pending = PendingConfig::new(&sim, config(six[..4].to_vec()));
+        assert!(pending.needs_remap());
+        assert_eq!(pending.mapping(), [0, 1, 2, 3, 2, 0]);
+        drop(pending);
+        assert_eq!(sim.config().colors, six);
+        assert!(sim
+            .particles()
+            .iter()
+            .map(|p| p.color)
+            .eq(before.iter().copied()));
+
+        let mut pending = PendingConfig::new(&sim, config(six[..4].to_vec()));
+        pending.set_mapping(5, 3);
+        pending.apply(&mut sim);
+        assert_eq!(sim.config().colors.len(), 4);
+        for (particle, &old) in sim.particles().iter().zip(&before) {
+            assert_eq!(particle.color, [0, 1, 2, 3, 2, 3][old as usize]);
+        }
+
+        // More types need no remap, but start out empty
+        let pending = PendingConfig::new(&sim, config(six));
+        assert!(!pending.needs_remap());
+        assert_eq!(pending.unused_types(), vec![4, 5]);
+    }
+}
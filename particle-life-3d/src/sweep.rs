@@ -0,0 +1,313 @@
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    diagnostics::{find_clusters, ClusterConfig},
+    sim::{ConfigError, Field, Particle, SimConfig, SimState},
+};
+
+/// Most tiles along each side of a sweep's grid
+const MAX_TILES: usize = 8;
+
+/// Anyone to client: run a parameter sweep in place of the simulation, which holds meanwhile
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub enum SweepCommand {
+    /// Sweep the current configuration, sharing its particles among the tiles
+    Start(SweepConfig),
+    /// Print how much each tile has clustered
+    Report,
+    Stop,
+}
+
+/// Sweep of one behaviour coefficient across a grid of tiles
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct SweepConfig {
+    pub field: Field,
+    /// Acting and acted upon type of the swept behaviour
+    pub pair: (usize, usize),
+    pub min: f32,
+    pub max: f32,
+    /// Number of tiles along each side of the grid
+    pub tiles: usize,
+}
+
+/// One variation of the sweep
+pub struct SweepTile {
+    pub sim: SimState,
+    /// Value of the swept coefficient in this tile
+    pub value: f32,
+    /// Position of the tile's center relative to the center of the grid
+    pub offset: Vec3,
+}
+
+/// Many variations of a configuration side by side in the XZ plane, each confined to its own
+/// tile so that they do not mix
+pub struct Sweep {
+    tiles: Vec<SweepTile>,
+    half_width: f32,
+    /// Every tile's particles at once, for display
+    view: SimState,
+}
+
+impl SweepConfig {
+    /// Swept value for each tile, in row-major order
+    pub fn values(&self) -> Vec<f32> {
+        let n = self.tiles * self.tiles;
+        (0..n)
+            .map(|i| {
+                let t = if n > 1 { i as f32 / (n - 1) as f32 } else { 0. };
+                self.min + (self.max - self.min) * t
+            })
+            .collect()
+    }
+
+    /// Check that the grid is of a usable size, and that every tile's configuration is
+    /// valid, the swept pair included
+    pub fn validate(&self, base: &SimConfig) -> Result<(), ConfigError> {
+        if !(1..=MAX_TILES).contains(&self.tiles) {
+            return Err(ConfigError::Invalid("Number of tiles out of range"));
+        }
+        let n_colors = base.colors.len();
+        if self.pair.0 >= n_colors || self.pair.1 >= n_colors {
+            return Err(ConfigError::Invalid("Swept pair out of range"));
+        }
+        if !(self.min.is_finite() && self.max.is_finite()) {
+            return Err(ConfigError::Invalid("Swept range must be finite"));
+        }
+        self.tile_configs(base)
+            .iter()
+            .try_for_each(SimConfig::validate)
+    }
+
+    /// Configuration for each tile, in row-major order
+    pub fn tile_configs(&self, base: &SimConfig) -> Vec<SimConfig> {
+        let n_colors = base.colors.len();
+        let idx = self.pair.0 * n_colors + self.pair.1;
+        assert!(
+            self.pair.0 < n_colors && self.pair.1 < n_colors,
+            "Swept pair {:?} out of range",
+            self.pair
+        );
+
+        self.values()
+            .into_iter()
+            .map(|value| {
+                let mut config = base.clone();
+                *self.field.get_mut(&mut config.behaviours[idx]) = value;
+                config
+            })
+            .collect()
+    }
+}
+
+impl Sweep {
+    /// Create a sweep with `n` particles per tile. Tiles are squares of the given half-width.
+    pub fn new(
+        sweep: &SweepConfig,
+        base: &SimConfig,
+        n: usize,
+        half_width: f32,
+        rng: &mut Pcg,
+    ) -> Self {
+        // Leave a gap between tiles
+        let pitch = half_width * 2.2;
+        let center = (sweep.tiles as f32 - 1.) / 2.;
+
+        let tiles: Vec<SweepTile> = sweep
+            .tile_configs(base)
+            .into_iter()
+            .zip(sweep.values())
+            .enumerate()
+            .map(|(i, (config, value))| {
+                let (row, col) = (i / sweep.tiles, i % sweep.tiles);
+                let mut sim = SimState::new(rng, config, n);
+                sim.set_constrain_2d(true, rng);
+                sim.rerandomize_positions(half_width, rng);
+                SweepTile {
+                    sim,
+                    value,
+                    offset: Vec3::new(col as f32 - center, 0., row as f32 - center) * pitch,
+                }
+            })
+            .collect();
+
+        let view = composite(&tiles, base);
+        Self {
+            tiles,
+            half_width,
+            view,
+        }
+    }
+
+    pub fn step(&mut self, dt: f32) {
+        for tile in &mut self.tiles {
+            tile.sim.step(dt);
+            tile.sim.reflect_walls(self.half_width);
+        }
+        self.view = composite(&self.tiles, self.view.config());
+    }
+
+    /// Every tile's particles, placed at their tiles, under the base configuration
+    pub fn view(&self) -> &SimState {
+        &self.view
+    }
+
+    pub fn tiles(&self) -> &[SweepTile] {
+        &self.tiles
+    }
+
+    /// Half-width of each square tile
+    pub fn half_width(&self) -> f32 {
+        self.half_width
+    }
+
+    /// Table of the swept values, laid out like the tiles (rows along +Z)
+    pub fn legend(&self) -> String {
+        let side = (self.tiles.len() as f32).sqrt().round() as usize;
+        let mut legend = String::new();
+        for row in self.tiles.chunks(side.max(1)) {
+            let cells: Vec<String> = row.iter().map(|t| format!("{:>8.3}", t.value)).collect();
+            legend += &cells.join(" ");
+            legend.push('\n');
+        }
+        legend
+    }
+
+    /// Fraction of each tile's particles in its largest cluster, laid out like the
+    /// [`Self::legend`]. Stable blobs come close to 1, while streams and gases break up.
+    pub fn report(&self, cluster: ClusterConfig) -> String {
+        let side = (self.tiles.len() as f32).sqrt().round() as usize;
+        let mut report = String::from("Largest cluster fraction of each tile:\n");
+        for row in self.tiles.chunks(side.max(1)) {
+            let cells: Vec<String> = (row.iter())
+                .map(|t| format!("{:>8.3}", t.largest_cluster_fraction(cluster)))
+                .collect();
+            report += &cells.join(" ");
+            report.push('\n');
+        }
+        report
+    }
+}
+
+impl SweepTile {
+    /// Fraction of the tile's particles in its largest cluster
+    pub fn largest_cluster_fraction(&self, cluster: ClusterConfig) -> f32 {
+        let clusters = find_clusters(&self.sim, cluster.radius).clusters;
+        let largest = clusters.iter().map(|c| c.size).max().unwrap_or(0);
+        largest as f32 / self.sim.particles().len().max(1) as f32
+    }
+}
+
+/// Particles of all the tiles in one simulation, offset to their tiles
+fn composite(tiles: &[SweepTile], base: &SimConfig) -> SimState {
+    let particles = (tiles.iter())
+        .flat_map(|tile| {
+            (tile.sim.particles().iter()).map(|p| Particle {
+                pos: p.pos + tile.offset,
+                ..*p
+            })
+        })
+        .collect();
+    SimState::from_particles(base.clone(), particles)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Behaviour;
+
+    #[test]
+    fn test_sweep() {
+        let base = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 10.,
+            ..Default::default()
+        };
+        let config = SweepConfig {
+            field: Field::Strength,
+            pair: (0, 1),
+            min: -10.,
+            max: 10.,
+            tiles: 3,
+        };
+
+        let values = config.values();
+        assert_eq!(values.len(), 9);
+        assert_eq!((values[0], values[4], values[8]), (-10., 0., 10.));
+
+        let configs = config.tile_configs(&base);
+        for (cfg, value) in configs.iter().zip(&values) {
+            assert_eq!(cfg.behaviours[1].inter_strength, *value);
+            assert_eq!(
+                cfg.behaviours[2].inter_strength,
+                base.behaviours[2].inter_strength
+            );
+        }
+
+        let mut sweep = Sweep::new(&config, &base, 50, 0.25, &mut Pcg::new());
+        (0..100).for_each(|_| sweep.step(1e-3));
+        for tile in sweep.tiles() {
+            assert!(tile
+                .sim
+                .particles()
+                .iter()
+                .all(|p| p.pos.abs().max_element() <= 0.25 && p.pos.y == 0.));
+        }
+        assert_eq!(sweep.tiles()[5].offset, Vec3::new(0.55, 0., 0.));
+        assert_eq!(sweep.legend().lines().count(), 3);
+    }
+
+    #[test]
+    fn test_sweep_results() {
+        let base = SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour::default()],
+            damping: 10.,
+            ..Default::default()
+        };
+        let config = SweepConfig {
+            field: Field::Strength,
+            pair: (0, 0),
+            min: -5.,
+            max: 5.,
+            tiles: 2,
+        };
+        assert_eq!(config.validate(&base), Ok(()));
+        for invalid in [
+            SweepConfig {
+                pair: (0, 1),
+                ..config.clone()
+            },
+            SweepConfig {
+                tiles: 0,
+                ..config.clone()
+            },
+        ] {
+            assert!(invalid.validate(&base).is_err());
+        }
+
+        let mut sweep = Sweep::new(&config, &base, 60, 0.25, &mut Pcg::new());
+        (0..1000).for_each(|_| sweep.step(1e-3));
+
+        // Repelled particles spread over their tile, attracted ones gather into a blob
+        let cluster = ClusterConfig::default();
+        let fractions: Vec<f32> = (sweep.tiles().iter())
+            .map(|tile| tile.largest_cluster_fraction(cluster))
+            .collect();
+        assert!(fractions[3] > 0.5, "{:?}", fractions);
+        assert!(fractions[0] < 0.2, "{:?}", fractions);
+        assert_eq!(sweep.report(cluster).lines().count(), 3);
+
+        // The view holds every tile's particles, each within its tile
+        let view = sweep.view().particles();
+        assert_eq!(view.len(), 4 * 60);
+        for (i, tile) in sweep.tiles().iter().enumerate() {
+            assert!(view[i * 60..(i + 1) * 60]
+                .iter()
+                .all(|p| (p.pos - tile.offset).abs().max_element() <= 0.25));
+        }
+    }
+}
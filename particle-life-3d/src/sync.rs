@@ -0,0 +1,383 @@
+//! Compact position sync for a server-authoritative mode.
+//!
+//! The encoder sends particle positions quantized to a fixed grid. Most packets are deltas that
+//! only carry particles which moved more than a threshold since they were last sent; every so
+//! often, or when the decoder asks for one, a keyframe carries every particle.
+//!
+//! Packet layout, with integers as LEB128 varints (signed ones zigzag encoded):
+//! ```text
+//! kind: u8, seq: u32 (little endian), hash: u64 (little endian), count: varint
+//! keyframe: count * (x, y, z)
+//! delta:    count * (index - previous index - 1, dx, dy, dz)
+//! ```
+//! `hash` is the [`state_hash`] of the sender's view of the state after this packet, which lets
+//! the decoder detect that it has fallen out of sync.
+use cimvr_common::glam::Vec3;
+
+/// Size of the quantization grid
+pub const QUANTUM: f32 = 1e-3;
+
+const KIND_KEYFRAME: u8 = 0;
+const KIND_DELTA: u8 = 1;
+
+/// Quantized position
+pub type QuantPos = [i32; 3];
+
+/// Errors arising from decoding a packet
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncError {
+    /// The packet ended early
+    Truncated,
+    UnknownKind(u8),
+    /// A delta referred to a particle which does not exist
+    IndexOutOfRange(usize),
+}
+
+/// Server side of the sync
+pub struct SyncEncoder {
+    /// Positions as last sent, which is the decoder's view of the state
+    sent: Vec<QuantPos>,
+    seq: u32,
+    /// Minimum change along any axis, in quanta, for a particle to be sent
+    pub threshold: i32,
+    /// Number of frames between keyframes
+    pub keyframe_interval: usize,
+    until_keyframe: usize,
+}
+
+/// Client side of the sync
+#[derive(Default)]
+pub struct SyncDecoder {
+    positions: Vec<QuantPos>,
+    last_seq: Option<u32>,
+    needs_keyframe: bool,
+}
+
+impl SyncEncoder {
+    pub fn new(threshold: i32, keyframe_interval: usize) -> Self {
+        Self {
+            sent: vec![],
+            seq: 0,
+            threshold,
+            keyframe_interval: keyframe_interval.max(1),
+            until_keyframe: 0,
+        }
+    }
+
+    /// Send a keyframe next, e.g. because the decoder fell out of sync
+    pub fn request_keyframe(&mut self) {
+        self.until_keyframe = 0;
+    }
+
+    /// Encode this frame's positions into a packet
+    pub fn encode(&mut self, positions: &[Vec3]) -> Vec<u8> {
+        let quantized: Vec<QuantPos> = positions.iter().map(|&p| quantize(p)).collect();
+        self.seq = self.seq.wrapping_add(1);
+
+        let keyframe = self.until_keyframe == 0 || quantized.len() != self.sent.len();
+        let mut body = vec![];
+        let count;
+        if keyframe {
+            self.until_keyframe = self.keyframe_interval;
+            for q in &quantized {
+                q.iter().for_each(|&v| write_signed(&mut body, v));
+            }
+            count = quantized.len();
+            self.sent = quantized;
+        } else {
+            let mut n_records = 0;
+            let mut last_idx = None;
+            for (idx, (q, sent)) in quantized.iter().zip(&mut self.sent).enumerate() {
+                let delta = [q[0] - sent[0], q[1] - sent[1], q[2] - sent[2]];
+                if delta.iter().all(|d| d.abs() <= self.threshold) {
+                    continue;
+                }
+
+                let gap = last_idx.map_or(idx, |last| idx - last - 1);
+                write_unsigned(&mut body, gap as u64);
+                delta.iter().for_each(|&d| write_signed(&mut body, d));
+                *sent = *q;
+                last_idx = Some(idx);
+                n_records += 1;
+            }
+            count = n_records;
+        }
+        self.until_keyframe -= 1;
+
+        let mut packet = vec![if keyframe { KIND_KEYFRAME } else { KIND_DELTA }];
+        packet.extend_from_slice(&self.seq.to_le_bytes());
+        packet.extend_from_slice(&state_hash(&self.sent).to_le_bytes());
+        write_unsigned(&mut packet, count as u64);
+        packet.extend_from_slice(&body);
+        packet
+    }
+}
+
+impl SyncDecoder {
+    /// Apply a packet. Deltas are ignored while waiting for a keyframe, see
+    /// [`SyncDecoder::needs_keyframe`]. A malformed packet changes no position, and leaves
+    /// the decoder waiting for a keyframe.
+    pub fn apply(&mut self, packet: &[u8]) -> Result<(), SyncError> {
+        let result = self.decode(packet);
+        if result.is_err() {
+            self.needs_keyframe = true;
+        }
+        result
+    }
+
+    fn decode(&mut self, packet: &[u8]) -> Result<(), SyncError> {
+        let mut reader = Reader { buf: packet };
+        let kind = reader.byte()?;
+        let seq = u32::from_le_bytes(reader.array()?);
+        let hash = u64::from_le_bytes(reader.array()?);
+        let count = reader.unsigned()? as usize;
+
+        match kind {
+            KIND_KEYFRAME => {
+                let mut positions = Vec::with_capacity(count.min(packet.len()));
+                for _ in 0..count {
+                    positions.push([reader.signed()?, reader.signed()?, reader.signed()?]);
+                }
+                self.positions = positions;
+                self.needs_keyframe = false;
+            }
+            KIND_DELTA => {
+                // A lost packet leaves us with a stale state
+                if self.last_seq.map(|s| s.wrapping_add(1)) != Some(seq) {
+                    self.needs_keyframe = true;
+                }
+                if self.needs_keyframe {
+                    self.last_seq = Some(seq);
+                    return Ok(());
+                }
+
+                // Read every record before touching a position
+                let mut records = Vec::with_capacity(count.min(packet.len()));
+                let mut idx = 0_usize;
+                for i in 0..count {
+                    let gap = reader.unsigned()? as usize;
+                    let next = (idx.checked_add(gap)).and_then(|v| v.checked_add((i > 0) as usize));
+                    idx = (next.filter(|&v| v < self.positions.len()))
+                        .ok_or(SyncError::IndexOutOfRange(next.unwrap_or(usize::MAX)))?;
+                    records.push((idx, [reader.signed()?, reader.signed()?, reader.signed()?]));
+                }
+                for (idx, delta) in records {
+                    let pos = &mut self.positions[idx];
+                    for axis in 0..3 {
+                        pos[axis] = pos[axis].wrapping_add(delta[axis]);
+                    }
+                }
+            }
+            other => return Err(SyncError::UnknownKind(other)),
+        }
+
+        self.last_seq = Some(seq);
+        if state_hash(&self.positions) != hash {
+            self.needs_keyframe = true;
+        }
+        Ok(())
+    }
+
+    /// Whether the decoder is out of sync, and the encoder should be asked for a keyframe
+    pub fn needs_keyframe(&self) -> bool {
+        self.needs_keyframe
+    }
+
+    pub fn positions(&self) -> Vec<Vec3> {
+        self.positions.iter().map(|&q| dequantize(q)).collect()
+    }
+}
+
+pub fn quantize(pos: Vec3) -> QuantPos {
+    (pos / QUANTUM).round().as_ivec3().to_array()
+}
+
+pub fn dequantize(q: QuantPos) -> Vec3 {
+    Vec3::new(q[0] as f32, q[1] as f32, q[2] as f32) * QUANTUM
+}
+
+/// FNV-1a hash of quantized positions
+pub fn state_hash(positions: &[QuantPos]) -> u64 {
+    let mut hash = 0xcbf2_9ce4_8422_2325_u64;
+    for byte in positions.iter().flatten().flat_map(|v| v.to_le_bytes()) {
+        hash = (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3);
+    }
+    hash
+}
+
+fn write_unsigned(buf: &mut Vec<u8>, mut v: u64) {
+    while v >= 0x80 {
+        buf.push(v as u8 | 0x80);
+        v >>= 7;
+    }
+    buf.push(v as u8);
+}
+
+fn write_signed(buf: &mut Vec<u8>, v: i32) {
+    write_unsigned(buf, ((v << 1) ^ (v >> 31)) as u32 as u64);
+}
+
+struct Reader<'a> {
+    buf: &'a [u8],
+}
+
+impl Reader<'_> {
+    fn byte(&mut self) -> Result<u8, SyncError> {
+        let (&first, rest) = self.buf.split_first().ok_or(SyncError::Truncated)?;
+        self.buf = rest;
+        Ok(first)
+    }
+
+    fn array<const N: usize>(&mut self) -> Result<[u8; N], SyncError> {
+        if self.buf.len() < N {
+            return Err(SyncError::Truncated);
+        }
+        let (head, rest) = self.buf.split_at(N);
+        self.buf = rest;
+        Ok(head.try_into().unwrap())
+    }
+
+    fn unsigned(&mut self) -> Result<u64, SyncError> {
+        let mut v = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.byte()?;
+            v |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+        Ok(v)
+    }
+
+    fn signed(&mut self) -> Result<i32, SyncError> {
+        let v = self.unsigned()? as u32;
+        Ok((v >> 1) as i32 ^ -((v & 1) as i32))
+    }
+}
+
+impl std::fmt::Display for SyncError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SyncError::Truncated => write!(f, "Packet ended early"),
+            SyncError::UnknownKind(kind) => write!(f, "Unknown packet kind {}", kind),
+            SyncError::IndexOutOfRange(idx) => write!(f, "Particle index {} out of range", idx),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cimvr_engine_interface::pcg::Pcg;
+
+    const N: usize = 10_000;
+
+    fn random_walk(rng: &mut Pcg, positions: &mut [Vec3]) {
+        for pos in positions {
+            // Most particles barely move
+            let scale = if rng.gen_f32() < 0.2 { 0.02 } else { 0.001 };
+            *pos += (Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) - 0.5) * scale;
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut rng = Pcg::new();
+        let mut positions: Vec<Vec3> = (0..N)
+            .map(|_| Vec3::new(rng.gen_f32(), rng.gen_f32(), rng.gen_f32()) * 2. - 1.)
+            .collect();
+
+        let mut encoder = SyncEncoder::new(2, 60);
+        let mut decoder = SyncDecoder::default();
+        let max_err = (encoder.threshold as f32 + 0.5) * QUANTUM + 1e-6;
+
+        let frames = 30;
+        let mut delta_bytes = 0;
+        for frame in 0..frames {
+            random_walk(&mut rng, &mut positions);
+            let packet = encoder.encode(&positions);
+            if frame > 0 {
+                delta_bytes += packet.len();
+            }
+            decoder.apply(&packet).unwrap();
+            assert!(!decoder.needs_keyframe());
+
+            for (a, b) in positions.iter().zip(decoder.positions()) {
+                assert!((*a - b).abs().max_element() <= max_err, "{} {}", a, b);
+            }
+        }
+        assert!(delta_bytes < (frames - 1) * N);
+    }
+
+    #[test]
+    fn test_packet_loss_recovery() {
+        let mut rng = Pcg::new();
+        let mut positions = vec![Vec3::ZERO; N];
+
+        let mut encoder = SyncEncoder::new(0, 1000);
+        let mut decoder = SyncDecoder::default();
+        decoder.apply(&encoder.encode(&positions)).unwrap();
+
+        for frame in 0..10 {
+            random_walk(&mut rng, &mut positions);
+            let packet = encoder.encode(&positions);
+            // Drop a few packets
+            if frame % 4 != 1 {
+                decoder.apply(&packet).unwrap();
+            }
+        }
+        assert!(decoder.needs_keyframe());
+
+        encoder.request_keyframe();
+        random_walk(&mut rng, &mut positions);
+        decoder.apply(&encoder.encode(&positions)).unwrap();
+        assert!(!decoder.needs_keyframe());
+        let expected: Vec<QuantPos> = positions.iter().map(|&p| quantize(p)).collect();
+        assert_eq!(decoder.positions, expected);
+
+        random_walk(&mut rng, &mut positions);
+        decoder.apply(&encoder.encode(&positions)).unwrap();
+        assert!(!decoder.needs_keyframe());
+    }
+
+    #[test]
+    fn test_malformed_packets() {
+        let mut encoder = SyncEncoder::new(0, 10);
+        let packet = encoder.encode(&[Vec3::X; 4]);
+        let mut decoder = SyncDecoder::default();
+        assert_eq!(
+            decoder.apply(&packet[..packet.len() - 1]),
+            Err(SyncError::Truncated)
+        );
+        assert_eq!(decoder.apply(&[7; 20]), Err(SyncError::UnknownKind(7)));
+        assert!(decoder.needs_keyframe());
+
+        decoder.apply(&packet).unwrap();
+        let before = decoder.positions.clone();
+        let delta = |count: u64, records: &[u64]| {
+            let mut delta = vec![KIND_DELTA];
+            delta.extend_from_slice(&2_u32.to_le_bytes());
+            delta.extend_from_slice(&state_hash(&before).to_le_bytes());
+            write_unsigned(&mut delta, count);
+            records.iter().for_each(|&v| write_unsigned(&mut delta, v));
+            delta
+        };
+
+        // A gap which overflows the index, after a good record
+        let overflow = delta(2, &[0, 2, 2, 2, u64::MAX, 2, 2, 2]);
+        assert!(matches!(
+            decoder.apply(&overflow),
+            Err(SyncError::IndexOutOfRange(_))
+        ));
+        assert_eq!(decoder.positions, before);
+        assert!(decoder.needs_keyframe());
+
+        // Past the last particle, and cut short
+        for bad in [delta(2, &[1, 2, 2, 2, 2, 2, 2, 2]), delta(1, &[0, 2, 2])] {
+            decoder.apply(&packet).unwrap();
+            assert!(decoder.apply(&bad).is_err());
+            assert_eq!(decoder.positions, before);
+            assert!(decoder.needs_keyframe());
+        }
+    }
+}
@@ -0,0 +1,285 @@
+//! Lookup tables of the pair potential and force, sampled once per behaviour matrix so that
+//! the Monte Carlo and force loops interpolate instead of evaluating the kernel. See
+//! [`crate::sim::SimState::set_use_tables`].
+use cimvr_common::glam::Vec3;
+
+use crate::sim::Behaviour;
+
+/// Samples per table before refining to meet the tolerance
+pub const TABLE_SAMPLES: usize = 256;
+
+/// Tables are not refined beyond this many samples, whatever the tolerance
+pub const MAX_TABLE_SAMPLES: usize = 4096;
+
+/// Default tolerance of [`BehaviourTables::new`]
+pub const DEFAULT_TABLE_TOLERANCE: f32 = 1e-3;
+
+/// Potential and force of every pair of types, sampled evenly over `0..=inter_max_dist` and
+/// linearly interpolated, indexed like `SimConfig::behaviours`
+#[derive(Clone, Debug)]
+pub struct BehaviourTables {
+    pairs: Vec<PairTable>,
+}
+
+#[derive(Clone, Debug)]
+struct PairTable {
+    behaviour: Behaviour,
+    /// Start of the core, rising and falling segments of the kernel, and the max distance.
+    /// Each segment is sampled separately so that the kernel's kinks fall on samples.
+    breaks: [f32; 4],
+    /// Reciprocal of the distance between samples in each segment
+    inv_steps: [f32; 3],
+    /// Index of the first sample of each segment, and the total
+    offsets: [usize; 4],
+    /// Potential plus its logarithmic divergence `repulse * ln(min(d, threshold))`, which
+    /// leaves it linear inside the repulsive core, so two samples cover the core exactly
+    potential: Vec<f32>,
+    /// Force over distance squared, the factor [`Behaviour::accel`] scales the difference
+    /// by. The force diverges inside the core, whose samples are unused.
+    force: Vec<f32>,
+}
+impl BehaviourTables {
+    /// Sample each behaviour with [`TABLE_SAMPLES`] points, doubling them while the
+    /// interpolation error exceeds `tolerance`, up to [`MAX_TABLE_SAMPLES`]. See
+    /// [`BehaviourTables::max_error`] for how the error is measured.
+    pub fn new(behaviours: &[Behaviour], tolerance: f32) -> Self {
+        let pairs = behaviours
+            .iter()
+            .map(|behav| {
+                let mut samples = TABLE_SAMPLES;
+                loop {
+                    let table = PairTable::new(*behav, samples);
+                    if samples >= MAX_TABLE_SAMPLES || table.max_error() <= tolerance {
+                        break table;
+                    }
+                    samples *= 2;
+                }
+            })
+            .collect();
+        Self { pairs }
+    }
+
+    /// Whether these tables were sampled from exactly these behaviours
+    pub fn matches(&self, behaviours: &[Behaviour]) -> bool {
+        self.pairs.len() == behaviours.len()
+            && self
+                .pairs
+                .iter()
+                .zip(behaviours)
+                .all(|(t, b)| t.behaviour == *b)
+    }
+
+    /// Number of samples in the table of the given pair
+    pub fn samples(&self, pair: usize) -> usize {
+        self.pairs[pair].potential.len()
+    }
+
+    /// Largest interpolation error over all pairs. Potential errors are relative to the
+    /// pair's strength `|default_repulse| + |inter_strength|`, force errors relative to the
+    /// largest force outside the core. Probed between the samples, where linear
+    /// interpolation is furthest off.
+    pub fn max_error(&self) -> f32 {
+        self.pairs
+            .iter()
+            .map(PairTable::max_error)
+            .fold(0., f32::max)
+    }
+
+    /// Interpolated [`Behaviour::potential`] of the given pair
+    pub fn potential(&self, pair: usize, dist: f32) -> f32 {
+        self.pairs[pair].potential(dist)
+    }
+
+    /// Interpolated acceleration of the given pair towards a particle at `diff`, already in
+    /// the metric of the behaviour. Inside the repulsive core, where the force diverges, it
+    /// is evaluated directly.
+    pub fn accel(&self, pair: usize, diff: Vec3, cutoff_sq: f32) -> Vec3 {
+        self.pairs[pair].accel(diff, cutoff_sq)
+    }
+}
+
+impl PairTable {
+    /// Sample the behaviour with two samples in the core, and `samples` spread over the
+    /// rest in proportion to the length of each segment
+    fn new(behaviour: Behaviour, samples: usize) -> Self {
+        let m = behaviour.inter_max_dist.max(0.);
+        let t = behaviour.inter_threshold.clamp(0., m);
+        let breaks = [0., t, (t + m) / 2., m];
+
+        let mut inv_steps = [0.; 3];
+        let mut offsets = [0; 4];
+        let mut dists = vec![];
+        for s in 0..3 {
+            let len = breaks[s + 1] - breaks[s];
+            let n = match (s, len > 0.) {
+                (_, false) => 0,
+                (0, true) => 2,
+                (_, true) => ((samples as f32 * len / (m - t)) as usize).max(2),
+            };
+            if n > 0 {
+                let step = len / (n - 1) as f32;
+                inv_steps[s] = step.recip();
+                dists.extend((0..n).map(|k| breaks[s] + k as f32 * step));
+            }
+            offsets[s + 1] = offsets[s] + n;
+        }
+
+        let mut table = Self {
+            behaviour,
+            breaks,
+            inv_steps,
+            offsets,
+            potential: vec![],
+            force: vec![],
+        };
+        table.potential = dists
+            .iter()
+            .map(|&d| behaviour.potential(d) + table.core_log(d))
+            .collect();
+        table.force = dists
+            .iter()
+            .map(|&d| match d >= t && d < m {
+                true => behaviour.interact(d) / (d * d).max(1e-12),
+                false => 0.,
+            })
+            .collect();
+        table
+    }
+
+    /// The logarithmic divergence of the potential inside the core
+    fn core_log(&self, dist: f32) -> f32 {
+        let t = self.breaks[1];
+        if t > 0. {
+            self.behaviour.default_repulse * dist.max(1e-6).min(t).ln()
+        } else {
+            0.
+        }
+    }
+
+    /// Linear interpolation of `values` at `dist`, which must be below the max distance
+    fn lerp(&self, values: &[f32], dist: f32) -> f32 {
+        let s = match dist {
+            d if d < self.breaks[1] => 0,
+            d if d < self.breaks[2] => 1,
+            _ => 2,
+        };
+        let x = (dist - self.breaks[s]) * self.inv_steps[s];
+        let last = self.offsets[s + 1] - 2;
+        let i = (self.offsets[s] + x as usize).min(last);
+        let f = x - (i - self.offsets[s]) as f32;
+        values[i] + (values[i + 1] - values[i]) * f
+    }
+
+    fn potential(&self, dist: f32) -> f32 {
+        if dist >= self.breaks[3] {
+            return 0.;
+        }
+        self.lerp(&self.potential, dist) - self.core_log(dist)
+    }
+
+    fn accel(&self, diff: Vec3, cutoff_sq: f32) -> Vec3 {
+        let dist_sq = diff.length_squared();
+        if dist_sq > cutoff_sq || dist_sq == 0. {
+            return Vec3::ZERO;
+        }
+
+        let dist = dist_sq.sqrt();
+        if dist >= self.breaks[3] {
+            Vec3::ZERO
+        } else if dist < self.breaks[1] {
+            diff * (self.behaviour.interact(dist) / dist_sq)
+        } else {
+            diff * self.lerp(&self.force, dist)
+        }
+    }
+
+    fn max_error(&self) -> f32 {
+        let behav = &self.behaviour;
+        let m = self.breaks[3];
+
+        let probes: Vec<f32> = (0..3)
+            .flat_map(|s| {
+                let samples = self.offsets[s + 1] - self.offsets[s];
+                let quarter = 0.25 / self.inv_steps[s];
+                (0..samples.saturating_sub(1) * 4)
+                    .map(move |k| self.breaks[s] + (k as f32 + 0.5) * quarter)
+            })
+            .filter(|&d| d < m)
+            .collect();
+        let strength = behav.default_repulse.abs() + behav.inter_strength.abs();
+        let force = |d: f32| self.accel(Vec3::X * d, f32::INFINITY).x;
+        let exact_force = |d: f32| behav.interact(d) / d;
+        let peak_force = probes
+            .iter()
+            .filter(|&&d| d >= behav.inter_threshold)
+            .map(|&d| exact_force(d).abs())
+            .fold(0., f32::max);
+
+        let relative = |error: f32, scale: f32| if scale > 0. { error / scale } else { 0. };
+        probes
+            .iter()
+            .map(|&d| {
+                let potential = (self.potential(d) - behav.potential(d)).abs();
+                let force = (force(d) - exact_force(d)).abs();
+                relative(potential, strength).max(relative(force, peak_force))
+            })
+            .fold(0., f32::max)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Field, InteractionMode};
+    use cimvr_engine_interface::pcg::Pcg;
+
+    #[test]
+    fn test_tables_match_analytic() {
+        let mut rng = Pcg::new();
+        let tolerance = 1e-3;
+        let behaviours: Vec<Behaviour> = (0..200)
+            .map(|_| {
+                let mut behav = Behaviour {
+                    default_repulse: 0.,
+                    inter_threshold: 0.,
+                    inter_strength: 0.,
+                    inter_max_dist: 0.,
+                    anisotropy: Vec3::ONE,
+                    polarity: 0.,
+                    mode: InteractionMode::Potential,
+                };
+                for field in [
+                    Field::MaxDist,
+                    Field::Threshold,
+                    Field::Strength,
+                    Field::Repulse,
+                ] {
+                    behav.randomize_field(field, &mut rng);
+                }
+                behav
+            })
+            .collect();
+        let tables = BehaviourTables::new(&behaviours, tolerance);
+        assert!(tables.max_error() <= tolerance, "{}", tables.max_error());
+        assert!(tables.matches(&behaviours));
+
+        // Sweep densely, not just between samples
+        for (pair, behav) in behaviours.iter().enumerate() {
+            let strength = behav.default_repulse.abs() + behav.inter_strength.abs();
+            for k in 1..1000 {
+                let dist = behav.inter_max_dist * k as f32 / 1000.;
+                let error = (tables.potential(pair, dist) - behav.potential(dist)).abs();
+                assert!(error <= tolerance * strength, "{:?} at {}", behav, dist);
+            }
+            assert_eq!(tables.potential(pair, behav.inter_max_dist), 0.);
+            assert_eq!(tables.accel(pair, Vec3::X * 2., 4.), Vec3::ZERO);
+            assert_eq!(tables.accel(pair, Vec3::ZERO, 1.), Vec3::ZERO);
+        }
+
+        // A tighter tolerance refines the tables
+        let coarse = BehaviourTables::new(&behaviours[..1], 1.);
+        let fine = BehaviourTables::new(&behaviours[..1], 1e-5);
+        assert!(coarse.samples(0) <= TABLE_SAMPLES + 2);
+        assert!(fine.samples(0) > coarse.samples(0));
+    }
+}
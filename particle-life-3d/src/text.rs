@@ -0,0 +1,268 @@
+//! Text drawn as line segments, for labels floating in the scene without an engine text
+//! system: a tiny hardcoded vector font of digits, uppercase letters and a few symbols.
+use cimvr_common::{
+    glam::Vec3,
+    render::{Mesh, Vertex},
+};
+
+/// Largest number of vertices a mesh is grown to by text. Text beyond it is cut off.
+pub const MAX_TEXT_VERTICES: usize = 16_384;
+
+/// Width of a glyph, in grid units
+const GLYPH_WIDTH: f32 = 4.;
+
+/// Height of a capital, in grid units; the size of text is the height of its capitals
+const CAP_HEIGHT: f32 = 6.;
+
+/// Distance from one glyph to the next, in grid units
+const ADVANCE: f32 = 6.;
+
+/// Distance from one line to the next, in grid units
+const LINE_HEIGHT: f32 = 9.;
+
+/// Lowest and highest grid coordinates used by glyphs; commas and underscores dip below the
+/// baseline
+pub const GLYPH_BOUNDS: ([i8; 2], [i8; 2]) = ([0, -1], [4, 6]);
+
+/// Strokes of a glyph, each a polyline through points of a grid 4 units wide and 6 high, with
+/// the origin at the left of the baseline
+type Glyph = &'static [&'static [[i8; 2]]];
+
+/// Characters drawn, lowercase letters aside, which are drawn as uppercase
+pub const SUPPORTED: &str = "0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ.,-+=:/%()<>_?";
+
+/// Strokes of the glyph for `c`, if it has one. Lowercase letters share the uppercase
+/// glyphs, and spaces have none.
+pub fn glyph(c: char) -> Option<Glyph> {
+    let glyph: Glyph = match c.to_ascii_uppercase() {
+        '0' => &[&[[0, 0], [4, 0], [4, 6], [0, 6], [0, 0], [4, 6]]],
+        '1' => &[&[[1, 5], [2, 6], [2, 0]], &[[1, 0], [3, 0]]],
+        '2' => &[&[[0, 6], [4, 6], [4, 3], [0, 3], [0, 0], [4, 0]]],
+        '3' => &[&[[0, 6], [4, 6], [4, 0], [0, 0]], &[[1, 3], [4, 3]]],
+        '4' => &[&[[0, 6], [0, 3], [4, 3]], &[[4, 6], [4, 0]]],
+        '5' => &[&[
+            [4, 6],
+            [0, 6],
+            [0, 4],
+            [3, 4],
+            [4, 3],
+            [4, 1],
+            [3, 0],
+            [0, 0],
+        ]],
+        '6' => &[&[[4, 6], [0, 6], [0, 0], [4, 0], [4, 3], [0, 3]]],
+        '7' => &[&[[0, 6], [4, 6], [1, 0]]],
+        '8' => &[&[[0, 0], [4, 0], [4, 6], [0, 6], [0, 0]], &[[0, 3], [4, 3]]],
+        '9' => &[&[[4, 3], [0, 3], [0, 6], [4, 6], [4, 0], [0, 0]]],
+        'A' => &[&[[0, 0], [0, 4], [2, 6], [4, 4], [4, 0]], &[[0, 3], [4, 3]]],
+        'B' => &[
+            &[[0, 0], [0, 6], [3, 6], [4, 5], [4, 4], [3, 3], [0, 3]],
+            &[[3, 3], [4, 2], [4, 1], [3, 0], [0, 0]],
+        ],
+        'C' => &[&[[4, 6], [0, 6], [0, 0], [4, 0]]],
+        'D' => &[&[[0, 0], [0, 6], [2, 6], [4, 4], [4, 2], [2, 0], [0, 0]]],
+        'E' => &[&[[4, 6], [0, 6], [0, 0], [4, 0]], &[[0, 3], [3, 3]]],
+        'F' => &[&[[4, 6], [0, 6], [0, 0]], &[[0, 3], [3, 3]]],
+        'G' => &[&[[4, 6], [0, 6], [0, 0], [4, 0], [4, 3], [2, 3]]],
+        'H' => &[&[[0, 6], [0, 0]], &[[4, 6], [4, 0]], &[[0, 3], [4, 3]]],
+        'I' => &[&[[1, 6], [3, 6]], &[[2, 6], [2, 0]], &[[1, 0], [3, 0]]],
+        'J' => &[&[[4, 6], [4, 0], [0, 0], [0, 2]]],
+        'K' => &[&[[0, 6], [0, 0]], &[[4, 6], [0, 3], [4, 0]]],
+        'L' => &[&[[0, 6], [0, 0], [4, 0]]],
+        'M' => &[&[[0, 0], [0, 6], [2, 3], [4, 6], [4, 0]]],
+        'N' => &[&[[0, 0], [0, 6], [4, 0], [4, 6]]],
+        'O' => &[&[[0, 0], [4, 0], [4, 6], [0, 6], [0, 0]]],
+        'P' => &[&[[0, 0], [0, 6], [4, 6], [4, 3], [0, 3]]],
+        'Q' => &[&[[0, 0], [4, 0], [4, 6], [0, 6], [0, 0]], &[[2, 2], [4, 0]]],
+        'R' => &[&[[0, 0], [0, 6], [4, 6], [4, 3], [0, 3], [4, 0]]],
+        'S' => &[&[[4, 6], [0, 6], [0, 3], [4, 3], [4, 0], [0, 0]]],
+        'T' => &[&[[0, 6], [4, 6]], &[[2, 6], [2, 0]]],
+        'U' => &[&[[0, 6], [0, 0], [4, 0], [4, 6]]],
+        'V' => &[&[[0, 6], [2, 0], [4, 6]]],
+        'W' => &[&[[0, 6], [1, 0], [2, 3], [3, 0], [4, 6]]],
+        'X' => &[&[[0, 6], [4, 0]], &[[0, 0], [4, 6]]],
+        'Y' => &[&[[0, 6], [2, 3], [4, 6]], &[[2, 3], [2, 0]]],
+        'Z' => &[&[[0, 6], [4, 6], [0, 0], [4, 0]]],
+        '.' => &[&[[2, 0], [2, 1]]],
+        ',' => &[&[[2, 1], [1, -1]]],
+        '-' => &[&[[1, 3], [3, 3]]],
+        '+' => &[&[[0, 3], [4, 3]], &[[2, 1], [2, 5]]],
+        '=' => &[&[[0, 2], [4, 2]], &[[0, 4], [4, 4]]],
+        ':' => &[&[[2, 1], [2, 2]], &[[2, 4], [2, 5]]],
+        '/' => &[&[[0, 0], [4, 6]]],
+        '%' => &[&[[0, 0], [4, 6]], &[[0, 6], [0, 5]], &[[4, 0], [4, 1]]],
+        '(' => &[&[[3, 6], [1, 4], [1, 2], [3, 0]]],
+        ')' => &[&[[1, 6], [3, 4], [3, 2], [1, 0]]],
+        '<' => &[&[[4, 6], [0, 3], [4, 0]]],
+        '>' => &[&[[0, 6], [4, 3], [0, 0]]],
+        '_' => &[&[[0, -1], [4, -1]]],
+        '?' => &[
+            &[[0, 5], [1, 6], [3, 6], [4, 5], [4, 4], [2, 3], [2, 2]],
+            &[[2, 0], [2, 1]],
+        ],
+        _ => return None,
+    };
+    Some(glyph)
+}
+
+/// Number of line segments of a glyph
+fn segment_count(glyph: Glyph) -> usize {
+    glyph
+        .iter()
+        .map(|stroke| stroke.len().saturating_sub(1))
+        .sum()
+}
+
+/// Width and height of `text` drawn at `size`, from the left of its first baseline to the
+/// right of its longest line and the baseline of its last
+pub fn text_extent(text: &str, size: f32) -> (f32, f32) {
+    let unit = size / CAP_HEIGHT;
+    let longest = text.lines().map(|line| line.chars().count()).max();
+    let width = longest
+        .filter(|&n| n > 0)
+        .map_or(0., |n| (n - 1) as f32 * ADVANCE + GLYPH_WIDTH);
+    let lines = text.lines().count().max(1);
+    (width * unit, (lines - 1) as f32 * LINE_HEIGHT * unit)
+}
+
+/// Add `text` to a line mesh, its capitals `size` high, reading along +X in the XY plane
+/// from `origin` at the left of the first baseline. Lines after the first go down. Unsupported
+/// characters are drawn as question marks. Returns false if the text was cut off at
+/// [`MAX_TEXT_VERTICES`].
+pub fn add_text(mesh: &mut Mesh, text: &str, origin: Vec3, size: f32, color: [f32; 3]) -> bool {
+    add_text_oriented(mesh, text, origin, size, color, Vec3::X, Vec3::Y)
+}
+
+/// [`add_text`], facing `camera` rather than +Z and kept upright
+pub fn add_text_facing(
+    mesh: &mut Mesh,
+    text: &str,
+    origin: Vec3,
+    size: f32,
+    color: [f32; 3],
+    camera: Vec3,
+) -> bool {
+    let (right, up) = billboard(origin, camera);
+    add_text_oriented(mesh, text, origin, size, color, right, up)
+}
+
+/// Add `text` centered on `anchor` along its first baseline, facing `camera` if known, or +Z
+pub fn add_label(
+    mesh: &mut Mesh,
+    text: &str,
+    anchor: Vec3,
+    size: f32,
+    color: [f32; 3],
+    camera: Option<Vec3>,
+) -> bool {
+    let (right, up) = camera.map_or((Vec3::X, Vec3::Y), |camera| billboard(anchor, camera));
+    let (width, _) = text_extent(text, size);
+    let origin = anchor - right * (width / 2.);
+    add_text_oriented(mesh, text, origin, size, color, right, up)
+}
+
+/// Directions to the right and up of text at `origin` facing `camera`, upright unless seen
+/// from straight above or below
+fn billboard(origin: Vec3, camera: Vec3) -> (Vec3, Vec3) {
+    let Some(toward) = (camera - origin).try_normalize() else {
+        return (Vec3::X, Vec3::Y);
+    };
+    let right = Vec3::Y.cross(toward).try_normalize().unwrap_or(Vec3::X);
+    (right, toward.cross(right))
+}
+
+fn add_text_oriented(
+    mesh: &mut Mesh,
+    text: &str,
+    origin: Vec3,
+    size: f32,
+    color: [f32; 3],
+    right: Vec3,
+    up: Vec3,
+) -> bool {
+    let unit = size / CAP_HEIGHT;
+    for (row, line) in text.lines().enumerate() {
+        let baseline = origin - up * (row as f32 * LINE_HEIGHT * unit);
+        for (col, c) in line.chars().enumerate() {
+            if c.is_whitespace() {
+                continue;
+            }
+            let glyph = glyph(c).or_else(|| glyph('?')).unwrap();
+            if mesh.vertices.len() + segment_count(glyph) * 2 > MAX_TEXT_VERTICES {
+                return false;
+            }
+
+            let left = baseline + right * (col as f32 * ADVANCE * unit);
+            for stroke in glyph {
+                for segment in stroke.windows(2) {
+                    let [a, b] = [segment[0], segment[1]].map(|[x, y]| {
+                        let pos = left + (right * x as f32 + up * y as f32) * unit;
+                        mesh.push_vertex(Vertex {
+                            pos: pos.to_array(),
+                            uvw: color,
+                        })
+                    });
+                    mesh.push_indices(&[a, b]);
+                }
+            }
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glyphs() {
+        let ([min_x, min_y], [max_x, max_y]) = GLYPH_BOUNDS;
+        for c in SUPPORTED.chars().chain('a'..='z') {
+            let glyph = glyph(c).unwrap_or_else(|| panic!("No glyph for {:?}", c));
+            assert!(segment_count(glyph) > 0, "{:?} is empty", c);
+            for &[x, y] in glyph.iter().copied().flatten() {
+                assert!((min_x..=max_x).contains(&x), "{:?} is too wide", c);
+                assert!((min_y..=max_y).contains(&y), "{:?} is too tall", c);
+            }
+        }
+        assert!(glyph(' ').is_none());
+        assert!(glyph('é').is_none());
+    }
+
+    #[test]
+    fn test_add_text() {
+        let mut mesh = Mesh::new();
+        assert!(add_text(&mut mesh, "AB 12\n-0.5", Vec3::ZERO, 0.6, [1.; 3]));
+        assert_eq!(mesh.indices.len(), mesh.vertices.len());
+        let (width, height) = text_extent("AB 12\n-0.5", 0.6);
+        for v in &mesh.vertices {
+            let [x, y, z] = v.pos;
+            assert!((-1e-6..=width + 1e-6).contains(&x));
+            assert!((-height - 0.1 - 1e-6..=0.6 + 1e-6).contains(&y));
+            assert_eq!(z, 0.);
+        }
+
+        // Unsupported characters are still shown
+        let mut unknown = Mesh::new();
+        add_text(&mut unknown, "é", Vec3::ZERO, 1., [1.; 3]);
+        assert!(!unknown.vertices.is_empty());
+
+        // Facing a camera along +X, text reads along -Z
+        let mut facing = Mesh::new();
+        add_text_facing(&mut facing, "-", Vec3::ZERO, 6., [1.; 3], Vec3::X * 5.);
+        assert!(facing.vertices.iter().all(|v| v.pos[0].abs() < 1e-6));
+        assert!(facing.vertices.iter().all(|v| v.pos[2] < 0.));
+
+        // Labels are centered
+        let mut label = Mesh::new();
+        add_label(&mut label, "-", Vec3::ONE, 6., [1.; 3], None);
+        let xs: Vec<f32> = label.vertices.iter().map(|v| v.pos[0]).collect();
+        assert_eq!(xs, [0., 2.]);
+
+        // The vertex budget cuts off long text
+        let mut long = Mesh::new();
+        let text = "8".repeat(MAX_TEXT_VERTICES);
+        assert!(!add_text(&mut long, &text, Vec3::ZERO, 1., [1.; 3]));
+        assert!(long.vertices.len() <= MAX_TEXT_VERTICES);
+    }
+}
@@ -0,0 +1,317 @@
+//! Thermodynamic integration: the free-energy difference between two configurations, from
+//! Metropolis sampling along [`SimConfig::lerp`] between them. At each rung `λ` of a ladder,
+//! the ensemble average of `dU/dλ` is measured, and the averages are integrated over `λ` by
+//! the trapezoid rule, giving `F(b) - F(a)`.
+//!
+//! `U(λ)` is linear in `λ` when the configurations differ only in strengths (see
+//! [`SimConfig::lerp_is_linear`]), and `dU/dλ` is then exactly the energy under `b` minus the
+//! energy under `a`. Thresholds, max distances and anisotropy enter the potential
+//! nonlinearly, in which case `dU/dλ` is taken by central finite differences instead.
+use cimvr_engine_interface::{pcg::Pcg, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    mcmc::{metropolis_step, MetropolisConfig},
+    sim::{SimConfig, SimState},
+};
+
+/// Anyone to client: measure the free energy of `to` relative to the current configuration,
+/// sampling a copy of the simulation over the following frames. The result is printed once
+/// done. `None` cancels a run.
+#[derive(Message, Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[locality("Local")]
+pub struct IntegrateFreeEnergy {
+    pub to: Option<SimConfig>,
+}
+
+/// Step in `λ` of the finite differences, when the energy is not linear in it
+const FINITE_DIFFERENCE_STEP: f32 = 1e-2;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TiConfig {
+    /// Values of `λ` to sample at, in increasing order. Should start at 0 and end at 1 to
+    /// cover the whole path.
+    pub lambdas: Vec<f32>,
+    /// Sampling at each `λ`
+    pub metropolis: MetropolisConfig,
+    /// Sweeps discarded after each change of `λ`
+    pub equilibration_sweeps: usize,
+    /// Sweeps averaged over at each `λ`
+    pub sample_sweeps: usize,
+}
+
+/// Outcome of a thermodynamic integration
+#[derive(Clone, Debug, PartialEq)]
+pub struct TiResult {
+    /// Mean `dU/dλ` at each `λ`
+    pub curve: Vec<(f32, f32)>,
+    /// Free energy of `b` minus that of `a`
+    pub delta_f: f32,
+}
+
+/// A thermodynamic integration in progress, advanced a budget of sweeps at a time so that it
+/// can run over many frames
+pub struct ThermoIntegration {
+    config: TiConfig,
+    a: SimConfig,
+    b: SimConfig,
+    linear: bool,
+    sim: SimState,
+    /// Index of the current `λ`
+    rung: usize,
+    /// Sweeps done at the current `λ`
+    sweeps: usize,
+    sum: f64,
+    curve: Vec<(f32, f32)>,
+}
+
+impl TiConfig {
+    /// Ladder of `n` evenly spaced values of `λ` from 0 to 1
+    pub fn ladder(n: usize) -> Vec<f32> {
+        (0..n).map(|i| i as f32 / (n.max(2) - 1) as f32).collect()
+    }
+}
+
+impl Default for TiConfig {
+    fn default() -> Self {
+        Self {
+            lambdas: Self::ladder(11),
+            metropolis: MetropolisConfig {
+                temperature: 1e-2,
+                walk_sigma: 1e-2,
+            },
+            equilibration_sweeps: 200,
+            sample_sweeps: 1000,
+        }
+    }
+}
+
+impl TiResult {
+    /// The free energy difference, followed by the mean `dU/dλ` at each `λ`
+    pub fn report(&self) -> String {
+        let mut report = format!("Free energy difference: {:.4}", self.delta_f);
+        for (lambda, slope) in &self.curve {
+            report += &format!("\n  λ = {:.2}: dU/dλ = {:.4}", lambda, slope);
+        }
+        report
+    }
+}
+
+impl ThermoIntegration {
+    /// Integrate from `a` to `b`, which must have as many types, sampling with `sim`. Its
+    /// configuration is replaced along the way; everything else about it, e.g. tethers, is
+    /// kept.
+    pub fn new(config: TiConfig, a: SimConfig, b: SimConfig, mut sim: SimState) -> Self {
+        assert_eq!(a.colors.len(), b.colors.len(), "Number of types");
+        let linear = a.lerp_is_linear(&b);
+        if let Some(&lambda) = config.lambdas.first() {
+            set_config(&mut sim, a.lerp(&b, lambda));
+        }
+        Self {
+            config,
+            a,
+            b,
+            linear,
+            sim,
+            rung: 0,
+            sweeps: 0,
+            sum: 0.,
+            curve: vec![],
+        }
+    }
+
+    /// Run up to `max_sweeps` more sweeps. Returns the result once every `λ` is done.
+    pub fn advance(&mut self, max_sweeps: usize, rng: &mut Pcg) -> Option<TiResult> {
+        let per_rung = self.config.equilibration_sweeps + self.config.sample_sweeps;
+        for _ in 0..max_sweeps {
+            let Some(&lambda) = self.config.lambdas.get(self.rung) else {
+                break;
+            };
+
+            metropolis_step(&mut self.sim, &self.config.metropolis, rng);
+            self.sweeps += 1;
+            if self.sweeps > self.config.equilibration_sweeps {
+                self.sum += self.energy_slope(lambda) as f64;
+            }
+
+            if self.sweeps == per_rung {
+                let mean = self.sum / self.config.sample_sweeps.max(1) as f64;
+                self.curve.push((lambda, mean as f32));
+                self.rung += 1;
+                self.sweeps = 0;
+                self.sum = 0.;
+                if let Some(&next) = self.config.lambdas.get(self.rung) {
+                    set_config(&mut self.sim, self.a.lerp(&self.b, next));
+                }
+            }
+        }
+        self.result()
+    }
+
+    /// Fraction of the sweeps done, from 0 to 1
+    pub fn progress(&self) -> f32 {
+        let per_rung = self.config.equilibration_sweeps + self.config.sample_sweeps;
+        let total = per_rung * self.config.lambdas.len();
+        match total {
+            0 => 1.,
+            total => (self.rung * per_rung + self.sweeps) as f32 / total as f32,
+        }
+    }
+
+    /// The result, once every `λ` is done
+    pub fn result(&self) -> Option<TiResult> {
+        (self.rung >= self.config.lambdas.len()).then(|| TiResult {
+            delta_f: trapezoid(&self.curve),
+            curve: self.curve.clone(),
+        })
+    }
+
+    /// The simulation being sampled
+    pub fn sim(&self) -> &SimState {
+        &self.sim
+    }
+
+    /// `dU/dλ` of the current positions at `lambda`
+    fn energy_slope(&mut self, lambda: f32) -> f32 {
+        let (lo, hi) = match self.linear {
+            true => (0., 1.),
+            false => (
+                (lambda - FINITE_DIFFERENCE_STEP).max(0.),
+                (lambda + FINITE_DIFFERENCE_STEP).min(1.),
+            ),
+        };
+        let mut energy_at = |t: f32| {
+            set_config(&mut self.sim, self.a.lerp(&self.b, t));
+            self.sim.potential_energy()
+        };
+        let slope = (energy_at(hi) - energy_at(lo)) / (hi - lo);
+        set_config(&mut self.sim, self.a.lerp(&self.b, lambda));
+        slope
+    }
+}
+
+/// Integral of a curve of `(x, y)` points over `x` by the trapezoid rule
+pub fn trapezoid(curve: &[(f32, f32)]) -> f32 {
+    curve
+        .windows(2)
+        .map(|w| (w[1].0 - w[0].0) * (w[0].1 + w[1].1) / 2.)
+        .sum()
+}
+
+/// Replace the configuration, which never changes the number of types here
+fn set_config(sim: &mut SimState, config: SimConfig) {
+    // No particle's type goes out of range, so nothing is drawn at random
+    sim.set_config(config, &mut Pcg::new());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::{Behaviour, Gravity, Particle};
+    use cimvr_common::glam::Vec3;
+
+    #[test]
+    fn test_trapezoid() {
+        assert_eq!(trapezoid(&[(0., 1.), (0.5, 2.), (1., 1.)]), 1.5);
+        assert_eq!(trapezoid(&[(0., 1.)]), 0.);
+    }
+
+    #[test]
+    fn test_identical_configs() {
+        let mut rng = Pcg::new();
+        let mut config = SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![Behaviour::default(); 4],
+            damping: 0.,
+            ..Default::default()
+        };
+        config.randomize_field(crate::sim::Field::Strength, true, &mut rng);
+        let sim = SimState::new(&mut rng, config.clone(), 100);
+        let ti_config = TiConfig {
+            lambdas: TiConfig::ladder(3),
+            equilibration_sweeps: 5,
+            sample_sweeps: 20,
+            ..Default::default()
+        };
+        let mut ti = ThermoIntegration::new(ti_config, config.clone(), config, sim);
+        assert_eq!(ti.advance(10, &mut rng), None);
+        assert!((ti.progress() - 10. / 75.).abs() < 1e-6);
+        let result = ti.advance(usize::MAX, &mut rng).unwrap();
+        assert_eq!(result.curve.len(), 3);
+        assert!(result.delta_f.abs() < 1e-3, "{:?}", result);
+        let report = result.report();
+        assert!(report.starts_with("Free energy difference"));
+        assert_eq!(report.lines().count(), 4);
+    }
+
+    #[test]
+    fn test_harmonic_well() {
+        // A particle tethered to the origin, pulled aside by gravity of weight `w` at `b`.
+        // The well shifts by `w / k`, lowering the free energy by `w^2 / 2k`.
+        let (k, w) = (100., 2.);
+        let config = |weight: f32| SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![Behaviour::default()],
+            damping: 0.,
+            gravity: Some(Gravity {
+                down: Vec3::X,
+                weights: vec![weight],
+            }),
+            ..Default::default()
+        };
+        let particle = Particle {
+            pos: Vec3::ZERO,
+            vel: Vec3::ZERO,
+            color: 0,
+        };
+        let mut sim = SimState::from_particles(config(0.), vec![particle]);
+        sim.set_homes_to_current();
+        sim.set_tether_stiffness(k);
+
+        let mut rng = Pcg::new();
+        let ti_config = TiConfig {
+            lambdas: TiConfig::ladder(5),
+            equilibration_sweeps: 200,
+            sample_sweeps: 4000,
+            ..Default::default()
+        };
+        let mut ti = ThermoIntegration::new(ti_config, config(0.), config(w), sim);
+        let result = ti.advance(usize::MAX, &mut rng).unwrap();
+        let expected = -w * w / (2. * k);
+        assert!(
+            (result.delta_f - expected).abs() < expected.abs() * 0.1,
+            "{} {}",
+            result.delta_f,
+            expected
+        );
+    }
+
+    #[test]
+    fn test_nonlinear_path() {
+        // Changing the range is nonlinear, but with one particle there is no pair energy
+        let mut far = Behaviour::default();
+        far.inter_max_dist *= 0.5;
+        let config = |behav: Behaviour| SimConfig {
+            colors: vec![[1.; 3]],
+            behaviours: vec![behav],
+            damping: 0.,
+            ..Default::default()
+        };
+        let (a, b) = (config(Behaviour::default()), config(far));
+        assert!(!a.lerp_is_linear(&b));
+        assert!(a.lerp_is_linear(&a.lerp(&a, 0.5)));
+
+        let mut rng = Pcg::new();
+        let sim = SimState::new(&mut rng, a.clone(), 1);
+        let ti_config = TiConfig {
+            lambdas: TiConfig::ladder(2),
+            equilibration_sweeps: 1,
+            sample_sweeps: 2,
+            ..Default::default()
+        };
+        let result = ThermoIntegration::new(ti_config, a, b, sim)
+            .advance(usize::MAX, &mut rng)
+            .unwrap();
+        assert_eq!(result.delta_f, 0.);
+    }
+}
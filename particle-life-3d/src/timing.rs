@@ -0,0 +1,394 @@
+//! Lightweight frame profiling. Everything here compiles to no-ops unless the `profiling`
+//! feature is enabled.
+//!
+//! There is no clock inside the wasm plugin sandbox, so durations are only measured on native
+//! targets (e.g. tests and benchmarks); counters work everywhere.
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::forces::ContributorStatus;
+
+/// Phases of a client frame which are timed separately
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    AccelRebuild,
+    Step,
+    MeshBuild,
+    Send,
+}
+
+/// Measures the time since it was started
+pub struct Timer {
+    #[cfg(all(feature = "profiling", not(target_arch = "wasm32")))]
+    start: std::time::Instant,
+}
+
+/// Exponentially smoothed phase timings and per-frame counters
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    phase_ms: [f32; Phase::ALL.len()],
+    pub particles: usize,
+    pub neighbor_pairs: usize,
+    pub accel_cells: usize,
+    /// Fraction of the particles binned by an amortized accelerator rebuild in progress
+    pub rebuild_progress: Option<f32>,
+    pub capped_particles: usize,
+    /// Particles found far out from the cloud, see [`find_escapes`]
+    ///
+    /// [`find_escapes`]: crate::diagnostics::find_escapes
+    pub escaped: usize,
+    /// Growth of the diagonal of the cloud's bounding box in the world, in meters per unit
+    /// of simulated time, see [`SpreadTracker`]
+    ///
+    /// [`SpreadTracker`]: crate::diagnostics::SpreadTracker
+    pub spread_rate: f32,
+    /// Force contributors with their cost per step, refreshed for each report
+    pub forces: Vec<ContributorStatus>,
+    until_report: usize,
+}
+
+/// Decides which frames the simulation steps on, pausing while hidden and throttling while
+/// nobody interacts with it
+#[derive(Clone, Debug)]
+pub struct Pacer {
+    /// Stop stepping entirely while the simulation is hidden
+    pub auto_pause_hidden: bool,
+    /// Frames without interaction before stepping is throttled, if at all
+    pub idle_after: Option<usize>,
+    /// While idle, step once every this many frames
+    pub idle_stride: usize,
+    hidden: bool,
+    frames_since_interaction: usize,
+    until_step: usize,
+}
+
+/// Whether the simulation runs, waits, or is being advanced a few steps while paused, one per
+/// frame so that every step is seen
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum StepController {
+    #[default]
+    Running,
+    Paused,
+    /// Paused, with this many commanded steps still to run
+    SteppingRemaining(u32),
+}
+
+/// Anyone to client: pause, resume or single-step the simulation, e.g. from a button. See
+/// [`StepController`].
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub enum StepCommand {
+    Pause,
+    Resume,
+    TogglePause,
+    /// Run this many steps, one per frame, then stay paused
+    Step(u32),
+}
+
+/// Weight of the newest sample in the smoothed timings
+const SMOOTHING: f32 = 0.1;
+
+/// Number of frames between reports
+const REPORT_INTERVAL: usize = 240;
+
+impl Phase {
+    pub const ALL: [Phase; 4] = [
+        Phase::AccelRebuild,
+        Phase::Step,
+        Phase::MeshBuild,
+        Phase::Send,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Phase::AccelRebuild => "Accelerator rebuild",
+            Phase::Step => "Integrator step",
+            Phase::MeshBuild => "Mesh build",
+            Phase::Send => "Message send",
+        }
+    }
+}
+
+impl Timer {
+    pub fn start() -> Self {
+        Self {
+            #[cfg(all(feature = "profiling", not(target_arch = "wasm32")))]
+            start: std::time::Instant::now(),
+        }
+    }
+
+    /// Milliseconds since the timer was started, if a clock is available
+    pub fn elapsed_ms(&self) -> Option<f32> {
+        #[cfg(all(feature = "profiling", not(target_arch = "wasm32")))]
+        return Some(self.start.elapsed().as_secs_f32() * 1e3);
+
+        #[allow(unreachable_code)]
+        None
+    }
+}
+
+impl Pacer {
+    pub fn set_hidden(&mut self, hidden: bool) {
+        self.hidden = hidden;
+    }
+
+    /// Note that the user interacted with the simulation, leaving idle mode
+    pub fn interacted(&mut self) {
+        self.frames_since_interaction = 0;
+        self.until_step = 0;
+    }
+
+    pub fn is_idle(&self) -> bool {
+        self.idle_after
+            .is_some_and(|after| self.frames_since_interaction >= after)
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.auto_pause_hidden && self.hidden
+    }
+
+    /// Advance one frame; returns true if the simulation should step on this frame. Frames
+    /// which are skipped are not made up for later.
+    pub fn tick(&mut self) -> bool {
+        if self.is_paused() {
+            return false;
+        }
+
+        self.frames_since_interaction = self.frames_since_interaction.saturating_add(1);
+        if !self.is_idle() {
+            return true;
+        }
+
+        if self.until_step == 0 {
+            self.until_step = self.idle_stride.max(1);
+        }
+        self.until_step -= 1;
+        self.until_step == 0
+    }
+}
+
+impl StepController {
+    pub fn pause(&mut self) {
+        *self = StepController::Paused;
+    }
+
+    pub fn resume(&mut self) {
+        *self = StepController::Running;
+    }
+
+    /// Pause if running, or resume otherwise, cancelling any commanded steps
+    pub fn toggle(&mut self) {
+        match self {
+            StepController::Running => self.pause(),
+            _ => self.resume(),
+        }
+    }
+
+    /// Run `n` more steps, then stay paused. Pauses first if running.
+    pub fn step(&mut self, n: u32) {
+        *self = match *self {
+            StepController::SteppingRemaining(left) => left.saturating_add(n),
+            _ => n,
+        }
+        .into();
+    }
+
+    /// Whether stepping is up to the user, including while commanded steps remain
+    pub fn is_paused(&self) -> bool {
+        *self != StepController::Running
+    }
+
+    /// Advance one frame on which the simulation may step; returns true if it should. The
+    /// last commanded step leaves the controller paused.
+    pub fn tick(&mut self) -> bool {
+        match *self {
+            StepController::Running => true,
+            StepController::Paused => false,
+            StepController::SteppingRemaining(left) => {
+                *self = (left - 1).into();
+                true
+            }
+        }
+    }
+}
+
+impl StepCommand {
+    pub fn apply(&self, control: &mut StepController) {
+        match *self {
+            StepCommand::Pause => control.pause(),
+            StepCommand::Resume => control.resume(),
+            StepCommand::TogglePause => control.toggle(),
+            StepCommand::Step(n) => control.step(n),
+        }
+    }
+}
+
+impl From<u32> for StepController {
+    /// Paused with `n` steps to run
+    fn from(n: u32) -> Self {
+        match n {
+            0 => StepController::Paused,
+            n => StepController::SteppingRemaining(n),
+        }
+    }
+}
+
+impl Default for Pacer {
+    fn default() -> Self {
+        Self {
+            auto_pause_hidden: true,
+            // Five minutes at 60 FPS
+            idle_after: Some(5 * 60 * 60),
+            idle_stride: 4,
+            hidden: false,
+            frames_since_interaction: 0,
+            until_step: 0,
+        }
+    }
+}
+
+impl Profile {
+    /// Run `f`, recording how long it took as `phase`
+    pub fn time<R>(&mut self, phase: Phase, f: impl FnOnce() -> R) -> R {
+        let timer = Timer::start();
+        let ret = f();
+        self.record(phase, timer.elapsed_ms());
+        ret
+    }
+
+    /// Add a sample to the smoothed timing of `phase`
+    pub fn record(&mut self, phase: Phase, ms: Option<f32>) {
+        if let Some(ms) = ms {
+            let avg = &mut self.phase_ms[phase as usize];
+            *avg += (ms - *avg) * SMOOTHING;
+        }
+    }
+
+    /// Smoothed duration of the given phase in milliseconds
+    pub fn ms(&self, phase: Phase) -> f32 {
+        self.phase_ms[phase as usize]
+    }
+
+    /// Advance one frame; returns true when it is time to show a report
+    pub fn tick(&mut self) -> bool {
+        if self.until_report == 0 {
+            self.until_report = REPORT_INTERVAL;
+        }
+        self.until_report -= 1;
+        cfg!(feature = "profiling") && self.until_report == 0
+    }
+
+    /// Human readable summary, one line per phase with a bar proportional to its share
+    pub fn report(&self) -> String {
+        let total: f32 = self.phase_ms.iter().sum();
+        let mut report = String::new();
+        for phase in Phase::ALL {
+            let ms = self.ms(phase);
+            let bar_len = if total > 0. {
+                (ms / total * 20.).round() as usize
+            } else {
+                0
+            };
+            report += &format!(
+                "{:>20}: {:>7.3} ms {}\n",
+                phase.name(),
+                ms,
+                "#".repeat(bar_len)
+            );
+        }
+        report += &format!(
+            "{} particles, {} neighbor pairs, {} accelerator cells, {} particles over the neighbor cap, {} escaped\n",
+            self.particles, self.neighbor_pairs, self.accel_cells, self.capped_particles, self.escaped
+        );
+        report += &format!(
+            "bounding box growing {:.3} m per unit time\n",
+            self.spread_rate
+        );
+        if let Some(progress) = self.rebuild_progress {
+            report += &format!("accelerator rebuild {:.0}% done\n", progress * 100.);
+        }
+        for force in &self.forces {
+            let cost = match (force.enabled, force.cost_ms) {
+                (false, _) => "off".to_string(),
+                (true, Some(ms)) => format!("{:.3} ms", ms),
+                (true, None) => "on".to_string(),
+            };
+            report += &format!("{:>20}: {}\n", force.name, cost);
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_step_controller() {
+        let mut control = StepController::default();
+        let steps = |control: &mut StepController, frames: usize| {
+            (0..frames).filter(|_| control.tick()).count()
+        };
+        assert_eq!(steps(&mut control, 5), 5);
+
+        control.toggle();
+        assert_eq!(control, StepController::Paused);
+        assert_eq!(steps(&mut control, 5), 0);
+
+        control.step(1);
+        assert!(control.is_paused());
+        assert_eq!(steps(&mut control, 5), 1);
+        assert_eq!(control, StepController::Paused);
+
+        // Commands add up, and each frame runs one step
+        control.step(3);
+        assert!(control.tick());
+        control.step(2);
+        assert_eq!(control, StepController::SteppingRemaining(4));
+        assert_eq!(steps(&mut control, 10), 4);
+        control.step(0);
+        assert_eq!(control, StepController::Paused);
+
+        // Stepping while running pauses afterwards; toggling cancels the rest
+        control.resume();
+        control.step(2);
+        assert_eq!(steps(&mut control, 5), 2);
+        control.step(5);
+        control.toggle();
+        assert_eq!(control, StepController::Running);
+    }
+
+    #[test]
+    fn test_smoothing() {
+        let mut profile = Profile::default();
+        for _ in 0..200 {
+            profile.record(Phase::Step, Some(4.));
+        }
+        profile.record(Phase::MeshBuild, Some(1.));
+        assert!((profile.ms(Phase::Step) - 4.).abs() < 1e-3);
+        assert!((profile.ms(Phase::MeshBuild) - 0.1).abs() < 1e-6);
+        assert!(profile.time(Phase::Send, || 5) == 5);
+    }
+
+    #[test]
+    fn test_pacer() {
+        let mut pacer = Pacer {
+            idle_after: Some(10),
+            idle_stride: 3,
+            ..Default::default()
+        };
+
+        let steps = |pacer: &mut Pacer, frames: usize| (0..frames).filter(|_| pacer.tick()).count();
+        assert_eq!(steps(&mut pacer, 9), 9);
+        assert!(!pacer.is_idle());
+        assert_eq!(steps(&mut pacer, 30), 10);
+        assert!(pacer.is_idle());
+
+        pacer.set_hidden(true);
+        assert_eq!(steps(&mut pacer, 30), 0);
+        pacer.set_hidden(false);
+        pacer.interacted();
+        assert!(!pacer.is_idle());
+        assert_eq!(steps(&mut pacer, 5), 5);
+    }
+}
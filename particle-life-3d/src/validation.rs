@@ -0,0 +1,347 @@
+//! Two-body validation scenario: two particles, and a high precision reference for how they
+//! should move, to check the integrators against.
+//!
+//! The reference integrates the same force law as [`SimState::step`] in f64 with RK4 and many
+//! substeps, so its own error is negligible next to the integrator's.
+use cimvr_common::{
+    glam::{DVec3, Vec3},
+    render::{Mesh, MeshHandle, Vertex},
+};
+use cimvr_engine_interface::{pkg_namespace, prelude::*};
+use serde::{Deserialize, Serialize};
+
+use crate::sim::{Behaviour, Particle, SimConfig, SimState};
+
+/// RK4 substeps per simulation step
+const SUBSTEPS: usize = 16;
+
+/// Largest number of reference positions kept for the overlay
+const MAX_PATH_LEN: usize = 4_096;
+
+/// Handle of the reference path overlay, see [`TwoBodyValidation::path_mesh`]
+pub const PATH_HANDLE: MeshHandle = MeshHandle::new(pkg_namespace!("TwoBodyPath"));
+
+/// Anyone to client: replace the simulation with a two-body test, keeping its configuration,
+/// and follow it with the reference path and readouts. `None` ends the test, leaving the
+/// two particles.
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[locality("Local")]
+pub struct TwoBodyTest {
+    pub test: Option<TwoBody>,
+}
+
+/// Initial conditions of the scenario
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TwoBody {
+    /// Types of the two particles
+    pub types: [u8; 2],
+    /// Initial distance, along X
+    pub separation: f32,
+    /// Initial relative speed along Z, perpendicular to the separation
+    pub tangential_speed: f32,
+}
+
+/// Position and velocity of both particles
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct State {
+    pos: [DVec3; 2],
+    vel: [DVec3; 2],
+}
+
+/// Comparison of the simulation against the reference
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Readout {
+    pub separation: f32,
+    pub reference_separation: f32,
+    /// Change in the simulation's energy since the start; see [`TwoBodyValidation::energy`]
+    pub energy_error: f32,
+    /// Angle between the simulated and reference separation vectors, in radians
+    pub phase_drift: f32,
+}
+
+/// Reference trajectory for a running two-body scenario
+pub struct TwoBodyValidation {
+    /// Behaviour of each particle towards the other
+    behaviours: [Behaviour; 2],
+    damping: f64,
+    state: State,
+    initial_energy: f32,
+    path: Vec<[Vec3; 2]>,
+    /// Steps taken since the start
+    steps: usize,
+}
+
+impl TwoBody {
+    /// Whether both types exist in `config`, and the initial conditions are finite with the
+    /// particles apart
+    pub fn is_valid(&self, config: &SimConfig) -> bool {
+        self.types
+            .iter()
+            .all(|&t| (t as usize) < config.colors.len())
+            && self.separation.is_finite()
+            && self.separation > 0.
+            && self.tangential_speed.is_finite()
+    }
+
+    /// Simulation containing just the two particles, at rest apart from the tangential
+    /// velocity, with their center of mass at the origin
+    pub fn setup(&self, config: SimConfig) -> SimState {
+        let particles = (0..2)
+            .map(|i| {
+                let sign = if i == 0 { -0.5 } else { 0.5 };
+                Particle {
+                    pos: Vec3::X * self.separation * sign,
+                    vel: Vec3::Z * self.tangential_speed * sign,
+                    color: self.types[i],
+                }
+            })
+            .collect();
+        SimState::from_particles(config, particles)
+    }
+}
+
+impl Readout {
+    /// One line summary of the comparison
+    pub fn report(&self) -> String {
+        format!(
+            "Separation {:.4} (reference {:.4}), energy error {:.2e}, phase drift {:.2}°",
+            self.separation,
+            self.reference_separation,
+            self.energy_error,
+            self.phase_drift.to_degrees()
+        )
+    }
+}
+
+impl TwoBodyValidation {
+    /// Start the reference from the current state of a two-particle simulation
+    pub fn new(sim: &SimState) -> Self {
+        let p = sim.particles();
+        assert_eq!(
+            p.len(),
+            2,
+            "Two-body validation needs exactly two particles"
+        );
+        let state = State {
+            pos: [p[0].pos.as_dvec3(), p[1].pos.as_dvec3()],
+            vel: [p[0].vel.as_dvec3(), p[1].vel.as_dvec3()],
+        };
+        let config = sim.config();
+        Self {
+            behaviours: [
+                config.get_bahaviour(p[0].color, p[1].color),
+                config.get_bahaviour(p[1].color, p[0].color),
+            ],
+            damping: config.damping as f64,
+            state,
+            initial_energy: Self::energy(sim),
+            path: vec![[p[0].pos, p[1].pos]],
+            steps: 0,
+        }
+    }
+
+    /// Advance the reference by the same time step as the simulation
+    pub fn step(&mut self, dt: f32) {
+        let h = dt as f64 / SUBSTEPS as f64;
+        for _ in 0..SUBSTEPS {
+            self.state = self.rk4(self.state, h);
+        }
+        if self.path.len() < MAX_PATH_LEN {
+            self.path.push(self.reference_positions());
+        }
+        self.steps += 1;
+    }
+
+    /// Steps taken since the start
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    /// Kinetic energy plus the potential of the first particle's behaviour towards the
+    /// second. Only conserved when the pair's behaviours are symmetric and there is no
+    /// damping.
+    pub fn energy(sim: &SimState) -> f32 {
+        let [a, b] = [sim.particles()[0], sim.particles()[1]];
+        let behav = sim.config().get_bahaviour(a.color, b.color);
+        let dist = ((b.pos - a.pos) * behav.anisotropy).length();
+        let kinetic = (a.vel.length_squared() + b.vel.length_squared()) / 2.;
+        kinetic + behav.potential(dist)
+    }
+
+    pub fn readout(&self, sim: &SimState) -> Readout {
+        let [a, b] = [sim.particles()[0], sim.particles()[1]];
+        let sim_sep = b.pos - a.pos;
+        let ref_sep = (self.state.pos[1] - self.state.pos[0]).as_vec3();
+        Readout {
+            separation: sim_sep.length(),
+            reference_separation: ref_sep.length(),
+            energy_error: Self::energy(sim) - self.initial_energy,
+            phase_drift: sim_sep.angle_between(ref_sep),
+        }
+    }
+
+    /// Current reference positions of both particles
+    pub fn reference_positions(&self) -> [Vec3; 2] {
+        self.state.pos.map(|p| p.as_vec3())
+    }
+
+    /// Faint line mesh of the path each particle should have followed
+    pub fn path_mesh(&self) -> Mesh {
+        let mut mesh = Mesh::new();
+        for particle in 0..2 {
+            let mut last = None;
+            for positions in &self.path {
+                let idx = mesh.push_vertex(Vertex {
+                    pos: positions[particle].to_array(),
+                    uvw: [0.3; 3],
+                });
+                if let Some(last) = last {
+                    mesh.push_indices(&[last, idx]);
+                }
+                last = Some(idx);
+            }
+        }
+        mesh
+    }
+
+    /// One RK4 step of the equations of motion
+    fn rk4(&self, s: State, h: f64) -> State {
+        let deriv = |s: State| (s.vel, self.accel(&s));
+        let add = |s: State, (dp, dv): ([DVec3; 2], [DVec3; 2]), k: f64| State {
+            pos: [s.pos[0] + dp[0] * k, s.pos[1] + dp[1] * k],
+            vel: [s.vel[0] + dv[0] * k, s.vel[1] + dv[1] * k],
+        };
+
+        let k1 = deriv(s);
+        let k2 = deriv(add(s, k1, h / 2.));
+        let k3 = deriv(add(s, k2, h / 2.));
+        let k4 = deriv(add(s, k3, h));
+
+        let mut out = s;
+        for i in 0..2 {
+            out.pos[i] += (k1.0[i] + (k2.0[i] + k3.0[i]) * 2. + k4.0[i]) * h / 6.;
+            out.vel[i] += (k1.1[i] + (k2.1[i] + k3.1[i]) * 2. + k4.1[i]) * h / 6.;
+        }
+        out
+    }
+
+    /// Acceleration of each particle, with the force law and damping of [`SimState::step`]
+    fn accel(&self, s: &State) -> [DVec3; 2] {
+        [0, 1].map(|i| {
+            let behav = &self.behaviours[i];
+            let diff = (s.pos[1 - i] - s.pos[i]) * behav.anisotropy.as_dvec3();
+            pair_accel(behav, diff) - s.vel[i] * self.damping
+        })
+    }
+}
+
+/// f64 version of the pair force
+fn pair_accel(behav: &Behaviour, diff: DVec3) -> DVec3 {
+    let dist_sq = diff.length_squared();
+    let max = behav.inter_max_dist as f64;
+    if dist_sq > max * max || dist_sq == 0. {
+        return DVec3::ZERO;
+    }
+    let dist = dist_sq.sqrt();
+
+    let t = behav.inter_threshold as f64;
+    let force = if dist < t {
+        (1. - dist / t) * -behav.default_repulse as f64
+    } else if dist >= max {
+        0.
+    } else {
+        let x = (dist - t) / (max - t) * 2. - 1.;
+        (1. - x.abs()) * behav.inter_strength as f64
+    };
+    diff * (force / dist_sq)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::InteractionMode;
+
+    fn config(damping: f32) -> SimConfig {
+        let behav = Behaviour {
+            default_repulse: 5.,
+            inter_threshold: 0.05,
+            inter_strength: 1.,
+            inter_max_dist: 0.3,
+            anisotropy: Vec3::ONE,
+            polarity: 0.,
+            mode: InteractionMode::Potential,
+        };
+        SimConfig {
+            colors: vec![[1.; 3]; 2],
+            behaviours: vec![behav; 4],
+            damping,
+            ..Default::default()
+        }
+    }
+
+    /// Largest distance between the simulated and reference particles over `time`
+    fn max_error(scenario: &TwoBody, dt: f32, time: f32) -> f32 {
+        let mut sim = scenario.setup(config(0.));
+        let mut validation = TwoBodyValidation::new(&sim);
+        let mut max_error: f32 = 0.;
+        for _ in 0..(time / dt).round() as usize {
+            sim.step(dt);
+            validation.step(dt);
+            for (particle, reference) in
+                sim.particles().iter().zip(validation.reference_positions())
+            {
+                max_error = max_error.max(particle.pos.distance(reference));
+            }
+        }
+        max_error
+    }
+
+    #[test]
+    fn test_integrator_converges_to_reference() {
+        let scenario = TwoBody {
+            types: [0, 1],
+            separation: 0.2,
+            tangential_speed: 0.5,
+        };
+
+        // The explicit integrator is first order: a quarter of the step, a quarter of the error
+        let coarse = max_error(&scenario, 4e-3, 0.4);
+        let fine = max_error(&scenario, 1e-3, 0.4);
+        assert!(fine < 1e-3, "{}", fine);
+        let ratio = coarse / fine;
+        assert!((3.0..5.0).contains(&ratio), "{} {}", coarse, fine);
+    }
+
+    #[test]
+    fn test_readout() {
+        let scenario = TwoBody {
+            types: [0, 1],
+            separation: 0.2,
+            tangential_speed: 0.5,
+        };
+        let config = config(0.);
+        assert!(scenario.is_valid(&config));
+        let mut invalid = [scenario; 2];
+        invalid[0].types[1] = 2;
+        invalid[1].separation = 0.;
+        assert!(invalid.iter().all(|test| !test.is_valid(&config)));
+        let mut sim = scenario.setup(config);
+        assert_eq!(sim.particles()[1].pos.distance(sim.particles()[0].pos), 0.2);
+
+        let mut validation = TwoBodyValidation::new(&sim);
+        for _ in 0..200 {
+            sim.step(1e-3);
+            validation.step(1e-3);
+        }
+        assert_eq!(validation.steps(), 200);
+        let readout = validation.readout(&sim);
+        assert!(readout.report().starts_with("Separation 0.065"));
+        assert!((readout.separation - readout.reference_separation).abs() < 2e-3);
+        assert!(readout.phase_drift < 0.05, "{:?}", readout);
+        assert!(readout.energy_error.abs() < 1e-2, "{:?}", readout);
+
+        let mesh = validation.path_mesh();
+        assert_eq!(mesh.vertices.len(), 2 * 201);
+        assert_eq!(mesh.indices.len(), 2 * 2 * 200);
+    }
+}
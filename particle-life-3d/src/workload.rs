@@ -0,0 +1,254 @@
+//! Captured force pass workloads, for optimizing the hot loops offline.
+//!
+//! Sending [`CaptureWorkload`] makes the client log everything the force pass consumed on the
+//! last step as a hex encoded [`Workload`]: positions, types, the configuration, the cell
+//! layout of the query accelerator and the neighbors visited by each particle. The
+//! `replay_workload` example reruns the force and energy calculations over a captured
+//! workload in a timing loop, checking that the accelerator still finds the same neighbors.
+//!
+//! Blobs start with a little endian `u32` version, followed by the bincode encoded
+//! [`Workload`], like the settings blobs.
+use cimvr_common::glam::Vec3;
+use cimvr_engine_interface::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    persist::PersistError,
+    query_accel::AccelMode,
+    sim::{Particle, SimConfig, SimState},
+};
+
+/// Version of the workload blob layout
+const WORKLOAD_VERSION: u32 = 3;
+
+/// Largest particle count accepted from a blob
+const MAX_PARTICLES: usize = 10_000_000;
+
+/// Anyone to client: log the workload of the last step
+#[derive(Message, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[locality("Local")]
+pub struct CaptureWorkload;
+
+/// Everything the force pass of one step consumed, and the neighbors it visited
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Workload {
+    pub config: SimConfig,
+    /// Positions the accelerator was built over
+    pub positions: Vec<Vec3>,
+    pub colors: Vec<u8>,
+    /// Cell size of the accelerator
+    pub radius: f32,
+    pub mode: AccelMode,
+    /// Coordinates and point count of each occupied cell, sorted by coordinates
+    pub cells: Vec<([i32; 3], u32)>,
+    /// Start of each particle's neighbors in `neighbors`, plus the end of the last
+    pub neighbor_starts: Vec<u32>,
+    /// Neighbors of every particle, each particle's sorted
+    pub neighbors: Vec<u32>,
+}
+
+impl Workload {
+    /// Capture the workload of the last step, or `None` if particles were added or removed
+    /// since, leaving the accelerator behind
+    pub fn capture(sim: &SimState) -> Option<Self> {
+        let (accel, points) = sim.last_accel();
+        if points.len() != sim.particles().len() {
+            return None;
+        }
+        let (neighbor_starts, neighbors) = neighbor_lists(sim);
+        Some(Self {
+            config: sim.config().clone(),
+            positions: points.to_vec(),
+            colors: sim.particles().iter().map(|p| p.color).collect(),
+            radius: accel.radius(),
+            mode: accel.mode(),
+            cells: accel.cell_layout(),
+            neighbor_starts,
+            neighbors,
+        })
+    }
+
+    /// A simulation at rest in the captured state, with its accelerator built over it
+    pub fn sim(&self) -> SimState {
+        let particles = (self.positions.iter().zip(&self.colors))
+            .map(|(&pos, &color)| Particle {
+                pos,
+                vel: Vec3::ZERO,
+                color,
+            })
+            .collect();
+        SimState::from_particles(self.config.clone(), particles)
+    }
+
+    /// Neighbors the force pass visited for particle `i`
+    pub fn neighbors_of(&self, i: usize) -> &[u32] {
+        let (start, end) = (self.neighbor_starts[i], self.neighbor_starts[i + 1]);
+        &self.neighbors[start as usize..end as usize]
+    }
+
+    /// Total number of neighbors visited, counting each pair from both sides
+    pub fn neighbor_pairs(&self) -> usize {
+        self.neighbors.len()
+    }
+
+    /// Check that `sim`, built by [`Workload::sim`], lays out its accelerator and finds
+    /// neighbors exactly as captured
+    pub fn check(&self, sim: &SimState) -> Result<(), String> {
+        let (accel, _) = sim.last_accel();
+        if (accel.radius(), accel.mode()) != (self.radius, self.mode) {
+            return Err(format!(
+                "Accelerator is {:?} with radius {}, captured as {:?} with radius {}",
+                accel.mode(),
+                accel.radius(),
+                self.mode,
+                self.radius
+            ));
+        }
+        if accel.cell_layout() != self.cells {
+            return Err("Cell layout differs from the capture".into());
+        }
+
+        let (starts, neighbors) = neighbor_lists(sim);
+        match (0..self.positions.len()).find(|&i| {
+            let (start, end) = (starts[i] as usize, starts[i + 1] as usize);
+            neighbors[start..end] != *self.neighbors_of(i)
+        }) {
+            Some(i) => Err(format!(
+                "Particle {} has neighbors {:?}, captured as {:?}",
+                i,
+                &neighbors[starts[i] as usize..starts[i + 1] as usize],
+                self.neighbors_of(i)
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        let mut blob = WORKLOAD_VERSION.to_le_bytes().to_vec();
+        bincode::serialize_into(&mut blob, self).expect("Workloads are always serializable");
+        blob
+    }
+
+    pub fn decode(blob: &[u8]) -> Result<Self, PersistError> {
+        let (version, body) = blob
+            .split_first_chunk::<4>()
+            .ok_or(PersistError::Truncated)?;
+        let version = u32::from_le_bytes(*version);
+        if version != WORKLOAD_VERSION {
+            return Err(PersistError::Version(version));
+        }
+        let workload: Self =
+            bincode::deserialize(body).map_err(|e| PersistError::Malformed(e.to_string()))?;
+
+        let n = workload.positions.len();
+        let n_colors = workload.config.colors.len();
+        if n_colors == 0 || workload.config.behaviours.len() != n_colors * n_colors {
+            return Err(PersistError::Invalid(
+                "Behaviour matrix does not match the colors",
+            ));
+        }
+        if n > MAX_PARTICLES || workload.colors.len() != n {
+            return Err(PersistError::Invalid("Particle arrays do not match"));
+        }
+        if workload.colors.iter().any(|&c| c as usize >= n_colors) {
+            return Err(PersistError::Invalid("Particle type out of range"));
+        }
+        let starts = &workload.neighbor_starts;
+        if starts.len() != n + 1
+            || starts.first() != Some(&0)
+            || starts.windows(2).any(|w| w[0] > w[1])
+            || starts[n] as usize != workload.neighbors.len()
+            || workload.neighbors.iter().any(|&j| j as usize >= n)
+        {
+            return Err(PersistError::Invalid("Neighbor lists are inconsistent"));
+        }
+        Ok(workload)
+    }
+
+    /// Hex encoded blob, for copying out of the log
+    pub fn to_hex(&self) -> String {
+        self.encode().iter().map(|b| format!("{:02x}", b)).collect()
+    }
+
+    pub fn from_hex(hex: &str) -> Result<Self, PersistError> {
+        let hex = hex.trim();
+        let blob = (0..hex.len() / 2)
+            .map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+            .collect::<Option<Vec<u8>>>()
+            .ok_or_else(|| PersistError::Malformed("Not hexadecimal".into()))?;
+        Self::decode(&blob)
+    }
+}
+
+/// Sorted neighbors of every particle of `sim`, as starts into one array
+fn neighbor_lists(sim: &SimState) -> (Vec<u32>, Vec<u32>) {
+    let mut starts = vec![0];
+    let mut neighbors = vec![];
+    for i in 0..sim.particles().len() {
+        let first = neighbors.len();
+        neighbors.extend(sim.interaction_neighbors(i).map(|j| j as u32));
+        neighbors[first..].sort_unstable();
+        starts.push(neighbors.len() as u32);
+    }
+    (starts, neighbors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::Behaviour;
+    use cimvr_engine_interface::pcg::Pcg;
+
+    /// Captured from a three type cloud after a few hundred steps
+    const FIXTURE: &[u8] = include_bytes!("../fixtures/workload_small.bin");
+
+    fn cloud() -> SimState {
+        let mut rng = Pcg::new();
+        let mut config = SimConfig {
+            colors: vec![[1., 0., 0.], [0., 1., 0.], [0., 0., 1.]],
+            behaviours: vec![Behaviour::default(); 9],
+            damping: 100.,
+            ..Default::default()
+        };
+        for (idx, behav) in config.behaviours.iter_mut().enumerate() {
+            behav.inter_strength = [4., -2., 1., 3., 5., -3., -1., 2., 6.][idx];
+        }
+        let mut sim = SimState::new(&mut rng, config, 120);
+        (0..300).for_each(|_| sim.step(1e-3));
+        sim
+    }
+
+    #[test]
+    fn test_workload_round_trip() {
+        let sim = cloud();
+        let workload = Workload::capture(&sim).unwrap();
+        assert!(workload.neighbor_pairs() > 0);
+        assert_eq!(Workload::decode(&workload.encode()), Ok(workload.clone()));
+        assert_eq!(Workload::from_hex(&workload.to_hex()), Ok(workload.clone()));
+
+        let replayed = workload.sim();
+        assert_eq!(workload.check(&replayed), Ok(()));
+
+        let mut blob = workload.encode();
+        blob[0] += 1;
+        assert_eq!(Workload::decode(&blob), Err(PersistError::Version(4)));
+        assert_eq!(Workload::decode(&blob[..3]), Err(PersistError::Truncated));
+
+        let mut broken = workload.clone();
+        broken.neighbors[0] = sim.particles().len() as u32;
+        assert!(matches!(
+            Workload::decode(&broken.encode()),
+            Err(PersistError::Invalid(_))
+        ));
+        let mut moved = workload;
+        moved.neighbors[0] = (moved.neighbors[0] + 1) % sim.particles().len() as u32;
+        assert!(moved.check(&replayed).unwrap_err().contains("Particle 0"));
+    }
+
+    #[test]
+    fn test_fixture() {
+        let workload = Workload::decode(FIXTURE).unwrap();
+        assert_eq!(workload.encode(), FIXTURE);
+        assert_eq!(workload.check(&workload.sim()), Ok(()));
+    }
+}